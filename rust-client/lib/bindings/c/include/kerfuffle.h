@@ -0,0 +1,80 @@
+/*
+ * SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+/*
+ * C ABI for Dynamo runtime leases and discovery registration.
+ * Link against libkerfuffle (cdylib or staticlib from lib/bindings/c).
+ *
+ * All functions block. Do not call them from inside a watch callback.
+ * After kerfuffle_shutdown, every function returns KERFUFFLE_SHUT_DOWN at once.
+ */
+
+#ifndef KERFUFFLE_H
+#define KERFUFFLE_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef enum kerfuffle_result {
+    KERFUFFLE_OK = 0,
+    KERFUFFLE_ERR = 1,
+    KERFUFFLE_INVALID_ARGUMENT = 2,
+    KERFUFFLE_NOT_INITIALIZED = 3,
+    KERFUFFLE_ALREADY_INITIALIZED = 4,
+    KERFUFFLE_NOT_FOUND = 5,
+    KERFUFFLE_SHUT_DOWN = 6,
+} kerfuffle_result_t;
+
+typedef enum kerfuffle_watch_event {
+    KERFUFFLE_WATCH_PUT = 0,
+    KERFUFFLE_WATCH_DELETE = 1,
+} kerfuffle_watch_event_t;
+
+/*
+ * key is NUL terminated, value is not. Both are only valid during the call.
+ *
+ * Called on one of the runtime's worker threads, never the thread that started the watch.
+ * One watch's events arrive one at a time and in order; the callbacks of different watches
+ * may run at the same time on different threads. Return quickly, and do not call kerfuffle
+ * functions from the callback.
+ */
+typedef void (*kerfuffle_watch_callback_t)(void *ctx,
+                                           kerfuffle_watch_event_t event,
+                                           const char *key,
+                                           const uint8_t *value,
+                                           size_t value_len);
+
+/* endpoints: comma separated etcd URLs, or NULL to use ETCD_ENDPOINTS */
+kerfuffle_result_t kerfuffle_init(const char *endpoints);
+kerfuffle_result_t kerfuffle_shutdown(void);
+
+kerfuffle_result_t kerfuffle_primary_lease_id(uint64_t *lease_id_out);
+kerfuffle_result_t kerfuffle_lease_create(uint64_t ttl, uint64_t *lease_id_out);
+/* returns once etcd has dropped the lease and deleted its keys */
+kerfuffle_result_t kerfuffle_lease_revoke(uint64_t lease_id);
+/* 1 valid, 0 expired, -1 revoked or unknown lease, not initialized or shut down */
+int32_t kerfuffle_lease_is_valid(uint64_t lease_id);
+
+/* lease_id 0 attaches the key to the primary lease */
+kerfuffle_result_t kerfuffle_register_ephemeral(const char *key,
+                                                const uint8_t *value,
+                                                size_t value_len,
+                                                uint64_t lease_id);
+
+kerfuffle_result_t kerfuffle_watch_prefix(const char *prefix,
+                                          kerfuffle_watch_callback_t callback,
+                                          void *ctx,
+                                          uint64_t *watch_id_out);
+kerfuffle_result_t kerfuffle_watch_cancel(uint64_t watch_id);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* KERFUFFLE_H */
@@ -0,0 +1,429 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! C ABI for leases and discovery registration.
+//!
+//! Lets a non-Rust process (our C++ engine) hold etcd leases, register ephemeral keys and watch
+//! prefixes through the same client the Rust workers use. The matching header is
+//! `include/kerfuffle.h`; keep the two in sync, the ABI is what we promise to keep stable.
+//!
+//! All functions are blocking and must not be called from inside a watch callback: callbacks
+//! run on a runtime worker thread, and blocking it on the runtime deadlocks.
+//!
+//! After [`kerfuffle_shutdown`] every function returns [`KerfuffleResult::ShutDown`] at once,
+//! rather than waiting on the stopped runtime.
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString, c_char, c_void};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use tokio_util::sync::CancellationToken;
+
+use dynamo_runtime::{
+    Runtime,
+    transports::etcd::{self, Lease},
+};
+
+/// Return code of every `kerfuffle_*` function
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KerfuffleResult {
+    Ok = 0,
+    /// The operation failed, details are in the log
+    Err = 1,
+    /// A pointer was NULL, or a string was not valid UTF-8
+    InvalidArgument = 2,
+    /// `kerfuffle_init` has not been called, or failed
+    NotInitialized = 3,
+    AlreadyInitialized = 4,
+    /// No lease or watch with that ID was created through this library
+    NotFound = 5,
+    /// `kerfuffle_shutdown` has been called
+    ShutDown = 6,
+}
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KerfuffleWatchEvent {
+    Put = 0,
+    Delete = 1,
+}
+
+/// Called once per watch event. `key` is NUL terminated, `value` is not and may be NULL when
+/// `value_len` is 0. Both are only valid for the duration of the call.
+pub type KerfuffleWatchCallback = extern "C" fn(
+    ctx: *mut c_void,
+    event: KerfuffleWatchEvent,
+    key: *const c_char,
+    value: *const u8,
+    value_len: usize,
+);
+
+struct State {
+    runtime: Runtime,
+    client: etcd::Client,
+    /// Leases created through [`kerfuffle_lease_create`], keyed by lease ID, so C callers can
+    /// revoke them by ID.
+    leases: Mutex<HashMap<u64, Lease>>,
+    watches: Mutex<HashMap<u64, CancellationToken>>,
+    next_watch_id: AtomicU64,
+    /// Set by [`kerfuffle_shutdown`], after which the runtime can't be used
+    shut_down: AtomicBool,
+}
+
+static STATE: OnceLock<State> = OnceLock::new();
+
+fn state() -> Result<&'static State, KerfuffleResult> {
+    let state = STATE.get().ok_or(KerfuffleResult::NotInitialized)?;
+    if state.shut_down.load(Ordering::Acquire) {
+        return Err(KerfuffleResult::ShutDown);
+    }
+    Ok(state)
+}
+
+/// # Safety
+/// `ptr` must be NULL or point at a NUL terminated string.
+unsafe fn str_arg<'a>(ptr: *const c_char) -> Result<&'a str, KerfuffleResult> {
+    if ptr.is_null() {
+        return Err(KerfuffleResult::InvalidArgument);
+    }
+    unsafe { CStr::from_ptr(ptr) }
+        .to_str()
+        .map_err(|_| KerfuffleResult::InvalidArgument)
+}
+
+fn report(op: &str, result: anyhow::Result<()>) -> KerfuffleResult {
+    match result {
+        Ok(()) => KerfuffleResult::Ok,
+        Err(err) => {
+            tracing::error!(error = %err, op, "kerfuffle C API call failed");
+            KerfuffleResult::Err
+        }
+    }
+}
+
+macro_rules! try_arg {
+    ($e:expr) => {
+        match $e {
+            Ok(v) => v,
+            Err(code) => return code,
+        }
+    };
+}
+
+/// Start the runtime and connect to etcd. `endpoints` is a comma separated list of URLs, or
+/// NULL to read `ETCD_ENDPOINTS` from the environment.
+///
+/// # Safety
+/// `endpoints` must be NULL or a valid NUL terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kerfuffle_init(endpoints: *const c_char) -> KerfuffleResult {
+    if STATE.get().is_some() {
+        return KerfuffleResult::AlreadyInitialized;
+    }
+    let mut options = etcd::ClientOptions::default();
+    if !endpoints.is_null() {
+        let endpoints = try_arg!(unsafe { str_arg(endpoints) });
+        options.etcd_url = endpoints.split(',').map(|s| s.trim().to_string()).collect();
+    }
+
+    report(
+        "init",
+        (|| {
+            let runtime = Runtime::from_settings()?;
+            let client = runtime
+                .primary()
+                .block_on(etcd::Client::new(options, runtime.clone()))?;
+            let state = State {
+                runtime,
+                client,
+                leases: Mutex::new(HashMap::new()),
+                watches: Mutex::new(HashMap::new()),
+                next_watch_id: AtomicU64::new(1),
+                shut_down: AtomicBool::new(false),
+            };
+            if STATE.set(state).is_err() {
+                anyhow::bail!("initialized concurrently");
+            }
+            Ok(())
+        })(),
+    )
+}
+
+/// Revoke all leases and stop the runtime. The library cannot be re-initialized afterwards,
+/// and every call after this one returns [`KerfuffleResult::ShutDown`].
+#[unsafe(no_mangle)]
+pub extern "C" fn kerfuffle_shutdown() -> KerfuffleResult {
+    let state = try_arg!(state());
+    if state.shut_down.swap(true, Ordering::AcqRel) {
+        return KerfuffleResult::ShutDown;
+    }
+    for (_, token) in state.watches.lock().drain() {
+        token.cancel();
+    }
+    for (_, lease) in state.leases.lock().drain() {
+        lease.revoke();
+    }
+    state.runtime.shutdown();
+    KerfuffleResult::Ok
+}
+
+/// The primary lease ID of the connection. Keys registered with lease 0 are attached to it.
+///
+/// # Safety
+/// `lease_id_out` must be a valid pointer.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kerfuffle_primary_lease_id(lease_id_out: *mut u64) -> KerfuffleResult {
+    let state = try_arg!(state());
+    if lease_id_out.is_null() {
+        return KerfuffleResult::InvalidArgument;
+    }
+    unsafe { *lease_id_out = state.client.lease_id() };
+    KerfuffleResult::Ok
+}
+
+/// Create a lease with a background keep-alive. It lives until revoked or the runtime stops.
+///
+/// # Safety
+/// `lease_id_out` must be a valid pointer.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kerfuffle_lease_create(
+    ttl: u64,
+    lease_id_out: *mut u64,
+) -> KerfuffleResult {
+    let state = try_arg!(state());
+    if lease_id_out.is_null() || ttl == 0 {
+        return KerfuffleResult::InvalidArgument;
+    }
+    report(
+        "lease_create",
+        state
+            .runtime
+            .primary()
+            .block_on(state.client.create_lease(ttl))
+            .map(|lease| {
+                unsafe { *lease_id_out = lease.id() };
+                state.leases.lock().insert(lease.id(), lease);
+            }),
+    )
+}
+
+/// How often [`kerfuffle_lease_revoke`] checks whether etcd has dropped the lease
+const REVOKE_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Revoke a lease created by [`kerfuffle_lease_create`]. Keys attached to it are deleted by
+/// the time this returns.
+#[unsafe(no_mangle)]
+pub extern "C" fn kerfuffle_lease_revoke(lease_id: u64) -> KerfuffleResult {
+    let state = try_arg!(state());
+    let Some(lease) = state.leases.lock().remove(&lease_id) else {
+        return KerfuffleResult::NotFound;
+    };
+    // Cancelling the lease has its keep-alive task revoke it in etcd. Wait for that rather
+    // than revoking it a second time: the loser of the two would fail with "lease not found".
+    lease.revoke();
+    report(
+        "lease_revoke",
+        state.runtime.primary().block_on(async {
+            while state.client.lease_info(lease_id).await?.is_some() {
+                tokio::time::sleep(REVOKE_POLL_INTERVAL).await;
+            }
+            Ok(())
+        }),
+    )
+}
+
+/// 1 if the lease is still being kept alive, 0 if it expired, -1 if it was revoked, is unknown,
+/// or the library isn't initialized or was shut down. Revoked leases are forgotten, so they
+/// are as unknown as one never created.
+#[unsafe(no_mangle)]
+pub extern "C" fn kerfuffle_lease_is_valid(lease_id: u64) -> i32 {
+    let Ok(state) = state() else {
+        return -1;
+    };
+    if lease_id == state.client.lease_id() {
+        return !state.runtime.primary_token().is_cancelled() as i32;
+    }
+    match state.leases.lock().get(&lease_id) {
+        Some(lease) => !lease.primary_token().is_cancelled() as i32,
+        None => -1,
+    }
+}
+
+/// Create `key` attached to `lease_id` (0 for the primary lease). Fails if the key exists.
+///
+/// # Safety
+/// `key` must be a valid NUL terminated string. `value` must point at `value_len` bytes, or be
+/// NULL if `value_len` is 0.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kerfuffle_register_ephemeral(
+    key: *const c_char,
+    value: *const u8,
+    value_len: usize,
+    lease_id: u64,
+) -> KerfuffleResult {
+    let state = try_arg!(state());
+    let key = try_arg!(unsafe { str_arg(key) });
+    let value = match (value.is_null(), value_len) {
+        (_, 0) => Vec::new(),
+        (true, _) => return KerfuffleResult::InvalidArgument,
+        (false, len) => unsafe { std::slice::from_raw_parts(value, len) }.to_vec(),
+    };
+    let lease_id = (lease_id != 0).then_some(lease_id);
+    report(
+        "register_ephemeral",
+        state
+            .runtime
+            .primary()
+            .block_on(state.client.kv_create(key, value, lease_id)),
+    )
+}
+
+/// Watch every key under `prefix`. Existing keys are delivered first as puts. `ctx` is passed
+/// back to `callback` untouched.
+///
+/// `callback` runs on one of the runtime's worker threads, never the thread that called this.
+/// The events of one watch are delivered one at a time and in order, but the callbacks of
+/// different watches may run at the same time on different threads.
+///
+/// # Safety
+/// `prefix` must be a valid NUL terminated string and `watch_id_out` a valid pointer. `ctx`
+/// must remain valid, and be usable from another thread, until [`kerfuffle_watch_cancel`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kerfuffle_watch_prefix(
+    prefix: *const c_char,
+    callback: KerfuffleWatchCallback,
+    ctx: *mut c_void,
+    watch_id_out: *mut u64,
+) -> KerfuffleResult {
+    let state = try_arg!(state());
+    let prefix = try_arg!(unsafe { str_arg(prefix) }).to_string();
+    if watch_id_out.is_null() {
+        return KerfuffleResult::InvalidArgument;
+    }
+
+    let watcher = match state
+        .runtime
+        .primary()
+        .block_on(state.client.kv_get_and_watch_prefix(&prefix))
+    {
+        Ok(watcher) => watcher,
+        Err(err) => return report("watch_prefix", Err(err)),
+    };
+    let (_prefix, watcher, mut rx) = watcher.dissolve();
+
+    let watch_id = state.next_watch_id.fetch_add(1, Ordering::Relaxed);
+    let token = state.runtime.child_token();
+    state.watches.lock().insert(watch_id, token.clone());
+
+    let ctx = SendPtr(ctx);
+    state.runtime.primary().spawn(async move {
+        // Dropping the watcher ends the etcd watch, keep it until we are done
+        let _watcher = watcher;
+        loop {
+            let event = tokio::select! {
+                _ = token.cancelled() => break,
+                event = rx.recv() => match event {
+                    Some(event) => event,
+                    None => break,
+                },
+            };
+            let (kind, kv) = match event {
+                etcd::WatchEvent::Put(kv) => (KerfuffleWatchEvent::Put, kv),
+                etcd::WatchEvent::Delete(kv) => (KerfuffleWatchEvent::Delete, kv),
+            };
+            let Ok(key) = CString::new(kv.key()) else {
+                tracing::warn!(prefix, "Skipping watch event for key containing NUL");
+                continue;
+            };
+            let value = kv.value();
+            callback(ctx.get(), kind, key.as_ptr(), value.as_ptr(), value.len());
+        }
+    });
+
+    unsafe { *watch_id_out = watch_id };
+    KerfuffleResult::Ok
+}
+
+/// Stop a watch. A callback already running on a runtime thread may still finish after this
+/// returns, so do not free `ctx` immediately.
+#[unsafe(no_mangle)]
+pub extern "C" fn kerfuffle_watch_cancel(watch_id: u64) -> KerfuffleResult {
+    let state = try_arg!(state());
+    match state.watches.lock().remove(&watch_id) {
+        Some(token) => {
+            token.cancel();
+            KerfuffleResult::Ok
+        }
+        None => KerfuffleResult::NotFound,
+    }
+}
+
+/// The caller's context pointer. The C side promises it is usable from any thread.
+struct SendPtr(*mut c_void);
+unsafe impl Send for SendPtr {}
+
+impl SendPtr {
+    // A method rather than `.0`, so that closures capture the whole `SendPtr`
+    fn get(&self) -> *mut c_void {
+        self.0
+    }
+}
+
+#[cfg(feature = "testing-etcd")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One test, as the library's state is global to the process
+    #[test]
+    fn test_lifecycle() {
+        let mut lease_id = 0;
+        assert_eq!(kerfuffle_shutdown(), KerfuffleResult::NotInitialized);
+        assert_eq!(kerfuffle_lease_is_valid(1), -1);
+
+        assert_eq!(
+            unsafe { kerfuffle_init(std::ptr::null()) },
+            KerfuffleResult::Ok
+        );
+        let endpoints = c"http://localhost:2379";
+        assert_eq!(
+            unsafe { kerfuffle_init(endpoints.as_ptr()) },
+            KerfuffleResult::AlreadyInitialized
+        );
+
+        assert_eq!(
+            unsafe { kerfuffle_lease_create(0, &mut lease_id) },
+            KerfuffleResult::InvalidArgument
+        );
+        assert_eq!(
+            unsafe { kerfuffle_lease_create(10, &mut lease_id) },
+            KerfuffleResult::Ok
+        );
+        assert_ne!(lease_id, 0);
+        assert_eq!(kerfuffle_lease_is_valid(lease_id), 1);
+        assert_eq!(kerfuffle_lease_revoke(lease_id), KerfuffleResult::Ok);
+        assert_eq!(kerfuffle_lease_is_valid(lease_id), -1);
+        assert_eq!(kerfuffle_lease_revoke(lease_id), KerfuffleResult::NotFound);
+
+        assert_eq!(kerfuffle_shutdown(), KerfuffleResult::Ok);
+        // Answered at once, not by the stopped runtime
+        assert_eq!(
+            unsafe { kerfuffle_lease_create(10, &mut lease_id) },
+            KerfuffleResult::ShutDown
+        );
+        assert_eq!(
+            unsafe { kerfuffle_primary_lease_id(&mut lease_id) },
+            KerfuffleResult::ShutDown
+        );
+        assert_eq!(kerfuffle_lease_is_valid(lease_id), -1);
+        assert_eq!(kerfuffle_shutdown(), KerfuffleResult::ShutDown);
+        assert_eq!(
+            unsafe { kerfuffle_init(std::ptr::null()) },
+            KerfuffleResult::AlreadyInitialized
+        );
+    }
+}
@@ -0,0 +1,8 @@
+# SPDX-FileCopyrightText: Copyright (c) 2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+# SPDX-License-Identifier: Apache-2.0
+
+from kerfuffle._core import Bucket as Bucket
+from kerfuffle._core import EtcdClient as EtcdClient
+from kerfuffle._core import KeyValueStore as KeyValueStore
+from kerfuffle._core import Lease as Lease
+from kerfuffle._core import WatchStream as WatchStream
@@ -0,0 +1,543 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Python bindings for the etcd [`Client`](rs::transports::etcd::Client) and the
+//! [`KeyValueStoreManager`](rs::storage::key_value_store::KeyValueStoreManager).
+//!
+//! Every method that talks to the store returns an awaitable driven by a tokio runtime owned by
+//! `pyo3-async-runtimes`, so Python callers use these from `asyncio` like any other coroutine.
+//! Watches are exposed as async iterators yielding `(event, key, value)` tuples where `event` is
+//! `"put"` or `"delete"`. A watch that fails raises an exception from the iterator; one that is
+//! closed ends the iteration.
+
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use futures::StreamExt;
+use pyo3::exceptions::{PyException, PyStopAsyncIteration};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict};
+use tokio::sync::{Mutex, mpsc, oneshot};
+
+use dynamo_runtime::{
+    self as rs,
+    storage::key_value_store::{
+        Key, KeyValueBucket, KeyValueStoreManager, StoreOutcome, WatchEvent as StoreWatchEvent,
+    },
+    transports::etcd,
+};
+
+/// Watch events buffered between the background pump and the Python iterator
+const WATCH_BUFFER: usize = 32;
+
+/// The [`rs::Runtime`] shared by every client created from Python. It wraps the tokio runtime
+/// owned by `pyo3-async-runtimes`, so it must be created from inside one of its futures.
+static RUNTIME: OnceLock<rs::Runtime> = OnceLock::new();
+
+fn runtime() -> PyResult<rs::Runtime> {
+    if let Some(rt) = RUNTIME.get() {
+        return Ok(rt.clone());
+    }
+    let rt = rs::Runtime::from_current().map_err(to_pyerr)?;
+    Ok(RUNTIME.get_or_init(|| rt).clone())
+}
+
+fn to_pyerr<E: std::fmt::Display>(err: E) -> PyErr {
+    PyException::new_err(err.to_string())
+}
+
+#[pymodule]
+fn _core(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<EtcdClient>()?;
+    m.add_class::<Lease>()?;
+    m.add_class::<KeyValueStore>()?;
+    m.add_class::<Bucket>()?;
+    m.add_class::<WatchStream>()?;
+    Ok(())
+}
+
+/// One event from a watch. Converted to a Python tuple on the way out.
+type Event = (&'static str, String, bytes::Bytes);
+
+/// Send the key changes from a bucket watch to `tx` until it ends. A failed watch is sent as
+/// an error, for the iterator to raise.
+async fn pump_bucket_watch(
+    mut stream: impl futures::Stream<Item = StoreWatchEvent> + Unpin,
+    tx: mpsc::Sender<PyResult<Event>>,
+) {
+    // Until the marker, the store sends what the bucket held when the watch started
+    let mut syncing = true;
+    while let Some(event) = stream.next().await {
+        let (kind, kv) = match event {
+            StoreWatchEvent::Put(_) | StoreWatchEvent::Delete(_) if syncing => continue,
+            StoreWatchEvent::Put(kv) => ("put", kv),
+            StoreWatchEvent::Delete(kv) => ("delete", kv),
+            StoreWatchEvent::Error(err) => {
+                let _ = tx.send(Err(to_pyerr(err))).await;
+                return;
+            }
+            StoreWatchEvent::Closed => return,
+            StoreWatchEvent::InitialSyncComplete => {
+                syncing = false;
+                continue;
+            }
+            // Markers, not key changes
+            StoreWatchEvent::Disconnected | StoreWatchEvent::Reconnected => continue,
+        };
+        let event = (kind, kv.key().to_string(), kv.value().clone());
+        if tx.send(Ok(event)).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn bytes_to_py(value: &[u8]) -> PyObject {
+    Python::with_gil(|py| PyBytes::new(py, value).into_any().unbind())
+}
+
+#[pyclass]
+#[derive(Clone)]
+struct EtcdClient {
+    inner: etcd::Client,
+}
+
+#[pymethods]
+impl EtcdClient {
+    /// Connect to etcd. Without `endpoints` the `ETCD_ENDPOINTS` environment variable is used,
+    /// the same as the Rust [`etcd::ClientOptions::default`].
+    #[staticmethod]
+    #[pyo3(signature = (endpoints=None, attach_lease=true))]
+    fn connect<'p>(
+        py: Python<'p>,
+        endpoints: Option<Vec<String>>,
+        attach_lease: bool,
+    ) -> PyResult<Bound<'p, PyAny>> {
+        let mut options = etcd::ClientOptions::default();
+        if let Some(endpoints) = endpoints {
+            options.etcd_url = endpoints;
+        }
+        options.attach_lease = attach_lease;
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let inner = etcd::Client::new(options, runtime()?)
+                .await
+                .map_err(to_pyerr)?;
+            Ok(EtcdClient { inner })
+        })
+    }
+
+    /// The primary lease ID. Zero if the client was created with `attach_lease=False`.
+    fn lease_id(&self) -> u64 {
+        self.inner.lease_id()
+    }
+
+    fn primary_lease(&self) -> Lease {
+        Lease {
+            inner: self.inner.primary_lease(),
+        }
+    }
+
+    /// Create a lease with its own keep-alive, tied to the lifetime of the client's runtime.
+    fn create_lease<'p>(&self, py: Python<'p>, ttl: u64) -> PyResult<Bound<'p, PyAny>> {
+        let client = self.inner.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let inner = client.create_lease(ttl).await.map_err(to_pyerr)?;
+            Ok(Lease { inner })
+        })
+    }
+
+    fn revoke_lease<'p>(&self, py: Python<'p>, lease_id: u64) -> PyResult<Bound<'p, PyAny>> {
+        let client = self.inner.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            client.revoke_lease(lease_id).await.map_err(to_pyerr)
+        })
+    }
+
+    /// Create `key`, failing if it already exists.
+    #[pyo3(signature = (key, value, lease_id=None))]
+    fn kv_create<'p>(
+        &self,
+        py: Python<'p>,
+        key: String,
+        value: Vec<u8>,
+        lease_id: Option<u64>,
+    ) -> PyResult<Bound<'p, PyAny>> {
+        let client = self.inner.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            client
+                .kv_create(&key, value, lease_id)
+                .await
+                .map_err(to_pyerr)
+        })
+    }
+
+    #[pyo3(signature = (key, value, lease_id=None))]
+    fn kv_put<'p>(
+        &self,
+        py: Python<'p>,
+        key: String,
+        value: Vec<u8>,
+        lease_id: Option<u64>,
+    ) -> PyResult<Bound<'p, PyAny>> {
+        let client = self.inner.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            client.kv_put(key, value, lease_id).await.map_err(to_pyerr)
+        })
+    }
+
+    /// The value of `key` as `bytes`, or `None`
+    fn kv_get<'p>(&self, py: Python<'p>, key: String) -> PyResult<Bound<'p, PyAny>> {
+        let client = self.inner.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let kvs = client.kv_get(key, None).await.map_err(to_pyerr)?;
+            Ok(kvs.first().map(|kv| bytes_to_py(kv.value())))
+        })
+    }
+
+    /// All keys under `prefix` as a dict of `str` to `bytes`
+    fn kv_get_prefix<'p>(&self, py: Python<'p>, prefix: String) -> PyResult<Bound<'p, PyAny>> {
+        let client = self.inner.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let kvs = client.kv_get_prefix(prefix).await.map_err(to_pyerr)?;
+            Python::with_gil(|py| {
+                let out = PyDict::new(py);
+                for kv in kvs {
+                    out.set_item(
+                        kv.key_str().map_err(to_pyerr)?,
+                        PyBytes::new(py, kv.value()),
+                    )?;
+                }
+                Ok(out.unbind())
+            })
+        })
+    }
+
+    /// Delete `key`. Returns the number of keys deleted.
+    fn kv_delete<'p>(&self, py: Python<'p>, key: String) -> PyResult<Bound<'p, PyAny>> {
+        let client = self.inner.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            client.kv_delete(key, None).await.map_err(to_pyerr)
+        })
+    }
+
+    /// Acquire the distributed lock `key`. Returns the lock key to pass to [`Self::unlock`].
+    /// The lock is held until unlocked or the lease expires.
+    #[pyo3(signature = (key, lease_id=None))]
+    fn lock<'p>(
+        &self,
+        py: Python<'p>,
+        key: String,
+        lease_id: Option<u64>,
+    ) -> PyResult<Bound<'p, PyAny>> {
+        let client = self.inner.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let response = client.lock(key, lease_id).await.map_err(to_pyerr)?;
+            Ok(bytes_to_py(response.key()))
+        })
+    }
+
+    fn unlock<'p>(&self, py: Python<'p>, lock_key: Vec<u8>) -> PyResult<Bound<'p, PyAny>> {
+        let client = self.inner.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            client.unlock(lock_key).await.map_err(to_pyerr)
+        })
+    }
+
+    /// Watch `prefix`. With `include_existing` the current keys are delivered first as puts.
+    #[pyo3(signature = (prefix, include_existing=true))]
+    fn watch_prefix<'p>(
+        &self,
+        py: Python<'p>,
+        prefix: String,
+        include_existing: bool,
+    ) -> PyResult<Bound<'p, PyAny>> {
+        let client = self.inner.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let watcher = if include_existing {
+                client.kv_get_and_watch_prefix(prefix).await
+            } else {
+                client.kv_watch_prefix(prefix).await
+            }
+            .map_err(to_pyerr)?;
+            let (_prefix, watcher, mut rx) = watcher.dissolve();
+
+            let (tx, out_rx) = mpsc::channel(WATCH_BUFFER);
+            tokio::spawn(async move {
+                // Keep the watcher alive as long as someone is listening
+                let _watcher = watcher;
+                while let Some(event) = rx.recv().await {
+                    let (kind, kv) = match event {
+                        etcd::WatchEvent::Put(kv) => ("put", kv),
+                        etcd::WatchEvent::Delete(kv) => ("delete", kv),
+                    };
+                    let key = String::from_utf8_lossy(kv.key()).to_string();
+                    let value = bytes::Bytes::copy_from_slice(kv.value());
+                    if tx.send(Ok((kind, key, value))).await.is_err() {
+                        break;
+                    }
+                }
+            });
+            Ok(WatchStream::new(out_rx))
+        })
+    }
+}
+
+#[pyclass]
+#[derive(Clone)]
+struct Lease {
+    inner: etcd::Lease,
+}
+
+#[pymethods]
+impl Lease {
+    #[getter]
+    fn id(&self) -> u64 {
+        self.inner.id()
+    }
+
+    /// Monotonic across leases; pass it to external systems so they can reject stale writers
+    #[getter]
+    fn fence_token(&self) -> u64 {
+        self.inner.fence_token()
+    }
+
+    /// Revoke the lease. Keys attached to it are deleted by etcd.
+    fn revoke(&self) {
+        self.inner.revoke()
+    }
+
+    /// False once the lease is revoked or its keep-alive has given up
+    fn is_valid(&self) -> bool {
+        !self.inner.primary_token().is_cancelled()
+    }
+}
+
+#[pyclass]
+#[derive(Clone)]
+struct KeyValueStore {
+    inner: KeyValueStoreManager,
+}
+
+#[pymethods]
+impl KeyValueStore {
+    /// An in-process store, for tests
+    #[staticmethod]
+    fn memory() -> Self {
+        KeyValueStore {
+            inner: KeyValueStoreManager::memory(),
+        }
+    }
+
+    #[staticmethod]
+    fn etcd(client: &EtcdClient) -> Self {
+        KeyValueStore {
+            inner: KeyValueStoreManager::etcd(client.inner.clone()),
+        }
+    }
+
+    fn connection_id(&self) -> u64 {
+        self.inner.connection_id()
+    }
+
+    /// `ttl` is in seconds. Entries older than this are removed, if the backend supports it.
+    #[pyo3(signature = (bucket_name, ttl=None))]
+    fn get_or_create_bucket<'p>(
+        &self,
+        py: Python<'p>,
+        bucket_name: String,
+        ttl: Option<f64>,
+    ) -> PyResult<Bound<'p, PyAny>> {
+        let store = self.inner.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let bucket = store
+                .get_or_create_bucket(&bucket_name, ttl.map(Duration::from_secs_f64))
+                .await
+                .map_err(to_pyerr)?;
+            Ok(Bucket {
+                inner: Arc::from(bucket),
+            })
+        })
+    }
+
+    /// The bucket, or `None` if it does not exist
+    fn get_bucket<'p>(&self, py: Python<'p>, bucket_name: String) -> PyResult<Bound<'p, PyAny>> {
+        let store = self.inner.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let bucket = store.get_bucket(&bucket_name).await.map_err(to_pyerr)?;
+            Ok(bucket.map(|b| Bucket {
+                inner: Arc::from(b),
+            }))
+        })
+    }
+}
+
+/// A bucket from a [`KeyValueStore`]. Keys are slugified the same way as [`Key::new`] so Rust
+/// and Python writers agree on the stored key.
+#[pyclass]
+#[derive(Clone)]
+struct Bucket {
+    inner: Arc<dyn KeyValueBucket>,
+}
+
+#[pymethods]
+impl Bucket {
+    /// Insert if absent. Returns `(created, revision)`; `created` is False if the key existed.
+    #[pyo3(signature = (key, value, revision=0))]
+    fn insert<'p>(
+        &self,
+        py: Python<'p>,
+        key: String,
+        value: String,
+        revision: u64,
+    ) -> PyResult<Bound<'p, PyAny>> {
+        let bucket = self.inner.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let outcome = bucket
+                .insert(&Key::new(&key), &value, revision)
+                .await
+                .map_err(to_pyerr)?;
+            Ok(match outcome {
+                StoreOutcome::Created(rev) => (true, rev),
+                StoreOutcome::Exists(rev) => (false, rev),
+            })
+        })
+    }
+
+    fn get<'p>(&self, py: Python<'p>, key: String) -> PyResult<Bound<'p, PyAny>> {
+        let bucket = self.inner.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let value = bucket.get(&Key::new(&key)).await.map_err(to_pyerr)?;
+            Ok(value.map(|v| bytes_to_py(&v)))
+        })
+    }
+
+    fn delete<'p>(&self, py: Python<'p>, key: String) -> PyResult<Bound<'p, PyAny>> {
+        let bucket = self.inner.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            bucket.delete(&Key::new(&key)).await.map_err(to_pyerr)
+        })
+    }
+
+    /// All entries as a dict of `str` to `bytes`
+    fn entries<'p>(&self, py: Python<'p>) -> PyResult<Bound<'p, PyAny>> {
+        let bucket = self.inner.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let entries: HashMap<String, bytes::Bytes> =
+                bucket.entries().await.map_err(to_pyerr)?;
+            Python::with_gil(|py| {
+                let out = PyDict::new(py);
+                for (key, value) in entries {
+                    out.set_item(key, PyBytes::new(py, &value))?;
+                }
+                Ok(out.unbind())
+            })
+        })
+    }
+
+    /// Changes to the bucket from now on. Existing entries are not included, use
+    /// [`Self::entries`] first if you need them.
+    fn watch<'p>(&self, py: Python<'p>) -> PyResult<Bound<'p, PyAny>> {
+        let bucket = self.inner.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let (tx, rx) = mpsc::channel(WATCH_BUFFER);
+            // Return once the watch is in place, so changes made right after are seen
+            let (started_tx, started_rx) = oneshot::channel();
+            tokio::spawn(async move {
+                let started = bucket.watch().await;
+                let _ = started_tx.send(());
+                match started {
+                    Ok(stream) => pump_bucket_watch(stream, tx).await,
+                    Err(err) => {
+                        let _ = tx.send(Err(to_pyerr(err))).await;
+                    }
+                }
+            });
+            let _ = started_rx.await;
+            Ok(WatchStream::new(rx))
+        })
+    }
+}
+
+/// Async iterator over watch events. Iteration ends when the watch is closed, and raises if it
+/// failed.
+#[pyclass]
+struct WatchStream {
+    rx: Arc<Mutex<mpsc::Receiver<PyResult<Event>>>>,
+}
+
+impl WatchStream {
+    fn new(rx: mpsc::Receiver<PyResult<Event>>) -> Self {
+        WatchStream {
+            rx: Arc::new(Mutex::new(rx)),
+        }
+    }
+}
+
+#[pymethods]
+impl WatchStream {
+    fn __aiter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __anext__<'p>(&self, py: Python<'p>) -> PyResult<Bound<'p, PyAny>> {
+        let rx = self.rx.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            match rx.lock().await.recv().await {
+                Some(Ok((kind, key, value))) => Ok((kind, key, bytes_to_py(&value))),
+                Some(Err(err)) => Err(err),
+                None => Err(PyStopAsyncIteration::new_err("watch ended")),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dynamo_runtime::storage::key_value_store::{KeyValue, StoreError};
+
+    fn put(key: &str) -> StoreWatchEvent {
+        StoreWatchEvent::Put(KeyValue::new(
+            key.to_string(),
+            bytes::Bytes::from_static(b"v"),
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_closed_ends_iteration() {
+        let events = vec![
+            // What the bucket held, not a change
+            put("existing"),
+            StoreWatchEvent::InitialSyncComplete,
+            put("a"),
+            StoreWatchEvent::Delete(KeyValue::new("a".to_string(), bytes::Bytes::new())),
+            StoreWatchEvent::Closed,
+            put("after"),
+        ];
+        let (tx, mut rx) = mpsc::channel(WATCH_BUFFER);
+        pump_bucket_watch(futures::stream::iter(events), tx).await;
+
+        let (kind, key, _) = rx.recv().await.unwrap().unwrap();
+        assert_eq!((kind, key.as_str()), ("put", "a"));
+        let (kind, key, _) = rx.recv().await.unwrap().unwrap();
+        assert_eq!((kind, key.as_str()), ("delete", "a"));
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_error_is_raised() {
+        let err = Arc::new(StoreError::MissingBucket("gone".to_string()));
+        let events = vec![
+            StoreWatchEvent::InitialSyncComplete,
+            put("a"),
+            StoreWatchEvent::Error(err),
+            put("after"),
+        ];
+        let (tx, mut rx) = mpsc::channel(WATCH_BUFFER);
+        pump_bucket_watch(futures::stream::iter(events), tx).await;
+
+        assert!(rx.recv().await.unwrap().is_ok());
+        assert!(rx.recv().await.unwrap().is_err());
+        assert!(rx.recv().await.is_none());
+    }
+}
@@ -0,0 +1,37 @@
+# SPDX-FileCopyrightText: Copyright (c) 2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+# SPDX-License-Identifier: Apache-2.0
+
+import asyncio
+
+from kerfuffle import KeyValueStore
+
+
+def test_insert_get_delete():
+    async def main():
+        store = KeyValueStore.memory()
+        bucket = await store.get_or_create_bucket("test")
+        created, revision = await bucket.insert("a", '{"x": 1}')
+        assert created and revision > 0
+        created, _ = await bucket.insert("a", '{"x": 2}')
+        assert not created
+        assert await bucket.get("a") == b'{"x": 1}'
+        assert await bucket.entries() == {"a": b'{"x": 1}'}
+        await bucket.delete("a")
+        assert await bucket.get("a") is None
+
+    asyncio.run(main())
+
+
+def test_watch():
+    async def main():
+        store = KeyValueStore.memory()
+        bucket = await store.get_or_create_bucket("test")
+        await bucket.insert("existing", "0")
+        events = await bucket.watch()
+        await bucket.insert("a", "1")
+        await bucket.delete("a")
+        assert await anext(events) == ("put", "a", b"1")
+        event, key, _ = await anext(events)
+        assert (event, key) == ("delete", "a")
+
+    asyncio.run(main())
@@ -0,0 +1,94 @@
+// SPDX-FileCopyrightText: Copyright (c) 2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Insert, get, watch and delete throughput of the store backends.
+//!
+//! Only the in-memory store runs by default. Configure with:
+//! - `KERFUFFLE_BENCH_STORES`: comma separated store URLs, as for `DISCOVERY_URL`,
+//!   e.g. `mem://,etcd://localhost:2379,nats://localhost:4222`
+//! - `KERFUFFLE_BENCH_KEYS`: comma separated key counts, default `100,1000`
+//! - `KERFUFFLE_BENCH_VALUE_SIZES`: comma separated value sizes in bytes, default `64,4096`
+//!
+//! `rust-client bench` runs the same workload once and prints latency percentiles.
+
+use std::time::Duration;
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use dynamo_runtime::Runtime;
+use dynamo_runtime::distributed::StoreUrl;
+use dynamo_runtime::storage::key_value_store::bench::{self, BenchConfig};
+
+fn env_list<T: std::str::FromStr>(name: &str, default: &str) -> Vec<T>
+where
+    T::Err: std::fmt::Debug,
+{
+    std::env::var(name)
+        .unwrap_or_else(|_| default.to_string())
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(|v| {
+            v.parse()
+                .unwrap_or_else(|err| panic!("Invalid {name} entry '{v}': {err:?}"))
+        })
+        .collect()
+}
+
+fn bench_store_backends(c: &mut Criterion) {
+    let urls: Vec<String> = env_list("KERFUFFLE_BENCH_STORES", "mem://");
+    let key_counts: Vec<usize> = env_list("KERFUFFLE_BENCH_KEYS", "100,1000");
+    let value_sizes: Vec<usize> = env_list("KERFUFFLE_BENCH_VALUE_SIZES", "64,4096");
+
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let runtime = rt.block_on(async { Runtime::from_current() }).unwrap();
+
+    let mut group = c.benchmark_group("store_backends");
+    group.sample_size(10);
+
+    for url in urls {
+        let store_url: StoreUrl = url.parse().unwrap();
+        let scheme = url
+            .split_once("://")
+            .map(|(s, _)| s)
+            .unwrap_or(&url)
+            .to_string();
+        let store = rt
+            .block_on(store_url.connect(runtime.clone()))
+            .unwrap_or_else(|err| panic!("Unable to connect to {url}: {err}"));
+
+        for &keys in &key_counts {
+            for &value_size in &value_sizes {
+                let config = BenchConfig { keys, value_size };
+                group.throughput(Throughput::Elements(keys as u64));
+                for (index, op) in ["insert", "get", "watch", "delete"].into_iter().enumerate() {
+                    let id =
+                        BenchmarkId::new(format!("{scheme}/{op}"), format!("{keys}x{value_size}B"));
+                    group.bench_with_input(id, &config, |b, config| {
+                        // One run times all four operations; report the one this benchmark is for
+                        b.to_async(&rt).iter_custom(|iters| {
+                            let store = store.clone();
+                            let config = *config;
+                            async move {
+                                let mut total = Duration::ZERO;
+                                for _ in 0..iters {
+                                    let stats = bench::run(&store, &config).await.unwrap();
+                                    total += stats[index].elapsed;
+                                }
+                                total
+                            }
+                        });
+                    });
+                }
+            }
+        }
+    }
+
+    group.finish();
+    runtime.shutdown();
+}
+
+criterion_group!(benches, bench_store_backends);
+criterion_main!(benches);
@@ -33,9 +33,9 @@ async fn app(runtime: Runtime) -> Result<()> {
         println!("{:?}", resp);
     }
 
-    // This is just an illustration to invoke the server's stats_registry(<action>), where
-    // the action currently increments the `service_requests_total` metric. You can validate
-    // the result by running `curl http://localhost:8000/metrics`
+    // The server counts requests in `service_requests_total`, created through its
+    // component's stats_registry(). It is in the "metrics" field of the endpoint stats below,
+    // and also served by `curl http://localhost:8000/metrics`
     let service_set = component.scrape_stats(Duration::from_millis(100)).await?;
     println!("{:?}", service_set);
 
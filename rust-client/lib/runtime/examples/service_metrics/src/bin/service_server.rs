@@ -25,11 +25,13 @@ async fn app(runtime: Runtime) -> Result<()> {
     backend(distributed).await
 }
 
-struct RequestHandler {}
+struct RequestHandler {
+    requests: prometheus::IntCounter,
+}
 
 impl RequestHandler {
-    fn new() -> Arc<Self> {
-        Arc::new(Self {})
+    fn new(requests: prometheus::IntCounter) -> Arc<Self> {
+        Arc::new(Self { requests })
     }
 }
 
@@ -37,6 +39,7 @@ impl RequestHandler {
 impl AsyncEngine<SingleIn<String>, ManyOut<Annotated<String>>, Error> for RequestHandler {
     async fn generate(&self, input: SingleIn<String>) -> Result<ManyOut<Annotated<String>>> {
         let (data, ctx) = input.into_parts();
+        self.requests.inc();
 
         let chars = data
             .chars()
@@ -50,13 +53,19 @@ impl AsyncEngine<SingleIn<String>, ManyOut<Annotated<String>>, Error> for Reques
 }
 
 async fn backend(runtime: DistributedRuntime) -> Result<()> {
+    let mut component = runtime.namespace(DEFAULT_NAMESPACE)?.component("backend")?;
+
+    // served on /metrics, and in the stats of each endpoint under "metrics"
+    let requests = component
+        .stats_registry()
+        .await
+        .intcounter("service_requests_total", "Requests handled by the backend")?;
+
     // attach an ingress to an engine
-    let ingress = Ingress::for_engine(RequestHandler::new())?;
+    let ingress = Ingress::for_engine(RequestHandler::new(requests))?;
 
     // make the ingress discoverable via a component service
     // we must first create a service, then we can attach one more more endpoints
-
-    let mut component = runtime.namespace(DEFAULT_NAMESPACE)?.component("backend")?;
     component.add_stats_service().await?;
     component
         .endpoint("generate")
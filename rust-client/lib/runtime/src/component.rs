@@ -47,9 +47,17 @@ use super::{
     utils::Duration,
 };
 
-use crate::pipeline::network::{PushWorkHandler, ingress::push_endpoint::PushEndpoint};
+use crate::pipeline::network::{
+    PushWorkHandler,
+    codec::PayloadCodec,
+    ingress::push_endpoint::{PushEndpoint, RequestSource},
+};
 use crate::protocols::EndpointId;
 use crate::service::ComponentNatsServerPrometheusMetrics;
+use crate::storage::encoding;
+use crate::storage::key_value_store::Key;
+use crate::storage::layout::LayoutMarker;
+use crate::utils::tasks::supervisor::RestartPolicy;
 use async_nats::{
     rustls::quic,
     service::{Service, ServiceExt},
@@ -69,8 +77,14 @@ mod endpoint;
 mod namespace;
 mod registry;
 pub mod service;
+pub mod signing;
+mod stats_cache;
+mod stats_registry;
 
 pub use client::{Client, InstanceSource};
+pub use signing::{InstanceSignature, InstanceSigner, InstanceVerifier};
+pub use stats_cache::{ComponentStats, StatsCacheConfig};
+pub use stats_registry::{STATS_KEY, StatsRegistry};
 
 /// The root key-value path where each instance registers itself in.
 /// An instance is namespace+component+endpoint+lease_id and must be unique.
@@ -86,6 +100,12 @@ pub enum TransportType {
 pub struct RegistryInner {
     services: HashMap<String, Service>,
     stats_handlers: HashMap<String, Arc<parking_lot::Mutex<HashMap<String, EndpointStatsHandler>>>>,
+    /// The codec each endpoint served here advertises, by subject
+    codecs: HashMap<String, PayloadCodec>,
+    /// For [`Component::stats_cached`], by service name
+    stats_caches: HashMap<String, Arc<stats_cache::StatsCache>>,
+    /// Metrics from [`Component::stats_registry`], by service name
+    registered_stats: HashMap<String, stats_registry::RegisteredStats>,
 }
 
 #[derive(Clone)]
@@ -100,6 +120,47 @@ pub struct Instance {
     pub namespace: String,
     pub instance_id: u64,
     pub transport: TransportType,
+    /// The [stable ID](crate::identity) of the worker serving this instance, if it has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub worker_id: Option<crate::identity::WorkerId>,
+    /// Whether routers should send it new requests. Registrations from before this field
+    /// existed are active.
+    #[serde(default, skip_serializing_if = "InstanceStatus::is_active")]
+    pub status: InstanceStatus,
+    /// The [federated](crate::discovery::Federation) region the instance was discovered in.
+    /// None for instances registered in this region's own etcd, which routers prefer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    /// How to serialize the requests sent to it. JSON if the worker is newer and uses a codec
+    /// this build doesn't know.
+    #[serde(
+        default,
+        skip_serializing_if = "PayloadCodec::is_json",
+        deserialize_with = "PayloadCodec::deserialize_known"
+    )]
+    pub codec: PayloadCodec,
+    /// The worker's, for routers that verify registrations. See [`signing`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<InstanceSignature>,
+}
+
+/// Set by the worker with [`Endpoint::set_status`], for rolling upgrades
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum InstanceStatus {
+    #[default]
+    Active,
+    /// Finishing the requests it has but taking no new ones, before shutting down
+    Draining,
+    /// Still serving, but only routed to when no instance is active. For the old version
+    /// while the new one comes up.
+    Deprecated,
+}
+
+impl InstanceStatus {
+    pub fn is_active(&self) -> bool {
+        *self == InstanceStatus::Active
+    }
 }
 
 impl Instance {
@@ -115,6 +176,23 @@ impl Instance {
     }
 }
 
+/// An active instance of `ns/backend/generate`, for tests to change what they need of
+#[cfg(test)]
+pub(crate) fn test_instance(instance_id: u64) -> Instance {
+    Instance {
+        component: "backend".to_string(),
+        endpoint: "generate".to_string(),
+        namespace: "ns".to_string(),
+        instance_id,
+        transport: TransportType::NatsTcp(format!("ns.backend.generate-{instance_id:x}")),
+        worker_id: None,
+        status: InstanceStatus::Active,
+        region: None,
+        codec: Default::default(),
+        signature: None,
+    }
+}
+
 impl fmt::Display for Instance {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
@@ -247,6 +325,13 @@ impl Component {
         Slug::slugify(&service_name).to_string()
     }
 
+    /// What the NATS subjects of the component's endpoints start with: the service name, after
+    /// the namespace's subject prefix if it has one
+    pub fn subject_root(&self) -> String {
+        let prefix = self.drt().nats_subject_prefix(&self.namespace.name());
+        format!("{prefix}{}", self.service_name())
+    }
+
     pub fn path(&self) -> String {
         format!("{}/{}", self.namespace.name(), self.name)
     }
@@ -286,7 +371,7 @@ impl Component {
         let entries = bucket.entries().await?;
         let mut instances = Vec::with_capacity(entries.len());
         for (name, bytes) in entries.into_iter() {
-            let val = match serde_json::from_slice::<Instance>(&bytes) {
+            let val = match encoding::decode::<Instance>(&bytes) {
                 Ok(val) => val,
                 Err(err) => {
                     anyhow::bail!("Error converting storage response to Instance: {err}. {name}",);
@@ -303,7 +388,7 @@ impl Component {
     pub async fn scrape_stats(&self, timeout: Duration) -> Result<ServiceSet> {
         // Debug: scraping stats for component
         let service_name = self.service_name();
-        let Some(service_client) = self.drt().service_client() else {
+        let Some(service_client) = self.drt().service_client(&self.namespace.name()) else {
             anyhow::bail!("ServiceSet is gathered via NATS, do not call this in non-NATS setups.");
         };
         service_client
@@ -311,6 +396,41 @@ impl Component {
             .await
     }
 
+    /// [`Component::scrape_stats`], shared by every caller in this process and refreshed in the
+    /// background while they keep reading it. See [`StatsCacheConfig`] for how old it may be.
+    pub async fn stats_cached(&self) -> Result<Arc<ComponentStats>> {
+        self.stats_cache().await.get(self).await
+    }
+
+    /// [`Component::stats_cached`], changing the config of this component's cache for all its
+    /// callers
+    pub async fn stats_cached_with(&self, config: StatsCacheConfig) -> Result<Arc<ComponentStats>> {
+        let cache = self.stats_cache().await;
+        cache.set_config(config);
+        cache.get(self).await
+    }
+
+    /// Where to create the metrics of the application serving this component, to have them in
+    /// its service stats as well as on `/metrics`
+    pub async fn stats_registry(&self) -> StatsRegistry {
+        let mut guard = self.drt.component_registry.inner.lock().await;
+        let stats = guard
+            .registered_stats
+            .entry(self.service_name())
+            .or_default()
+            .clone();
+        StatsRegistry::new(self.clone(), stats)
+    }
+
+    async fn stats_cache(&self) -> Arc<stats_cache::StatsCache> {
+        let mut guard = self.drt.component_registry.inner.lock().await;
+        guard
+            .stats_caches
+            .entry(self.service_name())
+            .or_insert_with(|| Arc::new(stats_cache::StatsCache::new()))
+            .clone()
+    }
+
     /// Add Prometheus metrics for this component's NATS service stats.
     ///
     /// Starts a background task that periodically requests service statistics from NATS
@@ -330,35 +450,41 @@ impl Component {
         let m = component_metrics.clone();
         let c = component_clone.clone();
 
-        // Use the DRT's runtime handle to spawn the background task.
-        // We cannot use regular `tokio::spawn` here because:
+        // Use the runtime's supervisor to spawn the background task, which spawns it on the
+        // DRT's runtime handle. We cannot use regular `tokio::spawn` here because:
         // 1. This method may be called from contexts without an active Tokio runtime
         //    (e.g., tests that create a DRT in a blocking context)
         // 2. Tests often create a temporary runtime just to build the DRT, then drop it
         // 3. `tokio::spawn` requires being called from within a runtime context
         // By using the DRT's own runtime handle, we ensure the task runs in the
         // correct runtime that will persist for the lifetime of the component.
-        c.drt().runtime().secondary().spawn(async move {
-            let timeout = std::time::Duration::from_millis(500);
-            let mut interval = tokio::time::interval(MAX_WAIT_MS);
-            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
-
-            loop {
-                match c.scrape_stats(timeout).await {
-                    Ok(service_set) => {
-                        m.update_from_service_set(&service_set);
+        let supervisor = c.drt().runtime().supervisor().child("stats");
+        let name = format!("scrape {}", c.service_name());
+        supervisor.spawn(name, RestartPolicy::default(), move || {
+            let c = c.clone();
+            let m = m.clone();
+            async move {
+                let timeout = std::time::Duration::from_millis(500);
+                let mut interval = tokio::time::interval(MAX_WAIT_MS);
+                interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+                loop {
+                    match c.scrape_stats(timeout).await {
+                        Ok(service_set) => {
+                            m.update_from_service_set(&service_set);
+                        }
+                        Err(err) => {
+                            tracing::error!(
+                                "Background scrape failed for {}: {}",
+                                c.service_name(),
+                                err
+                            );
+                            m.reset_to_zeros();
+                        }
                     }
-                    Err(err) => {
-                        tracing::error!(
-                            "Background scrape failed for {}: {}",
-                            c.service_name(),
-                            err
-                        );
-                        m.reset_to_zeros();
-                    }
-                }
 
-                interval.tick().await;
+                    interval.tick().await;
+                }
             }
         });
 
@@ -391,12 +517,26 @@ impl Component {
             anyhow::bail!("Service {service_name} already exists");
         }
 
-        let Some(nats_client) = self.drt.nats_client() else {
+        if self.drt.in_process_network().is_some() {
+            // In-process endpoints serve straight off the cluster's network, no service needed
+            return Ok(());
+        }
+        let Some(nats_client) = self.drt.nats_client_for(&self.namespace.name()) else {
             anyhow::bail!("Cannot create NATS service without NATS.");
         };
         let description = None;
+        let registered_stats = self
+            .drt
+            .component_registry
+            .inner
+            .lock()
+            .await
+            .registered_stats
+            .entry(service_name.clone())
+            .or_default()
+            .clone();
         let (nats_service, stats_reg) =
-            service::build_nats_service(nats_client, self, description).await?;
+            service::build_nats_service(nats_client, self, description, registered_stats).await?;
 
         let mut guard = self.drt.component_registry.inner.lock().await;
         if !guard.services.contains_key(&service_name) {
@@ -544,6 +684,16 @@ impl Endpoint {
         format!("{INSTANCE_ROOT_PATH}/{}", self.unique_path(lease_id))
     }
 
+    /// Where an instance is registered in etcd, by each key layout written: the one routers
+    /// read first, then any a [migration](crate::storage::layout) writes as well
+    pub fn etcd_paths_with_lease_id(&self, marker: &LayoutMarker, lease_id: u64) -> Vec<String> {
+        let ns = self.component.namespace().name();
+        marker
+            .writes()
+            .map(|layout| layout.instance_path(&ns, &self.component.name, &self.name, lease_id))
+            .collect()
+    }
+
     /// Full path of this endpoint with forward slash separators, including lease id
     pub fn unique_path(&self, lease_id: u64) -> String {
         let ns = self.component.namespace().name();
@@ -576,14 +726,14 @@ impl Endpoint {
     }
 
     pub fn subject(&self) -> String {
-        format!("{}.{}", self.component.service_name(), self.name)
+        format!("{}.{}", self.component.subject_root(), self.name)
     }
 
     /// Subject to an instance of the [Endpoint] with a specific lease id
     pub fn subject_to(&self, lease_id: u64) -> String {
         format!(
             "{}.{}",
-            self.component.service_name(),
+            self.component.subject_root(),
             self.name_with_id(lease_id)
         )
     }
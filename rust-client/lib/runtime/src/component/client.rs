@@ -11,7 +11,9 @@ use std::sync::Arc;
 use tokio::net::unix::pipe::Receiver;
 
 use crate::{
+    discovery::KubernetesDiscovery,
     pipeline::async_trait,
+    storage::key_value_store::WatchEvent as StoreWatchEvent,
     transports::etcd::{Client as EtcdClient, WatchEvent},
 };
 
@@ -42,10 +44,12 @@ pub struct Client {
     pub endpoint: Endpoint,
     // These are the remotes I know about from watching etcd
     pub instance_source: Arc<InstanceSource>,
-    // These are the instance source ids less those reported as down from sending rpc
+    // These are the routable instance source ids less those reported as down from sending rpc
     instance_avail: Arc<ArcSwap<Vec<u64>>>,
     // These are the instance source ids less those reported as busy (above threshold)
     instance_free: Arc<ArcSwap<Vec<u64>>>,
+    // Routed to until the instance source finds instances, see `restore_instances`
+    restored: Arc<parking_lot::Mutex<Option<Arc<Vec<Instance>>>>>,
 }
 
 #[derive(Clone, Debug)]
@@ -62,6 +66,7 @@ impl Client {
             instance_source: Arc::new(InstanceSource::Static),
             instance_avail: Arc::new(ArcSwap::from(Arc::new(vec![]))),
             instance_free: Arc::new(ArcSwap::from(Arc::new(vec![]))),
+            restored: Default::default(),
         })
     }
 
@@ -70,18 +75,28 @@ impl Client {
         const INSTANCE_REFRESH_PERIOD: Duration = Duration::from_secs(1);
 
         // create live endpoint watcher
-        let Some(etcd_client) = &endpoint.component.drt.etcd_client else {
-            anyhow::bail!("Attempt to create a dynamic client on a static endpoint");
+        let instance_source = if let Some(kubernetes) = endpoint.component.drt.kubernetes() {
+            Self::get_or_create_kubernetes_instance_source(kubernetes, &endpoint).await?
+        } else if endpoint.component.drt.store_instance_id().is_some() {
+            Self::get_or_create_store_instance_source(&endpoint).await?
+        } else {
+            let Some(etcd_client) = endpoint.component.drt.etcd_client() else {
+                if endpoint.component.drt.offline().is_some() {
+                    anyhow::bail!(
+                        "Not connected to etcd yet, wait for `DistributedRuntime::offline` to connect"
+                    );
+                }
+                anyhow::bail!("Attempt to create a dynamic client on a static endpoint");
+            };
+            Self::get_or_create_dynamic_instance_source(&etcd_client, &endpoint).await?
         };
 
-        let instance_source =
-            Self::get_or_create_dynamic_instance_source(etcd_client, &endpoint).await?;
-
         let client = Client {
             endpoint,
             instance_source: instance_source.clone(),
             instance_avail: Arc::new(ArcSwap::from(Arc::new(vec![]))),
             instance_free: Arc::new(ArcSwap::from(Arc::new(vec![]))),
+            restored: Default::default(),
         };
         client.monitor_instance_source();
         Ok(client)
@@ -104,6 +119,72 @@ impl Client {
         }
     }
 
+    /// The codec instance `instance_id` takes requests in, JSON if it isn't known
+    pub fn codec_of(&self, instance_id: u64) -> PayloadCodec {
+        let InstanceSource::Dynamic(watch_rx) = self.instance_source.as_ref() else {
+            return PayloadCodec::default();
+        };
+        let codec = |instances: &[Instance]| {
+            instances
+                .iter()
+                .find(|instance| instance.id() == instance_id)
+                .map(|instance| instance.codec)
+        };
+        if let Some(codec) = codec(&watch_rx.borrow()) {
+            return codec;
+        }
+        let restored = self.restored.lock();
+        restored
+            .as_deref()
+            .and_then(|restored| codec(restored))
+            .unwrap_or_default()
+    }
+
+    /// Route to `instances`, less those `down` and with those `busy` not free, until the
+    /// instance source finds instances of its own or `expires_in` has passed. Restarted routers
+    /// restore these from a [`RouterSnapshot`](crate::pipeline::RouterSnapshot), so they can
+    /// route in the seconds their watch takes to catch up. Instances that have gone away since
+    /// are found down by the first request sent to them.
+    ///
+    /// False, and nothing changes, if the source has found instances already.
+    pub(crate) fn restore_instances(
+        &self,
+        instances: Vec<Instance>,
+        down: &[u64],
+        busy: &[u64],
+        expires_in: Duration,
+    ) -> bool {
+        if self.is_static() || !self.instances().is_empty() {
+            return false;
+        }
+        let (avail, free) = restored_ids(&instances, down, busy);
+        let restored = Arc::new(instances);
+        *self.restored.lock() = Some(restored.clone());
+        self.instance_avail.store(Arc::new(avail));
+        self.instance_free.store(Arc::new(free));
+
+        let client = self.clone();
+        let cancel_token = self.endpoint.drt().primary_token();
+        self.endpoint.drt().runtime().secondary().spawn(async move {
+            tokio::select! {
+                _ = cancel_token.cancelled() => return,
+                _ = tokio::time::sleep(expires_in) => {}
+            }
+            let mut current = client.restored.lock();
+            if current
+                .as_ref()
+                .is_some_and(|current| Arc::ptr_eq(current, &restored))
+            {
+                current.take();
+                let instance_ids = routable_ids(&client.instances());
+                client.instance_avail.store(Arc::new(instance_ids.clone()));
+                client.instance_free.store(Arc::new(instance_ids));
+                tracing::debug!("restored instances expired");
+            }
+        });
+        true
+    }
+
     pub fn instance_ids(&self) -> Vec<u64> {
         self.instances().into_iter().map(|ep| ep.id()).collect()
     }
@@ -116,7 +197,8 @@ impl Client {
         self.instance_free.load()
     }
 
-    /// Wait for at least one Instance to be available for this Endpoint
+    /// Wait for at least one Instance to be available for this Endpoint. Existing instances
+    /// are read a page at a time, so this returns once the first page is in, not all of them.
     pub async fn wait_for_instances(&self) -> Result<Vec<Instance>> {
         let mut instances: Vec<Instance> = vec![];
         if let InstanceSource::Dynamic(mut rx) = self.instance_source.as_ref().clone() {
@@ -152,7 +234,7 @@ impl Client {
 
     /// Update the set of free instances based on busy instance IDs
     pub fn update_free_instances(&self, busy_instance_ids: &[u64]) {
-        let all_instance_ids = self.instance_ids();
+        let all_instance_ids = routable_ids(&self.instances());
         let free_ids: Vec<u64> = all_instance_ids
             .into_iter()
             .filter(|id| !busy_instance_ids.contains(id))
@@ -173,15 +255,16 @@ impl Client {
                 InstanceSource::Dynamic(rx) => rx.clone(),
             };
             while !cancel_token.is_cancelled() {
-                let instance_ids: Vec<u64> = rx
-                    .borrow_and_update()
-                    .iter()
-                    .map(|instance| instance.id())
-                    .collect();
-
-                // TODO: this resets both tracked available and free instances
-                client.instance_avail.store(Arc::new(instance_ids.clone()));
-                client.instance_free.store(Arc::new(instance_ids));
+                let instance_ids = routable_ids(&rx.borrow_and_update());
+
+                // Until the source finds instances, the restored ones are routed to
+                let restoring = instance_ids.is_empty() && client.restored.lock().is_some();
+                if !restoring {
+                    client.restored.lock().take();
+                    // TODO: this resets both tracked available and free instances
+                    client.instance_avail.store(Arc::new(instance_ids.clone()));
+                    client.instance_free.store(Arc::new(instance_ids));
+                }
 
                 tracing::debug!("instance source updated");
 
@@ -209,20 +292,41 @@ impl Client {
             }
         }
 
-        let prefix_watcher = etcd_client
-            .kv_get_and_watch_prefix(endpoint.etcd_root())
-            .await?;
+        let mut watch_rx = Self::watch_etcd_instances(etcd_client, endpoint, None).await?;
+        if let Some(federation) = drt.federation() {
+            watch_rx = federation.merge_instances(endpoint, watch_rx).await;
+        }
+
+        let instance_source = Arc::new(InstanceSource::Dynamic(watch_rx));
+        instance_sources.insert(endpoint.clone(), Arc::downgrade(&instance_source));
+        Ok(instance_source)
+    }
+
+    /// Watch the instances of `endpoint` registered in `etcd_client`, tagged with `region` when
+    /// that is a federated remote region. The watch ends, with no instances, when the etcd
+    /// watch stream does or every receiver is dropped.
+    pub(crate) async fn watch_etcd_instances(
+        etcd_client: &EtcdClient,
+        endpoint: &Endpoint,
+        region: Option<String>,
+    ) -> Result<tokio::sync::watch::Receiver<Vec<Instance>>> {
+        // In the key layout read when the watch starts, later cutovers keep writing it
+        let layout = LayoutMarker::load(etcd_client).await?.layout;
+        let namespace = endpoint.component.namespace().name();
+        let root = layout.endpoint_root(&namespace, endpoint.component.name(), &endpoint.name);
+        let prefix_watcher = etcd_client.kv_get_and_watch_prefix(root).await?;
 
         let (prefix, _watcher, mut kv_event_rx) = prefix_watcher.dissolve();
 
         let (watch_tx, watch_rx) = tokio::sync::watch::channel(vec![]);
 
-        let secondary = endpoint.component.drt.runtime.secondary().clone();
+        let control_plane = endpoint.component.drt.runtime.control_plane();
+        let verifier = endpoint.drt().instance_verifier().cloned();
 
         // this task should be included in the registry
         // currently this is created once per client, but this object/task should only be instantiated
         // once per worker/instance
-        secondary.spawn(async move {
+        control_plane.spawn(async move {
             tracing::debug!("Starting endpoint watcher for prefix: {}", prefix);
             let mut map = HashMap::new();
 
@@ -246,9 +350,15 @@ impl Client {
                 match kv_event {
                     WatchEvent::Put(kv) => {
                         let key = String::from_utf8(kv.key().to_vec());
-                        let val = serde_json::from_slice::<Instance>(kv.value());
-                        if let (Ok(key), Ok(val)) = (key, val) {
-                            map.insert(key.clone(), val);
+                        let val = encoding::decode::<Instance>(kv.value());
+                        if let (Ok(key), Ok(mut val)) = (key, val) {
+                            if let Some(err) = untrusted(verifier.as_deref(), &val) {
+                                tracing::warn!(%err, %key, "Not routing to instance");
+                                map.remove(&key);
+                            } else {
+                                val.region = region.clone();
+                                map.insert(key.clone(), val);
+                            }
                         } else {
                             tracing::error!("Unable to parse put endpoint event; shutting down endpoint watcher for prefix: {prefix}");
                             break;
@@ -265,6 +375,10 @@ impl Client {
                     }
                 }
 
+                // The rest of a page of existing instances is already here, publish them together
+                if !kv_event_rx.is_empty() {
+                    continue;
+                }
                 let instances: Vec<Instance> = map.values().cloned().collect();
 
                 if watch_tx.send(instances).is_err() {
@@ -278,8 +392,219 @@ impl Client {
             let _ = watch_tx.send(vec![]);
         });
 
+        Ok(watch_rx)
+    }
+
+    /// The ready pods of `endpoint`, one list and watch of its EndpointSlices shared by the
+    /// clients of the endpoint, as etcd's is
+    async fn get_or_create_kubernetes_instance_source(
+        kubernetes: &KubernetesDiscovery,
+        endpoint: &Endpoint,
+    ) -> Result<Arc<InstanceSource>> {
+        let drt = endpoint.drt();
+        let instance_sources = drt.instance_sources();
+        let mut instance_sources = instance_sources.lock().await;
+
+        if let Some(instance_source) = instance_sources
+            .get(endpoint)
+            .and_then(std::sync::Weak::upgrade)
+        {
+            return Ok(instance_source);
+        }
+
+        let watch_rx = kubernetes.watch_instances(endpoint).await?;
         let instance_source = Arc::new(InstanceSource::Dynamic(watch_rx));
         instance_sources.insert(endpoint.clone(), Arc::downgrade(&instance_source));
         Ok(instance_source)
     }
+
+    /// Instances registered in the shared store of an in-process cluster, under the same
+    /// `{namespace}/{component}/{endpoint}/{instance_id}` paths etcd uses
+    async fn get_or_create_store_instance_source(
+        endpoint: &Endpoint,
+    ) -> Result<Arc<InstanceSource>> {
+        let drt = endpoint.drt();
+        let instance_sources = drt.instance_sources();
+        let mut instance_sources = instance_sources.lock().await;
+
+        if let Some(instance_source) = instance_sources
+            .get(endpoint)
+            .and_then(std::sync::Weak::upgrade)
+        {
+            return Ok(instance_source);
+        }
+
+        let prefix = format!(
+            "{}/{}/{}/",
+            endpoint.component.namespace().name(),
+            endpoint.component.name(),
+            endpoint.name
+        );
+        let cancel_token = drt.primary_token().child_token();
+        let store = Arc::new(drt.store());
+        let mut events = store.watch(INSTANCE_ROOT_PATH, None, cancel_token.clone());
+        let verifier = drt.instance_verifier().cloned();
+
+        let (watch_tx, watch_rx) = tokio::sync::watch::channel(vec![]);
+
+        drt.runtime.spawn_control_plane(async move {
+            tracing::debug!("Starting store endpoint watcher for prefix: {prefix}");
+            let mut map = HashMap::new();
+
+            loop {
+                let event = tokio::select! {
+                    _ = watch_tx.closed() => break,
+                    event = events.recv() => match event {
+                        Some(event) => event,
+                        None => break,
+                    },
+                };
+                let changed = match event {
+                    StoreWatchEvent::Put(kv) if kv.key().starts_with(&prefix) => {
+                        match encoding::decode::<Instance>(kv.value()) {
+                            Ok(instance) => {
+                                if let Some(err) = untrusted(verifier.as_deref(), &instance) {
+                                    tracing::warn!(%err, key = kv.key(), "Not routing to instance");
+                                    map.remove(kv.key()).is_some()
+                                } else {
+                                    map.insert(kv.key().to_string(), instance);
+                                    true
+                                }
+                            }
+                            Err(err) => {
+                                tracing::error!(%err, key = kv.key(), "Unable to parse instance");
+                                false
+                            }
+                        }
+                    }
+                    StoreWatchEvent::Delete(kv) => map.remove(kv.key()).is_some(),
+                    StoreWatchEvent::Error(err) => {
+                        tracing::error!(%err, "Store endpoint watcher failed");
+                        false
+                    }
+                    // Publish the list even if it is empty, so routers know it is complete
+                    StoreWatchEvent::InitialSyncComplete => true,
+                    // Missed changes arrive as puts and deletes after a reconnect
+                    _ => false,
+                };
+                if changed && watch_tx.send(map.values().cloned().collect()).is_err() {
+                    break;
+                }
+            }
+
+            tracing::debug!("Completed store endpoint watcher for prefix: {prefix}");
+            cancel_token.cancel();
+            let _ = watch_tx.send(vec![]);
+        });
+
+        let instance_source = Arc::new(InstanceSource::Dynamic(watch_rx));
+        instance_sources.insert(endpoint.clone(), Arc::downgrade(&instance_source));
+        Ok(instance_source)
+    }
+}
+
+/// Why `instance` isn't to be routed to, if `verifier` doesn't trust it
+fn untrusted(verifier: Option<&InstanceVerifier>, instance: &Instance) -> Option<anyhow::Error> {
+    verifier.and_then(|verifier| verifier.verify(instance).err())
+}
+
+/// The instances routers may send new requests to: those in this region if any of them is
+/// routable, otherwise those in [federated](crate::discovery::Federation) remote regions, so
+/// requests fail over across regions only when the local workers are all gone. Within a region
+/// that is the active instances, or the deprecated ones if none is active. Draining instances get
+/// none.
+fn routable_ids(instances: &[Instance]) -> Vec<u64> {
+    let (local, remote): (Vec<&Instance>, Vec<&Instance>) = instances
+        .iter()
+        .partition(|instance| instance.region.is_none());
+    let local = routable_by_status(&local);
+    if local.is_empty() {
+        routable_by_status(&remote)
+    } else {
+        local
+    }
+}
+
+/// The available and the free ids of restored `instances`
+fn restored_ids(instances: &[Instance], down: &[u64], busy: &[u64]) -> (Vec<u64>, Vec<u64>) {
+    let avail: Vec<u64> = routable_ids(instances)
+        .into_iter()
+        .filter(|id| !down.contains(id))
+        .collect();
+    let free = avail
+        .iter()
+        .copied()
+        .filter(|id| !busy.contains(id))
+        .collect();
+    (avail, free)
+}
+
+fn routable_by_status(instances: &[&Instance]) -> Vec<u64> {
+    let with_status = |status: InstanceStatus| -> Vec<u64> {
+        instances
+            .iter()
+            .filter(|instance| instance.status == status)
+            .map(|instance| instance.id())
+            .collect()
+    };
+    let active = with_status(InstanceStatus::Active);
+    if active.is_empty() {
+        with_status(InstanceStatus::Deprecated)
+    } else {
+        active
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::component::test_instance;
+
+    #[test]
+    fn test_routable_ids_prefer_local_region() {
+        let remote = Instance {
+            region: Some("eu-west".to_string()),
+            ..test_instance(3)
+        };
+        let mut instances = vec![
+            test_instance(1),
+            Instance {
+                status: InstanceStatus::Deprecated,
+                ..test_instance(2)
+            },
+            remote.clone(),
+        ];
+        assert_eq!(routable_ids(&instances), vec![1]);
+
+        // A deprecated local instance is still closer than an active remote one
+        instances.remove(0);
+        assert_eq!(routable_ids(&instances), vec![2]);
+
+        instances[0].status = InstanceStatus::Draining;
+        assert_eq!(routable_ids(&instances), vec![3]);
+
+        instances.clear();
+        assert!(routable_ids(&instances).is_empty());
+        instances.push(remote);
+        assert_eq!(routable_ids(&instances), vec![3]);
+    }
+
+    #[test]
+    fn test_restored_ids() {
+        let instances = vec![
+            test_instance(1),
+            test_instance(2),
+            test_instance(3),
+            Instance {
+                status: InstanceStatus::Draining,
+                ..test_instance(4)
+            },
+        ];
+        assert_eq!(restored_ids(&instances, &[2], &[3]), (vec![1, 3], vec![1]));
+        assert_eq!(
+            restored_ids(&instances, &[], &[]),
+            (vec![1, 2, 3], vec![1, 2, 3])
+        );
+        assert_eq!(restored_ids(&[], &[1], &[2]), (vec![], vec![]));
+    }
 }
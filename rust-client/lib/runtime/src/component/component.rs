@@ -13,7 +13,11 @@ use crate::traits::events::{EventPublisher, EventSubscriber};
 #[async_trait]
 impl EventPublisher for Component {
     fn subject(&self) -> String {
-        format!("namespace.{}.component.{}", self.namespace.name, self.name)
+        let prefix = self.drt().nats_subject_prefix(&self.namespace.name());
+        format!(
+            "{prefix}namespace.{}.component.{}",
+            self.namespace.name, self.name
+        )
     }
 
     async fn publish(
@@ -31,7 +35,7 @@ impl EventPublisher for Component {
         bytes: Vec<u8>,
     ) -> Result<()> {
         let subject = format!("{}.{}", self.subject(), event_name.as_ref());
-        let Some(nats_client) = self.drt().nats_client() else {
+        let Some(nats_client) = self.drt().nats_client_for(&self.namespace.name()) else {
             anyhow::bail!("KV router's EventPublisher requires NATS");
         };
         nats_client.client().publish(subject, bytes.into()).await?;
@@ -46,7 +50,7 @@ impl EventSubscriber for Component {
         event_name: impl AsRef<str> + Send + Sync,
     ) -> Result<async_nats::Subscriber> {
         let subject = format!("{}.{}", self.subject(), event_name.as_ref());
-        let Some(nats_client) = self.drt().nats_client() else {
+        let Some(nats_client) = self.drt().nats_client_for(&self.namespace.name()) else {
             anyhow::bail!("KV router's EventSubscriber requires NATS");
         };
         Ok(nats_client.client().subscribe(subject).await?)
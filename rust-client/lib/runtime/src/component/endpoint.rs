@@ -5,6 +5,14 @@ use derive_getters::Dissolve;
 use tokio_util::sync::CancellationToken;
 
 use super::*;
+use crate::logging::sampling::TraceSampling;
+use crate::policy::Action;
+use crate::retirement;
+use crate::storage::encoding::{self, ValueEncoding};
+use crate::storage::key_value_store::{StoreError, StoreOutcome};
+use crate::storage::layout::LayoutMarker;
+use crate::transports::accounting::{self, Transport};
+use crate::transports::etcd;
 
 pub use async_nats::service::endpoint::Stats as EndpointStats;
 
@@ -44,6 +52,17 @@ pub struct EndpointConfig {
     #[educe(Debug(ignore))]
     #[builder(default, setter(into, strip_option))]
     health_check_payload: Option<serde_json::Value>,
+
+    /// Replace a registration of the same instance made by another process, instead of failing
+    /// with [`AlreadyRegistered`](crate::transports::etcd::AlreadyRegistered). That process
+    /// loses its lease, so it stops serving.
+    #[builder(default)]
+    takeover: bool,
+
+    /// The codec routers should serialize requests with, see [`PayloadCodec`]. Requests in any
+    /// codec are served regardless.
+    #[builder(default)]
+    codec: PayloadCodec,
 }
 
 impl EndpointConfigBuilder {
@@ -67,9 +86,14 @@ impl EndpointConfigBuilder {
             metrics_labels,
             graceful_shutdown,
             health_check_payload,
+            takeover,
+            codec,
         ) = self.build_internal()?.dissolve();
         let lease = lease.or(endpoint.drt().primary_lease());
-        let lease_id = lease.as_ref().map(|l| l.id()).unwrap_or(0);
+        let lease_id = endpoint.instance_id(lease.as_ref());
+        let in_process = endpoint.drt().in_process_network().cloned();
+        endpoint.check_policy(Action::Register)?;
+        endpoint.check_not_retired().await?;
 
         tracing::debug!(
             "Starting endpoint: {}",
@@ -79,7 +103,8 @@ impl EndpointConfigBuilder {
         let service_name = endpoint.component.service_name();
 
         // acquire the registry lock
-        let registry = endpoint.drt().component_registry.inner.lock().await;
+        let mut registry = endpoint.drt().component_registry.inner.lock().await;
+        registry.codecs.insert(endpoint.subject(), codec);
 
         let metrics_labels: Option<Vec<(&str, &str)>> = metrics_labels
             .as_ref()
@@ -87,34 +112,42 @@ impl EndpointConfigBuilder {
         // Add metrics to the handler. The endpoint provides additional information to the handler.
         handler.add_metrics(&endpoint, metrics_labels.as_deref())?;
 
-        // get the group
-        let group = registry
-            .services
-            .get(&service_name)
-            .map(|service| service.group(endpoint.component.service_name()))
-            .ok_or(error!("Service not found"))?;
-
-        // get the stats handler map
-        let handler_map = registry
-            .stats_handlers
-            .get(&service_name)
-            .cloned()
-            .expect("no stats handler registry; this is unexpected");
-
-        drop(registry);
-
-        // insert the stats handler
-        if let Some(stats_handler) = stats_handler {
-            handler_map
-                .lock()
-                .insert(endpoint.subject_to(lease_id), stats_handler);
-        }
+        let request_source = if let Some(network) = &in_process {
+            // In-process workers have no NATS service to add the endpoint or stats handler to
+            drop(registry);
+            RequestSource::InProcess(network.serve(endpoint.subject_to(lease_id)))
+        } else {
+            // get the group
+            let group = registry
+                .services
+                .get(&service_name)
+                .map(|service| service.group(endpoint.component.subject_root()))
+                .ok_or(error!("Service not found"))?;
+
+            // get the stats handler map
+            let handler_map = registry
+                .stats_handlers
+                .get(&service_name)
+                .cloned()
+                .expect("no stats handler registry; this is unexpected");
+
+            drop(registry);
+
+            // insert the stats handler
+            if let Some(stats_handler) = stats_handler {
+                handler_map
+                    .lock()
+                    .insert(endpoint.subject_to(lease_id), stats_handler);
+            }
 
-        // creates an endpoint for the service
-        let service_endpoint = group
-            .endpoint(&endpoint.name_with_id(lease_id))
-            .await
-            .map_err(|e| anyhow::anyhow!("Failed to start endpoint: {e}"))?;
+            // creates an endpoint for the service
+            let service_endpoint = group
+                .endpoint(&endpoint.name_with_id(lease_id))
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to start endpoint: {e}"))?;
+
+            RequestSource::Nats(Box::new(service_endpoint))
+        };
 
         // Create a token that responds to both runtime shutdown and lease expiration
         let runtime_shutdown_token = endpoint.drt().child_token();
@@ -126,17 +159,12 @@ impl EndpointConfigBuilder {
         let system_health = endpoint.drt().system_health.clone();
         let subject = endpoint.subject_to(lease_id);
         let etcd_path = endpoint.etcd_path_with_lease_id(lease_id);
-        let etcd_client = endpoint.component.drt.etcd_client.clone();
+        let etcd_client = endpoint.component.drt.etcd_client();
+        let offline = endpoint.component.drt.offline.clone();
 
         // Register health check target in SystemHealth if provided
         if let Some(health_check_payload) = &health_check_payload {
-            let instance = Instance {
-                component: component_name.clone(),
-                endpoint: endpoint_name.clone(),
-                namespace: namespace_name.clone(),
-                instance_id: lease_id,
-                transport: TransportType::NatsTcp(subject.clone()),
-            };
+            let instance = endpoint.instance(lease_id, InstanceStatus::Active, codec)?;
             tracing::debug!(endpoint_name = %endpoint_name, "Registering endpoint health check target");
             let guard = system_health.lock();
             guard.register_health_check_target(
@@ -154,8 +182,8 @@ impl EndpointConfigBuilder {
             let combined_token = CancellationToken::new();
             let combined_for_select = combined_token.clone();
             let lease_token = lease.child_token();
-            // Use secondary runtime for this lightweight monitoring task
-            endpoint.drt().runtime().secondary().spawn(async move {
+            // Losing the lease must stop the endpoint even while requests saturate the primary
+            endpoint.drt().runtime().spawn_control_plane(async move {
                 tokio::select! {
                     _ = lease_token.cancelled() => {
                         tracing::trace!("Lease cancelled, triggering endpoint shutdown");
@@ -184,10 +212,21 @@ impl EndpointConfigBuilder {
             tracing::debug!("Endpoint '{}' has graceful_shutdown=false", endpoint.name);
         }
 
+        let sampling = TraceSampling::watch(
+            &endpoint.drt().store(),
+            format!("{namespace_name}/{component_name}/{endpoint_name}"),
+            cancel_token.clone(),
+        );
         let push_endpoint = PushEndpoint::builder()
             .service_handler(handler)
             .cancellation_token(cancel_token.clone())
             .graceful_shutdown(graceful_shutdown)
+            .meter(
+                in_process
+                    .is_none()
+                    .then(|| accounting::meter(Transport::Nats, &endpoint.subject())),
+            )
+            .sampling(sampling)
             .build()
             .map_err(|e| anyhow::anyhow!("Failed to build push endpoint: {e}"))?;
 
@@ -206,7 +245,7 @@ impl EndpointConfigBuilder {
         let task = tokio::spawn(async move {
             let result = push_endpoint
                 .start(
-                    service_endpoint,
+                    request_source,
                     namespace_name_for_task,
                     component_name_for_task,
                     endpoint_name_for_task,
@@ -227,19 +266,69 @@ impl EndpointConfigBuilder {
         // make the components service endpoint discovery in etcd
 
         // client.register_service()
-        let info = Instance {
-            component: component_name.clone(),
-            endpoint: endpoint_name.clone(),
-            namespace: namespace_name.clone(),
-            instance_id: lease_id,
-            transport: TransportType::NatsTcp(subject),
-        };
+        let instance = endpoint.instance(lease_id, InstanceStatus::Active, codec)?;
+
+        if endpoint.drt().store_instance_id().is_some() {
+            // Discovery goes through the store instead of etcd
+            let store = endpoint.drt().store();
+            let bucket = store.get_or_create_bucket(INSTANCE_ROOT_PATH, None).await?;
+            let key = Key::from_raw(endpoint.unique_path(lease_id));
+            let encoding = ValueEncoding::negotiate(&store, &[INSTANCE_ROOT_PATH]).await?;
+            let info = encoding::encode_text(&instance, encoding)?;
+            let info = info.as_str();
+            if let StoreOutcome::Exists(_) = bucket.insert(&key, info, 0).await? {
+                if !takeover {
+                    cancel_token.cancel();
+                    if let Some(network) = &in_process {
+                        network.stop_serving(&subject);
+                    }
+                    return Err(etcd::AlreadyRegistered {
+                        key: key.to_string(),
+                        holder: lease_id,
+                    }
+                    .into());
+                }
+                bucket.delete(&key).await?;
+                bucket.insert(&key, info, 0).await?;
+            }
+            let result = task.await?;
+            // A disconnected worker keeps its registration, like a lease that has not expired yet
+            if cancel_token.is_cancelled() {
+                if let Some(network) = &in_process {
+                    network.stop_serving(&subject);
+                }
+                bucket.delete(&key).await?;
+            }
+            return result;
+        }
 
-        let info = serde_json::to_vec_pretty(&info)?;
+        // One path per key layout written, the one routers read first. Registered later, once
+        // etcd is reachable, it is JSON, which every bucket version reads.
+        let (etcd_paths, info) = match &etcd_client {
+            Some(client) => match endpoint.etcd_registration(client, &instance).await {
+                Ok(registration) => registration,
+                Err(e) => {
+                    cancel_token.cancel();
+                    return Err(e);
+                }
+            },
+            None => (
+                vec![etcd_path],
+                encoding::encode(&instance, ValueEncoding::Json)?,
+            ),
+        };
+        let (etcd_path, mirror_paths) = etcd_paths
+            .split_first()
+            .expect("a key layout is always written");
 
-        if let Some(etcd_client) = &etcd_client
+        if etcd_client.is_none()
+            && let Some(offline) = offline
+        {
+            // Started without etcd, it is registered once etcd is reachable
+            offline.register(etcd_path.clone(), info.clone()).await?;
+        } else if let Some(etcd_client) = &etcd_client
             && let Err(e) = etcd_client
-                .kv_create(&etcd_path, info, Some(lease_id))
+                .kv_register(etcd_path, info.clone(), Some(lease_id), takeover)
                 .await
         {
             tracing::error!(
@@ -249,12 +338,208 @@ impl EndpointConfigBuilder {
                 "Unable to register service for discovery"
             );
             cancel_token.cancel();
+            if e.is::<etcd::AlreadyRegistered>() {
+                return Err(e);
+            }
             return Err(error!(
                 "Unable to register service for discovery. Check discovery service status"
             ));
         }
+        if let Some(etcd_client) = &etcd_client {
+            // A copy that failed is made by the migration's verify phase
+            for path in mirror_paths {
+                if let Err(err) = etcd_client.kv_put(path, &info, Some(lease_id)).await {
+                    tracing::warn!(%err, %path, "Unable to register in the other key layout");
+                }
+            }
+            for path in &etcd_paths {
+                let token = cancel_token.clone();
+                restore_on_resume(etcd_client, path.clone(), info.clone(), lease_id, token);
+            }
+        }
         task.await??;
 
+        // A shared lease stays alive for the other runtimes using it, so it cannot clean up
+        // after us
+        if cancel_token.is_cancelled()
+            && let Some(etcd_client) = etcd_client.filter(|c| c.is_shared())
+        {
+            for etcd_path in &etcd_paths {
+                if let Err(err) = etcd_client.kv_delete(etcd_path.as_str(), None).await {
+                    tracing::warn!(%err, %etcd_path, "Unable to deregister endpoint");
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Create the registration at `etcd_path` again if it went missing while the process was
+/// suspended, until `cancel_token` is cancelled. Its lease survived, or the runtime would be
+/// shutting down.
+fn restore_on_resume(
+    client: &etcd::Client,
+    etcd_path: String,
+    info: Vec<u8>,
+    lease_id: u64,
+    cancel_token: CancellationToken,
+) {
+    let client = client.clone();
+    let mut events = client.watchdog_events();
+    tokio::spawn(async move {
+        loop {
+            let event = tokio::select! {
+                _ = cancel_token.cancelled() => return,
+                event = events.recv() => event,
+            };
+            match event {
+                Ok(etcd::WatchdogEvent::Resumed {
+                    lease_id: resumed, ..
+                }) if resumed == lease_id => {}
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => return,
+                _ => continue,
+            }
+            // Fails if the key is still there, as it usually is
+            if client
+                .kv_create(&etcd_path, info.clone(), Some(lease_id))
+                .await
+                .is_ok()
+            {
+                tracing::warn!(%etcd_path, "Registered endpoint again after a suspension");
+            }
+        }
+    });
+}
+
+impl Endpoint {
+    /// The ID this worker's instance of the endpoint serves and registers under. Without etcd
+    /// there is no lease; under Kubernetes discovery clients address us by pod.
+    pub(crate) fn instance_id(&self, lease: Option<&Lease>) -> u64 {
+        lease
+            .map(|l| l.id())
+            .or_else(|| self.drt().kubernetes().and_then(|k| k.instance_id()))
+            .or_else(|| self.drt().offline().map(|o| o.instance_id()))
+            .or_else(|| self.drt().store_instance_id())
+            .unwrap_or(0)
+    }
+
+    /// Fails if the [access policy](crate::policy) is enforced and doesn't allow `action` on
+    /// this endpoint
+    pub(crate) fn check_policy(&self, action: Action) -> Result<()> {
+        let Some(policy) = self.drt().policy() else {
+            return Ok(());
+        };
+        let namespace = self.component.namespace.name();
+        policy.check(action, &namespace, &self.component.name, &self.name)?;
         Ok(())
     }
+
+    /// Fails if the endpoint's namespace was [retired](crate::retirement), or is being
+    pub(crate) async fn check_not_retired(&self) -> Result<()> {
+        let namespace = self.component.namespace.name();
+        if let Some(tombstone) = retirement::tombstone(&self.drt().store(), &namespace).await? {
+            return Err(error!(
+                "Namespace {} was retired by '{}'",
+                tombstone.namespace, tombstone.retired_by
+            ));
+        }
+        Ok(())
+    }
+
+    /// The discovery record of this worker's instance of the endpoint, signed if the worker
+    /// has a [signing key](super::signing)
+    pub(crate) fn instance(
+        &self,
+        instance_id: u64,
+        status: InstanceStatus,
+        codec: PayloadCodec,
+    ) -> Result<Instance> {
+        let mut instance = Instance {
+            component: self.component.name.clone(),
+            endpoint: self.name.clone(),
+            namespace: self.component.namespace.name.clone(),
+            instance_id,
+            transport: TransportType::NatsTcp(self.subject_to(instance_id)),
+            worker_id: crate::identity::worker_id(),
+            status,
+            region: None,
+            codec,
+            signature: None,
+        };
+        if let Some(signer) = self.drt().instance_signer() {
+            signer.sign(&mut instance)?;
+        }
+        Ok(instance)
+    }
+
+    /// Change the status advertised for this worker's instance of the endpoint, which must be
+    /// serving under the primary lease. Routers send a [draining](InstanceStatus::Draining)
+    /// instance no new requests, but the requests it already has carry on.
+    pub async fn set_status(&self, status: InstanceStatus) -> Result<()> {
+        let instance_id = self.instance_id(self.drt().primary_lease().as_ref());
+        let codec = self
+            .drt()
+            .component_registry
+            .inner
+            .lock()
+            .await
+            .codecs
+            .get(&self.subject())
+            .copied()
+            .unwrap_or_default();
+        let instance = self.instance(instance_id, status, codec)?;
+
+        if self.drt().store_instance_id().is_some() {
+            let store = self.drt().store();
+            let bucket = store.get_or_create_bucket(INSTANCE_ROOT_PATH, None).await?;
+            let key = Key::from_raw(self.unique_path(instance_id));
+            let encoding = ValueEncoding::negotiate(&store, &[INSTANCE_ROOT_PATH]).await?;
+            let info = encoding::encode_text(&instance, encoding)?;
+            return match bucket.update_existing(&key, &info).await {
+                Ok(_) => Ok(()),
+                Err(StoreError::MissingKey(_)) => Err(error!("{key} is not registered")),
+                Err(err) => Err(err.into()),
+            };
+        }
+
+        let etcd_path = self.etcd_path_with_lease_id(instance_id);
+        let etcd_client = self
+            .drt()
+            .etcd_client()
+            .or_else(|| self.drt().offline().and_then(|o| o.client()));
+        let Some(etcd_client) = etcd_client else {
+            return Err(error!("{etcd_path} is not registered in etcd yet"));
+        };
+        let (etcd_paths, info) = self.etcd_registration(&etcd_client, &instance).await?;
+        let (etcd_path, mirror_paths) = etcd_paths
+            .split_first()
+            .expect("a key layout is always written");
+        if !etcd_client.kv_update(etcd_path, info.clone()).await? {
+            return Err(error!("{etcd_path} is not registered"));
+        }
+        // Not copied yet is fine, the copy is made from the path above
+        for path in mirror_paths {
+            etcd_client.kv_update(path, info.clone()).await?;
+        }
+        tracing::info!(%etcd_path, ?status, "Instance status changed");
+        Ok(())
+    }
+
+    /// Where `instance` is registered in etcd, by each key layout written, and its record in the
+    /// encoding all of their buckets allow
+    async fn etcd_registration(
+        &self,
+        client: &etcd::Client,
+        instance: &Instance,
+    ) -> Result<(Vec<String>, Vec<u8>)> {
+        let marker = LayoutMarker::load(client).await?;
+        let buckets: Vec<_> = marker
+            .writes()
+            .map(|layout| layout.instance_bucket())
+            .collect();
+        let encoding = ValueEncoding::negotiate_etcd(client, &buckets).await?;
+        let paths = self.etcd_paths_with_lease_id(&marker, instance.instance_id);
+        Ok((paths, encoding::encode(instance, encoding)?))
+    }
 }
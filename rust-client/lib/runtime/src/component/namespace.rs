@@ -13,7 +13,8 @@ use crate::traits::events::{EventPublisher, EventSubscriber};
 #[async_trait]
 impl EventPublisher for Namespace {
     fn subject(&self) -> String {
-        format!("namespace.{}", self.name)
+        let prefix = self.drt().nats_subject_prefix(&self.name());
+        format!("{prefix}namespace.{}", self.name)
     }
 
     async fn publish(
@@ -31,7 +32,7 @@ impl EventPublisher for Namespace {
         bytes: Vec<u8>,
     ) -> Result<()> {
         let subject = format!("{}.{}", self.subject(), event_name.as_ref());
-        let Some(nats_client) = self.drt().nats_client() else {
+        let Some(nats_client) = self.drt().nats_client_for(&self.name()) else {
             anyhow::bail!("KV router's Namespace EventPublisher requires NATS");
         };
         nats_client.client().publish(subject, bytes.into()).await?;
@@ -46,7 +47,7 @@ impl EventSubscriber for Namespace {
         event_name: impl AsRef<str> + Send + Sync,
     ) -> Result<async_nats::Subscriber> {
         let subject = format!("{}.{}", self.subject(), event_name.as_ref());
-        let Some(nats_client) = self.drt().nats_client() else {
+        let Some(nats_client) = self.drt().nats_client_for(&self.name()) else {
             anyhow::bail!("KV router's Namespace EventSubscriber requires NATS");
         };
         Ok(nats_client.client().subscribe(subject).await?)
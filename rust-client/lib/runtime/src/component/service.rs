@@ -11,6 +11,8 @@ use std::sync::Arc;
 
 use crate::component::Component;
 
+use super::stats_registry::RegisteredStats;
+
 pub use super::endpoint::EndpointStats;
 
 type StatsHandlerRegistry = Arc<Mutex<HashMap<String, EndpointStatsHandler>>>;
@@ -22,10 +24,11 @@ pub type EndpointStatsHandler =
 pub const PROJECT_NAME: &str = "Dynamo";
 const SERVICE_VERSION: &str = env!("CARGO_PKG_VERSION");
 
-pub async fn build_nats_service(
+pub(crate) async fn build_nats_service(
     nats_client: &crate::transports::nats::Client,
     component: &Component,
     description: Option<String>,
+    registered_stats: RegisteredStats,
 ) -> anyhow::Result<(NatsService, StatsHandlerRegistry)> {
     let service_name = component.service_name();
     tracing::trace!("component: {component}; creating, service_name: {service_name}");
@@ -46,10 +49,11 @@ pub async fn build_nats_service(
             .stats_handler(move |name, stats| {
                 tracing::trace!("stats_handler: {name}, {stats:?}");
                 let mut guard = stats_handler_registry.lock();
-                match guard.get_mut(&name) {
+                let data = match guard.get_mut(&name) {
                     Some(handler) => handler(stats),
                     None => serde_json::Value::Null,
-                }
+                };
+                registered_stats.add_to(data)
             });
     let nats_service = nats_service_builder
         .start(service_name, SERVICE_VERSION.to_string())
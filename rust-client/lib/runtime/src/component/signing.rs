@@ -0,0 +1,225 @@
+// SPDX-FileCopyrightText: Copyright (c) 2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Signed instance registrations.
+//!
+//! Whoever can write to etcd can register an instance under any component, and routers would
+//! send it that component's requests. A worker whose [secrets provider](crate::secrets) has an
+//! `INSTANCE_SIGNING_KEY`, the base64 of a 32 byte Ed25519 seed (`openssl rand -base64 32`),
+//! signs every [`Instance`] it registers and logs the public key at startup. Routers started
+//! with `DYN_VERIFY_INSTANCES` route only to the instances signed by a key trusted for their
+//! `{namespace}/{component}`, as listed by the secret `INSTANCE_TRUSTED_KEYS`:
+//!
+//! ```text
+//! {"dynamo/backend": ["<public key>"], "*": ["<a key trusted for every component>"]}
+//! ```
+//!
+//! The other registrations are left out and logged. With a key per component, a compromised
+//! pod can register more instances of its own component, but not pass for another's workers.
+
+use std::collections::HashMap;
+
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+
+use crate::secrets::SecretsProvider;
+use crate::{Result, error};
+
+use super::Instance;
+
+/// The secret holding the base64 seed of the worker's signing key
+pub const SIGNING_KEY_SECRET: &str = "INSTANCE_SIGNING_KEY";
+
+/// The secret holding the keys routers trust, by component
+pub const TRUSTED_KEYS_SECRET: &str = "INSTANCE_TRUSTED_KEYS";
+
+/// Trusted keys under this name are trusted for every component
+pub const ANY_COMPONENT: &str = "*";
+
+/// Keeps a signature over a registration from verifying over anything else
+const SIGNATURE_CONTEXT: &[u8] = b"dynamo instance registration v1\n";
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct InstanceSignature {
+    /// The signer's public key, base64
+    pub key: String,
+    /// Ed25519, base64
+    pub signature: String,
+}
+
+/// What is signed: the instance without its signature, or the region routers tag it with
+fn signed_bytes(instance: &Instance) -> Result<Vec<u8>> {
+    let unsigned = Instance {
+        signature: None,
+        region: None,
+        ..instance.clone()
+    };
+    let mut bytes = SIGNATURE_CONTEXT.to_vec();
+    serde_json::to_writer(&mut bytes, &unsigned)?;
+    Ok(bytes)
+}
+
+fn decode_key(key: &str) -> Result<VerifyingKey> {
+    let bytes: [u8; 32] = BASE64
+        .decode(key.trim())?
+        .try_into()
+        .map_err(|_| error!("Public key {key} is not 32 bytes"))?;
+    VerifyingKey::from_bytes(&bytes).map_err(|err| error!("Public key {key}: {err}"))
+}
+
+pub struct InstanceSigner {
+    key: SigningKey,
+    public_key: String,
+}
+
+impl std::fmt::Debug for InstanceSigner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "InstanceSigner({})", self.public_key)
+    }
+}
+
+impl InstanceSigner {
+    /// From the base64 of a 32 byte seed
+    pub fn from_seed(seed: &str) -> Result<Self> {
+        let seed: [u8; 32] = BASE64
+            .decode(seed.trim())?
+            .try_into()
+            .map_err(|_| error!("{SIGNING_KEY_SECRET} is not 32 bytes"))?;
+        let key = SigningKey::from_bytes(&seed);
+        let public_key = BASE64.encode(key.verifying_key().as_bytes());
+        Ok(InstanceSigner { key, public_key })
+    }
+
+    /// None if `secrets` has no signing key
+    pub async fn from_secrets(secrets: &dyn SecretsProvider) -> Result<Option<Self>> {
+        match secrets.get(SIGNING_KEY_SECRET).await? {
+            Some(seed) => InstanceSigner::from_seed(seed.expose()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Base64, as routers list it in `INSTANCE_TRUSTED_KEYS`
+    pub fn public_key(&self) -> &str {
+        &self.public_key
+    }
+
+    /// Sign `instance`, replacing any signature it had
+    pub fn sign(&self, instance: &mut Instance) -> Result<()> {
+        let signature = self.key.sign(&signed_bytes(instance)?);
+        instance.signature = Some(InstanceSignature {
+            key: self.public_key.clone(),
+            signature: BASE64.encode(signature.to_bytes()),
+        });
+        Ok(())
+    }
+}
+
+/// The keys a router trusts, by `{namespace}/{component}` or [`ANY_COMPONENT`]
+#[derive(Debug, Default)]
+pub struct InstanceVerifier {
+    trusted: HashMap<String, Vec<VerifyingKey>>,
+}
+
+impl InstanceVerifier {
+    pub fn new(trusted: HashMap<String, Vec<String>>) -> Result<Self> {
+        let trusted = trusted
+            .into_iter()
+            .map(|(component, keys)| {
+                let keys = keys
+                    .iter()
+                    .map(|key| decode_key(key))
+                    .collect::<Result<_>>()?;
+                Ok((component, keys))
+            })
+            .collect::<Result<_>>()?;
+        Ok(InstanceVerifier { trusted })
+    }
+
+    /// Fails if `secrets` has no trusted keys, as a router that should verify would otherwise
+    /// trust nothing and route nowhere
+    pub async fn from_secrets(secrets: &dyn SecretsProvider) -> Result<Self> {
+        let Some(trusted) = secrets.get(TRUSTED_KEYS_SECRET).await? else {
+            return Err(error!(
+                "DYN_VERIFY_INSTANCES is set, but there is no {TRUSTED_KEYS_SECRET} secret"
+            ));
+        };
+        let trusted = serde_json::from_str(trusted.expose())
+            .map_err(|err| error!("{TRUSTED_KEYS_SECRET} is not a map of key lists: {err}"))?;
+        InstanceVerifier::new(trusted)
+    }
+
+    /// Ok if `instance` is signed by a key trusted for its component
+    pub fn verify(&self, instance: &Instance) -> Result<()> {
+        let Some(signature) = &instance.signature else {
+            return Err(error!("Registration is not signed"));
+        };
+        let key = decode_key(&signature.key)?;
+        let component = format!("{}/{}", instance.namespace, instance.component);
+        let trusted = [component.as_str(), ANY_COMPONENT]
+            .into_iter()
+            .filter_map(|name| self.trusted.get(name))
+            .flatten()
+            .any(|trusted| *trusted == key);
+        if !trusted {
+            return Err(error!("{} is not trusted for {component}", signature.key));
+        }
+        let bytes: [u8; 64] = BASE64
+            .decode(&signature.signature)?
+            .try_into()
+            .map_err(|_| error!("Signature is not 64 bytes"))?;
+        key.verify_strict(&signed_bytes(instance)?, &Signature::from_bytes(&bytes))
+            .map_err(|_| error!("Signature does not match the registration"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::component::{TransportType, test_instance};
+
+    #[test]
+    fn test_sign_and_verify() {
+        let backend = InstanceSigner::from_seed(&BASE64.encode([1u8; 32])).unwrap();
+        let other = InstanceSigner::from_seed(&BASE64.encode([2u8; 32])).unwrap();
+        assert!(InstanceSigner::from_seed(&BASE64.encode([1u8; 16])).is_err());
+        let trusted = HashMap::from([(
+            "ns/backend".to_string(),
+            vec![backend.public_key().to_string()],
+        )]);
+        let verifier = InstanceVerifier::new(trusted).unwrap();
+
+        let mut signed = test_instance(7);
+        assert!(verifier.verify(&signed).is_err());
+        backend.sign(&mut signed).unwrap();
+        verifier.verify(&signed).unwrap();
+        // It survives the store, and the region a federated router tags it with
+        let mut stored: Instance =
+            serde_json::from_slice(&serde_json::to_vec_pretty(&signed).unwrap()).unwrap();
+        stored.region = Some("us-west".to_string());
+        verifier.verify(&stored).unwrap();
+
+        let mut tampered = signed.clone();
+        tampered.transport = TransportType::NatsTcp("attacker".to_string());
+        assert!(verifier.verify(&tampered).is_err());
+        // A key trusted for one component doesn't vouch for another
+        let mut impostor = Instance {
+            component: "prefill".to_string(),
+            ..test_instance(7)
+        };
+        backend.sign(&mut impostor).unwrap();
+        assert!(verifier.verify(&impostor).is_err());
+        let mut untrusted = test_instance(7);
+        other.sign(&mut untrusted).unwrap();
+        assert!(verifier.verify(&untrusted).is_err());
+
+        let any = HashMap::from([(
+            ANY_COMPONENT.to_string(),
+            vec![other.public_key().to_string()],
+        )]);
+        let verifier = InstanceVerifier::new(any).unwrap();
+        verifier.verify(&untrusted).unwrap();
+        assert!(InstanceVerifier::new(HashMap::from([("x".into(), vec!["bad".into()])])).is_err());
+    }
+}
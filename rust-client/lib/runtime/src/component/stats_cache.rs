@@ -0,0 +1,253 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A shared, periodically refreshed [`Component::scrape_stats`].
+//!
+//! Each scrape asks every instance of the component for its stats and waits out the timeout for
+//! the ones that are slow to answer, so a dashboard with a few panels polling every second keeps
+//! all the workers busy answering it. [`Component::stats_cached`] hands every caller in the
+//! process the same scrape, and while it is being read a background task scrapes again before
+//! the stats get older than [`StatsCacheConfig::max_staleness`]. The task stops a minute after
+//! the last read.
+//!
+//! Instances registered for the component that didn't answer in time are listed in
+//! [`ComponentStats::missing`], rather than failing the scrape.
+
+use std::collections::{BTreeSet, HashSet};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::utils::tasks::supervisor::RestartPolicy;
+use crate::{Result, service::ServiceSet, traits::DistributedRuntimeProvider};
+
+use super::{Component, Instance};
+
+/// How long the refresh task keeps scraping after the last read
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatsCacheConfig {
+    /// Stats older than this are scraped again before they are returned
+    pub max_staleness: Duration,
+    /// How long a scrape waits for the instances to answer
+    pub timeout: Duration,
+}
+
+impl StatsCacheConfig {
+    /// Often enough that a scrape started then finishes before the stats go stale
+    fn refresh_interval(&self) -> Duration {
+        self.max_staleness
+            .saturating_sub(self.timeout)
+            .max(self.timeout)
+    }
+}
+
+impl Default for StatsCacheConfig {
+    fn default() -> Self {
+        StatsCacheConfig {
+            max_staleness: Duration::from_secs(5),
+            timeout: Duration::from_millis(500),
+        }
+    }
+}
+
+/// One scrape of all the instances of a component
+#[derive(Debug, Clone)]
+pub struct ComponentStats {
+    pub services: ServiceSet,
+    /// Instances registered for the component that didn't answer within the timeout
+    pub missing: Vec<u64>,
+    pub scraped_at: Instant,
+}
+
+impl ComponentStats {
+    /// Some instances didn't answer, so totals over the services fall short
+    pub fn is_partial(&self) -> bool {
+        !self.missing.is_empty()
+    }
+
+    pub fn age(&self) -> Duration {
+        self.scraped_at.elapsed()
+    }
+}
+
+/// The stats of one component, shared through the component registry
+pub(super) struct StatsCache {
+    config: Mutex<StatsCacheConfig>,
+    latest: Mutex<Option<Arc<ComponentStats>>>,
+    last_read: Mutex<Instant>,
+    /// Held while scraping, so callers finding the stats stale wait for one scrape together
+    scraping: tokio::sync::Mutex<()>,
+    refreshing: AtomicBool,
+}
+
+impl StatsCache {
+    pub(super) fn new() -> Self {
+        StatsCache {
+            config: Mutex::new(StatsCacheConfig::default()),
+            latest: Mutex::new(None),
+            last_read: Mutex::new(Instant::now()),
+            scraping: tokio::sync::Mutex::new(()),
+            refreshing: AtomicBool::new(false),
+        }
+    }
+
+    pub(super) fn set_config(&self, config: StatsCacheConfig) {
+        *self.config.lock() = config;
+    }
+
+    pub(super) async fn get(
+        self: &Arc<Self>,
+        component: &Component,
+    ) -> Result<Arc<ComponentStats>> {
+        *self.last_read.lock() = Instant::now();
+        let max_staleness = self.config.lock().max_staleness;
+        let stats = match self.fresh(max_staleness) {
+            Some(stats) => stats,
+            None => self.refresh(component, max_staleness).await?,
+        };
+        self.keep_refreshing(component);
+        Ok(stats)
+    }
+
+    fn fresh(&self, max_staleness: Duration) -> Option<Arc<ComponentStats>> {
+        let latest = self.latest.lock();
+        latest.clone().filter(|stats| stats.age() <= max_staleness)
+    }
+
+    async fn refresh(
+        &self,
+        component: &Component,
+        max_staleness: Duration,
+    ) -> Result<Arc<ComponentStats>> {
+        let _scraping = self.scraping.lock().await;
+        // Scraped by another caller while this one waited
+        if let Some(stats) = self.fresh(max_staleness) {
+            return Ok(stats);
+        }
+        let timeout = self.config.lock().timeout;
+        let stats = Arc::new(scrape(component, timeout).await?);
+        *self.latest.lock() = Some(stats.clone());
+        Ok(stats)
+    }
+
+    /// Start the refresh task unless it is running. A read racing with the task stopping finds
+    /// it running, and the read after it scrapes itself once the stats are stale.
+    fn keep_refreshing(self: &Arc<Self>, component: &Component) {
+        if self.refreshing.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let cache = self.clone();
+        let component = component.clone();
+        // Dropped when the runtime shuts down
+        let supervisor = component.drt().runtime().supervisor().child("stats");
+        let name = format!("refresh {}", component.service_name());
+        supervisor.spawn(name, RestartPolicy::default(), move || {
+            let cache = cache.clone();
+            let component = component.clone();
+            async move {
+                cache.refresh_until_idle(&component).await;
+                Ok(())
+            }
+        });
+    }
+
+    async fn refresh_until_idle(&self, component: &Component) {
+        loop {
+            let interval = self.config.lock().refresh_interval();
+            tokio::time::sleep(interval).await;
+            if self.last_read.lock().elapsed() > IDLE_TIMEOUT {
+                break;
+            }
+            if let Err(err) = self.refresh(component, Duration::ZERO).await {
+                let service_name = component.service_name();
+                tracing::warn!(service_name, %err, "Refreshing cached stats failed");
+            }
+        }
+        self.refreshing.store(false, Ordering::Release);
+    }
+}
+
+async fn scrape(component: &Component, timeout: Duration) -> Result<ComponentStats> {
+    let services = component.scrape_stats(timeout).await?;
+    let missing = match component.list_instances().await {
+        Ok(instances) => missing_instances(&instances, &services),
+        Err(err) => {
+            let service_name = component.service_name();
+            tracing::debug!(service_name, %err, "Can't tell which instances didn't answer");
+            Vec::new()
+        }
+    };
+    Ok(ComponentStats {
+        services,
+        missing,
+        scraped_at: Instant::now(),
+    })
+}
+
+/// The ids of `instances` none of whose endpoints is in `services`
+fn missing_instances(instances: &[Instance], services: &ServiceSet) -> Vec<u64> {
+    // The subject ends in the instance id in hex. Not `EndpointInfo::id`, as ids above
+    // `i64::MAX` don't fit in that.
+    let answered: HashSet<u64> = services
+        .services()
+        .iter()
+        .flat_map(|service| &service.endpoints)
+        .filter_map(|endpoint| endpoint.subject.rsplit('-').next())
+        .filter_map(|id| u64::from_str_radix(id, 16).ok())
+        .collect();
+    let registered: BTreeSet<u64> = instances.iter().map(Instance::id).collect();
+    registered
+        .into_iter()
+        .filter(|id| !answered.contains(id))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_instance;
+    use super::*;
+
+    #[test]
+    fn test_missing_instances() {
+        let instances = vec![
+            test_instance(0x694d988806b92e39),
+            Instance {
+                endpoint: "clear_kv_blocks".to_string(),
+                ..test_instance(0x694d988806b92e39)
+            },
+            test_instance(0xf00dcafe00000001),
+            test_instance(0x1234),
+        ];
+        let services: ServiceSet = serde_json::from_value(serde_json::json!({
+            "services": [{
+                "name": "dynamo_backend",
+                "id": "bdu7nA8tbhy9mEkxIWlkBA",
+                "version": "0.0.1",
+                "started": "2025-08-08T05:07:17.720783523Z",
+                "endpoints": [
+                    {"name": "a", "subject": "dynamo_backend.generate-694d988806b92e39"},
+                    {"name": "b", "subject": "dynamo_backend.generate-f00dcafe00000001"},
+                ],
+            }],
+        }))
+        .unwrap();
+        assert_eq!(missing_instances(&instances, &services), vec![0x1234]);
+        assert_eq!(
+            missing_instances(&instances[..2], &services),
+            Vec::<u64>::new()
+        );
+
+        let config = StatsCacheConfig::default();
+        assert_eq!(config.refresh_interval(), Duration::from_millis(4500));
+        let config = StatsCacheConfig {
+            max_staleness: Duration::from_millis(100),
+            timeout: Duration::from_millis(500),
+        };
+        // Not spinning
+        assert_eq!(config.refresh_interval(), Duration::from_millis(500));
+    }
+}
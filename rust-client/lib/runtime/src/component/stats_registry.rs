@@ -0,0 +1,181 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Application metrics of a component, served both on `/metrics` and in its service stats.
+//!
+//! A metric created through [`Component::stats_registry`] is a metric of the component like one
+//! from [`MetricsHierarchy::metrics`], with the same automatic labels. Its value is also part
+//! of what every endpoint of the component answers [`Component::scrape_stats`] with, under
+//! [`STATS_KEY`], so applications don't need to run an exporter of their own next to the
+//! runtime's. Values are added to the output of an endpoint's own stats handler when that is a
+//! JSON object, and are all there is when the endpoint has no handler.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use serde_json::{Map, Value, json};
+
+use crate::{Result, metrics::MetricsHierarchy};
+
+use super::Component;
+
+/// The field of the stats data holding the registered metrics
+pub const STATS_KEY: &str = "metrics";
+
+#[derive(Clone)]
+enum Stat {
+    Counter(prometheus::Counter),
+    IntCounter(prometheus::IntCounter),
+    Gauge(prometheus::Gauge),
+    IntGauge(prometheus::IntGauge),
+    Histogram(prometheus::Histogram),
+}
+
+impl Stat {
+    fn value(&self) -> Value {
+        match self {
+            Stat::Counter(counter) => json!(counter.get()),
+            Stat::IntCounter(counter) => json!(counter.get()),
+            Stat::Gauge(gauge) => json!(gauge.get()),
+            Stat::IntGauge(gauge) => json!(gauge.get()),
+            Stat::Histogram(histogram) => json!({
+                "count": histogram.get_sample_count(),
+                "sum": histogram.get_sample_sum(),
+            }),
+        }
+    }
+}
+
+/// The metrics registered for one component, by name. Shared with its service's stats handler.
+#[derive(Clone, Default)]
+pub(crate) struct RegisteredStats(Arc<Mutex<BTreeMap<String, Stat>>>);
+
+impl RegisteredStats {
+    /// `data` from an endpoint's stats handler, with the registered metrics added
+    pub(super) fn add_to(&self, data: Value) -> Value {
+        let stats = self.0.lock();
+        if stats.is_empty() {
+            return data;
+        }
+        let values: Map<String, Value> = stats
+            .iter()
+            .map(|(name, stat)| (name.clone(), stat.value()))
+            .collect();
+        match data {
+            Value::Null => json!({ STATS_KEY: values }),
+            Value::Object(mut object) if !object.contains_key(STATS_KEY) => {
+                object.insert(STATS_KEY.to_string(), Value::Object(values));
+                Value::Object(object)
+            }
+            // The handler's output can't take them, or already has a field of that name
+            data => {
+                tracing::trace!("Stats handler output has no room for registered metrics");
+                data
+            }
+        }
+    }
+}
+
+/// See [`Component::stats_registry`]
+#[derive(Clone)]
+pub struct StatsRegistry {
+    component: Component,
+    stats: RegisteredStats,
+}
+
+impl StatsRegistry {
+    pub(super) fn new(component: Component, stats: RegisteredStats) -> Self {
+        StatsRegistry { component, stats }
+    }
+
+    pub fn counter(&self, name: &str, description: &str) -> Result<prometheus::Counter> {
+        let counter = self
+            .component
+            .metrics()
+            .create_counter(name, description, &[])?;
+        self.register(name, Stat::Counter(counter.clone()));
+        Ok(counter)
+    }
+
+    pub fn intcounter(&self, name: &str, description: &str) -> Result<prometheus::IntCounter> {
+        let counter = self
+            .component
+            .metrics()
+            .create_intcounter(name, description, &[])?;
+        self.register(name, Stat::IntCounter(counter.clone()));
+        Ok(counter)
+    }
+
+    pub fn gauge(&self, name: &str, description: &str) -> Result<prometheus::Gauge> {
+        let gauge = self
+            .component
+            .metrics()
+            .create_gauge(name, description, &[])?;
+        self.register(name, Stat::Gauge(gauge.clone()));
+        Ok(gauge)
+    }
+
+    pub fn intgauge(&self, name: &str, description: &str) -> Result<prometheus::IntGauge> {
+        let gauge = self
+            .component
+            .metrics()
+            .create_intgauge(name, description, &[])?;
+        self.register(name, Stat::IntGauge(gauge.clone()));
+        Ok(gauge)
+    }
+
+    /// In the service stats as its count and sum, the buckets are only on `/metrics`
+    pub fn histogram(
+        &self,
+        name: &str,
+        description: &str,
+        buckets: Option<Vec<f64>>,
+    ) -> Result<prometheus::Histogram> {
+        let metrics = self.component.metrics();
+        let histogram = metrics.create_histogram(name, description, &[], buckets)?;
+        self.register(name, Stat::Histogram(histogram.clone()));
+        Ok(histogram)
+    }
+
+    /// Creating the metric failed already if the name was taken
+    fn register(&self, name: &str, stat: Stat) {
+        self.stats.0.lock().insert(name.to_string(), stat);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_to() {
+        let stats = RegisteredStats::default();
+        // Nothing registered, nothing changes
+        assert_eq!(stats.add_to(Value::Null), Value::Null);
+
+        let requests = prometheus::IntCounter::new("requests", "requests").unwrap();
+        let opts = prometheus::HistogramOpts::new("latency", "latency");
+        let latency = prometheus::Histogram::with_opts(opts).unwrap();
+        requests.inc_by(3);
+        latency.observe(0.5);
+        stats
+            .0
+            .lock()
+            .insert("requests".to_string(), Stat::IntCounter(requests));
+        stats
+            .0
+            .lock()
+            .insert("latency".to_string(), Stat::Histogram(latency));
+
+        let expected = json!({"requests": 3, "latency": {"count": 1, "sum": 0.5}});
+        assert_eq!(stats.add_to(Value::Null), json!({ STATS_KEY: expected }));
+        // Next to what the endpoint's handler returns
+        assert_eq!(
+            stats.add_to(json!({"val": 10})),
+            json!({"val": 10, STATS_KEY: expected})
+        );
+        assert_eq!(stats.add_to(json!([1, 2])), json!([1, 2]));
+        assert_eq!(stats.add_to(json!({STATS_KEY: 1})), json!({STATS_KEY: 1}));
+    }
+}
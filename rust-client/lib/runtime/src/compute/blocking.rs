@@ -0,0 +1,272 @@
+// SPDX-FileCopyrightText: Copyright (c) 2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Blocking work offloaded from the async workers, counted by what it is.
+//!
+//! [`Runtime::spawn_blocking_instrumented`] runs CPU-heavy work, such as tokenizing or validating
+//! a JSON schema, on tokio's blocking pool. At most half of the pool runs it at once, so the file
+//! and DNS lookups tokio also does there never wait behind it. Each task is counted under its
+//! name, with the time from spawning to starting as its queue time. Queue time that grows with
+//! the load means the limit, or the machine, is too small for it.
+//!
+//! [`WatchPolls`] finds the work that should have been offloaded and wasn't. In debug builds a
+//! poll of the wrapped future that takes longer than [`SLOW_POLL`] is logged, and one longer than
+//! [`BLOCKING_POLL`] fails a debug assertion.
+//!
+//! [`Runtime::spawn_blocking_instrumented`]: crate::Runtime::spawn_blocking_instrumented
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use parking_lot::Mutex;
+use tokio::sync::Semaphore;
+
+/// A poll this long holds up every other task of its worker
+pub const SLOW_POLL: Duration = Duration::from_millis(10);
+
+/// A poll this long is blocking work on an async worker
+pub const BLOCKING_POLL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Default)]
+struct TaskCounts {
+    queued: AtomicU64,
+    running: AtomicU64,
+    completed: AtomicU64,
+    queue_us: AtomicU64,
+    run_us: AtomicU64,
+}
+
+/// What the blocking tasks of one name did since the process started
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockingTaskStats {
+    /// Waiting for their turn or for a thread
+    pub queued: u64,
+    pub running: u64,
+    pub completed: u64,
+    /// Of the tasks that started, from being spawned to starting
+    pub queue_time: Duration,
+    /// Of the tasks that completed
+    pub run_time: Duration,
+}
+
+/// The blocking tasks of a [`Runtime`](crate::Runtime)
+#[derive(Debug)]
+pub struct BlockingTasks {
+    limit: Arc<Semaphore>,
+    counts: Mutex<HashMap<&'static str, Arc<TaskCounts>>>,
+}
+
+impl BlockingTasks {
+    /// For a blocking pool of `max_blocking_threads`
+    pub(crate) fn new(max_blocking_threads: usize) -> Self {
+        BlockingTasks {
+            limit: Arc::new(Semaphore::new((max_blocking_threads / 2).max(1))),
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) async fn run<F, R>(
+        &self,
+        handle: &tokio::runtime::Handle,
+        name: &'static str,
+        f: F,
+    ) -> Result<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let counts = self.counts.lock().entry(name).or_default().clone();
+        let mut tracked = Tracked::new(counts);
+        let queued_at = Instant::now();
+        // The semaphore is never closed
+        let permit = self.limit.clone().acquire_owned().await?;
+        let task = handle.spawn_blocking(move || {
+            tracked.start(queued_at.elapsed());
+            let result = f();
+            drop(tracked);
+            drop(permit);
+            result
+        });
+        task.await
+            .map_err(|err| anyhow::anyhow!("Blocking task '{name}' failed: {err}"))
+    }
+
+    /// By task name
+    pub fn stats(&self) -> Vec<(&'static str, BlockingTaskStats)> {
+        let mut stats: Vec<_> = self
+            .counts
+            .lock()
+            .iter()
+            .map(|(name, counts)| {
+                let stats = BlockingTaskStats {
+                    queued: counts.queued.load(Ordering::Relaxed),
+                    running: counts.running.load(Ordering::Relaxed),
+                    completed: counts.completed.load(Ordering::Relaxed),
+                    queue_time: Duration::from_micros(counts.queue_us.load(Ordering::Relaxed)),
+                    run_time: Duration::from_micros(counts.run_us.load(Ordering::Relaxed)),
+                };
+                (*name, stats)
+            })
+            .collect();
+        stats.sort_by_key(|(name, _)| *name);
+        stats
+    }
+}
+
+/// Counts one task as queued and then as running, until dropped. Cancelled while queued, or
+/// panicking while running, it still leaves the counts right.
+struct Tracked {
+    counts: Arc<TaskCounts>,
+    started: Option<Instant>,
+}
+
+impl Tracked {
+    fn new(counts: Arc<TaskCounts>) -> Self {
+        counts.queued.fetch_add(1, Ordering::Relaxed);
+        Tracked {
+            counts,
+            started: None,
+        }
+    }
+
+    fn start(&mut self, queue_time: Duration) {
+        self.counts.queued.fetch_sub(1, Ordering::Relaxed);
+        self.counts.running.fetch_add(1, Ordering::Relaxed);
+        let queue_us = queue_time.as_micros() as u64;
+        self.counts.queue_us.fetch_add(queue_us, Ordering::Relaxed);
+        self.started = Some(Instant::now());
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        let Some(started) = self.started else {
+            self.counts.queued.fetch_sub(1, Ordering::Relaxed);
+            return;
+        };
+        self.counts.running.fetch_sub(1, Ordering::Relaxed);
+        self.counts.completed.fetch_add(1, Ordering::Relaxed);
+        let run_us = started.elapsed().as_micros() as u64;
+        self.counts.run_us.fetch_add(run_us, Ordering::Relaxed);
+    }
+}
+
+/// `future`, with its polls timed in debug builds. See the [module docs](self).
+pub fn watch_polls<F: Future + Unpin>(name: &'static str, future: F) -> WatchPolls<F> {
+    WatchPolls { name, future }
+}
+
+/// See [`watch_polls`]
+pub struct WatchPolls<F> {
+    name: &'static str,
+    future: F,
+}
+
+impl<F: Future + Unpin> Future for WatchPolls<F> {
+    type Output = F::Output;
+
+    #[cfg(not(debug_assertions))]
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.future).poll(cx)
+    }
+
+    #[cfg(debug_assertions)]
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let start = Instant::now();
+        let poll = Pin::new(&mut self.future).poll(cx);
+        check_poll(self.name, start.elapsed());
+        poll
+    }
+}
+
+#[cfg(debug_assertions)]
+fn check_poll(name: &'static str, elapsed: Duration) {
+    if elapsed <= SLOW_POLL {
+        return;
+    }
+    let poll_ms = elapsed.as_millis() as u64;
+    tracing::warn!(
+        future = name,
+        poll_ms,
+        "Slow poll, offload the blocking work in it"
+    );
+    debug_assert!(
+        elapsed <= BLOCKING_POLL,
+        "{name} blocked its async worker for {poll_ms}ms, \
+         see Runtime::spawn_blocking_instrumented"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_blocking_tasks() {
+        let tasks = Arc::new(BlockingTasks::new(2));
+        let handle = tokio::runtime::Handle::current();
+
+        // With a limit of one, the second task waits for the first
+        let (release, wait) = std::sync::mpsc::channel::<()>();
+        let first = tokio::spawn({
+            let tasks = tasks.clone();
+            let handle = handle.clone();
+            async move {
+                tasks
+                    .run(&handle, "tokenize", move || wait.recv().is_ok())
+                    .await
+            }
+        });
+        while tasks.stats().first().map(|(_, stats)| stats.running) != Some(1) {
+            tokio::task::yield_now().await;
+        }
+        let second = tokio::spawn({
+            let tasks = tasks.clone();
+            async move { tasks.run(&handle, "tokenize", || 42).await }
+        });
+        while tasks.stats()[0].1.queued != 1 {
+            tokio::task::yield_now().await;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        release.send(()).unwrap();
+        assert!(first.await.unwrap().unwrap());
+        assert_eq!(second.await.unwrap().unwrap(), 42);
+
+        let [(name, stats)] = tasks.stats()[..] else {
+            panic!("expected the tokenize tasks only");
+        };
+        assert_eq!(name, "tokenize");
+        assert_eq!((stats.queued, stats.running, stats.completed), (0, 0, 2));
+        assert!(stats.queue_time >= Duration::from_millis(20), "{stats:?}");
+        assert!(stats.run_time >= Duration::from_millis(20), "{stats:?}");
+
+        // A panic fails the task, and is counted like it completed
+        let handle = tokio::runtime::Handle::current();
+        let err = tasks
+            .run(&handle, "panics", || -> u32 { panic!("boom") })
+            .await;
+        assert!(err.unwrap_err().to_string().contains("'panics' failed"));
+        assert_eq!(tasks.stats()[0].1.completed, 1);
+    }
+
+    #[tokio::test]
+    async fn test_watch_polls() {
+        let ready = watch_polls("ready", std::future::ready(7));
+        assert_eq!(ready.await, 7);
+        let boxed = watch_polls("boxed", Box::pin(async { 8 }));
+        assert_eq!(boxed.await, 8);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "blocked its async worker")]
+    fn test_blocking_poll_asserts() {
+        check_poll("operator", BLOCKING_POLL + Duration::from_millis(1));
+    }
+}
@@ -20,6 +20,7 @@ use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Instant;
 
+pub mod blocking;
 pub mod macros;
 pub mod metrics;
 pub mod pool;
@@ -27,6 +27,14 @@ pub const DEFAULT_CANARY_WAIT_TIME_SECS: u64 = 10;
 /// Default timeout for individual health check requests
 pub const DEFAULT_HEALTH_CHECK_REQUEST_TIMEOUT_SECS: u64 = 3;
 
+/// Default interval between metrics pushes, when a metrics exporter is configured
+pub const DEFAULT_METRICS_EXPORT_INTERVAL_SECS: u64 = 10;
+
+/// Default thresholds of the slow operation warnings, see [`crate::logging::slow_ops`]
+pub const DEFAULT_SLOW_ETCD_CALL_MS: u64 = 500;
+pub const DEFAULT_SLOW_STORE_OP_MS: u64 = 1000;
+pub const DEFAULT_SLOW_OPERATOR_MS: u64 = 1000;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WorkerConfig {
     /// Grace shutdown period for the system server.
@@ -65,6 +73,16 @@ pub enum HealthStatus {
     NotReady,
 }
 
+/// Where to push metrics to, besides serving them on `/metrics`. See [`crate::metrics::export`].
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricsExporter {
+    #[default]
+    None,
+    Statsd,
+    Otlp,
+}
+
 /// Runtime configuration
 /// Defines the configuration for Tokio runtimes
 #[derive(Serialize, Deserialize, Validate, Debug, Builder, Clone)]
@@ -87,6 +105,24 @@ pub struct RuntimeConfig {
     #[builder_field_attr(serde(skip_serializing_if = "Option::is_none"))]
     pub max_blocking_threads: usize,
 
+    /// Number of worker threads of a runtime of their own for the background tasks, such as the
+    /// stats and metrics refreshers. If not set, they share the worker threads of the primary
+    /// runtime, and a saturated primary delays them.
+    /// Set this at runtime with environment variable DYN_RUNTIME_BACKGROUND_THREADS.
+    #[validate(range(min = 1))]
+    #[builder(default = "None")]
+    #[builder_field_attr(serde(skip_serializing_if = "Option::is_none"))]
+    pub background_threads: Option<usize>,
+
+    /// Number of worker threads of the control plane runtime, which keeps the etcd leases alive
+    /// and pumps the watches, see [`crate::Runtime::control_plane`].
+    /// Set this at runtime with environment variable DYN_RUNTIME_CONTROL_PLANE_THREADS. Defaults
+    /// to 1.
+    #[validate(range(min = 1))]
+    #[builder(default = "1")]
+    #[builder_field_attr(serde(skip_serializing_if = "Option::is_none"))]
+    pub control_plane_threads: usize,
+
     /// System status server host for health and metrics endpoints
     /// Set this at runtime with environment variable DYN_SYSTEM_HOST
     #[builder(default = "DEFAULT_SYSTEM_HOST.to_string()")]
@@ -168,6 +204,44 @@ pub struct RuntimeConfig {
     #[builder(default = "DEFAULT_HEALTH_CHECK_REQUEST_TIMEOUT_SECS")]
     #[builder_field_attr(serde(skip_serializing_if = "Option::is_none"))]
     pub health_check_request_timeout_secs: u64,
+
+    /// Push metrics to a StatsD agent or an OTLP collector as well
+    /// Set this at runtime with environment variable DYN_METRICS_EXPORTER, to `statsd` or `otlp`
+    #[builder(default = "MetricsExporter::None")]
+    #[builder_field_attr(serde(skip_serializing_if = "Option::is_none"))]
+    pub metrics_exporter: MetricsExporter,
+
+    /// `host:port` of the StatsD agent, or URL of the OTLP collector. Defaults to the local
+    /// agent's or collector's usual port.
+    /// Set this at runtime with environment variable DYN_METRICS_EXPORT_ENDPOINT
+    #[builder(default = "None")]
+    #[builder_field_attr(serde(skip_serializing_if = "Option::is_none"))]
+    pub metrics_export_endpoint: Option<String>,
+
+    /// Seconds between metrics pushes
+    /// Set this at runtime with environment variable DYN_METRICS_EXPORT_INTERVAL
+    #[builder(default = "DEFAULT_METRICS_EXPORT_INTERVAL_SECS")]
+    #[builder_field_attr(serde(skip_serializing_if = "Option::is_none"))]
+    pub metrics_export_interval_secs: u64,
+
+    /// Milliseconds an etcd call may take before it is logged as slow, 0 to never log it
+    /// Set this at runtime with environment variable DYN_SLOW_OP_ETCD_MS
+    #[builder(default = "DEFAULT_SLOW_ETCD_CALL_MS")]
+    #[builder_field_attr(serde(skip_serializing_if = "Option::is_none"))]
+    pub slow_etcd_call_ms: u64,
+
+    /// Milliseconds a key-value store operation may take before it is logged as slow
+    /// Set this at runtime with environment variable DYN_SLOW_OP_STORE_MS
+    #[builder(default = "DEFAULT_SLOW_STORE_OP_MS")]
+    #[builder_field_attr(serde(skip_serializing_if = "Option::is_none"))]
+    pub slow_store_op_ms: u64,
+
+    /// Milliseconds a pipeline operator may take to return its response stream before it is
+    /// logged as slow
+    /// Set this at runtime with environment variable DYN_SLOW_OP_OPERATOR_MS
+    #[builder(default = "DEFAULT_SLOW_OPERATOR_MS")]
+    #[builder_field_attr(serde(skip_serializing_if = "Option::is_none"))]
+    pub slow_operator_ms: u64,
 }
 
 impl fmt::Display for RuntimeConfig {
@@ -179,6 +253,10 @@ impl fmt::Display for RuntimeConfig {
         }
 
         write!(f, "max_blocking_threads={}, ", self.max_blocking_threads)?;
+        if let Some(threads) = self.background_threads {
+            write!(f, "background_threads={threads}, ")?;
+        }
+        write!(f, "control_plane_threads={}, ", self.control_plane_threads)?;
         write!(f, "system_host={}, ", self.system_host)?;
         write!(f, "system_port={}, ", self.system_port)?;
         write!(f, "system_enabled={}", self.system_enabled)?;
@@ -201,6 +279,20 @@ impl fmt::Display for RuntimeConfig {
             ", health_check_request_timeout_secs={}",
             self.health_check_request_timeout_secs
         )?;
+        write!(f, ", metrics_exporter={:?}", self.metrics_exporter)?;
+        if let Some(endpoint) = &self.metrics_export_endpoint {
+            write!(f, ", metrics_export_endpoint={endpoint}")?;
+        }
+        write!(
+            f,
+            ", metrics_export_interval_secs={}",
+            self.metrics_export_interval_secs
+        )?;
+        write!(
+            f,
+            ", slow_etcd_call_ms={}, slow_store_op_ms={}, slow_operator_ms={}",
+            self.slow_etcd_call_ms, self.slow_store_op_ms, self.slow_operator_ms
+        )?;
 
         Ok(())
     }
@@ -293,6 +385,40 @@ impl RuntimeConfig {
                     _ => None,
                 }
             }))
+            .merge(Env::prefixed("DYN_METRICS_").filter_map(|k| {
+                let full_key = format!("DYN_METRICS_{}", k.as_str());
+                // filters out empty environment variables
+                match std::env::var(&full_key) {
+                    Ok(v) if !v.is_empty() => {
+                        // Map DYN_METRICS_* to the correct field names
+                        let mapped_key = match k.as_str() {
+                            "EXPORTER" => "metrics_exporter",
+                            "EXPORT_ENDPOINT" => "metrics_export_endpoint",
+                            "EXPORT_INTERVAL" => "metrics_export_interval_secs",
+                            _ => k.as_str(),
+                        };
+                        Some(mapped_key.into())
+                    }
+                    _ => None,
+                }
+            }))
+            .merge(Env::prefixed("DYN_SLOW_OP_").filter_map(|k| {
+                let full_key = format!("DYN_SLOW_OP_{}", k.as_str());
+                // filters out empty environment variables
+                match std::env::var(&full_key) {
+                    Ok(v) if !v.is_empty() => {
+                        // Map DYN_SLOW_OP_* to the correct field names
+                        let mapped_key = match k.as_str() {
+                            "ETCD_MS" => "slow_etcd_call_ms",
+                            "STORE_MS" => "slow_store_op_ms",
+                            "OPERATOR_MS" => "slow_operator_ms",
+                            _ => k.as_str(),
+                        };
+                        Some(mapped_key.into())
+                    }
+                    _ => None,
+                }
+            }))
     }
 
     /// Load the runtime configuration from the environment and configuration files
@@ -328,6 +454,8 @@ impl RuntimeConfig {
         RuntimeConfig {
             num_worker_threads: Some(1),
             max_blocking_threads: 1,
+            background_threads: None,
+            control_plane_threads: 1,
             system_host: DEFAULT_SYSTEM_HOST.to_string(),
             system_port: DEFAULT_SYSTEM_PORT,
             system_enabled: false,
@@ -341,6 +469,12 @@ impl RuntimeConfig {
             health_check_enabled: false,
             canary_wait_time_secs: DEFAULT_CANARY_WAIT_TIME_SECS,
             health_check_request_timeout_secs: DEFAULT_HEALTH_CHECK_REQUEST_TIMEOUT_SECS,
+            metrics_exporter: MetricsExporter::None,
+            metrics_export_endpoint: None,
+            metrics_export_interval_secs: DEFAULT_METRICS_EXPORT_INTERVAL_SECS,
+            slow_etcd_call_ms: DEFAULT_SLOW_ETCD_CALL_MS,
+            slow_store_op_ms: DEFAULT_SLOW_STORE_OP_MS,
+            slow_operator_ms: DEFAULT_SLOW_OPERATOR_MS,
         }
     }
 
@@ -355,6 +489,21 @@ impl RuntimeConfig {
             .enable_all()
             .build()
     }
+
+    /// The runtime of the background tasks, if they get one of their own
+    pub(crate) fn create_background_runtime(
+        &self,
+    ) -> std::io::Result<Option<tokio::runtime::Runtime>> {
+        let Some(threads) = self.background_threads else {
+            return Ok(None);
+        };
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(threads)
+            .thread_name("dyn-background")
+            .enable_all()
+            .build()
+            .map(Some)
+    }
 }
 
 impl Default for RuntimeConfig {
@@ -363,6 +512,8 @@ impl Default for RuntimeConfig {
         Self {
             num_worker_threads: Some(num_cores),
             max_blocking_threads: num_cores,
+            background_threads: None,
+            control_plane_threads: 1,
             system_host: DEFAULT_SYSTEM_HOST.to_string(),
             system_port: DEFAULT_SYSTEM_PORT,
             system_enabled: false,
@@ -376,6 +527,12 @@ impl Default for RuntimeConfig {
             health_check_enabled: false,
             canary_wait_time_secs: DEFAULT_CANARY_WAIT_TIME_SECS,
             health_check_request_timeout_secs: DEFAULT_HEALTH_CHECK_REQUEST_TIMEOUT_SECS,
+            metrics_exporter: MetricsExporter::None,
+            metrics_export_endpoint: None,
+            metrics_export_interval_secs: DEFAULT_METRICS_EXPORT_INTERVAL_SECS,
+            slow_etcd_call_ms: DEFAULT_SLOW_ETCD_CALL_MS,
+            slow_store_op_ms: DEFAULT_SLOW_STORE_OP_MS,
+            slow_operator_ms: DEFAULT_SLOW_OPERATOR_MS,
         }
     }
 }
@@ -565,6 +722,80 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_metrics_exporter_env_vars() {
+        temp_env::with_vars(vec![("DYN_METRICS_EXPORTER", None::<&str>)], || {
+            let config = RuntimeConfig::from_settings().unwrap();
+            assert_eq!(config.metrics_exporter, MetricsExporter::None);
+        });
+        temp_env::with_vars(
+            vec![
+                ("DYN_METRICS_EXPORTER", Some("statsd")),
+                ("DYN_METRICS_EXPORT_ENDPOINT", Some("statsd.local:8125")),
+                ("DYN_METRICS_EXPORT_INTERVAL", Some("30")),
+            ],
+            || {
+                let config = RuntimeConfig::from_settings().unwrap();
+                assert_eq!(config.metrics_exporter, MetricsExporter::Statsd);
+                let endpoint = config.metrics_export_endpoint.as_deref();
+                assert_eq!(endpoint, Some("statsd.local:8125"));
+                assert_eq!(config.metrics_export_interval_secs, 30);
+            },
+        );
+    }
+
+    #[test]
+    fn test_dedicated_runtimes_env_vars() {
+        temp_env::with_vars(
+            vec![
+                ("DYN_RUNTIME_BACKGROUND_THREADS", None::<&str>),
+                ("DYN_RUNTIME_CONTROL_PLANE_THREADS", None),
+            ],
+            || {
+                let config = RuntimeConfig::from_settings().unwrap();
+                assert_eq!(config.background_threads, None);
+                assert_eq!(config.control_plane_threads, 1);
+                assert!(config.create_background_runtime().unwrap().is_none());
+            },
+        );
+        temp_env::with_vars(
+            vec![
+                ("DYN_RUNTIME_BACKGROUND_THREADS", Some("2")),
+                ("DYN_RUNTIME_CONTROL_PLANE_THREADS", Some("3")),
+            ],
+            || {
+                let config = RuntimeConfig::from_settings().unwrap();
+                assert_eq!(config.background_threads, Some(2));
+                assert_eq!(config.control_plane_threads, 3);
+                let background = config.create_background_runtime().unwrap().unwrap();
+                assert_eq!(background.metrics().num_workers(), 2);
+            },
+        );
+        temp_env::with_vars(
+            vec![("DYN_RUNTIME_CONTROL_PLANE_THREADS", Some("0"))],
+            || {
+                assert!(RuntimeConfig::from_settings().is_err());
+            },
+        );
+    }
+
+    #[test]
+    fn test_slow_op_env_vars() {
+        temp_env::with_vars(
+            vec![
+                ("DYN_SLOW_OP_ETCD_MS", Some("250")),
+                ("DYN_SLOW_OP_STORE_MS", None),
+                ("DYN_SLOW_OP_OPERATOR_MS", Some("0")),
+            ],
+            || {
+                let config = RuntimeConfig::from_settings().unwrap();
+                assert_eq!(config.slow_etcd_call_ms, 250);
+                assert_eq!(config.slow_store_op_ms, DEFAULT_SLOW_STORE_OP_MS);
+                assert_eq!(config.slow_operator_ms, 0);
+            },
+        );
+    }
+
     #[test]
     fn test_system_use_endpoint_health_status() {
         temp_env::with_vars(
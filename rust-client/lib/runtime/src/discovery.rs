@@ -3,7 +3,14 @@
 
 use crate::{Result, transports::etcd};
 
+mod federation;
+mod kubernetes;
+mod offline;
+
 pub use etcd::Lease;
+pub use federation::{Federation, FederationConfig, RemoteRegion};
+pub use kubernetes::{COMPONENT_LABEL, KubernetesDiscovery, NAMESPACE_LABEL, pod_instance_id};
+pub use offline::{OfflineRegistrations, OfflineState, RegistrationConflict};
 
 pub struct DiscoveryClient {
     namespace: String,
@@ -0,0 +1,268 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Instances from the etcd clusters of other regions, for cross-region failover.
+//!
+//! Each region runs its own etcd, and its workers register there as usual. With
+//! `DYN_FEDERATED_REGIONS` set, clients also watch the etcd of every listed remote region,
+//! read-only, and see the instances registered there tagged with [`Instance::region`]. Routers
+//! keep to the instances of their own region and only send requests to remote ones once none of
+//! the local instances is routable.
+//!
+//! The format is `name=url[,url...]` per region, separated by `;`:
+//!
+//! ```text
+//! DYN_REGION=us
+//! DYN_FEDERATED_REGIONS="eu=http://etcd.eu:2379;ap=http://etcd-0.ap:2379,http://etcd-1.ap:2379"
+//! ```
+//!
+//! Requests still travel over NATS, so the regions' NATS clusters must be joined (with gateways
+//! or leaf nodes) for a remote instance's subject to reach it. A remote region whose etcd is
+//! unreachable is left out, and one whose watch ends is dropped until the client restarts.
+
+use tokio::sync::watch;
+
+use crate::{
+    Result, Runtime,
+    component::{Client, Endpoint, Instance},
+    error,
+    traits::DistributedRuntimeProvider,
+    transports::etcd,
+};
+
+/// The name of this region when `DYN_REGION` isn't set
+const DEFAULT_REGION: &str = "local";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FederationConfig {
+    /// This region's name, from `DYN_REGION`
+    pub region: String,
+    pub remotes: Vec<RemoteRegion>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteRegion {
+    pub name: String,
+    pub etcd_url: Vec<String>,
+}
+
+impl FederationConfig {
+    /// From the environment, see the [module docs](self). None without `DYN_FEDERATED_REGIONS`.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(remotes) = std::env::var("DYN_FEDERATED_REGIONS") else {
+            return Ok(None);
+        };
+        let region = std::env::var("DYN_REGION").unwrap_or_else(|_| DEFAULT_REGION.to_string());
+        Self::parse(region, &remotes).map(Some)
+    }
+
+    fn parse(region: String, remotes: &str) -> Result<Self> {
+        let mut parsed: Vec<RemoteRegion> = Vec::new();
+        for entry in remotes.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+            let Some((name, urls)) = entry.split_once('=') else {
+                return Err(error!(
+                    "Federated region '{entry}' is not name=url[,url...]"
+                ));
+            };
+            let name = name.trim().to_string();
+            let etcd_url: Vec<String> = urls
+                .split(',')
+                .map(str::trim)
+                .filter(|url| !url.is_empty())
+                .map(str::to_string)
+                .collect();
+            if name.is_empty() || etcd_url.is_empty() {
+                return Err(error!(
+                    "Federated region '{entry}' needs a name and an etcd URL"
+                ));
+            }
+            if name == region {
+                return Err(error!(
+                    "Federated region '{name}' is this region, DYN_REGION"
+                ));
+            }
+            if parsed.iter().any(|remote| remote.name == name) {
+                return Err(error!("Federated region '{name}' is listed twice"));
+            }
+            parsed.push(RemoteRegion { name, etcd_url });
+        }
+        Ok(FederationConfig {
+            region,
+            remotes: parsed,
+        })
+    }
+}
+
+/// Read-only connections to the etcd clusters of the remote regions
+#[derive(Clone)]
+pub struct Federation {
+    region: String,
+    remotes: Vec<(String, etcd::Client)>,
+}
+
+impl Federation {
+    /// Connect to every remote region's etcd. Regions that can't be reached are logged and
+    /// left out rather than holding up startup.
+    pub(crate) async fn connect(config: FederationConfig, runtime: &Runtime) -> Self {
+        let mut remotes = Vec::with_capacity(config.remotes.len());
+        for remote in config.remotes {
+            let options = etcd::ClientOptions {
+                etcd_url: remote.etcd_url,
+                attach_lease: false,
+                read_only: true,
+                // ETCD_DNS_* describe this region's etcd
+                dns: None,
+                ..Default::default()
+            };
+            match etcd::Client::new(options, runtime.clone()).await {
+                Ok(client) => remotes.push((remote.name, client)),
+                Err(err) => {
+                    tracing::warn!(%err, region = %remote.name, "Leaving out federated region");
+                }
+            }
+        }
+        tracing::info!(
+            region = %config.region,
+            remotes = ?remotes.iter().map(|(name, _)| name).collect::<Vec<_>>(),
+            "Federated discovery"
+        );
+        Federation {
+            region: config.region,
+            remotes,
+        }
+    }
+
+    /// This region's name
+    pub fn region(&self) -> &str {
+        &self.region
+    }
+
+    /// The remote regions connected to
+    pub fn remote_regions(&self) -> impl Iterator<Item = &str> {
+        self.remotes.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// `local`, the instances of `endpoint` in this region, followed by those of every remote
+    /// region. Ends when `local` does.
+    pub(crate) async fn merge_instances(
+        &self,
+        endpoint: &Endpoint,
+        local: watch::Receiver<Vec<Instance>>,
+    ) -> watch::Receiver<Vec<Instance>> {
+        let mut sources = vec![local];
+        for (name, client) in &self.remotes {
+            match Client::watch_etcd_instances(client, endpoint, Some(name.clone())).await {
+                Ok(instances) => sources.push(instances),
+                Err(err) => {
+                    tracing::warn!(%err, region = %name, "Not watching federated region");
+                }
+            }
+        }
+        spawn_merge(&endpoint.drt().runtime().control_plane(), sources)
+    }
+}
+
+/// Concatenate `sources`, the first of which is the local region's, as any of them changes
+fn spawn_merge(
+    handle: &tokio::runtime::Handle,
+    mut sources: Vec<watch::Receiver<Vec<Instance>>>,
+) -> watch::Receiver<Vec<Instance>> {
+    let (tx, rx) = watch::channel(vec![]);
+    handle.spawn(async move {
+        loop {
+            let merged: Vec<Instance> = sources
+                .iter_mut()
+                .flat_map(|source| source.borrow_and_update().clone())
+                .collect();
+            if tx.send(merged).is_err() {
+                break;
+            }
+
+            let changed = {
+                let changes = sources.iter_mut().map(|source| Box::pin(source.changed()));
+                tokio::select! {
+                    _ = tx.closed() => None,
+                    (result, index, _) = futures::future::select_all(changes) => {
+                        Some((index, result.is_ok()))
+                    }
+                }
+            };
+            match changed {
+                None | Some((0, false)) => break,
+                Some((_, true)) => {}
+                Some((index, false)) => {
+                    sources.remove(index);
+                }
+            }
+        }
+        let _ = tx.send(vec![]);
+    });
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::component::test_instance;
+
+    #[test]
+    fn test_parse_regions() {
+        let config = FederationConfig::parse(
+            "us-east".to_string(),
+            "eu-west=http://a:2379; ap-south=http://b:2379,http://c:2379;",
+        )
+        .unwrap();
+        assert_eq!(config.region, "us-east");
+        assert_eq!(
+            config.remotes,
+            vec![
+                RemoteRegion {
+                    name: "eu-west".to_string(),
+                    etcd_url: vec!["http://a:2379".to_string()],
+                },
+                RemoteRegion {
+                    name: "ap-south".to_string(),
+                    etcd_url: vec!["http://b:2379".to_string(), "http://c:2379".to_string()],
+                },
+            ]
+        );
+
+        let local = "local".to_string();
+        assert!(FederationConfig::parse(local.clone(), "eu-west").is_err());
+        assert!(FederationConfig::parse(local.clone(), "eu-west=").is_err());
+        assert!(FederationConfig::parse(local.clone(), "local=http://a:2379").is_err());
+        assert!(FederationConfig::parse(local, "eu=http://a:2379;eu=http://b:2379").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_merge_follows_sources() {
+        let (local_tx, local_rx) = watch::channel(vec![test_instance(1)]);
+        let (remote_tx, remote_rx) = watch::channel(vec![]);
+        let handle = tokio::runtime::Handle::current();
+        let mut merged = spawn_merge(&handle, vec![local_rx, remote_rx]);
+
+        let mut remote = test_instance(2);
+        remote.region = Some("eu-west".to_string());
+        remote_tx.send(vec![remote.clone()]).unwrap();
+        merged
+            .wait_for(|instances| instances.len() == 2)
+            .await
+            .unwrap();
+        assert_eq!(merged.borrow()[1], remote);
+
+        // A remote region going away leaves the local instances
+        drop(remote_tx);
+        local_tx
+            .send(vec![test_instance(1), test_instance(3)])
+            .unwrap();
+        merged
+            .wait_for(|instances| instances.iter().map(Instance::id).eq([1, 3]))
+            .await
+            .unwrap();
+
+        // The local region going away ends the merged view
+        drop(local_tx);
+        merged.wait_for(Vec::is_empty).await.unwrap();
+        assert!(merged.changed().await.is_err());
+    }
+}
@@ -0,0 +1,489 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Instance discovery from Kubernetes EndpointSlices instead of etcd.
+//!
+//! Pods serving a component are selected by a Service labelled with [`NAMESPACE_LABEL`] and
+//! [`COMPONENT_LABEL`]; Kubernetes maintains the EndpointSlices for that Service as pods become
+//! ready or go away. Every ready endpoint becomes an [`Instance`] whose ID is derived from the pod
+//! UID with [`pod_instance_id`]. The worker computes the same ID from its own `POD_UID` (set via
+//! the downward API), so its NATS subject matches what clients expect without either side
+//! talking to etcd.
+
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::sync::watch;
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::{
+    Result,
+    component::{Endpoint, Instance, TransportType},
+    traits::DistributedRuntimeProvider,
+};
+
+/// Label on the Service (and therefore its EndpointSlices) naming the Dynamo namespace
+pub const NAMESPACE_LABEL: &str = "nvidia.com/dynamo-namespace";
+
+/// Label on the Service (and therefore its EndpointSlices) naming the Dynamo component
+pub const COMPONENT_LABEL: &str = "nvidia.com/dynamo-component";
+
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+/// Wait before listing EndpointSlices again after the watch failed
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Asked of the API server for each watch request. It ends the watch about then, and the next
+/// one picks up from the last resource version seen.
+const WATCH_TIMEOUT_SECS: u64 = 300;
+
+/// The instance ID for a pod. Stable for the pod's lifetime and unique across restarts, like a
+/// lease ID is with etcd discovery.
+pub fn pod_instance_id(pod_uid: &str) -> u64 {
+    xxh3_64(pod_uid.as_bytes())
+}
+
+#[derive(Clone, Debug)]
+pub struct KubernetesDiscovery {
+    http: reqwest::Client,
+    api_server: String,
+    token: Option<String>,
+    /// The Kubernetes namespace to list EndpointSlices in
+    k8s_namespace: String,
+    /// The ID this process serves its own endpoints under, if it runs in a pod
+    instance_id: Option<u64>,
+    retry_delay: Duration,
+}
+
+impl KubernetesDiscovery {
+    /// Configure from inside a pod, using the mounted service account and the
+    /// `KUBERNETES_SERVICE_HOST`/`KUBERNETES_SERVICE_PORT` variables every pod gets.
+    /// `POD_UID` must be set from `metadata.uid` for this process to serve endpoints.
+    pub fn from_in_cluster() -> Result<Self> {
+        let host = std::env::var("KUBERNETES_SERVICE_HOST").map_err(|_| {
+            anyhow::anyhow!("KUBERNETES_SERVICE_HOST not set; not running in a pod?")
+        })?;
+        let port = std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".to_string());
+        let host = if host.contains(':') {
+            format!("[{host}]")
+        } else {
+            host
+        };
+
+        let read = |name: &str| std::fs::read(format!("{SERVICE_ACCOUNT_DIR}/{name}"));
+        let token = String::from_utf8(read("token")?)?.trim().to_string();
+        let k8s_namespace = String::from_utf8(read("namespace")?)?.trim().to_string();
+        let ca = reqwest::Certificate::from_pem(&read("ca.crt")?)?;
+        let http = reqwest::Client::builder()
+            .add_root_certificate(ca)
+            .build()?;
+
+        Ok(KubernetesDiscovery {
+            http,
+            api_server: format!("https://{host}:{port}"),
+            token: Some(token),
+            k8s_namespace,
+            instance_id: std::env::var("POD_UID")
+                .ok()
+                .map(|uid| pod_instance_id(&uid)),
+            retry_delay: DEFAULT_RETRY_DELAY,
+        })
+    }
+
+    /// Talk to an API server directly, e.g. through `kubectl proxy` during development
+    pub fn new(api_server: impl Into<String>, k8s_namespace: impl Into<String>) -> Self {
+        KubernetesDiscovery {
+            http: reqwest::Client::new(),
+            api_server: api_server.into(),
+            token: None,
+            k8s_namespace: k8s_namespace.into(),
+            instance_id: None,
+            retry_delay: DEFAULT_RETRY_DELAY,
+        }
+    }
+
+    /// How long to wait before listing again when the watch fails
+    pub fn with_retry_delay(mut self, retry_delay: Duration) -> Self {
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    /// The instance ID this process should serve under, from `POD_UID`
+    pub fn instance_id(&self) -> Option<u64> {
+        self.instance_id
+    }
+
+    /// Keep a list of the ready instances of `endpoint` up to date until the receiver is dropped
+    /// or the runtime shuts down, by watching its EndpointSlices. The first list is fetched
+    /// before returning so a bad configuration fails here rather than leaving the client with no
+    /// instances.
+    pub async fn watch_instances(
+        &self,
+        endpoint: &Endpoint,
+    ) -> Result<watch::Receiver<Vec<Instance>>> {
+        let (slices, resource_version) = self.list_slices(endpoint).await?;
+        let (tx, rx) = watch::channel(instances(endpoint, slices.values()));
+
+        let this = self.clone();
+        let endpoint = endpoint.clone();
+        let cancel_token = endpoint.drt().child_token();
+        let runtime = endpoint.drt().runtime().clone();
+        runtime.spawn_control_plane(async move {
+            let mut slices = slices;
+            let mut resource_version = Some(resource_version);
+            loop {
+                let follow = async {
+                    let from = match resource_version.take() {
+                        Some(from) => from,
+                        None => {
+                            let (listed, from) = this.list_slices(&endpoint).await?;
+                            slices = listed;
+                            publish(&tx, instances(&endpoint, slices.values()));
+                            from
+                        }
+                    };
+                    this.follow(&endpoint, from, &mut slices, &tx).await
+                };
+                let ended = tokio::select! {
+                    _ = cancel_token.cancelled() => break,
+                    _ = tx.closed() => break,
+                    ended = follow => ended,
+                };
+                match ended {
+                    Ok(resume) => resource_version = resume,
+                    Err(err) => {
+                        // Keep the last known list; a flaky API server should not drop all routes
+                        tracing::warn!(
+                            %err,
+                            endpoint = endpoint.path(),
+                            "Failed to watch EndpointSlices"
+                        );
+                        tokio::select! {
+                            _ = cancel_token.cancelled() => break,
+                            _ = tokio::time::sleep(this.retry_delay) => {}
+                        }
+                    }
+                }
+            }
+            tracing::debug!(
+                endpoint = endpoint.path(),
+                "Kubernetes instance watcher stopped"
+            );
+        });
+
+        Ok(rx)
+    }
+
+    fn endpoint_slices_request(&self, endpoint: &Endpoint) -> reqwest::RequestBuilder {
+        let namespace = endpoint.component().namespace().name();
+        let component = endpoint.component().name();
+        let selector = format!("{NAMESPACE_LABEL}={namespace},{COMPONENT_LABEL}={component}");
+        let url = format!(
+            "{}/apis/discovery.k8s.io/v1/namespaces/{}/endpointslices",
+            self.api_server, self.k8s_namespace
+        );
+
+        let mut request = self.http.get(url).query(&[("labelSelector", selector)]);
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        request
+    }
+
+    /// The component's EndpointSlices by name, and the resource version to watch them from
+    async fn list_slices(
+        &self,
+        endpoint: &Endpoint,
+    ) -> Result<(HashMap<String, EndpointSlice>, String)> {
+        let list: EndpointSliceList = self
+            .endpoint_slices_request(endpoint)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let slices = list
+            .items
+            .into_iter()
+            .map(|slice| (slice.metadata.name.clone().unwrap_or_default(), slice))
+            .collect();
+        Ok((slices, list.metadata.resource_version.unwrap_or_default()))
+    }
+
+    /// Apply the changes to `slices` from resource version `from` on, publishing the instances
+    /// as they change, until the API server ends the watch. The version to resume from, or None
+    /// if the slices must be listed again.
+    async fn follow(
+        &self,
+        endpoint: &Endpoint,
+        from: String,
+        slices: &mut HashMap<String, EndpointSlice>,
+        tx: &watch::Sender<Vec<Instance>>,
+    ) -> Result<Option<String>> {
+        let mut response = self
+            .endpoint_slices_request(endpoint)
+            .query(&[
+                ("watch", "true"),
+                ("allowWatchBookmarks", "true"),
+                ("resourceVersion", from.as_str()),
+                ("timeoutSeconds", &WATCH_TIMEOUT_SECS.to_string()),
+            ])
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let mut resource_version = from;
+        // One JSON event per line, lines can span chunks
+        let mut pending = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            pending.extend_from_slice(&chunk);
+            while let Some(newline) = pending.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = pending.drain(..=newline).collect();
+                if line.trim_ascii().is_empty() {
+                    continue;
+                }
+                let event: SliceEvent = serde_json::from_slice(&line)?;
+                match apply(slices, &mut resource_version, event) {
+                    Applied::Changed => publish(tx, instances(endpoint, slices.values())),
+                    Applied::Unchanged => {}
+                    Applied::Expired => return Ok(None),
+                }
+            }
+        }
+        Ok(Some(resource_version))
+    }
+}
+
+/// Send `instances` if they differ from the last ones sent
+fn publish(tx: &watch::Sender<Vec<Instance>>, instances: Vec<Instance>) {
+    tx.send_if_modified(|current| {
+        if *current == instances {
+            return false;
+        }
+        *current = instances;
+        true
+    });
+}
+
+/// The ready instances of `endpoint` in `slices`
+fn instances<'a>(
+    endpoint: &Endpoint,
+    slices: impl IntoIterator<Item = &'a EndpointSlice>,
+) -> Vec<Instance> {
+    let namespace = endpoint.component().namespace().name();
+    let component = endpoint.component().name();
+    ready_pod_uids(slices)
+        .into_iter()
+        .map(|uid| {
+            let instance_id = pod_instance_id(&uid);
+            Instance {
+                component: component.to_string(),
+                endpoint: endpoint.name().to_string(),
+                namespace: namespace.clone(),
+                instance_id,
+                transport: TransportType::NatsTcp(endpoint.subject_to(instance_id)),
+                worker_id: None,
+                status: Default::default(),
+                region: None,
+                codec: Default::default(),
+                signature: None,
+            }
+        })
+        .collect()
+}
+
+/// What a watch event did to the slices
+#[derive(Debug, PartialEq, Eq)]
+enum Applied {
+    Changed,
+    Unchanged,
+    /// The resource version we watch from is too old, usually 410 Gone. List again.
+    Expired,
+}
+
+fn apply(
+    slices: &mut HashMap<String, EndpointSlice>,
+    resource_version: &mut String,
+    event: SliceEvent,
+) -> Applied {
+    if event.kind == "ERROR" {
+        return Applied::Expired;
+    }
+    let Ok(slice) = serde_json::from_value::<EndpointSlice>(event.object) else {
+        return Applied::Unchanged;
+    };
+    if let Some(version) = &slice.metadata.resource_version {
+        resource_version.clone_from(version);
+    }
+    let name = slice.metadata.name.clone().unwrap_or_default();
+    match event.kind.as_str() {
+        "ADDED" | "MODIFIED" => {
+            slices.insert(name, slice);
+            Applied::Changed
+        }
+        "DELETED" => {
+            slices.remove(&name);
+            Applied::Changed
+        }
+        // BOOKMARK only moves the resource version on
+        _ => Applied::Unchanged,
+    }
+}
+
+/// The UIDs of pods that are ready in any of the slices, in a stable order. A pod can appear in
+/// more than one slice (one per address family), so this also de-duplicates.
+fn ready_pod_uids<'a>(slices: impl IntoIterator<Item = &'a EndpointSlice>) -> Vec<String> {
+    let mut uids = BTreeSet::new();
+    for endpoint in slices.into_iter().flat_map(|s| &s.endpoints) {
+        // Per the API, a missing `ready` means ready
+        if endpoint.conditions.ready == Some(false) || endpoint.conditions.terminating == Some(true)
+        {
+            continue;
+        }
+        if let Some(target) = &endpoint.target_ref
+            && target.kind.as_deref() == Some("Pod")
+            && let Some(uid) = &target.uid
+        {
+            uids.insert(uid.clone());
+        }
+    }
+    uids.into_iter().collect()
+}
+
+// Just the parts of discovery.k8s.io/v1 EndpointSliceList, and the watch events on it, we use
+
+#[derive(Debug, Deserialize)]
+struct EndpointSliceList {
+    #[serde(default)]
+    metadata: ObjectMeta,
+    #[serde(default)]
+    items: Vec<EndpointSlice>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EndpointSlice {
+    #[serde(default)]
+    metadata: ObjectMeta,
+    #[serde(default)]
+    endpoints: Vec<SliceEndpoint>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ObjectMeta {
+    name: Option<String>,
+    resource_version: Option<String>,
+}
+
+/// `object` is an EndpointSlice, or for an `ERROR` a Status
+#[derive(Debug, Deserialize)]
+struct SliceEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    object: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SliceEndpoint {
+    #[serde(default)]
+    conditions: Conditions,
+    target_ref: Option<ObjectReference>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Conditions {
+    ready: Option<bool>,
+    terminating: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ObjectReference {
+    kind: Option<String>,
+    uid: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ready_pod_uids() {
+        let slices: EndpointSliceList = serde_json::from_value(serde_json::json!({
+            "items": [
+                {
+                    "endpoints": [
+                        { "conditions": { "ready": true }, "targetRef": { "kind": "Pod", "uid": "b" } },
+                        { "conditions": { "ready": false }, "targetRef": { "kind": "Pod", "uid": "c" } },
+                        { "conditions": {}, "targetRef": { "kind": "Pod", "uid": "a" } },
+                        { "conditions": { "ready": true, "terminating": true }, "targetRef": { "kind": "Pod", "uid": "d" } },
+                    ]
+                },
+                {
+                    "endpoints": [
+                        { "conditions": { "ready": true }, "targetRef": { "kind": "Pod", "uid": "b" } },
+                        { "conditions": { "ready": true }, "targetRef": { "kind": "Node", "uid": "e" } },
+                        { "conditions": { "ready": true } },
+                    ]
+                }
+            ]
+        }))
+        .unwrap();
+
+        assert_eq!(ready_pod_uids(&slices.items), vec!["a", "b"]);
+    }
+
+    fn event(kind: &str, name: &str, version: &str, uids: &[&str]) -> SliceEvent {
+        let endpoints: Vec<_> = uids
+            .iter()
+            .map(|uid| serde_json::json!({ "targetRef": { "kind": "Pod", "uid": uid } }))
+            .collect();
+        serde_json::from_value(serde_json::json!({
+            "type": kind,
+            "object": {
+                "metadata": { "name": name, "resourceVersion": version },
+                "endpoints": endpoints,
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_apply_watch_events() {
+        let mut slices = HashMap::new();
+        let mut version = "1".to_string();
+
+        let added = event("ADDED", "s1", "2", &["a"]);
+        assert_eq!(apply(&mut slices, &mut version, added), Applied::Changed);
+        let added = event("ADDED", "s2", "3", &["b"]);
+        assert_eq!(apply(&mut slices, &mut version, added), Applied::Changed);
+        assert_eq!(ready_pod_uids(slices.values()), vec!["a", "b"]);
+
+        let modified = event("MODIFIED", "s1", "4", &["a", "c"]);
+        assert_eq!(apply(&mut slices, &mut version, modified), Applied::Changed);
+        let deleted = event("DELETED", "s2", "5", &[]);
+        assert_eq!(apply(&mut slices, &mut version, deleted), Applied::Changed);
+        assert_eq!(ready_pod_uids(slices.values()), vec!["a", "c"]);
+
+        let bookmark = event("BOOKMARK", "", "9", &[]);
+        assert_eq!(
+            apply(&mut slices, &mut version, bookmark),
+            Applied::Unchanged
+        );
+        assert_eq!(version, "9");
+
+        let expired: SliceEvent = serde_json::from_value(serde_json::json!({
+            "type": "ERROR",
+            "object": { "kind": "Status", "code": 410, "reason": "Expired" }
+        }))
+        .unwrap();
+        assert_eq!(apply(&mut slices, &mut version, expired), Applied::Expired);
+    }
+
+    #[test]
+    fn test_pod_instance_id_is_stable() {
+        assert_eq!(pod_instance_id("uid-1"), pod_instance_id("uid-1"));
+        assert_ne!(pod_instance_id("uid-1"), pod_instance_id("uid-2"));
+    }
+}
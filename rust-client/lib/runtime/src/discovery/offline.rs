@@ -0,0 +1,331 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Starting without etcd and catching up once it is reachable.
+//!
+//! With `DYN_OFFLINE_FALLBACK=true`, a worker that cannot reach etcd at startup runs against the
+//! in-memory store instead of exiting. With `DYN_ETCD_LAZY_CONNECT=true` it does not wait for etcd
+//! at all, so workers can start ahead of the control plane. Either way its endpoints serve under a
+//! locally generated instance ID and their discovery registrations are queued here. A background
+//! task keeps trying to connect, backing off exponentially, and when it does it creates every
+//! queued key under the new primary lease. The runtime then uses the new etcd client and a store
+//! on it, so clients created from then on discover instances in etcd.
+//!
+//! A key that already exists with a different value is a conflict: someone else registered the
+//! same path while we were cut off. Theirs is kept, ours is reported through
+//! [`OfflineRegistrations::conflicts`] and the log. A registration that fails for any other
+//! reason, such as etcd going away again, is tried again with the same backoff until it lands.
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+use crate::{Result, Runtime, transports::etcd};
+
+/// Wait before the second connection attempt, doubled after every failure
+const INITIAL_RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Longest wait between connection attempts while offline
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OfflineState {
+    /// etcd has not been reachable yet, registrations are queued
+    Offline,
+    /// Connected; new registrations go straight to etcd while the queued ones are applied
+    Connected,
+    /// Connected and every queued registration either landed or conflicted
+    Reconciled,
+}
+
+/// A queued registration that could not be applied because the key was taken
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegistrationConflict {
+    pub key: String,
+    pub ours: Vec<u8>,
+    pub theirs: Vec<u8>,
+}
+
+#[derive(Clone)]
+pub struct OfflineRegistrations {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    instance_id: u64,
+    pending: Mutex<Vec<(String, Vec<u8>)>>,
+    conflicts: Mutex<Vec<RegistrationConflict>>,
+    /// Set once connected; registrations made after that go straight to etcd
+    client: Mutex<Option<etcd::Client>>,
+    state: watch::Sender<OfflineState>,
+}
+
+impl OfflineRegistrations {
+    pub(crate) fn new() -> Self {
+        let (state, _) = watch::channel(OfflineState::Offline);
+        OfflineRegistrations {
+            inner: Arc::new(Inner {
+                // Not a lease ID, but drawn from the same 63 bit space so it looks like one
+                instance_id: rand::random::<u64>() >> 1,
+                pending: Mutex::new(Vec::new()),
+                conflicts: Mutex::new(Vec::new()),
+                client: Mutex::new(None),
+                state,
+            }),
+        }
+    }
+
+    /// The instance ID endpoints serve under instead of a lease ID. Kept after reconciliation
+    /// so the NATS subjects clients were given stay valid.
+    pub fn instance_id(&self) -> u64 {
+        self.inner.instance_id
+    }
+
+    pub fn state(&self) -> watch::Receiver<OfflineState> {
+        self.inner.state.subscribe()
+    }
+
+    /// The etcd client, once connected
+    pub fn client(&self) -> Option<etcd::Client> {
+        self.inner.client.lock().clone()
+    }
+
+    /// Keys that were registered by someone else while we were offline
+    pub fn conflicts(&self) -> Vec<RegistrationConflict> {
+        self.inner.conflicts.lock().clone()
+    }
+
+    /// Register `key` now if connected, otherwise queue it for reconciliation
+    pub async fn register(&self, key: String, value: Vec<u8>) -> Result<()> {
+        let client = {
+            let client = self.inner.client.lock();
+            match client.as_ref() {
+                Some(client) => client.clone(),
+                None => {
+                    tracing::debug!(key, "etcd unreachable, queueing registration");
+                    self.inner.pending.lock().push((key, value));
+                    return Ok(());
+                }
+            }
+        };
+        self.apply(&client, key, value).await
+    }
+
+    /// Wait for the [`OfflineState::Connected`] event and return the etcd client. Never
+    /// completes if the runtime shuts down first, so race it against a cancellation token.
+    pub async fn connected(&self) -> etcd::Client {
+        let mut state = self.state();
+        loop {
+            if let Some(client) = self.client() {
+                return client;
+            }
+            // We hold the sender, so this cannot fail
+            let _ = state.changed().await;
+        }
+    }
+
+    /// Keep trying to connect to etcd until it works or the runtime shuts down, then hand the
+    /// client to `on_connect` and apply the queued registrations. The first attempt is made
+    /// straight away.
+    pub(crate) fn spawn_reconciler(
+        &self,
+        runtime: Runtime,
+        etcd_config: etcd::ClientOptions,
+        on_connect: impl FnOnce(&etcd::Client) + Send + 'static,
+    ) {
+        let this = self.clone();
+        let cancel_token = runtime.child_token();
+        runtime.secondary().spawn(async move {
+            let mut retry_interval = INITIAL_RETRY_INTERVAL;
+            let client = loop {
+                let attempt = etcd::Client::new(etcd_config.clone(), runtime.clone());
+                let result = tokio::select! {
+                    _ = cancel_token.cancelled() => return,
+                    result = attempt => result,
+                };
+                match result {
+                    Ok(client) => break client,
+                    Err(err) => tracing::debug!(%err, ?retry_interval, "etcd still unreachable"),
+                }
+                tokio::select! {
+                    _ = cancel_token.cancelled() => return,
+                    _ = tokio::time::sleep(retry_interval) => {}
+                }
+                retry_interval = next_retry_interval(retry_interval);
+            };
+            tracing::info!("Connected to etcd, reconciling offline registrations");
+            // Before anyone waiting on the state sees it connected
+            on_connect(&client);
+            this.reconcile(client, cancel_token).await;
+        });
+    }
+
+    async fn reconcile(&self, client: etcd::Client, cancel_token: CancellationToken) {
+        // Registrations arriving from here on see the client and go direct. Taking the queue
+        // under the same lock means none can slip in between.
+        let pending = {
+            let mut slot = self.inner.client.lock();
+            *slot = Some(client.clone());
+            std::mem::take(&mut *self.inner.pending.lock())
+        };
+        self.inner.state.send_replace(OfflineState::Connected);
+        let applied = apply_all(pending, &cancel_token, |key, value| {
+            self.apply(&client, key, value)
+        });
+        if applied.await {
+            self.inner.state.send_replace(OfflineState::Reconciled);
+        }
+    }
+
+    async fn apply(&self, client: &etcd::Client, key: String, value: Vec<u8>) -> Result<()> {
+        if client.kv_create(&key, value.clone(), None).await.is_ok() {
+            return Ok(());
+        }
+        // kv_create fails if the key exists, find out whether it is ours
+        let existing = client.kv_get(key.as_str(), None).await?;
+        let Some(theirs) = existing.first() else {
+            // Gone again in between, e.g. a lease expired. Try once more.
+            return client.kv_create(&key, value, None).await;
+        };
+        if theirs.value() == value.as_slice() {
+            return Ok(());
+        }
+        tracing::error!(
+            key,
+            "Offline registration conflicts with an existing key, keeping theirs"
+        );
+        self.inner.conflicts.lock().push(RegistrationConflict {
+            key,
+            ours: value,
+            theirs: theirs.value().to_vec(),
+        });
+        Ok(())
+    }
+}
+
+/// Apply every registration of `pending`, trying those that fail again with a backoff until
+/// they all went through. False if cancelled before.
+async fn apply_all<F, Fut>(
+    mut pending: Vec<(String, Vec<u8>)>,
+    cancel_token: &CancellationToken,
+    mut apply: F,
+) -> bool
+where
+    F: FnMut(String, Vec<u8>) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let mut retry_interval = INITIAL_RETRY_INTERVAL;
+    loop {
+        let mut failed = Vec::new();
+        for (key, value) in pending {
+            if let Err(err) = apply(key.clone(), value.clone()).await {
+                tracing::warn!(%err, key, ?retry_interval, "Failed to apply offline registration");
+                failed.push((key, value));
+            }
+        }
+        if failed.is_empty() {
+            return true;
+        }
+        tokio::select! {
+            _ = cancel_token.cancelled() => return false,
+            _ = tokio::time::sleep(retry_interval) => {}
+        }
+        retry_interval = next_retry_interval(retry_interval);
+        pending = failed;
+    }
+}
+
+fn next_retry_interval(current: Duration) -> Duration {
+    (current * 2).min(MAX_RETRY_INTERVAL)
+}
+
+impl std::fmt::Debug for OfflineRegistrations {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OfflineRegistrations")
+            .field("instance_id", &self.inner.instance_id)
+            .field("pending", &self.inner.pending.lock().len())
+            .field("state", &*self.inner.state.borrow())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_register_queues_while_offline() {
+        let offline = OfflineRegistrations::new();
+        assert!(offline.instance_id() < 1 << 63);
+        assert_eq!(*offline.state().borrow(), OfflineState::Offline);
+
+        offline
+            .register("a".to_string(), b"1".to_vec())
+            .await
+            .unwrap();
+        offline
+            .register("b".to_string(), b"2".to_vec())
+            .await
+            .unwrap();
+
+        assert!(offline.client().is_none());
+        assert_eq!(offline.inner.pending.lock().len(), 2);
+        assert!(offline.conflicts().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failed_registrations_are_retried() {
+        let pending = vec![
+            ("a".to_string(), b"1".to_vec()),
+            ("b".to_string(), b"2".to_vec()),
+        ];
+        // "b" fails twice, as with etcd going away again right after connecting
+        let attempts = Mutex::new(Vec::new());
+        let apply = |key: String, _| {
+            let mut attempts = attempts.lock();
+            attempts.push(key.clone());
+            let failures = attempts.iter().filter(|k| **k == "b").count();
+            async move {
+                match key.as_str() {
+                    "b" if failures <= 2 => Err(crate::error!("etcd unavailable")),
+                    _ => Ok(()),
+                }
+            }
+        };
+        let started = tokio::time::Instant::now();
+        assert!(apply_all(pending.clone(), &CancellationToken::new(), apply).await);
+        assert_eq!(*attempts.lock(), ["a", "b", "b", "b"]);
+        assert_eq!(started.elapsed(), Duration::from_millis(250 + 500));
+
+        // Never landing, until shutdown
+        let cancel_token = CancellationToken::new();
+        let failing = |_, _| async { Err(crate::error!("etcd unavailable")) };
+        let applied = apply_all(pending, &cancel_token, failing);
+        tokio::pin!(applied);
+        assert!(
+            tokio::time::timeout(MAX_RETRY_INTERVAL * 4, &mut applied)
+                .await
+                .is_err()
+        );
+        cancel_token.cancel();
+        assert!(!applied.await);
+    }
+
+    #[test]
+    fn test_retry_interval_backs_off() {
+        let intervals: Vec<_> = std::iter::successors(Some(INITIAL_RETRY_INTERVAL), |i| {
+            Some(next_retry_interval(*i))
+        })
+        .take(10)
+        .collect();
+        assert_eq!(intervals[0], Duration::from_millis(250));
+        assert_eq!(intervals[1], Duration::from_millis(500));
+        assert_eq!(intervals[6], Duration::from_secs(16));
+        assert_eq!(intervals[7], MAX_RETRY_INTERVAL);
+        assert_eq!(intervals[9], MAX_RETRY_INTERVAL);
+    }
+}
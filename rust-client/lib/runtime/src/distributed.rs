@@ -2,16 +2,27 @@
 // SPDX-License-Identifier: Apache-2.0
 
 pub use crate::component::Component;
+use crate::protocols::EndpointId;
 use crate::storage::key_value_store::{
-    EtcdStore, KeyValueStore, KeyValueStoreEnum, KeyValueStoreManager, MemoryStore,
+    EtcdStore, KeyValueStore, KeyValueStoreEnum, KeyValueStoreManager, MemoryStore, RedisOptions,
+    RedisStore,
 };
 use crate::transports::nats::DRTNatsClientPrometheusMetrics;
 use crate::{
     ErrorContext,
-    component::{self, ComponentBuilder, Endpoint, InstanceSource, Namespace},
-    discovery::DiscoveryClient,
+    component::{
+        self, ComponentBuilder, Endpoint, InstanceSigner, InstanceSource, InstanceVerifier,
+        Namespace,
+    },
+    discovery::{
+        DiscoveryClient, Federation, FederationConfig, KubernetesDiscovery, OfflineRegistrations,
+    },
+    metering::{Metering, MeteringConfig},
     metrics::PrometheusUpdateCallback,
     metrics::{MetricsHierarchy, MetricsRegistry},
+    pipeline::network::in_process::InProcessNetwork,
+    policy::{Policy, PolicyMode},
+    secrets::{EnvSecrets, SecretsProvider},
     service::ServiceClient,
     transports::{etcd, nats, tcp},
 };
@@ -20,6 +31,7 @@ use super::utils::GracefulShutdownTracker;
 use super::{Arc, DistributedRuntime, OK, OnceCell, Result, Runtime, SystemHealth, Weak, error};
 use std::sync::OnceLock;
 
+use arc_swap::{ArcSwap, ArcSwapOption};
 use derive_getters::Dissolve;
 use figment::error;
 use std::collections::HashMap;
@@ -48,19 +60,148 @@ impl std::fmt::Debug for DistributedRuntime {
 
 impl DistributedRuntime {
     pub async fn new(runtime: Runtime, config: DistributedConfig) -> Result<Self> {
-        let (etcd_config, nats_config, is_static) = config.dissolve();
+        let (
+            etcd_config,
+            nats_config,
+            is_static,
+            store_url,
+            offline_fallback,
+            lazy_connect,
+            federation,
+            secrets,
+            verify_instances,
+            policy_mode,
+            metering,
+            nats_clusters,
+        ) = config.dissolve();
+
+        let secrets: Arc<dyn SecretsProvider> = secrets.unwrap_or_else(|| Arc::new(EnvSecrets));
+        let instance_signer = InstanceSigner::from_secrets(secrets.as_ref()).await?;
+        if let Some(signer) = &instance_signer {
+            let public_key = signer.public_key();
+            tracing::info!(public_key, "Signing instance registrations");
+        }
+        let instance_verifier = if verify_instances {
+            Some(Arc::new(
+                InstanceVerifier::from_secrets(secrets.as_ref()).await?,
+            ))
+        } else {
+            None
+        };
 
         let runtime_clone = runtime.clone();
 
+        let nats_client = Some(nats_config.clone().connect().await?);
+        let nats_clusters = match nats_clusters {
+            Some(clusters) => Some(Arc::new(clusters.connect(&nats_config).await?)),
+            None => None,
+        };
+
+        let mut kubernetes = None;
+        let mut offline = None;
+        // Without etcd nor a lease, instances are told apart by an ID of our own
+        let mut store_instance_id = None;
         let (etcd_client, store) = if is_static {
             (None, KeyValueStoreManager::memory())
         } else {
-            let etcd_client = etcd::Client::new(etcd_config.clone(), runtime_clone).await?;
-            let store = KeyValueStoreManager::etcd(etcd_client.clone());
-            (Some(etcd_client), store)
+            match store_url {
+                Some(StoreUrl::Kubernetes) => {
+                    kubernetes = Some(KubernetesDiscovery::from_in_cluster()?);
+                    (None, KeyValueStoreManager::memory())
+                }
+                // `DistributedConfig::from_settings` already put the URL's hosts in `etcd_config`
+                None | Some(StoreUrl::Etcd(_)) if lazy_connect => {
+                    tracing::info!("Connecting to etcd lazily, registrations are queued");
+                    offline = Some(OfflineRegistrations::new());
+                    (None, KeyValueStoreManager::memory())
+                }
+                None | Some(StoreUrl::Etcd(_)) => {
+                    match etcd::Client::new(etcd_config.clone(), runtime_clone).await {
+                        Ok(etcd_client) => {
+                            let store = KeyValueStoreManager::etcd(etcd_client.clone());
+                            (Some(etcd_client), store)
+                        }
+                        Err(err) if offline_fallback => {
+                            tracing::warn!(
+                                %err,
+                                "etcd unreachable, starting offline; registrations are queued"
+                            );
+                            offline = Some(OfflineRegistrations::new());
+                            (None, KeyValueStoreManager::memory())
+                        }
+                        Err(err) => return Err(err),
+                    }
+                }
+                Some(StoreUrl::Nats(_)) => {
+                    let nats_client = nats_client.clone().expect("NATS client connected above");
+                    let store = KeyValueStoreManager::nats(nats_client, EndpointId::default());
+                    store_instance_id = Some(rand::random::<u64>() >> 1);
+                    (None, store)
+                }
+                Some(StoreUrl::Memory) => {
+                    store_instance_id = Some(rand::random::<u64>() >> 1);
+                    (None, KeyValueStoreManager::memory())
+                }
+                Some(StoreUrl::Redis(url)) => {
+                    let store = RedisStore::new(RedisOptions::from_url(&url)?, &runtime).await?;
+                    store_instance_id = Some(rand::random::<u64>() >> 1);
+                    (None, KeyValueStoreManager::redis(store))
+                }
+            }
         };
 
-        let nats_client = Some(nats_config.clone().connect().await?);
+        // The store's watches are restarted under the runtime's supervisor, and stop with it
+        let store = store.with_supervisor(runtime.supervisor().child("store"));
+
+        let policy = match policy_mode {
+            PolicyMode::Off => None,
+            mode => {
+                let token = runtime.primary_token();
+                Some(Policy::watch(&store, crate::policy::identity(), mode, token).await)
+            }
+        };
+
+        let metering = match metering {
+            Some(config) => Some(Metering::start(
+                config,
+                nats_client.clone(),
+                &store,
+                &runtime.supervisor().child("metering"),
+            )?),
+            None => None,
+        };
+
+        let federation = match federation {
+            Some(config) if etcd_client.is_some() => {
+                Some(Federation::connect(config, &runtime).await)
+            }
+            Some(_) => {
+                tracing::warn!("DYN_FEDERATED_REGIONS needs etcd discovery, ignoring it");
+                None
+            }
+            None => None,
+        };
+
+        // Before anything registers or creates metrics, which carry the worker ID
+        let worker_id = crate::identity::init(&store, etcd_client.as_ref()).await?;
+        if let (Some(worker_id), Some(client)) = (worker_id, &etcd_client)
+            && client.lease_id() != 0
+            && let Err(err) = crate::identity::register(client, worker_id).await
+        {
+            tracing::warn!(%err, %worker_id, "Failed to record the worker's lease");
+        }
+        // Nothing removes its lease-less keys when it dies, the janitor goes by this instead
+        if let (Some(worker_id), Some(client)) = (worker_id, &etcd_client)
+            && client.lease_id() == 0
+            && !client.is_read_only()
+        {
+            runtime.secondary().spawn(etcd::heartbeat_owner(
+                client.clone(),
+                etcd::JanitorConfig::default(),
+                worker_id.to_string(),
+                runtime.child_token(),
+            ));
+        }
 
         // Start system status server for health and metrics if enabled in configuration
         let config = crate::config::RuntimeConfig::from_settings().unwrap_or_default();
@@ -86,22 +227,40 @@ impl DistributedRuntime {
 
         let distributed_runtime = Self {
             runtime,
-            etcd_client,
-            store,
+            etcd_client: Arc::new(ArcSwapOption::from(etcd_client.map(Arc::new))),
+            store: Arc::new(ArcSwap::from_pointee(store)),
             nats_client,
+            nats_clusters,
             tcp_server: Arc::new(OnceCell::new()),
             system_status_server: Arc::new(OnceLock::new()),
             component_registry: component::Registry::new(),
             is_static,
+            kubernetes,
+            offline,
+            federation,
+            in_process: None,
+            store_instance_id,
             instance_sources: Arc::new(Mutex::new(HashMap::new())),
+            instance_signer: instance_signer.map(Arc::new),
+            instance_verifier,
+            policy,
+            metering,
             metrics_registry: crate::MetricsRegistry::new(),
             system_health,
         };
 
+        if let Some(offline) = &distributed_runtime.offline {
+            // Started on the in-memory store, switch to etcd once it can be reached
+            let drt = distributed_runtime.clone();
+            offline.spawn_reconciler(drt.runtime.clone(), etcd_config, move |client| {
+                drt.use_etcd(client)
+            });
+        }
+
         if let Some(nats_client_for_metrics) = nats_client_for_metrics {
             let nats_client_metrics = DRTNatsClientPrometheusMetrics::new(
                 &distributed_runtime,
-                nats_client_for_metrics.client().clone(),
+                &nats_client_for_metrics,
             )?;
             // Register a callback to update NATS client metrics on the DRT's metrics registry
             let nats_client_callback = Arc::new({
@@ -116,12 +275,41 @@ impl DistributedRuntime {
                 .add_update_callback(nats_client_callback);
         }
 
+        distributed_runtime.metrics_registry.add_metric(Box::new(
+            crate::storage::key_value_store::watch_propagation(),
+        ))?;
+        distributed_runtime.metrics_registry.add_metric(Box::new(
+            crate::storage::key_value_store::integrity_failures(),
+        ))?;
+
+        let process_metrics = crate::metrics::process::ProcessMetrics::new(&distributed_runtime)?;
+        distributed_runtime
+            .metrics_registry
+            .add_update_callback(Arc::new(move || {
+                process_metrics.update();
+                Ok(())
+            }));
+
+        let transport_metrics =
+            crate::transports::accounting::TransportMetrics::new(&distributed_runtime)?;
+        distributed_runtime
+            .metrics_registry
+            .add_update_callback(Arc::new(move || {
+                transport_metrics.update();
+                Ok(())
+            }));
+
         // Initialize the uptime gauge in SystemHealth
         distributed_runtime
             .system_health
             .lock()
             .initialize_uptime_gauge(&distributed_runtime)?;
 
+        // Pushing metrics is optional like the status server, so failing to start it isn't fatal
+        if let Err(err) = crate::metrics::export::start(&distributed_runtime, &config).await {
+            tracing::error!(%err, "Metrics exporter startup failed");
+        }
+
         // Handle system status server initialization
         if let Some(cancel_token) = cancel_token {
             // System server is enabled - start both the state and HTTP server
@@ -192,14 +380,61 @@ impl DistributedRuntime {
         Ok(distributed_runtime)
     }
 
+    /// A worker of an in-process cluster: no etcd or NATS, discovery through the shared
+    /// `store` and requests through `network`. See [`crate::testing::InProcessCluster`].
+    pub(crate) fn in_process(
+        runtime: Runtime,
+        network: InProcessNetwork,
+        store: MemoryStore,
+    ) -> Result<Self> {
+        let config = crate::config::RuntimeConfig::from_settings().unwrap_or_default();
+        let system_health = Arc::new(parking_lot::Mutex::new(SystemHealth::new(
+            config.starting_health_status.clone(),
+            config.use_endpoint_health_status.clone(),
+            config.system_health_path.clone(),
+            config.system_live_path.clone(),
+        )));
+        let store_instance_id = Some(network.instance_id());
+        let store = KeyValueStoreManager::shared_memory(store)
+            .with_supervisor(runtime.supervisor().child("store"));
+        let distributed_runtime = Self {
+            runtime,
+            etcd_client: Arc::new(ArcSwapOption::empty()),
+            store: Arc::new(ArcSwap::from_pointee(store)),
+            nats_client: None,
+            nats_clusters: None,
+            tcp_server: Arc::new(OnceCell::new()),
+            system_status_server: Arc::new(OnceLock::new()),
+            component_registry: component::Registry::new(),
+            is_static: false,
+            kubernetes: None,
+            offline: None,
+            federation: None,
+            in_process: Some(network),
+            store_instance_id,
+            instance_sources: Arc::new(Mutex::new(HashMap::new())),
+            instance_signer: None,
+            instance_verifier: None,
+            policy: None,
+            metering: None,
+            metrics_registry: crate::MetricsRegistry::new(),
+            system_health,
+        };
+        distributed_runtime
+            .system_health
+            .lock()
+            .initialize_uptime_gauge(&distributed_runtime)?;
+        Ok(distributed_runtime)
+    }
+
     pub async fn from_settings(runtime: Runtime) -> Result<Self> {
-        let config = DistributedConfig::from_settings(false);
+        let config = DistributedConfig::from_settings(false)?;
         Self::new(runtime, config).await
     }
 
     // Call this if you are using static workers that do not need etcd-based discovery.
     pub async fn from_settings_without_discovery(runtime: Runtime) -> Result<Self> {
-        let config = DistributedConfig::from_settings(true);
+        let config = DistributedConfig::from_settings(true)?;
         Self::new(runtime, config).await
     }
 
@@ -214,7 +449,7 @@ impl DistributedRuntime {
     /// The etcd lease all our components will be attached to.
     /// Not available for static workers.
     pub fn primary_lease(&self) -> Option<etcd::Lease> {
-        self.etcd_client.as_ref().map(|c| c.primary_lease())
+        self.etcd_client().map(|c| c.primary_lease())
     }
 
     pub fn shutdown(&self) {
@@ -241,21 +476,24 @@ impl DistributedRuntime {
     pub(crate) fn discovery_client(&self, namespace: impl Into<String>) -> DiscoveryClient {
         DiscoveryClient::new(
             namespace.into(),
-            self.etcd_client
-                .clone()
+            self.etcd_client()
                 .expect("Attempt to get discovery_client on static DistributedRuntime"),
         )
     }
 
-    pub(crate) fn service_client(&self) -> Option<ServiceClient> {
-        self.nats_client().map(|nc| ServiceClient::new(nc.clone()))
+    pub(crate) fn service_client(&self, namespace: &str) -> Option<ServiceClient> {
+        self.nats_client_for(namespace)
+            .map(|nc| ServiceClient::new(nc.clone()))
     }
 
     pub async fn tcp_server(&self) -> Result<Arc<tcp::server::TcpStreamServer>> {
         Ok(self
             .tcp_server
             .get_or_try_init(async move {
-                let options = tcp::server::ServerOptions::default();
+                let options = tcp::server::ServerOptions {
+                    dual_stack: crate::config::env_is_truthy("DYN_TCP_DUAL_STACK"),
+                    ..Default::default()
+                };
                 let server = tcp::server::TcpStreamServer::new(options).await?;
                 OK(server)
             })
@@ -267,6 +505,41 @@ impl DistributedRuntime {
         self.nats_client.as_ref()
     }
 
+    /// The client of the cluster `namespace` is routed to by `DYN_NATS_NAMESPACES`, the default
+    /// one if none. See [`nats::NatsClusters`].
+    pub fn nats_client_for(&self, namespace: &str) -> Option<&nats::Client> {
+        self.nats_clusters
+            .as_ref()
+            .and_then(|clusters| clusters.client(namespace))
+            .or(self.nats_client())
+    }
+
+    /// What goes in front of the NATS subjects of `namespace`, empty unless `DYN_NATS_NAMESPACES`
+    /// gives it a prefix
+    pub fn nats_subject_prefix(&self, namespace: &str) -> &str {
+        self.nats_clusters
+            .as_ref()
+            .map_or("", |clusters| clusters.subject_prefix(namespace))
+    }
+
+    /// The NATS clusters besides the default one, if `DYN_NATS_CLUSTERS` is set
+    pub fn nats_clusters(&self) -> Option<&Arc<nats::NatsClusters>> {
+        self.nats_clusters.as_ref()
+    }
+
+    /// Add a user check to the system status server's `/healthz` or `/readyz` probe. See
+    /// [`SystemHealth::add_probe_check`].
+    pub fn add_probe_check(
+        &self,
+        probe: crate::Probe,
+        name: impl Into<String>,
+        check: impl Fn() -> std::result::Result<(), String> + Send + Sync + 'static,
+    ) {
+        self.system_health
+            .lock()
+            .add_probe_check(probe, name, check);
+    }
+
     /// Get system status server information if available
     pub fn system_status_server_info(
         &self,
@@ -276,19 +549,32 @@ impl DistributedRuntime {
 
     // todo(ryan): deprecate this as we move to Discovery traits and Component Identifiers
     pub fn etcd_client(&self) -> Option<etcd::Client> {
-        self.etcd_client.clone()
+        self.etcd_client.load().as_deref().cloned()
     }
 
     // Deprecated but our CI blocks us using the feature currently.
     //#[deprecated(note = "Use KeyValueStoreManager via store(); this will be removed")]
     pub fn deprecated_etcd_client(&self) -> Option<etcd::Client> {
-        self.etcd_client.clone()
+        self.etcd_client()
     }
 
     /// An interface to store things. Will eventually replace `etcd_client`.
     /// Currently does key-value, but will grow to include whatever we need to store.
-    pub fn store(&self) -> &KeyValueStoreManager {
-        &self.store
+    ///
+    /// A runtime started without etcd switches from the in-memory store to etcd once it
+    /// connects, so hold on to the returned manager only as long as that doesn't matter.
+    pub fn store(&self) -> KeyValueStoreManager {
+        KeyValueStoreManager::clone(&self.store.load())
+    }
+
+    /// Switch to `client` and a store on it, once connected after starting without etcd.
+    /// Clients created from here on discover instances in etcd; what already runs on the
+    /// in-memory store, such as the access policy watch, stays there.
+    fn use_etcd(&self, client: &etcd::Client) {
+        let store = KeyValueStoreManager::etcd(client.clone())
+            .with_supervisor(self.runtime.supervisor().child("store"));
+        self.store.store(Arc::new(store));
+        self.etcd_client.store(Some(Arc::new(client.clone())));
     }
 
     pub fn child_token(&self) -> CancellationToken {
@@ -302,6 +588,169 @@ impl DistributedRuntime {
     pub fn instance_sources(&self) -> Arc<Mutex<HashMap<Endpoint, Weak<InstanceSource>>>> {
         self.instance_sources.clone()
     }
+
+    /// Kubernetes discovery, when configured with `DISCOVERY_URL=k8s://`
+    pub fn kubernetes(&self) -> Option<&KubernetesDiscovery> {
+        self.kubernetes.as_ref()
+    }
+
+    /// Queued registrations and reconciliation state, when started without etcd under
+    /// `DYN_OFFLINE_FALLBACK=true` or `DYN_ETCD_LAZY_CONNECT=true`
+    pub fn offline(&self) -> Option<&OfflineRegistrations> {
+        self.offline.as_ref()
+    }
+
+    /// The remote regions whose instances clients fail over to, when configured with
+    /// `DYN_FEDERATED_REGIONS`
+    pub fn federation(&self) -> Option<&Federation> {
+        self.federation.as_ref()
+    }
+
+    /// The ID this worker's instances register under in [`Self::store`], when they are
+    /// discovered through it instead of etcd: with a `mem://`, `nats://` or `redis://`
+    /// `DISCOVERY_URL`, or
+    /// in an in-process cluster
+    pub fn store_instance_id(&self) -> Option<u64> {
+        self.store_instance_id
+    }
+
+    /// This worker's handle on its in-process cluster's network, for runtimes created by
+    /// [`crate::testing::InProcessCluster`]
+    pub fn in_process_network(&self) -> Option<&InProcessNetwork> {
+        self.in_process.as_ref()
+    }
+
+    /// Signs this worker's instance registrations, when its secrets provider has a key. See
+    /// [`component::signing`].
+    pub fn instance_signer(&self) -> Option<&Arc<InstanceSigner>> {
+        self.instance_signer.as_ref()
+    }
+
+    /// Checks the registrations clients route to, when started with `DYN_VERIFY_INSTANCES`
+    pub fn instance_verifier(&self) -> Option<&Arc<InstanceVerifier>> {
+        self.instance_verifier.as_ref()
+    }
+
+    /// The access policy registrations and requests are checked against, when `DYN_POLICY`
+    /// is set. See [`crate::policy`].
+    pub fn policy(&self) -> Option<&Policy> {
+        self.policy.as_ref()
+    }
+
+    /// Counts the usage of each tenant, when `DYN_METERING` is set. See [`crate::metering`].
+    pub fn metering(&self) -> Option<&Arc<Metering>> {
+        self.metering.as_ref()
+    }
+}
+
+/// The key-value store the runtime uses, and where it discovers instances, chosen with a
+/// single `DISCOVERY_URL`:
+///
+/// - `etcd://host:2379[,host2:2379]` (or `etcds://` for TLS)
+/// - `nats://host:4222`, using JetStream key-value buckets on the same server as the transport
+/// - `mem://`, a process local [`crate::storage::key_value_store::MemoryStore`]
+/// - `redis://[[user]:password@]host:6379[/db]` (or `rediss://` for TLS), see
+///   [`crate::storage::key_value_store::RedisStore`]
+/// - `k8s://`, instances are the ready pods behind a labelled Service of the cluster the
+///   process runs in, see [`KubernetesDiscovery`]; the store is process local
+///
+/// With `nats://`, `mem://` and `redis://`, instances register in and are discovered from that
+/// store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreUrl {
+    /// etcd endpoints as `http(s)://host:port`
+    Etcd(Vec<String>),
+    Nats(String),
+    Memory,
+    Kubernetes,
+    /// The URL as given, see [`RedisOptions::from_url`]
+    Redis(String),
+}
+
+impl StoreUrl {
+    /// From `DISCOVERY_URL`, or None if it is not set
+    pub fn from_env() -> Result<Option<Self>> {
+        match std::env::var("DISCOVERY_URL") {
+            Ok(url) if !url.trim().is_empty() => url.parse().map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Connect to the store on its own, without a primary lease, NATS transport or the rest
+    /// of a [`DistributedRuntime`]. For tools that only talk to the store.
+    pub async fn connect(&self, runtime: Runtime) -> Result<KeyValueStoreManager> {
+        let supervisor = runtime.supervisor().child("store");
+        let store = match self {
+            StoreUrl::Etcd(hosts) => {
+                let options = etcd::ClientOptions {
+                    etcd_url: hosts.clone(),
+                    attach_lease: false,
+                    dns: None,
+                    ..Default::default()
+                };
+                let client = etcd::Client::new(options, runtime).await?;
+                KeyValueStoreManager::etcd(client)
+            }
+            StoreUrl::Nats(server) => {
+                let client = nats::ClientOptions::builder()
+                    .server(server.clone())
+                    .build()?
+                    .connect()
+                    .await?;
+                KeyValueStoreManager::nats(client, EndpointId::default())
+            }
+            StoreUrl::Memory => KeyValueStoreManager::memory(),
+            StoreUrl::Redis(url) => {
+                let store = RedisStore::new(RedisOptions::from_url(url)?, &runtime).await?;
+                KeyValueStoreManager::redis(store)
+            }
+            StoreUrl::Kubernetes => {
+                return Err(error!("Kubernetes discovery has no store to connect to"));
+            }
+        };
+        Ok(store.with_supervisor(supervisor))
+    }
+}
+
+impl std::str::FromStr for StoreUrl {
+    type Err = anyhow::Error;
+
+    fn from_str(url: &str) -> Result<Self> {
+        let url = url.trim();
+        let Some((scheme, rest)) = url.split_once("://") else {
+            return Err(error!("DISCOVERY_URL '{url}' has no scheme"));
+        };
+        let etcd_hosts = |http: &str| -> Result<Vec<String>> {
+            let hosts: Vec<String> = rest
+                .trim_end_matches('/')
+                .split(',')
+                .map(str::trim)
+                .filter(|h| !h.is_empty())
+                .map(|h| format!("{http}://{h}"))
+                .collect();
+            if hosts.is_empty() {
+                return Err(error!("DISCOVERY_URL '{url}' has no etcd hosts"));
+            }
+            Ok(hosts)
+        };
+        match scheme.to_lowercase().as_str() {
+            "etcd" => Ok(StoreUrl::Etcd(etcd_hosts("http")?)),
+            "etcds" => Ok(StoreUrl::Etcd(etcd_hosts("https")?)),
+            "nats" => Ok(StoreUrl::Nats(url.to_string())),
+            "mem" | "memory" => Ok(StoreUrl::Memory),
+            "k8s" | "kubernetes" if rest.trim_end_matches('/').is_empty() => {
+                Ok(StoreUrl::Kubernetes)
+            }
+            "k8s" | "kubernetes" => Err(error!(
+                "DISCOVERY_URL '{url}': Kubernetes discovery is of the cluster it runs in, no host"
+            )),
+            "redis" | "rediss" => {
+                RedisOptions::from_url(url)?;
+                Ok(StoreUrl::Redis(url.to_string()))
+            }
+            other => Err(error!("Unknown DISCOVERY_URL scheme '{other}'")),
+        }
+    }
 }
 
 #[derive(Dissolve)]
@@ -309,15 +758,94 @@ pub struct DistributedConfig {
     pub etcd_config: etcd::ClientOptions,
     pub nats_config: nats::ClientOptions,
     pub is_static: bool,
+    /// From `DISCOVERY_URL`. None keeps the etcd and NATS settings from their own variables.
+    pub store_url: Option<StoreUrl>,
+    /// Start with the in-memory store if etcd is unreachable, from `DYN_OFFLINE_FALLBACK`.
+    /// See [`OfflineRegistrations`].
+    pub offline_fallback: bool,
+    /// Don't wait for etcd: start on the in-memory store and connect in the background, from
+    /// `DYN_ETCD_LAZY_CONNECT`. See [`OfflineRegistrations`].
+    pub lazy_connect: bool,
+    /// Other regions' etcd clusters to discover instances in, from `DYN_FEDERATED_REGIONS`.
+    /// See [`Federation`].
+    pub federation: Option<FederationConfig>,
+    /// From `DYN_SECRETS_PROVIDER`, for the instance signing and trusted keys. The environment
+    /// if None. The etcd and NATS configs hold their own.
+    pub secrets: Option<Arc<dyn SecretsProvider>>,
+    /// Route only to instances with a trusted signature, from `DYN_VERIFY_INSTANCES`. See
+    /// [`component::signing`].
+    pub verify_instances: bool,
+    /// Whether to check registrations and requests against the access policy, from
+    /// `DYN_POLICY`. See [`crate::policy`].
+    pub policy_mode: PolicyMode,
+    /// Where to publish the usage of each tenant, from `DYN_METERING`. See [`crate::metering`].
+    pub metering: Option<MeteringConfig>,
+    /// Other NATS clusters and the namespaces routed to them, from `DYN_NATS_CLUSTERS` and
+    /// `DYN_NATS_NAMESPACES`. See [`nats::NatsClusters`].
+    pub nats_clusters: Option<nats::NatsClustersConfig>,
 }
 
 impl DistributedConfig {
-    pub fn from_settings(is_static: bool) -> DistributedConfig {
-        DistributedConfig {
-            etcd_config: etcd::ClientOptions::default(),
-            nats_config: nats::ClientOptions::default(),
-            is_static,
+    /// Fails if `DISCOVERY_URL` is set but can't be used, rather than starting on another store
+    pub fn from_settings(is_static: bool) -> Result<DistributedConfig> {
+        let store_url = StoreUrl::from_env()?;
+        let federation = FederationConfig::from_env().unwrap_or_else(|err| {
+            tracing::warn!(%err, "Ignoring DYN_FEDERATED_REGIONS");
+            None
+        });
+        let secrets = crate::secrets::from_env().unwrap_or_else(|err| {
+            tracing::warn!(%err, "Ignoring DYN_SECRETS_PROVIDER");
+            None
+        });
+        // A mistyped mode shouldn't leave the cluster open
+        let policy_mode = PolicyMode::from_env().unwrap_or_else(|err| {
+            tracing::warn!(%err, "Enforcing the access policy");
+            PolicyMode::Enforce
+        });
+        let metering = MeteringConfig::from_env().unwrap_or_else(|err| {
+            tracing::warn!(%err, "Ignoring DYN_METERING");
+            None
+        });
+        let nats_clusters = nats::NatsClustersConfig::from_env().unwrap_or_else(|err| {
+            tracing::warn!(%err, "Ignoring DYN_NATS_CLUSTERS and DYN_NATS_NAMESPACES");
+            None
+        });
+
+        let mut etcd_config = etcd::ClientOptions {
+            shared: crate::config::env_is_truthy("DYN_ETCD_SHARED_CONNECTION"),
+            ..Default::default()
+        };
+        let mut nats_config = nats::ClientOptions::default();
+        match &store_url {
+            Some(StoreUrl::Etcd(hosts)) => {
+                etcd_config.etcd_url = hosts.clone();
+                etcd_config.dns = None;
+            }
+            Some(StoreUrl::Nats(server)) => {
+                nats_config = nats::ClientOptions::builder()
+                    .server(server.clone())
+                    .build()
+                    .with_context(|| format!("DISCOVERY_URL '{server}'"))?;
+            }
+            _ => {}
         }
+        etcd_config.secrets = secrets.clone();
+        nats_config.secrets = secrets.clone();
+
+        Ok(DistributedConfig {
+            etcd_config,
+            nats_config,
+            is_static,
+            store_url,
+            offline_fallback: crate::config::env_is_truthy("DYN_OFFLINE_FALLBACK"),
+            lazy_connect: crate::config::env_is_truthy("DYN_ETCD_LAZY_CONNECT"),
+            federation,
+            secrets,
+            verify_instances: crate::config::env_is_truthy("DYN_VERIFY_INSTANCES"),
+            policy_mode,
+            metering,
+            nats_clusters,
+        })
     }
 
     pub fn for_cli() -> DistributedConfig {
@@ -325,6 +853,15 @@ impl DistributedConfig {
             etcd_config: etcd::ClientOptions::default(),
             nats_config: nats::ClientOptions::default(),
             is_static: false,
+            store_url: None,
+            offline_fallback: false,
+            lazy_connect: false,
+            federation: None,
+            secrets: None,
+            verify_instances: false,
+            policy_mode: PolicyMode::Off,
+            metering: None,
+            nats_clusters: None,
         };
 
         config.etcd_config.attach_lease = false;
@@ -352,6 +889,12 @@ pub mod distributed_test_utils {
 #[cfg(all(test, feature = "integration"))]
 mod tests {
     use super::distributed_test_utils::create_test_drt_async;
+    use crate::pipeline::{
+        AsyncEngine, AsyncEngineContextProvider, Error, ManyOut, ResponseStream, SingleIn,
+        async_trait, network::Ingress,
+    };
+    use crate::protocols::annotated::Annotated;
+    use std::time::Duration;
 
     #[tokio::test]
     async fn test_drt_uptime_after_delay_system_disabled() {
@@ -404,4 +947,143 @@ mod tests {
         })
         .await;
     }
+
+    struct Echo;
+
+    #[async_trait]
+    impl AsyncEngine<SingleIn<String>, ManyOut<Annotated<String>>, Error> for Echo {
+        async fn generate(
+            &self,
+            input: SingleIn<String>,
+        ) -> crate::Result<ManyOut<Annotated<String>>> {
+            let (data, ctx) = input.into_parts();
+            let stream = futures::stream::iter([Annotated::from_data(data)]);
+            Ok(ResponseStream::new(Box::pin(stream), ctx.context()))
+        }
+    }
+
+    fn serve(drt: &crate::DistributedRuntime) -> tokio::task::JoinHandle<crate::Result<()>> {
+        let drt = drt.clone();
+        tokio::spawn(async move {
+            let mut component = drt.namespace("discovery")?.component("echo")?;
+            component.add_stats_service().await?;
+            let ingress = Ingress::for_engine(std::sync::Arc::new(Echo))?;
+            component
+                .endpoint("generate")
+                .endpoint_builder()
+                .handler(ingress)
+                .start()
+                .await
+        })
+    }
+
+    async fn discover(drt: &crate::DistributedRuntime, instance_id: u64) {
+        let component = drt
+            .namespace("discovery")
+            .unwrap()
+            .component("echo")
+            .unwrap();
+        let client = component.endpoint("generate").client().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !client.instance_ids().contains(&instance_id) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("{instance_id:x} not discovered"));
+    }
+
+    #[tokio::test]
+    async fn test_mem_discovery_url_registers_in_store() {
+        temp_env::async_with_vars([("DISCOVERY_URL", Some("mem://"))], async {
+            let rt = crate::Runtime::from_current().unwrap();
+            let drt = crate::DistributedRuntime::from_settings(rt).await.unwrap();
+            assert!(drt.etcd_client().is_none());
+            let instance_id = drt.store_instance_id().expect("registers in the store");
+
+            let server = serve(&drt);
+            discover(&drt, instance_id).await;
+
+            drt.shutdown();
+            let _ = server.await;
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_discover_after_connecting_late() {
+        let rt = crate::Runtime::from_current().unwrap();
+        let worker = crate::DistributedRuntime::from_settings(rt).await.unwrap();
+        let instance_id = worker.primary_lease().unwrap().id();
+        let server = serve(&worker);
+
+        temp_env::async_with_vars([("DYN_ETCD_LAZY_CONNECT", Some("true"))], async {
+            let rt = crate::Runtime::from_current().unwrap();
+            let late = crate::DistributedRuntime::from_settings(rt).await.unwrap();
+            let offline = late.offline().expect("started without etcd").clone();
+            tokio::time::timeout(Duration::from_secs(10), offline.connected())
+                .await
+                .expect("connected to etcd");
+
+            assert!(late.etcd_client().is_some());
+            discover(&late, instance_id).await;
+        })
+        .await;
+
+        worker.shutdown();
+        let _ = server.await;
+    }
+}
+
+#[cfg(test)]
+mod store_url_tests {
+    use super::StoreUrl;
+
+    #[test]
+    fn test_parse_store_url() {
+        assert_eq!(
+            "etcd://a:2379,b:2379".parse::<StoreUrl>().unwrap(),
+            StoreUrl::Etcd(vec![
+                "http://a:2379".to_string(),
+                "http://b:2379".to_string()
+            ])
+        );
+        assert_eq!(
+            "etcds://a:2379/".parse::<StoreUrl>().unwrap(),
+            StoreUrl::Etcd(vec!["https://a:2379".to_string()])
+        );
+        assert_eq!(
+            "nats://localhost:4222".parse::<StoreUrl>().unwrap(),
+            StoreUrl::Nats("nats://localhost:4222".to_string())
+        );
+        assert_eq!("mem://".parse::<StoreUrl>().unwrap(), StoreUrl::Memory);
+        assert_eq!("k8s://".parse::<StoreUrl>().unwrap(), StoreUrl::Kubernetes);
+        assert_eq!(
+            "rediss://:secret@cache:6380/1".parse::<StoreUrl>().unwrap(),
+            StoreUrl::Redis("rediss://:secret@cache:6380/1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_store_url_errors() {
+        assert!("localhost:2379".parse::<StoreUrl>().is_err());
+        assert!("etcd://".parse::<StoreUrl>().is_err());
+        assert!("zk://localhost:2181".parse::<StoreUrl>().is_err());
+        assert!("redis://localhost/cache".parse::<StoreUrl>().is_err());
+        assert!("k8s://cluster.example.com".parse::<StoreUrl>().is_err());
+    }
+
+    #[test]
+    fn test_bad_discovery_url_is_an_error() {
+        temp_env::with_var("DISCOVERY_URL", Some("redis://"), || {
+            assert!(super::DistributedConfig::from_settings(false).is_err());
+        });
+        temp_env::with_var("DISCOVERY_URL", Some("localhost:2379"), || {
+            assert!(super::DistributedConfig::from_settings(false).is_err());
+        });
+        temp_env::with_var("DISCOVERY_URL", Some("mem://"), || {
+            let config = super::DistributedConfig::from_settings(false).unwrap();
+            assert_eq!(config.store_url, Some(StoreUrl::Memory));
+        });
+    }
 }
@@ -3,9 +3,11 @@
 
 use crate::component::{Client, Component, Endpoint, Instance};
 use crate::pipeline::PushRouter;
+use crate::pipeline::context::CallerContext;
 use crate::pipeline::{AsyncEngine, Context, ManyOut, SingleIn};
 use crate::protocols::annotated::Annotated;
 use crate::protocols::maybe_error::MaybeError;
+use crate::utils::tasks::supervisor::{RestartPolicy, Supervisor};
 use crate::{DistributedRuntime, HealthStatus, SystemHealth};
 use futures::StreamExt;
 use parking_lot::Mutex;
@@ -17,6 +19,9 @@ use tokio::task::JoinHandle;
 use tokio::time::{MissedTickBehavior, interval};
 use tracing::{debug, error, info, warn};
 
+/// The [origin](crate::pipeline::context::caller) of canary health check requests
+pub const HEALTH_CHECK_ORIGIN: &str = "health_check";
+
 /// Configuration for health check behavior
 pub struct HealthCheckConfig {
     /// Wait time before sending canary health checks (when no activity)
@@ -50,11 +55,15 @@ pub struct HealthCheckManager {
     /// Track per-endpoint health check tasks
     /// Maps: endpoint_subject -> task_handle
     endpoint_tasks: Arc<Mutex<HashMap<String, JoinHandle<()>>>>,
+    /// Runs the per-endpoint tasks
+    supervisor: Supervisor,
 }
 
 impl HealthCheckManager {
     pub fn new(drt: DistributedRuntime, config: HealthCheckConfig) -> Self {
+        let supervisor = drt.runtime().supervisor().child("health_check");
         Self {
+            supervisor,
             drt,
             config,
             router_cache: Arc::new(Mutex::new(HashMap::new())),
@@ -138,46 +147,54 @@ impl HealthCheckManager {
                 .expect("Notifier should exist for registered endpoint")
         };
 
-        let task = tokio::spawn(async move {
-            let endpoint_subject = endpoint_subject_clone;
-            info!("Health check task started for: {}", endpoint_subject);
-
-            loop {
-                // Wait for either timeout or activity notification
-                tokio::select! {
-                    _ = tokio::time::sleep(canary_wait) => {
-                        // Timeout - send health check for this specific endpoint
-                        info!("Canary timer expired for {}, sending health check", endpoint_subject);
-
-                        // Get the health check payload for this endpoint
-                        let target = {
-                            let system_health = manager.drt.system_health.lock();
-                            system_health.get_health_check_target(&endpoint_subject)
-                        };
-
-                        if let Some(target) = target {
-                            if let Err(e) = manager.send_health_check_request(&endpoint_subject, &target.payload).await {
-                                error!("Failed to send health check for {}: {}", endpoint_subject, e);
+        // Restarted if it panics; it only ends by itself when the target is gone
+        let policy = RestartPolicy::default();
+        let task = self.supervisor.spawn(&endpoint_subject, policy, move || {
+            let manager = manager.clone();
+            let notifier = notifier.clone();
+            let endpoint_subject_clone = endpoint_subject_clone.clone();
+            async move {
+                let endpoint_subject = endpoint_subject_clone;
+                info!("Health check task started for: {}", endpoint_subject);
+
+                loop {
+                    // Wait for either timeout or activity notification
+                    tokio::select! {
+                        _ = tokio::time::sleep(canary_wait) => {
+                            // Timeout - send health check for this specific endpoint
+                            info!("Canary timer expired for {}, sending health check", endpoint_subject);
+
+                            // Get the health check payload for this endpoint
+                            let target = {
+                                let system_health = manager.drt.system_health.lock();
+                                system_health.get_health_check_target(&endpoint_subject)
+                            };
+
+                            if let Some(target) = target {
+                                if let Err(e) = manager.send_health_check_request(&endpoint_subject, &target.payload).await {
+                                    error!("Failed to send health check for {}: {}", endpoint_subject, e);
+                                }
+                            } else {
+                                // This should never happen - targets are registered at startup and never removed
+                                error!(
+                                    "CRITICAL: Health check target for {} disappeared unexpectedly! This indicates a bug. Stopping health check task.",
+                                    endpoint_subject
+                                );
+                                break;
                             }
-                        } else {
-                            // This should never happen - targets are registered at startup and never removed
-                            error!(
-                                "CRITICAL: Health check target for {} disappeared unexpectedly! This indicates a bug. Stopping health check task.",
-                                endpoint_subject
-                            );
-                            break;
                         }
-                    }
 
-                    _ = notifier.notified() => {
-                        // Activity detected - reset timer for this endpoint only
-                        debug!("Activity detected for {}, resetting health check timer", endpoint_subject);
-                        // Loop continues, timer resets
+                        _ = notifier.notified() => {
+                            // Activity detected - reset timer for this endpoint only
+                            debug!("Activity detected for {}, resetting health check timer", endpoint_subject);
+                            // Loop continues, timer resets
+                        }
                     }
                 }
-            }
 
-            info!("Health check task for {} exiting", endpoint_subject);
+                info!("Health check task for {} exiting", endpoint_subject);
+                Ok(())
+            }
         });
 
         // Store the task handle
@@ -271,7 +288,9 @@ impl HealthCheckManager {
             .await?;
 
         // Create the request context
-        let request: SingleIn<serde_json::Value> = Context::new(payload.clone());
+        let caller = CallerContext::default().with_origin(HEALTH_CHECK_ORIGIN);
+        let request: SingleIn<serde_json::Value> =
+            Context::new(payload.clone()).with_caller(caller);
 
         // Clone what we need for the spawned task
         let system_health = self.drt.system_health.clone();
@@ -451,6 +470,11 @@ mod integration_tests {
                 namespace: "test_namespace".to_string(),
                 instance_id: 12345,
                 transport: crate::component::TransportType::NatsTcp(endpoint.to_string()),
+                worker_id: None,
+                status: Default::default(),
+                region: None,
+                codec: Default::default(),
+                signature: None,
             },
             payload.clone(),
         );
@@ -486,6 +510,11 @@ mod integration_tests {
                     namespace: "test_namespace".to_string(),
                     instance_id: i,
                     transport: crate::component::TransportType::NatsTcp(endpoint.clone()),
+                    worker_id: None,
+                    status: Default::default(),
+                    region: None,
+                    codec: Default::default(),
+                    signature: None,
                 },
                 payload,
             );
@@ -529,6 +558,11 @@ mod integration_tests {
                 namespace: "test_namespace".to_string(),
                 instance_id: 999,
                 transport: crate::component::TransportType::NatsTcp(endpoint.to_string()),
+                worker_id: None,
+                status: Default::default(),
+                region: None,
+                codec: Default::default(),
+                signature: None,
             },
             payload.clone(),
         );
@@ -0,0 +1,256 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A worker ID that survives restarts.
+//!
+//! Lease and instance IDs are new every time a worker starts, so nothing in etcd, the metrics or
+//! the traces says that the worker before a restart and the one after it are the same. Set one
+//! of these and the worker gets a UUID that stays the same:
+//! - `DYN_WORKER_ID`: the UUID itself
+//! - `DYN_WORKER_ID_FILE`: a file holding it, created with a new UUID on first start. Put it on
+//!   a volume that outlives the container.
+//! - `DYN_WORKER_ID_STORE=true`: kept in the key-value store under the host name, for
+//!   StatefulSet pods and other hosts whose name survives a restart
+//!
+//! The ID is added to endpoint registrations as [`Instance::worker_id`], written under the
+//! primary lease at `v1/workers/{id}`, added to every metric as the `dynamo_worker_id` label and
+//! to request spans as `worker_id`. Without any of the variables there is no worker ID.
+//!
+//! [`Instance::worker_id`]: crate::component::Instance::worker_id
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::storage::key_value_store::{Key, KeyValueStoreManager, StoreOutcome};
+use crate::transports::etcd;
+use crate::{ErrorContext, Result, error};
+
+/// Where each worker's lease is recorded, by worker ID
+pub const WORKER_ROOT_PATH: &str = "v1/workers";
+
+/// Bucket of worker IDs by host name, for `DYN_WORKER_ID_STORE`
+const STORE_BUCKET: &str = "v1/worker_ids";
+
+static WORKER_ID: OnceLock<WorkerId> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct WorkerId(Uuid);
+
+impl WorkerId {
+    pub fn new() -> Self {
+        WorkerId(Uuid::new_v4())
+    }
+
+    pub fn as_uuid(&self) -> &Uuid {
+        &self.0
+    }
+}
+
+impl Default for WorkerId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for WorkerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromStr for WorkerId {
+    type Err = uuid::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(WorkerId(s.trim().parse()?))
+    }
+}
+
+/// Where the worker ID is kept between restarts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdentitySource {
+    Fixed(WorkerId),
+    File(PathBuf),
+    /// In the key-value store under `key`, usually the host name
+    Store {
+        key: String,
+    },
+}
+
+impl IdentitySource {
+    /// From the environment, see the [module docs](self). None without any of the variables.
+    pub fn from_env() -> Result<Option<Self>> {
+        if let Ok(id) = std::env::var("DYN_WORKER_ID") {
+            let id = id
+                .parse()
+                .with_context(|| format!("Invalid DYN_WORKER_ID '{id}'"))?;
+            return Ok(Some(IdentitySource::Fixed(id)));
+        }
+        if let Ok(path) = std::env::var("DYN_WORKER_ID_FILE") {
+            return Ok(Some(IdentitySource::File(path.into())));
+        }
+        if crate::config::env_is_truthy("DYN_WORKER_ID_STORE") {
+            let Some(key) = hostname() else {
+                return Err(error!("DYN_WORKER_ID_STORE needs a host name"));
+            };
+            return Ok(Some(IdentitySource::Store { key }));
+        }
+        Ok(None)
+    }
+
+    /// The ID kept here, creating and keeping a new one the first time. With etcd, a stored ID
+    /// is kept there without a lease so that it outlives the worker; otherwise it goes in
+    /// `store`.
+    pub async fn load(
+        &self,
+        store: &KeyValueStoreManager,
+        etcd_client: Option<&etcd::Client>,
+    ) -> Result<WorkerId> {
+        match (self, etcd_client) {
+            (IdentitySource::Fixed(id), _) => Ok(*id),
+            (IdentitySource::File(path), _) => load_file(path),
+            (IdentitySource::Store { key }, Some(client)) => load_etcd(client, key).await,
+            (IdentitySource::Store { key }, None) => load_store(store, key).await,
+        }
+    }
+}
+
+/// This process's worker ID, if it has one
+pub fn worker_id() -> Option<WorkerId> {
+    WORKER_ID.get().copied()
+}
+
+/// Load the worker ID configured in the environment. Once loaded, later runtimes in the process
+/// share it.
+pub(crate) async fn init(
+    store: &KeyValueStoreManager,
+    etcd_client: Option<&etcd::Client>,
+) -> Result<Option<WorkerId>> {
+    if let Some(id) = worker_id() {
+        return Ok(Some(id));
+    }
+    let Some(source) = IdentitySource::from_env()? else {
+        return Ok(None);
+    };
+    let id = source.load(store, etcd_client).await?;
+    let id = *WORKER_ID.get_or_init(|| id);
+    tracing::info!(worker_id = %id, ?source, "Worker identity");
+    Ok(Some(id))
+}
+
+/// Record which lease `id` holds this time round, removed with the lease
+pub(crate) async fn register(client: &etcd::Client, id: WorkerId) -> Result<()> {
+    let value = serde_json::json!({
+        "lease_id": client.lease_id(),
+        "hostname": hostname(),
+    });
+    client
+        .kv_put(
+            format!("{WORKER_ROOT_PATH}/{id}"),
+            serde_json::to_vec(&value)?,
+            None,
+        )
+        .await
+}
+
+fn load_file(path: &Path) -> Result<WorkerId> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => contents
+            .parse()
+            .with_context(|| format!("Invalid worker ID in {}", path.display())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            let id = WorkerId::new();
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            // Write then rename, so a crash half way through can't leave a truncated ID
+            let tmp = path.with_extension("tmp");
+            std::fs::write(&tmp, format!("{id}\n"))?;
+            std::fs::rename(&tmp, path)
+                .with_context(|| format!("Failed to write worker ID to {}", path.display()))?;
+            Ok(id)
+        }
+        Err(err) => Err(err).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+async fn load_etcd(client: &etcd::Client, key: &str) -> Result<WorkerId> {
+    let key = format!("{STORE_BUCKET}/{}", Key::new(key));
+    let new_id = WorkerId::new();
+    let value = new_id.to_string().into_bytes();
+    // Lease 0 is no lease
+    if client.kv_create(&key, value, Some(0)).await.is_ok() {
+        return Ok(new_id);
+    }
+    let existing = client.kv_get(key.as_str(), None).await?;
+    let stored = existing
+        .first()
+        .ok_or(error!("Worker ID {key} vanished from etcd"))?;
+    stored
+        .value_str()?
+        .parse()
+        .with_context(|| format!("Invalid worker ID at {key}"))
+}
+
+async fn load_store(store: &KeyValueStoreManager, key: &str) -> Result<WorkerId> {
+    let bucket = store.get_or_create_bucket(STORE_BUCKET, None).await?;
+    let key = Key::new(key);
+    let new_id = WorkerId::new();
+    let stored = match bucket.insert(&key, &new_id.to_string(), 0).await? {
+        StoreOutcome::Created(_) => return Ok(new_id),
+        StoreOutcome::Exists(_) => bucket.get(&key).await?,
+    };
+    let Some(stored) = stored else {
+        return Err(error!("Worker ID {key} vanished from {STORE_BUCKET}"));
+    };
+    std::str::from_utf8(&stored)?
+        .parse()
+        .with_context(|| format!("Invalid worker ID at {STORE_BUCKET}/{key}"))
+}
+
+fn hostname() -> Option<String> {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_identity_is_stable() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state").join("worker_id");
+
+        let first = load_file(&path).unwrap();
+        assert_eq!(load_file(&path).unwrap(), first);
+        assert!(!path.with_extension("tmp").exists());
+
+        std::fs::write(&path, "not a uuid").unwrap();
+        assert!(load_file(&path).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_store_identity_is_stable() {
+        let store = KeyValueStoreManager::memory();
+        let source = IdentitySource::Store {
+            key: "worker-0".to_string(),
+        };
+        let first = source.load(&store, None).await.unwrap();
+        assert_eq!(source.load(&store, None).await.unwrap(), first);
+
+        let other = IdentitySource::Store {
+            key: "worker-1".to_string(),
+        };
+        assert_ne!(other.load(&store, None).await.unwrap(), first);
+    }
+}
@@ -6,12 +6,27 @@
 //! This module provides functionality to list and manage instances across
 //! the entire distributed system, complementing the component-specific
 //! instance listing in `component.rs`.
+//!
+//! [`InstanceTracker`] turns the store's puts and deletes of registrations into what they mean
+//! for a router: an instance came or went, started draining, or moved to a new address.
+//! [With damping](InstanceTracker::with_flap_damping) it also holds back workers that keep
+//! registering and going away, as crash-looping ones do, so that routers don't send requests
+//! to an instance that is about to die again.
 
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{Stream, TryStreamExt};
+use tokio::time::Instant;
 
-use crate::component::{INSTANCE_ROOT_PATH, Instance};
-use crate::storage::key_value_store::{KeyValueStore, KeyValueStoreManager};
+use crate::CancellationToken;
+use crate::component::{INSTANCE_ROOT_PATH, Instance, InstanceStatus, TransportType};
+use crate::storage::encoding;
+use crate::storage::key_value_store::{KeyValueStore, KeyValueStoreManager, WatchEvent};
+use crate::storage::layout::KeyLayout;
 use crate::transports::etcd::Client as EtcdClient;
+use crate::utils::clock::{Clock, system_clock};
 
 pub async fn list_all_instances(client: &KeyValueStoreManager) -> anyhow::Result<Vec<Instance>> {
     let Some(bucket) = client.get_bucket(INSTANCE_ROOT_PATH).await? else {
@@ -21,7 +36,7 @@ pub async fn list_all_instances(client: &KeyValueStoreManager) -> anyhow::Result
     let entries = bucket.entries().await?;
     let mut instances = Vec::with_capacity(entries.len());
     for (name, bytes) in entries.into_iter() {
-        match serde_json::from_slice::<Instance>(&bytes) {
+        match encoding::decode::<Instance>(&bytes) {
             Ok(instance) => instances.push(instance),
             Err(err) => {
                 tracing::warn!(%err, key = name, "Failed to parse instance from storage");
@@ -32,3 +47,565 @@ pub async fn list_all_instances(client: &KeyValueStoreManager) -> anyhow::Result
 
     Ok(instances)
 }
+
+/// The instances registered in etcd in `layout` whose key starts with `prefix`, e.g.
+/// `ns/backend/generate/`, `page_size` at a time, so that a router can route to the first page
+/// while the rest are read. Registrations that don't parse are logged and skipped.
+pub fn instance_pages(
+    client: &EtcdClient,
+    layout: KeyLayout,
+    prefix: &str,
+    page_size: usize,
+) -> impl Stream<Item = anyhow::Result<Vec<Instance>>> + Send + 'static {
+    let prefix = format!("{}/{prefix}", layout.instance_bucket());
+    client
+        .kv_get_prefix_pages(prefix, page_size)
+        .map_ok(|page| {
+            let mut instances = Vec::with_capacity(page.kvs.len());
+            for kv in page.kvs {
+                match encoding::decode::<Instance>(kv.value()) {
+                    Ok(instance) => instances.push(instance),
+                    Err(err) => {
+                        let key = String::from_utf8_lossy(kv.key());
+                        tracing::warn!(%err, %key, "Failed to parse instance from etcd");
+                    }
+                }
+            }
+            instances
+        })
+}
+
+/// A change to the instances an [`InstanceTracker`] follows
+#[derive(Debug, Clone, PartialEq)]
+pub enum InstanceEvent {
+    Added(Instance),
+    Removed(Instance),
+    /// Its [`InstanceStatus`] changed, e.g. it started draining
+    StatusChanged {
+        instance: Instance,
+        from: InstanceStatus,
+    },
+    /// It re-registered at another address
+    EndpointMoved {
+        instance: Instance,
+        from: TransportType,
+    },
+    /// Something else in its registration changed, e.g. its codec
+    Updated {
+        instance: Instance,
+        previous: Instance,
+    },
+    /// It deregistered too often, see [`FlapDamping`]. Its worker's registrations are held
+    /// back for `quarantine`, and [added](InstanceEvent::Added) then if still there.
+    Flapping {
+        instance: Instance,
+        quarantine: Duration,
+    },
+    /// The instances that existed when tracking started have all been reported as added
+    Synced,
+}
+
+/// When an [`InstanceTracker`] takes a worker to be flapping, and for how long it is held back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlapDamping {
+    /// Deregistrations older than this are forgotten
+    pub window: Duration,
+    /// Deregistrations within the window that make a worker flapping
+    pub threshold: usize,
+    /// Held back for this long the first time, twice as long each time after
+    pub quarantine: Duration,
+    pub max_quarantine: Duration,
+}
+
+impl Default for FlapDamping {
+    fn default() -> Self {
+        FlapDamping {
+            window: Duration::from_secs(60),
+            threshold: 3,
+            quarantine: Duration::from_secs(10),
+            max_quarantine: Duration::from_secs(300),
+        }
+    }
+}
+
+impl FlapDamping {
+    /// The quarantine after `previous` ones, without a quiet `max_quarantine` in between
+    fn quarantine_after(&self, previous: u32) -> Duration {
+        let factor = 2u32.saturating_pow(previous);
+        self.quarantine
+            .saturating_mul(factor)
+            .min(self.max_quarantine)
+    }
+}
+
+/// The deregistrations of one worker, see [`flap_identity`]
+#[derive(Debug, Default)]
+struct FlapHistory {
+    /// Within the window, oldest first
+    deregistered: VecDeque<Instant>,
+    last: Option<Instant>,
+    quarantines: u32,
+    until: Option<Instant>,
+}
+
+/// The current instances under a key prefix, kept up to date from a watch on
+/// [`INSTANCE_ROOT_PATH`]
+#[derive(Debug)]
+pub struct InstanceTracker {
+    prefix: String,
+    /// By store key
+    instances: HashMap<String, Instance>,
+    damping: Option<FlapDamping>,
+    /// By [`flap_identity`]
+    flaps: HashMap<String, FlapHistory>,
+    /// Registered while their worker is in quarantine, by store key
+    held: HashMap<String, Instance>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for InstanceTracker {
+    fn default() -> Self {
+        InstanceTracker::new("")
+    }
+}
+
+impl InstanceTracker {
+    /// Follow the registrations whose key starts with `prefix`, e.g. `ns/backend/generate/`
+    /// for one endpoint, or "" for all of them
+    pub fn new(prefix: impl Into<String>) -> Self {
+        InstanceTracker {
+            prefix: prefix.into(),
+            instances: HashMap::new(),
+            damping: None,
+            flaps: HashMap::new(),
+            held: HashMap::new(),
+            clock: system_clock(),
+        }
+    }
+
+    /// Hold back the instances of workers that flap as `damping` says, reporting
+    /// [`InstanceEvent::Flapping`]. A worker is known by its [worker ID](crate::identity),
+    /// which stays the same across restarts; without one, only an instance that deregisters
+    /// and comes back under the same ID is caught.
+    pub fn with_flap_damping(mut self, damping: FlapDamping) -> Self {
+        self.damping = Some(damping);
+        self
+    }
+
+    /// Time quarantines with `clock` instead of tokio's
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Start a task that tracks `prefix` until `cancel_token` is cancelled or the watch fails
+    /// for good, when the receiver is closed
+    pub fn spawn(
+        store: &KeyValueStoreManager,
+        prefix: impl Into<String>,
+        cancel_token: CancellationToken,
+    ) -> tokio::sync::mpsc::UnboundedReceiver<InstanceEvent> {
+        InstanceTracker::new(prefix).track(store, cancel_token)
+    }
+
+    /// Like [`InstanceTracker::spawn`], with this tracker, which also releases the instances
+    /// it held back as their quarantines end
+    pub fn track(
+        mut self,
+        store: &KeyValueStoreManager,
+        cancel_token: CancellationToken,
+    ) -> tokio::sync::mpsc::UnboundedReceiver<InstanceEvent> {
+        let mut events = Arc::new(store.clone()).watch(INSTANCE_ROOT_PATH, None, cancel_token);
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let release = self.next_release();
+                let clock = self.clock.clone();
+                let released = async move {
+                    match release {
+                        Some(at) => clock.sleep_until(at).await,
+                        None => std::future::pending().await,
+                    }
+                };
+                let changes = tokio::select! {
+                    event = events.recv() => {
+                        let Some(event) = event else {
+                            return;
+                        };
+                        if let WatchEvent::Error(err) = &event {
+                            tracing::error!(%err, prefix = %self.prefix, "Instance tracker failed");
+                        }
+                        self.apply(&event)
+                    }
+                    _ = released => self.release_expired(),
+                };
+                for change in changes {
+                    if tx.send(change).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        rx
+    }
+
+    /// Take in one event from the watch, returning what it changed. A registration that
+    /// doesn't parse is logged and otherwise ignored.
+    pub fn apply(&mut self, event: &WatchEvent) -> Vec<InstanceEvent> {
+        match event {
+            WatchEvent::Put(kv) if kv.key().starts_with(&self.prefix) => {
+                let instance = match encoding::decode::<Instance>(kv.value()) {
+                    Ok(instance) => instance,
+                    Err(err) => {
+                        tracing::warn!(%err, key = kv.key(), "Unable to parse instance");
+                        return vec![];
+                    }
+                };
+                if self.held.contains_key(kv.key()) || self.in_quarantine(kv.key(), &instance) {
+                    self.held.insert(kv.key().to_string(), instance);
+                    return vec![];
+                }
+                match self
+                    .instances
+                    .insert(kv.key().to_string(), instance.clone())
+                {
+                    None => vec![InstanceEvent::Added(instance)],
+                    Some(previous) => diff(previous, instance),
+                }
+            }
+            WatchEvent::Delete(kv) => {
+                if let Some(instance) = self.held.remove(kv.key()) {
+                    return self.deregistered(kv.key(), &instance).into_iter().collect();
+                }
+                let Some(instance) = self.instances.remove(kv.key()) else {
+                    return vec![];
+                };
+                let flapping = self.deregistered(kv.key(), &instance);
+                let mut events = vec![InstanceEvent::Removed(instance)];
+                events.extend(flapping);
+                events
+            }
+            WatchEvent::InitialSyncComplete => vec![InstanceEvent::Synced],
+            // Changes missed while disconnected come as puts and deletes after reconnecting
+            _ => vec![],
+        }
+    }
+
+    /// Not those held back
+    pub fn instances(&self) -> impl Iterator<Item = &Instance> {
+        self.instances.values()
+    }
+
+    /// Registered, but held back while their worker is in quarantine
+    pub fn held(&self) -> impl Iterator<Item = &Instance> {
+        self.held.values()
+    }
+
+    /// When the first quarantine of a held back instance ends
+    pub fn next_release(&self) -> Option<Instant> {
+        let now = self.clock.now();
+        self.held
+            .iter()
+            .map(|(key, instance)| self.quarantine_end(key, instance).unwrap_or(now))
+            .min()
+    }
+
+    /// Add the held back instances whose quarantine has ended
+    pub fn release_expired(&mut self) -> Vec<InstanceEvent> {
+        let now = self.clock.now();
+        let released: Vec<String> = self
+            .held
+            .iter()
+            .filter(|(key, instance)| {
+                self.quarantine_end(key, instance)
+                    .is_none_or(|at| at <= now)
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        let mut events = Vec::with_capacity(released.len());
+        for key in released {
+            let instance = self.held.remove(&key).expect("just found");
+            tracing::info!(%key, "Flapping instance released from quarantine");
+            self.instances.insert(key, instance.clone());
+            events.push(InstanceEvent::Added(instance));
+        }
+        events
+    }
+
+    fn quarantine_end(&self, key: &str, instance: &Instance) -> Option<Instant> {
+        self.flaps.get(&flap_identity(key, instance))?.until
+    }
+
+    fn in_quarantine(&self, key: &str, instance: &Instance) -> bool {
+        self.quarantine_end(key, instance)
+            .is_some_and(|until| until > self.clock.now())
+    }
+
+    /// Count a deregistration, returning the event if it makes the instance's worker flapping
+    fn deregistered(&mut self, key: &str, instance: &Instance) -> Option<InstanceEvent> {
+        let damping = self.damping?;
+        let now = self.clock.now();
+        // Quiet for long enough, the next quarantine is a first one again
+        self.flaps.retain(|_, history| {
+            history
+                .last
+                .is_some_and(|last| now - last <= damping.max_quarantine)
+        });
+        let history = self.flaps.entry(flap_identity(key, instance)).or_default();
+        history.last = Some(now);
+        history.deregistered.push_back(now);
+        while history
+            .deregistered
+            .front()
+            .is_some_and(|at| now - *at > damping.window)
+        {
+            history.deregistered.pop_front();
+        }
+        if history.deregistered.len() < damping.threshold {
+            return None;
+        }
+        history.deregistered.clear();
+        let quarantine = damping.quarantine_after(history.quarantines);
+        history.quarantines += 1;
+        history.until = Some(now + quarantine);
+        tracing::warn!(%key, ?quarantine, "Instance is flapping, holding it back");
+        Some(InstanceEvent::Flapping {
+            instance: instance.clone(),
+            quarantine,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.instances.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instances.is_empty()
+    }
+}
+
+/// Who is flapping: the worker, whose ID stays the same across restarts while its instance ID
+/// changes, or without one the registration itself
+fn flap_identity(key: &str, instance: &Instance) -> String {
+    match instance.worker_id {
+        Some(worker_id) => format!(
+            "{}/{}/{}/{worker_id}",
+            instance.namespace, instance.component, instance.endpoint
+        ),
+        None => key.to_string(),
+    }
+}
+
+/// The events that take `previous` to `instance`, the registration under the same key
+fn diff(previous: Instance, instance: Instance) -> Vec<InstanceEvent> {
+    let mut events = Vec::new();
+    if instance.transport != previous.transport {
+        events.push(InstanceEvent::EndpointMoved {
+            instance: instance.clone(),
+            from: previous.transport.clone(),
+        });
+    }
+    if instance.status != previous.status {
+        events.push(InstanceEvent::StatusChanged {
+            instance: instance.clone(),
+            from: previous.status,
+        });
+    }
+    // Anything left once the fields reported above are the same
+    let rest = Instance {
+        transport: instance.transport.clone(),
+        status: instance.status,
+        signature: instance.signature.clone(),
+        ..previous.clone()
+    };
+    if rest != instance {
+        events.push(InstanceEvent::Updated { instance, previous });
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::component::test_instance;
+    use crate::identity::WorkerId;
+    use crate::storage::key_value_store::{Key, KeyValue, KeyValueBucket};
+    use crate::utils::clock::TestClock;
+
+    fn put(key: &str, instance: &Instance) -> WatchEvent {
+        let value = serde_json::to_vec(instance).unwrap();
+        WatchEvent::Put(KeyValue::new(key.to_string(), value.into()))
+    }
+
+    fn delete(key: &str) -> WatchEvent {
+        WatchEvent::Delete(KeyValue::new(key.to_string(), bytes::Bytes::new()))
+    }
+
+    #[test]
+    fn test_tracker_reports_what_changed() {
+        let mut tracker = InstanceTracker::new("ns/backend/generate/");
+        let key = "ns/backend/generate/1";
+        let first = test_instance(1);
+        assert_eq!(
+            tracker.apply(&put(key, &first)),
+            [InstanceEvent::Added(first.clone())]
+        );
+        assert_eq!(
+            tracker.apply(&WatchEvent::InitialSyncComplete),
+            [InstanceEvent::Synced]
+        );
+
+        // The same registration again is no change
+        assert!(tracker.apply(&put(key, &first)).is_empty());
+
+        let draining = Instance {
+            status: InstanceStatus::Draining,
+            ..first.clone()
+        };
+        assert_eq!(
+            tracker.apply(&put(key, &draining)),
+            [InstanceEvent::StatusChanged {
+                instance: draining.clone(),
+                from: InstanceStatus::Active
+            }]
+        );
+
+        let moved = Instance {
+            transport: TransportType::NatsTcp("elsewhere".to_string()),
+            region: Some("eu-west".to_string()),
+            ..draining.clone()
+        };
+        assert_eq!(
+            tracker.apply(&put(key, &moved)),
+            [
+                InstanceEvent::EndpointMoved {
+                    instance: moved.clone(),
+                    from: first.transport.clone()
+                },
+                InstanceEvent::Updated {
+                    instance: moved.clone(),
+                    previous: draining
+                }
+            ]
+        );
+
+        // Other endpoints are not followed
+        let other = test_instance(2);
+        assert!(tracker.apply(&put("ns/backend/load/2", &other)).is_empty());
+        assert_eq!(tracker.len(), 1);
+
+        assert_eq!(tracker.apply(&delete(key)), [InstanceEvent::Removed(moved)]);
+        assert!(tracker.apply(&delete(key)).is_empty());
+        assert!(tracker.is_empty());
+    }
+
+    #[test]
+    fn test_flapping_worker_is_held_back() {
+        let clock = TestClock::new();
+        let damping = FlapDamping {
+            window: Duration::from_secs(60),
+            threshold: 3,
+            quarantine: Duration::from_secs(10),
+            max_quarantine: Duration::from_secs(300),
+        };
+        let mut tracker = InstanceTracker::new("ns/backend/generate/")
+            .with_flap_damping(damping)
+            .with_clock(Arc::new(clock.clone()));
+        let worker_id = Some(WorkerId::new());
+        // A new instance ID, and key, each time it restarts
+        let restarted = |id: u64| {
+            let key = format!("ns/backend/generate/{id:x}");
+            let instance = Instance {
+                worker_id,
+                ..test_instance(id)
+            };
+            (key, instance)
+        };
+
+        for id in 1..=2 {
+            let (key, instance) = restarted(id);
+            assert_eq!(
+                tracker.apply(&put(&key, &instance)),
+                [InstanceEvent::Added(instance)]
+            );
+            clock.advance(Duration::from_secs(5));
+            assert_eq!(tracker.apply(&delete(&key)).len(), 1);
+        }
+        let (key, third) = restarted(3);
+        tracker.apply(&put(&key, &third));
+        assert_eq!(
+            tracker.apply(&delete(&key)),
+            [
+                InstanceEvent::Removed(third.clone()),
+                InstanceEvent::Flapping {
+                    instance: third,
+                    quarantine: Duration::from_secs(10)
+                }
+            ]
+        );
+
+        // Back again, but not routed to until the quarantine is over
+        let (key, fourth) = restarted(4);
+        assert!(tracker.apply(&put(&key, &fourth)).is_empty());
+        assert!(tracker.is_empty());
+        assert_eq!(tracker.held().count(), 1);
+        assert_eq!(
+            tracker.next_release(),
+            Some(clock.now() + Duration::from_secs(10))
+        );
+        clock.advance(Duration::from_secs(9));
+        assert!(tracker.release_expired().is_empty());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(
+            tracker.release_expired(),
+            [InstanceEvent::Added(fourth.clone())]
+        );
+        assert_eq!(tracker.len(), 1);
+
+        // Flapping again doubles the quarantine
+        tracker.apply(&delete(&key));
+        for id in 5..=6 {
+            let (key, instance) = restarted(id);
+            tracker.apply(&put(&key, &instance));
+            let events = tracker.apply(&delete(&key));
+            if let Some(InstanceEvent::Flapping { quarantine, .. }) = events.get(1) {
+                assert_eq!(*quarantine, Duration::from_secs(20));
+            } else {
+                assert_eq!(id, 5, "{events:?}");
+            }
+        }
+
+        // Other workers are not held back
+        let other = test_instance(7);
+        let key = "ns/backend/generate/7";
+        assert_eq!(
+            tracker.apply(&put(key, &other)),
+            [InstanceEvent::Added(other)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_spawned_tracker_follows_the_store() -> anyhow::Result<()> {
+        let store = KeyValueStoreManager::memory();
+        let bucket = store.get_or_create_bucket(INSTANCE_ROOT_PATH, None).await?;
+        let first = test_instance(1);
+        let json = serde_json::to_string(&first)?;
+        let key = Key::from_raw("ns/backend/generate/1".to_string());
+        bucket.insert(&key, &json, 0).await?;
+
+        let cancel_token = CancellationToken::new();
+        let mut events = InstanceTracker::spawn(&store, "ns/", cancel_token.clone());
+        assert_eq!(events.recv().await, Some(InstanceEvent::Added(first)));
+        assert_eq!(events.recv().await, Some(InstanceEvent::Synced));
+
+        bucket.delete(&key).await?;
+        assert!(matches!(
+            events.recv().await,
+            Some(InstanceEvent::Removed(_))
+        ));
+
+        cancel_token.cancel();
+        assert!(events.recv().await.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,509 @@
+// SPDX-FileCopyrightText: Copyright (c) 2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Jobs: requests that run for minutes, whose callers go away and come back for the outcome.
+//!
+//! A streaming response ends with the connection that carries it, which doesn't suit work that
+//! outlasts its caller. A job endpoint, serving a [`job_engine`], instead answers a request with
+//! the [`JobId`] of a job it starts in the background, and keeps the job's [`JobStatus`] in
+//! [`JOBS_BUCKET`] of the key-value store. From any process, [`Jobs`] then:
+//!
+//! - [polls](Jobs::status) the status, or [watches](Jobs::watch) each change of it: the progress
+//!   the job reports through its [`JobHandle`], then how it ended
+//! - [fetches](Jobs::fetch_result) the result of a job that succeeded, kept for a while after,
+//!   see [`JobResultsConfig`]
+//! - [cancels](Jobs::cancel) the job, whichever worker runs it
+//!
+//! [`submit`] sends a request to a job endpoint and returns the job's id. Statuses are dropped
+//! [`JOB_TTL`] after they were last written. A job whose worker shuts down fails; one whose
+//! worker dies stays [running](JobState::Running), its status no longer updated.
+
+use std::future::Future;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+
+use crate::DistributedRuntime;
+use crate::pipeline::{
+    AsyncEngine, AsyncEngineContextProvider, Data, Error, ManyOut, PushRouter, ResponseStream,
+    ServiceEngine, SingleIn, async_trait,
+};
+use crate::protocols::annotated::Annotated;
+use crate::storage::key_value_store::{Key, KeyValueStoreManager, WatchEvent, WatchFilter};
+use crate::{Result, error};
+
+mod results;
+
+pub use results::{
+    DEFAULT_MAX_RESULT_BYTES, DEFAULT_RESULT_TTL, JOB_RESULTS_BUCKET, JobResultsConfig,
+};
+
+pub const JOBS_BUCKET: &str = "v1/jobs";
+
+/// How long the status of a job is kept after its last change
+pub const JOB_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct JobId(String);
+
+impl JobId {
+    fn new() -> Self {
+        JobId(uuid::Uuid::new_v4().simple().to_string())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for JobId {
+    fn from(id: String) -> Self {
+        JobId(id)
+    }
+}
+
+impl std::fmt::Display for JobId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl JobState {
+    pub fn is_finished(&self) -> bool {
+        *self != JobState::Running
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JobStatus {
+    pub id: JobId,
+    pub state: JobState,
+    /// From 0 to 1, as last reported
+    pub progress: f64,
+    /// What the job last said it was doing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// Once the job succeeded, when the result kept for [`Jobs::fetch_result`] expires, in Unix
+    /// milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result_expires_at: Option<u64>,
+    /// Why it failed or stopped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Set by [`Jobs::cancel`], for the worker to stop the job
+    #[serde(default)]
+    pub cancel_requested: bool,
+    /// In Unix milliseconds
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+/// The statuses of jobs, in the runtime's key-value store
+#[derive(Clone)]
+pub struct Jobs {
+    store: KeyValueStoreManager,
+    /// How the results of the jobs this process runs are kept
+    results: JobResultsConfig,
+}
+
+impl Jobs {
+    pub fn new(store: KeyValueStoreManager) -> Self {
+        Jobs {
+            store,
+            results: JobResultsConfig::default(),
+        }
+    }
+
+    pub fn with_results(mut self, results: JobResultsConfig) -> Self {
+        self.results = results;
+        self
+    }
+
+    /// None if there is no such job, or its status expired
+    pub async fn status(&self, id: &JobId) -> Result<Option<JobStatus>> {
+        let Some(bucket) = self.store.get_bucket(JOBS_BUCKET).await? else {
+            return Ok(None);
+        };
+        match bucket.get(&key(id)).await? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The job's status, then each change of it, until it finishes or `cancel_token` is
+    /// cancelled. Ends straight away if there is no such job.
+    pub fn watch(
+        &self,
+        id: &JobId,
+        cancel_token: CancellationToken,
+    ) -> impl Stream<Item = JobStatus> + Send + 'static {
+        let store = Arc::new(self.store.clone());
+        let filter = WatchFilter::prefix(id.as_str());
+        let events = store.watch_filtered(JOBS_BUCKET, Some(JOB_TTL), filter, cancel_token);
+        let jobs = self.clone();
+        let id = id.clone();
+        // The events, and whether the job's status was seen yet, None once it finished
+        futures::stream::unfold(Some((events, false)), move |state| {
+            let jobs = jobs.clone();
+            let id = id.clone();
+            async move {
+                let (mut events, seen) = state?;
+                loop {
+                    let status = match events.recv().await? {
+                        WatchEvent::Put(kv) if key_in_bucket(kv.key()) == id.as_str() => {
+                            match serde_json::from_slice::<JobStatus>(kv.value()) {
+                                Ok(status) => status,
+                                Err(_) => continue,
+                            }
+                        }
+                        // No put of the job among the existing keys, or the store sends none
+                        WatchEvent::InitialSyncComplete if !seen => {
+                            jobs.status(&id).await.ok().flatten()?
+                        }
+                        WatchEvent::Delete(kv) if key_in_bucket(kv.key()) == id.as_str() => {
+                            return None;
+                        }
+                        WatchEvent::Error(_) | WatchEvent::Closed => return None,
+                        _ => continue,
+                    };
+                    let next = (!status.state.is_finished()).then_some((events, true));
+                    return Some((status, next));
+                }
+            }
+        })
+    }
+
+    /// Ask the job's worker to stop it. Does nothing to a finished job.
+    pub async fn cancel(&self, id: &JobId) -> Result<()> {
+        self.store
+            .update(JOBS_BUCKET, &key(id), |status: Option<JobStatus>| {
+                let mut status = status.ok_or_else(|| error!("No job {id}"))?;
+                if !status.state.is_finished() {
+                    status.cancel_requested = true;
+                    status.updated_at = self.store.clock().unix_millis();
+                }
+                Ok(status)
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Record a new job, running
+    async fn create(&self) -> Result<JobStatus> {
+        let now = self.store.clock().unix_millis();
+        let status = JobStatus {
+            id: JobId::new(),
+            state: JobState::Running,
+            progress: 0.0,
+            message: None,
+            result_expires_at: None,
+            error: None,
+            cancel_requested: false,
+            created_at: now,
+            updated_at: now,
+        };
+        let bucket = self
+            .store
+            .get_or_create_bucket(JOBS_BUCKET, Some(JOB_TTL))
+            .await?;
+        bucket
+            .insert_new(&key(&status.id), &serde_json::to_string(&status)?)
+            .await?;
+        Ok(status)
+    }
+
+    /// Change the job's status with `f`, returning the changed one
+    async fn change(
+        &self,
+        id: &JobId,
+        mut f: impl FnMut(&mut JobStatus) + Send,
+    ) -> Result<JobStatus> {
+        self.store
+            .update(JOBS_BUCKET, &key(id), |status: Option<JobStatus>| {
+                let mut status = status.ok_or_else(|| error!("The status of job {id} is gone"))?;
+                f(&mut status);
+                status.updated_at = self.store.clock().unix_millis();
+                Ok(status)
+            })
+            .await
+    }
+}
+
+/// What a job's handler reports its progress to, and learns of its cancellation from
+#[derive(Clone)]
+pub struct JobHandle {
+    id: JobId,
+    jobs: Jobs,
+    cancel_token: CancellationToken,
+}
+
+impl JobHandle {
+    pub fn id(&self) -> &JobId {
+        &self.id
+    }
+
+    /// Record how far the job got, from 0 to 1, and what it is doing
+    pub async fn progress(&self, progress: f64, message: impl Into<String>) -> Result<()> {
+        let message = message.into();
+        let status = self
+            .jobs
+            .change(&self.id, |status| {
+                status.progress = progress.clamp(0.0, 1.0);
+                status.message = Some(message.clone());
+            })
+            .await?;
+        if status.cancel_requested {
+            self.cancel_token.cancel();
+        }
+        Ok(())
+    }
+
+    /// Cancelled when the job is, or the worker shuts down. The handler is dropped then anyway;
+    /// this is for work it handed off elsewhere.
+    pub fn cancel_token(&self) -> &CancellationToken {
+        &self.cancel_token
+    }
+}
+
+/// An engine for a job endpoint: each request starts a job, running `handler` in the background,
+/// and is answered with the job's id. The job's result is what the handler returns, as JSON,
+/// kept as `DYN_JOB_RESULT_TTL` and `DYN_JOB_RESULT_MAX_BYTES` say.
+pub fn job_engine<T, R, F, Fut>(
+    drt: &DistributedRuntime,
+    handler: F,
+) -> ServiceEngine<SingleIn<T>, ManyOut<Annotated<JobId>>>
+where
+    T: Data,
+    R: Serialize + Send + 'static,
+    F: Fn(T, JobHandle) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<R>> + Send + 'static,
+{
+    let results = JobResultsConfig::from_env().unwrap_or_else(|err| {
+        tracing::warn!(%err, "Keeping job results for the default time and size");
+        JobResultsConfig::default()
+    });
+    Arc::new(JobEngine {
+        jobs: Jobs::new(drt.store()).with_results(results),
+        handler,
+        cancel_token: drt.primary_token(),
+        _types: PhantomData,
+    })
+}
+
+/// Start a job on a job endpoint, returning its id once a worker has taken it
+pub async fn submit<T>(router: &PushRouter<T, Annotated<JobId>>, request: T) -> Result<JobId>
+where
+    T: Data + Serialize,
+{
+    let mut stream = router.generate(request.into()).await?;
+    let answer = stream
+        .next()
+        .await
+        .ok_or_else(|| error!("The job endpoint answered nothing"))?;
+    let answer = answer
+        .ok()
+        .map_err(|err| error!("Starting the job failed: {err}"))?;
+    answer
+        .data
+        .ok_or_else(|| error!("The job endpoint answered without a job id"))
+}
+
+struct JobEngine<T, R, F> {
+    jobs: Jobs,
+    handler: F,
+    /// The worker's, cancelled when it shuts down
+    cancel_token: CancellationToken,
+    _types: PhantomData<fn(T) -> R>,
+}
+
+#[async_trait]
+impl<T, R, F, Fut> AsyncEngine<SingleIn<T>, ManyOut<Annotated<JobId>>, Error> for JobEngine<T, R, F>
+where
+    T: Data,
+    R: Serialize + Send + 'static,
+    F: Fn(T, JobHandle) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<R>> + Send + 'static,
+{
+    async fn generate(&self, input: SingleIn<T>) -> Result<ManyOut<Annotated<JobId>>> {
+        let (request, ctx) = input.into_parts();
+        let status = self.jobs.create().await?;
+        let handle = JobHandle {
+            id: status.id.clone(),
+            jobs: self.jobs.clone(),
+            cancel_token: self.cancel_token.child_token(),
+        };
+        let job = (self.handler)(request, handle.clone());
+        tokio::spawn(run_job(job, handle, self.cancel_token.clone()));
+        let stream = futures::stream::iter([Annotated::from_data(status.id)]);
+        Ok(ResponseStream::new(Box::pin(stream), ctx.context()))
+    }
+}
+
+async fn run_job<R: Serialize>(
+    job: impl Future<Output = Result<R>>,
+    handle: JobHandle,
+    shutdown: CancellationToken,
+) {
+    let id = handle.id.clone();
+    let stop_watching = handle.cancel_token.child_token();
+    watch_for_cancel(&handle, stop_watching.clone());
+    let outcome = tokio::select! {
+        outcome = job => Some(outcome),
+        _ = handle.cancel_token.cancelled() => None,
+    };
+    stop_watching.cancel();
+    // Before the job counts as succeeded, so that its result can be fetched by then
+    let outcome = match outcome {
+        Some(outcome) => Some(keep_result(&handle.jobs, &id, outcome).await),
+        None => None,
+    };
+    let finished = handle
+        .jobs
+        .change(&id, |status| match &outcome {
+            Some(Ok(expires_at)) => {
+                status.state = JobState::Succeeded;
+                status.progress = 1.0;
+                status.result_expires_at = Some(*expires_at);
+            }
+            Some(Err(err)) => {
+                status.state = JobState::Failed;
+                status.error = Some(format!("{err:#}"));
+            }
+            None if shutdown.is_cancelled() => {
+                status.state = JobState::Failed;
+                status.error = Some("The worker running the job shut down".to_string());
+            }
+            None => {
+                status.state = JobState::Cancelled;
+                status.error = Some("Cancelled".to_string());
+            }
+        })
+        .await;
+    match finished {
+        Ok(status) => tracing::debug!(%id, state = ?status.state, "Job finished"),
+        Err(err) => tracing::error!(%id, %err, "Failed to record how a job finished"),
+    }
+}
+
+/// The job's result, once kept, or why it failed. Returns when the result expires.
+async fn keep_result<R: Serialize>(jobs: &Jobs, id: &JobId, outcome: Result<R>) -> Result<u64> {
+    let result = serde_json::to_value(outcome?)?;
+    jobs.store_result(id, result).await
+}
+
+/// Cancel the job's token when someone asks [`Jobs::cancel`] to
+fn watch_for_cancel(handle: &JobHandle, stop_watching: CancellationToken) {
+    let statuses = handle.jobs.watch(&handle.id, stop_watching);
+    let cancel_token = handle.cancel_token.clone();
+    tokio::spawn(async move {
+        let mut statuses = std::pin::pin!(statuses);
+        while let Some(status) = statuses.next().await {
+            if status.cancel_requested {
+                cancel_token.cancel();
+                return;
+            }
+        }
+    });
+}
+
+fn key(id: &JobId) -> Key {
+    Key::from_raw(id.to_string())
+}
+
+fn key_in_bucket(key: &str) -> &str {
+    key.strip_prefix(JOBS_BUCKET)
+        .and_then(|key| key.strip_prefix('/'))
+        .unwrap_or(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn start(jobs: &Jobs, shutdown: &CancellationToken) -> JobHandle {
+        let status = jobs.create().await.unwrap();
+        JobHandle {
+            id: status.id,
+            jobs: jobs.clone(),
+            cancel_token: shutdown.child_token(),
+        }
+    }
+
+    async fn wait_for(jobs: &Jobs, id: &JobId) -> JobStatus {
+        let statuses = jobs.watch(id, CancellationToken::new());
+        let mut statuses = std::pin::pin!(statuses);
+        let mut last = None;
+        while let Some(status) = statuses.next().await {
+            last = Some(status);
+        }
+        last.expect("no such job")
+    }
+
+    #[tokio::test]
+    async fn test_job_lifecycle() {
+        let jobs = Jobs::new(KeyValueStoreManager::memory());
+        let shutdown = CancellationToken::new();
+
+        // Reports progress, then succeeds
+        let handle = start(&jobs, &shutdown).await;
+        let reporter = handle.clone();
+        let job = async move {
+            reporter.progress(0.5, "halfway").await?;
+            Ok(42)
+        };
+        run_job(job, handle.clone(), shutdown.clone()).await;
+        let status = jobs.status(handle.id()).await.unwrap().unwrap();
+        assert_eq!(status.state, JobState::Succeeded);
+        assert!(status.result_expires_at.is_some());
+        assert_eq!(
+            jobs.fetch_result::<u32>(handle.id()).await.unwrap(),
+            Some(42)
+        );
+        assert_eq!(status.message.as_deref(), Some("halfway"));
+        assert_eq!(wait_for(&jobs, handle.id()).await, status);
+
+        // Fails
+        let handle = start(&jobs, &shutdown).await;
+        let job = async { Err::<(), _>(error!("out of disk")) };
+        run_job(job, handle.clone(), shutdown.clone()).await;
+        let status = wait_for(&jobs, handle.id()).await;
+        assert_eq!(status.state, JobState::Failed);
+        assert_eq!(status.error.as_deref(), Some("out of disk"));
+
+        // Cancelled from elsewhere while it runs
+        let handle = start(&jobs, &shutdown).await;
+        let running = tokio::spawn(run_job(
+            std::future::pending::<Result<()>>(),
+            handle.clone(),
+            shutdown.clone(),
+        ));
+        jobs.cancel(handle.id()).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), running)
+            .await
+            .unwrap()
+            .unwrap();
+        let status = jobs.status(handle.id()).await.unwrap().unwrap();
+        assert_eq!(status.state, JobState::Cancelled);
+        // Cancelling a finished job leaves it be
+        jobs.cancel(handle.id()).await.unwrap();
+        assert_eq!(jobs.status(handle.id()).await.unwrap().unwrap(), status);
+
+        let missing = JobId::from("missing".to_string());
+        assert!(jobs.status(&missing).await.unwrap().is_none());
+        assert!(jobs.cancel(&missing).await.is_err());
+    }
+}
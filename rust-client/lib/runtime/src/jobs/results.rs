@@ -0,0 +1,152 @@
+// SPDX-FileCopyrightText: Copyright (c) 2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The results of jobs that succeeded, kept apart from their statuses so that watching a job
+//! doesn't carry its result with every progress report.
+//!
+//! A result is kept in [`JOB_RESULTS_BUCKET`] for `DYN_JOB_RESULT_TTL` after the job finished,
+//! [`DEFAULT_RESULT_TTL`] if unset, for callers that were away then to
+//! [fetch](Jobs::fetch_result). A result over `DYN_JOB_RESULT_MAX_BYTES` of JSON,
+//! [`DEFAULT_MAX_RESULT_BYTES`] if unset, isn't kept and fails the job instead.
+//!
+//! The bucket drops results after the TTL of the worker that created it, so the workers of a
+//! cluster should agree on it.
+
+use anyhow::Context as _;
+use serde::de::DeserializeOwned;
+
+use super::*;
+
+pub const JOB_RESULTS_BUCKET: &str = "v1/job_results";
+
+pub const DEFAULT_RESULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub const DEFAULT_MAX_RESULT_BYTES: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JobResultsConfig {
+    pub ttl: Duration,
+    pub max_bytes: usize,
+}
+
+impl Default for JobResultsConfig {
+    fn default() -> Self {
+        JobResultsConfig {
+            ttl: DEFAULT_RESULT_TTL,
+            max_bytes: DEFAULT_MAX_RESULT_BYTES,
+        }
+    }
+}
+
+impl JobResultsConfig {
+    pub fn from_env() -> Result<Self> {
+        let mut config = JobResultsConfig::default();
+        if let Ok(ttl) = std::env::var("DYN_JOB_RESULT_TTL") {
+            config.ttl = humantime::parse_duration(&ttl)
+                .with_context(|| format!("Invalid DYN_JOB_RESULT_TTL '{ttl}'"))?;
+        }
+        if let Ok(max_bytes) = std::env::var("DYN_JOB_RESULT_MAX_BYTES") {
+            config.max_bytes = max_bytes
+                .parse()
+                .with_context(|| format!("Invalid DYN_JOB_RESULT_MAX_BYTES '{max_bytes}'"))?;
+        }
+        if config.ttl.is_zero() {
+            anyhow::bail!("DYN_JOB_RESULT_TTL must be more than 0");
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredResult {
+    result: serde_json::Value,
+    /// In Unix milliseconds, for buckets created with a longer TTL
+    expires_at: u64,
+}
+
+impl Jobs {
+    /// What the job returned, as `R`. None if it didn't succeed, or not yet, or its result expired.
+    pub async fn fetch_result<R: DeserializeOwned>(&self, id: &JobId) -> Result<Option<R>> {
+        let Some(bucket) = self.store.get_bucket(JOB_RESULTS_BUCKET).await? else {
+            return Ok(None);
+        };
+        let Some(value) = bucket.get(&key(id)).await? else {
+            return Ok(None);
+        };
+        let stored: StoredResult = serde_json::from_slice(&value)?;
+        if stored.expires_at <= self.store.clock().unix_millis() {
+            return Ok(None);
+        }
+        let result = serde_json::from_value(stored.result)
+            .with_context(|| format!("The result of job {id} is of another type"))?;
+        Ok(Some(result))
+    }
+
+    /// Keep the result of a job that succeeded, returning when it expires
+    pub(super) async fn store_result(&self, id: &JobId, result: serde_json::Value) -> Result<u64> {
+        let expires_at = self.store.clock().unix_millis() + self.results.ttl.as_millis() as u64;
+        let value = serde_json::to_string(&StoredResult { result, expires_at })?;
+        if value.len() > self.results.max_bytes {
+            anyhow::bail!(
+                "The job's result of {} bytes is over the {} bytes kept",
+                value.len(),
+                self.results.max_bytes
+            );
+        }
+        let bucket = self
+            .store
+            .get_or_create_bucket(JOB_RESULTS_BUCKET, Some(self.results.ttl))
+            .await?;
+        bucket.insert_new(&key(id), &value).await?;
+        Ok(expires_at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::clock::{Clock, TestClock};
+
+    #[tokio::test]
+    async fn test_results() {
+        let clock = TestClock::new();
+        let store = KeyValueStoreManager::memory().with_clock(Arc::new(clock.clone()));
+        let jobs = Jobs::new(store).with_results(JobResultsConfig {
+            ttl: Duration::from_secs(60),
+            max_bytes: 64,
+        });
+        let id = JobId::new();
+        assert_eq!(jobs.fetch_result::<Vec<u32>>(&id).await.unwrap(), None);
+
+        let expires_at = jobs
+            .store_result(&id, serde_json::json!([1, 2]))
+            .await
+            .unwrap();
+        assert_eq!(expires_at, clock.unix_millis() + 60_000);
+        assert_eq!(
+            jobs.fetch_result::<Vec<u32>>(&id).await.unwrap(),
+            Some(vec![1, 2])
+        );
+        assert!(jobs.fetch_result::<String>(&id).await.is_err());
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(jobs.fetch_result::<Vec<u32>>(&id).await.unwrap(), None);
+
+        let big = serde_json::json!("x".repeat(64));
+        assert!(jobs.store_result(&JobId::new(), big).await.is_err());
+
+        temp_env::with_vars(
+            [
+                ("DYN_JOB_RESULT_TTL", Some("2h")),
+                ("DYN_JOB_RESULT_MAX_BYTES", Some("1000")),
+            ],
+            || {
+                let config = JobResultsConfig::from_env().unwrap();
+                assert_eq!(config.ttl, Duration::from_secs(7200));
+                assert_eq!(config.max_bytes, 1000);
+            },
+        );
+        temp_env::with_var("DYN_JOB_RESULT_TTL", Some("0s"), || {
+            assert!(JobResultsConfig::from_env().is_err());
+        });
+    }
+}
@@ -25,20 +25,31 @@ pub mod compute;
 pub mod discovery;
 pub mod engine;
 pub mod health_check;
+pub mod identity;
 pub mod system_status_server;
 pub use system_status_server::SystemStatusServerInfo;
 pub mod instances;
+pub mod jobs;
+pub mod loadgen;
 pub mod logging;
+pub mod metering;
 pub mod metrics;
 pub mod pipeline;
+pub mod policy;
 pub mod prelude;
 pub mod protocols;
+pub mod replay;
+pub mod retirement;
 pub mod runnable;
 pub mod runtime;
+pub mod secrets;
 pub mod service;
+#[cfg(feature = "simulation")]
+pub mod simulation;
 pub mod slug;
 pub mod storage;
 pub mod system_health;
+pub mod testing;
 pub mod traits;
 pub mod transports;
 pub mod utils;
@@ -48,7 +59,7 @@ pub mod distributed;
 pub use distributed::distributed_test_utils;
 pub use futures::stream;
 pub use metrics::MetricsRegistry;
-pub use system_health::{HealthCheckTarget, SystemHealth};
+pub use system_health::{HealthCheckTarget, Probe, SystemHealth};
 pub use tokio_util::sync::CancellationToken;
 pub use worker::Worker;
 
@@ -80,6 +91,10 @@ pub struct Runtime {
     graceful_shutdown_tracker: Arc<GracefulShutdownTracker>,
     compute_pool: Option<Arc<compute::ComputePool>>,
     block_in_place_permits: Option<Arc<tokio::sync::Semaphore>>,
+    control_plane_threads: usize,
+    control_plane: Arc<std::sync::OnceLock<tokio::runtime::Handle>>,
+    blocking_tasks: Arc<compute::blocking::BlockingTasks>,
+    supervisor: utils::tasks::supervisor::Supervisor,
 }
 
 /// Distributed [Runtime] which provides access to shared resources across the cluster, this includes
@@ -90,9 +105,14 @@ pub struct DistributedRuntime {
     runtime: Runtime,
 
     // we might consider a unifed transport manager here
-    etcd_client: Option<transports::etcd::Client>,
+    // Swapped in along with `store` once connected, if etcd was unreachable at startup
+    etcd_client: Arc<arc_swap::ArcSwapOption<transports::etcd::Client>>,
     nats_client: Option<transports::nats::Client>,
-    store: KeyValueStoreManager,
+
+    // Set with `DYN_NATS_CLUSTERS`, for namespaces on other NATS clusters
+    nats_clusters: Option<Arc<transports::nats::NatsClusters>>,
+
+    store: Arc<arc_swap::ArcSwap<KeyValueStoreManager>>,
     tcp_server: Arc<OnceCell<Arc<transports::tcp::server::TcpStreamServer>>>,
     system_status_server: Arc<OnceLock<Arc<system_status_server::SystemStatusServerInfo>>>,
 
@@ -107,8 +127,37 @@ pub struct DistributedRuntime {
     // startup. Will not start etcd.
     is_static: bool,
 
+    // Set when instances are discovered from Kubernetes EndpointSlices instead of etcd
+    kubernetes: Option<discovery::KubernetesDiscovery>,
+
+    // Set when etcd was unreachable at startup and `DYN_OFFLINE_FALLBACK` allowed us to carry on
+    offline: Option<discovery::OfflineRegistrations>,
+
+    // Set when `DYN_FEDERATED_REGIONS` lists other regions' etcd clusters to fail over to
+    federation: Option<discovery::Federation>,
+
+    // Set for the workers of a `testing::InProcessCluster`, which have neither etcd nor NATS
+    in_process: Option<pipeline::network::in_process::InProcessNetwork>,
+
+    // Set when instances register in and are discovered from `store` instead of etcd, as with
+    // a `mem://`, `nats://` or `redis://` `DISCOVERY_URL`: the ID this worker's instances
+    // register under
+    store_instance_id: Option<u64>,
+
     instance_sources: Arc<tokio::sync::Mutex<HashMap<Endpoint, Weak<InstanceSource>>>>,
 
+    // Set when the secrets provider has a signing key for our instance registrations
+    instance_signer: Option<Arc<component::InstanceSigner>>,
+
+    // Set with `DYN_VERIFY_INSTANCES`, to route only to instances signed by a trusted key
+    instance_verifier: Option<Arc<component::InstanceVerifier>>,
+
+    // Set with `DYN_POLICY`, to check registrations and requests against the access policy
+    policy: Option<policy::Policy>,
+
+    // Set with `DYN_METERING`, to count and publish the usage of each tenant
+    metering: Option<Arc<metering::Metering>>,
+
     // Health Status
     system_health: Arc<parking_lot::Mutex<SystemHealth>>,
 
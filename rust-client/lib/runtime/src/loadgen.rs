@@ -0,0 +1,410 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Open-loop load against a live endpoint, to watch routing while etcd fails over underneath it.
+//!
+//! Requests go out on the schedule a [`Profile`] sets whatever the endpoint does, so a stalled
+//! endpoint piles up requests in flight instead of lowering the offered load, as real clients
+//! would. Each request is rendered from a [`PayloadTemplate`] and sent through a [`PushRouter`].
+//! The [`LoadReport`] has the latency to the first and to the last response item of each, and
+//! when the failures happened.
+//!
+//! [`PushRouter`]: crate::pipeline::PushRouter
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::StreamExt;
+use serde_json::Value;
+use tokio::sync::Semaphore;
+
+use crate::engine::AsyncEngine;
+use crate::pipeline::Context;
+use crate::pipeline::context::CallerContext;
+use crate::replay::ReplayRouter;
+use crate::storage::key_value_store::bench::OpStats;
+use crate::{ErrorContext, Result, error};
+
+/// The longest step taken when working out when the next request is due
+const SCHEDULE_STEP: Duration = Duration::from_millis(10);
+
+/// One part of a [`Profile`]: the rate moves linearly from `from` to `to` over `duration`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stage {
+    pub from: f64,
+    pub to: f64,
+    pub duration: Duration,
+}
+
+/// Requests per second over time, as stages that run one after the other.
+///
+/// Written as comma separated `RATE@DURATION` for a steady rate and `FROM-TO@DURATION` for a
+/// ramp, so `10@30s,10-200@1m,200@5m` holds 10/s for 30 seconds, ramps up to 200/s over a minute
+/// and holds that for five.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    stages: Vec<Stage>,
+}
+
+impl Profile {
+    pub fn new(stages: Vec<Stage>) -> Result<Self> {
+        if stages.is_empty() {
+            return Err(error!("A load profile needs at least one stage"));
+        }
+        for stage in &stages {
+            let valid = |rate: f64| rate.is_finite() && rate >= 0.0;
+            if !valid(stage.from) || !valid(stage.to) {
+                return Err(error!(
+                    "Rates must be finite and not negative, got {stage:?}"
+                ));
+            }
+        }
+        Ok(Profile { stages })
+    }
+
+    /// A steady `rate` for `duration`
+    pub fn constant(rate: f64, duration: Duration) -> Result<Self> {
+        Profile::new(vec![Stage {
+            from: rate,
+            to: rate,
+            duration,
+        }])
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.stages.iter().map(|stage| stage.duration).sum()
+    }
+
+    /// The rate `elapsed` into the profile, None once it is over
+    pub fn rate_at(&self, elapsed: Duration) -> Option<f64> {
+        let mut start = Duration::ZERO;
+        for stage in &self.stages {
+            let end = start + stage.duration;
+            if elapsed < end {
+                let progress = (elapsed - start).as_secs_f64() / stage.duration.as_secs_f64();
+                return Some(stage.from + (stage.to - stage.from) * progress);
+            }
+            start = end;
+        }
+        None
+    }
+
+    /// When the request after one due at `due` is, None if the profile ends first. The rate is
+    /// summed in small steps rather than stepping by its inverse, which would overshoot a ramp
+    /// that starts from a low rate.
+    fn next_due(&self, mut due: Duration) -> Option<Duration> {
+        let mut requests = 0.0;
+        loop {
+            let rate = self.rate_at(due)?;
+            let step = if rate > 0.0 {
+                // At least a nanosecond, or what is left of a request can round to no time
+                let rest = Duration::from_secs_f64((1.0 - requests) / rate);
+                SCHEDULE_STEP.min(rest.max(Duration::from_nanos(1)))
+            } else {
+                SCHEDULE_STEP
+            };
+            requests += rate * step.as_secs_f64();
+            due += step;
+            if requests >= 1.0 - 1e-9 {
+                return Some(due);
+            }
+        }
+    }
+}
+
+impl FromStr for Profile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let stages = s
+            .split(',')
+            .map(|stage| {
+                let stage = stage.trim();
+                let (rates, duration) = stage.split_once('@').ok_or_else(|| {
+                    error!("Stage '{stage}' is not RATE@DURATION or FROM-TO@DURATION")
+                })?;
+                let duration = humantime::parse_duration(duration)
+                    .with_context(|| format!("Invalid duration in stage '{stage}'"))?;
+                let (from, to) = rates.split_once('-').unwrap_or((rates, rates));
+                let rate = |rate: &str| {
+                    rate.parse::<f64>()
+                        .with_context(|| format!("Invalid rate in stage '{stage}'"))
+                };
+                Ok(Stage {
+                    from: rate(from)?,
+                    to: rate(to)?,
+                    duration,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Profile::new(stages)
+    }
+}
+
+/// A JSON request in which `{{seq}}` becomes the number of the request, from 0, and `{{uuid}}`
+/// a fresh UUID. A string that is only `{{seq}}` becomes a JSON number.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PayloadTemplate(Value);
+
+impl PayloadTemplate {
+    pub fn new(template: Value) -> Self {
+        PayloadTemplate(template)
+    }
+
+    pub fn render(&self, seq: u64) -> Value {
+        render(&self.0, seq)
+    }
+}
+
+impl FromStr for PayloadTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let template = serde_json::from_str(s).context("Payload template is not JSON")?;
+        Ok(PayloadTemplate(template))
+    }
+}
+
+impl Default for PayloadTemplate {
+    fn default() -> Self {
+        PayloadTemplate(Value::from("request {{seq}}"))
+    }
+}
+
+fn render(value: &Value, seq: u64) -> Value {
+    match value {
+        Value::String(s) if s == "{{seq}}" => Value::from(seq),
+        Value::String(s) if s.contains("{{") => Value::from(
+            s.replace("{{seq}}", &seq.to_string())
+                .replace("{{uuid}}", &uuid::Uuid::new_v4().to_string()),
+        ),
+        Value::Array(items) => items.iter().map(|item| render(item, seq)).collect(),
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(name, field)| (name.clone(), render(field, seq)))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LoadConfig {
+    pub profile: Profile,
+    pub template: PayloadTemplate,
+    /// Send every request to this instance instead of using the router's mode
+    pub instance_id: Option<u64>,
+    /// Requests due while this many are in flight are dropped rather than sent, so that an
+    /// endpoint that stops answering doesn't exhaust memory
+    pub max_in_flight: usize,
+}
+
+impl LoadConfig {
+    pub fn new(profile: Profile) -> Self {
+        LoadConfig {
+            profile,
+            template: PayloadTemplate::default(),
+            instance_id: None,
+            max_in_flight: 10_000,
+        }
+    }
+}
+
+/// What happened to one request
+#[derive(Debug, Clone)]
+pub struct Sample {
+    /// When it was due, from the start of the run
+    pub offset: Duration,
+    /// Until the first response item, None if there was none
+    pub first_response: Option<Duration>,
+    /// Until the end of the response stream
+    pub elapsed: Duration,
+    /// Set if the request could not be sent, or the stream contained an error item
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LoadReport {
+    /// Wall time of the run, including waiting for the last responses
+    pub elapsed: Duration,
+    /// In the order the requests were sent
+    pub samples: Vec<Sample>,
+    /// Requests not sent for [`LoadConfig::max_in_flight`]
+    pub dropped: usize,
+}
+
+impl LoadReport {
+    pub fn failed(&self) -> usize {
+        self.samples.iter().filter(|s| s.error.is_some()).count()
+    }
+
+    /// Latency to the first response item, of the requests that succeeded
+    pub fn first_response(&self) -> OpStats {
+        let latencies = self.succeeded().filter_map(|s| s.first_response).collect();
+        OpStats::new("first", self.elapsed, latencies)
+    }
+
+    /// Latency to the end of the response stream, of the requests that succeeded
+    pub fn total(&self) -> OpStats {
+        let latencies = self.succeeded().map(|s| s.elapsed).collect();
+        OpStats::new("total", self.elapsed, latencies)
+    }
+
+    /// The failed requests by the second of the run they were sent in, for seconds with any
+    pub fn failures_by_second(&self) -> BTreeMap<u64, usize> {
+        let mut failures = BTreeMap::new();
+        for sample in self.samples.iter().filter(|s| s.error.is_some()) {
+            *failures.entry(sample.offset.as_secs()).or_default() += 1;
+        }
+        failures
+    }
+
+    fn succeeded(&self) -> impl Iterator<Item = &Sample> {
+        self.samples.iter().filter(|s| s.error.is_none())
+    }
+}
+
+impl fmt::Display for LoadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "sent {} in {:.1?}, {} failed, {} dropped",
+            self.samples.len(),
+            self.elapsed,
+            self.failed(),
+            self.dropped
+        )?;
+        writeln!(f, "{}", self.first_response())?;
+        write!(f, "{}", self.total())
+    }
+}
+
+/// Put the load `config` describes on `router`, and wait for the responses to the last of it
+pub async fn run(router: &ReplayRouter, config: &LoadConfig) -> Result<LoadReport> {
+    let in_flight = Arc::new(Semaphore::new(config.max_in_flight));
+    let mut handles = Vec::new();
+    let mut dropped = 0;
+    let mut seq = 0;
+    let start = tokio::time::Instant::now();
+    let mut next = config.profile.next_due(Duration::ZERO);
+    while let Some(due) = next {
+        tokio::time::sleep_until(start + due).await;
+        match in_flight.clone().try_acquire_owned() {
+            Ok(permit) => {
+                let router = router.clone();
+                let request = config.template.render(seq);
+                let instance_id = config.instance_id;
+                handles.push(tokio::spawn(async move {
+                    let sample = send(&router, request, instance_id, due).await;
+                    drop(permit);
+                    sample
+                }));
+            }
+            Err(_) => dropped += 1,
+        }
+        seq += 1;
+        next = config.profile.next_due(due);
+    }
+
+    let mut samples = Vec::with_capacity(handles.len());
+    for handle in handles {
+        samples.push(handle.await?);
+    }
+    Ok(LoadReport {
+        elapsed: start.elapsed(),
+        samples,
+        dropped,
+    })
+}
+
+async fn send(
+    router: &ReplayRouter,
+    request: Value,
+    instance_id: Option<u64>,
+    offset: Duration,
+) -> Sample {
+    let start = Instant::now();
+    let caller = CallerContext::default().with_origin("loadgen");
+    let request = Context::new(request).with_caller(caller);
+    let stream = match instance_id {
+        Some(instance_id) => router.direct(request, instance_id).await,
+        None => router.generate(request).await,
+    };
+    let mut sample = Sample {
+        offset,
+        first_response: None,
+        elapsed: Duration::ZERO,
+        error: None,
+    };
+    match stream {
+        Ok(mut stream) => {
+            while let Some(item) = stream.next().await {
+                sample.first_response.get_or_insert_with(|| start.elapsed());
+                if sample.error.is_none() {
+                    sample.error = item.ok().err();
+                }
+            }
+        }
+        Err(err) => sample.error = Some(err.to_string()),
+    }
+    sample.elapsed = start.elapsed();
+    sample
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_profile() {
+        let profile: Profile = "10@30s, 10-200@1m,0@5s".parse().unwrap();
+        assert_eq!(profile.duration(), Duration::from_secs(95));
+        assert_eq!(profile.rate_at(Duration::from_secs(5)), Some(10.0));
+        assert_eq!(profile.rate_at(Duration::from_secs(60)), Some(105.0));
+        assert_eq!(profile.rate_at(Duration::from_secs(92)), Some(0.0));
+        assert_eq!(profile.rate_at(Duration::from_secs(95)), None);
+
+        assert!("10".parse::<Profile>().is_err());
+        assert!("fast@10s".parse::<Profile>().is_err());
+        assert!("10@soon".parse::<Profile>().is_err());
+        assert!("".parse::<Profile>().is_err());
+    }
+
+    #[test]
+    fn test_schedule_follows_ramp() {
+        let count = |profile: &Profile| {
+            std::iter::successors(profile.next_due(Duration::ZERO), |due| {
+                profile.next_due(*due)
+            })
+            .count()
+        };
+        let steady = Profile::constant(100.0, Duration::from_secs(2)).unwrap();
+        assert!((199..=200).contains(&count(&steady)), "{}", count(&steady));
+
+        // Averages 50/s, and a first step of 1/rate would skip most of it
+        let ramp: Profile = "0-100@2s".parse().unwrap();
+        assert!((98..=100).contains(&count(&ramp)), "{}", count(&ramp));
+
+        let idle: Profile = "0@1s".parse().unwrap();
+        assert_eq!(count(&idle), 0);
+    }
+
+    #[test]
+    fn test_render_template() {
+        let template = PayloadTemplate::new(json!({
+            "id": "{{seq}}",
+            "prompt": ["request {{seq}}", 7],
+            "nonce": "{{uuid}}",
+        }));
+        let rendered = template.render(3);
+        assert_eq!(rendered["id"], json!(3));
+        assert_eq!(rendered["prompt"], json!(["request 3", 7]));
+        let nonce = rendered["nonce"].as_str().unwrap();
+        assert!(uuid::Uuid::parse_str(nonce).is_ok(), "{nonce}");
+    }
+}
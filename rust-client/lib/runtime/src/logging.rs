@@ -26,6 +26,9 @@
 //! "test_logging::api" = "trace"
 //! ```
 
+pub mod sampling;
+pub mod slow_ops;
+
 use std::collections::{BTreeMap, HashMap};
 use std::sync::Once;
 
@@ -103,7 +106,7 @@ const OTEL_EXPORT_ENABLED_ENV: &str = "OTEL_EXPORT_ENABLED";
 const OTEL_EXPORT_ENDPOINT_ENV: &str = "OTEL_EXPORT_ENDPOINT";
 
 /// Default OTLP endpoint
-const DEFAULT_OTLP_ENDPOINT: &str = "http://localhost:4317";
+pub(crate) const DEFAULT_OTLP_ENDPOINT: &str = "http://localhost:4317";
 
 /// Service name environment variable
 const OTEL_SERVICE_NAME_ENV: &str = "OTEL_SERVICE_NAME";
@@ -151,7 +154,7 @@ fn otlp_exporter_enabled() -> bool {
 }
 
 /// Get the service name from environment or use default
-fn get_service_name() -> String {
+pub(crate) fn get_service_name() -> String {
     std::env::var(OTEL_SERVICE_NAME_ENV).unwrap_or_else(|_| DEFAULT_OTEL_SERVICE_NAME.to_string())
 }
 
@@ -305,6 +308,10 @@ pub fn make_request_span<B>(req: &Request<B>) -> Span {
 }
 
 /// Create a handle_payload span from NATS headers with component context
+///
+/// The caller fields are recorded once the request is decoded, see [`CallerContext`]
+///
+/// [`CallerContext`]: crate::pipeline::context::CallerContext
 pub fn make_handle_payload_span(
     headers: &async_nats::HeaderMap,
     component: &str,
@@ -314,6 +321,7 @@ pub fn make_handle_payload_span(
 ) -> Span {
     let (otel_context, trace_id, parent_span_id) = extract_otel_context_from_nats_headers(headers);
     let trace_parent = TraceParent::from_headers(headers);
+    let worker_id = crate::identity::worker_id();
 
     if let (Some(trace_id), Some(parent_id)) = (trace_id.as_ref(), parent_span_id.as_ref()) {
         let span = tracing::info_span!(
@@ -327,6 +335,10 @@ pub fn make_handle_payload_span(
             endpoint = endpoint,
             namespace = namespace,
             instance_id = instance_id,
+            worker_id = worker_id.map(tracing::field::display),
+            caller_id = tracing::field::Empty,
+            tenant = tracing::field::Empty,
+            origin = tracing::field::Empty,
         );
 
         if let Some(context) = otel_context {
@@ -343,6 +355,10 @@ pub fn make_handle_payload_span(
             endpoint = endpoint,
             namespace = namespace,
             instance_id = instance_id,
+            worker_id = worker_id.map(tracing::field::display),
+            caller_id = tracing::field::Empty,
+            tenant = tracing::field::Empty,
+            origin = tracing::field::Empty,
         )
     }
 }
@@ -0,0 +1,325 @@
+// SPDX-FileCopyrightText: Copyright (c) 2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Per-endpoint sampling of the `handle_payload` spans of the requests an endpoint serves.
+//!
+//! At a high request rate, tracing every request costs more than the traces are worth, but the
+//! requests that fail are the ones someone will come looking for. Each endpoint reads its
+//! [`SamplingConfig`] from the store's [`TRACE_SAMPLING_BUCKET`], under
+//! `{namespace}/{component}/{endpoint}`, and follows changes to it while serving:
+//!
+//! ```text
+//! etcdctl put v1/trace_sampling/dynamo/backend/generate '{"rate": 0.01, "tail_ms": 2000}'
+//! ```
+//!
+//! A [`SamplingConfig::rate`] of the requests are traced from the start, with everything that
+//! happens in them. Of the others, those that fail, unless [`SamplingConfig::always_errors`] is
+//! off, and those taking [`SamplingConfig::tail_ms`] or longer, are traced once they finish: a
+//! `handle_payload` span without children, holding an event that says why and how long the
+//! request took. The draw comes from the trace id when the request has one, so the services a
+//! trace passes through agree on it. An endpoint without a config traces every request.
+//!
+//! A request that isn't sampled still has its span, under a parent marked as not sampled, so
+//! its logs keep their trace id while the exporter drops it and every span started in it.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use opentelemetry::trace::{SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState};
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::storage::key_value_store::{Key, KeyValueStoreManager, WatchEvent, WatchFilter};
+
+use super::{TraceParent, extract_otel_context_from_nats_headers};
+
+/// Where the sampling configs are, by endpoint
+pub const TRACE_SAMPLING_BUCKET: &str = "v1/trace_sampling";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SamplingConfig {
+    /// The fraction of the requests traced from the start, from 0.0 to 1.0
+    pub rate: f64,
+    /// Trace the failed requests that weren't sampled, once they finish
+    pub always_errors: bool,
+    /// Trace the requests that weren't sampled and took at least this long, once they finish
+    pub tail_ms: Option<u64>,
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        SamplingConfig {
+            rate: 1.0,
+            always_errors: true,
+            tail_ms: None,
+        }
+    }
+}
+
+impl SamplingConfig {
+    /// Whether to trace the request of `trace_id`, or of none
+    pub fn sample(&self, trace_id: Option<&str>) -> Sampled {
+        let draw = trace_id
+            .and_then(draw_from_trace_id)
+            .unwrap_or_else(rand::random::<f64>);
+        if draw < self.rate {
+            return Sampled::Yes;
+        }
+        Sampled::Afterwards {
+            errors: self.always_errors,
+            tail: self.tail_ms.map(Duration::from_millis),
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&self.rate) {
+            return Err(format!("rate {} is not between 0 and 1", self.rate));
+        }
+        Ok(())
+    }
+}
+
+/// Whether a request is traced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sampled {
+    /// From the start
+    Yes,
+    /// Once it finishes, if it failed and `errors`, or took `tail` or longer
+    Afterwards {
+        errors: bool,
+        tail: Option<Duration>,
+    },
+}
+
+impl Sampled {
+    /// Why a request that wasn't traced from the start is traced after all, if it is
+    pub fn afterwards(&self, failed: bool, took: Duration) -> Option<&'static str> {
+        match *self {
+            Sampled::Yes => None,
+            Sampled::Afterwards { errors: true, .. } if failed => Some("error"),
+            Sampled::Afterwards {
+                tail: Some(tail), ..
+            } if took >= tail => Some("tail"),
+            Sampled::Afterwards { .. } => None,
+        }
+    }
+}
+
+/// The low 64 bits of a W3C trace id, as a number from 0 up to 1. Trace ids are random.
+fn draw_from_trace_id(trace_id: &str) -> Option<f64> {
+    let low = u64::from_str_radix(trace_id.get(16..32)?, 16).ok()?;
+    // The 53 bits an f64 holds exactly
+    Some((low >> 11) as f64 / (1u64 << 53) as f64)
+}
+
+/// The sampling config of one endpoint, kept current by [`TraceSampling::watch`]
+#[derive(Debug, Clone)]
+pub struct TraceSampling(Arc<ArcSwap<SamplingConfig>>);
+
+impl Default for TraceSampling {
+    fn default() -> Self {
+        TraceSampling::fixed(SamplingConfig::default())
+    }
+}
+
+impl TraceSampling {
+    /// Never changes
+    pub fn fixed(config: SamplingConfig) -> Self {
+        TraceSampling(Arc::new(ArcSwap::from_pointee(config)))
+    }
+
+    /// The config of the endpoint at `endpoint_path`, and the changes to it until `cancel_token`
+    /// is cancelled. Starts with the default until the store's value is read.
+    pub fn watch(
+        store: &KeyValueStoreManager,
+        endpoint_path: String,
+        cancel_token: CancellationToken,
+    ) -> Self {
+        let sampling = TraceSampling::default();
+        let config = sampling.0.clone();
+        let filter = WatchFilter::prefix(endpoint_path.clone());
+        let store = Arc::new(store.clone());
+        let mut events = store.watch_filtered(TRACE_SAMPLING_BUCKET, None, filter, cancel_token);
+        tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                let changed = match event {
+                    WatchEvent::Put(kv) if key_in_bucket(kv.key()) == endpoint_path => {
+                        let parsed = serde_json::from_slice::<SamplingConfig>(kv.value())
+                            .map_err(|err| err.to_string())
+                            .and_then(|changed| changed.validate().map(|()| changed));
+                        match parsed {
+                            Ok(changed) => changed,
+                            Err(err) => {
+                                tracing::warn!(endpoint_path, %err, "Ignoring sampling config");
+                                continue;
+                            }
+                        }
+                    }
+                    WatchEvent::Delete(kv) if key_in_bucket(kv.key()) == endpoint_path => {
+                        SamplingConfig::default()
+                    }
+                    _ => continue,
+                };
+                tracing::info!(endpoint_path, ?changed, "Trace sampling changed");
+                config.store(Arc::new(changed));
+            }
+        });
+        sampling
+    }
+
+    pub fn config(&self) -> SamplingConfig {
+        **self.0.load()
+    }
+
+    /// Whether to trace a request that came with `headers`
+    pub fn sample(&self, headers: Option<&async_nats::HeaderMap>) -> Sampled {
+        let trace_id = headers.and_then(|headers| TraceParent::from_headers(headers).trace_id);
+        self.config().sample(trace_id.as_deref())
+    }
+}
+
+/// Keep `span`, which hasn't been entered yet, and the spans started in it out of the exported
+/// traces, by giving it a parent that wasn't sampled: the caller's span from `headers` if there
+/// is one, otherwise one of a new trace
+pub fn unsample(span: &Span, headers: Option<&async_nats::HeaderMap>) {
+    let remote = headers.and_then(|headers| extract_otel_context_from_nats_headers(headers).0);
+    let (trace_id, span_id) = match &remote {
+        Some(context) => {
+            let caller = context.span().span_context().clone();
+            (caller.trace_id(), caller.span_id())
+        }
+        None => (
+            TraceId::from_bytes(rand::random()),
+            SpanId::from_bytes(rand::random()),
+        ),
+    };
+    let flags = TraceFlags::default();
+    let parent = SpanContext::new(trace_id, span_id, flags, true, TraceState::default());
+    let _ = span.set_parent(opentelemetry::Context::new().with_remote_span_context(parent));
+}
+
+/// Some stores send keys bucket-qualified
+fn key_in_bucket(key: &str) -> &str {
+    key.strip_prefix(TRACE_SAMPLING_BUCKET)
+        .and_then(|key| key.strip_prefix('/'))
+        .unwrap_or(key)
+}
+
+/// Set the sampling of the endpoint at `endpoint_path`, `{namespace}/{component}/{endpoint}`,
+/// or with None go back to the default. Its instances follow without restarting.
+pub async fn set_sampling(
+    store: &KeyValueStoreManager,
+    endpoint_path: &str,
+    config: Option<SamplingConfig>,
+) -> anyhow::Result<()> {
+    let key = Key::from_raw(endpoint_path.to_string());
+    let Some(config) = config else {
+        let bucket = store
+            .get_or_create_bucket(TRACE_SAMPLING_BUCKET, None)
+            .await?;
+        bucket.delete(&key).await?;
+        return Ok(());
+    };
+    config.validate().map_err(anyhow::Error::msg)?;
+    store
+        .update(TRACE_SAMPLING_BUCKET, &key, |_| Ok(config))
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample() {
+        let half = SamplingConfig {
+            rate: 0.5,
+            always_errors: true,
+            tail_ms: Some(100),
+        };
+        // By the low half of the trace id
+        let low = "4bf92f3577b34da613ce929d0e0e4736";
+        let high = "4bf92f3577b34da6f3ce929d0e0e4736";
+        assert_eq!(half.sample(Some(low)), Sampled::Yes);
+        let sampled = half.sample(Some(high));
+        assert_ne!(sampled, Sampled::Yes);
+        assert_eq!(sampled.afterwards(false, Duration::from_millis(99)), None);
+        assert_eq!(sampled.afterwards(true, Duration::ZERO), Some("error"));
+        assert_eq!(
+            sampled.afterwards(false, Duration::from_millis(100)),
+            Some("tail")
+        );
+        assert_eq!(Sampled::Yes.afterwards(true, Duration::from_secs(1)), None);
+
+        let none = SamplingConfig {
+            rate: 0.0,
+            always_errors: false,
+            tail_ms: None,
+        };
+        let sampled = none.sample(Some(low));
+        assert_eq!(sampled.afterwards(true, Duration::from_secs(60)), None);
+        // Without a trace id, or with one that isn't, drawn at random
+        assert_eq!(SamplingConfig::default().sample(None), Sampled::Yes);
+        assert_eq!(
+            SamplingConfig::default().sample(Some("short")),
+            Sampled::Yes
+        );
+        assert_ne!(none.sample(None), Sampled::Yes);
+
+        let parsed: SamplingConfig = serde_json::from_str(r#"{"rate": 0.01}"#).unwrap();
+        assert_eq!(
+            (parsed.rate, parsed.always_errors, parsed.tail_ms),
+            (0.01, true, None)
+        );
+        let bad = SamplingConfig {
+            rate: 1.5,
+            ..Default::default()
+        };
+        assert!(bad.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_watch() {
+        let store = KeyValueStoreManager::memory();
+        let cancel_token = CancellationToken::new();
+        let path = "dynamo/backend/generate";
+        let sampling = TraceSampling::watch(&store, path.to_string(), cancel_token.clone());
+        assert_eq!(sampling.config(), SamplingConfig::default());
+
+        let config = SamplingConfig {
+            rate: 0.1,
+            always_errors: true,
+            tail_ms: Some(2000),
+        };
+        set_sampling(&store, path, Some(config)).await.unwrap();
+        // Another endpoint's config, and one that isn't valid, change nothing
+        let other = SamplingConfig {
+            rate: 0.0,
+            ..config
+        };
+        let other_path = "dynamo/backend/generate_v2";
+        set_sampling(&store, other_path, Some(other)).await.unwrap();
+        while sampling.config() != config {
+            tokio::task::yield_now().await;
+        }
+        let key = Key::from_raw(path.to_string());
+        let invalid = serde_json::json!({"rate": 7});
+        let update = store.update(TRACE_SAMPLING_BUCKET, &key, |_| Ok(invalid.clone()));
+        update.await.unwrap();
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(sampling.config(), config);
+
+        set_sampling(&store, path, None).await.unwrap();
+        while sampling.config() != SamplingConfig::default() {
+            tokio::task::yield_now().await;
+        }
+        cancel_token.cancel();
+    }
+}
@@ -0,0 +1,174 @@
+// SPDX-FileCopyrightText: Copyright (c) 2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Warnings for etcd calls, store operations and pipeline operators that take too long.
+//!
+//! Each kind of operation has its threshold, from the [`RuntimeConfig`] of the runtime last
+//! created: `DYN_SLOW_OP_ETCD_MS`, `DYN_SLOW_OP_STORE_MS` and `DYN_SLOW_OP_OPERATOR_MS`, 0 to
+//! never warn. An operation that finishes over it logs a `Slow operation` warning with its kind,
+//! name, arguments and how long it took. The arguments name the keys or buckets involved, and
+//! give the values by their size only: etcd values hold registrations and credentials too.
+//!
+//! Each operation name warns at most once per [`WARN_INTERVAL`]. The next warning says how many
+//! slow calls were not logged in between, so a stalled etcd doesn't flood the logs with one line
+//! per call.
+//!
+//! [`RuntimeConfig`]: crate::RuntimeConfig
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+use crate::config::{
+    DEFAULT_SLOW_ETCD_CALL_MS, DEFAULT_SLOW_OPERATOR_MS, DEFAULT_SLOW_STORE_OP_MS,
+};
+
+/// The least time between two warnings about the same operation
+pub const WARN_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SlowOpKind {
+    /// A call to the etcd server, see [`crate::transports::etcd::Client`]
+    Etcd,
+    /// A call on a bucket of a [`KeyValueStoreManager`], whatever the store
+    ///
+    /// [`KeyValueStoreManager`]: crate::storage::key_value_store::KeyValueStoreManager
+    Store,
+    /// A pipeline operator's `generate`, until it returns its response stream
+    Operator,
+}
+
+impl SlowOpKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SlowOpKind::Etcd => "etcd",
+            SlowOpKind::Store => "store",
+            SlowOpKind::Operator => "operator",
+        }
+    }
+
+    fn threshold(&self) -> &'static AtomicU64 {
+        match self {
+            SlowOpKind::Etcd => &ETCD_THRESHOLD_MS,
+            SlowOpKind::Store => &STORE_THRESHOLD_MS,
+            SlowOpKind::Operator => &OPERATOR_THRESHOLD_MS,
+        }
+    }
+}
+
+static ETCD_THRESHOLD_MS: AtomicU64 = AtomicU64::new(DEFAULT_SLOW_ETCD_CALL_MS);
+static STORE_THRESHOLD_MS: AtomicU64 = AtomicU64::new(DEFAULT_SLOW_STORE_OP_MS);
+static OPERATOR_THRESHOLD_MS: AtomicU64 = AtomicU64::new(DEFAULT_SLOW_OPERATOR_MS);
+
+/// Warnings held back, by operation
+static SUPPRESSED: Lazy<Mutex<HashMap<(SlowOpKind, &'static str), Suppressed>>> =
+    Lazy::new(Default::default);
+
+#[derive(Debug, Default)]
+struct Suppressed {
+    last_warned: Option<Instant>,
+    count: u64,
+}
+
+/// Set how long operations of `kind` may take, [`Duration::ZERO`] to never warn about them
+pub fn set_threshold(kind: SlowOpKind, threshold: Duration) {
+    let threshold_ms = threshold.as_millis() as u64;
+    kind.threshold().store(threshold_ms, Ordering::Relaxed);
+}
+
+pub fn threshold(kind: SlowOpKind) -> Duration {
+    Duration::from_millis(kind.threshold().load(Ordering::Relaxed))
+}
+
+/// Await `future`, operation `op` of `kind`, and warn if it took longer than the threshold.
+/// `args` is only called then. A future dropped before it finishes isn't reported.
+pub async fn watch_slow<F: Future>(
+    kind: SlowOpKind,
+    op: &'static str,
+    args: impl FnOnce() -> String,
+    future: F,
+) -> F::Output {
+    let threshold = threshold(kind);
+    if threshold.is_zero() {
+        return future.await;
+    }
+    let started = Instant::now();
+    let output = future.await;
+    let took = started.elapsed();
+    if took > threshold
+        && let Some(suppressed) = should_warn(kind, op, Instant::now())
+    {
+        // Outside the macro, which skips its fields when warnings are filtered out
+        let args = args();
+        tracing::warn!(
+            kind = kind.as_str(),
+            op,
+            args,
+            took_ms = took.as_millis() as u64,
+            threshold_ms = threshold.as_millis() as u64,
+            suppressed,
+            "Slow operation"
+        );
+    }
+    output
+}
+
+/// Whether to warn about a slow `op` now, and if so, the slow calls since the last warning
+fn should_warn(kind: SlowOpKind, op: &'static str, now: Instant) -> Option<u64> {
+    let mut suppressed = SUPPRESSED.lock();
+    let entry = suppressed.entry((kind, op)).or_default();
+    if let Some(last_warned) = entry.last_warned
+        && now.duration_since(last_warned) < WARN_INTERVAL
+    {
+        entry.count += 1;
+        return None;
+    }
+    entry.last_warned = Some(now);
+    Some(std::mem::take(&mut entry.count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_warn() {
+        let start = Instant::now();
+        let op = "test_should_warn";
+        assert_eq!(should_warn(SlowOpKind::Store, op, start), Some(0));
+        let soon = start + Duration::from_secs(1);
+        assert_eq!(should_warn(SlowOpKind::Store, op, soon), None);
+        assert_eq!(should_warn(SlowOpKind::Store, op, soon), None);
+        // Operations of another kind or name have their own interval
+        assert_eq!(should_warn(SlowOpKind::Etcd, op, soon), Some(0));
+        let later = start + WARN_INTERVAL;
+        assert_eq!(should_warn(SlowOpKind::Store, op, later), Some(2));
+        assert_eq!(should_warn(SlowOpKind::Store, op, later), None);
+    }
+
+    #[tokio::test]
+    async fn test_watch_slow() {
+        let op = "test_watch_slow";
+        let called = std::sync::atomic::AtomicBool::new(false);
+        let args = || {
+            called.store(true, Ordering::Relaxed);
+            "key=a".to_string()
+        };
+        set_threshold(SlowOpKind::Operator, Duration::from_millis(10));
+        assert_eq!(
+            watch_slow(SlowOpKind::Operator, op, args, async { 1 }).await,
+            1
+        );
+        assert!(!called.load(Ordering::Relaxed));
+
+        let slow = tokio::time::sleep(Duration::from_millis(20));
+        watch_slow(SlowOpKind::Operator, op, args, slow).await;
+        assert!(called.load(Ordering::Relaxed));
+        let default = Duration::from_millis(DEFAULT_SLOW_OPERATOR_MS);
+        set_threshold(SlowOpKind::Operator, default);
+    }
+}
@@ -0,0 +1,477 @@
+// SPDX-FileCopyrightText: Copyright (c) 2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Usage per tenant, for billing and chargeback.
+//!
+//! With `DYN_METERING` set, each endpoint this worker serves counts, per
+//! [tenant](crate::pipeline::context::caller) of the requests it handles, the requests, the
+//! response items it streams back and the bytes of both. The counts are added up in the process
+//! and, every `DYN_METERING_INTERVAL_SECS` (60 by default), published as one [`UsageRecord`] per
+//! tenant and endpoint, in JSON:
+//!
+//! - `nats:billing.usage`: a message on that subject per record
+//! - `bucket:v1/usage`: a key per record in that bucket of the key-value store, kept for
+//!   [`BUCKET_TTL`]. Keys start with the end of the record's window, in Unix milliseconds.
+//!
+//! A request is counted once it has finished, in the window it finished in, and health checks
+//! aren't counted. Records that fail to publish are tried again with the next window's, keeping
+//! at most [`MAX_PENDING_RECORDS`]. At shutdown, the counts of the last window are published too.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+
+use crate::component::Endpoint;
+use crate::health_check::HEALTH_CHECK_ORIGIN;
+use crate::pipeline::context::CallerContext;
+use crate::storage::key_value_store::{Key, KeyValueStoreManager};
+use crate::transports::nats;
+use crate::utils::clock::{Clock, system_clock};
+use crate::utils::tasks::supervisor::{RestartPolicy, Supervisor};
+use crate::{Result, error};
+
+/// Where usage is published, see the [module docs](self)
+pub const METERING_ENV: &str = "DYN_METERING";
+
+pub const METERING_INTERVAL_ENV: &str = "DYN_METERING_INTERVAL_SECS";
+
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long a `bucket:` sink keeps records, for its consumer to collect them
+pub const BUCKET_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Records that failed to publish beyond these are dropped, oldest first
+pub const MAX_PENDING_RECORDS: usize = 100_000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MeteringSink {
+    /// A NATS subject
+    Nats(String),
+    /// A bucket of the [`KeyValueStoreManager`]
+    Bucket(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MeteringConfig {
+    pub sink: MeteringSink,
+    pub interval: Duration,
+}
+
+impl MeteringConfig {
+    /// From a sink in the form of `DYN_METERING`
+    pub fn parse(spec: &str, interval: Duration) -> Result<Self> {
+        let (kind, name) = spec.split_once(':').unwrap_or((spec, ""));
+        let name = name.trim().to_string();
+        if name.is_empty() {
+            anyhow::bail!("{METERING_ENV} '{spec}' has no subject or bucket");
+        }
+        let sink = match kind.trim() {
+            "nats" => MeteringSink::Nats(name),
+            "bucket" => MeteringSink::Bucket(name),
+            other => anyhow::bail!("Unknown metering sink '{other}' in {METERING_ENV}"),
+        };
+        if interval.is_zero() {
+            anyhow::bail!("{METERING_INTERVAL_ENV} must be more than 0");
+        }
+        Ok(MeteringConfig { sink, interval })
+    }
+
+    /// None if `DYN_METERING` is not set
+    pub fn from_env() -> Result<Option<Self>> {
+        let spec = match std::env::var(METERING_ENV) {
+            Ok(spec) if !spec.trim().is_empty() => spec,
+            _ => return Ok(None),
+        };
+        let interval = match std::env::var(METERING_INTERVAL_ENV) {
+            Ok(secs) => Duration::from_secs(
+                secs.trim()
+                    .parse()
+                    .map_err(|err| error!("{METERING_INTERVAL_ENV} '{secs}': {err}"))?,
+            ),
+            Err(_) => DEFAULT_INTERVAL,
+        };
+        MeteringConfig::parse(&spec, interval).map(Some)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub requests: u64,
+    /// Response items streamed back
+    pub items: u64,
+    pub request_bytes: u64,
+    pub response_bytes: u64,
+}
+
+impl Usage {
+    fn add(&mut self, other: &Usage) {
+        self.requests += other.requests;
+        self.items += other.items;
+        self.request_bytes += other.request_bytes;
+        self.response_bytes += other.response_bytes;
+    }
+}
+
+/// The usage of one tenant on one endpoint of this worker, over a window
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageRecord {
+    /// None for the requests that didn't name a tenant
+    pub tenant: Option<String>,
+    pub namespace: String,
+    pub component: String,
+    pub endpoint: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub worker_id: Option<crate::identity::WorkerId>,
+    /// Unix time in milliseconds
+    pub window_start: u64,
+    pub window_end: u64,
+    #[serde(flatten)]
+    pub usage: Usage,
+}
+
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct EndpointPath {
+    namespace: String,
+    component: String,
+    endpoint: String,
+}
+
+struct Window {
+    /// Unix time in milliseconds
+    start: u64,
+    usage: HashMap<(Option<String>, Arc<EndpointPath>), Usage>,
+}
+
+/// The usage counted by a [`DistributedRuntime`](crate::DistributedRuntime) since it was last
+/// published
+pub struct Metering {
+    window: Mutex<Window>,
+    /// Dates the windows
+    clock: Arc<dyn Clock>,
+}
+
+impl std::fmt::Debug for Metering {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Metering")
+    }
+}
+
+impl Metering {
+    pub fn new() -> Arc<Self> {
+        Metering::with_clock(system_clock())
+    }
+
+    /// Dating the windows by `clock`
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Arc<Self> {
+        Arc::new(Metering {
+            window: Mutex::new(Window {
+                start: clock.unix_millis(),
+                usage: HashMap::new(),
+            }),
+            clock,
+        })
+    }
+
+    /// Counts usage and publishes it to `config`'s sink under `supervisor`, until its token is
+    /// cancelled. The last window is published then.
+    pub fn start(
+        config: MeteringConfig,
+        nats_client: Option<nats::Client>,
+        store: &KeyValueStoreManager,
+        supervisor: &Supervisor,
+    ) -> Result<Arc<Self>> {
+        let sink = match config.sink {
+            MeteringSink::Nats(subject) => {
+                let client = nats_client
+                    .ok_or_else(|| error!("Metering to NATS subject {subject} needs NATS"))?;
+                Sink::Nats(Box::new(client), subject)
+            }
+            MeteringSink::Bucket(bucket) => Sink::Bucket(store.clone(), bucket),
+        };
+        let metering = Metering::with_clock(store.clock().clone());
+        let sink = Arc::new(sink);
+        let cancel_token = supervisor.token();
+        let policy = RestartPolicy {
+            run_to_completion: true,
+            ..Default::default()
+        };
+        let publishing = metering.clone();
+        supervisor.spawn("metering", policy, move || {
+            let metering = publishing.clone();
+            let sink = sink.clone();
+            let cancel_token = cancel_token.clone();
+            async move {
+                publish_usage(metering, &sink, config.interval, cancel_token).await;
+                Ok(())
+            }
+        });
+        Ok(metering)
+    }
+
+    /// Where the requests `endpoint` serves are counted
+    pub fn endpoint(self: &Arc<Self>, endpoint: &Endpoint) -> EndpointMetering {
+        EndpointMetering {
+            metering: self.clone(),
+            path: Arc::new(EndpointPath {
+                namespace: endpoint.component().namespace().name(),
+                component: endpoint.component().name().to_string(),
+                endpoint: endpoint.name().to_string(),
+            }),
+        }
+    }
+
+    fn add(&self, tenant: Option<String>, path: &Arc<EndpointPath>, usage: &Usage) {
+        let mut window = self.window.lock();
+        window
+            .usage
+            .entry((tenant, path.clone()))
+            .or_default()
+            .add(usage);
+    }
+
+    /// End the window, returning its records
+    pub fn take(&self) -> Vec<UsageRecord> {
+        let now = self.clock.unix_millis();
+        let (start, usage) = {
+            let mut window = self.window.lock();
+            let start = std::mem::replace(&mut window.start, now);
+            (start, std::mem::take(&mut window.usage))
+        };
+        let worker_id = crate::identity::worker_id();
+        usage
+            .into_iter()
+            .map(|((tenant, path), usage)| UsageRecord {
+                tenant,
+                namespace: path.namespace.clone(),
+                component: path.component.clone(),
+                endpoint: path.endpoint.clone(),
+                worker_id,
+                window_start: start,
+                window_end: now,
+                usage,
+            })
+            .collect()
+    }
+}
+
+/// Counts the requests of one endpoint, see [`Metering::endpoint`]
+#[derive(Clone)]
+pub struct EndpointMetering {
+    metering: Arc<Metering>,
+    path: Arc<EndpointPath>,
+}
+
+impl EndpointMetering {
+    /// Start counting a request of `bytes` from `caller`. None if it isn't metered.
+    pub fn request(&self, caller: &CallerContext, bytes: usize) -> Option<RequestUsage> {
+        if caller.origin.as_deref() == Some(HEALTH_CHECK_ORIGIN) {
+            return None;
+        }
+        Some(RequestUsage {
+            endpoint: self.clone(),
+            tenant: caller.tenant.clone(),
+            usage: Usage {
+                requests: 1,
+                request_bytes: bytes as u64,
+                ..Default::default()
+            },
+        })
+    }
+}
+
+/// The usage of one request so far, added to its window when dropped
+pub struct RequestUsage {
+    endpoint: EndpointMetering,
+    tenant: Option<String>,
+    usage: Usage,
+}
+
+impl RequestUsage {
+    /// A response item of `bytes` was streamed back
+    pub fn item(&mut self, bytes: usize) {
+        self.usage.items += 1;
+        self.usage.response_bytes += bytes as u64;
+    }
+}
+
+impl Drop for RequestUsage {
+    fn drop(&mut self) {
+        let metering = &self.endpoint.metering;
+        metering.add(self.tenant.take(), &self.endpoint.path, &self.usage);
+    }
+}
+
+enum Sink {
+    Nats(Box<nats::Client>, String),
+    Bucket(KeyValueStoreManager, String),
+}
+
+async fn publish_usage(
+    metering: Arc<Metering>,
+    sink: &Sink,
+    interval: Duration,
+    cancel_token: CancellationToken,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    ticker.tick().await;
+    let mut pending = Vec::new();
+    loop {
+        let stopping = tokio::select! {
+            _ = ticker.tick() => false,
+            _ = cancel_token.cancelled() => true,
+        };
+        pending.extend(metering.take());
+        if !pending.is_empty() {
+            let published = publish(sink, &pending).await;
+            pending.drain(..published);
+        }
+        if pending.len() > MAX_PENDING_RECORDS {
+            let dropped = pending.len() - MAX_PENDING_RECORDS;
+            tracing::error!(
+                dropped,
+                "Dropping usage records that could not be published"
+            );
+            pending.drain(..dropped);
+        }
+        if stopping {
+            return;
+        }
+    }
+}
+
+/// Publish `records` in order, returning how many were before one failed
+async fn publish(sink: &Sink, records: &[UsageRecord]) -> usize {
+    let mut published = 0;
+    let result: Result<()> = async {
+        match sink {
+            Sink::Nats(client, subject) => {
+                for record in records {
+                    let payload = serde_json::to_vec(record)?;
+                    client
+                        .client()
+                        .publish(subject.clone(), payload.into())
+                        .await?;
+                    published += 1;
+                }
+                client.client().flush().await?;
+            }
+            Sink::Bucket(store, bucket) => {
+                let bucket = store.get_or_create_bucket(bucket, Some(BUCKET_TTL)).await?;
+                for record in records {
+                    let id = uuid::Uuid::new_v4().simple();
+                    let key = Key::from_raw(format!("{:013}-{id}", record.window_end));
+                    bucket
+                        .insert(&key, &serde_json::to_string(record)?, 0)
+                        .await?;
+                    published += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+    .await;
+    if let Err(err) = result {
+        let unpublished = records.len() - published;
+        tracing::warn!(%err, unpublished, "Failed to publish usage records, retrying later");
+    }
+    published
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::clock::TestClock;
+
+    fn path(endpoint: &str) -> Arc<EndpointPath> {
+        Arc::new(EndpointPath {
+            namespace: "dynamo".to_string(),
+            component: "backend".to_string(),
+            endpoint: endpoint.to_string(),
+        })
+    }
+
+    #[test]
+    fn test_parse() {
+        let config = MeteringConfig::parse("nats: billing.usage", DEFAULT_INTERVAL).unwrap();
+        assert_eq!(config.sink, MeteringSink::Nats("billing.usage".to_string()));
+        let config = MeteringConfig::parse("bucket:v1/usage", DEFAULT_INTERVAL).unwrap();
+        assert_eq!(config.sink, MeteringSink::Bucket("v1/usage".to_string()));
+        for spec in ["nats", "bucket:", "kafka:usage"] {
+            assert!(
+                MeteringConfig::parse(spec, DEFAULT_INTERVAL).is_err(),
+                "{spec}"
+            );
+        }
+        assert!(MeteringConfig::parse("nats:usage", Duration::ZERO).is_err());
+    }
+
+    #[test]
+    fn test_usage() {
+        let clock = TestClock::new();
+        let started = clock.unix_millis();
+        let metering = Metering::with_clock(Arc::new(clock.clone()));
+        let generate = EndpointMetering {
+            metering: metering.clone(),
+            path: path("generate"),
+        };
+        let acme = CallerContext::default().with_tenant("acme");
+        for _ in 0..2 {
+            let mut usage = generate.request(&acme, 100).unwrap();
+            usage.item(10);
+            usage.item(20);
+        }
+        drop(generate.request(&CallerContext::default(), 50));
+        let health_check = CallerContext::default().with_origin(HEALTH_CHECK_ORIGIN);
+        assert!(generate.request(&health_check, 50).is_none());
+
+        clock.advance(Duration::from_secs(60));
+        let mut records = metering.take();
+        records.sort_by(|a, b| a.tenant.cmp(&b.tenant));
+        assert_eq!(records[0].window_start, started);
+        assert_eq!(records[0].window_end, started + 60_000);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].tenant, None);
+        assert_eq!(records[0].usage.requests, 1);
+        let expected = Usage {
+            requests: 2,
+            items: 4,
+            request_bytes: 200,
+            response_bytes: 60,
+        };
+        assert_eq!(records[1].tenant.as_deref(), Some("acme"));
+        assert_eq!(records[1].usage, expected);
+        assert_eq!(records[1].endpoint, "generate");
+        let json = serde_json::to_value(&records[1]).unwrap();
+        assert_eq!(json["response_bytes"], 60);
+        // The next window starts where this one ended, empty
+        assert!(metering.take().is_empty());
+        assert_eq!(metering.window.lock().start, records[1].window_end);
+    }
+
+    #[tokio::test]
+    async fn test_publish_to_bucket() {
+        let store = KeyValueStoreManager::memory();
+        let metering = Metering::new();
+        let generate = EndpointMetering {
+            metering: metering.clone(),
+            path: path("generate"),
+        };
+        drop(generate.request(&CallerContext::default().with_tenant("acme"), 1));
+        let sink = Sink::Bucket(store.clone(), "v1/usage".to_string());
+        let records = metering.take();
+        assert_eq!(publish(&sink, &records).await, 1);
+
+        let bucket = store.get_bucket("v1/usage").await.unwrap().unwrap();
+        let entries = bucket.entries().await.unwrap();
+        let stored: Vec<UsageRecord> = entries
+            .values()
+            .map(|value| serde_json::from_slice(value).unwrap())
+            .collect();
+        assert_eq!(stored, records);
+    }
+}
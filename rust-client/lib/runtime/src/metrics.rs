@@ -6,6 +6,8 @@
 //! This module provides a trait-based interface for creating and managing Prometheus metrics
 //! with automatic label injection and hierarchical naming support.
 
+pub mod export;
+pub mod process;
 pub mod prometheus_names;
 
 use parking_lot::Mutex;
@@ -41,6 +43,9 @@ pub const USE_AUTO_LABELS: bool = true;
 // Prometheus imports
 use prometheus::Encoder;
 
+/// Default of [`MetricsRegistry::set_max_label_sets`]
+pub const DEFAULT_MAX_LABEL_SETS: usize = 1000;
+
 /// Validate that a label slice has no duplicate keys.
 /// Returns Ok(()) when all keys are unique; otherwise returns an error naming the duplicate key.
 fn validate_no_duplicate_label_keys(labels: &[(&str, &str)]) -> anyhow::Result<()> {
@@ -265,6 +270,17 @@ pub fn create_metric<T: PrometheusMetric, H: MetricsHierarchy + ?Sized>(
         }
     }
 
+    // Without a configured worker ID, label sets stay as they were
+    if let Some(worker_id) = crate::identity::worker_id() {
+        if labels.iter().any(|(key, _)| *key == labels::WORKER_ID) {
+            return Err(anyhow::anyhow!(
+                "Label '{}' is added automatically and cannot be set",
+                labels::WORKER_ID
+            ));
+        }
+        updated_labels.push((labels::WORKER_ID.to_string(), worker_id.to_string()));
+    }
+
     // Add user labels
     updated_labels.extend(
         labels
@@ -273,6 +289,13 @@ pub fn create_metric<T: PrometheusMetric, H: MetricsHierarchy + ?Sized>(
     );
     // Note: stored labels functionality has been removed
 
+    // Every metric ends up in the root registry, so that is where label sets are counted
+    let root_registry = match parent_hierarchies.first() {
+        Some(root) => root.get_metrics_registry(),
+        None => hierarchy.get_metrics_registry(),
+    };
+    root_registry.admit_label_set(&metric_name, &updated_labels)?;
+
     // Handle different metric types
     let prometheus_metric = if std::any::TypeId::of::<T>()
         == std::any::TypeId::of::<prometheus::CounterVec>()
@@ -659,6 +682,16 @@ pub struct MetricsRegistry {
     /// Wrapped in Arc to preserve callbacks across clones (e.g., vLLM callbacks registered at Endpoint remain accessible at DRT).
     pub prometheus_expfmt_callbacks:
         Arc<std::sync::RwLock<Vec<PrometheusExpositionFormatCallback>>>,
+
+    /// The label sets each metric was created with, see [`MetricsRegistry::admit_label_set`].
+    /// Shared across clones like the callbacks.
+    label_sets: Arc<Mutex<LabelSets>>,
+}
+
+/// Label sets by metric name, and how many a metric may have
+struct LabelSets {
+    by_metric: HashMap<String, HashSet<Vec<(String, String)>>>,
+    max: usize,
 }
 
 impl std::fmt::Debug for MetricsRegistry {
@@ -693,6 +726,7 @@ impl Clone for MetricsRegistry {
             // Previously used Vec::new() here, which caused vllm: metrics to disappear.
             prometheus_update_callbacks: Arc::clone(&self.prometheus_update_callbacks),
             prometheus_expfmt_callbacks: Arc::clone(&self.prometheus_expfmt_callbacks),
+            label_sets: Arc::clone(&self.label_sets),
         }
     }
 }
@@ -704,9 +738,53 @@ impl MetricsRegistry {
             prometheus_registry: std::sync::RwLock::new(prometheus::Registry::new()),
             prometheus_update_callbacks: Arc::new(std::sync::RwLock::new(Vec::new())),
             prometheus_expfmt_callbacks: Arc::new(std::sync::RwLock::new(Vec::new())),
+            label_sets: Arc::new(Mutex::new(LabelSets {
+                by_metric: HashMap::new(),
+                max: DEFAULT_MAX_LABEL_SETS,
+            })),
         }
     }
 
+    /// Limit the label sets a metric may be created with, so that label values taken from
+    /// user-supplied names, of models or endpoints, can't grow the number of series without
+    /// bound. Metrics already over it keep what they have.
+    pub fn set_max_label_sets(&self, max: usize) {
+        self.label_sets.lock().max = max;
+    }
+
+    /// Record that `metric_name` is being created with `labels`. Fails if that would make
+    /// more label sets than allowed by [`MetricsRegistry::set_max_label_sets`].
+    pub fn admit_label_set(
+        &self,
+        metric_name: &str,
+        labels: &[(String, String)],
+    ) -> anyhow::Result<()> {
+        let mut label_sets = self.label_sets.lock();
+        let max = label_sets.max;
+        let mut labels = labels.to_vec();
+        labels.sort();
+        let sets = label_sets
+            .by_metric
+            .entry(metric_name.to_string())
+            .or_default();
+        if sets.contains(&labels) {
+            return Ok(());
+        }
+        if sets.len() >= max {
+            tracing::warn!(
+                metric_name,
+                max,
+                ?labels,
+                "Refusing metric over its label set limit"
+            );
+            return Err(anyhow::anyhow!(
+                "Metric '{metric_name}' already has {max} label sets, the most allowed"
+            ));
+        }
+        sets.insert(labels);
+        Ok(())
+    }
+
     /// Add a callback function that receives a reference to any MetricsHierarchy
     pub fn add_update_callback(&self, callback: PrometheusUpdateCallback) {
         self.prometheus_update_callbacks
@@ -900,6 +978,26 @@ mod test_helpers {
 mod test_metricsregistry_units {
     use super::*;
 
+    #[test]
+    fn test_label_set_limit() {
+        let registry = MetricsRegistry::new();
+        registry.set_max_label_sets(2);
+        let labels = |model: &str| vec![("model".to_string(), model.to_string())];
+        registry.admit_label_set("requests", &labels("a")).unwrap();
+        registry.admit_label_set("requests", &labels("b")).unwrap();
+        // Seen before, so not another one
+        registry.admit_label_set("requests", &labels("a")).unwrap();
+        let err = registry
+            .admit_label_set("requests", &labels("c"))
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("already has 2 label sets"),
+            "{err}"
+        );
+        // Counted per metric
+        registry.admit_label_set("errors", &labels("c")).unwrap();
+    }
+
     #[test]
     fn test_build_component_metric_name_with_prefix() {
         // Test that build_component_metric_name correctly prepends the dynamo_component prefix
@@ -1922,6 +2020,38 @@ mod test_metricsregistry_nats {
                 0.0001,
                 1.0,
             ), // Processing time sum (wide range)
+            (
+                format!(
+                    "{}_count",
+                    build_component_metric_name(work_handler::TIME_TO_FIRST_RESPONSE_SECONDS)
+                ),
+                10.0,
+                10.0,
+            ), // one response item per message
+            (
+                format!(
+                    "{}_count",
+                    build_component_metric_name(work_handler::INTER_RESPONSE_SECONDS)
+                ),
+                0.0,
+                0.0,
+            ), // so no gaps between items
+            (
+                format!(
+                    "{}_count",
+                    build_component_metric_name(work_handler::REQUEST_SIZE_BYTES)
+                ),
+                10.0,
+                10.0,
+            ), // 10 messages
+            (
+                format!(
+                    "{}_count",
+                    build_component_metric_name(work_handler::RESPONSE_SIZE_BYTES)
+                ),
+                10.0,
+                20.0,
+            ), // each response item, and the final one of a stream
         ];
 
         println!("\n=== Checking Post-Activity All Metrics (NATS + Work Handler) ===");
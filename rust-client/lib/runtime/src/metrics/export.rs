@@ -0,0 +1,398 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Pushing metrics to a StatsD agent or an OTLP collector, where nothing scrapes `/metrics`.
+//!
+//! Selected with [`RuntimeConfig::metrics_exporter`], `DYN_METRICS_EXPORTER=statsd` or `otlp`.
+//! Every [`RuntimeConfig::metrics_export_interval_secs`] the runtime's metrics are gathered as
+//! for a scrape of `/metrics`, which keeps working, and sent on:
+//!
+//! - StatsD: over UDP, counters as their increase since the last push and gauges as they are,
+//!   labels as DogStatsD tags.
+//! - OTLP: over gRPC, through an OpenTelemetry meter provider exporting at the same interval.
+//!
+//! Both take histograms and summaries as their `_sum` and `_count`. The buckets and quantiles
+//! are only on `/metrics`.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use opentelemetry::KeyValue;
+use opentelemetry::metrics::MeterProvider as _;
+use opentelemetry_otlp::WithExportConfig;
+use prometheus::proto::{MetricFamily, MetricType};
+
+use crate::config::{MetricsExporter, RuntimeConfig};
+use crate::utils::tasks::supervisor::RestartPolicy;
+use crate::{DistributedRuntime, Result, metrics::MetricsHierarchy};
+
+const DEFAULT_STATSD_ENDPOINT: &str = "127.0.0.1:8125";
+
+/// Fits in one Ethernet frame with the IP and UDP headers
+const STATSD_MAX_PACKET: usize = 1432;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Counter,
+    Gauge,
+}
+
+/// One series of a gathered metric
+#[derive(Debug, Clone, PartialEq)]
+struct Sample {
+    name: String,
+    labels: Vec<(String, String)>,
+    kind: Kind,
+    value: f64,
+}
+
+impl Sample {
+    fn series(&self) -> (String, Vec<(String, String)>) {
+        (self.name.clone(), self.labels.clone())
+    }
+}
+
+fn samples(families: &[MetricFamily]) -> Vec<Sample> {
+    let mut samples = Vec::new();
+    for family in families {
+        for metric in &family.metric {
+            let labels: Vec<(String, String)> = metric
+                .label
+                .iter()
+                .map(|label| (label.name().to_string(), label.value().to_string()))
+                .collect();
+            let mut push = |suffix: &str, kind, value| {
+                samples.push(Sample {
+                    name: format!("{}{suffix}", family.name()),
+                    labels: labels.clone(),
+                    kind,
+                    value,
+                })
+            };
+            match family.type_() {
+                MetricType::COUNTER => push("", Kind::Counter, metric.counter.value()),
+                MetricType::GAUGE => push("", Kind::Gauge, metric.gauge.value()),
+                MetricType::UNTYPED => push("", Kind::Gauge, metric.untyped.value()),
+                MetricType::HISTOGRAM => {
+                    let histogram = &metric.histogram;
+                    push("_sum", Kind::Counter, histogram.sample_sum());
+                    push("_count", Kind::Counter, histogram.sample_count() as f64);
+                }
+                MetricType::SUMMARY => {
+                    let summary = &metric.summary;
+                    push("_sum", Kind::Counter, summary.sample_sum());
+                    push("_count", Kind::Counter, summary.sample_count() as f64);
+                }
+            }
+        }
+    }
+    samples
+}
+
+/// The last value of each counter pushed, to push the increase from it
+#[derive(Default)]
+struct Increases(HashMap<(String, Vec<(String, String)>), f64>);
+
+impl Increases {
+    /// A counter that went down was reset, by a restart of what counts, so all of it is new
+    fn of(&mut self, sample: &Sample) -> f64 {
+        let last = self.0.insert(sample.series(), sample.value).unwrap_or(0.0);
+        match sample.value >= last {
+            true => sample.value - last,
+            false => sample.value,
+        }
+    }
+}
+
+/// The StatsD lines for `samples`, leaving out counters that didn't go up
+fn statsd_lines(samples: &[Sample], increases: &mut Increases) -> Vec<String> {
+    let mut lines = Vec::with_capacity(samples.len());
+    for sample in samples {
+        let (value, kind) = match sample.kind {
+            Kind::Counter => (increases.of(sample), "c"),
+            Kind::Gauge => (sample.value, "g"),
+        };
+        if sample.kind == Kind::Counter && value == 0.0 {
+            continue;
+        }
+        let mut line = format!("{}:{value}|{kind}", sample.name);
+        for (i, (key, value)) in sample.labels.iter().enumerate() {
+            line.push_str(if i == 0 { "|#" } else { "," });
+            line.push_str(&format!("{key}:{value}"));
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+/// `lines` in as few packets of at most `max` bytes as they fit in. A longer line goes alone.
+fn statsd_packets(lines: Vec<String>, max: usize) -> Vec<String> {
+    let mut packets: Vec<String> = Vec::new();
+    for line in lines {
+        match packets.last_mut() {
+            Some(packet) if packet.len() + 1 + line.len() <= max => {
+                packet.push('\n');
+                packet.push_str(&line);
+            }
+            _ => packets.push(line),
+        }
+    }
+    packets
+}
+
+struct Statsd {
+    socket: tokio::net::UdpSocket,
+    increases: Increases,
+}
+
+impl Statsd {
+    async fn connect(endpoint: &str) -> Result<Self> {
+        let socket = tokio::net::UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(endpoint).await?;
+        Ok(Statsd {
+            socket,
+            increases: Increases::default(),
+        })
+    }
+
+    async fn push(&mut self, samples: &[Sample]) -> Result<()> {
+        let lines = statsd_lines(samples, &mut self.increases);
+        for packet in statsd_packets(lines, STATSD_MAX_PACKET) {
+            self.socket.send(packet.as_bytes()).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Records the samples into OpenTelemetry instruments, which the provider exports by itself
+struct Otlp {
+    provider: opentelemetry_sdk::metrics::SdkMeterProvider,
+    meter: opentelemetry::metrics::Meter,
+    counters: HashMap<String, opentelemetry::metrics::Counter<f64>>,
+    gauges: HashMap<String, opentelemetry::metrics::Gauge<f64>>,
+    increases: Increases,
+}
+
+impl Otlp {
+    fn new(endpoint: &str, interval: Duration) -> Result<Self> {
+        let exporter = opentelemetry_otlp::MetricExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build()?;
+        let reader = opentelemetry_sdk::metrics::PeriodicReader::builder(exporter)
+            .with_interval(interval)
+            .build();
+        let resource = opentelemetry_sdk::Resource::builder_empty()
+            .with_service_name(crate::logging::get_service_name())
+            .build();
+        let provider = opentelemetry_sdk::metrics::SdkMeterProvider::builder()
+            .with_reader(reader)
+            .with_resource(resource)
+            .build();
+        let meter = provider.meter("dynamo");
+        Ok(Otlp {
+            provider,
+            meter,
+            counters: HashMap::new(),
+            gauges: HashMap::new(),
+            increases: Increases::default(),
+        })
+    }
+
+    fn record(&mut self, samples: &[Sample]) {
+        for sample in samples {
+            let attributes: Vec<KeyValue> = sample
+                .labels
+                .iter()
+                .map(|(key, value)| KeyValue::new(key.clone(), value.clone()))
+                .collect();
+            match sample.kind {
+                Kind::Counter => {
+                    let increase = self.increases.of(sample);
+                    let meter = &self.meter;
+                    self.counters
+                        .entry(sample.name.clone())
+                        .or_insert_with(|| meter.f64_counter(sample.name.clone()).build())
+                        .add(increase, &attributes);
+                }
+                Kind::Gauge => {
+                    let meter = &self.meter;
+                    self.gauges
+                        .entry(sample.name.clone())
+                        .or_insert_with(|| meter.f64_gauge(sample.name.clone()).build())
+                        .record(sample.value, &attributes);
+                }
+            }
+        }
+    }
+}
+
+enum Exporter {
+    Statsd(Statsd),
+    Otlp(Otlp),
+}
+
+/// Start pushing the metrics of `drt` as `config` says, if it says to, under the runtime's
+/// supervisor. Pushing stops when the runtime shuts down, after a last push.
+pub(crate) async fn start(drt: &DistributedRuntime, config: &RuntimeConfig) -> Result<()> {
+    let interval = Duration::from_secs(config.metrics_export_interval_secs.max(1));
+    let endpoint = config.metrics_export_endpoint.as_deref();
+    let exporter = match config.metrics_exporter {
+        MetricsExporter::None => return Ok(()),
+        MetricsExporter::Statsd => {
+            let endpoint = endpoint.unwrap_or(DEFAULT_STATSD_ENDPOINT);
+            tracing::info!(endpoint, ?interval, "Pushing metrics to StatsD");
+            Exporter::Statsd(Statsd::connect(endpoint).await?)
+        }
+        MetricsExporter::Otlp => {
+            let endpoint = endpoint.unwrap_or(crate::logging::DEFAULT_OTLP_ENDPOINT);
+            tracing::info!(endpoint, ?interval, "Exporting metrics over OTLP");
+            Exporter::Otlp(Otlp::new(endpoint, interval)?)
+        }
+    };
+
+    let exporter = Arc::new(tokio::sync::Mutex::new(exporter));
+    let cancel_token = drt.runtime().child_token();
+    // Stops on the endpoint token, which fires before the supervisor's, and pushes once more
+    let policy = RestartPolicy {
+        run_to_completion: true,
+        ..Default::default()
+    };
+    let supervisor = drt.runtime().supervisor().child("metrics");
+    let drt = drt.clone();
+    supervisor.spawn("export", policy, move || {
+        let drt = drt.clone();
+        let exporter = exporter.clone();
+        let cancel_token = cancel_token.clone();
+        async move {
+            let mut exporter = exporter.lock().await;
+            push(&drt, &mut exporter, interval, &cancel_token).await;
+            Ok(())
+        }
+    });
+    Ok(())
+}
+
+/// Push every `interval` until `cancel_token` is cancelled, then push and flush a last time
+async fn push(
+    drt: &DistributedRuntime,
+    exporter: &mut Exporter,
+    interval: Duration,
+    cancel_token: &tokio_util::sync::CancellationToken,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        let stopping = tokio::select! {
+            _ = cancel_token.cancelled() => true,
+            _ = ticker.tick() => false,
+        };
+        let samples = gather(drt);
+        match exporter {
+            Exporter::Statsd(statsd) => {
+                if let Err(err) = statsd.push(&samples).await {
+                    tracing::warn!(%err, "Pushing metrics to StatsD failed");
+                }
+            }
+            Exporter::Otlp(otlp) => otlp.record(&samples),
+        }
+        if stopping {
+            break;
+        }
+    }
+    // Flushes what was recorded last
+    if let Exporter::Otlp(otlp) = exporter
+        && let Err(err) = otlp.provider.shutdown()
+    {
+        tracing::warn!(%err, "Shutting down the OTLP metrics exporter failed");
+    }
+}
+
+/// What a scrape of `/metrics` would see, less the exposition text callbacks, which only
+/// produce text
+fn gather(drt: &DistributedRuntime) -> Vec<Sample> {
+    let registry = drt.get_metrics_registry();
+    for result in registry.execute_update_callbacks() {
+        if let Err(err) = result {
+            tracing::error!(%err, "Error executing metrics callback");
+        }
+    }
+    samples(&registry.get_prometheus_registry().gather())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(name: &str, kind: Kind, value: f64) -> Sample {
+        Sample {
+            name: name.to_string(),
+            labels: vec![("dynamo_component".to_string(), "backend".to_string())],
+            kind,
+            value,
+        }
+    }
+
+    #[test]
+    fn test_samples() {
+        let registry = prometheus::Registry::new();
+        let requests = prometheus::IntCounter::new("requests_total", "requests").unwrap();
+        let opts = prometheus::HistogramOpts::new("latency_seconds", "latency");
+        let latency = prometheus::Histogram::with_opts(opts).unwrap();
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(latency.clone())).unwrap();
+        requests.inc_by(2);
+        latency.observe(0.25);
+
+        let samples = samples(&registry.gather());
+        let names: Vec<_> = samples.iter().map(|s| (s.name.as_str(), s.value)).collect();
+        assert_eq!(
+            names,
+            vec![
+                ("latency_seconds_sum", 0.25),
+                ("latency_seconds_count", 1.0),
+                ("requests_total", 2.0)
+            ]
+        );
+        assert!(samples.iter().all(|s| s.kind == Kind::Counter));
+    }
+
+    #[test]
+    fn test_statsd_lines() {
+        let mut increases = Increases::default();
+        let samples = vec![
+            sample("requests_total", Kind::Counter, 5.0),
+            sample("inflight", Kind::Gauge, 3.0),
+        ];
+        assert_eq!(
+            statsd_lines(&samples, &mut increases),
+            vec![
+                "requests_total:5|c|#dynamo_component:backend",
+                "inflight:3|g|#dynamo_component:backend"
+            ]
+        );
+
+        // Only the increase, and nothing for a counter that stayed put
+        let samples = vec![
+            sample("requests_total", Kind::Counter, 7.0),
+            sample("errors_total", Kind::Counter, 0.0),
+        ];
+        let lines = statsd_lines(&samples, &mut increases);
+        assert_eq!(lines, vec!["requests_total:2|c|#dynamo_component:backend"]);
+        // Reset by a restart
+        let samples = vec![sample("requests_total", Kind::Counter, 1.0)];
+        let lines = statsd_lines(&samples, &mut increases);
+        assert_eq!(lines, vec!["requests_total:1|c|#dynamo_component:backend"]);
+
+        let lines = vec![
+            "a".repeat(10),
+            "b".repeat(10),
+            "c".repeat(30),
+            "d".repeat(5),
+        ];
+        let packets = statsd_packets(lines, 21);
+        let lens: Vec<_> = packets.iter().map(String::len).collect();
+        assert_eq!(lens, vec![21, 30, 5]);
+    }
+}
@@ -0,0 +1,284 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The process's memory, CPU, file descriptors and threads, and the load of its tokio executors.
+//!
+//! Latency that grows while the endpoints themselves are no slower is usually an executor with
+//! more ready tasks than its workers get through. The [`tokio_runtime`] metrics show the tasks
+//! alive and queued and how busy the workers are, for the primary and the secondary runtime,
+//! which are the same executor in a [`Runtime`](crate::Runtime) made from a handle, or from
+//! settings without background threads of its own. The blocking pool metrics need tokio's
+//! unstable metrics, so a build with `RUSTFLAGS="--cfg tokio_unstable"`.
+//!
+//! The [`process`] metrics are read from `/proc` and stay at zero on other systems. The
+//! [`blocking_tasks`] metrics are those of [`crate::compute::blocking`].
+//!
+//! [`tokio_runtime`]: crate::metrics::prometheus_names::tokio_runtime
+//! [`blocking_tasks`]: crate::metrics::prometheus_names::blocking_tasks
+//! [`process`]: crate::metrics::prometheus_names::process
+
+use prometheus::{Gauge, GaugeVec, IntGauge, IntGaugeVec};
+
+use crate::metrics::MetricsHierarchy;
+use crate::metrics::prometheus_names::{blocking_tasks, process as process_metrics, tokio_runtime};
+
+/// Clock ticks per second of the times in `/proc/<pid>/stat`. USER_HZ is 100 on every
+/// architecture Linux exports it to userspace for.
+const CLOCK_TICKS_PER_SEC: f64 = 100.0;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct ProcessStats {
+    resident_bytes: u64,
+    cpu_seconds: f64,
+    open_fds: u64,
+    threads: u64,
+}
+
+/// User plus system time and threads, from `/proc/self/stat`
+fn parse_stat(stat: &str) -> Option<(f64, u64)> {
+    // The command name in parentheses may itself hold spaces and parentheses
+    let (_, fields) = stat.rsplit_once(')')?;
+    let fields: Vec<&str> = fields.split_whitespace().collect();
+    // Numbered from the state, the third field
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    let threads: u64 = fields.get(17)?.parse().ok()?;
+    Some(((utime + stime) as f64 / CLOCK_TICKS_PER_SEC, threads))
+}
+
+/// Resident set size from `/proc/self/status`, which has it in kB
+fn parse_vm_rss(status: &str) -> Option<u64> {
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+fn read_process_stats() -> Option<ProcessStats> {
+    let (cpu_seconds, threads) = parse_stat(&std::fs::read_to_string("/proc/self/stat").ok()?)?;
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    Some(ProcessStats {
+        resident_bytes: parse_vm_rss(&status).unwrap_or(0),
+        cpu_seconds,
+        open_fds: std::fs::read_dir("/proc/self/fd").map_or(0, |fds| fds.count() as u64),
+        threads,
+    })
+}
+
+pub(crate) struct ProcessMetrics {
+    resident_bytes: IntGauge,
+    cpu_seconds: Gauge,
+    open_fds: IntGauge,
+    threads: IntGauge,
+    workers: IntGaugeVec,
+    alive_tasks: IntGaugeVec,
+    global_queue_depth: IntGaugeVec,
+    busy_seconds: GaugeVec,
+    #[cfg(tokio_unstable)]
+    blocking_threads: IntGaugeVec,
+    #[cfg(tokio_unstable)]
+    idle_blocking_threads: IntGaugeVec,
+    #[cfg(tokio_unstable)]
+    blocking_queue_depth: IntGaugeVec,
+    runtimes: Vec<(&'static str, tokio::runtime::Handle)>,
+    tasks_queued: IntGaugeVec,
+    tasks_running: IntGaugeVec,
+    tasks_completed: IntGaugeVec,
+    tasks_queue_seconds: GaugeVec,
+    tasks_run_seconds: GaugeVec,
+    runtime: crate::Runtime,
+}
+
+impl ProcessMetrics {
+    pub(crate) fn new(drt: &crate::DistributedRuntime) -> anyhow::Result<Self> {
+        let metrics = drt.metrics();
+        let runtime_label = &[tokio_runtime::RUNTIME_LABEL];
+        let task_label = &[blocking_tasks::TASK_LABEL];
+        let runtimes = vec![
+            ("primary", drt.runtime().primary()),
+            ("secondary", drt.runtime().secondary()),
+        ];
+        Ok(ProcessMetrics {
+            resident_bytes: metrics.create_intgauge(
+                process_metrics::RESIDENT_MEMORY_BYTES,
+                "Resident set size of the process in bytes",
+                &[],
+            )?,
+            cpu_seconds: metrics.create_gauge(
+                process_metrics::CPU_SECONDS_TOTAL,
+                "User and system CPU time of the process in seconds",
+                &[],
+            )?,
+            open_fds: metrics.create_intgauge(
+                process_metrics::OPEN_FDS,
+                "File descriptors the process has open",
+                &[],
+            )?,
+            threads: metrics.create_intgauge(
+                process_metrics::THREADS,
+                "OS threads of the process",
+                &[],
+            )?,
+            workers: metrics.create_intgaugevec(
+                tokio_runtime::WORKERS,
+                "Worker threads of the tokio runtime",
+                runtime_label,
+                &[],
+            )?,
+            alive_tasks: metrics.create_intgaugevec(
+                tokio_runtime::ALIVE_TASKS,
+                "Tasks spawned on the tokio runtime and not yet finished",
+                runtime_label,
+                &[],
+            )?,
+            global_queue_depth: metrics.create_intgaugevec(
+                tokio_runtime::GLOBAL_QUEUE_DEPTH,
+                "Tasks waiting in the tokio runtime's shared queue",
+                runtime_label,
+                &[],
+            )?,
+            busy_seconds: metrics.create_gaugevec(
+                tokio_runtime::WORKER_BUSY_SECONDS_TOTAL,
+                "Seconds the tokio runtime's workers together spent running tasks",
+                runtime_label,
+                &[],
+            )?,
+            #[cfg(tokio_unstable)]
+            blocking_threads: metrics.create_intgaugevec(
+                tokio_runtime::BLOCKING_THREADS,
+                "Threads of the tokio runtime's blocking pool",
+                runtime_label,
+                &[],
+            )?,
+            #[cfg(tokio_unstable)]
+            idle_blocking_threads: metrics.create_intgaugevec(
+                tokio_runtime::IDLE_BLOCKING_THREADS,
+                "Threads of the tokio runtime's blocking pool waiting for work",
+                runtime_label,
+                &[],
+            )?,
+            #[cfg(tokio_unstable)]
+            blocking_queue_depth: metrics.create_intgaugevec(
+                tokio_runtime::BLOCKING_QUEUE_DEPTH,
+                "Blocking tasks waiting for a thread of the tokio runtime's blocking pool",
+                runtime_label,
+                &[],
+            )?,
+            runtimes,
+            tasks_queued: metrics.create_intgaugevec(
+                blocking_tasks::QUEUED,
+                "Blocking tasks waiting for their turn or for a thread",
+                task_label,
+                &[],
+            )?,
+            tasks_running: metrics.create_intgaugevec(
+                blocking_tasks::RUNNING,
+                "Blocking tasks running",
+                task_label,
+                &[],
+            )?,
+            tasks_completed: metrics.create_intgaugevec(
+                blocking_tasks::COMPLETED_TOTAL,
+                "Blocking tasks completed",
+                task_label,
+                &[],
+            )?,
+            tasks_queue_seconds: metrics.create_gaugevec(
+                blocking_tasks::QUEUE_SECONDS_TOTAL,
+                "Seconds the blocking tasks that started spent queued",
+                task_label,
+                &[],
+            )?,
+            tasks_run_seconds: metrics.create_gaugevec(
+                blocking_tasks::RUN_SECONDS_TOTAL,
+                "Seconds the completed blocking tasks spent running",
+                task_label,
+                &[],
+            )?,
+            runtime: drt.runtime().clone(),
+        })
+    }
+
+    pub(crate) fn update(&self) {
+        if let Some(stats) = read_process_stats() {
+            self.resident_bytes.set(stats.resident_bytes as i64);
+            self.cpu_seconds.set(stats.cpu_seconds);
+            self.open_fds.set(stats.open_fds as i64);
+            self.threads.set(stats.threads as i64);
+        }
+        for (name, handle) in &self.runtimes {
+            let runtime = handle.metrics();
+            let labels = &[*name];
+            self.workers
+                .with_label_values(labels)
+                .set(runtime.num_workers() as i64);
+            self.alive_tasks
+                .with_label_values(labels)
+                .set(runtime.num_alive_tasks() as i64);
+            self.global_queue_depth
+                .with_label_values(labels)
+                .set(runtime.global_queue_depth() as i64);
+            #[cfg(target_has_atomic = "64")]
+            {
+                let busy: std::time::Duration = (0..runtime.num_workers())
+                    .map(|worker| runtime.worker_total_busy_duration(worker))
+                    .sum();
+                self.busy_seconds
+                    .with_label_values(labels)
+                    .set(busy.as_secs_f64());
+            }
+            #[cfg(tokio_unstable)]
+            {
+                self.blocking_threads
+                    .with_label_values(labels)
+                    .set(runtime.num_blocking_threads() as i64);
+                self.idle_blocking_threads
+                    .with_label_values(labels)
+                    .set(runtime.num_idle_blocking_threads() as i64);
+                self.blocking_queue_depth
+                    .with_label_values(labels)
+                    .set(runtime.blocking_queue_depth() as i64);
+            }
+        }
+        for (name, stats) in self.runtime.blocking_tasks().stats() {
+            let labels = &[name];
+            self.tasks_queued
+                .with_label_values(labels)
+                .set(stats.queued as i64);
+            self.tasks_running
+                .with_label_values(labels)
+                .set(stats.running as i64);
+            self.tasks_completed
+                .with_label_values(labels)
+                .set(stats.completed as i64);
+            self.tasks_queue_seconds
+                .with_label_values(labels)
+                .set(stats.queue_time.as_secs_f64());
+            self.tasks_run_seconds
+                .with_label_values(labels)
+                .set(stats.run_time.as_secs_f64());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_proc() {
+        let stat = "4242 (dynamo (worker) 1) S 1 4242 4242 0 -1 4194560 18392 0 0 0 \
+                    250 130 0 0 20 0 37 0 12345 2147483648 51200 18446744073709551615";
+        assert_eq!(parse_stat(stat), Some((3.8, 37)));
+        assert_eq!(parse_stat("4242 (truncated"), None);
+
+        let status = "Name:\tdynamo\nVmPeak:\t  300000 kB\nVmRSS:\t  204800 kB\nThreads:\t37\n";
+        assert_eq!(parse_vm_rss(status), Some(200 * 1024 * 1024));
+        assert_eq!(parse_vm_rss("Name:\tkthreadd\n"), None);
+
+        // Whatever the system, reading it doesn't fail the update
+        if cfg!(target_os = "linux") {
+            let stats = read_process_stats().unwrap();
+            assert!(stats.resident_bytes > 0 && stats.threads > 0 && stats.open_fds > 0);
+        }
+    }
+}
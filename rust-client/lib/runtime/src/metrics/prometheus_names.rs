@@ -80,6 +80,9 @@ pub mod labels {
 
     /// Label for endpoint identification
     pub const ENDPOINT: &str = "dynamo_endpoint";
+
+    /// Label for the stable worker ID, see [`crate::identity`]
+    pub const WORKER_ID: &str = "dynamo_worker_id";
 }
 
 /// Frontend service metrics (LLM HTTP service)
@@ -171,6 +174,12 @@ pub mod work_handler {
     /// Total number of bytes sent in responses by work handler
     pub const RESPONSE_BYTES_TOTAL: &str = "response_bytes_total";
 
+    /// Size of each request payload received by work handler (histogram)
+    pub const REQUEST_SIZE_BYTES: &str = "request_size_bytes";
+
+    /// Size of each response item sent by work handler (histogram)
+    pub const RESPONSE_SIZE_BYTES: &str = "response_size_bytes";
+
     /// Number of requests currently being processed by work handler
     /// Note: This is a gauge metric (current state) that can go up and down, so no _total suffix
     pub const INFLIGHT_REQUESTS: &str = "inflight_requests";
@@ -178,6 +187,12 @@ pub mod work_handler {
     /// Time spent processing requests by work handler (histogram)
     pub const REQUEST_DURATION_SECONDS: &str = "request_duration_seconds";
 
+    /// Time from receiving a request to sending its first response item, TTFT for LLMs (histogram)
+    pub const TIME_TO_FIRST_RESPONSE_SECONDS: &str = "time_to_first_response_seconds";
+
+    /// Time between consecutive response items of a stream, ITL for LLMs (histogram)
+    pub const INTER_RESPONSE_SECONDS: &str = "inter_response_seconds";
+
     /// Total number of errors in work handler processing
     pub const ERRORS_TOTAL: &str = "errors_total";
 
@@ -203,6 +218,9 @@ pub mod work_handler {
 
         /// Final message publishing error
         pub const PUBLISH_FINAL: &str = "publish_final";
+
+        /// The handler panicked, failing only the request it was handling
+        pub const PANIC: &str = "panic";
     }
 }
 
@@ -236,6 +254,15 @@ pub mod nats_client {
 
     /// Current connection state of NATS client (0=disconnected, 1=connected, 2=reconnecting)
     pub const CONNECTION_STATE: &str = nats_client_name!("connection_state");
+
+    /// Total number of times NATS client reconnected after losing its connection
+    pub const RECONNECTS: &str = nats_client_name!("reconnects");
+
+    /// Total number of requests in flight when NATS client lost its connection
+    pub const DROPPED_REQUESTS: &str = nats_client_name!("dropped_requests");
+
+    /// Total number of requests NATS client sent again after reconnecting
+    pub const REDISPATCHED_REQUESTS: &str = nats_client_name!("redispatched_requests");
 }
 
 /// NATS service metrics, from the $SRV.STATS.<service_name> requests on NATS server
@@ -277,6 +304,9 @@ pub const DRT_NATS_METRICS: &[&str] = &[
     nats_client::IN_MESSAGES,
     nats_client::OUT_OVERHEAD_BYTES,
     nats_client::OUT_MESSAGES,
+    nats_client::RECONNECTS,
+    nats_client::DROPPED_REQUESTS,
+    nats_client::REDISPATCHED_REQUESTS,
 ];
 
 /// All component service Prometheus metric names as an array for iteration/validation
@@ -290,6 +320,37 @@ pub const COMPONENT_NATS_METRICS: &[&str] = &[
     nats_service::ACTIVE_ENDPOINTS,  // derived from ServiceInfo.endpoints
 ];
 
+/// Per transport and endpoint byte accounting, see [`crate::transports::accounting`]
+pub mod transport {
+    /// Bytes sent (gauge set from the running count)
+    pub const SENT_BYTES: &str = "transport_sent_bytes";
+
+    /// Bytes received (gauge set from the running count)
+    pub const RECEIVED_BYTES: &str = "transport_received_bytes";
+
+    /// Bytes handed to the transport and not yet written out
+    pub const INFLIGHT_BYTES: &str = "transport_inflight_bytes";
+
+    /// Open connections
+    pub const CONNECTIONS: &str = "transport_connections";
+
+    /// Label for the transport: tcp, nats or zmq
+    pub const TRANSPORT_LABEL: &str = "transport";
+
+    /// Label for the endpoint subject the traffic belongs to
+    pub const ENDPOINT_LABEL: &str = "endpoint";
+}
+
+/// Key-value store Prometheus metric names
+pub mod kv_store {
+    /// Seconds from a store commit to its arrival at a watch, for stores that timestamp commits
+    pub const WATCH_PROPAGATION_SECONDS: &str =
+        "dynamo_component_kv_store_watch_propagation_seconds";
+
+    /// Values read from the store whose content hash didn't match
+    pub const INTEGRITY_FAILURES_TOTAL: &str = "dynamo_component_kv_store_integrity_failures_total";
+}
+
 /// Task tracker Prometheus metric name suffixes
 pub mod task_tracker {
     /// Total number of tasks issued/submitted
@@ -311,6 +372,70 @@ pub mod task_tracker {
     pub const TASKS_REJECTED_TOTAL: &str = "tasks_rejected_total";
 }
 
+/// The process's own resource use, see [`crate::metrics::process`]
+pub mod process {
+    /// Resident set size
+    pub const RESIDENT_MEMORY_BYTES: &str = "process_resident_memory_bytes";
+
+    /// User and system CPU time (gauge set from the running count)
+    pub const CPU_SECONDS_TOTAL: &str = "process_cpu_seconds_total";
+
+    /// Open file descriptors, sockets included
+    pub const OPEN_FDS: &str = "process_open_fds";
+
+    /// OS threads
+    pub const THREADS: &str = "process_threads";
+}
+
+/// Load of the runtime's tokio executors, see [`crate::metrics::process`]
+pub mod tokio_runtime {
+    /// Worker threads
+    pub const WORKERS: &str = "tokio_workers";
+
+    /// Tasks spawned and not yet finished
+    pub const ALIVE_TASKS: &str = "tokio_alive_tasks";
+
+    /// Tasks waiting in the shared queue for a worker to pick them up
+    pub const GLOBAL_QUEUE_DEPTH: &str = "tokio_global_queue_depth";
+
+    /// Time all workers together spent running tasks (gauge set from the running count)
+    pub const WORKER_BUSY_SECONDS_TOTAL: &str = "tokio_worker_busy_seconds_total";
+
+    /// Threads of the blocking pool. Only with `--cfg tokio_unstable`.
+    pub const BLOCKING_THREADS: &str = "tokio_blocking_threads";
+
+    /// Threads of the blocking pool waiting for work. Only with `--cfg tokio_unstable`.
+    pub const IDLE_BLOCKING_THREADS: &str = "tokio_idle_blocking_threads";
+
+    /// Blocking tasks waiting for a thread. Only with `--cfg tokio_unstable`.
+    pub const BLOCKING_QUEUE_DEPTH: &str = "tokio_blocking_queue_depth";
+
+    /// Label for the executor: primary or secondary
+    pub const RUNTIME_LABEL: &str = "runtime";
+}
+
+/// Tasks of [`crate::Runtime::spawn_blocking_instrumented`], by name, see
+/// [`crate::compute::blocking`]
+pub mod blocking_tasks {
+    /// Tasks waiting for their turn or for a thread of the blocking pool
+    pub const QUEUED: &str = "blocking_tasks_queued";
+
+    /// Tasks running
+    pub const RUNNING: &str = "blocking_tasks_running";
+
+    /// Tasks completed (gauge set from the runtime's count)
+    pub const COMPLETED_TOTAL: &str = "blocking_tasks_completed_total";
+
+    /// Time the tasks that started spent queued (gauge set from the runtime's count)
+    pub const QUEUE_SECONDS_TOTAL: &str = "blocking_tasks_queue_seconds_total";
+
+    /// Time the completed tasks spent running (gauge set from the runtime's count)
+    pub const RUN_SECONDS_TOTAL: &str = "blocking_tasks_run_seconds_total";
+
+    /// Label for the name the tasks were spawned with
+    pub const TASK_LABEL: &str = "task";
+}
+
 /// DistributedRuntime core metrics
 pub mod distributed_runtime {
     /// Total uptime of the DistributedRuntime in seconds
@@ -10,11 +10,16 @@ use async_trait::async_trait;
 
 use super::registry::Registry;
 
+pub mod caller;
+pub use caller::CallerContext;
+
 pub struct Context<T: Data> {
     current: T,
     controller: Arc<Controller>, //todo: hold this as an arc
     registry: Registry,
     stages: Vec<String>,
+    caller: CallerContext,
+    idempotency_key: Option<String>,
 }
 
 impl<T: Send + Sync + 'static> Context<T> {
@@ -25,6 +30,8 @@ impl<T: Send + Sync + 'static> Context<T> {
             controller: Arc::new(Controller::default()),
             registry: Registry::new(),
             stages: Vec::new(),
+            caller: CallerContext::default(),
+            idempotency_key: None,
         }
     }
 
@@ -34,6 +41,8 @@ impl<T: Send + Sync + 'static> Context<T> {
             controller: context.controller,
             registry: context.registry,
             stages: context.stages,
+            caller: context.caller,
+            idempotency_key: context.idempotency_key,
         }
     }
 
@@ -43,6 +52,8 @@ impl<T: Send + Sync + 'static> Context<T> {
             controller: Arc::new(controller),
             registry: Registry::new(),
             stages: Vec::new(),
+            caller: CallerContext::default(),
+            idempotency_key: None,
         }
     }
 
@@ -52,6 +63,8 @@ impl<T: Send + Sync + 'static> Context<T> {
             controller: Arc::new(Controller::new(id)),
             registry: Registry::new(),
             stages: Vec::new(),
+            caller: CallerContext::default(),
+            idempotency_key: None,
         }
     }
 
@@ -69,6 +82,31 @@ impl<T: Send + Sync + 'static> Context<T> {
         &self.controller
     }
 
+    /// Who the request is for and where it came from, see [`caller`]
+    pub fn caller(&self) -> &CallerContext {
+        &self.caller
+    }
+
+    pub fn caller_mut(&mut self) -> &mut CallerContext {
+        &mut self.caller
+    }
+
+    pub fn with_caller(mut self, caller: CallerContext) -> Self {
+        self.caller = caller;
+        self
+    }
+
+    /// Retries of the request with the same key get the response of the first try, see
+    /// [`idempotency`](crate::pipeline::network::ingress::idempotency)
+    pub fn idempotency_key(&self) -> Option<&str> {
+        self.idempotency_key.as_deref()
+    }
+
+    pub fn with_idempotency_key(mut self, key: impl Into<String>) -> Self {
+        self.idempotency_key = Some(key.into());
+        self
+    }
+
     /// Insert an object into the registry with a specific key.
     pub fn insert<K: ToString, U: Send + Sync + 'static>(&mut self, key: K, value: U) {
         self.registry.insert_shared(key, value);
@@ -104,6 +142,8 @@ impl<T: Send + Sync + 'static> Context<T> {
                 controller: self.controller,
                 registry: self.registry,
                 stages: self.stages,
+                caller: self.caller,
+                idempotency_key: self.idempotency_key,
             },
         )
     }
@@ -213,17 +253,24 @@ pub struct StreamContext {
     controller: Arc<Controller>,
     registry: Arc<Registry>,
     stages: Vec<String>,
+    caller: Arc<CallerContext>,
 }
 
 impl StreamContext {
-    fn new(controller: Arc<Controller>, registry: Registry) -> Self {
+    fn new(controller: Arc<Controller>, registry: Registry, caller: CallerContext) -> Self {
         StreamContext {
             controller,
             registry: Arc::new(registry),
             stages: Vec::new(),
+            caller: Arc::new(caller),
         }
     }
 
+    /// Of the request this is the response stream to
+    pub fn caller(&self) -> &CallerContext {
+        &self.caller
+    }
+
     /// Retrieve an object from the registry by key and type.
     pub fn get<V: Send + Sync + 'static>(&self, key: &str) -> Result<Arc<V>, String> {
         self.registry.get_shared(key)
@@ -294,7 +341,7 @@ impl AsyncEngineContextProvider for StreamContext {
 
 impl<T: Send + Sync + 'static> From<Context<T>> for StreamContext {
     fn from(value: Context<T>) -> Self {
-        StreamContext::new(value.controller, value.registry)
+        StreamContext::new(value.controller, value.registry, value.caller)
     }
 }
 
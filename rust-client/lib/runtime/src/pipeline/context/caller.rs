@@ -0,0 +1,145 @@
+// SPDX-FileCopyrightText: Copyright (c) 2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Who a request is for and where it came from.
+//!
+//! A [`CallerContext`] rides along with each request's [`Context`](super::Context), through local
+//! operators and over every request transport, so that metrics, quotas and logs can be kept per
+//! tenant the same way in every service:
+//!
+//! - `caller_id`: the service that sent the request. A router sets it to its own
+//!   [identity](crate::policy::identity) when it has one, so each hop sees its immediate caller.
+//! - `tenant`: who the work is done for, e.g. the customer an API key belongs to. Set once at the
+//!   edge and passed on unchanged.
+//! - `origin`: what the request first came in through, e.g. `http` or `batch`. Also passed on
+//!   unchanged.
+//!
+//! An HTTP frontend reads them from the [`CALLER_ID_HEADER`], [`TENANT_HEADER`] and
+//! [`ORIGIN_HEADER`] headers with [`CallerContext::from_http_headers`]. Each served request's
+//! `handle_payload` span records them as `caller_id`, `tenant` and `origin`.
+
+use serde::{Deserialize, Serialize};
+
+pub const CALLER_ID_HEADER: &str = "x-dynamo-caller-id";
+
+pub const TENANT_HEADER: &str = "x-dynamo-tenant";
+
+pub const ORIGIN_HEADER: &str = "x-dynamo-origin";
+
+/// Longer values are cut, as they end up in logs and metric labels
+pub const MAX_FIELD_LEN: usize = 128;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CallerContext {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub caller_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+}
+
+impl CallerContext {
+    pub fn is_empty(&self) -> bool {
+        self.caller_id.is_none() && self.tenant.is_none() && self.origin.is_none()
+    }
+
+    pub fn with_caller_id(mut self, caller_id: impl Into<String>) -> Self {
+        self.caller_id = field(caller_id.into());
+        self
+    }
+
+    pub fn with_tenant(mut self, tenant: impl Into<String>) -> Self {
+        self.tenant = field(tenant.into());
+        self
+    }
+
+    pub fn with_origin(mut self, origin: impl Into<String>) -> Self {
+        self.origin = field(origin.into());
+        self
+    }
+
+    /// The fields set in `headers`, by their standard names
+    pub fn from_http_headers(headers: &axum::http::HeaderMap) -> Self {
+        let header = |name: &str| {
+            let value = headers.get(name)?.to_str().ok()?;
+            field(value.to_string())
+        };
+        CallerContext {
+            caller_id: header(CALLER_ID_HEADER),
+            tenant: header(TENANT_HEADER),
+            origin: header(ORIGIN_HEADER),
+        }
+    }
+
+    /// What a router sends on: this process as the caller if it has an identity, the rest as is
+    pub(crate) fn forwarded(&self) -> Self {
+        let identity = crate::policy::identity();
+        let mut forwarded = self.clone();
+        if !identity.is_empty() {
+            forwarded.caller_id = field(identity);
+        }
+        forwarded
+    }
+
+    /// Record the fields on `span`, which declares them
+    pub(crate) fn record(&self, span: &tracing::Span) {
+        let fields = [
+            ("caller_id", &self.caller_id),
+            ("tenant", &self.tenant),
+            ("origin", &self.origin),
+        ];
+        for (name, value) in fields {
+            if let Some(value) = value {
+                span.record(name, value.as_str());
+            }
+        }
+    }
+}
+
+/// None if empty, and at most [`MAX_FIELD_LEN`] bytes
+fn field(value: String) -> Option<String> {
+    let value = value.trim();
+    if value.is_empty() {
+        return None;
+    }
+    let mut end = value.len().min(MAX_FIELD_LEN);
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    Some(value[..end].to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_caller_context() {
+        let mut headers = axum::http::HeaderMap::new();
+        headers.insert(TENANT_HEADER, " acme ".parse().unwrap());
+        headers.insert(ORIGIN_HEADER, "".parse().unwrap());
+        let caller = CallerContext::from_http_headers(&headers);
+        assert_eq!(caller, CallerContext::default().with_tenant("acme"));
+        assert!(!caller.is_empty());
+        assert_eq!(
+            serde_json::to_string(&caller).unwrap(),
+            r#"{"tenant":"acme"}"#
+        );
+        let parsed: CallerContext = serde_json::from_str("{}").unwrap();
+        assert!(parsed.is_empty());
+
+        let long = CallerContext::default().with_origin("é".repeat(MAX_FIELD_LEN));
+        assert_eq!(long.origin.unwrap().len(), MAX_FIELD_LEN);
+
+        temp_env::with_var("DYN_IDENTITY", Some("frontend"), || {
+            let forwarded = caller.clone().with_caller_id("client").forwarded();
+            assert_eq!(forwarded.caller_id.as_deref(), Some("frontend"));
+            assert_eq!(forwarded.tenant.as_deref(), Some("acme"));
+        });
+        temp_env::with_var("DYN_IDENTITY", None::<&str>, || {
+            let forwarded = caller.clone().with_caller_id("client").forwarded();
+            assert_eq!(forwarded.caller_id.as_deref(), Some("client"));
+        });
+    }
+}
@@ -72,9 +72,17 @@ pub enum PipelineError {
     #[error("Failed to establish a streaming connection: {0}")]
     ConnectionFailed(String),
 
+    /// The two ends of a connection speak protocol versions one of them can't read
+    #[error(transparent)]
+    IncompatiblePeer(#[from] super::network::protocol::IncompatiblePeer),
+
     #[error("Generate Error: {0}")]
     GenerateError(Error),
 
+    /// The handler of a request panicked, in `generate` or while streaming its responses
+    #[error("Handler panicked: {0}")]
+    HandlerPanicked(String),
+
     #[error("An endpoint URL must have the format: namespace/component/endpoint")]
     InvalidEndpointFormat,
 
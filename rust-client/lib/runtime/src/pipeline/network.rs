@@ -5,16 +5,20 @@
 
 pub mod codec;
 pub mod egress;
+pub mod in_process;
 pub mod ingress;
+pub mod protocol;
 pub mod tcp;
 
 use crate::SystemHealth;
+use crate::metering::EndpointMetering;
+use crate::traits::DistributedRuntimeProvider;
 use std::sync::{Arc, OnceLock};
 
 use anyhow::Result;
 use async_trait::async_trait;
 use bytes::Bytes;
-use codec::{TwoPartCodec, TwoPartMessage, TwoPartMessageType};
+use codec::{PayloadCodec, TwoPartCodec, TwoPartMessage, TwoPartMessageType};
 use derive_builder::Builder;
 use futures::StreamExt;
 // io::Cursor, TryStreamExt
@@ -25,6 +29,7 @@ use super::{
     AsyncTransportEngine, Context, Data, Error, ManyOut, PipelineError, PipelineIO, SegmentSource,
     ServiceBackend, ServiceEngine, SingleIn, Source, context,
 };
+use context::CallerContext;
 use ingress::push_handler::WorkHandlerMetrics;
 
 // Define stream error message constant
@@ -56,6 +61,9 @@ pub enum ControlMessage {
     Stop,
     Kill,
     Sentinel,
+    /// Tells the peer this end is still there. Sent only to peers that advertise a
+    /// [ping interval](protocol::ProtocolInfo::ping_interval_ms), as older ones fail on it.
+    Ping,
 }
 
 /// This is the first message in a `ResponseStream`. This is not a message that gets process
@@ -67,9 +75,22 @@ pub enum ControlMessage {
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct ResponseStreamPrologue {
     error: Option<String>,
+    /// Set with `error` when the responding end is too new for the requesting one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    incompatible: Option<protocol::IncompatiblePeer>,
 }
 
-pub type StreamProvider<T> = tokio::sync::oneshot::Receiver<Result<T, String>>;
+impl ResponseStreamPrologue {
+    /// The error the requester's stream fails with, if any
+    fn into_error(self) -> Option<PipelineError> {
+        if let Some(incompatible) = self.incompatible {
+            return Some(PipelineError::IncompatiblePeer(incompatible));
+        }
+        self.error.map(PipelineError::ConnectionFailed)
+    }
+}
+
+pub type StreamProvider<T> = tokio::sync::oneshot::Receiver<Result<T, PipelineError>>;
 
 /// The [`RegisteredStream`] object is acquired from a [`StreamProvider`] and is used to provide
 /// an awaitable receiver which will the `T` which is either a stream writer for a request stream
@@ -227,6 +248,11 @@ pub struct StreamOptions {
     /// The number of messages to buffer before blocking
     #[builder(default = "8")]
     pub recv_buffer_count: usize,
+
+    /// The endpoint whose responses the stream carries, to attribute its bytes to, see
+    /// [`crate::transports::accounting`]
+    #[builder(default)]
+    pub endpoint: Option<String>,
 }
 
 impl StreamOptions {
@@ -271,11 +297,24 @@ struct RequestControlMessage {
     request_type: RequestType,
     response_type: ResponseType,
     connection_info: ConnectionInfo,
+    /// Of the request and its responses. The control message itself is always JSON.
+    #[serde(default, skip_serializing_if = "PayloadCodec::is_json")]
+    codec: PayloadCodec,
+    /// Who the request is for, see [`crate::pipeline::context::caller`]
+    #[serde(default, skip_serializing_if = "CallerContext::is_empty")]
+    caller: CallerContext,
+    /// See [`crate::pipeline::network::ingress::idempotency`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    idempotency_key: Option<String>,
 }
 
 pub struct Ingress<Req: PipelineIO, Resp: PipelineIO> {
     segment: OnceLock<Arc<SegmentSource<Req, Resp>>>,
     metrics: OnceLock<Arc<WorkHandlerMetrics>>,
+    /// Counts each tenant's usage of the endpoint, when the runtime meters it
+    metering: OnceLock<EndpointMetering>,
+    /// Keeps the responses of requests with an idempotency key
+    idempotency: OnceLock<ingress::idempotency::IdempotencyStore>,
     /// Endpoint-specific notifier for health check timer resets
     endpoint_health_check_notifier: OnceLock<Arc<tokio::sync::Notify>>,
 }
@@ -285,6 +324,8 @@ impl<Req: PipelineIO + Sync, Resp: PipelineIO> Ingress<Req, Resp> {
         Arc::new(Self {
             segment: OnceLock::new(),
             metrics: OnceLock::new(),
+            metering: OnceLock::new(),
+            idempotency: OnceLock::new(),
             endpoint_health_check_notifier: OnceLock::new(),
         })
     }
@@ -303,6 +344,13 @@ impl<Req: PipelineIO + Sync, Resp: PipelineIO> Ingress<Req, Resp> {
         let metrics = WorkHandlerMetrics::from_endpoint(endpoint, metrics_labels)
             .map_err(|e| anyhow::anyhow!("Failed to create work handler metrics: {}", e))?;
 
+        if let Some(metering) = endpoint.drt().metering() {
+            let _ = self.metering.set(metering.endpoint(endpoint));
+        }
+        let _ = self
+            .idempotency
+            .set(ingress::idempotency::IdempotencyStore::new(endpoint));
+
         self.metrics
             .set(Arc::new(metrics))
             .map_err(|_| anyhow::anyhow!("Metrics already set"))
@@ -379,8 +427,9 @@ pub trait PushWorkHandler: Send + Sync {
 /// At AsyncEngine:
 ///   response 1 -> response 2 -> response 3 -> <end>
 ///
-/// Between ingress/egress:
-///   response 1 <end=false> -> response 2 <end=false> -> response 3 <end=false> -> (null) <end=true>
+/// Between ingress/egress, each in its own frame:
+///   response 1 <seq=0, end=false> -> response 2 <seq=1, end=false> -> response 3 <seq=2, end=false>
+///   -> (null) <seq=3, end=true>
 ///
 /// At client:
 ///   response 1 -> response 2 -> response 3 -> <end>
@@ -409,4 +458,8 @@ pub struct NetworkStreamWrapper<U> {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub data: Option<U>,
     pub complete_final: bool,
+    /// The position of `data` in the stream, from 0, or on the end-of-stream marker the number
+    /// of items before it. None from workers that predate numbering.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
 }
@@ -0,0 +1,319 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The serialization of requests and response items, as opposed to the framing around them.
+//!
+//! A worker picks one with [`EndpointConfigBuilder::codec`] and advertises it in its
+//! [`Instance`]. Routers encode each request with the codec of the instance they send it to and
+//! name the codec in the request's control message, and the worker decodes the request and
+//! encodes its responses with whichever codec that names. So a worker still serves routers that
+//! predate codecs, which send JSON and name none.
+//!
+//! That is the negotiation: every worker decodes every codec of its build, and a router reads
+//! a codec it doesn't know, advertised by a newer worker, as JSON, see
+//! [`PayloadCodec::deserialize_known`]. The tcp handshake lists the codecs of each end too, see
+//! [`ProtocolInfo`].
+//!
+//! The wire types skip fields that are unset, which bincode can't read back, and protobuf needs
+//! a schema rather than serde types. So both carry the payload as a tree of JSON values: bincode
+//! as a tagged enum, protobuf as a `google.protobuf.Value`, which any protobuf library reads.
+//! They save bytes on the wire more than CPU over JSON.
+//!
+//! [`EndpointConfigBuilder::codec`]: crate::component::EndpointConfigBuilder::codec
+//! [`Instance`]: crate::component::Instance
+//! [`ProtocolInfo`]: crate::pipeline::network::protocol::ProtocolInfo
+
+use std::fmt;
+use std::str::FromStr;
+
+use prost::Message;
+use serde::{Deserialize, Deserializer, Serialize, de::DeserializeOwned};
+use serde_json::{Map, Number, Value};
+
+use crate::{Result, error};
+
+/// Integers past this don't survive a protobuf number, which is a double
+const MAX_SAFE_INTEGER: u64 = (1 << 53) - 1;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadCodec {
+    #[default]
+    Json,
+    /// With field names, so that it reads back fields that were skipped or added
+    MessagePack,
+    Bincode,
+    /// Integers are doubles, so ones beyond 2^53 fail to encode
+    Protobuf,
+}
+
+impl PayloadCodec {
+    /// Every codec this build encodes and decodes
+    pub const ALL: [PayloadCodec; 4] = [
+        PayloadCodec::Json,
+        PayloadCodec::MessagePack,
+        PayloadCodec::Bincode,
+        PayloadCodec::Protobuf,
+    ];
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(match self {
+            PayloadCodec::Json => serde_json::to_vec(value)?,
+            PayloadCodec::MessagePack => rmp_serde::to_vec_named(value)?,
+            PayloadCodec::Bincode => bincode::serialize(&Tree::from(serde_json::to_value(value)?))?,
+            PayloadCodec::Protobuf => to_protobuf(serde_json::to_value(value)?)?.encode_to_vec(),
+        })
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        Ok(match self {
+            PayloadCodec::Json => serde_json::from_slice(bytes)?,
+            PayloadCodec::MessagePack => rmp_serde::from_slice(bytes)?,
+            PayloadCodec::Bincode => {
+                let tree: Tree = bincode::deserialize(bytes)?;
+                serde_json::from_value(tree.into())?
+            }
+            PayloadCodec::Protobuf => {
+                let value = prost_types::Value::decode(bytes)?;
+                serde_json::from_value(from_protobuf(value)?)?
+            }
+        })
+    }
+
+    pub fn is_json(&self) -> bool {
+        *self == PayloadCodec::Json
+    }
+
+    /// For a codec a peer advertises: one this build doesn't know is JSON, which every worker
+    /// decodes, rather than a record that fails to read
+    pub fn deserialize_known<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Ok(name.parse().unwrap_or_default())
+    }
+}
+
+/// A JSON value as bincode can read it back: bincode isn't self-describing, but it keeps the
+/// variant of an enum
+#[derive(Serialize, Deserialize)]
+enum Tree {
+    Null,
+    Bool(bool),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+    String(String),
+    Array(Vec<Tree>),
+    Object(Vec<(String, Tree)>),
+}
+
+impl From<Value> for Tree {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Null => Tree::Null,
+            Value::Bool(b) => Tree::Bool(b),
+            Value::Number(n) => match (n.as_u64(), n.as_i64()) {
+                (Some(u), _) => Tree::U64(u),
+                (None, Some(i)) => Tree::I64(i),
+                (None, None) => Tree::F64(n.as_f64().unwrap_or_default()),
+            },
+            Value::String(s) => Tree::String(s),
+            Value::Array(items) => Tree::Array(items.into_iter().map(Tree::from).collect()),
+            Value::Object(members) => Tree::Object(
+                members
+                    .into_iter()
+                    .map(|(key, value)| (key, Tree::from(value)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl From<Tree> for Value {
+    fn from(tree: Tree) -> Self {
+        match tree {
+            Tree::Null => Value::Null,
+            Tree::Bool(b) => Value::Bool(b),
+            Tree::U64(u) => Value::Number(u.into()),
+            Tree::I64(i) => Value::Number(i.into()),
+            Tree::F64(f) => Number::from_f64(f).map_or(Value::Null, Value::Number),
+            Tree::String(s) => Value::String(s),
+            Tree::Array(items) => Value::Array(items.into_iter().map(Value::from).collect()),
+            Tree::Object(members) => Value::Object(
+                members
+                    .into_iter()
+                    .map(|(key, tree)| (key, Value::from(tree)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+fn to_protobuf(value: Value) -> Result<prost_types::Value> {
+    use prost_types::value::Kind;
+    let kind = match value {
+        Value::Null => Kind::NullValue(prost_types::NullValue::NullValue.into()),
+        Value::Bool(b) => Kind::BoolValue(b),
+        Value::Number(n) => {
+            let magnitude = n.as_u64().or_else(|| n.as_i64().map(i64::unsigned_abs));
+            if magnitude.is_some_and(|m| m > MAX_SAFE_INTEGER) {
+                return Err(error!("{n} is too large for a protobuf number"));
+            }
+            Kind::NumberValue(n.as_f64().unwrap_or_default())
+        }
+        Value::String(s) => Kind::StringValue(s),
+        Value::Array(items) => Kind::ListValue(prost_types::ListValue {
+            values: items.into_iter().map(to_protobuf).collect::<Result<_>>()?,
+        }),
+        Value::Object(members) => Kind::StructValue(prost_types::Struct {
+            fields: members
+                .into_iter()
+                .map(|(key, value)| Ok((key, to_protobuf(value)?)))
+                .collect::<Result<_>>()?,
+        }),
+    };
+    Ok(prost_types::Value { kind: Some(kind) })
+}
+
+fn from_protobuf(value: prost_types::Value) -> Result<Value> {
+    use prost_types::value::Kind;
+    Ok(match value.kind {
+        None | Some(Kind::NullValue(_)) => Value::Null,
+        Some(Kind::BoolValue(b)) => Value::Bool(b),
+        // Integers were encoded as doubles; give them back as integers for integer fields
+        Some(Kind::NumberValue(f)) if f.fract() == 0.0 && f.abs() <= MAX_SAFE_INTEGER as f64 => {
+            match f < 0.0 {
+                true => Value::Number((f as i64).into()),
+                false => Value::Number((f as u64).into()),
+            }
+        }
+        Some(Kind::NumberValue(f)) => {
+            Value::Number(Number::from_f64(f).ok_or_else(|| error!("{f} is not a JSON number"))?)
+        }
+        Some(Kind::StringValue(s)) => Value::String(s),
+        Some(Kind::ListValue(list)) => Value::Array(
+            list.values
+                .into_iter()
+                .map(from_protobuf)
+                .collect::<Result<_>>()?,
+        ),
+        Some(Kind::StructValue(fields)) => Value::Object(
+            fields
+                .fields
+                .into_iter()
+                .map(|(key, value)| Ok((key, from_protobuf(value)?)))
+                .collect::<Result<Map<_, _>>>()?,
+        ),
+    })
+}
+
+impl fmt::Display for PayloadCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PayloadCodec::Json => f.write_str("json"),
+            PayloadCodec::MessagePack => f.write_str("msgpack"),
+            PayloadCodec::Bincode => f.write_str("bincode"),
+            PayloadCodec::Protobuf => f.write_str("protobuf"),
+        }
+    }
+}
+
+impl FromStr for PayloadCodec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(PayloadCodec::Json),
+            "msgpack" | "messagepack" | "message_pack" => Ok(PayloadCodec::MessagePack),
+            "bincode" => Ok(PayloadCodec::Bincode),
+            "protobuf" | "proto" => Ok(PayloadCodec::Protobuf),
+            _ => Err(error!(
+                "Unknown payload codec '{s}', expected json, msgpack, bincode or protobuf"
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::network::NetworkStreamWrapper;
+    use crate::protocols::annotated::Annotated;
+
+    #[test]
+    fn test_round_trip_skipped_fields() {
+        let items = [
+            NetworkStreamWrapper {
+                data: Some(Annotated::from_data("token".to_string())),
+                complete_final: false,
+                seq: Some(0),
+            },
+            NetworkStreamWrapper {
+                data: None,
+                complete_final: true,
+                seq: None,
+            },
+        ];
+        for codec in PayloadCodec::ALL {
+            for item in &items {
+                let bytes = codec.encode(item).unwrap();
+                let decoded: NetworkStreamWrapper<Annotated<String>> =
+                    codec.decode(&bytes).unwrap();
+                assert_eq!(decoded.complete_final, item.complete_final);
+                assert_eq!(decoded.seq, item.seq);
+                assert_eq!(
+                    decoded.data.and_then(|a| a.data),
+                    item.data.as_ref().and_then(|a| a.data.clone())
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_codec() {
+        assert_eq!("JSON".parse::<PayloadCodec>().unwrap(), PayloadCodec::Json);
+        let codec: PayloadCodec = "msgpack".parse().unwrap();
+        assert_eq!(codec, PayloadCodec::MessagePack);
+        for codec in PayloadCodec::ALL {
+            assert_eq!(codec.to_string().parse::<PayloadCodec>().unwrap(), codec);
+        }
+        assert!("cbor".parse::<PayloadCodec>().is_err());
+
+        // Advertised by a newer worker
+        let known = |name: &str| {
+            PayloadCodec::deserialize_known(&mut serde_json::Deserializer::from_str(name)).unwrap()
+        };
+        assert_eq!(known(r#""bincode""#), PayloadCodec::Bincode);
+        assert_eq!(known(r#""cbor""#), PayloadCodec::Json);
+    }
+
+    #[test]
+    fn test_tree_codecs_keep_values() {
+        let value = serde_json::json!({
+            "id": 1_u64 << 60,
+            "delta": -3,
+            "logprob": -0.25,
+            "tokens": [1, 2, 3],
+            "text": "hi",
+            "stop": null,
+            "done": true,
+        });
+        let bytes = PayloadCodec::Bincode.encode(&value).unwrap();
+        assert_eq!(
+            PayloadCodec::Bincode.decode::<Value>(&bytes).unwrap(),
+            value
+        );
+
+        let err = PayloadCodec::Protobuf.encode(&value).unwrap_err();
+        assert!(
+            err.to_string().contains("too large for a protobuf number"),
+            "{err}"
+        );
+        let mut value = value;
+        value["id"] = 7.into();
+        let bytes = PayloadCodec::Protobuf.encode(&value).unwrap();
+        assert_eq!(
+            PayloadCodec::Protobuf.decode::<Value>(&bytes).unwrap(),
+            value
+        );
+    }
+}
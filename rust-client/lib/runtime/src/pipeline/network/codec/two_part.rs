@@ -194,6 +194,11 @@ impl TwoPartMessage {
         (self.header, self.data)
     }
 
+    /// The bytes this message takes on the wire, lengths and checksum included
+    pub fn encoded_len(&self) -> usize {
+        24 + self.header.len() + self.data.len()
+    }
+
     pub fn header(&self) -> Option<&Bytes> {
         if self.header.is_empty() {
             None
@@ -1,7 +1,6 @@
 // SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-use async_nats::client::Client;
 use async_nats::{HeaderMap, HeaderValue};
 use tracing as log;
 
@@ -9,6 +8,9 @@ use super::*;
 use crate::logging::DistributedTraceContext;
 use crate::logging::get_distributed_tracing_context;
 use crate::logging::inject_otel_context_into_nats_headers;
+use crate::pipeline::context::CallerContext;
+use crate::transports::accounting::{self, Transport};
+use crate::transports::nats;
 use crate::{Result, protocols::maybe_error::MaybeError};
 use tokio_stream::{StreamExt, StreamNotifyClose, wrappers::ReceiverStream};
 use tracing::Instrument;
@@ -33,41 +35,87 @@ struct RequestControlMessage {
     request_type: RequestType,
     response_type: ResponseType,
     connection_info: ConnectionInfo,
+    #[serde(default, skip_serializing_if = "PayloadCodec::is_json")]
+    codec: PayloadCodec,
+    /// Who the request is for, see [`crate::pipeline::context::caller`]
+    #[serde(default, skip_serializing_if = "CallerContext::is_empty")]
+    caller: CallerContext,
+    /// See [`crate::pipeline::network::ingress::idempotency`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    idempotency_key: Option<String>,
 }
 
 pub struct AddressedRequest<T> {
     request: T,
     address: String,
+    endpoint: Option<String>,
+    codec: PayloadCodec,
+    redispatch: bool,
 }
 
 impl<T> AddressedRequest<T> {
     pub fn new(request: T, address: String) -> Self {
-        Self { request, address }
+        Self {
+            request,
+            address,
+            endpoint: None,
+            codec: PayloadCodec::default(),
+            redispatch: false,
+        }
+    }
+
+    /// Attribute the request's bytes to `endpoint` rather than to its address, see
+    /// [`crate::transports::accounting`]
+    pub fn with_endpoint(mut self, endpoint: String) -> Self {
+        self.endpoint = Some(endpoint);
+        self
     }
 
-    fn into_parts(self) -> (T, String) {
-        (self.request, self.address)
+    /// Serialize the request, and have its responses serialized, with `codec` rather than JSON
+    pub fn with_codec(mut self, codec: PayloadCodec) -> Self {
+        self.codec = codec;
+        self
     }
+
+    /// Send the request again if the NATS connection drops before it is acknowledged, see
+    /// [`nats::Client::request_with_reconnect`]
+    pub fn with_redispatch(mut self, redispatch: bool) -> Self {
+        self.redispatch = redispatch;
+        self
+    }
+}
+
+/// Where requests are sent
+enum RequestTransport {
+    Nats(Box<nats::Client>),
+    InProcess(in_process::InProcessNetwork),
 }
 
 pub struct AddressedPushRouter {
     // todo: generalize with a generic
-    req_transport: Client,
+    req_transport: RequestTransport,
 
-    // todo: generalize with a generic
-    resp_transport: Arc<tcp::server::TcpStreamServer>,
+    resp_transport: Arc<dyn ResponseService + Send + Sync>,
 }
 
 impl AddressedPushRouter {
     pub fn new(
-        req_transport: Client,
+        req_transport: nats::Client,
         resp_transport: Arc<tcp::server::TcpStreamServer>,
     ) -> Result<Arc<Self>> {
         Ok(Arc::new(Self {
-            req_transport,
+            req_transport: RequestTransport::Nats(Box::new(req_transport)),
             resp_transport,
         }))
     }
+
+    /// Requests and responses both stay in this process, see [`in_process`]
+    pub fn in_process(network: in_process::InProcessNetwork) -> Arc<Self> {
+        Arc::new(Self {
+            req_transport: RequestTransport::InProcess(network),
+            resp_transport: Arc::new(in_process::InProcessResponseServer),
+        })
+    }
 }
 
 #[async_trait]
@@ -79,15 +127,22 @@ where
     async fn generate(&self, request: SingleIn<AddressedRequest<T>>) -> Result<ManyOut<U>, Error> {
         let request_id = request.context().id().to_string();
         let (addressed_request, context) = request.transfer(());
-        let (request, address) = addressed_request.into_parts();
+        let AddressedRequest {
+            request,
+            address,
+            endpoint,
+            codec,
+            redispatch,
+        } = addressed_request;
+        let endpoint = endpoint.unwrap_or_else(|| address.clone());
         let engine_ctx = context.context();
-        let engine_ctx_ = engine_ctx.clone();
 
         // registration options for the data plane in a singe in / many out configuration
         let options = StreamOptions::builder()
             .context(engine_ctx.clone())
             .enable_request_stream(false)
             .enable_response_stream(true)
+            .endpoint(Some(endpoint.clone()))
             .build()
             .unwrap();
 
@@ -115,13 +170,16 @@ where
             request_type: RequestType::SingleIn,
             response_type: ResponseType::ManyOut,
             connection_info,
+            codec,
+            caller: context.caller().forwarded(),
+            idempotency_key: context.idempotency_key().map(str::to_string),
         };
 
         // next build the two part message where we package the connection info and the request into
         // a single Vec<u8> that can be sent over the wire.
         // --- package this up in the WorkQueuePublisher ---
         let ctrl = serde_json::to_vec(&control_message)?;
-        let data = serde_json::to_vec(&request)?;
+        let data = codec.encode(&request)?;
 
         log::trace!(
             request_id,
@@ -135,11 +193,22 @@ where
         // the request plane / work queue should provide a two part message codec that can be used
         // or it should take a two part message directly
         // todo - update this
-        let codec = TwoPartCodec::default();
-        let buffer = codec.encode_message(msg)?;
+        let framing = TwoPartCodec::default();
+        let buffer = framing.encode_message(msg)?;
 
         // TRANSPORT ABSTRACT REQUIRED - END HERE
 
+        let req_transport = match &self.req_transport {
+            RequestTransport::Nats(client) => client,
+            RequestTransport::InProcess(network) => {
+                log::trace!(request_id, "enqueueing two-part message in process");
+                network.request(&address, buffer)?;
+                return self
+                    .await_response_stream(response_stream_provider, engine_ctx, codec)
+                    .await;
+            }
+        };
+
         log::trace!(request_id, "enqueueing two-part message to nats");
 
         // Insert Trace Context into Headers
@@ -163,19 +232,40 @@ where
 
         // we might need to add a timeout on this if there is no subscriber to the subject; however, I think nats
         // will handle this for us
-        let _response = self
-            .req_transport
-            .request_with_headers(address.to_string(), headers, buffer)
+        let meter = accounting::meter(Transport::Nats, &endpoint);
+        let sending = meter.sending(buffer.len());
+        let response = req_transport
+            .request_with_reconnect(address.to_string(), headers, buffer, redispatch)
             .await?;
+        sending.finish();
+        meter.received(response.payload.len());
+
+        self.await_response_stream(response_stream_provider, engine_ctx, codec)
+            .await
+    }
+}
 
-        log::trace!(request_id, "awaiting transport handshake");
+impl AddressedPushRouter {
+    /// Wait for the serving side to connect its response stream, then decode what it sends
+    async fn await_response_stream<U>(
+        &self,
+        response_stream_provider: StreamProvider<StreamReceiver>,
+        engine_ctx: Arc<dyn AsyncEngineContext>,
+        codec: PayloadCodec,
+    ) -> Result<ManyOut<U>, Error>
+    where
+        U: Data + for<'de> Deserialize<'de> + MaybeError,
+    {
+        let engine_ctx_ = engine_ctx.clone();
+
+        log::trace!(context = engine_ctx.id(), "awaiting transport handshake");
         let response_stream = response_stream_provider
             .await
-            .map_err(|_| PipelineError::DetachedStreamReceiver)?
-            .map_err(PipelineError::ConnectionFailed)?;
+            .map_err(|_| PipelineError::DetachedStreamReceiver)??;
 
         // TODO: Detect end-of-stream using Server-Sent Events (SSE)
         let mut is_complete_final = false;
+        let mut next_seq = 0;
         let stream = tokio_stream::StreamNotifyClose::new(
             tokio_stream::wrappers::ReceiverStream::new(response_stream.rx),
         )
@@ -189,9 +279,22 @@ where
                         .into(),
                     ));
                 }
-                match serde_json::from_slice::<NetworkStreamWrapper<U>>(&res_bytes) {
+                match codec.decode::<NetworkStreamWrapper<U>>(&res_bytes) {
                     Ok(item) => {
                         is_complete_final = item.complete_final;
+                        if let Some(seq) = item.seq {
+                            let expected = std::mem::replace(&mut next_seq, seq + 1);
+                            if seq != expected {
+                                // reported once, then counted on from where the stream is now
+                                return Some(U::from_err(
+                                    Error::msg(format!(
+                                        "Response {seq} received when {expected} was expected - \
+                                         responses were lost or reordered"
+                                    ))
+                                    .into(),
+                                ));
+                            }
+                        }
                         if let Some(data) = item.data {
                             Some(data)
                         } else if is_complete_final {
@@ -208,7 +311,7 @@ where
                         let json_str = String::from_utf8_lossy(&res_bytes);
                         log::warn!(%err, %json_str, "Failed deserializing JSON to response");
 
-                        Some(U::from_err(Error::new(err).into()))
+                        Some(U::from_err(err.into()))
                     }
                 }
             } else if is_complete_final {
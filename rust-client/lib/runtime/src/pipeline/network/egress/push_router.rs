@@ -1,14 +1,15 @@
 // SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-use super::{AsyncEngineContextProvider, ResponseStream, STREAM_ERR_MSG};
+use super::{AsyncEngineContextProvider, ResponseStream, STREAM_ERR_MSG, in_process};
 use crate::{
-    component::{Client, Endpoint, InstanceSource},
+    component::{Client, Endpoint, Instance, InstanceSource},
     engine::{AsyncEngine, Data},
     pipeline::{
         AddressedPushRouter, AddressedRequest, Error, ManyOut, SingleIn,
         error::{PipelineError, PipelineErrorExt},
     },
+    policy::Action,
     protocols::maybe_error::MaybeError,
     traits::DistributedRuntimeProvider,
 };
@@ -21,10 +22,12 @@ use serde::{Deserialize, Serialize};
 use std::{
     future::Future,
     marker::PhantomData,
+    path::Path,
     sync::{
         Arc,
         atomic::{AtomicU64, Ordering},
     },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use tokio_stream::StreamExt;
 
@@ -66,12 +69,55 @@ where
     /// If None, busy detection is disabled
     busy_threshold: Option<f64>,
 
+    /// Whether requests in flight when the NATS connection drops are sent again
+    redispatch: bool,
+
     /// An internal Rust type. This says that PushRouter is generic over the T and U types,
     /// which are the input and output types of it's `generate` function. It allows the
     /// compiler to specialize us at compile time.
     _phantom: PhantomData<(T, U)>,
 }
 
+/// What a [`PushRouter`] routes by, saved so a restarted process can route sensibly before its
+/// watch of the instances catches up. See [`PushRouter::snapshot`] and [`PushRouter::restore`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RouterSnapshot {
+    /// Path of the endpoint routed to
+    pub endpoint: String,
+    /// Unix time in milliseconds
+    pub taken_at_ms: u64,
+    pub instances: Vec<Instance>,
+    /// Reported down since the instances last changed
+    pub down: Vec<u64>,
+    /// Over the busy threshold
+    pub busy: Vec<u64>,
+    pub round_robin_counter: u64,
+}
+
+impl RouterSnapshot {
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        std::fs::write(path, serde_json::to_vec(self)?)?;
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+
+    /// How much longer it may be routed by, if it is younger than `max_age`
+    fn remaining(&self, now_ms: u64, max_age: Duration) -> Option<Duration> {
+        let age = Duration::from_millis(now_ms.saturating_sub(self.taken_at_ms));
+        max_age
+            .checked_sub(age)
+            .filter(|remaining| !remaining.is_zero())
+    }
+}
+
+fn unix_time_ms() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH);
+    now.map_or(0, |now| now.as_millis() as u64)
+}
+
 #[derive(Default, Debug, Clone, Copy, PartialEq)]
 pub enum RouterMode {
     #[default]
@@ -89,13 +135,14 @@ impl RouterMode {
 }
 
 async fn addressed_router(endpoint: &Endpoint) -> anyhow::Result<Arc<AddressedPushRouter>> {
-    let Some(nats_client) = endpoint.drt().nats_client() else {
+    if let Some(network) = endpoint.drt().in_process_network() {
+        return Ok(AddressedPushRouter::in_process(network.clone()));
+    }
+    let namespace = endpoint.component().namespace().name();
+    let Some(nats_client) = endpoint.drt().nats_client_for(&namespace) else {
         anyhow::bail!("Missing NATS. Please ensure it is running and accessible.");
     };
-    AddressedPushRouter::new(
-        nats_client.client().clone(),
-        endpoint.drt().tcp_server().await?,
-    )
+    AddressedPushRouter::new(nats_client.clone(), endpoint.drt().tcp_server().await?)
 }
 
 impl<T, U> PushRouter<T, U>
@@ -130,12 +177,82 @@ where
             router_mode,
             round_robin_counter: Arc::new(AtomicU64::new(0)),
             busy_threshold,
+            redispatch: false,
             _phantom: PhantomData,
         };
 
         Ok(router)
     }
 
+    /// Send requests that were in flight when the NATS connection dropped again once it is back,
+    /// rather than failing them with [`ConnectionLost`](crate::transports::nats::ConnectionLost).
+    /// Only for endpoints that are idempotent, as a worker may get the same request twice.
+    pub fn with_redispatch(mut self) -> Self {
+        self.redispatch = true;
+        self
+    }
+
+    /// The instances this router knows about and their state, to [`PushRouter::restore`] after
+    /// a restart
+    pub fn snapshot(&self) -> RouterSnapshot {
+        let avail = self.client.instance_ids_avail();
+        let free = self.client.instance_ids_free();
+        let instances = self.client.instances();
+        let down = instances
+            .iter()
+            .map(Instance::id)
+            .filter(|id| !avail.contains(id))
+            .collect();
+        let busy = avail
+            .iter()
+            .copied()
+            .filter(|id| !free.contains(id))
+            .collect();
+        RouterSnapshot {
+            endpoint: self.client.path(),
+            taken_at_ms: unix_time_ms(),
+            instances,
+            down,
+            busy,
+            round_robin_counter: self.round_robin_counter.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Route by `snapshot` until the watch of the instances finds instances of its own, for at
+    /// most what is left of `max_age` since the snapshot was taken. Meant for right after
+    /// startup: false, and nothing changes, if the watch has found instances already, the
+    /// snapshot is older than `max_age`, or it is of another endpoint.
+    pub fn restore(&self, snapshot: RouterSnapshot, max_age: Duration) -> bool {
+        if snapshot.endpoint != self.client.path() {
+            tracing::warn!(
+                snapshot = snapshot.endpoint,
+                endpoint = self.client.path(),
+                "Not restoring the snapshot of another endpoint"
+            );
+            return false;
+        }
+        let Some(expires_in) = snapshot.remaining(unix_time_ms(), max_age) else {
+            tracing::debug!(
+                taken_at_ms = snapshot.taken_at_ms,
+                "Router snapshot too old"
+            );
+            return false;
+        };
+        let instances = snapshot.instances.len();
+        let restored = self.client.restore_instances(
+            snapshot.instances,
+            &snapshot.down,
+            &snapshot.busy,
+            expires_in,
+        );
+        if restored {
+            let counter = snapshot.round_robin_counter;
+            self.round_robin_counter.store(counter, Ordering::Relaxed);
+            tracing::info!(instances, ?expires_in, "Routing by snapshot for now");
+        }
+        restored
+    }
+
     /// Issue a request to the next available instance in a round-robin fashion
     pub async fn round_robin(&self, request: SingleIn<T>) -> anyhow::Result<ManyOut<U>> {
         let counter = self.round_robin_counter.fetch_add(1, Ordering::Relaxed) as usize;
@@ -197,9 +314,11 @@ where
     }
 
     pub async fn r#static(&self, request: SingleIn<T>) -> anyhow::Result<ManyOut<U>> {
+        self.client.endpoint.check_policy(Action::Invoke)?;
         let subject = self.client.endpoint.subject();
         tracing::debug!("static got subject: {subject}");
-        let request = request.map(|req| AddressedRequest::new(req, subject));
+        let request =
+            request.map(|req| AddressedRequest::new(req, subject).with_redispatch(self.redispatch));
         tracing::debug!("router generate");
         self.addressed.generate(request).await
     }
@@ -209,6 +328,8 @@ where
         instance_id: u64,
         request: SingleIn<T>,
     ) -> anyhow::Result<ManyOut<U>> {
+        self.client.endpoint.check_policy(Action::Invoke)?;
+
         // Check if all workers are busy (only if busy threshold is set)
         if self.busy_threshold.is_some() {
             let free_instances = self.client.instance_ids_free();
@@ -225,7 +346,14 @@ where
         }
 
         let subject = self.client.endpoint.subject_to(instance_id);
-        let request = request.map(|req| AddressedRequest::new(req, subject));
+        let endpoint = self.client.endpoint.subject();
+        let codec = self.client.codec_of(instance_id);
+        let request = request.map(|req| {
+            AddressedRequest::new(req, subject)
+                .with_endpoint(endpoint)
+                .with_codec(codec)
+                .with_redispatch(self.redispatch)
+        });
 
         let stream: anyhow::Result<ManyOut<U>> = self.addressed.generate(request).await;
         match stream {
@@ -247,11 +375,13 @@ where
                 Ok(ResponseStream::new(Box::pin(stream), engine_ctx))
             }
             Err(err) => {
-                if let Some(req_err) = err.downcast_ref::<NatsRequestError>()
-                    && matches!(req_err.kind(), NatsNoResponders)
-                {
+                let no_responders = err
+                    .downcast_ref::<NatsRequestError>()
+                    .is_some_and(|req_err| matches!(req_err.kind(), NatsNoResponders))
+                    || err.downcast_ref::<in_process::NoResponders>().is_some();
+                if no_responders {
                     tracing::debug!(
-                        "Reporting instance {instance_id} down due to request error: {req_err}"
+                        "Reporting instance {instance_id} down due to request error: {err}"
                     );
                     self.client.report_instance_down(instance_id);
                 }
@@ -281,3 +411,32 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot() {
+        let snapshot = RouterSnapshot {
+            endpoint: "dynamo/backend/generate".to_string(),
+            taken_at_ms: 1_000_000,
+            instances: vec![],
+            down: vec![2],
+            busy: vec![3],
+            round_robin_counter: 42,
+        };
+        let max_age = Duration::from_secs(30);
+        let remaining = snapshot.remaining(1_010_000, max_age);
+        assert_eq!(remaining, Some(Duration::from_secs(20)));
+        assert_eq!(snapshot.remaining(1_030_000, max_age), None);
+        // Clocks that went back make it no older
+        assert_eq!(snapshot.remaining(999_000, max_age), Some(max_age));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("router.json");
+        snapshot.save(&path).unwrap();
+        assert_eq!(RouterSnapshot::load(&path).unwrap(), snapshot);
+        assert!(RouterSnapshot::load(dir.path().join("missing.json")).is_err());
+    }
+}
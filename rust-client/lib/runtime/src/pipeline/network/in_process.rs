@@ -0,0 +1,359 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Request and response planes that never leave the process.
+//!
+//! The NATS request plane is replaced by a map from endpoint subject to the serving endpoint's
+//! queue, and the TCP call-home response stream by channels. Requests and responses are still
+//! encoded exactly as on the network, so ingress handlers and routers run the same code paths.
+//! Used by [`crate::testing::InProcessCluster`].
+
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock};
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+
+use super::{
+    ConnectionInfo, ControlMessage, PendingConnections, RegisteredStream, ResponseService,
+    ResponseStreamPrologue, StreamOptions, StreamReceiver, StreamSender,
+    codec::{TwoPartMessage, TwoPartMessageType},
+};
+use crate::engine::AsyncEngineContext;
+use crate::pipeline::PipelineError;
+use crate::{Result, error};
+
+pub const IN_PROCESS_TRANSPORT: &str = "in_process";
+
+/// Buffer between the serving side and the router, the same as the TCP server uses
+const RESPONSE_BUFFER: usize = 64;
+
+/// The in-process equivalent of NATS' no responders: nothing serves the subject any more
+#[derive(thiserror::Error, Debug)]
+#[error("No in-process endpoint is serving {0}")]
+pub struct NoResponders(pub String);
+
+/// The subjects served by the workers of one in-process cluster. Each worker holds its own
+/// handle from [`InProcessNetwork::join`], which carries the ID it serves under.
+#[derive(Clone)]
+pub struct InProcessNetwork {
+    inner: Arc<NetworkInner>,
+    instance_id: u64,
+}
+
+struct NetworkInner {
+    /// Endpoint subject -> (instance ID, request queue of the endpoint serving it)
+    subjects: Mutex<HashMap<String, (u64, mpsc::UnboundedSender<Bytes>)>>,
+    /// Instances that requests can't reach for now, see [`InProcessNetwork::partition`]
+    partitioned: Mutex<HashSet<u64>>,
+    last_instance_id: AtomicU64,
+}
+
+impl InProcessNetwork {
+    pub fn new() -> Self {
+        InProcessNetwork {
+            inner: Arc::new(NetworkInner {
+                subjects: Mutex::new(HashMap::new()),
+                partitioned: Mutex::new(HashSet::new()),
+                last_instance_id: AtomicU64::new(0),
+            }),
+            instance_id: 0,
+        }
+    }
+
+    /// A handle for a new worker, with an instance ID no other worker on this network has.
+    /// The ID stands in for the etcd lease ID.
+    pub fn join(&self) -> InProcessNetwork {
+        InProcessNetwork {
+            inner: self.inner.clone(),
+            instance_id: self.inner.last_instance_id.fetch_add(1, Ordering::Relaxed) + 1,
+        }
+    }
+
+    pub fn instance_id(&self) -> u64 {
+        self.instance_id
+    }
+
+    /// Start serving `subject`, replacing whoever served it before
+    pub fn serve(&self, subject: String) -> mpsc::UnboundedReceiver<Bytes> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.inner
+            .subjects
+            .lock()
+            .insert(subject, (self.instance_id, tx));
+        rx
+    }
+
+    pub fn stop_serving(&self, subject: &str) {
+        self.inner.subjects.lock().remove(subject);
+    }
+
+    /// Stop serving every subject of `instance_id` at once, as if the worker's process died.
+    /// Its discovery registrations are left behind.
+    pub fn disconnect(&self, instance_id: u64) {
+        self.inner
+            .subjects
+            .lock()
+            .retain(|_, (id, _)| *id != instance_id);
+    }
+
+    /// Fail requests to `instance_id` (`true`) as [`InProcessNetwork::disconnect`] does, but
+    /// keep its endpoints serving so that they answer again once healed (`false`)
+    pub fn partition(&self, instance_id: u64, partitioned: bool) {
+        let mut instances = self.inner.partitioned.lock();
+        if partitioned {
+            instances.insert(instance_id);
+        } else {
+            instances.remove(&instance_id);
+        }
+    }
+
+    /// Queue a request for the endpoint serving `subject`
+    pub fn request(&self, subject: &str, payload: Bytes) -> Result<(), NoResponders> {
+        let mut subjects = self.inner.subjects.lock();
+        let Some((instance_id, tx)) = subjects.get(subject) else {
+            return Err(NoResponders(subject.to_string()));
+        };
+        if self.inner.partitioned.lock().contains(instance_id) {
+            return Err(NoResponders(subject.to_string()));
+        }
+        if tx.send(payload).is_err() {
+            // The endpoint stopped without saying so
+            subjects.remove(subject);
+            return Err(NoResponders(subject.to_string()));
+        }
+        Ok(())
+    }
+}
+
+impl Default for InProcessNetwork {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for InProcessNetwork {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InProcessNetwork")
+            .field("instance_id", &self.instance_id)
+            .field("subjects", &self.inner.subjects.lock().len())
+            .finish()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct InProcessConnectionInfo {
+    stream_id: String,
+    context: String,
+}
+
+struct PendingResponse {
+    /// The router's side of the request, stopped or killed by the caller
+    context: Arc<dyn AsyncEngineContext>,
+    connection: oneshot::Sender<Result<StreamReceiver, PipelineError>>,
+}
+
+/// Response streams waiting for the serving side to pick them up. Stream IDs are UUIDs, so all
+/// networks in the process can share one map, and the serving side only needs the
+/// [`ConnectionInfo`] from the request to find it.
+static PENDING_RESPONSES: LazyLock<Mutex<HashMap<String, PendingResponse>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// The router side of the response plane, in place of the TCP server
+pub struct InProcessResponseServer;
+
+#[async_trait::async_trait]
+impl ResponseService for InProcessResponseServer {
+    async fn register(&self, options: StreamOptions) -> PendingConnections {
+        let recv_stream = if options.enable_response_stream {
+            let (connection, stream_provider) = oneshot::channel();
+            let stream_id = uuid::Uuid::new_v4().to_string();
+            let info = InProcessConnectionInfo {
+                stream_id: stream_id.clone(),
+                context: options.context.id().to_string(),
+            };
+            let mut pending = PENDING_RESPONSES.lock();
+            // Requests that never reached an endpoint leave their stream behind
+            pending.retain(|_, p| !p.connection.is_closed());
+            pending.insert(
+                stream_id,
+                PendingResponse {
+                    context: options.context.clone(),
+                    connection,
+                },
+            );
+            Some(RegisteredStream {
+                connection_info: ConnectionInfo {
+                    transport: IN_PROCESS_TRANSPORT.to_string(),
+                    info: serde_json::to_string(&info)
+                        .expect("Failed to serialize InProcessConnectionInfo"),
+                },
+                stream_provider,
+            })
+        } else {
+            None
+        };
+
+        // Request streams are not implemented by the TCP server either
+        PendingConnections {
+            send_stream: None,
+            recv_stream,
+        }
+    }
+}
+
+/// The serving side of the response plane, in place of
+/// [`super::tcp::client::TcpClient::create_response_stream`]
+pub async fn create_response_stream(
+    context: Arc<dyn AsyncEngineContext>,
+    info: ConnectionInfo,
+) -> Result<StreamSender> {
+    if info.transport != IN_PROCESS_TRANSPORT {
+        return Err(error!(
+            "Invalid transport; expected `{IN_PROCESS_TRANSPORT}`, got `{}`",
+            info.transport
+        ));
+    }
+    let info: InProcessConnectionInfo = serde_json::from_str(&info.info)?;
+    if info.context != context.id() {
+        return Err(error!(
+            "Invalid context; expected {:?}, got {:?}",
+            context.id(),
+            info.context
+        ));
+    }
+    let Some(pending) = PENDING_RESPONSES.lock().remove(&info.stream_id) else {
+        return Err(error!(
+            "Response stream {} is not registered, or was already taken",
+            info.stream_id
+        ));
+    };
+
+    let (tx, rx) = mpsc::channel(RESPONSE_BUFFER);
+    tokio::spawn(forward_responses(rx, pending, context));
+
+    Ok(StreamSender {
+        tx,
+        prologue: Some(ResponseStreamPrologue {
+            error: None,
+            incompatible: None,
+        }),
+    })
+}
+
+/// Hand the stream to the router once the prologue says generate worked, then forward data
+/// until either side goes away
+async fn forward_responses(
+    mut rx: mpsc::Receiver<TwoPartMessage>,
+    pending: PendingResponse,
+    serving_context: Arc<dyn AsyncEngineContext>,
+) {
+    let PendingResponse {
+        context,
+        connection,
+    } = pending;
+
+    let prologue = match rx.recv().await.map(TwoPartMessage::into_message_type) {
+        Some(TwoPartMessageType::HeaderOnly(header)) => {
+            serde_json::from_slice::<ResponseStreamPrologue>(&header)
+                .map_err(|e| format!("Failed to deserialize prologue: {e}"))
+        }
+        Some(_) => Err("Expected a prologue as the first response message".to_string()),
+        None => Err("Response stream closed before the prologue".to_string()),
+    };
+    let prologue = match prologue {
+        Ok(prologue) => prologue,
+        Err(err) => {
+            let _ = connection.send(Err(PipelineError::ConnectionFailed(err)));
+            return;
+        }
+    };
+    if let Some(err) = prologue.into_error() {
+        let _ = connection.send(Err(err));
+        return;
+    }
+
+    let (response_tx, response_rx) = mpsc::channel(RESPONSE_BUFFER);
+    if connection
+        .send(Ok(StreamReceiver { rx: response_rx }))
+        .is_err()
+    {
+        tracing::trace!("Router dropped the response stream before it was connected");
+        serving_context.kill();
+        return;
+    }
+
+    let mut can_stop = true;
+    loop {
+        tokio::select! {
+            biased;
+
+            _ = response_tx.closed() => {
+                serving_context.kill();
+                break;
+            }
+
+            _ = context.killed() => {
+                serving_context.kill();
+                break;
+            }
+
+            _ = context.stopped(), if can_stop => {
+                can_stop = false;
+                serving_context.stop();
+            }
+
+            msg = rx.recv() => {
+                let Some(msg) = msg else {
+                    // The serving side dropped its StreamSender
+                    break;
+                };
+                let (header, data) = msg.into_parts();
+                if !header.is_empty()
+                    && let Ok(ControlMessage::Sentinel) = serde_json::from_slice(&header)
+                {
+                    break;
+                }
+                if !data.is_empty() && response_tx.send(data).await.is_err() {
+                    serving_context.kill();
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_network_request_routing() {
+        let network = InProcessNetwork::new();
+        let a = network.join();
+        let b = network.join();
+        assert_ne!(a.instance_id(), b.instance_id());
+        assert_ne!(a.instance_id(), 0);
+
+        let mut rx_a = a.serve("svc.generate-1".to_string());
+        let _rx_b = b.serve("svc.generate-2".to_string());
+
+        network
+            .request("svc.generate-1", Bytes::from_static(b"hi"))
+            .unwrap();
+        assert_eq!(rx_a.try_recv().unwrap(), Bytes::from_static(b"hi"));
+
+        assert!(network.request("svc.other", Bytes::new()).is_err());
+
+        // A crashed worker stops answering all its subjects at once
+        network.disconnect(b.instance_id());
+        assert!(network.request("svc.generate-2", Bytes::new()).is_err());
+        network.request("svc.generate-1", Bytes::new()).unwrap();
+
+        drop(rx_a);
+        assert!(network.request("svc.generate-1", Bytes::new()).is_err());
+    }
+}
@@ -0,0 +1,209 @@
+// SPDX-FileCopyrightText: Copyright (c) 2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Answering a retried request with the response it already got, instead of running it again.
+//!
+//! A request whose [`Context`] has an
+//! [idempotency key](crate::pipeline::context::Context::with_idempotency_key) carries it to the
+//! worker. Once the worker has streamed a complete response without errors, it keeps the response
+//! in [`IDEMPOTENCY_BUCKET`] under the endpoint and the key. The same key sent to the endpoint
+//! again, by any of its workers, gets that response replayed until the bucket's
+//! [`IDEMPOTENCY_TTL`] drops it. That makes non-streaming endpoints that change things safe to
+//! retry after a failover: the retry either runs the request, if the first try never finished,
+//! or hands back what it answered.
+//!
+//! An HTTP frontend reads the key from the [`IDEMPOTENCY_KEY_HEADER`] header with
+//! [`key_from_http_headers`].
+//!
+//! Responses bigger than [`MAX_STORED_BYTES`] aren't kept. Two tries with the same key running
+//! at once both run, so a caller retries only once it has given up on the first.
+
+use std::time::Duration;
+
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD as BASE64;
+
+use super::*;
+use crate::component::Endpoint;
+use crate::storage::key_value_store::{Key, KeyValueStoreManager};
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// Where the responses of requests with an idempotency key are kept
+pub const IDEMPOTENCY_BUCKET: &str = "v1/idempotency";
+
+/// How long a response is kept for retries
+pub const IDEMPOTENCY_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Larger responses are not kept, as the store isn't meant for bulk data
+pub const MAX_STORED_BYTES: usize = 512 * 1024;
+
+/// The key in `headers`, if any
+pub fn key_from_http_headers(headers: &axum::http::HeaderMap) -> Option<String> {
+    let key = headers.get(IDEMPOTENCY_KEY_HEADER)?.to_str().ok()?.trim();
+    (!key.is_empty()).then(|| key.to_string())
+}
+
+/// A complete response, as the encoded frames that were sent
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct StoredResponse {
+    codec: PayloadCodec,
+    /// In base64, the end of stream marker included
+    frames: Vec<String>,
+}
+
+/// The stored responses of one endpoint
+pub(crate) struct IdempotencyStore {
+    store: KeyValueStoreManager,
+    /// The endpoint's path, as a key, which its responses are kept under
+    prefix: String,
+}
+
+impl IdempotencyStore {
+    pub(crate) fn new(endpoint: &Endpoint) -> Self {
+        IdempotencyStore {
+            store: endpoint.drt().store(),
+            prefix: Key::new(&endpoint.path()).to_string(),
+        }
+    }
+
+    /// Hashed, as callers pick keys of any length and with any characters
+    fn key(&self, idempotency_key: &str) -> Key {
+        let hash = blake3::hash(idempotency_key.as_bytes());
+        Key::from_raw(format!("{}/{}", self.prefix, hash.to_hex()))
+    }
+
+    /// The frames to replay for `idempotency_key`, if a response sent with `codec` is kept
+    pub(crate) async fn load(
+        &self,
+        idempotency_key: &str,
+        codec: PayloadCodec,
+    ) -> Option<Vec<Bytes>> {
+        let key = self.key(idempotency_key);
+        let loaded: Result<Option<Vec<Bytes>>> = async {
+            let Some(bucket) = self.store.get_bucket(IDEMPOTENCY_BUCKET).await? else {
+                return Ok(None);
+            };
+            let Some(value) = bucket.get(&key).await? else {
+                return Ok(None);
+            };
+            let stored: StoredResponse = serde_json::from_slice(&value)?;
+            if stored.codec != codec {
+                // Sent by a router that reads another codec, it runs again
+                return Ok(None);
+            }
+            let frames = stored
+                .frames
+                .iter()
+                .map(|frame| BASE64.decode(frame).map(Bytes::from))
+                .collect::<Result<_, _>>()?;
+            Ok(Some(frames))
+        }
+        .await;
+        loaded.unwrap_or_else(|err| {
+            tracing::warn!(%err, %key, "Failed to load a stored response, running the request");
+            None
+        })
+    }
+
+    async fn save(&self, idempotency_key: &str, response: &StoredResponse) -> Result<()> {
+        let bucket = self
+            .store
+            .get_or_create_bucket(IDEMPOTENCY_BUCKET, Some(IDEMPOTENCY_TTL))
+            .await?;
+        let value = serde_json::to_string(response)?;
+        bucket
+            .insert_new(&self.key(idempotency_key), &value)
+            .await?;
+        Ok(())
+    }
+}
+
+/// The frames of a response being sent, to keep once it is complete
+pub(crate) struct Recording {
+    idempotency_key: String,
+    response: StoredResponse,
+    bytes: usize,
+}
+
+impl Recording {
+    pub(crate) fn new(idempotency_key: String, codec: PayloadCodec) -> Self {
+        Recording {
+            idempotency_key,
+            response: StoredResponse {
+                codec,
+                frames: Vec::new(),
+            },
+            bytes: 0,
+        }
+    }
+
+    /// Add a frame, false if the response got too big to keep
+    pub(crate) fn push(&mut self, frame: &[u8]) -> bool {
+        self.bytes += frame.len();
+        if self.bytes > MAX_STORED_BYTES {
+            return false;
+        }
+        self.response.frames.push(BASE64.encode(frame));
+        true
+    }
+
+    /// Keep the recorded response. The first one kept for a key stays.
+    pub(crate) async fn save(self, store: &IdempotencyStore) {
+        if let Err(err) = store.save(&self.idempotency_key, &self.response).await {
+            let key = self.idempotency_key;
+            tracing::warn!(%err, key, "Failed to keep a response for retries");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_store_and_load() {
+        let store = IdempotencyStore {
+            store: KeyValueStoreManager::memory(),
+            prefix: "dynamo_backend_charge".to_string(),
+        };
+        assert!(store.load("order-1", PayloadCodec::Json).await.is_none());
+
+        let mut recording = Recording::new("order-1".to_string(), PayloadCodec::Json);
+        assert!(recording.push(br#"{"data":"ok","complete_final":false}"#));
+        assert!(recording.push(br#"{"complete_final":true}"#));
+        recording.save(&store).await;
+
+        let frames = store.load("order-1", PayloadCodec::Json).await.unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(&frames[1][..], br#"{"complete_final":true}"#);
+        assert!(store.load("order-2", PayloadCodec::Json).await.is_none());
+        assert!(
+            store
+                .load("order-1", PayloadCodec::MessagePack)
+                .await
+                .is_none()
+        );
+
+        // The first response kept stays
+        let mut again = Recording::new("order-1".to_string(), PayloadCodec::Json);
+        assert!(again.push(b"other"));
+        again.save(&store).await;
+        assert_eq!(
+            store
+                .load("order-1", PayloadCodec::Json)
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+
+        let mut big = Recording::new("order-3".to_string(), PayloadCodec::Json);
+        assert!(!big.push(&vec![0; MAX_STORED_BYTES + 1]));
+
+        let mut headers = axum::http::HeaderMap::new();
+        assert_eq!(key_from_http_headers(&headers), None);
+        headers.insert(IDEMPOTENCY_KEY_HEADER, " order-1 ".parse().unwrap());
+        assert_eq!(key_from_http_headers(&headers).as_deref(), Some("order-1"));
+    }
+}
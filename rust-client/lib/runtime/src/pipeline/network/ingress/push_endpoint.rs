@@ -2,12 +2,15 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
 
 use super::*;
 use crate::SystemHealth;
 use crate::config::HealthStatus;
 use crate::logging::make_handle_payload_span;
+use crate::logging::sampling::{self, Sampled, TraceSampling};
 use crate::protocols::LeaseId;
+use crate::transports::accounting::Meter;
 use anyhow::Result;
 use async_nats::service::endpoint::Endpoint;
 use derive_builder::Builder;
@@ -23,6 +26,57 @@ pub struct PushEndpoint {
     pub cancellation_token: CancellationToken,
     #[builder(default = "true")]
     pub graceful_shutdown: bool,
+    /// Counts the bytes of the requests received, see [`crate::transports::accounting`]
+    #[builder(default)]
+    pub meter: Option<Meter>,
+    /// Which requests are traced, see [`crate::logging::sampling`]
+    #[builder(default)]
+    pub sampling: TraceSampling,
+}
+
+/// Where an endpoint's requests come from
+pub enum RequestSource {
+    /// A NATS service endpoint
+    Nats(Box<Endpoint>),
+    /// The queue of an [`in_process`](crate::pipeline::network::in_process) subject
+    InProcess(tokio::sync::mpsc::UnboundedReceiver<Bytes>),
+}
+
+impl From<Endpoint> for RequestSource {
+    fn from(endpoint: Endpoint) -> Self {
+        RequestSource::Nats(Box::new(endpoint))
+    }
+}
+
+impl RequestSource {
+    /// The next request's payload and headers, acknowledged if the transport expects it
+    async fn next(&mut self) -> Option<(Bytes, Option<async_nats::HeaderMap>)> {
+        match self {
+            RequestSource::Nats(endpoint) => {
+                let req = endpoint.next().await?;
+                let response = "".to_string();
+                if let Err(e) = req.respond(Ok(response.into())).await {
+                    tracing::warn!(
+                        "Failed to respond to request; this may indicate the request has shutdown: {:?}",
+                        e
+                    );
+                }
+                Some((req.message.payload.clone(), req.message.headers.clone()))
+            }
+            RequestSource::InProcess(rx) => rx.recv().await.map(|payload| (payload, None)),
+        }
+    }
+
+    async fn stop(&mut self) {
+        match self {
+            RequestSource::Nats(endpoint) => {
+                if let Err(e) = endpoint.stop().await {
+                    tracing::warn!("Failed to stop NATS service: {:?}", e);
+                }
+            }
+            RequestSource::InProcess(rx) => rx.close(),
+        }
+    }
 }
 
 /// version of crate
@@ -35,14 +89,14 @@ impl PushEndpoint {
 
     pub async fn start(
         self,
-        endpoint: Endpoint,
+        endpoint: impl Into<RequestSource>,
         namespace: String,
         component_name: String,
         endpoint_name: String,
         instance_id: u64,
         system_health: Arc<Mutex<SystemHealth>>,
     ) -> Result<()> {
-        let mut endpoint = endpoint;
+        let mut endpoint = endpoint.into();
 
         let inflight = Arc::new(AtomicU64::new(0));
         let notify = Arc::new(Notify::new());
@@ -66,22 +120,15 @@ impl PushEndpoint {
                 // process shutdown
                 _ = self.cancellation_token.cancelled() => {
                     tracing::info!("PushEndpoint received cancellation signal, shutting down service");
-                    if let Err(e) = endpoint.stop().await {
-                        tracing::warn!("Failed to stop NATS service: {:?}", e);
-                    }
+                    endpoint.stop().await;
                     break;
                 }
             };
 
-            if let Some(req) = req {
-                let response = "".to_string();
-                if let Err(e) = req.respond(Ok(response.into())).await {
-                    tracing::warn!(
-                        "Failed to respond to request; this may indicate the request has shutdown: {:?}",
-                        e
-                    );
+            if let Some((payload, headers)) = req {
+                if let Some(meter) = &self.meter {
+                    meter.received(payload.len());
                 }
-
                 let ingress = self.service_handler.clone();
                 let endpoint_name: Arc<String> = Arc::clone(&endpoint_name_local);
                 let component_name: Arc<String> = Arc::clone(&component_name_local);
@@ -93,24 +140,43 @@ impl PushEndpoint {
                 let notify_clone = notify.clone();
 
                 // Handle headers here for tracing
-                let span = if let Some(headers) = req.message.headers.as_ref() {
-                    make_handle_payload_span(
-                        headers,
-                        component_name.as_ref(),
-                        endpoint_name.as_ref(),
-                        namespace.as_ref(),
-                        instance_id,
-                    )
-                } else {
-                    tracing::info_span!("handle_payload")
+                let sampled = self.sampling.sample(headers.as_ref());
+                let payload_span = move |exported: bool| {
+                    let span = if let Some(headers) = headers.as_ref() {
+                        make_handle_payload_span(
+                            headers,
+                            component_name.as_ref(),
+                            endpoint_name.as_ref(),
+                            namespace.as_ref(),
+                            instance_id,
+                        )
+                    } else {
+                        tracing::info_span!(
+                            "handle_payload",
+                            caller_id = tracing::field::Empty,
+                            tenant = tracing::field::Empty,
+                            origin = tracing::field::Empty,
+                        )
+                    };
+                    if !exported {
+                        sampling::unsample(&span, headers.as_ref());
+                    }
+                    span
                 };
+                let span = payload_span(sampled == Sampled::Yes);
 
                 tokio::spawn(async move {
                     tracing::trace!(instance_id, "handling new request");
-                    let result = ingress
-                        .handle_payload(req.message.payload)
-                        .instrument(span)
-                        .await;
+                    let started = Instant::now();
+                    let result = ingress.handle_payload(payload).instrument(span).await;
+                    let took = started.elapsed();
+                    if let Some(sampled_by) = sampled.afterwards(result.is_err(), took) {
+                        let took_ms = took.as_millis() as u64;
+                        let failed = result.is_err();
+                        payload_span(true).in_scope(|| {
+                            tracing::info!(sampled_by, took_ms, failed, "Traced after it finished");
+                        });
+                    }
                     match result {
                         Ok(_) => {
                             tracing::trace!(instance_id, "request handled successfully");
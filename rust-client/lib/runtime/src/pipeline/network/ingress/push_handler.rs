@@ -4,39 +4,70 @@
 use super::*;
 use crate::metrics::prometheus_names::work_handler;
 use crate::protocols::maybe_error::MaybeError;
+use futures::FutureExt;
 use prometheus::{Histogram, IntCounter, IntCounterVec, IntGauge};
 use serde::{Deserialize, Serialize};
+use std::panic::AssertUnwindSafe;
 use std::sync::Arc;
 use std::time::Instant;
 use tracing::Instrument;
 use tracing::info_span;
 
+/// Up to a minute, for prefills of long prompts behind a queue
+const TIME_TO_FIRST_RESPONSE_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+/// Finer, as decode steps take milliseconds
+const INTER_RESPONSE_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.02, 0.03, 0.05, 0.075, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+/// 64 bytes to 64 MiB by factors of 4, from token streams to prompts with images
+const PAYLOAD_SIZE_BUCKETS: &[f64] = &[
+    64.0, 256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0, 4194304.0, 16777216.0,
+    67108864.0,
+];
+
 /// Metrics configuration for profiling work handlers
 #[derive(Clone, Debug)]
 pub struct WorkHandlerMetrics {
     pub request_counter: IntCounter,
     pub request_duration: Histogram,
+    pub time_to_first_response: Histogram,
+    pub inter_response: Histogram,
     pub inflight_requests: IntGauge,
     pub request_bytes: IntCounter,
     pub response_bytes: IntCounter,
+    pub request_size: Histogram,
+    pub response_size: Histogram,
     pub error_counter: IntCounterVec,
 }
 
 impl WorkHandlerMetrics {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         request_counter: IntCounter,
         request_duration: Histogram,
+        time_to_first_response: Histogram,
+        inter_response: Histogram,
         inflight_requests: IntGauge,
         request_bytes: IntCounter,
         response_bytes: IntCounter,
+        request_size: Histogram,
+        response_size: Histogram,
         error_counter: IntCounterVec,
     ) -> Self {
         Self {
             request_counter,
             request_duration,
+            time_to_first_response,
+            inter_response,
             inflight_requests,
             request_bytes,
             response_bytes,
+            request_size,
+            response_size,
             error_counter,
         }
     }
@@ -61,6 +92,20 @@ impl WorkHandlerMetrics {
             None,
         )?;
 
+        let time_to_first_response = metrics.create_histogram(
+            work_handler::TIME_TO_FIRST_RESPONSE_SECONDS,
+            "Time from receiving a request to sending its first response item",
+            metrics_labels,
+            Some(TIME_TO_FIRST_RESPONSE_BUCKETS.to_vec()),
+        )?;
+
+        let inter_response = metrics.create_histogram(
+            work_handler::INTER_RESPONSE_SECONDS,
+            "Time between consecutive response items of a stream",
+            metrics_labels,
+            Some(INTER_RESPONSE_BUCKETS.to_vec()),
+        )?;
+
         let inflight_requests = metrics.create_intgauge(
             work_handler::INFLIGHT_REQUESTS,
             "Number of requests currently being processed by work handler",
@@ -79,6 +124,20 @@ impl WorkHandlerMetrics {
             metrics_labels,
         )?;
 
+        let request_size = metrics.create_histogram(
+            work_handler::REQUEST_SIZE_BYTES,
+            "Size of each request payload received by work handler",
+            metrics_labels,
+            Some(PAYLOAD_SIZE_BUCKETS.to_vec()),
+        )?;
+
+        let response_size = metrics.create_histogram(
+            work_handler::RESPONSE_SIZE_BYTES,
+            "Size of each response item sent by work handler",
+            metrics_labels,
+            Some(PAYLOAD_SIZE_BUCKETS.to_vec()),
+        )?;
+
         let error_counter = metrics.create_intcountervec(
             work_handler::ERRORS_TOTAL,
             "Total number of errors in work handler processing",
@@ -89,14 +148,32 @@ impl WorkHandlerMetrics {
         Ok(Self::new(
             request_counter,
             request_duration,
+            time_to_first_response,
+            inter_response,
             inflight_requests,
             request_bytes,
             response_bytes,
+            request_size,
+            response_size,
             error_counter,
         ))
     }
 }
 
+impl<Req: PipelineIO + Sync, Resp: PipelineIO> Ingress<Req, Resp> {
+    /// Count and log a panic of the handler, as the error for the request it was handling
+    fn panicked(&self, panic: &(dyn std::any::Any + Send)) -> PipelineError {
+        let message = crate::utils::tasks::panic_message(panic);
+        tracing::error!(%message, "Request handler panicked");
+        if let Some(m) = self.metrics() {
+            m.error_counter
+                .with_label_values(&[work_handler::error_types::PANIC])
+                .inc();
+        }
+        PipelineError::HandlerPanicked(message)
+    }
+}
+
 // RAII guard to ensure inflight gauge is decremented and request duration is observed on all code paths.
 struct RequestMetricsGuard {
     inflight_requests: prometheus::IntGauge,
@@ -137,12 +214,14 @@ where
 
     async fn handle_payload(&self, payload: Bytes) -> Result<(), PipelineError> {
         let start_time = std::time::Instant::now();
+        let payload_len = payload.len();
 
         // Increment inflight and ensure it's decremented on all exits via RAII guard
         let _inflight_guard = self.metrics().map(|m| {
             m.request_counter.inc();
             m.inflight_requests.inc();
             m.request_bytes.inc_by(payload.len() as u64);
+            m.request_size.observe(payload.len() as f64);
             RequestMetricsGuard {
                 inflight_requests: m.inflight_requests.clone(),
                 request_duration: m.request_duration.clone(),
@@ -178,7 +257,10 @@ where
                         )));
                     }
                 };
-                let request: T = serde_json::from_slice(&data)?;
+                let request: T = control_msg
+                    .codec
+                    .decode(&data)
+                    .map_err(|e| PipelineError::DeserializationError(e.to_string()))?;
                 (control_msg, request)
             }
             _ => {
@@ -196,17 +278,36 @@ where
         // extend request with context
         tracing::trace!("received control message: {:?}", control_msg);
         tracing::trace!("received request: {:?}", request);
-        let request: context::Context<T> = Context::with_id(request, control_msg.id);
+        let codec = control_msg.codec;
+        let mut usage = self
+            .metering
+            .get()
+            .and_then(|metering| metering.request(&control_msg.caller, payload_len));
+        control_msg.caller.record(&tracing::Span::current());
+        let mut request: context::Context<T> =
+            Context::with_id(request, control_msg.id).with_caller(control_msg.caller);
+        let idempotency_key = control_msg
+            .idempotency_key
+            .filter(|_| self.idempotency.get().is_some());
+        if let Some(key) = &idempotency_key {
+            request = request.with_idempotency_key(key.clone());
+        }
 
         // todo - eventually have a handler class which will returned an abstracted object, but for now,
-        // we only support tcp here, so we can just unwrap the connection info
-        tracing::trace!("creating tcp response stream");
-        let mut publisher = tcp::client::TcpClient::create_response_stream(
-            request.context(),
-            control_msg.connection_info,
-        )
-        .await
-        .map_err(|e| {
+        // tcp is the only network transport; in-process clusters bring their own
+        let publisher = if control_msg.connection_info.transport == in_process::IN_PROCESS_TRANSPORT
+        {
+            tracing::trace!("creating in-process response stream");
+            in_process::create_response_stream(request.context(), control_msg.connection_info).await
+        } else {
+            tracing::trace!("creating tcp response stream");
+            tcp::client::TcpClient::create_response_stream(
+                request.context(),
+                control_msg.connection_info,
+            )
+            .await
+        };
+        let mut publisher = publisher.map_err(|e| {
             if let Some(m) = self.metrics() {
                 m.error_counter
                     .with_label_values(&[work_handler::error_types::RESPONSE_STREAM])
@@ -215,21 +316,42 @@ where
             PipelineError::Generic(format!("Failed to create response stream: {:?}", e,))
         })?;
 
+        // a retry of a request that already completed gets the same response, without running it
+        if let (Some(key), Some(store)) = (&idempotency_key, self.idempotency.get())
+            && let Some(frames) = store.load(key, codec).await
+        {
+            tracing::debug!(key, "Replaying the kept response of a retried request");
+            let _result = publisher.send_prologue(None).await;
+            for frame in frames {
+                if publisher.send(frame).await.is_err() {
+                    tracing::error!(
+                        "Failed to replay a kept response for stream {}",
+                        request.id()
+                    );
+                    break;
+                }
+            }
+            return Ok(());
+        }
+        let mut recording = idempotency_key.map(|key| idempotency::Recording::new(key, codec));
+
         tracing::trace!("calling generate");
-        let stream = self
-            .segment
-            .get()
-            .expect("segment not set")
-            .generate(request)
-            .await
-            .map_err(|e| {
+        // a panic fails this request only, instead of the task serving it
+        let segment = self.segment.get().expect("segment not set");
+        let generated = AssertUnwindSafe(segment.generate(request))
+            .catch_unwind()
+            .await;
+        let stream = match generated {
+            Ok(stream) => stream.map_err(|e| {
                 if let Some(m) = self.metrics() {
                     m.error_counter
                         .with_label_values(&[work_handler::error_types::GENERATE])
                         .inc();
                 }
                 PipelineError::GenerateError(e)
-            });
+            }),
+            Err(panic) => Err(self.panicked(&*panic)),
+        };
 
         // the prolouge is sent to the client to indicate that the stream is ready to receive data
         // or if the generate call failed, the error is sent to the client
@@ -263,7 +385,20 @@ where
 
         // TODO: Detect end-of-stream using Server-Sent Events (SSE)
         let mut send_complete_final = true;
-        while let Some(resp) = stream.next().await {
+        let mut seq = 0;
+        let mut last_sent = start_time;
+        let mut panicked = false;
+        loop {
+            let resp = match AssertUnwindSafe(stream.next()).catch_unwind().await {
+                Ok(Some(resp)) => resp,
+                Ok(None) => break,
+                // the stream can't be polled again, so end it with the panic as an error item
+                Err(panic) => {
+                    panicked = true;
+                    context.stop_generating();
+                    U::from_err(self.panicked(&*panic).into())
+                }
+            };
             tracing::trace!("Sending response: {:?}", resp);
             if let Some(err) = resp.err()
                 && format!("{:?}", err) == STREAM_ERR_MSG
@@ -272,14 +407,39 @@ where
                 send_complete_final = false;
                 break;
             }
+            // errors aren't kept, so that a retry runs again
+            if resp.err().is_some() {
+                recording = None;
+            }
             let resp_wrapper = NetworkStreamWrapper {
                 data: Some(resp),
                 complete_final: false,
+                seq: Some(seq),
             };
-            let resp_bytes = serde_json::to_vec(&resp_wrapper)
+            seq += 1;
+            let resp_bytes = codec
+                .encode(&resp_wrapper)
                 .expect("fatal error: invalid response object - this should never happen");
+            if let Some(usage) = &mut usage {
+                usage.item(resp_bytes.len());
+            }
+            if let Some(kept) = &mut recording
+                && !kept.push(&resp_bytes)
+            {
+                recording = None;
+            }
             if let Some(m) = self.metrics() {
                 m.response_bytes.inc_by(resp_bytes.len() as u64);
+                m.response_size.observe(resp_bytes.len() as f64);
+                // the gap is measured when the item is handed over, before any wait on the socket
+                let now = Instant::now();
+                let gap = now.duration_since(last_sent).as_secs_f64();
+                if seq == 1 {
+                    m.time_to_first_response.observe(gap);
+                } else {
+                    m.inter_response.observe(gap);
+                }
+                last_sent = now;
             }
             if (publisher.send(resp_bytes.into()).await).is_err() {
                 tracing::error!("Failed to publish response for stream {}", context.id());
@@ -292,16 +452,27 @@ where
                 }
                 break;
             }
+            if panicked {
+                break;
+            }
         }
         if send_complete_final {
             let resp_wrapper = NetworkStreamWrapper::<U> {
                 data: None,
                 complete_final: true,
+                seq: Some(seq),
             };
-            let resp_bytes = serde_json::to_vec(&resp_wrapper)
+            let resp_bytes = codec
+                .encode(&resp_wrapper)
                 .expect("fatal error: invalid response object - this should never happen");
             if let Some(m) = self.metrics() {
                 m.response_bytes.inc_by(resp_bytes.len() as u64);
+                m.response_size.observe(resp_bytes.len() as f64);
+            }
+            if let Some(kept) = &mut recording
+                && !kept.push(&resp_bytes)
+            {
+                recording = None;
             }
             if (publisher.send(resp_bytes.into()).await).is_err() {
                 tracing::error!(
@@ -314,6 +485,10 @@ where
                         .inc();
                 }
             }
+            // complete even if the caller missed the end, so that its retry gets the response
+            if let (Some(recording), Some(store)) = (recording, self.idempotency.get()) {
+                recording.save(store).await;
+            }
             // Notify the health check manager that the stream has finished.
             // This resets the timer, delaying the next canary health check.
             if let Some(notifier) = self.endpoint_health_check_notifier.get() {
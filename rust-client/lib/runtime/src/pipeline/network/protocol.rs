@@ -0,0 +1,214 @@
+// SPDX-FileCopyrightText: Copyright (c) 2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The version of the wire protocol the tcp and zmq transports speak, and the features each end
+//! supports.
+//!
+//! Both ends of a connection tell each other their [`ProtocolInfo`] before anything else is
+//! exchanged, and [`negotiate`] what they have in common:
+//!
+//! - tcp: the router puts its own in the connection info it sends with a request, and the worker
+//!   answers with its own in the handshake it opens the response stream with, so it takes no
+//!   extra round trip. A worker too new for the router fails the stream with its prologue.
+//! - zmq: the client sends a handshake frame first, see [`crate::transports::zmq::Client`].
+//!
+//! A peer that sends none predates the handshake, and is taken to be [`ProtocolInfo::legacy`].
+//! Two ends are compatible if each one's version is at least the other's `min_peer_version`;
+//! otherwise the connection fails with [`IncompatiblePeer`] rather than carrying messages one end
+//! can't decode. So far every version talks to every other:
+//!
+//! | Version | Adds                                                          | Talks to |
+//! |---------|---------------------------------------------------------------|----------|
+//! | 1       | Two part frames, JSON control messages, stop and kill         | 1, 2, 3  |
+//! | 2       | This handshake, with the codecs and compression of each end  | 1, 2, 3  |
+//! | 3       | Keep-alive pings on tcp, to ends that advertise an interval   | 1, 2, 3  |
+//!
+//! A change old peers would misread bumps [`PROTOCOL_VERSION`], and raises
+//! [`MIN_PEER_VERSION`] once the versions before it are no longer deployed.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use super::codec::PayloadCodec;
+
+/// The version this build speaks
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// The oldest version this build talks to
+pub const MIN_PEER_VERSION: u32 = 1;
+
+/// The version of peers that send no [`ProtocolInfo`]
+pub const LEGACY_VERSION: u32 = 1;
+
+/// What one end of a connection speaks. Unknown codecs and compression names are kept as
+/// strings, so that an older peer reads the info of a newer one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProtocolInfo {
+    pub version: u32,
+    pub min_peer_version: u32,
+    /// The [`PayloadCodec`]s it decodes, by name
+    #[serde(default)]
+    pub codecs: Vec<String>,
+    /// The compression it decodes, none so far
+    #[serde(default)]
+    pub compression: Vec<String>,
+    /// Whether it acts on stop and kill control messages
+    #[serde(default)]
+    pub cancellation: bool,
+    /// How often it pings a connection, None if it neither sends nor expects pings
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ping_interval_ms: Option<u64>,
+}
+
+impl ProtocolInfo {
+    /// This build's
+    pub fn local() -> Self {
+        ProtocolInfo {
+            version: PROTOCOL_VERSION,
+            min_peer_version: MIN_PEER_VERSION,
+            codecs: PayloadCodec::ALL
+                .iter()
+                .map(PayloadCodec::to_string)
+                .collect(),
+            compression: Vec::new(),
+            cancellation: true,
+            ping_interval_ms: None,
+        }
+    }
+
+    /// A peer from before the handshake
+    pub fn legacy() -> Self {
+        ProtocolInfo {
+            version: LEGACY_VERSION,
+            min_peer_version: LEGACY_VERSION,
+            codecs: vec![PayloadCodec::Json.to_string()],
+            compression: Vec::new(),
+            cancellation: true,
+            ping_interval_ms: None,
+        }
+    }
+}
+
+/// What two compatible ends have in common
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Negotiated {
+    /// The lower of the two versions
+    pub version: u32,
+    pub codecs: Vec<PayloadCodec>,
+    pub compression: Vec<String>,
+    pub cancellation: bool,
+    /// How often the peer pings, if it does
+    pub peer_ping_interval: Option<Duration>,
+}
+
+impl Negotiated {
+    pub fn supports_codec(&self, codec: PayloadCodec) -> bool {
+        self.codecs.contains(&codec)
+    }
+}
+
+/// One end is older than the other accepts
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error, Serialize, Deserialize)]
+#[error(
+    "Incompatible peer: it speaks protocol version {peer_version} and accepts {peer_min_version} \
+     or later, this end speaks {local_version} and accepts {local_min_version} or later"
+)]
+pub struct IncompatiblePeer {
+    pub local_version: u32,
+    pub local_min_version: u32,
+    pub peer_version: u32,
+    pub peer_min_version: u32,
+}
+
+/// What `local` and `peer` have in common, if they can talk at all
+pub fn negotiate(
+    local: &ProtocolInfo,
+    peer: &ProtocolInfo,
+) -> Result<Negotiated, IncompatiblePeer> {
+    if peer.version < local.min_peer_version || local.version < peer.min_peer_version {
+        return Err(IncompatiblePeer {
+            local_version: local.version,
+            local_min_version: local.min_peer_version,
+            peer_version: peer.version,
+            peer_min_version: peer.min_peer_version,
+        });
+    }
+    let codecs = local
+        .codecs
+        .iter()
+        .filter(|codec| peer.codecs.contains(codec))
+        .filter_map(|codec| codec.parse().ok())
+        .collect();
+    let compression = local
+        .compression
+        .iter()
+        .filter(|name| peer.compression.contains(name))
+        .cloned()
+        .collect();
+    Ok(Negotiated {
+        version: local.version.min(peer.version),
+        codecs,
+        compression,
+        cancellation: local.cancellation && peer.cancellation,
+        peer_ping_interval: peer.ping_interval_ms.map(Duration::from_millis),
+    })
+}
+
+/// What this build has in common with `peer`, or with a legacy peer if it sent nothing
+pub fn negotiate_with(peer: Option<&ProtocolInfo>) -> Result<Negotiated, IncompatiblePeer> {
+    match peer {
+        Some(peer) => negotiate(&ProtocolInfo::local(), peer),
+        None => negotiate(&ProtocolInfo::local(), &ProtocolInfo::legacy()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let local = ProtocolInfo::local();
+        let legacy = negotiate_with(None).unwrap();
+        assert_eq!(legacy.version, LEGACY_VERSION);
+        assert!(legacy.supports_codec(PayloadCodec::Json));
+        assert!(!legacy.supports_codec(PayloadCodec::MessagePack));
+        assert!(legacy.compression.is_empty());
+        assert_eq!(legacy.peer_ping_interval, None);
+
+        let same = negotiate_with(Some(&local)).unwrap();
+        assert_eq!(same.version, PROTOCOL_VERSION);
+        assert!(same.supports_codec(PayloadCodec::MessagePack));
+        assert!(same.cancellation);
+
+        // A newer peer, with a codec this build doesn't know, that still talks to this one
+        let newer: ProtocolInfo = serde_json::from_str(
+            r#"{"version": 9, "min_peer_version": 2, "codecs": ["json", "cbor"], "zstd": true,
+                "ping_interval_ms": 500}"#,
+        )
+        .unwrap();
+        let negotiated = negotiate(&local, &newer).unwrap();
+        assert_eq!(negotiated.version, PROTOCOL_VERSION);
+        assert_eq!(negotiated.codecs, vec![PayloadCodec::Json]);
+        assert!(!negotiated.cancellation);
+        assert_eq!(
+            negotiated.peer_ping_interval,
+            Some(Duration::from_millis(500))
+        );
+
+        // Each direction of too old
+        let too_new = ProtocolInfo {
+            min_peer_version: PROTOCOL_VERSION + 1,
+            ..newer
+        };
+        let err = negotiate(&local, &too_new).unwrap_err();
+        assert_eq!(err.peer_min_version, PROTOCOL_VERSION + 1);
+        assert_eq!(negotiate(&too_new, &local).unwrap_err().local_version, 9);
+        let ancient = ProtocolInfo {
+            version: 0,
+            ..ProtocolInfo::legacy()
+        };
+        assert!(negotiate(&local, &ancient).is_err());
+    }
+}
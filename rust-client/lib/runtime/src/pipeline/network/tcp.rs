@@ -16,11 +16,22 @@
 //! - CallHome stream - the address for the listening socket is forward via some mechanism which then
 //!   connects back to the source of the CallHome stream. To match the socket with an awaiting data
 //!   stream, the CallHomeHandshake is used.
+//!
+//! Each end pings a response stream every [`KeepAlive::interval`] and gives up on it when it hears
+//! nothing from its peer for [`KeepAlive::timeout`], so a peer that vanished without closing the
+//! connection fails the stream in seconds, rather than when the OS's own tcp timeouts fire. The
+//! router then sees the stream end early and routes around the worker. Pings are only sent when
+//! both ends advertise an interval in their [`ProtocolInfo`].
 
 pub mod client;
 pub mod server;
 
+use std::time::Duration;
+
 use super::ControlMessage;
+use super::protocol::{Negotiated, ProtocolInfo};
+use crate::{ErrorContext, Result, error};
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 
 #[allow(unused_imports)]
@@ -31,12 +42,95 @@ use super::{
 
 const TCP_TRANSPORT: &str = "tcp_server";
 
+static KEEPALIVE: Lazy<Option<KeepAlive>> = Lazy::new(|| {
+    KeepAlive::from_env().unwrap_or_else(|err| {
+        tracing::warn!(%err, "Using the default tcp keep-alive");
+        Some(KeepAlive::default())
+    })
+});
+
+/// Pings on response streams, to notice peers that are gone without having closed them: ones that
+/// crashed, or sit behind a NAT that dropped the mapping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepAlive {
+    /// How often this end pings
+    pub interval: Duration,
+    /// How long this end waits to hear from its peer
+    pub timeout: Duration,
+}
+
+impl Default for KeepAlive {
+    fn default() -> Self {
+        KeepAlive {
+            interval: Duration::from_secs(2),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl KeepAlive {
+    /// From `DYN_TCP_KEEPALIVE_INTERVAL` and `DYN_TCP_KEEPALIVE_TIMEOUT`, e.g. `2s` and `10s`.
+    /// None if the interval is 0, which turns pings off.
+    pub fn from_env() -> Result<Option<Self>> {
+        let mut keepalive = KeepAlive::default();
+        if let Ok(interval) = std::env::var("DYN_TCP_KEEPALIVE_INTERVAL") {
+            keepalive.interval = humantime::parse_duration(&interval)
+                .with_context(|| format!("Invalid DYN_TCP_KEEPALIVE_INTERVAL '{interval}'"))?;
+        }
+        if let Ok(timeout) = std::env::var("DYN_TCP_KEEPALIVE_TIMEOUT") {
+            keepalive.timeout = humantime::parse_duration(&timeout)
+                .with_context(|| format!("Invalid DYN_TCP_KEEPALIVE_TIMEOUT '{timeout}'"))?;
+        }
+        if keepalive.interval.is_zero() {
+            return Ok(None);
+        }
+        if keepalive.timeout <= keepalive.interval {
+            return Err(error!(
+                "DYN_TCP_KEEPALIVE_TIMEOUT must be longer than DYN_TCP_KEEPALIVE_INTERVAL"
+            ));
+        }
+        Ok(Some(keepalive))
+    }
+
+    /// What applies on a connection to a peer: None unless it pings too, and otherwise waiting
+    /// through at least three of its intervals, which may be longer than this end's
+    pub fn with_peer(self, peer: &Negotiated) -> Option<Self> {
+        let peer_interval = peer.peer_ping_interval?;
+        Some(KeepAlive {
+            interval: self.interval,
+            timeout: self.timeout.max(peer_interval * 3),
+        })
+    }
+}
+
+/// This end's [`ProtocolInfo`], with its ping interval
+fn local_protocol() -> ProtocolInfo {
+    ProtocolInfo {
+        ping_interval_ms: KEEPALIVE.map(|keepalive| keepalive.interval.as_millis() as u64),
+        ..ProtocolInfo::local()
+    }
+}
+
+/// What applies on a connection to `peer`, see [`KeepAlive::with_peer`]
+fn keepalive_with(peer: &Negotiated) -> Option<KeepAlive> {
+    KEEPALIVE.and_then(|keepalive| keepalive.with_peer(peer))
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TcpStreamConnectionInfo {
     pub address: String,
+    /// The same server at its addresses in the other IP family, when it listens on both
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alternate_addresses: Vec<String>,
+    /// See [`StreamOptions::endpoint`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<String>,
     pub subject: String,
     pub context: String,
     pub stream_type: StreamType,
+    /// Of the server, None from one that predates the [handshake](super::protocol)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<ProtocolInfo>,
 }
 
 impl From<TcpStreamConnectionInfo> for ConnectionInfo {
@@ -78,6 +172,9 @@ impl TryFrom<ConnectionInfo> for TcpStreamConnectionInfo {
 struct CallHomeHandshake {
     subject: String,
     stream_type: StreamType,
+    /// Of the client, None from one that predates the [handshake](super::protocol)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    protocol: Option<ProtocolInfo>,
 }
 
 #[cfg(test)]
@@ -174,4 +271,31 @@ mod tests {
 
         // assert!(data.is_none());
     }
+
+    #[test]
+    fn test_keepalive() {
+        let vars = [
+            ("DYN_TCP_KEEPALIVE_INTERVAL", Some("1s")),
+            ("DYN_TCP_KEEPALIVE_TIMEOUT", Some("4s")),
+        ];
+        let keepalive = temp_env::with_vars(vars, KeepAlive::from_env)
+            .unwrap()
+            .unwrap();
+        assert_eq!(keepalive.timeout, Duration::from_secs(4));
+        let off = [("DYN_TCP_KEEPALIVE_INTERVAL", Some("0s"))];
+        assert_eq!(temp_env::with_vars(off, KeepAlive::from_env).unwrap(), None);
+        let backwards = [
+            ("DYN_TCP_KEEPALIVE_INTERVAL", Some("5s")),
+            ("DYN_TCP_KEEPALIVE_TIMEOUT", Some("1s")),
+        ];
+        assert!(temp_env::with_vars(backwards, KeepAlive::from_env).is_err());
+
+        // a peer that doesn't ping gets none, one that pings slowly is waited on for longer
+        let mut peer = crate::pipeline::network::protocol::negotiate_with(None).unwrap();
+        assert_eq!(keepalive.with_peer(&peer), None);
+        peer.peer_ping_interval = Some(Duration::from_secs(3));
+        let applied = keepalive.with_peer(&peer).unwrap();
+        assert_eq!(applied.interval, Duration::from_secs(1));
+        assert_eq!(applied.timeout, Duration::from_secs(9));
+    }
 }
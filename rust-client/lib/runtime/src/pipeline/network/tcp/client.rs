@@ -1,9 +1,11 @@
 // SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::net::SocketAddr;
 use std::sync::Arc;
 
 use futures::{SinkExt, StreamExt};
+use once_cell::sync::Lazy;
 use tokio::io::{AsyncReadExt, ReadHalf, WriteHalf};
 use tokio::{
     io::AsyncWriteExt,
@@ -17,10 +19,66 @@ use crate::engine::AsyncEngineContext;
 use crate::pipeline::network::{
     ConnectionInfo, ResponseStreamPrologue, StreamSender,
     codec::{TwoPartCodec, TwoPartMessage},
+    protocol,
     tcp::StreamType,
 };
+use crate::transports::accounting::{self, Meter, Sending, Transport};
 use crate::{ErrorContext, Result, error}; // Import SinkExt to use the `send` method
 
+static FLUSH_POLICY: Lazy<FlushPolicy> = Lazy::new(|| {
+    FlushPolicy::from_env().unwrap_or_else(|err| {
+        tracing::warn!(%err, "Flushing every response item");
+        FlushPolicy::default()
+    })
+});
+
+/// When the items of a response stream are pushed out to the socket.
+///
+/// Each item is always its own frame, written as soon as it is produced; this only decides how
+/// many written frames may wait in the write buffer. By default none wait, so each item leaves
+/// as it is produced, which is what first-token latency wants. Letting a few wait trades latency
+/// for fewer, larger writes on streams with many small items.
+///
+/// Read from the environment once per process:
+/// - `DYN_TCP_FLUSH_MAX_ITEMS`: `max_items`, 1 by default.
+/// - `DYN_TCP_FLUSH_MAX_DELAY`: `max_delay`, such as `2ms`, 0 by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlushPolicy {
+    /// Flush once this many items are waiting. 1 flushes every item.
+    pub max_items: usize,
+    /// Flush items that have waited this long. With 0, items wait only while more are already
+    /// queued behind them.
+    pub max_delay: Duration,
+}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        FlushPolicy {
+            max_items: 1,
+            max_delay: Duration::ZERO,
+        }
+    }
+}
+
+impl FlushPolicy {
+    pub fn from_env() -> Result<Self> {
+        let mut policy = FlushPolicy::default();
+        if let Ok(max_items) = std::env::var("DYN_TCP_FLUSH_MAX_ITEMS") {
+            policy.max_items = max_items
+                .parse()
+                .with_context(|| format!("Invalid DYN_TCP_FLUSH_MAX_ITEMS '{max_items}'"))?;
+            if policy.max_items == 0 {
+                return Err(error!("DYN_TCP_FLUSH_MAX_ITEMS must be at least 1"));
+            }
+        }
+        if let Ok(max_delay) = std::env::var("DYN_TCP_FLUSH_MAX_DELAY") {
+            policy.max_delay = humantime::parse_duration(&max_delay)
+                .with_context(|| format!("Invalid DYN_TCP_FLUSH_MAX_DELAY '{max_delay}'"))?;
+        }
+        Ok(policy)
+    }
+}
+
 #[allow(dead_code)]
 pub struct TcpClient {
     worker_id: String,
@@ -39,11 +97,20 @@ impl TcpClient {
         TcpClient { worker_id }
     }
 
-    async fn connect(address: &str) -> std::io::Result<TcpStream> {
+    async fn connect(info: &TcpStreamConnectionInfo) -> std::io::Result<TcpStream> {
+        let addresses: Option<Vec<SocketAddr>> = std::iter::once(&info.address)
+            .chain(&info.alternate_addresses)
+            .map(|address| address.parse().ok())
+            .collect();
         // try to connect to the address; retry with linear backoff if AddrNotAvailable
         let backoff = std::time::Duration::from_millis(200);
         loop {
-            match TcpStream::connect(address).await {
+            let connected = match &addresses {
+                Some(addresses) => crate::transports::tcp::connect(addresses).await,
+                // a host name rather than IP addresses
+                None => TcpStream::connect(info.address.as_str()).await,
+            };
+            match connected {
                 Ok(socket) => {
                     socket.set_nodelay(true)?;
                     return Ok(socket);
@@ -83,7 +150,12 @@ impl TcpClient {
             ));
         }
 
-        let stream = TcpClient::connect(&info.address).await?;
+        let stream = TcpClient::connect(&info).await?;
+        let endpoint = info.endpoint.as_deref().unwrap_or(accounting::UNATTRIBUTED);
+        let peer = stream
+            .peer_addr()
+            .map_or_else(|_| info.address.clone(), |addr| addr.to_string());
+        let meter = accounting::connection(Transport::Tcp, endpoint, peer);
         let (read_half, write_half) = tokio::io::split(stream);
 
         let framed_reader = FramedRead::new(read_half, TwoPartCodec::default());
@@ -96,12 +168,22 @@ impl TcpClient {
         // captured by the monitor task
         let (alive_tx, alive_rx) = tokio::sync::oneshot::channel::<()>();
 
-        let reader_task = tokio::spawn(handle_reader(framed_reader, context.clone(), alive_tx));
+        let negotiated = protocol::negotiate_with(info.protocol.as_ref());
+        let keepalive = negotiated.as_ref().ok().and_then(super::keepalive_with);
+
+        let reader_task = tokio::spawn(handle_reader(
+            framed_reader,
+            context.clone(),
+            alive_tx,
+            meter.clone(),
+            keepalive.map(|keepalive| keepalive.timeout),
+        ));
 
         // transport specific handshake message
         let handshake = CallHomeHandshake {
             subject: info.subject,
             stream_type: StreamType::Response,
+            protocol: Some(super::local_protocol()),
         };
 
         let handshake_bytes = match serde_json::to_vec(&handshake) {
@@ -115,17 +197,43 @@ impl TcpClient {
         let msg = TwoPartMessage::from_header(handshake_bytes.into());
 
         // issue the the first tcp handshake message
+        let sending = meter.sending(msg.encoded_len());
         framed_writer
             .send(msg)
             .await
             .map_err(|e| error!("failed to send handshake: {:?}", e))?;
+        sending.finish();
+
+        // a router too old for this worker hears why through the prologue; it would otherwise wait
+        // for the stream forever, as it reads neither the handshake's protocol nor a kill
+        if let Err(incompatible) = negotiated {
+            reader_task.abort();
+            let prologue = ResponseStreamPrologue {
+                error: Some(incompatible.to_string()),
+                incompatible: Some(incompatible.clone()),
+            };
+            let header = serde_json::to_vec(&prologue)?;
+            framed_writer
+                .send(TwoPartMessage::from_header(header.into()))
+                .await
+                .map_err(|e| error!("failed to send prologue: {:?}", e))?;
+            return Err(incompatible.into());
+        }
 
         // set up the channel to send bytes to the transport layer
         let (bytes_tx, bytes_rx) = tokio::sync::mpsc::channel(64);
 
         // forwards the bytes send from this stream to the transport layer; hold the alive_rx half of the oneshot channel
 
-        let writer_task = tokio::spawn(handle_writer(framed_writer, bytes_rx, alive_rx, context));
+        let writer_task = tokio::spawn(handle_writer(
+            framed_writer,
+            bytes_rx,
+            alive_rx,
+            context,
+            meter,
+            *FLUSH_POLICY,
+            keepalive.map(|keepalive| keepalive.interval),
+        ));
 
         tokio::spawn(async move {
             // await both tasks
@@ -175,7 +283,10 @@ impl TcpClient {
 
         // set up the prologue for the stream
         // this might have transport specific metadata in the future
-        let prologue = Some(ResponseStreamPrologue { error: None });
+        let prologue = Some(ResponseStreamPrologue {
+            error: None,
+            incompatible: None,
+        });
 
         // create the stream sender
         let stream_sender = StreamSender {
@@ -191,14 +302,19 @@ async fn handle_reader(
     framed_reader: FramedRead<tokio::io::ReadHalf<tokio::net::TcpStream>, TwoPartCodec>,
     context: Arc<dyn AsyncEngineContext>,
     alive_tx: tokio::sync::oneshot::Sender<()>,
+    meter: Meter,
+    timeout: Option<Duration>,
 ) -> FramedRead<tokio::io::ReadHalf<tokio::net::TcpStream>, TwoPartCodec> {
     let mut framed_reader = framed_reader;
     let mut alive_tx = alive_tx;
+    let mut deadline = Instant::now() + timeout.unwrap_or_default();
     loop {
         tokio::select! {
             msg = framed_reader.next() => {
                 match msg {
                     Some(Ok(two_part_msg)) => {
+                        meter.received(two_part_msg.encoded_len());
+                        deadline = Instant::now() + timeout.unwrap_or_default();
                         match two_part_msg.optional_parts() {
                            (Some(bytes), None) => {
                                 let msg = match serde_json::from_slice::<ControlMessage>(bytes) {
@@ -216,6 +332,7 @@ async fn handle_reader(
                                     ControlMessage::Kill => {
                                         context.kill();
                                     }
+                                    ControlMessage::Ping => {}
                                     ControlMessage::Sentinel => {
                                         // TODO(#171) - address fatal errors
                                         panic!("received a sentinel message; this should never happen");
@@ -241,6 +358,12 @@ async fn handle_reader(
             _ = alive_tx.closed() => {
                 break;
             }
+            // nobody is left to read what the worker generates
+            _ = time::sleep_until(deadline), if timeout.is_some() => {
+                tracing::warn!(?timeout, "no message from the router in time; killing the stream");
+                context.kill();
+                break;
+            }
         }
     }
     framed_reader
@@ -251,7 +374,14 @@ async fn handle_writer(
     mut bytes_rx: tokio::sync::mpsc::Receiver<TwoPartMessage>,
     alive_rx: tokio::sync::oneshot::Receiver<()>,
     context: Arc<dyn AsyncEngineContext>,
+    meter: Meter,
+    policy: FlushPolicy,
+    ping_interval: Option<Duration>,
 ) -> Result<FramedWrite<tokio::io::WriteHalf<tokio::net::TcpStream>, TwoPartCodec>> {
+    // written to the framed writer but not yet flushed, and when the oldest of them must be
+    let mut unflushed: Vec<Sending> = Vec::new();
+    let mut flush_at = Instant::now();
+    let mut ping_at = Instant::now() + ping_interval.unwrap_or_default();
     loop {
         let msg = tokio::select! {
             biased;
@@ -275,22 +405,157 @@ async fn handle_writer(
                     }
                 }
             }
+
+            // the channel comes first, so a zero delay flushes only once nothing is queued
+            _ = time::sleep_until(flush_at), if !unflushed.is_empty() => {
+                if let Err(e) = flush(&mut framed_writer, &mut unflushed).await {
+                    tracing::trace!("failed to flush to network; possible disconnect: {:?}", e);
+                    break;
+                }
+                continue;
+            }
+
+            // sent whether or not items are flowing, as those may be held back by the flush policy
+            _ = time::sleep_until(ping_at), if ping_interval.is_some() => {
+                ping_at = Instant::now() + ping_interval.unwrap_or_default();
+                let message = serde_json::to_vec(&ControlMessage::Ping)?;
+                let msg = TwoPartMessage::from_header(message.into());
+                let sending = meter.sending(msg.encoded_len());
+                // sending flushes the items before it as well
+                if let Err(e) = framed_writer.send(msg).await {
+                    tracing::trace!("failed to send ping; possible disconnect: {:?}", e);
+                    break;
+                }
+                sending.finish();
+                unflushed.drain(..).for_each(Sending::finish);
+                continue;
+            }
         };
 
-        if let Err(e) = framed_writer.send(msg).await {
+        // the bytes are in flight while the socket can't take them, which is what a
+        // saturated link looks like from here
+        let sending = meter.sending(msg.encoded_len());
+        if let Err(e) = framed_writer.feed(msg).await {
             tracing::trace!(
                 "failed to send message to network; possible disconnect: {:?}",
                 e
             );
             break;
         }
+        if unflushed.is_empty() {
+            flush_at = Instant::now() + policy.max_delay;
+        }
+        unflushed.push(sending);
+        // a steady stream never lets the timer win, so the delay is checked here as well
+        let due = unflushed.len() >= policy.max_items
+            || (!policy.max_delay.is_zero() && Instant::now() >= flush_at);
+        if due && let Err(e) = flush(&mut framed_writer, &mut unflushed).await {
+            tracing::trace!("failed to flush to network; possible disconnect: {:?}", e);
+            break;
+        }
     }
 
     // send sentinel message
     let message = serde_json::to_vec(&ControlMessage::Sentinel)?;
     let msg = TwoPartMessage::from_header(message.into());
+    let sending = meter.sending(msg.encoded_len());
     framed_writer.send(msg).await?;
+    sending.finish();
+    // sending the sentinel flushed whatever was left
+    unflushed.drain(..).for_each(Sending::finish);
 
     drop(alive_rx);
     Ok(framed_writer)
 }
+
+async fn flush(
+    framed_writer: &mut FramedWrite<tokio::io::WriteHalf<tokio::net::TcpStream>, TwoPartCodec>,
+    unflushed: &mut Vec<Sending>,
+) -> Result<()> {
+    framed_writer.flush().await?;
+    unflushed.drain(..).for_each(Sending::finish);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::context::Controller;
+    use bytes::Bytes;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_writer_holds_items_until_max_items() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let mut reader = FramedRead::new(server, TwoPartCodec::default());
+        let (_read_half, write_half) = tokio::io::split(stream);
+
+        let (bytes_tx, bytes_rx) = tokio::sync::mpsc::channel(8);
+        let (_alive_tx, alive_rx) = tokio::sync::oneshot::channel();
+        let policy = FlushPolicy {
+            max_items: 2,
+            max_delay: Duration::from_secs(3600),
+        };
+        tokio::spawn(handle_writer(
+            FramedWrite::new(write_half, TwoPartCodec::default()),
+            bytes_rx,
+            alive_rx,
+            Arc::new(Controller::default()),
+            accounting::meter(Transport::Tcp, "test"),
+            policy,
+            None,
+        ));
+
+        let item = |data: &'static str| TwoPartMessage::from_data(Bytes::from(data));
+        bytes_tx.send(item("1")).await.unwrap();
+        let early = time::timeout(Duration::from_millis(100), reader.next()).await;
+        assert!(early.is_err(), "the first item was flushed on its own");
+
+        bytes_tx.send(item("2")).await.unwrap();
+        for expected in ["1", "2"] {
+            let (_, data) = reader.next().await.unwrap().unwrap().into_parts();
+            assert_eq!(data, expected.as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reader_kills_a_silent_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        // the router's end stays open but says nothing, like one that is gone
+        let (mut router, _) = listener.accept().await.unwrap();
+        let (read_half, _write_half) = tokio::io::split(stream);
+
+        let context = Arc::new(Controller::default());
+        let (alive_tx, _alive_rx) = tokio::sync::oneshot::channel();
+        let reader = tokio::spawn(handle_reader(
+            FramedRead::new(read_half, TwoPartCodec::default()),
+            context.clone(),
+            alive_tx,
+            accounting::meter(Transport::Tcp, "test"),
+            Some(Duration::from_millis(300)),
+        ));
+
+        // a ping holds it off
+        time::sleep(Duration::from_millis(200)).await;
+        let ping = serde_json::to_vec(&ControlMessage::Ping).unwrap();
+        FramedWrite::new(&mut router, TwoPartCodec::default())
+            .send(TwoPartMessage::from_header(ping.into()))
+            .await
+            .unwrap();
+        time::sleep(Duration::from_millis(200)).await;
+        assert!(!context.is_killed());
+
+        time::timeout(Duration::from_secs(5), reader)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(context.is_killed());
+    }
+}
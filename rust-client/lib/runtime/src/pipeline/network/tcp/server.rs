@@ -5,7 +5,7 @@ use core::panic;
 use socket2::{Domain, SockAddr, Socket, Type};
 use std::{
     collections::HashMap,
-    net::{IpAddr, SocketAddr, TcpListener},
+    net::{IpAddr, Ipv6Addr, SocketAddr, TcpListener},
     os::fd::{AsFd, FromRawFd},
     sync::Arc,
 };
@@ -53,9 +53,11 @@ use crate::pipeline::{
     network::{
         ResponseService, ResponseStreamPrologue,
         codec::{TwoPartMessage, TwoPartMessageType},
+        protocol::{self, ProtocolInfo},
         tcp::StreamType,
     },
 };
+use crate::transports::accounting::{self, Meter, Transport};
 use crate::{ErrorContext, Result, error};
 
 #[allow(dead_code)]
@@ -68,6 +70,12 @@ pub struct ServerOptions {
 
     #[builder(default)]
     pub interface: Option<String>,
+
+    /// Listen on every IPv4 and IPv6 address and advertise one of each, unless an `interface`
+    /// is given. See [`crate::transports::tcp`].
+    #[builder(default)]
+    #[serde(default)]
+    pub dual_stack: bool,
 }
 
 impl ServerOptions {
@@ -80,7 +88,9 @@ impl ServerOptions {
 /// A Response connection is a connection that is established by a client with the intention of sending
 /// specific data back to the server.
 pub struct TcpStreamServer {
-    local_ip: String,
+    local_ip: IpAddr,
+    /// Addresses of the other family, advertised alongside `local_ip` when dual-stack
+    alternate_ips: Vec<IpAddr>,
     local_port: u16,
     state: Arc<Mutex<State>>,
 }
@@ -94,12 +104,13 @@ pub struct TcpStreamServer {
 #[allow(dead_code)]
 struct RequestedSendConnection {
     context: Arc<dyn AsyncEngineContext>,
-    connection: oneshot::Sender<Result<StreamSender, String>>,
+    connection: oneshot::Sender<Result<StreamSender, PipelineError>>,
 }
 
 struct RequestedRecvConnection {
     context: Arc<dyn AsyncEngineContext>,
-    connection: oneshot::Sender<Result<StreamReceiver, String>>,
+    connection: oneshot::Sender<Result<StreamReceiver, PipelineError>>,
+    endpoint: Option<String>,
 }
 
 // /// When registering a new TcpStream on the server, the registration method will return a [`Connections`] object.
@@ -138,18 +149,33 @@ impl TcpStreamServer {
         options: ServerOptions,
         resolver: R,
     ) -> Result<Arc<Self>, PipelineError> {
+        let mut dual_stack = options.dual_stack && options.interface.is_none();
+        let mut alternate_ips = Vec::new();
         let local_ip = match options.interface {
             Some(interface) => {
                 let interfaces: HashMap<String, std::net::IpAddr> =
                     list_afinet_netifas()?.into_iter().collect();
 
-                interfaces
+                *interfaces
                     .get(&interface)
                     .ok_or(PipelineError::Generic(format!(
                         "Interface not found: {}",
                         interface
                     )))?
-                    .to_string()
+            }
+            None if dual_stack => {
+                let mut ips: Vec<IpAddr> = [resolver.local_ip(), resolver.local_ipv6()]
+                    .into_iter()
+                    .filter_map(|ip| ip.ok())
+                    .collect();
+                if ips.is_empty() {
+                    ips = vec![
+                        IpAddr::from([127, 0, 0, 1]),
+                        IpAddr::from(Ipv6Addr::LOCALHOST),
+                    ];
+                }
+                alternate_ips = ips.split_off(1);
+                ips[0]
             }
             None => {
                 let resolved_ip = resolver.local_ip().or_else(|err| match err {
@@ -162,40 +188,63 @@ impl TcpStreamServer {
                     Err(Error::LocalIpAddressNotFound) => IpAddr::from([127, 0, 0, 1]),
                     Err(err) => return Err(err.into()),
                 }
-                .to_string()
             }
         };
 
         let state = Arc::new(Mutex::new(State::default()));
 
-        let local_port = Self::start(local_ip.clone(), options.port, state.clone())
-            .await
-            .map_err(|e| {
-                PipelineError::Generic(format!("Failed to start TcpStreamServer: {}", e))
-            })?;
-
-        tracing::debug!("tcp transport service on {local_ip}:{local_port}");
+        let mut started = Self::start(local_ip, options.port, dual_stack, state.clone()).await;
+        if dual_stack && started.is_err() {
+            // No IPv6 on this host
+            tracing::warn!("dual-stack tcp listener unavailable, listening on {local_ip} only");
+            dual_stack = false;
+            alternate_ips.clear();
+            started = Self::start(local_ip, options.port, dual_stack, state.clone()).await;
+        }
+        let local_port = started.map_err(|e| {
+            PipelineError::Generic(format!("Failed to start TcpStreamServer: {}", e))
+        })?;
+
+        tracing::debug!(
+            dual_stack,
+            ?alternate_ips,
+            "tcp transport service on {}",
+            SocketAddr::new(local_ip, local_port)
+        );
 
         Ok(Arc::new(Self {
             local_ip,
+            alternate_ips,
             local_port,
             state,
         }))
     }
 
     #[allow(clippy::await_holding_lock)]
-    async fn start(local_ip: String, local_port: u16, state: Arc<Mutex<State>>) -> Result<u16> {
-        let addr = format!("{}:{}", local_ip, local_port);
+    async fn start(
+        local_ip: IpAddr,
+        local_port: u16,
+        dual_stack: bool,
+        state: Arc<Mutex<State>>,
+    ) -> Result<u16> {
+        let addr = SocketAddr::new(local_ip, local_port);
         let state_clone = state.clone();
         let mut guard = state.lock().await;
         if guard.handle.is_some() {
             panic!("TcpStreamServer already started");
         }
         let (ready_tx, ready_rx) = tokio::sync::oneshot::channel::<Result<u16>>();
-        let handle = tokio::spawn(tcp_listener(addr, state_clone, ready_tx));
+        let handle = tokio::spawn(tcp_listener(addr, dual_stack, state_clone, ready_tx));
         guard.handle = Some(handle);
         drop(guard);
-        let local_port = ready_rx.await??;
+        let local_port = match ready_rx.await? {
+            Ok(port) => port,
+            Err(err) => {
+                // Free the slot for another attempt
+                state.lock().await.handle = None;
+                return Err(err);
+            }
+        };
         Ok(local_port)
     }
 }
@@ -226,7 +275,12 @@ impl ResponseService for TcpStreamServer {
     async fn register(&self, options: StreamOptions) -> PendingConnections {
         // oneshot channels to pass back the sender and receiver objects
 
-        let address = format!("{}:{}", self.local_ip, self.local_port);
+        let address = SocketAddr::new(self.local_ip, self.local_port).to_string();
+        let alternate_addresses: Vec<String> = self
+            .alternate_ips
+            .iter()
+            .map(|ip| SocketAddr::new(*ip, self.local_port).to_string())
+            .collect();
         tracing::debug!("Registering new TcpStream on {}", address);
 
         let send_stream = if options.enable_request_stream {
@@ -247,9 +301,12 @@ impl ResponseService for TcpStreamServer {
             let registered_stream = RegisteredStream {
                 connection_info: TcpStreamConnectionInfo {
                     address: address.clone(),
+                    alternate_addresses: alternate_addresses.clone(),
+                    endpoint: options.endpoint.clone(),
                     subject: sender_subject.clone(),
                     context: options.context.id().to_string(),
                     stream_type: StreamType::Request,
+                    protocol: Some(super::local_protocol()),
                 }
                 .into(),
                 stream_provider: pending_sender_rx,
@@ -267,6 +324,7 @@ impl ResponseService for TcpStreamServer {
             let connection_info = RequestedRecvConnection {
                 context: options.context.clone(),
                 connection: pending_recver_tx,
+                endpoint: options.endpoint.clone(),
             };
 
             let mut state = self.state.lock().await;
@@ -277,9 +335,12 @@ impl ResponseService for TcpStreamServer {
             let registered_stream = RegisteredStream {
                 connection_info: TcpStreamConnectionInfo {
                     address: address.clone(),
+                    alternate_addresses: alternate_addresses.clone(),
+                    endpoint: options.endpoint.clone(),
                     subject: receiver_subject.clone(),
                     context: options.context.id().to_string(),
                     stream_type: StreamType::Response,
+                    protocol: Some(super::local_protocol()),
                 }
                 .into(),
                 stream_provider: pending_recver_rx,
@@ -304,13 +365,18 @@ impl ResponseService for TcpStreamServer {
 // the sender, then we spawn a task to forward all bytes from the tcp stream
 // to the sender
 async fn tcp_listener(
-    addr: String,
+    addr: SocketAddr,
+    dual_stack: bool,
     state: Arc<Mutex<State>>,
     read_tx: tokio::sync::oneshot::Sender<Result<u16>>,
 ) -> Result<()> {
-    let listener = tokio::net::TcpListener::bind(&addr)
-        .await
-        .map_err(|e| anyhow::anyhow!("Failed to start TcpListender on {}: {}", addr, e));
+    let listener = if dual_stack {
+        crate::transports::tcp::bind_dual_stack(addr.port())
+    } else {
+        tokio::net::TcpListener::bind(addr).await
+    };
+    let listener =
+        listener.map_err(|e| anyhow::anyhow!("Failed to start TcpListender on {}: {}", addr, e));
 
     let listener = match listener {
         Ok(listener) => {
@@ -381,6 +447,10 @@ async fn tcp_listener(
     /// This method is responsible for the internal tcp stream handshake
     /// The handshake will specialize the stream as a request/sender or response/receiver stream
     async fn process_stream(stream: tokio::net::TcpStream, state: Arc<Mutex<State>>) -> Result<()> {
+        let peer = stream
+            .peer_addr()
+            .map_or_else(|_| "unknown".to_string(), |addr| addr.to_string());
+
         // split the socket in to a reader and writer
         let (read_half, write_half) = tokio::io::split(stream);
 
@@ -394,6 +464,7 @@ async fn tcp_listener(
             .next()
             .await
             .ok_or(error!("Connection closed without a ControlMessage"))??;
+        let handshake_len = first_message.encoded_len();
 
         // we await on the raw bytes which should come in as a header only message
         // todo - improve error handling - check for no data
@@ -412,8 +483,16 @@ async fn tcp_listener(
         match handshake.stream_type {
             StreamType::Request => process_request_stream().await,
             StreamType::Response => {
-                process_response_stream(handshake.subject, state, framed_reader, framed_writer)
-                    .await
+                process_response_stream(
+                    handshake.subject,
+                    handshake.protocol,
+                    peer,
+                    handshake_len,
+                    state,
+                    framed_reader,
+                    framed_writer,
+                )
+                .await
             }
         }
     }
@@ -424,6 +503,9 @@ async fn tcp_listener(
 
     async fn process_response_stream(
         subject: String,
+        peer_protocol: Option<ProtocolInfo>,
+        peer: String,
+        handshake_len: usize,
         state: Arc<Mutex<State>>,
         mut reader: FramedRead<tokio::io::ReadHalf<tokio::net::TcpStream>, TwoPartCodec>,
         writer: FramedWrite<tokio::io::WriteHalf<tokio::net::TcpStream>, TwoPartCodec>,
@@ -438,14 +520,31 @@ async fn tcp_listener(
         let RequestedRecvConnection {
             context,
             connection,
+            endpoint,
         } = response_stream;
 
+        let endpoint = endpoint.as_deref().unwrap_or(accounting::UNATTRIBUTED);
+        let meter = accounting::connection(Transport::Tcp, endpoint, peer);
+        meter.received(handshake_len);
+
+        // fail the requester's stream now, rather than on the first message it can't decode
+        let negotiated = match protocol::negotiate_with(peer_protocol.as_ref()) {
+            Ok(negotiated) => negotiated,
+            Err(incompatible) => {
+                tracing::warn!(%incompatible, endpoint, "Refusing a response stream");
+                let _ = connection.send(Err(incompatible.clone().into()));
+                return Err(incompatible.into());
+            }
+        };
+        let keepalive = super::keepalive_with(&negotiated);
+
         // the [`Prologue`]
         // there must be a second control message it indicate the other segment's generate method was successful
         let prologue = reader
             .next()
             .await
             .ok_or(error!("Connection closed without a ControlMessge"))??;
+        meter.received(prologue.encoded_len());
 
         // deserialize prologue
         let prologue = match prologue.into_message_type() {
@@ -465,9 +564,10 @@ async fn tcp_listener(
         // note: this second control message might be delayed, but the expensive part of setting up the connection
         // is both complete and ready for data flow; awaiting here is not a performance hit or problem and it allows
         // us to trace the initial setup time vs the time to prologue
-        if let Some(error) = &prologue.error {
-            let _ = connection.send(Err(error.clone()));
-            return Err(error!("Received error prologue: {}", error));
+        if let Some(error) = prologue.into_error() {
+            let message = error.to_string();
+            let _ = connection.send(Err(error));
+            return Err(error!("Received error prologue: {}", message));
         }
 
         // we need to know the buffer size from the registration options; add this to the RequestRecvConnection object
@@ -489,7 +589,12 @@ async fn tcp_listener(
         // sender task
         // issues control messages to the sender and when finished shuts down the socket
         // this should be the last task to finish and must
-        let send_task = tokio::spawn(network_send_handler(writer, control_rx));
+        let send_task = tokio::spawn(network_send_handler(
+            writer,
+            control_rx,
+            meter.clone(),
+            keepalive.map(|keepalive| keepalive.interval),
+        ));
 
         // forward task
         let recv_task = tokio::spawn(network_receive_handler(
@@ -497,6 +602,8 @@ async fn tcp_listener(
             response_tx,
             control_tx,
             context.clone(),
+            meter,
+            keepalive.map(|keepalive| keepalive.timeout),
         ));
 
         // check the results of each of the tasks
@@ -513,9 +620,12 @@ async fn tcp_listener(
         response_tx: mpsc::Sender<Bytes>,
         control_tx: mpsc::Sender<ControlMessage>,
         context: Arc<dyn AsyncEngineContext>,
+        meter: Meter,
+        timeout: Option<time::Duration>,
     ) {
         // loop over reading the tcp stream and checking if the writer is closed
         let mut can_stop = true;
+        let mut deadline = time::Instant::now() + timeout.unwrap_or_default();
         loop {
             tokio::select! {
                 biased;
@@ -538,9 +648,17 @@ async fn tcp_listener(
                     control_tx.send(ControlMessage::Stop).await.expect("the control channel should not be closed");
                 }
 
+                // a stream that ends early has the router route around the worker
+                _ = time::sleep_until(deadline), if timeout.is_some() => {
+                    tracing::warn!(?timeout, "no message from the worker; giving up on the stream");
+                    let _ = control_tx.try_send(ControlMessage::Kill);
+                    break;
+                }
+
                 msg = framed_reader.next() => {
                     match msg {
                         Some(Ok(msg)) => {
+                            meter.received(msg.encoded_len());
                             let (header, data) = msg.into_parts();
 
                             // received a control message
@@ -565,6 +683,10 @@ async fn tcp_listener(
                                     control_tx.send(ControlMessage::Kill).await.expect("the control channel should not be closed");
                                     break;
                                 };
+
+                            // counted from after the data is forwarded, as a full response
+                            // channel keeps this from reading the socket
+                            deadline = time::Instant::now() + timeout.unwrap_or_default();
                         }
                         Some(Err(_)) => {
                             // TODO(#171) - address fatal errors
@@ -589,11 +711,24 @@ async fn tcp_listener(
     async fn network_send_handler(
         socket_tx: FramedWrite<tokio::io::WriteHalf<tokio::net::TcpStream>, TwoPartCodec>,
         control_rx: mpsc::Receiver<ControlMessage>,
+        meter: Meter,
+        ping_interval: Option<time::Duration>,
     ) {
         let mut socket_tx = socket_tx;
         let mut control_rx = control_rx;
+        let mut ping_at = time::Instant::now() + ping_interval.unwrap_or_default();
 
-        while let Some(control_msg) = control_rx.recv().await {
+        loop {
+            let control_msg = tokio::select! {
+                control_msg = control_rx.recv() => match control_msg {
+                    Some(control_msg) => control_msg,
+                    None => break,
+                },
+                _ = time::sleep_until(ping_at), if ping_interval.is_some() => {
+                    ping_at = time::Instant::now() + ping_interval.unwrap_or_default();
+                    ControlMessage::Ping
+                }
+            };
             assert_ne!(
                 control_msg,
                 ControlMessage::Sentinel,
@@ -602,8 +737,12 @@ async fn tcp_listener(
             let bytes =
                 serde_json::to_vec(&control_msg).expect("failed to serialize control message");
             let message = TwoPartMessage::from_header(bytes.into());
+            let sending = meter.sending(message.encoded_len());
             match socket_tx.send(message).await {
-                Ok(_) => tracing::debug!("issued control message {control_msg:?} to sender"),
+                Ok(_) => {
+                    sending.finish();
+                    tracing::trace!("issued control message {control_msg:?} to sender");
+                }
                 Err(_) => {
                     tracing::debug!("failed to send control message {control_msg:?} to sender")
                 }
@@ -633,6 +772,7 @@ fn process_control_message(message: Bytes) -> Result<ControlAction> {
             tracing::trace!("sentinel received; shutting down");
             Ok(ControlAction::Shutdown)
         }
+        ControlMessage::Ping => Ok(ControlAction::Continue),
         ControlMessage::Kill | ControlMessage::Stop => {
             // TODO(#171) - address fatal errors
             anyhow::bail!(
@@ -765,4 +905,39 @@ mod tests {
         // The server should work with the fallback IP
         assert!(socket_addr.port() > 0, "Server should have a valid port");
     }
+
+    #[tokio::test]
+    async fn test_tcp_stream_server_dual_stack_advertises_both_families() {
+        let options = ServerOptions::builder().dual_stack(true).build().unwrap();
+        let server = TcpStreamServer::new_with_resolver(options, FailingIpResolver)
+            .await
+            .unwrap();
+
+        let context = Context::new(());
+        let stream_options = StreamOptions::builder()
+            .context(context.context())
+            .enable_request_stream(false)
+            .enable_response_stream(true)
+            .build()
+            .unwrap();
+        let pending_connection = server.register(stream_options).await;
+        let connection_info = pending_connection
+            .recv_stream
+            .as_ref()
+            .unwrap()
+            .connection_info
+            .clone();
+        let tcp_info: TcpStreamConnectionInfo = connection_info.try_into().unwrap();
+        let address: SocketAddr = tcp_info.address.parse().unwrap();
+        assert!(address.ip().is_loopback());
+
+        // Hosts without IPv6 fall back to listening on IPv4 only
+        if tcp_info.alternate_addresses.is_empty() {
+            return;
+        }
+        let alternate: SocketAddr = tcp_info.alternate_addresses[0].parse().unwrap();
+        assert!(alternate.is_ipv6() && alternate.ip().is_loopback());
+        assert_eq!(alternate.port(), address.port());
+        tokio::net::TcpStream::connect(alternate).await.unwrap();
+    }
 }
@@ -41,6 +41,7 @@ use async_trait::async_trait;
 use tokio::sync::oneshot;
 
 use super::{Data, Error, PipelineError, PipelineIO};
+use crate::logging::slow_ops::{SlowOpKind, watch_slow};
 
 mod sinks;
 mod sources;
@@ -215,7 +216,11 @@ where
     UpOut: PipelineIO,
 {
     async fn generate(&self, req: UpIn) -> Result<UpOut, Error> {
-        self.operator.generate(req, self.downstream.clone()).await
+        let name = std::any::type_name::<Self>();
+        let generate = self.operator.generate(req, self.downstream.clone());
+        let generate = crate::compute::blocking::watch_polls(name, generate);
+        // The request is the user's, so nothing of it is logged
+        watch_slow(SlowOpKind::Operator, name, String::new, generate).await
     }
 }
 
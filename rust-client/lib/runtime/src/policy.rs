@@ -0,0 +1,406 @@
+// SPDX-FileCopyrightText: Copyright (c) 2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Which namespaces and components an identity may register under or invoke.
+//!
+//! In a cluster teams share, nothing else keeps one team's workers from registering under
+//! another team's namespace, or its routers from calling endpoints that aren't theirs. With
+//! `DYN_POLICY` set, each endpoint registration and each request sent through a
+//! [`Client`](crate::component::Client) is checked against the rules in the store's
+//! [`POLICY_BUCKET`], one [`PolicyRule`] per key, followed as they change:
+//!
+//! ```text
+//! etcdctl put v1/policy/team-a '{"identities": ["team-a/*"], "namespaces": ["team-a"]}'
+//! ```
+//!
+//! The identity is `DYN_IDENTITY`, empty if it isn't set. An action is allowed if a rule allows
+//! it, so with no rules at all nothing is. `DYN_POLICY` is one of:
+//!
+//! - `audit`: denials are reported and nothing more, to try rules out before enforcing them
+//! - `enforce`: denied registrations and requests fail with [`PolicyDenied`]
+//!
+//! Each denial is logged and written to [`POLICY_AUDIT_BUCKET`] as a [`Denial`], at most once
+//! per [`AUDIT_INTERVAL`] for the same action and endpoint.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use arc_swap::ArcSwap;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+
+use crate::storage::key_value_store::{Key, KeyValueStoreManager, WatchEvent};
+use crate::{Result, error};
+
+/// The rules, under any key
+pub const POLICY_BUCKET: &str = "v1/policy";
+
+/// Where denials are recorded, for [`AUDIT_TTL`]
+pub const POLICY_AUDIT_BUCKET: &str = "v1/policy_audit";
+
+pub const AUDIT_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// The least time between two reports of the same denial
+pub const AUDIT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long to wait for the rules at startup before checking against none
+const INITIAL_SYNC_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Serve an endpoint, registering it for discovery
+    Register,
+    /// Send an endpoint requests
+    Invoke,
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::Register => write!(f, "register"),
+            Action::Invoke => write!(f, "invoke"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PolicyMode {
+    #[default]
+    Off,
+    Audit,
+    Enforce,
+}
+
+impl PolicyMode {
+    /// From `DYN_POLICY`, off if it isn't set
+    pub fn from_env() -> Result<Self> {
+        let Ok(mode) = std::env::var("DYN_POLICY") else {
+            return Ok(PolicyMode::Off);
+        };
+        match mode.trim().to_lowercase().as_str() {
+            "" | "off" => Ok(PolicyMode::Off),
+            "audit" => Ok(PolicyMode::Audit),
+            "enforce" => Ok(PolicyMode::Enforce),
+            other => Err(error!(
+                "DYN_POLICY must be off, audit or enforce, not '{other}'"
+            )),
+        }
+    }
+}
+
+/// The identity this process acts as, from `DYN_IDENTITY`
+pub fn identity() -> String {
+    std::env::var("DYN_IDENTITY").unwrap_or_default()
+}
+
+/// Allows the identities it names the actions it lists on the namespaces and components it
+/// names. Names are patterns: `*` for any, `team-a*` for a prefix, otherwise exact.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PolicyRule {
+    pub identities: Vec<String>,
+    pub namespaces: Vec<String>,
+    #[serde(default = "any_name")]
+    pub components: Vec<String>,
+    #[serde(default = "all_actions")]
+    pub actions: Vec<Action>,
+}
+
+fn any_name() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+fn all_actions() -> Vec<Action> {
+    vec![Action::Register, Action::Invoke]
+}
+
+impl PolicyRule {
+    pub fn allows(&self, identity: &str, action: Action, namespace: &str, component: &str) -> bool {
+        self.actions.contains(&action)
+            && matches(&self.identities, identity)
+            && matches(&self.namespaces, namespace)
+            && matches(&self.components, component)
+    }
+}
+
+fn matches(patterns: &[String], name: &str) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => pattern == name,
+        })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("'{identity}' may not {action} {namespace}/{component}, see DYN_POLICY")]
+pub struct PolicyDenied {
+    pub identity: String,
+    pub action: Action,
+    pub namespace: String,
+    pub component: String,
+}
+
+/// The record of a denial in [`POLICY_AUDIT_BUCKET`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Denial {
+    pub identity: String,
+    pub action: Action,
+    pub namespace: String,
+    pub component: String,
+    pub endpoint: String,
+    /// False in audit mode, when the action went ahead
+    pub enforced: bool,
+    /// Unix time in milliseconds
+    pub denied_at: u64,
+}
+
+/// The rules of a [`DistributedRuntime`](crate::DistributedRuntime), kept current from the store
+#[derive(Clone)]
+pub struct Policy(Arc<PolicyInner>);
+
+struct PolicyInner {
+    identity: String,
+    mode: PolicyMode,
+    store: KeyValueStoreManager,
+    /// By key
+    rules: ArcSwap<HashMap<String, PolicyRule>>,
+    /// When each denial, by action and endpoint path, was last reported
+    reported: Mutex<HashMap<(Action, String), Instant>>,
+}
+
+impl Policy {
+    /// Checks as `identity`, against the rules in `store` until `cancel_token` is cancelled.
+    /// Returns once the rules there have been read.
+    pub async fn watch(
+        store: &KeyValueStoreManager,
+        identity: String,
+        mode: PolicyMode,
+        cancel_token: CancellationToken,
+    ) -> Self {
+        let policy = Policy(Arc::new(PolicyInner {
+            identity,
+            mode,
+            store: store.clone(),
+            rules: ArcSwap::from_pointee(HashMap::new()),
+            reported: Mutex::new(HashMap::new()),
+        }));
+        let mut events = Arc::new(store.clone()).watch(POLICY_BUCKET, None, cancel_token);
+        let (synced_tx, synced_rx) = tokio::sync::oneshot::channel();
+        let inner = policy.0.clone();
+        tokio::spawn(async move {
+            let mut synced_tx = Some(synced_tx);
+            let mut rules = HashMap::new();
+            while let Some(event) = events.recv().await {
+                match event {
+                    WatchEvent::Put(kv) => match serde_json::from_slice::<PolicyRule>(kv.value()) {
+                        Ok(rule) => {
+                            rules.insert(kv.key().to_string(), rule);
+                        }
+                        Err(err) => {
+                            // A rule that doesn't parse allows nothing
+                            tracing::warn!(%err, key = kv.key(), "Ignoring policy rule");
+                            rules.remove(kv.key());
+                        }
+                    },
+                    WatchEvent::Delete(kv) => {
+                        rules.remove(kv.key());
+                    }
+                    WatchEvent::InitialSyncComplete => {
+                        if let Some(synced_tx) = synced_tx.take() {
+                            let _ = synced_tx.send(());
+                        }
+                    }
+                    _ => continue,
+                }
+                inner.rules.store(Arc::new(rules.clone()));
+            }
+        });
+        if tokio::time::timeout(INITIAL_SYNC_TIMEOUT, synced_rx)
+            .await
+            .is_err()
+        {
+            tracing::warn!("Policy rules not read yet, checking against those read so far");
+        }
+        policy
+    }
+
+    pub fn identity(&self) -> &str {
+        &self.0.identity
+    }
+
+    pub fn mode(&self) -> PolicyMode {
+        self.0.mode
+    }
+
+    pub fn rules(&self) -> Vec<PolicyRule> {
+        self.0.rules.load().values().cloned().collect()
+    }
+
+    /// Whether this process may take `action` on `endpoint` of `component` in `namespace`. A
+    /// denial is reported either way, and only fails in enforce mode.
+    pub fn check(
+        &self,
+        action: Action,
+        namespace: &str,
+        component: &str,
+        endpoint: &str,
+    ) -> std::result::Result<(), PolicyDenied> {
+        let identity = &self.0.identity;
+        let rules = self.0.rules.load();
+        if rules
+            .values()
+            .any(|rule| rule.allows(identity, action, namespace, component))
+        {
+            return Ok(());
+        }
+        let denied = PolicyDenied {
+            identity: identity.clone(),
+            action,
+            namespace: namespace.to_string(),
+            component: component.to_string(),
+        };
+        self.report(&denied, endpoint);
+        match self.0.mode {
+            PolicyMode::Enforce => Err(denied),
+            PolicyMode::Off | PolicyMode::Audit => Ok(()),
+        }
+    }
+
+    fn report(&self, denied: &PolicyDenied, endpoint: &str) {
+        let path = format!("{}/{}/{endpoint}", denied.namespace, denied.component);
+        let now = Instant::now();
+        {
+            let mut reported = self.0.reported.lock();
+            let key = (denied.action, path.clone());
+            if let Some(last) = reported.get(&key)
+                && now.duration_since(*last) < AUDIT_INTERVAL
+            {
+                return;
+            }
+            reported.insert(key, now);
+        }
+        let enforced = self.0.mode == PolicyMode::Enforce;
+        tracing::warn!(
+            identity = %denied.identity,
+            action = %denied.action,
+            endpoint = %path,
+            enforced,
+            "Access policy denied"
+        );
+        let denial = Denial {
+            identity: denied.identity.clone(),
+            action: denied.action,
+            namespace: denied.namespace.clone(),
+            component: denied.component.clone(),
+            endpoint: endpoint.to_string(),
+            enforced,
+            denied_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+        };
+        let store = self.0.store.clone();
+        tokio::spawn(async move {
+            if let Err(err) = record(&store, &denial).await {
+                tracing::warn!(%err, "Unable to record a policy denial");
+            }
+        });
+    }
+}
+
+async fn record(store: &KeyValueStoreManager, denial: &Denial) -> Result<()> {
+    let bucket = store
+        .get_or_create_bucket(POLICY_AUDIT_BUCKET, Some(AUDIT_TTL))
+        .await?;
+    let id = uuid::Uuid::new_v4().simple();
+    let key = Key::from_raw(format!("{:013}-{id}", denial.denied_at));
+    bucket
+        .insert(&key, &serde_json::to_string(denial)?, 0)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rule() {
+        let rule: PolicyRule =
+            serde_json::from_str(r#"{"identities": ["team-a/*"], "namespaces": ["team-a"]}"#)
+                .unwrap();
+        assert!(rule.allows("team-a/router", Action::Invoke, "team-a", "backend"));
+        assert!(rule.allows("team-a/worker", Action::Register, "team-a", "prefill"));
+        assert!(!rule.allows("team-b/router", Action::Invoke, "team-a", "backend"));
+        assert!(!rule.allows("team-a/router", Action::Invoke, "team-ab", "backend"));
+
+        let invoke_only = PolicyRule {
+            identities: vec!["*".to_string()],
+            namespaces: vec!["shared".to_string()],
+            components: vec!["tokenizer".to_string()],
+            actions: vec![Action::Invoke],
+        };
+        assert!(invoke_only.allows("", Action::Invoke, "shared", "tokenizer"));
+        assert!(!invoke_only.allows("", Action::Register, "shared", "tokenizer"));
+        assert!(!invoke_only.allows("", Action::Invoke, "shared", "backend"));
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        let store = KeyValueStoreManager::memory();
+        let cancel_token = CancellationToken::new();
+        let identity = "team-a/router".to_string();
+        let policy = Policy::watch(&store, identity, PolicyMode::Enforce, cancel_token.clone());
+        let policy = policy.await;
+        let err = policy
+            .check(Action::Invoke, "team-a", "backend", "generate")
+            .unwrap_err();
+        assert_eq!(err.action, Action::Invoke);
+
+        let rule = PolicyRule {
+            identities: vec!["team-a/*".to_string()],
+            namespaces: vec!["team-a".to_string()],
+            components: any_name(),
+            actions: all_actions(),
+        };
+        let key = Key::from_raw("team-a".to_string());
+        store
+            .update(POLICY_BUCKET, &key, |_| Ok(rule.clone()))
+            .await
+            .unwrap();
+        while policy.rules().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        policy
+            .check(Action::Invoke, "team-a", "backend", "generate")
+            .unwrap();
+        assert!(
+            policy
+                .check(Action::Invoke, "team-b", "backend", "generate")
+                .is_err()
+        );
+
+        // The first denial was recorded, and reported once
+        let audit = loop {
+            if let Some(bucket) = store.get_bucket(POLICY_AUDIT_BUCKET).await.unwrap() {
+                let entries = bucket.entries().await.unwrap();
+                if entries.len() == 2 {
+                    break entries;
+                }
+            }
+            tokio::task::yield_now().await;
+        };
+        let mut denials: Vec<Denial> = audit
+            .values()
+            .map(|value| serde_json::from_slice(value).unwrap())
+            .collect();
+        denials.sort_by(|a, b| a.namespace.cmp(&b.namespace));
+        assert_eq!(denials[0].namespace, "team-a");
+        assert!(denials[0].enforced);
+        assert_eq!(denials[1].endpoint, "generate");
+        cancel_token.cancel();
+    }
+}
@@ -0,0 +1,393 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Replay captured requests against a live endpoint.
+//!
+//! A capture file is JSON lines, one [`CapturedRequest`] per line, holding the original request
+//! and the response items that were recorded for it. The [`Replayer`] re-issues each request
+//! through a [`PushRouter`] at a fixed rate and diffs the new response stream against the
+//! recorded one. This is how we validate a fix after an incident: capture the requests that
+//! misbehaved, deploy, replay.
+
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::time::Duration;
+
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Result,
+    engine::AsyncEngine,
+    pipeline::{Context, PushRouter},
+    protocols::annotated::Annotated,
+};
+
+/// The router type used for replay. Requests and responses are opaque JSON so a capture file
+/// can be replayed against any endpoint.
+pub type ReplayRouter = PushRouter<serde_json::Value, Annotated<serde_json::Value>>;
+
+/// One captured request envelope and the responses originally produced for it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CapturedRequest {
+    /// Request ID at capture time. Used to correlate results, not sent on the wire.
+    pub id: String,
+
+    /// The request payload
+    pub request: serde_json::Value,
+
+    /// The response stream items, in order, as serialized `Annotated` values.
+    /// Empty if the responses were not captured; the replay then only checks for errors.
+    #[serde(default)]
+    pub responses: Vec<serde_json::Value>,
+}
+
+/// Read a JSON lines capture file. Blank lines are skipped.
+pub fn read_captures(path: impl AsRef<Path>) -> Result<Vec<CapturedRequest>> {
+    let path = path.as_ref();
+    let file = std::fs::File::open(path)
+        .map_err(|err| anyhow::anyhow!("Unable to open capture file {}: {err}", path.display()))?;
+    parse_captures(BufReader::new(file))
+}
+
+fn parse_captures(reader: impl BufRead) -> Result<Vec<CapturedRequest>> {
+    let mut out = Vec::new();
+    for (idx, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let captured: CapturedRequest = serde_json::from_str(&line)
+            .map_err(|err| anyhow::anyhow!("Invalid capture on line {}: {err}", idx + 1))?;
+        out.push(captured);
+    }
+    Ok(out)
+}
+
+/// A single difference between the recorded and replayed response streams.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ResponseDiff {
+    /// Position in the response stream
+    pub index: usize,
+    /// What was recorded. None if the replay produced more items than the capture.
+    pub expected: Option<serde_json::Value>,
+    /// What the replay produced. None if the replay produced fewer items than the capture.
+    pub actual: Option<serde_json::Value>,
+}
+
+/// Compare two response streams item by item.
+pub fn diff_responses(
+    expected: &[serde_json::Value],
+    actual: &[serde_json::Value],
+) -> Vec<ResponseDiff> {
+    let len = expected.len().max(actual.len());
+    (0..len)
+        .filter_map(|index| {
+            let e = expected.get(index);
+            let a = actual.get(index);
+            if e == a {
+                return None;
+            }
+            Some(ResponseDiff {
+                index,
+                expected: e.cloned(),
+                actual: a.cloned(),
+            })
+        })
+        .collect()
+}
+
+/// Outcome of replaying one [`CapturedRequest`]
+#[derive(Debug, Clone, Serialize)]
+pub struct ReplayResult {
+    /// The captured request ID
+    pub id: String,
+
+    /// Time from issuing the request to the end of the response stream
+    pub elapsed: Duration,
+
+    /// Response items that differ from the capture. Always empty if nothing was recorded.
+    pub diffs: Vec<ResponseDiff>,
+
+    /// Set if the request could not be issued, or the stream contained an error item
+    pub error: Option<String>,
+}
+
+impl ReplayResult {
+    /// The replay produced the recorded responses without error
+    pub fn is_match(&self) -> bool {
+        self.diffs.is_empty() && self.error.is_none()
+    }
+}
+
+/// Replay configuration
+#[derive(Debug, Clone)]
+pub struct ReplayOptions {
+    /// Requests issued per second. Requests are issued on a fixed schedule regardless of how
+    /// long responses take, so a slow endpoint does not lower the offered load.
+    pub rate: f64,
+
+    /// Send every request to this instance instead of using the router's mode
+    pub instance_id: Option<u64>,
+}
+
+impl Default for ReplayOptions {
+    fn default() -> Self {
+        ReplayOptions {
+            rate: 1.0,
+            instance_id: None,
+        }
+    }
+}
+
+/// Re-issues captured requests through a [`PushRouter`]
+#[derive(Clone)]
+pub struct Replayer {
+    router: ReplayRouter,
+    options: ReplayOptions,
+}
+
+impl Replayer {
+    pub fn new(router: ReplayRouter, options: ReplayOptions) -> Result<Self> {
+        if !(options.rate > 0.0 && options.rate.is_finite()) {
+            anyhow::bail!(
+                "Replay rate must be a positive number, got {}",
+                options.rate
+            );
+        }
+        Ok(Replayer { router, options })
+    }
+
+    /// Issue one captured request and diff the responses.
+    pub async fn replay_one(&self, captured: &CapturedRequest) -> ReplayResult {
+        let start = std::time::Instant::now();
+        let request = Context::new(captured.request.clone());
+        let stream = match self.options.instance_id {
+            Some(instance_id) => self.router.direct(request, instance_id).await,
+            None => self.router.generate(request).await,
+        };
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                return ReplayResult {
+                    id: captured.id.clone(),
+                    elapsed: start.elapsed(),
+                    diffs: vec![],
+                    error: Some(err.to_string()),
+                };
+            }
+        };
+
+        let mut actual = Vec::with_capacity(captured.responses.len());
+        let mut error = None;
+        while let Some(item) = stream.next().await {
+            if error.is_none() {
+                error = item.clone().ok().err();
+            }
+            match serde_json::to_value(&item) {
+                Ok(v) => actual.push(v),
+                Err(err) => {
+                    error.get_or_insert_with(|| format!("Unserializable response: {err}"));
+                }
+            }
+        }
+
+        let diffs = if captured.responses.is_empty() {
+            vec![]
+        } else {
+            diff_responses(&captured.responses, &actual)
+        };
+        ReplayResult {
+            id: captured.id.clone(),
+            elapsed: start.elapsed(),
+            diffs,
+            error,
+        }
+    }
+
+    /// Replay all the captures at the configured rate. Results are returned in capture order.
+    pub async fn replay_all(&self, captures: Vec<CapturedRequest>) -> Result<Vec<ReplayResult>> {
+        let period = Duration::from_secs_f64(1.0 / self.options.rate);
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        let mut handles = Vec::with_capacity(captures.len());
+        for captured in captures {
+            interval.tick().await;
+            let this = self.clone();
+            handles.push(tokio::spawn(
+                async move { this.replay_one(&captured).await },
+            ));
+        }
+
+        let mut results = Vec::with_capacity(handles.len());
+        for handle in handles {
+            results.push(handle.await?);
+        }
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::{
+        AsyncEngineContextProvider, Error, ManyOut, ResponseStream, RouterMode, SingleIn,
+        async_trait, network::Ingress,
+    };
+    use crate::testing::InProcessCluster;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    /// Answers a request with itself, or with an error item for "fail"
+    struct Echo;
+
+    #[async_trait]
+    impl AsyncEngine<SingleIn<serde_json::Value>, ManyOut<Annotated<serde_json::Value>>, Error>
+        for Echo
+    {
+        async fn generate(
+            &self,
+            input: SingleIn<serde_json::Value>,
+        ) -> Result<ManyOut<Annotated<serde_json::Value>>> {
+            let (request, ctx) = input.into_parts();
+            let item = if request == "fail" {
+                Annotated::from_error("failed on purpose".to_string())
+            } else {
+                Annotated::from_data(request)
+            };
+            let stream = futures::stream::iter([item]);
+            Ok(ResponseStream::new(Box::pin(stream), ctx.context()))
+        }
+    }
+
+    fn captured(id: &str, request: &str, responses: Vec<serde_json::Value>) -> CapturedRequest {
+        CapturedRequest {
+            id: id.to_string(),
+            request: json!(request),
+            responses,
+        }
+    }
+
+    #[test]
+    fn test_parse_captures() {
+        let input = r#"{"id":"a","request":"hello","responses":[{"data":"h"},{"data":"i"}]}
+
+{"id":"b","request":{"prompt":"x"}}
+"#;
+        let captures = parse_captures(input.as_bytes()).unwrap();
+        assert_eq!(captures.len(), 2);
+        assert_eq!(captures[0].id, "a");
+        assert_eq!(captures[0].responses.len(), 2);
+        assert_eq!(captures[1].request, json!({"prompt": "x"}));
+        assert!(captures[1].responses.is_empty());
+    }
+
+    #[test]
+    fn test_parse_captures_reports_line() {
+        let input = "{\"id\":\"a\",\"request\":1}\nnot json\n";
+        let err = parse_captures(input.as_bytes()).unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{err}");
+    }
+
+    #[test]
+    fn test_diff_responses() {
+        let expected = vec![json!({"data": "a"}), json!({"data": "b"})];
+
+        assert!(diff_responses(&expected, &expected).is_empty());
+
+        let actual = vec![
+            json!({"data": "a"}),
+            json!({"data": "c"}),
+            json!({"data": "d"}),
+        ];
+        let diffs = diff_responses(&expected, &actual);
+        assert_eq!(diffs.len(), 2);
+        assert_eq!(diffs[0].index, 1);
+        assert_eq!(diffs[0].expected, Some(json!({"data": "b"})));
+        assert_eq!(diffs[1].index, 2);
+        assert_eq!(diffs[1].expected, None);
+        assert_eq!(diffs[1].actual, Some(json!({"data": "d"})));
+    }
+
+    #[tokio::test]
+    async fn test_replay() -> Result<()> {
+        let cluster = InProcessCluster::new(2)?;
+        let drt = cluster.worker(0).clone();
+        let _server = tokio::spawn(async move {
+            let ingress = Ingress::for_engine(Arc::new(Echo))?;
+            let component = drt.namespace("replay")?.component("backend")?;
+            component
+                .endpoint("generate")
+                .endpoint_builder()
+                .handler(ingress)
+                .start()
+                .await
+        });
+
+        let client = cluster
+            .worker(1)
+            .namespace("replay")?
+            .component("backend")?
+            .endpoint("generate")
+            .client()
+            .await?;
+        client.wait_for_instances().await?;
+        let router = ReplayRouter::from_client(client, RouterMode::RoundRobin).await?;
+        let options = ReplayOptions {
+            rate: 1000.0,
+            instance_id: None,
+        };
+        let replayer = Replayer::new(router.clone(), options)?;
+
+        let captures = vec![
+            captured("same", "hello", vec![json!({"data": "hello"})]),
+            captured("changed", "hello", vec![json!({"data": "bye"})]),
+            captured("failed", "fail", vec![]),
+        ];
+        let results = replayer.replay_all(captures).await?;
+        let ids: Vec<_> = results.iter().map(|r| r.id.as_str()).collect();
+        assert_eq!(ids, ["same", "changed", "failed"]);
+
+        assert!(results[0].is_match(), "{:?}", results[0]);
+
+        assert!(!results[1].is_match());
+        assert_eq!(results[1].error, None);
+        assert_eq!(
+            results[1].diffs,
+            vec![ResponseDiff {
+                index: 0,
+                expected: Some(json!({"data": "bye"})),
+                actual: Some(json!({"data": "hello"})),
+            }]
+        );
+
+        // Nothing was recorded, so only the error item counts
+        assert!(results[2].diffs.is_empty());
+        assert!(
+            results[2]
+                .error
+                .as_ref()
+                .unwrap()
+                .contains("failed on purpose")
+        );
+
+        // Direct to the serving worker, or to one that serves nothing
+        let options = ReplayOptions {
+            rate: 1000.0,
+            instance_id: Some(cluster.instance_id(0)),
+        };
+        let direct = Replayer::new(router.clone(), options)?;
+        let request = captured("direct", "hi", vec![json!({"data": "hi"})]);
+        assert!(direct.replay_one(&request).await.is_match());
+        let options = ReplayOptions {
+            rate: 1000.0,
+            instance_id: Some(cluster.instance_id(1)),
+        };
+        let missing = Replayer::new(router, options)?;
+        let result = missing.replay_one(&captured("missing", "hi", vec![])).await;
+        assert!(result.error.is_some());
+        assert!(result.diffs.is_empty());
+        Ok(())
+    }
+}
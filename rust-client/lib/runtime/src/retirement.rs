@@ -0,0 +1,443 @@
+// SPDX-FileCopyrightText: Copyright (c) 2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Retiring the namespace of a deployment that is gone, so that nothing of it is left in the
+//! store.
+//!
+//! [`retire_namespace`] does in one call what decommissioning otherwise takes by hand:
+//!
+//! 1. It closes the namespace with a [`Tombstone`] in [`RETIRED_BUCKET`]. From then on no
+//!    endpoint registers in it, or in a namespace under it.
+//! 2. It sets each instance of the namespace [draining](InstanceStatus::Draining), so routers
+//!    send it no new requests. Routers that verify signatures drop it at once instead, as its
+//!    signature no longer matches.
+//! 3. Once [`RetireConfig::drain`] has passed since the namespace closed, for the requests
+//!    already sent to finish, it deletes every key of the namespace in
+//!    [`RetireConfig::buckets`].
+//! 4. It writes a [`RetirementRecord`] of what it deleted to [`RETIREMENT_AUDIT_BUCKET`], and
+//!    marks the tombstone retired.
+//!
+//! A retirement that stopped halfway is finished by running it again, which doesn't wait for
+//! the drain a second time. The tombstone stays, so that workers of the old deployment that are
+//! still up can't register again, until [`reopen_namespace`] removes it.
+//!
+//! Retiring through a [dry run](KeyValueStoreManager::with_dry_run) store previews what would be
+//! deleted, best with a drain of zero.
+//!
+//! Tombstones and audit records are written with the store's primary lease, if it has one, and
+//! go with it. Retire from a store connected without one, as with [`StoreUrl::connect`].
+//!
+//! [`StoreUrl::connect`]: crate::distributed::StoreUrl::connect
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::component::{INSTANCE_ROOT_PATH, Instance, InstanceStatus};
+use crate::storage::encoding::{self, ValueEncoding};
+use crate::storage::key_value_store::{
+    Conditional, Key, KeyValueBucket, KeyValueStoreManager, StoreError, StoreOutcome,
+};
+use crate::storage::layout::KeyLayout;
+use crate::transports::etcd::ETCD_ROOT_PATH;
+use crate::{Result, error};
+
+/// The tombstones of closed and retired namespaces, by namespace
+pub const RETIRED_BUCKET: &str = "v1/retired_namespaces";
+
+/// One [`RetirementRecord`] per retirement
+pub const RETIREMENT_AUDIT_BUCKET: &str = "v1/retirement_audit";
+
+#[derive(Debug, Clone)]
+pub struct RetireConfig {
+    /// Searched for the namespace's keys, which start with the namespace and a `/`, or a `.`
+    /// for the namespaces under it. Anything else is never touched.
+    pub buckets: Vec<String>,
+    /// Between closing the namespace and deleting its keys
+    pub drain: Duration,
+    /// Who asked, for the tombstone and the audit record
+    pub retired_by: String,
+}
+
+impl Default for RetireConfig {
+    fn default() -> Self {
+        RetireConfig {
+            buckets: vec![
+                INSTANCE_ROOT_PATH.to_string(),
+                KeyLayout::V2.instance_bucket().to_string(),
+                ETCD_ROOT_PATH.trim_end_matches('/').to_string(),
+            ],
+            drain: Duration::from_secs(60),
+            retired_by: crate::policy::identity(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TombstoneState {
+    /// Nothing registers in it any more, its keys are still there
+    Closed,
+    /// Its keys were deleted
+    Retired,
+}
+
+/// Left in [`RETIRED_BUCKET`] under a namespace that was retired, or is being
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tombstone {
+    pub namespace: String,
+    pub state: TombstoneState,
+    pub retired_by: String,
+    /// Unix time in milliseconds
+    pub closed_at: u64,
+}
+
+/// What a retirement did, written to [`RETIREMENT_AUDIT_BUCKET`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetirementRecord {
+    pub namespace: String,
+    pub retired_by: String,
+    /// Unix time in milliseconds
+    pub closed_at: u64,
+    /// Unix time in milliseconds
+    pub retired_at: u64,
+    /// How many instances were set draining
+    pub drained: usize,
+    /// As `{bucket}/{key}`
+    pub deleted_keys: Vec<String>,
+}
+
+/// Close `namespace`, drain it and delete its keys, see the [module docs](self). Fails without
+/// deleting anything if the namespace's tombstone can't be written.
+pub async fn retire_namespace(
+    store: &KeyValueStoreManager,
+    namespace: &str,
+    config: &RetireConfig,
+) -> Result<RetirementRecord> {
+    if namespace.is_empty() {
+        return Err(error!("Retiring needs a namespace"));
+    }
+    let tombstone = close(store, namespace, &config.retired_by).await?;
+    tracing::info!(namespace, retired_by = %tombstone.retired_by, "Namespace closed");
+
+    let mut drained = 0;
+    for bucket_name in instance_buckets(config) {
+        let Some(bucket) = store.get_bucket(bucket_name).await? else {
+            continue;
+        };
+        let encoding = ValueEncoding::negotiate(store, &[bucket_name]).await?;
+        drained += drain_instances(bucket.as_ref(), bucket_name, namespace, encoding).await?;
+    }
+    let drain_until = tombstone.closed_at + config.drain.as_millis() as u64;
+    let wait = Duration::from_millis(drain_until.saturating_sub(store.clock().unix_millis()));
+    if !wait.is_zero() {
+        tracing::info!(namespace, drained, ?wait, "Waiting for routers to drain");
+        store.clock().sleep(wait).await;
+    }
+
+    let mut deleted_keys = Vec::new();
+    for bucket_name in &config.buckets {
+        let Some(bucket) = store.get_bucket(bucket_name).await? else {
+            continue;
+        };
+        for key in bucket.entries().await?.into_keys() {
+            let key = key_in_bucket(bucket_name, &key);
+            if in_namespace(key, namespace) {
+                bucket.delete(&Key::from_raw(key.to_string())).await?;
+                deleted_keys.push(format!("{bucket_name}/{key}"));
+            }
+        }
+    }
+    deleted_keys.sort();
+
+    let record = RetirementRecord {
+        namespace: namespace.to_string(),
+        retired_by: config.retired_by.clone(),
+        closed_at: tombstone.closed_at,
+        retired_at: store.clock().unix_millis(),
+        drained,
+        deleted_keys,
+    };
+    let audit = store
+        .get_or_create_bucket(RETIREMENT_AUDIT_BUCKET, None)
+        .await?;
+    let audit_key = Key::from_raw(format!("{:013}-{namespace}", record.retired_at));
+    audit
+        .insert_new(&audit_key, &serde_json::to_string(&record)?)
+        .await?;
+    store
+        .update(
+            RETIRED_BUCKET,
+            &Key::from_raw(namespace.to_string()),
+            |_| {
+                Ok(Tombstone {
+                    state: TombstoneState::Retired,
+                    ..tombstone.clone()
+                })
+            },
+        )
+        .await?;
+    tracing::info!(
+        namespace,
+        deleted = record.deleted_keys.len(),
+        "Namespace retired"
+    );
+    Ok(record)
+}
+
+/// The tombstone of `namespace`, or of the nearest namespace above it that has one
+pub async fn tombstone(store: &KeyValueStoreManager, namespace: &str) -> Result<Option<Tombstone>> {
+    let Some(bucket) = store.get_bucket(RETIRED_BUCKET).await? else {
+        return Ok(None);
+    };
+    let lineage: Vec<Key> = namespace
+        .match_indices('.')
+        .map(|(end, _)| &namespace[..end])
+        .chain([namespace])
+        .map(|name| Key::from_raw(name.to_string()))
+        .collect();
+    let values = bucket.get_many(&lineage).await?;
+    match values.into_iter().rev().flatten().next() {
+        Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+        None => Ok(None),
+    }
+}
+
+/// Let `namespace` be used again. False if it had no tombstone.
+pub async fn reopen_namespace(store: &KeyValueStoreManager, namespace: &str) -> Result<bool> {
+    let Some(bucket) = store.get_bucket(RETIRED_BUCKET).await? else {
+        return Ok(false);
+    };
+    let key = Key::from_raw(namespace.to_string());
+    if bucket.get(&key).await?.is_none() {
+        return Ok(false);
+    }
+    bucket.delete(&key).await?;
+    tracing::info!(namespace, "Namespace reopened");
+    Ok(true)
+}
+
+/// Write the namespace's tombstone, or return the one a retirement that stopped halfway left
+async fn close(
+    store: &KeyValueStoreManager,
+    namespace: &str,
+    retired_by: &str,
+) -> Result<Tombstone> {
+    let bucket = store.get_or_create_bucket(RETIRED_BUCKET, None).await?;
+    let key = Key::from_raw(namespace.to_string());
+    let tombstone = Tombstone {
+        namespace: namespace.to_string(),
+        state: TombstoneState::Closed,
+        retired_by: retired_by.to_string(),
+        closed_at: store.clock().unix_millis(),
+    };
+    match bucket
+        .insert_new(&key, &serde_json::to_string(&tombstone)?)
+        .await?
+    {
+        StoreOutcome::Created(_) => Ok(tombstone),
+        StoreOutcome::Exists(_) => {
+            let value = bucket
+                .get(&key)
+                .await?
+                .ok_or_else(|| error!("The tombstone of {namespace} went while closing it"))?;
+            Ok(serde_json::from_slice(&value)?)
+        }
+    }
+}
+
+/// The buckets of [`RetireConfig::buckets`] that some [`KeyLayout`] keeps instances in. Both
+/// hold them while a migration writes both layouts.
+fn instance_buckets(config: &RetireConfig) -> impl Iterator<Item = &str> {
+    config
+        .buckets
+        .iter()
+        .map(String::as_str)
+        .filter(|bucket_name| {
+            [KeyLayout::V1, KeyLayout::V2]
+                .iter()
+                .any(|layout| layout.instance_bucket() == *bucket_name)
+        })
+}
+
+/// Set each active instance of `namespace` in `bucket` draining, returning how many. An
+/// instance that changed meanwhile is left as it is, to be deleted with the rest.
+async fn drain_instances(
+    bucket: &dyn KeyValueBucket,
+    bucket_name: &str,
+    namespace: &str,
+    encoding: ValueEncoding,
+) -> Result<usize> {
+    let mut drained = 0;
+    for key in bucket.entries().await?.into_keys() {
+        let key = key_in_bucket(bucket_name, &key);
+        if !in_namespace(key, namespace) {
+            continue;
+        }
+        let key = Key::from_raw(key.to_string());
+        let Conditional::Modified { value, revision } = bucket.get_if_changed(&key, 0).await?
+        else {
+            continue;
+        };
+        let mut instance: Instance = match encoding::decode(&value) {
+            Ok(instance) => instance,
+            Err(err) => {
+                tracing::warn!(%err, %key, "Unable to parse instance, not draining it");
+                continue;
+            }
+        };
+        if instance.status == InstanceStatus::Draining {
+            continue;
+        }
+        instance.status = InstanceStatus::Draining;
+        let value = encoding::encode_text(&instance, encoding)?;
+        match bucket.compare_and_swap(&key, &value, revision).await {
+            Ok(_) => drained += 1,
+            Err(StoreError::Retry) => {
+                tracing::debug!(%key, "Instance changed while draining it");
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(drained)
+}
+
+/// Whether `key` belongs to `namespace` or a namespace under it
+fn in_namespace(key: &str, namespace: &str) -> bool {
+    key.strip_prefix(namespace)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(['/', '.']))
+}
+
+fn key_in_bucket<'a>(bucket_name: &str, key: &'a str) -> &'a str {
+    key.strip_prefix(bucket_name)
+        .and_then(|key| key.strip_prefix('/'))
+        .unwrap_or(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::component::test_instance;
+    use crate::storage::layout::{LAYOUT_BUCKET, LAYOUT_MARKER, LayoutMarker};
+
+    #[tokio::test]
+    async fn test_retire_namespace() {
+        let store = KeyValueStoreManager::memory();
+        let instances = store
+            .get_or_create_bucket(INSTANCE_ROOT_PATH, None)
+            .await
+            .unwrap();
+        for (namespace, instance_id) in [("old", 1), ("old.eu", 2), ("older", 3)] {
+            let key = Key::from_raw(format!("{namespace}/backend/generate/{instance_id:x}"));
+            let instance = Instance {
+                namespace: namespace.to_string(),
+                ..test_instance(instance_id)
+            };
+            let value = serde_json::to_string(&instance).unwrap();
+            instances.insert_new(&key, &value).await.unwrap();
+        }
+        assert_eq!(tombstone(&store, "old.eu").await.unwrap(), None);
+
+        close(&store, "old", "ops").await.unwrap();
+        let drained = drain_instances(
+            instances.as_ref(),
+            INSTANCE_ROOT_PATH,
+            "old",
+            ValueEncoding::Json,
+        )
+        .await;
+        assert_eq!(drained.unwrap(), 2);
+        let draining = instances
+            .get(&Key::from_raw("old.eu/backend/generate/2".to_string()))
+            .await
+            .unwrap()
+            .unwrap();
+        let draining: Instance = serde_json::from_slice(&draining).unwrap();
+        assert_eq!(draining.status, InstanceStatus::Draining);
+        let closed = tombstone(&store, "old.eu").await.unwrap().unwrap();
+        assert_eq!(closed.state, TombstoneState::Closed);
+        assert_eq!(tombstone(&store, "older").await.unwrap(), None);
+
+        // Finishing the retirement it started
+        let config = RetireConfig {
+            drain: Duration::ZERO,
+            retired_by: "someone else".to_string(),
+            ..Default::default()
+        };
+        let record = retire_namespace(&store, "old", &config).await.unwrap();
+        assert_eq!(record.closed_at, closed.closed_at);
+        assert_eq!(record.drained, 0);
+        assert_eq!(
+            record.deleted_keys,
+            [
+                "v1/instances/old.eu/backend/generate/2",
+                "v1/instances/old/backend/generate/1"
+            ]
+        );
+        assert_eq!(instances.entries().await.unwrap().len(), 1);
+        let retired = tombstone(&store, "old").await.unwrap().unwrap();
+        assert_eq!(retired.state, TombstoneState::Retired);
+        assert_eq!(retired.retired_by, "ops");
+
+        let audit = store.get_bucket(RETIREMENT_AUDIT_BUCKET).await.unwrap();
+        let audit = audit.unwrap().entries().await.unwrap();
+        let written: RetirementRecord =
+            serde_json::from_slice(audit.values().next().unwrap()).unwrap();
+        assert_eq!(written, record);
+
+        assert!(reopen_namespace(&store, "old").await.unwrap());
+        assert!(!reopen_namespace(&store, "old").await.unwrap());
+        assert_eq!(tombstone(&store, "old.eu").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_retire_namespace_after_migration() {
+        let store = KeyValueStoreManager::memory();
+        let layout = store
+            .get_or_create_bucket(LAYOUT_BUCKET, None)
+            .await
+            .unwrap();
+        let marker = serde_json::to_string(&LayoutMarker::stable(KeyLayout::V2)).unwrap();
+        layout
+            .insert_new(&Key::from_raw(LAYOUT_MARKER.to_string()), &marker)
+            .await
+            .unwrap();
+        let instances = store
+            .get_or_create_bucket(KeyLayout::V2.instance_bucket(), None)
+            .await
+            .unwrap();
+        for (namespace, instance_id) in [("old", 1), ("older", 2)] {
+            let key = Key::from_raw(format!("{namespace}/backend/generate/{instance_id:x}"));
+            let instance = Instance {
+                namespace: namespace.to_string(),
+                ..test_instance(instance_id)
+            };
+            let value = serde_json::to_string(&instance).unwrap();
+            instances.insert_new(&key, &value).await.unwrap();
+        }
+
+        let config = RetireConfig {
+            drain: Duration::ZERO,
+            ..Default::default()
+        };
+        let record = retire_namespace(&store, "old", &config).await.unwrap();
+        assert_eq!(record.drained, 1);
+        assert_eq!(
+            record.deleted_keys,
+            ["v2/discovery/instances/old/backend/generate/1"]
+        );
+        let left = instances.entries().await.unwrap();
+        let left: Instance = serde_json::from_slice(left.values().next().unwrap()).unwrap();
+        assert_eq!(left.namespace, "older");
+        assert_eq!(left.status, InstanceStatus::Active);
+    }
+
+    #[test]
+    fn test_in_namespace() {
+        assert!(in_namespace("old", "old"));
+        assert!(in_namespace("old/backend/generate/1", "old"));
+        assert!(in_namespace("old.eu/backend", "old"));
+        assert!(!in_namespace("older/backend", "old"));
+        assert!(!in_namespace("ol", "old"));
+    }
+}
@@ -14,12 +14,16 @@
 //! private; however, for now we are exposing most objects as fully public while the API is maturing.
 
 use super::utils::GracefulShutdownTracker;
+use super::utils::tasks::supervisor::Supervisor;
 use super::{Result, Runtime, RuntimeType, error};
+use crate::compute::blocking::BlockingTasks;
 use crate::config::{self, RuntimeConfig};
+use crate::logging::slow_ops::{self, SlowOpKind};
 
 use futures::Future;
 use once_cell::sync::OnceCell;
-use std::sync::{Arc, atomic::Ordering};
+use std::sync::{Arc, OnceLock, atomic::Ordering};
+use std::time::Duration;
 use tokio::{signal, sync::Mutex, task::JoinHandle};
 
 pub use tokio_util::sync::CancellationToken;
@@ -48,6 +52,18 @@ impl Runtime {
         // This will be properly configured when created from RuntimeConfig
         let compute_pool = None;
         let block_in_place_permits = None;
+        let control_plane_threads = 1;
+        let control_plane = Arc::new(OnceLock::new());
+        // tokio's default size of the blocking pool
+        let blocking_tasks = Arc::new(BlockingTasks::new(512));
+
+        // background tasks are restarted on the secondary runtime until the primary token is
+        // cancelled
+        let supervisor = Supervisor::new(
+            "runtime",
+            cancellation_token.clone(),
+            Some(secondary.handle()),
+        );
 
         Ok(Runtime {
             id,
@@ -58,6 +74,10 @@ impl Runtime {
             graceful_shutdown_tracker: Arc::new(GracefulShutdownTracker::new()),
             compute_pool,
             block_in_place_permits,
+            control_plane_threads,
+            control_plane,
+            blocking_tasks,
+            supervisor,
         })
     }
 
@@ -67,6 +87,17 @@ impl Runtime {
         config: &RuntimeConfig,
     ) -> Result<Runtime> {
         let mut rt = Self::new(runtime, secondary)?;
+        rt.control_plane_threads = config.control_plane_threads;
+        rt.blocking_tasks = Arc::new(BlockingTasks::new(config.max_blocking_threads));
+
+        // Process wide, so the runtime created last sets them
+        for (kind, threshold_ms) in [
+            (SlowOpKind::Etcd, config.slow_etcd_call_ms),
+            (SlowOpKind::Store, config.slow_store_op_ms),
+            (SlowOpKind::Operator, config.slow_operator_ms),
+        ] {
+            slow_ops::set_threshold(kind, Duration::from_millis(threshold_ms));
+        }
 
         // Create compute pool from configuration
         let compute_config = crate::compute::ComputeConfig {
@@ -218,12 +249,16 @@ impl Runtime {
     }
 
     /// Create a [`Runtime`] instance from the settings
-    /// See [`config::RuntimeConfig::from_settings`]
+    /// See [`config::RuntimeConfig::from_settings`]. The background tasks run on the primary
+    /// runtime unless [`config::RuntimeConfig::background_threads`] gives them their own.
     pub fn from_settings() -> Result<Runtime> {
         let config = config::RuntimeConfig::from_settings()?;
         let runtime = Arc::new(config.create_runtime()?);
         let primary = RuntimeType::Shared(runtime.clone());
-        let secondary = RuntimeType::External(runtime.handle().clone());
+        let secondary = match config.create_background_runtime()? {
+            Some(background) => RuntimeType::Shared(Arc::new(background)),
+            None => RuntimeType::External(runtime.handle().clone()),
+        };
         Runtime::new_with_config(primary, Some(secondary), &config)
     }
 
@@ -249,6 +284,33 @@ impl Runtime {
         self.secondary.handle()
     }
 
+    /// Returns a [`tokio::runtime::Handle`] for the control plane, which runs the tasks that
+    /// keep this process registered: the etcd client with its lease keep-alives and watches,
+    /// and the pumps of the discovery watches. It has
+    /// [`RuntimeConfig::control_plane_threads`] workers of its own, so requests saturating the
+    /// primary runtime don't delay heartbeats until the lease expires. Created the first time it
+    /// is needed, and shared by the clones of this [`Runtime`].
+    pub fn control_plane(&self) -> tokio::runtime::Handle {
+        self.control_plane
+            .get_or_init(|| match build_control_plane(self.control_plane_threads) {
+                Ok(handle) => handle,
+                Err(err) => {
+                    tracing::error!(%err, "Unable to create the control plane runtime");
+                    self.secondary()
+                }
+            })
+            .clone()
+    }
+
+    /// Spawn `future` on the [`Runtime::control_plane`]
+    pub fn spawn_control_plane<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.control_plane().spawn(future)
+    }
+
     /// Access the primary [`CancellationToken`] for the [`Runtime`]
     pub fn primary_token(&self) -> CancellationToken {
         self.cancellation_token.clone()
@@ -259,6 +321,11 @@ impl Runtime {
         self.endpoint_shutdown_token.child_token()
     }
 
+    /// The root of the tree of supervised background tasks
+    pub fn supervisor(&self) -> &Supervisor {
+        &self.supervisor
+    }
+
     /// Get access to the graceful shutdown tracker
     pub(crate) fn graceful_shutdown_tracker(&self) -> Arc<GracefulShutdownTracker> {
         self.graceful_shutdown_tracker.clone()
@@ -271,6 +338,21 @@ impl Runtime {
         self.compute_pool.as_ref()
     }
 
+    /// Run CPU-heavy `f` on the blocking pool of the primary runtime, counted under `name` in
+    /// [`Runtime::blocking_tasks`]. See [`crate::compute::blocking`].
+    pub async fn spawn_blocking_instrumented<F, R>(&self, name: &'static str, f: F) -> Result<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        self.blocking_tasks.run(&self.primary(), name, f).await
+    }
+
+    /// What the tasks run by [`Runtime::spawn_blocking_instrumented`] did
+    pub fn blocking_tasks(&self) -> &BlockingTasks {
+        &self.blocking_tasks
+    }
+
     /// Shuts down the [`Runtime`] instance
     pub fn shutdown(&self) {
         tracing::info!("Runtime shutdown initiated");
@@ -306,6 +388,21 @@ impl Runtime {
     }
 }
 
+/// A runtime of `threads` workers that lives as long as the process. A thread of its own blocks
+/// on it, so it is never dropped from async code.
+fn build_control_plane(threads: usize) -> std::io::Result<tokio::runtime::Handle> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(threads)
+        .thread_name("dyn-control")
+        .enable_all()
+        .build()?;
+    let handle = runtime.handle().clone();
+    std::thread::Builder::new()
+        .name("dyn-control-rt".to_string())
+        .spawn(move || runtime.block_on(std::future::pending::<()>()))?;
+    Ok(handle)
+}
+
 impl RuntimeType {
     /// Get [`tokio::runtime::Handle`] to runtime
     pub fn handle(&self) -> tokio::runtime::Handle {
@@ -324,3 +421,55 @@ impl std::fmt::Debug for RuntimeType {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::time::Duration;
+
+    /// How late the latest of `beats` heartbeats `period` apart was
+    async fn heartbeat_lateness(period: Duration, beats: u32) -> Duration {
+        let mut deadline = tokio::time::Instant::now();
+        let mut latest = Duration::ZERO;
+        for _ in 0..beats {
+            deadline += period;
+            tokio::time::sleep_until(deadline).await;
+            latest = latest.max(deadline.elapsed());
+        }
+        latest
+    }
+
+    #[test]
+    fn test_control_plane_heartbeats_under_load() {
+        let runtime = Runtime::single_threaded().unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        // Requests blocking the primary's one worker for 50ms at a time
+        for _ in 0..4 {
+            let stop = stop.clone();
+            runtime.primary().spawn(async move {
+                while !stop.load(Ordering::Relaxed) {
+                    std::thread::sleep(Duration::from_millis(50));
+                    tokio::task::yield_now().await;
+                }
+            });
+        }
+
+        let period = Duration::from_millis(20);
+        let on_control_plane = runtime.spawn_control_plane(heartbeat_lateness(period, 10));
+        let on_primary = runtime.primary().spawn(heartbeat_lateness(period, 10));
+        let on_control_plane = runtime.secondary().block_on(on_control_plane).unwrap();
+        let on_primary = runtime.secondary().block_on(on_primary).unwrap();
+        stop.store(true, Ordering::Relaxed);
+
+        // Blocking threads can't run on paused time, so the bounds leave room for a busy
+        // machine: the control plane is never behind a request, but may be behind the OS
+        assert!(
+            on_control_plane < Duration::from_millis(200),
+            "{on_control_plane:?}"
+        );
+        // The same heartbeats waiting behind the requests. With beats 20ms apart, one falls
+        // due in the first 20ms of a 50ms request and waits out the rest.
+        assert!(on_primary >= Duration::from_millis(30), "{on_primary:?}");
+    }
+}
@@ -0,0 +1,298 @@
+// SPDX-FileCopyrightText: Copyright (c) 2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Where the etcd and NATS credentials come from, when not from the environment.
+//!
+//! A [`SecretsProvider`] looks secrets up by the names of the variables they would otherwise be
+//! in: `ETCD_AUTH_USERNAME`, `ETCD_AUTH_PASSWORD`, `ETCD_AUTH_CA`, `ETCD_AUTH_CLIENT_CERT`,
+//! `ETCD_AUTH_CLIENT_KEY`, `NATS_AUTH_USERNAME`, `NATS_AUTH_PASSWORD`, `NATS_AUTH_TOKEN` and
+//! `NATS_AUTH_NKEY`. `DYN_SECRETS_PROVIDER` picks it for
+//! [`DistributedConfig::from_settings`](crate::distributed::DistributedConfig::from_settings):
+//!
+//! - `env`: the environment, as without a provider
+//! - `file:/var/run/secrets/dynamo`: one file per secret in that directory, as Kubernetes
+//!   mounts a secret
+//! - `exec:/usr/local/bin/get-secret --json`: the output of the command, run with the name of
+//!   the secret as its last argument. Printing nothing means there is no such secret.
+//! - `vault:secret/data/dynamo`: the fields of a Vault secret, read over HTTP from `VAULT_ADDR`
+//!   with the token in `VAULT_TOKEN_FILE`, or else `VAULT_TOKEN`. KV version 1 and 2 both work.
+//!
+//! Secrets are read again each time a connection is made rather than once at startup, so
+//! rotated ones are used without restarting. NATS reads them on every reconnect, except an
+//! nkey, which signs the server's challenge and is read once. An etcd client authenticates when
+//! it connects and keeps doing so with what it read then, so rotated etcd credentials are used
+//! by the clients connected after the rotation.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Context as _;
+use async_trait::async_trait;
+
+use crate::Result;
+
+/// Picks the [`SecretsProvider`], see the [module docs](self)
+pub const SECRETS_PROVIDER_ENV: &str = "DYN_SECRETS_PROVIDER";
+
+/// A credential, kept out of `Debug` output
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Secret(value.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn into_inner(self) -> String {
+        self.0
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Secret(<redacted>)")
+    }
+}
+
+#[async_trait]
+pub trait SecretsProvider: Send + Sync + std::fmt::Debug {
+    /// The secret called `name`, or None if there is none by that name
+    async fn get(&self, name: &str) -> Result<Option<Secret>>;
+}
+
+/// Secrets from the environment variables of the same name
+#[derive(Debug, Clone, Default)]
+pub struct EnvSecrets;
+
+#[async_trait]
+impl SecretsProvider for EnvSecrets {
+    async fn get(&self, name: &str) -> Result<Option<Secret>> {
+        Ok(std::env::var(name).ok().map(Secret))
+    }
+}
+
+/// Secrets from the files of the same name in `dir`
+#[derive(Debug, Clone)]
+pub struct FileSecrets {
+    pub dir: PathBuf,
+}
+
+#[async_trait]
+impl SecretsProvider for FileSecrets {
+    async fn get(&self, name: &str) -> Result<Option<Secret>> {
+        let path = self.dir.join(name);
+        match tokio::fs::read_to_string(&path).await {
+            Ok(value) => Ok(Some(Secret(trim_newline(value)))),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("Reading secret {}", path.display())),
+        }
+    }
+}
+
+/// Secrets printed by `program`, run with `args` and then the name of the secret
+#[derive(Debug, Clone)]
+pub struct ExecSecrets {
+    pub program: String,
+    pub args: Vec<String>,
+}
+
+#[async_trait]
+impl SecretsProvider for ExecSecrets {
+    async fn get(&self, name: &str) -> Result<Option<Secret>> {
+        let output = tokio::process::Command::new(&self.program)
+            .args(&self.args)
+            .arg(name)
+            .kill_on_drop(true)
+            .output()
+            .await
+            .with_context(|| format!("Running {} for secret {name}", self.program))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            anyhow::bail!(
+                "{} failed for secret {name}, {}: {}",
+                self.program,
+                output.status,
+                stderr.trim()
+            );
+        }
+        let value = String::from_utf8(output.stdout)
+            .with_context(|| format!("{} printed secret {name} as non UTF-8", self.program))?;
+        let value = trim_newline(value);
+        Ok((!value.is_empty()).then_some(Secret(value)))
+    }
+}
+
+/// Secrets from the fields of the Vault secret at `path`, e.g. `secret/data/dynamo`
+#[derive(Debug, Clone)]
+pub struct VaultSecrets {
+    /// e.g. `https://vault.internal:8200`
+    pub address: String,
+    pub path: String,
+    http: reqwest::Client,
+}
+
+impl VaultSecrets {
+    pub fn new(address: impl Into<String>, path: impl Into<String>) -> Self {
+        VaultSecrets {
+            address: address.into().trim_end_matches('/').to_string(),
+            path: path.into().trim_matches('/').to_string(),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// Read on every request, so that a token renewed by an agent into its file is picked up
+    async fn token() -> Result<String> {
+        if let Ok(path) = std::env::var("VAULT_TOKEN_FILE") {
+            let token = tokio::fs::read_to_string(&path)
+                .await
+                .with_context(|| format!("Reading VAULT_TOKEN_FILE {path}"))?;
+            return Ok(token.trim().to_string());
+        }
+        std::env::var("VAULT_TOKEN").context("Neither VAULT_TOKEN_FILE nor VAULT_TOKEN is set")
+    }
+}
+
+#[async_trait]
+impl SecretsProvider for VaultSecrets {
+    async fn get(&self, name: &str) -> Result<Option<Secret>> {
+        let url = format!("{}/v1/{}", self.address, self.path);
+        let response = self
+            .http
+            .get(&url)
+            .header("X-Vault-Token", Self::token().await?)
+            .send()
+            .await
+            .with_context(|| format!("Reading Vault secret {}", self.path))?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let body: serde_json::Value = response
+            .error_for_status()
+            .with_context(|| format!("Reading Vault secret {}", self.path))?
+            .json()
+            .await?;
+        Ok(vault_field(&body, name).map(Secret))
+    }
+}
+
+/// Field `name` of a Vault KV read: under `data.data` in version 2, `data` in version 1
+fn vault_field(body: &serde_json::Value, name: &str) -> Option<String> {
+    let data = &body["data"];
+    let fields = if data["data"].is_object() && data["metadata"].is_object() {
+        &data["data"]
+    } else {
+        data
+    };
+    fields[name].as_str().map(str::to_string)
+}
+
+/// Files and command output usually end in a newline that isn't part of the secret
+fn trim_newline(mut value: String) -> String {
+    let trimmed = value.trim_end_matches(['\n', '\r']).len();
+    value.truncate(trimmed);
+    value
+}
+
+/// The provider `spec` names, in the form of `DYN_SECRETS_PROVIDER`
+pub fn parse(spec: &str) -> Result<Arc<dyn SecretsProvider>> {
+    let (kind, arg) = spec.split_once(':').unwrap_or((spec, ""));
+    let arg = arg.trim();
+    match kind.trim() {
+        "env" => Ok(Arc::new(EnvSecrets)),
+        "file" if !arg.is_empty() => Ok(Arc::new(FileSecrets { dir: arg.into() })),
+        "exec" => {
+            let mut words = arg.split_whitespace().map(str::to_string);
+            let Some(program) = words.next() else {
+                anyhow::bail!("{SECRETS_PROVIDER_ENV} '{spec}' has no command");
+            };
+            Ok(Arc::new(ExecSecrets {
+                program,
+                args: words.collect(),
+            }))
+        }
+        "vault" if !arg.is_empty() => {
+            let address =
+                std::env::var("VAULT_ADDR").context("A vault secrets provider needs VAULT_ADDR")?;
+            Ok(Arc::new(VaultSecrets::new(address, arg)))
+        }
+        "file" | "vault" => anyhow::bail!("{SECRETS_PROVIDER_ENV} '{spec}' has no path"),
+        other => anyhow::bail!("Unknown secrets provider '{other}' in {SECRETS_PROVIDER_ENV}"),
+    }
+}
+
+/// The provider `DYN_SECRETS_PROVIDER` names, or None if it is not set
+pub fn from_env() -> Result<Option<Arc<dyn SecretsProvider>>> {
+    match std::env::var(SECRETS_PROVIDER_ENV) {
+        Ok(spec) if !spec.trim().is_empty() => parse(&spec).map(Some),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert!(format!("{:?}", parse("env").unwrap()).contains("EnvSecrets"));
+        let file = format!("{:?}", parse("file:/var/run/secrets/dynamo").unwrap());
+        assert!(file.contains("/var/run/secrets/dynamo"), "{file}");
+        let exec = format!("{:?}", parse("exec: get-secret --json").unwrap());
+        assert!(
+            exec.contains(r#"program: "get-secret", args: ["--json"]"#),
+            "{exec}"
+        );
+        for spec in ["file:", "exec:", "vault", "consul:kv"] {
+            assert!(parse(spec).is_err(), "{spec}");
+        }
+        temp_env::with_var("VAULT_ADDR", Some("https://vault:8200/"), || {
+            let vault = format!("{:?}", parse("vault:/secret/data/dynamo").unwrap());
+            assert!(
+                vault.contains(r#"address: "https://vault:8200""#),
+                "{vault}"
+            );
+            assert!(vault.contains(r#"path: "secret/data/dynamo""#), "{vault}");
+        });
+    }
+
+    #[tokio::test]
+    async fn test_file_and_exec() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("ETCD_AUTH_PASSWORD"), "hunter2\n").unwrap();
+        let files = FileSecrets {
+            dir: dir.path().to_path_buf(),
+        };
+        let password = files.get("ETCD_AUTH_PASSWORD").await.unwrap().unwrap();
+        assert_eq!(password.expose(), "hunter2");
+        assert_eq!(format!("{password:?}"), "Secret(<redacted>)");
+        assert_eq!(files.get("ETCD_AUTH_USERNAME").await.unwrap(), None);
+
+        // echo prints the name it is given
+        let exec = parse("exec:echo -n").unwrap();
+        let token = exec.get("NATS_AUTH_TOKEN").await.unwrap();
+        assert_eq!(token, Some(Secret::new("NATS_AUTH_TOKEN")));
+        assert_eq!(parse("exec:true").unwrap().get("X").await.unwrap(), None);
+        assert!(parse("exec:false").unwrap().get("X").await.is_err());
+    }
+
+    #[test]
+    fn test_vault_field() {
+        let v2 = serde_json::json!({
+            "data": {"data": {"ETCD_AUTH_PASSWORD": "v2"}, "metadata": {"version": 3}}
+        });
+        assert_eq!(
+            vault_field(&v2, "ETCD_AUTH_PASSWORD").as_deref(),
+            Some("v2")
+        );
+        assert_eq!(vault_field(&v2, "NATS_AUTH_TOKEN"), None);
+        let v1 = serde_json::json!({"data": {"ETCD_AUTH_PASSWORD": "v1"}});
+        assert_eq!(
+            vault_field(&v1, "ETCD_AUTH_PASSWORD").as_deref(),
+            Some("v1")
+        );
+    }
+}
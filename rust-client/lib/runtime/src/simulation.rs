@@ -0,0 +1,392 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Deterministic lease timing over virtual time. Requires the `simulation` feature.
+//!
+//! Meant to run on tokio's paused clock (`#[tokio::test(start_paused = true)]`). Time then only
+//! moves once every task is idle, and jumps straight to the next timer, so a 30 second TTL
+//! takes no real time and every deadline fires at exactly the same instant on every run.
+//!
+//! A [`SimulatedLeaseServer`] stands in for etcd's lease API. Keys attached to a lease are
+//! deleted from a [`MemoryStore`] when it expires or is revoked, so watchers of that store see
+//! the same events as etcd watchers would. [`SimulatedLeaseServer::keep_alive`] runs the
+//! runtime's own keep-alive loop against it.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use tokio::sync::{Notify, mpsc};
+use tokio::time::Instant;
+
+use crate::storage::key_value_store::{Key, KeyValueBucket, KeyValueStore, MemoryStore};
+use crate::transports::etcd::{
+    Intercepted, KeepAliveInterceptor, LeaseHeartbeat, Progress, run_keep_alive,
+};
+use crate::{CancellationToken, Result, error};
+
+/// Virtual-time leases. Cheap to clone, clones share their leases.
+#[derive(Clone)]
+pub struct SimulatedLeaseServer {
+    inner: Arc<ServerInner>,
+}
+
+struct ServerInner {
+    store: MemoryStore,
+    leases: Mutex<LeaseTable>,
+    /// Wakes the reaper when a lease is granted, refreshed or revoked
+    changed: Notify,
+}
+
+#[derive(Default)]
+struct LeaseTable {
+    last_id: u64,
+    leases: HashMap<u64, SimulatedLease>,
+    /// Heartbeats are lost while set, as if the server were unreachable
+    partitioned: bool,
+}
+
+struct SimulatedLease {
+    ttl: u64,
+    expires_at: Instant,
+    /// (bucket, key) pairs deleted with the lease
+    keys: Vec<(String, String)>,
+}
+
+impl SimulatedLeaseServer {
+    /// Expires leases until `cancel_token` is cancelled
+    pub fn new(store: MemoryStore, cancel_token: CancellationToken) -> Self {
+        let server = SimulatedLeaseServer {
+            inner: Arc::new(ServerInner {
+                store,
+                leases: Mutex::new(LeaseTable::default()),
+                changed: Notify::new(),
+            }),
+        };
+        tokio::spawn(server.clone().reap(cancel_token));
+        server
+    }
+
+    /// Grant a lease of `ttl` seconds, returning its ID
+    pub fn grant(&self, ttl: u64) -> u64 {
+        let mut table = self.inner.leases.lock();
+        table.last_id += 1;
+        let lease_id = table.last_id;
+        table.leases.insert(
+            lease_id,
+            SimulatedLease {
+                ttl,
+                expires_at: Instant::now() + Duration::from_secs(ttl),
+                keys: Vec::new(),
+            },
+        );
+        drop(table);
+        self.inner.changed.notify_one();
+        lease_id
+    }
+
+    /// Delete `key` from `bucket` when the lease ends, like a put with a lease in etcd
+    pub fn attach(&self, lease_id: u64, bucket: &str, key: &Key) -> Result<()> {
+        let mut table = self.inner.leases.lock();
+        let Some(lease) = table.leases.get_mut(&lease_id) else {
+            return Err(error!("Lease {lease_id} not found"));
+        };
+        lease.keys.push((bucket.to_string(), key.to_string()));
+        Ok(())
+    }
+
+    /// Refresh a lease, returning its TTL, or 0 if it has already expired, as etcd does
+    pub fn refresh(&self, lease_id: u64) -> u64 {
+        let now = Instant::now();
+        let mut table = self.inner.leases.lock();
+        let ttl = match table.leases.get_mut(&lease_id) {
+            Some(lease) if lease.expires_at > now => {
+                lease.expires_at = now + Duration::from_secs(lease.ttl);
+                lease.ttl
+            }
+            _ => 0,
+        };
+        drop(table);
+        self.inner.changed.notify_one();
+        ttl
+    }
+
+    /// End a lease now. Its keys are deleted before the reaper next yields.
+    pub fn revoke(&self, lease_id: u64) {
+        if let Some(lease) = self.inner.leases.lock().leases.get_mut(&lease_id) {
+            lease.expires_at = Instant::now();
+        }
+        self.inner.changed.notify_one();
+    }
+
+    pub fn is_alive(&self, lease_id: u64) -> bool {
+        self.time_to_live(lease_id).is_some()
+    }
+
+    /// How long until the lease expires, None if it already has
+    pub fn time_to_live(&self, lease_id: u64) -> Option<Duration> {
+        let now = Instant::now();
+        let table = self.inner.leases.lock();
+        let lease = table.leases.get(&lease_id)?;
+        (lease.expires_at > now).then(|| lease.expires_at - now)
+    }
+
+    /// While partitioned, heartbeats never reach the server and get no response
+    pub fn partition(&self, partitioned: bool) {
+        self.inner.leases.lock().partitioned = partitioned;
+    }
+
+    /// Keep `lease_id` alive with the runtime's keep-alive loop, until `token` is cancelled
+    /// (which revokes it) or the loop gives up
+    pub fn keep_alive(
+        &self,
+        lease_id: u64,
+        token: CancellationToken,
+    ) -> tokio::task::JoinHandle<Result<()>> {
+        let (ttl, heartbeat) = self.heartbeat(lease_id);
+        let progress = Progress::default();
+        tokio::spawn(run_keep_alive(heartbeat, lease_id, ttl, token, progress))
+    }
+
+    /// [`SimulatedLeaseServer::keep_alive`] with faults injected by `interceptor`
+    pub fn keep_alive_intercepted(
+        &self,
+        lease_id: u64,
+        token: CancellationToken,
+        interceptor: Arc<dyn KeepAliveInterceptor>,
+    ) -> tokio::task::JoinHandle<Result<()>> {
+        let (ttl, heartbeat) = self.heartbeat(lease_id);
+        let heartbeat = Intercepted::new(heartbeat, lease_id, interceptor);
+        let progress = Progress::default();
+        tokio::spawn(run_keep_alive(heartbeat, lease_id, ttl, token, progress))
+    }
+
+    fn heartbeat(&self, lease_id: u64) -> (u64, SimulatedHeartbeat) {
+        let ttl = self
+            .inner
+            .leases
+            .lock()
+            .leases
+            .get(&lease_id)
+            .map(|lease| lease.ttl)
+            .unwrap_or_default();
+        let (responses_tx, responses) = mpsc::unbounded_channel();
+        let heartbeat = SimulatedHeartbeat {
+            server: self.clone(),
+            lease_id,
+            responses_tx,
+            responses,
+        };
+        (ttl, heartbeat)
+    }
+
+    async fn reap(self, cancel_token: CancellationToken) {
+        loop {
+            // The table's guard must be gone before deleting the keys, so the task stays Send
+            let (keys, next_expiry) = {
+                let now = Instant::now();
+                let mut table = self.inner.leases.lock();
+                let expired: Vec<u64> = table
+                    .leases
+                    .iter()
+                    .filter(|(_, lease)| lease.expires_at <= now)
+                    .map(|(id, _)| *id)
+                    .collect();
+                let mut keys = Vec::new();
+                for lease_id in expired {
+                    if let Some(lease) = table.leases.remove(&lease_id) {
+                        tracing::debug!(lease_id, "simulated lease expired");
+                        keys.extend(lease.keys);
+                    }
+                }
+                let next_expiry = table.leases.values().map(|lease| lease.expires_at).min();
+                (keys, next_expiry)
+            };
+            self.delete_keys(keys).await;
+
+            tokio::select! {
+                _ = cancel_token.cancelled() => break,
+                _ = self.inner.changed.notified() => {}
+                _ = async {
+                    match next_expiry {
+                        Some(at) => tokio::time::sleep_until(at).await,
+                        None => std::future::pending().await,
+                    }
+                } => {}
+            }
+        }
+    }
+
+    async fn delete_keys(&self, keys: Vec<(String, String)>) {
+        for (bucket_name, key) in keys {
+            let bucket = match self.inner.store.get_bucket(&bucket_name).await {
+                Ok(Some(bucket)) => bucket,
+                Ok(None) => continue,
+                Err(err) => {
+                    tracing::warn!(%err, %bucket_name, "Failed to open bucket of expired lease");
+                    continue;
+                }
+            };
+            if let Err(err) = bucket.delete(&Key::from_raw(key)).await {
+                tracing::warn!(%err, %bucket_name, "Failed to delete key of expired lease");
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for SimulatedLeaseServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let table = self.inner.leases.lock();
+        f.debug_struct("SimulatedLeaseServer")
+            .field("leases", &table.leases.len())
+            .field("partitioned", &table.partitioned)
+            .finish()
+    }
+}
+
+/// The keep-alive stream of one simulated lease. Responses are queued when a heartbeat is
+/// sent, so they arrive at the same virtual instant.
+struct SimulatedHeartbeat {
+    server: SimulatedLeaseServer,
+    lease_id: u64,
+    responses_tx: mpsc::UnboundedSender<u64>,
+    responses: mpsc::UnboundedReceiver<u64>,
+}
+
+#[async_trait::async_trait]
+impl LeaseHeartbeat for SimulatedHeartbeat {
+    async fn send(&mut self) -> Result<()> {
+        if self.server.inner.leases.lock().partitioned {
+            // Lost on the way, the client finds out from the missing response
+            return Ok(());
+        }
+        let ttl = self.server.refresh(self.lease_id);
+        let _ = self.responses_tx.send(ttl);
+        Ok(())
+    }
+
+    async fn receive(&mut self) -> Result<Option<u64>> {
+        // We hold a sender, so this only ends when a response arrives
+        Ok(self.responses.recv().await)
+    }
+
+    async fn remaining(&mut self) -> Result<u64> {
+        if self.server.inner.leases.lock().partitioned {
+            return Err(error!("Simulated lease server is unreachable"));
+        }
+        // etcd rounds down, but never to 0 for a live lease
+        let left = self.server.time_to_live(self.lease_id);
+        Ok(left.map_or(0, |left| left.as_secs().max(1)))
+    }
+
+    async fn revoke(&mut self) -> Result<()> {
+        self.server.revoke(self.lease_id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::key_value_store::{KeyValueStoreManager, WatchEvent};
+
+    const TTL: u64 = 3;
+
+    async fn leased_key(server: &SimulatedLeaseServer, store: &MemoryStore) -> Result<u64> {
+        let lease_id = server.grant(TTL);
+        let bucket = store.get_or_create_bucket("leased", None).await?;
+        let key = Key::from_raw("worker".to_string());
+        bucket.insert(&key, "{}", 0).await?;
+        server.attach(lease_id, "leased", &key)?;
+        Ok(lease_id)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lease_expires_after_three_missed_heartbeats() -> Result<()> {
+        let cancel_token = CancellationToken::new();
+        let store = MemoryStore::new();
+        let server = SimulatedLeaseServer::new(store.clone(), cancel_token.clone());
+        let lease_id = leased_key(&server, &store).await?;
+        let manager = Arc::new(KeyValueStoreManager::shared_memory(store.clone()));
+        let mut events = manager.watch("leased", None, cancel_token.clone());
+        assert!(matches!(events.recv().await, Some(WatchEvent::Put(_))));
+        assert_eq!(events.recv().await, Some(WatchEvent::InitialSyncComplete));
+
+        let start = Instant::now();
+        let keep_alive = server.keep_alive(lease_id, cancel_token.child_token());
+
+        // Heartbeats every TTL / 2 = 1s keep it alive indefinitely
+        tokio::time::sleep(Duration::from_millis(10_500)).await;
+        assert!(server.is_alive(lease_id));
+
+        // The heartbeat at 10s was the last to arrive. Those at 11s, 12s and 13s are lost, and
+        // the lease expires with the third.
+        server.partition(true);
+        let Some(WatchEvent::Delete(kv)) = events.recv().await else {
+            panic!("expected the leased key to be deleted");
+        };
+        assert_eq!(kv.key(), "worker");
+        assert_eq!(start.elapsed(), Duration::from_secs(13));
+        assert!(!server.is_alive(lease_id));
+
+        // The client notices at its first check after the deadline
+        assert!(keep_alive.await?.is_err());
+        assert_eq!(start.elapsed(), Duration::from_secs(14));
+
+        cancel_token.cancel();
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lease_survives_short_partition() -> Result<()> {
+        let cancel_token = CancellationToken::new();
+        let store = MemoryStore::new();
+        let server = SimulatedLeaseServer::new(store.clone(), cancel_token.clone());
+        let lease_id = leased_key(&server, &store).await?;
+        let _keep_alive = server.keep_alive(lease_id, cancel_token.child_token());
+
+        tokio::time::sleep(Duration::from_millis(10_500)).await;
+        server.partition(true);
+        // The heartbeat at 11s is lost, the one at 12s gets through
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        server.partition(false);
+        assert_eq!(
+            server.time_to_live(lease_id),
+            Some(Duration::from_millis(1_500))
+        );
+
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert!(server.is_alive(lease_id));
+
+        cancel_token.cancel();
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cancel_revokes_lease() -> Result<()> {
+        let cancel_token = CancellationToken::new();
+        let store = MemoryStore::new();
+        let server = SimulatedLeaseServer::new(store.clone(), cancel_token.clone());
+        let lease_id = leased_key(&server, &store).await?;
+        let manager = Arc::new(KeyValueStoreManager::shared_memory(store.clone()));
+        let mut events = manager.watch("leased", None, cancel_token.clone());
+        assert!(matches!(events.recv().await, Some(WatchEvent::Put(_))));
+        assert_eq!(events.recv().await, Some(WatchEvent::InitialSyncComplete));
+        let token = cancel_token.child_token();
+        let keep_alive = server.keep_alive(lease_id, token.clone());
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        let start = Instant::now();
+        token.cancel();
+        keep_alive.await??;
+        assert!(!server.is_alive(lease_id));
+
+        // Deleted at once, without waiting out the TTL
+        assert!(matches!(events.recv().await, Some(WatchEvent::Delete(_))));
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        cancel_token.cancel();
+        Ok(())
+    }
+}
@@ -1,4 +1,8 @@
 // SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+pub mod encoding;
 pub mod key_value_store;
+pub mod layout;
+pub mod model_card;
+pub mod outbox;
@@ -0,0 +1,408 @@
+// SPDX-FileCopyrightText: Copyright (c) 2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Protobuf for discovery records: instance registrations and model cards.
+//!
+//! Records are written as JSON unless the bucket they go in says otherwise. Protobuf is less
+//! than half the size, which the largest clusters feel in etcd's storage and on every watcher,
+//! as each change goes to all of them.
+//!
+//! Readers [`decode`] either, telling them apart by the first byte, so a bucket can hold both
+//! while it changes over. Writers only write protobuf to a bucket whose [`BucketVersion`], kept
+//! in [`ENCODING_BUCKET`] under the bucket's name, is [`PROTOBUF_VERSION`] or later. Raise it
+//! with [`BucketVersion::set`] once every process reading the bucket runs a build that decodes
+//! protobuf; until then nothing changes. A record written to several buckets, as during a
+//! [layout migration](super::layout), is protobuf only if all of them allow it.
+//!
+//! Values written through the key-value store API are text, so protobuf written that way is
+//! base64 behind a `pb:` prefix. Instances registered in etcd directly are raw protobuf.
+
+use std::str::FromStr;
+
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use prost::Message;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::component::{Instance, InstanceSignature, InstanceStatus, TransportType};
+use crate::identity::WorkerId;
+use crate::pipeline::network::codec::PayloadCodec;
+use crate::transports::etcd::Client as EtcdClient;
+
+use super::key_value_store::{Key, KeyValueStoreManager, StoreError, StoreOutcome};
+
+/// Holds a [`BucketVersion`] per bucket, by bucket name
+pub const ENCODING_BUCKET: &str = "v1/encoding";
+
+/// Of a bucket with no [`BucketVersion`]: JSON only
+pub const JSON_VERSION: u32 = 1;
+
+/// From this [`BucketVersion`] on, records are written as protobuf
+pub const PROTOBUF_VERSION: u32 = 2;
+
+/// Prefixes the base64 of protobuf written as text
+const TEXT_PREFIX: &str = "pb:";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ValueEncoding {
+    #[default]
+    Json,
+    Protobuf,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum EncodingError {
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Protobuf(#[from] prost::DecodeError),
+
+    #[error("Invalid base64 in protobuf text: {0}")]
+    Base64(#[from] base64::DecodeError),
+
+    #[error("Invalid {field} in protobuf record: {reason}")]
+    Invalid { field: &'static str, reason: String },
+}
+
+impl EncodingError {
+    fn invalid(field: &'static str, reason: impl ToString) -> Self {
+        EncodingError::Invalid {
+            field,
+            reason: reason.to_string(),
+        }
+    }
+}
+
+/// A record that can be written as protobuf as well as JSON. Field numbers stay below 14, so
+/// that no protobuf value starts with `{` or `p` as JSON and the text form do.
+pub trait DiscoveryRecord: Serialize + DeserializeOwned {
+    fn to_protobuf(&self) -> Vec<u8>;
+
+    fn from_protobuf(bytes: &[u8]) -> Result<Self, EncodingError>;
+}
+
+/// `record` as the bytes to store
+pub fn encode<T: DiscoveryRecord>(
+    record: &T,
+    encoding: ValueEncoding,
+) -> Result<Vec<u8>, EncodingError> {
+    match encoding {
+        ValueEncoding::Json => Ok(serde_json::to_vec(record)?),
+        ValueEncoding::Protobuf => Ok(record.to_protobuf()),
+    }
+}
+
+/// `record` as the text to store through the key-value store API
+pub fn encode_text<T: DiscoveryRecord>(
+    record: &T,
+    encoding: ValueEncoding,
+) -> Result<String, EncodingError> {
+    match encoding {
+        ValueEncoding::Json => Ok(serde_json::to_string(record)?),
+        ValueEncoding::Protobuf => {
+            let encoded = BASE64.encode(record.to_protobuf());
+            Ok(format!("{TEXT_PREFIX}{encoded}"))
+        }
+    }
+}
+
+/// A record as stored, in any encoding
+pub fn decode<T: DiscoveryRecord>(bytes: &[u8]) -> Result<T, EncodingError> {
+    if bytes.first() == Some(&b'{') {
+        Ok(serde_json::from_slice(bytes)?)
+    } else if let Some(text) = bytes.strip_prefix(TEXT_PREFIX.as_bytes()) {
+        T::from_protobuf(&BASE64.decode(text)?)
+    } else {
+        T::from_protobuf(bytes)
+    }
+}
+
+/// Which encodings a bucket's records may be written in, see the [module docs](self)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BucketVersion {
+    pub version: u32,
+}
+
+impl Default for BucketVersion {
+    fn default() -> Self {
+        BucketVersion {
+            version: JSON_VERSION,
+        }
+    }
+}
+
+impl BucketVersion {
+    pub fn encoding(&self) -> ValueEncoding {
+        if self.version >= PROTOBUF_VERSION {
+            ValueEncoding::Protobuf
+        } else {
+            ValueEncoding::Json
+        }
+    }
+
+    /// The version of `bucket_name` in `store`, the default if it has none
+    pub async fn fetch(
+        store: &KeyValueStoreManager,
+        bucket_name: &str,
+    ) -> Result<Self, StoreError> {
+        let Some(bucket) = store.get_bucket(ENCODING_BUCKET).await? else {
+            return Ok(BucketVersion::default());
+        };
+        match bucket.get(&marker_key(bucket_name)).await? {
+            Some(value) => Ok(serde_json::from_slice(&value)?),
+            None => Ok(BucketVersion::default()),
+        }
+    }
+
+    /// The version of `bucket_name` in etcd, the default if it has none
+    pub async fn load(client: &EtcdClient, bucket_name: &str) -> crate::Result<Self> {
+        let path = format!("{ENCODING_BUCKET}/{bucket_name}");
+        match client.kv_get(path, None).await?.first() {
+            Some(kv) => Ok(serde_json::from_slice(kv.value())?),
+            None => Ok(BucketVersion::default()),
+        }
+    }
+
+    /// Set the version of `bucket_name`. Written with the store's primary lease, if it has one,
+    /// so set it from a store connected without one.
+    pub async fn set(
+        store: &KeyValueStoreManager,
+        bucket_name: &str,
+        version: u32,
+    ) -> Result<(), StoreError> {
+        let bucket = store.get_or_create_bucket(ENCODING_BUCKET, None).await?;
+        let key = marker_key(bucket_name);
+        let value = serde_json::to_string(&BucketVersion { version })?;
+        if let StoreOutcome::Exists(_) = bucket.insert_new(&key, &value).await? {
+            bucket.update_existing(&key, &value).await?;
+        }
+        tracing::info!(bucket_name, version, "Bucket version set");
+        Ok(())
+    }
+}
+
+fn marker_key(bucket_name: &str) -> Key {
+    Key::from_raw(bucket_name.to_string())
+}
+
+impl ValueEncoding {
+    /// Protobuf if every one of `buckets` allows it
+    pub async fn negotiate(
+        store: &KeyValueStoreManager,
+        buckets: &[&str],
+    ) -> Result<Self, StoreError> {
+        for bucket_name in buckets {
+            if BucketVersion::fetch(store, bucket_name).await?.encoding() == ValueEncoding::Json {
+                return Ok(ValueEncoding::Json);
+            }
+        }
+        Ok(ValueEncoding::Protobuf)
+    }
+
+    /// As [`ValueEncoding::negotiate`], for buckets kept in etcd
+    pub async fn negotiate_etcd(client: &EtcdClient, buckets: &[&str]) -> crate::Result<Self> {
+        for bucket_name in buckets {
+            if BucketVersion::load(client, bucket_name).await?.encoding() == ValueEncoding::Json {
+                return Ok(ValueEncoding::Json);
+            }
+        }
+        Ok(ValueEncoding::Protobuf)
+    }
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct InstanceProto {
+    #[prost(string, tag = "1")]
+    namespace: String,
+    #[prost(string, tag = "2")]
+    component: String,
+    #[prost(string, tag = "3")]
+    endpoint: String,
+    #[prost(uint64, tag = "4")]
+    instance_id: u64,
+    /// [`TransportType::NatsTcp`], the only transport
+    #[prost(string, tag = "5")]
+    nats_tcp: String,
+    #[prost(string, optional, tag = "6")]
+    worker_id: Option<String>,
+    /// Active, draining, deprecated
+    #[prost(int32, tag = "7")]
+    status: i32,
+    #[prost(string, optional, tag = "8")]
+    region: Option<String>,
+    /// JSON, MessagePack, bincode, protobuf. One this build doesn't know is read as JSON, as
+    /// in [`PayloadCodec::deserialize_known`].
+    #[prost(int32, tag = "9")]
+    codec: i32,
+    #[prost(string, optional, tag = "10")]
+    signature_key: Option<String>,
+    #[prost(string, optional, tag = "11")]
+    signature: Option<String>,
+}
+
+impl DiscoveryRecord for Instance {
+    fn to_protobuf(&self) -> Vec<u8> {
+        let TransportType::NatsTcp(nats_tcp) = &self.transport;
+        InstanceProto {
+            namespace: self.namespace.clone(),
+            component: self.component.clone(),
+            endpoint: self.endpoint.clone(),
+            instance_id: self.instance_id,
+            nats_tcp: nats_tcp.clone(),
+            worker_id: self.worker_id.map(|id| id.to_string()),
+            status: match self.status {
+                InstanceStatus::Active => 0,
+                InstanceStatus::Draining => 1,
+                InstanceStatus::Deprecated => 2,
+            },
+            region: self.region.clone(),
+            codec: match self.codec {
+                PayloadCodec::Json => 0,
+                PayloadCodec::MessagePack => 1,
+                PayloadCodec::Bincode => 2,
+                PayloadCodec::Protobuf => 3,
+            },
+            signature_key: self.signature.as_ref().map(|s| s.key.clone()),
+            signature: self.signature.as_ref().map(|s| s.signature.clone()),
+        }
+        .encode_to_vec()
+    }
+
+    fn from_protobuf(bytes: &[u8]) -> Result<Self, EncodingError> {
+        let proto = InstanceProto::decode(bytes)?;
+        let worker_id = proto
+            .worker_id
+            .map(|id| WorkerId::from_str(&id))
+            .transpose()
+            .map_err(|err| EncodingError::invalid("worker_id", err))?;
+        let status = match proto.status {
+            0 => InstanceStatus::Active,
+            1 => InstanceStatus::Draining,
+            2 => InstanceStatus::Deprecated,
+            other => return Err(EncodingError::invalid("status", other)),
+        };
+        let codec = match proto.codec {
+            0 => PayloadCodec::Json,
+            1 => PayloadCodec::MessagePack,
+            2 => PayloadCodec::Bincode,
+            3 => PayloadCodec::Protobuf,
+            _ => PayloadCodec::Json,
+        };
+        let signature = match (proto.signature_key, proto.signature) {
+            (Some(key), Some(signature)) => Some(InstanceSignature { key, signature }),
+            (None, None) => None,
+            _ => {
+                return Err(EncodingError::invalid(
+                    "signature",
+                    "key or signature missing",
+                ));
+            }
+        };
+        Ok(Instance {
+            component: proto.component,
+            endpoint: proto.endpoint,
+            namespace: proto.namespace,
+            instance_id: proto.instance_id,
+            transport: TransportType::NatsTcp(proto.nats_tcp),
+            worker_id,
+            status,
+            region: proto.region,
+            codec,
+            signature,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::component::test_instance;
+
+    fn instance() -> Instance {
+        Instance {
+            worker_id: Some(WorkerId::new()),
+            status: InstanceStatus::Draining,
+            codec: PayloadCodec::MessagePack,
+            ..test_instance(0x694d9a3c5b1e2f07)
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let instance = instance();
+        for encoding in [ValueEncoding::Json, ValueEncoding::Protobuf] {
+            let bytes = encode(&instance, encoding).unwrap();
+            assert_eq!(
+                decode::<Instance>(&bytes).unwrap(),
+                instance,
+                "{encoding:?}"
+            );
+            let text = encode_text(&instance, encoding).unwrap();
+            assert_eq!(
+                decode::<Instance>(text.as_bytes()).unwrap(),
+                instance,
+                "{encoding:?}"
+            );
+        }
+
+        let signed = Instance {
+            signature: Some(InstanceSignature {
+                key: "a2V5".to_string(),
+                signature: "c2ln".to_string(),
+            }),
+            region: Some("eu".to_string()),
+            ..instance.clone()
+        };
+        let bytes = encode(&signed, ValueEncoding::Protobuf).unwrap();
+        assert_eq!(decode::<Instance>(&bytes).unwrap(), signed);
+
+        // As registered before there was an encoding to choose
+        let pretty = serde_json::to_vec_pretty(&instance).unwrap();
+        assert_eq!(decode::<Instance>(&pretty).unwrap(), instance);
+    }
+
+    #[test]
+    fn test_protobuf_size() {
+        let instance = instance();
+        let json = encode(&instance, ValueEncoding::Json).unwrap();
+        let protobuf = encode(&instance, ValueEncoding::Protobuf).unwrap();
+        assert!(
+            protobuf.len() * 2 < json.len(),
+            "{} vs {}",
+            protobuf.len(),
+            json.len()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_negotiate() {
+        let store = KeyValueStoreManager::memory();
+        let buckets = ["v1/instances", "v2/discovery/instances"];
+        let negotiated = ValueEncoding::negotiate(&store, &buckets).await.unwrap();
+        assert_eq!(negotiated, ValueEncoding::Json);
+
+        BucketVersion::set(&store, buckets[0], PROTOBUF_VERSION)
+            .await
+            .unwrap();
+        let negotiated = ValueEncoding::negotiate(&store, &buckets[..1])
+            .await
+            .unwrap();
+        assert_eq!(negotiated, ValueEncoding::Protobuf);
+        // Both have to allow it
+        let negotiated = ValueEncoding::negotiate(&store, &buckets).await.unwrap();
+        assert_eq!(negotiated, ValueEncoding::Json);
+
+        BucketVersion::set(&store, buckets[1], PROTOBUF_VERSION + 1)
+            .await
+            .unwrap();
+        let negotiated = ValueEncoding::negotiate(&store, &buckets).await.unwrap();
+        assert_eq!(negotiated, ValueEncoding::Protobuf);
+        BucketVersion::set(&store, buckets[0], JSON_VERSION)
+            .await
+            .unwrap();
+        let version = BucketVersion::fetch(&store, buckets[0]).await.unwrap();
+        assert_eq!(version.encoding(), ValueEncoding::Json);
+    }
+}
@@ -8,20 +8,48 @@ use std::collections::HashMap;
 use std::fmt;
 use std::pin::Pin;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 use crate::CancellationToken;
+use crate::metrics::prometheus_names::kv_store as kv_store_metrics;
 use crate::slug::Slug;
+use crate::utils::clock::{Clock, system_clock};
+use crate::utils::tasks::supervisor::{RestartPolicy, Supervisor};
 use async_trait::async_trait;
 use futures::StreamExt;
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
 
 mod mem;
 pub use mem::MemoryStore;
 mod nats;
 pub use nats::NATSStore;
 mod etcd;
-pub use etcd::EtcdStore;
+pub use etcd::{EtcdErrorKind, EtcdStore};
+mod consul;
+pub use consul::{ConsulOptions, ConsulStore};
+mod redis;
+pub use redis::{RedisOptions, RedisStore};
+mod channel;
+use channel::WatchSender;
+pub use channel::{WatchBounds, WatchLag, WatchOverflow, WatchReceiver};
+mod fanout;
+use fanout::WatchRegistry;
+pub use fanout::{WatchFanout, WatchGap, WatchSubscriber};
+mod delta;
+pub use delta::{apply_merge_patch, merge_diff, patch_paths};
+mod dry_run;
+use dry_run::DryRunBucket;
+pub use dry_run::{DryRun, PlannedChange, PlannedOp, PlannedOutcome};
+mod integrity;
+pub use integrity::integrity_failures;
+mod limits;
+pub use limits::SizeLimits;
+mod traced;
+pub use traced::OpId;
+use traced::{TracedBucket, traced};
+pub mod bench;
 
 /// A key that is safe to use directly in the KV store.
 #[derive(Debug, Clone, PartialEq)]
@@ -62,22 +90,339 @@ impl From<&Key> for String {
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone)]
 pub struct KeyValue {
     key: String,
     value: bytes::Bytes,
+    sequence: u64,
+    delta: Option<serde_json::Value>,
+    /// The hash of the value the writer's patch was made against, and the patch, until a
+    /// [`delta::DeltaTracker`] checks it
+    published_delta: Option<(u64, bytes::Bytes)>,
+    committed_at: Option<SystemTime>,
+    received_at: Option<SystemTime>,
 }
 
 impl KeyValue {
     pub fn new(key: String, value: bytes::Bytes) -> Self {
-        KeyValue { key, value }
+        KeyValue {
+            key,
+            value,
+            sequence: 0,
+            delta: None,
+            published_delta: None,
+            committed_at: None,
+            received_at: None,
+        }
+    }
+
+    pub fn with_committed_at(mut self, committed_at: SystemTime) -> Self {
+        self.committed_at = Some(committed_at);
+        self
+    }
+
+    /// When the store committed this change, by its clock. Set by the stores that say: NATS
+    /// and the memory store, not etcd or Consul.
+    pub fn committed_at(&self) -> Option<SystemTime> {
+        self.committed_at
+    }
+
+    pub fn with_received_at(mut self, received_at: SystemTime) -> Self {
+        self.received_at = Some(received_at);
+        self
+    }
+
+    /// When a [`KeyValueStoreManager::watch`] got this change from the store
+    pub fn received_at(&self) -> Option<SystemTime> {
+        self.received_at
+    }
+
+    /// How long the change took from commit to this process, if both ends are known. Clock
+    /// skew between the store and this host is included, and None if it makes it negative.
+    pub fn propagation_latency(&self) -> Option<Duration> {
+        self.received_at?.duration_since(self.committed_at?).ok()
+    }
+
+    pub fn with_sequence(mut self, sequence: u64) -> Self {
+        self.sequence = sequence;
+        self
+    }
+
+    /// Where this change falls in the store's history: the etcd mod revision, the NATS stream
+    /// sequence, the Consul modify index, or the memory store's change counter. Never decreases
+    /// along a watch, and changes made together (one etcd transaction, several Consul deletes
+    /// seen at once) share a number. Not contiguous, as other buckets' changes use numbers too.
+    /// 0 if unknown, e.g. for values not read from a watch.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    pub fn with_delta(mut self, delta: serde_json::Value) -> Self {
+        self.delta = Some(delta);
+        self
+    }
+
+    /// On a put from [`KeyValueStoreManager::watch_with_deltas`], the merge patch from this key's
+    /// previous value to this one, as its writer published it with
+    /// [`KeyValueStoreManager::publish_delta`]. Apply it with [`apply_merge_patch`]. The full
+    /// value is still in [`KeyValue::value`].
+    pub fn delta(&self) -> Option<&serde_json::Value> {
+        self.delta.as_ref()
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn value(&self) -> &bytes::Bytes {
+        &self.value
     }
 }
 
+/// The same change seen at different times is equal
+impl PartialEq for KeyValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+            && self.value == other.value
+            && self.sequence == other.sequence
+            && self.delta == other.delta
+    }
+}
+
+/// How up to date a read has to be. Stores that keep a single copy of the data, or always read
+/// through their leader, treat every level the same.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReadConsistency {
+    /// Sees every write acknowledged before the read started. Goes through the leader, so
+    /// use it where a stale answer is a correctness bug, e.g. fencing checks.
+    #[default]
+    Linearizable,
+    /// Whatever the member that answers has applied, which may lag. Cheap; for bulk reads.
+    Serializable,
+    /// A serializable read when the answer is known to be at most this far behind, otherwise
+    /// a linearizable one
+    BoundedStaleness(Duration),
+    /// Sees at least the writes the token was taken after, even ones made through another
+    /// client or bucket. Serializable when the member that answers has caught up, otherwise
+    /// linearizable.
+    AtLeast(CausalToken),
+}
+
+/// A point in a store's history, for read-your-writes across clients and buckets.
+///
+/// Take one after writing, e.g. with [`crate::transports::etcd::Client::causal_token`], and
+/// read with [`ReadConsistency::AtLeast`] wherever the write must be visible. For etcd it is
+/// the cluster revision, which every bucket shares.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct CausalToken(u64);
+
+impl CausalToken {
+    pub fn new(revision: u64) -> Self {
+        CausalToken(revision)
+    }
+
+    pub fn revision(&self) -> u64 {
+        self.0
+    }
+
+    /// Also cover what `other` covers, e.g. when a flow writes through several clients
+    pub fn merge(self, other: CausalToken) -> CausalToken {
+        self.max(other)
+    }
+}
+
+/// Result of [`KeyValueBucket::get_if_changed`]
 #[derive(Debug, Clone, PartialEq)]
+pub enum Conditional {
+    /// The value is still at the known revision
+    NotModified,
+    /// The current value, and the revision to pass next time
+    Modified { value: bytes::Bytes, revision: u64 },
+    /// The key does not exist
+    Missing,
+}
+
+/// A lease and its fencing token, see [`crate::transports::etcd::Lease::fence_token`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fence {
+    pub lease_id: u64,
+    pub token: u64,
+}
+
+#[derive(Debug, Clone)]
 pub enum WatchEvent {
     Put(KeyValue),
     Delete(KeyValue),
+    /// The keys that existed when the watch started have all been sent, as puts. What follows
+    /// are changes. Sent once per watch, before any [`WatchEvent::Disconnected`]; stores that
+    /// don't send the existing keys send it first.
+    InitialSyncComplete,
+    /// From [`KeyValueStoreManager::watch`]: the connection to the store broke. Nothing is
+    /// delivered until [`WatchEvent::Reconnected`].
+    Disconnected,
+    /// From [`KeyValueStoreManager::watch`]: the watch is back. The changes made while it was
+    /// down follow.
+    Reconnected,
+    /// From [`KeyValueStoreManager::watch`], the last event: the watch failed in a way that
+    /// reconnecting won't fix. A bucket's watch that fails ends with it too, and the manager
+    /// reconnects if the error is transient.
+    Error(Arc<StoreError>),
+    /// From [`KeyValueStoreManager::watch`], the last event: the watch was cancelled
+    Closed,
+}
+
+impl WatchEvent {
+    /// None for the events that aren't about a key
+    pub fn key_value(&self) -> Option<&KeyValue> {
+        match self {
+            WatchEvent::Put(kv) | WatchEvent::Delete(kv) => Some(kv),
+            _ => None,
+        }
+    }
+
+    /// See [`KeyValue::sequence`]. 0 for the events that aren't about a key.
+    pub fn sequence(&self) -> u64 {
+        self.key_value().map_or(0, KeyValue::sequence)
+    }
+}
+
+/// Errors compare by message, [`StoreError`] can't be compared itself
+impl PartialEq for WatchEvent {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (WatchEvent::Put(a), WatchEvent::Put(b)) => a == b,
+            (WatchEvent::Delete(a), WatchEvent::Delete(b)) => a == b,
+            (WatchEvent::Error(a), WatchEvent::Error(b)) => a.to_string() == b.to_string(),
+            (WatchEvent::InitialSyncComplete, WatchEvent::InitialSyncComplete)
+            | (WatchEvent::Disconnected, WatchEvent::Disconnected)
+            | (WatchEvent::Reconnected, WatchEvent::Reconnected)
+            | (WatchEvent::Closed, WatchEvent::Closed) => true,
+            _ => false,
+        }
+    }
+}
+
+/// See [`WatchFilter::matching`]
+type WatchPredicate = Arc<dyn Fn(&KeyValue) -> bool + Send + Sync>;
+
+/// Which keys a [`KeyValueStoreManager::watch_filtered`] delivers. The default takes them all.
+#[derive(Clone, Default)]
+pub struct WatchFilter {
+    prefix: String,
+    predicate: Option<WatchPredicate>,
+    shard: Option<Shard>,
+}
+
+/// The part of the keys a [`WatchFilter`] is cut down to
+#[derive(Clone, Debug)]
+enum Shard {
+    /// Index and count, see [`WatchFilter::shard`]
+    Hash(usize, usize),
+    /// Start and end, see [`WatchFilter::key_range`]
+    Range(String, String),
+}
+
+impl WatchFilter {
+    /// The keys starting with `prefix`, within the bucket. Stores that can watch part of a
+    /// bucket, etcd by key range and NATS by subject, only send those.
+    pub fn prefix(prefix: impl Into<String>) -> Self {
+        WatchFilter {
+            prefix: prefix.into(),
+            predicate: None,
+            shard: None,
+        }
+    }
+
+    /// Only the puts `predicate` accepts as well. It runs in this process, on everything the
+    /// store sends.
+    pub fn matching(
+        mut self,
+        predicate: impl Fn(&KeyValue) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.predicate = Some(Arc::new(predicate));
+        self
+    }
+
+    /// Only the keys in shard `index` of `count`: the range of a hash of the key is cut into
+    /// `count` equal parts, and a key is in the shard its hash falls in. Every key is in exactly
+    /// one shard, see [`KeyValueStoreManager::watch_sharded`].
+    pub fn shard(mut self, index: usize, count: usize) -> Self {
+        assert!(index < count, "shard {index} of {count}");
+        self.shard = Some(Shard::Hash(index, count));
+        self
+    }
+
+    /// Only the keys from `start` up to but not including `end`, within the bucket, or to the
+    /// end of the bucket if `end` is empty. Stores that can watch a key range, etcd, only send
+    /// those, see [`KeyValueBucket::watch_range`].
+    pub fn key_range(mut self, start: impl Into<String>, end: impl Into<String>) -> Self {
+        self.shard = Some(Shard::Range(start.into(), end.into()));
+        self
+    }
+
+    /// Whether `kv` from `bucket_name` passes. Some stores send keys bucket-qualified.
+    fn accepts(&self, bucket_name: &str, kv: &KeyValue) -> bool {
+        let key = key_in_bucket(bucket_name, kv);
+        key.starts_with(&self.prefix)
+            && self.in_shard(key)
+            && self.predicate.as_ref().is_none_or(|accept| accept(kv))
+    }
+
+    /// Whether `kv` from `bucket_name` is in this filter's shard, cheaper than the rest
+    fn in_shard_of(&self, bucket_name: &str, kv: &KeyValue) -> bool {
+        self.in_shard(key_in_bucket(bucket_name, kv))
+    }
+
+    fn in_shard(&self, key: &str) -> bool {
+        match &self.shard {
+            None => true,
+            Some(Shard::Hash(index, count)) => {
+                let hash = xxhash_rust::xxh3::xxh3_64(key.as_bytes());
+                ((hash as u128 * *count as u128) >> 64) as usize == *index
+            }
+            Some(Shard::Range(start, end)) => {
+                key >= start.as_str() && (end.is_empty() || key < end.as_str())
+            }
+        }
+    }
+}
+
+/// The first key after all those starting with `prefix`, empty for the end of the bucket if
+/// there is none
+fn prefix_end(prefix: &str) -> String {
+    let mut end = prefix.to_string();
+    while let Some(last) = end.pop() {
+        // Skipping the surrogates, which aren't chars
+        let next = match last {
+            '\u{d7ff}' => Some('\u{e000}'),
+            last => char::from_u32(last as u32 + 1),
+        };
+        if let Some(next) = next {
+            end.push(next);
+            return end;
+        }
+    }
+    end
+}
+
+fn key_in_bucket<'a>(bucket_name: &str, kv: &'a KeyValue) -> &'a str {
+    kv.key()
+        .strip_prefix(bucket_name)
+        .and_then(|key| key.strip_prefix('/'))
+        .unwrap_or(kv.key())
+}
+
+impl fmt::Debug for WatchFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WatchFilter")
+            .field("prefix", &self.prefix)
+            .field("predicate", &self.predicate.is_some())
+            .field("shard", &self.shard)
+            .finish()
+    }
 }
 
 #[async_trait]
@@ -101,6 +446,8 @@ pub enum KeyValueStoreEnum {
     Memory(MemoryStore),
     Nats(NATSStore),
     Etcd(EtcdStore),
+    Consul(ConsulStore),
+    Redis(RedisStore),
 }
 
 impl KeyValueStoreEnum {
@@ -115,6 +462,8 @@ impl KeyValueStoreEnum {
             Memory(x) => Box::new(x.get_or_create_bucket(bucket_name, ttl).await?),
             Nats(x) => Box::new(x.get_or_create_bucket(bucket_name, ttl).await?),
             Etcd(x) => Box::new(x.get_or_create_bucket(bucket_name, ttl).await?),
+            Consul(x) => Box::new(x.get_or_create_bucket(bucket_name, ttl).await?),
+            Redis(x) => Box::new(x.get_or_create_bucket(bucket_name, ttl).await?),
         })
     }
 
@@ -136,6 +485,14 @@ impl KeyValueStoreEnum {
                 .get_bucket(bucket_name)
                 .await?
                 .map(|b| Box::new(b) as Box<dyn KeyValueBucket>),
+            Consul(x) => x
+                .get_bucket(bucket_name)
+                .await?
+                .map(|b| Box::new(b) as Box<dyn KeyValueBucket>),
+            Redis(x) => x
+                .get_bucket(bucket_name)
+                .await?
+                .map(|b| Box::new(b) as Box<dyn KeyValueBucket>),
         };
         Ok(maybe_bucket)
     }
@@ -146,163 +503,1360 @@ impl KeyValueStoreEnum {
             Memory(x) => x.connection_id(),
             Etcd(x) => x.connection_id(),
             Nats(x) => x.connection_id(),
+            Consul(x) => x.connection_id(),
+            Redis(x) => x.connection_id(),
         }
     }
 }
 
+/// What [`KeyValueStoreManager::publish`] may do to an existing key, or a missing one
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PublishMode {
+    /// Fail with [`StoreError::AlreadyExists`] if the key exists, e.g. for registrations that
+    /// must be unique
+    CreateOnly,
+    /// Fail with [`StoreError::MissingKey`] if the key doesn't exist, so a deleted entry isn't
+    /// brought back
+    UpdateOnly,
+    /// Create the key or overwrite it
+    #[default]
+    Upsert,
+    /// Write only if the key is still at the revision the value was read or last published
+    /// at, or doesn't exist for revision 0. Otherwise fail with [`StoreError::Retry`], so a
+    /// writer that lost a race doesn't overwrite the winner.
+    CompareAndSwap,
+}
+
+/// Wait before trying to restore a broken [`KeyValueStoreManager::watch`], doubled after each
+/// failed try
+const WATCH_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const WATCH_MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// How long [`KeyValueStoreManager`] trusts what it found out about a bucket. Another process
+/// creating or deleting it is noticed at most this late.
+const BUCKET_CACHE_TTL: Duration = Duration::from_secs(1);
+
+/// Events a [`KeyValueStoreManager::subscribe`] subscriber can fall behind by before it
+/// misses some
+const SHARED_WATCH_CAPACITY: usize = 1024;
+
+static WATCH_PROPAGATION: Lazy<prometheus::Histogram> = Lazy::new(|| {
+    let opts = prometheus::HistogramOpts::new(
+        kv_store_metrics::WATCH_PROPAGATION_SECONDS,
+        "Seconds from a store commit to its arrival at a watch in this process",
+    )
+    .buckets(vec![
+        0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+    ]);
+    prometheus::Histogram::with_opts(opts).expect("valid histogram options")
+});
+
+/// [`KeyValue::propagation_latency`] of every change delivered by a
+/// [`KeyValueStoreManager::watch`] in this process, for the stores that timestamp commits.
+/// The distributed runtime exports it.
+pub fn watch_propagation() -> prometheus::Histogram {
+    WATCH_PROPAGATION.clone()
+}
+
+/// How often [`KeyValueStoreManager::update`] tries before giving up on a contended key, and
+/// how long it waits after the first lost race, doubled after each
+const UPDATE_MAX_ATTEMPTS: u32 = 8;
+const UPDATE_INITIAL_BACKOFF: Duration = Duration::from_millis(10);
+
 #[derive(Clone)]
-pub struct KeyValueStoreManager(Arc<KeyValueStoreEnum>);
+pub struct KeyValueStoreManager {
+    store: Arc<KeyValueStoreEnum>,
+    bucket_cache: Arc<BucketCache>,
+    /// The shared watches of [`KeyValueStoreManager::subscribe`]
+    watches: Arc<WatchRegistry>,
+    options: BucketOptions,
+    /// See [`KeyValueStoreManager::with_dry_run`]
+    dry_run: Option<Arc<DryRun>>,
+    /// Runs the watch tasks, see [`KeyValueStoreManager::with_supervisor`]
+    supervisor: Supervisor,
+}
 
 impl Default for KeyValueStoreManager {
     fn default() -> Self {
         KeyValueStoreManager::memory()
     }
-}
+}
+
+impl KeyValueStoreManager {
+    /// In-memory KeyValueStoreManager for testing
+    pub fn memory() -> Self {
+        Self::new(KeyValueStoreEnum::Memory(MemoryStore::new()))
+    }
+
+    /// In-memory store shared with other managers holding the same `MemoryStore`
+    pub fn shared_memory(store: MemoryStore) -> Self {
+        Self::new(KeyValueStoreEnum::Memory(store))
+    }
+
+    pub fn etcd(etcd_client: crate::transports::etcd::Client) -> Self {
+        Self::new(KeyValueStoreEnum::Etcd(EtcdStore::new(etcd_client)))
+    }
+
+    pub fn nats(
+        nats_client: crate::transports::nats::Client,
+        endpoint: crate::protocols::EndpointId,
+    ) -> Self {
+        Self::new(KeyValueStoreEnum::Nats(NATSStore::new(
+            nats_client,
+            endpoint,
+        )))
+    }
+
+    pub fn consul(consul_store: ConsulStore) -> Self {
+        Self::new(KeyValueStoreEnum::Consul(consul_store))
+    }
+
+    pub fn redis(redis_store: RedisStore) -> Self {
+        Self::new(KeyValueStoreEnum::Redis(redis_store))
+    }
+
+    fn new(s: KeyValueStoreEnum) -> KeyValueStoreManager {
+        let options = BucketOptions {
+            limits: SizeLimits::of(&s),
+            content_hashes: false,
+        };
+        KeyValueStoreManager {
+            store: Arc::new(s),
+            bucket_cache: Arc::new(BucketCache::new(system_clock())),
+            watches: Arc::new(WatchRegistry::default()),
+            options,
+            dry_run: None,
+            supervisor: Supervisor::new("store", CancellationToken::new(), None),
+        }
+    }
+
+    /// Run the watch tasks under `supervisor`, which restarts them if they panic and stops them
+    /// when its token is cancelled. Without one they run under a supervisor of their own, which
+    /// nothing cancels.
+    pub fn with_supervisor(self, supervisor: Supervisor) -> Self {
+        KeyValueStoreManager { supervisor, ..self }
+    }
+
+    /// Expire what it found out about buckets by `clock` instead of tokio's, and date the
+    /// records written through it by the same clock. Forgets what it found out so far.
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        KeyValueStoreManager {
+            bucket_cache: Arc::new(BucketCache::new(clock)),
+            ..self
+        }
+    }
+
+    /// The clock set with [`KeyValueStoreManager::with_clock`], the system's by default
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.bucket_cache.clock
+    }
+
+    /// Refuse writes beyond `limits` instead of those of the backend's default configuration,
+    /// for a server configured otherwise
+    pub fn with_size_limits(mut self, limits: SizeLimits) -> Self {
+        self.options.limits = limits;
+        self
+    }
+
+    pub fn size_limits(&self) -> SizeLimits {
+        self.options.limits
+    }
+
+    /// Evaluate writes against the store instead of making them, see [`DryRun`]. Each call
+    /// starts a new dry run.
+    pub fn with_dry_run(mut self) -> Self {
+        self.dry_run = Some(Arc::new(DryRun::default()));
+        self
+    }
+
+    /// What the writes so far would have done, None unless [`Self::with_dry_run`]
+    pub fn dry_run(&self) -> Option<&DryRun> {
+        self.dry_run.as_deref()
+    }
+
+    /// Write values behind a hash of their content, see [`integrity_failures`]. Values that
+    /// have one are checked on reading either way.
+    pub fn with_content_hashes(mut self) -> Self {
+        self.options.content_hashes = true;
+        self
+    }
+
+    /// A bucket looked up in the last [`BUCKET_CACHE_TTL`] is not looked up again, so `ttl`
+    /// only matters when creating it.
+    pub async fn get_or_create_bucket(
+        &self,
+        bucket_name: &str,
+        // auto-delete items older than this
+        ttl: Option<Duration>,
+    ) -> Result<Box<dyn KeyValueBucket>, StoreError> {
+        if let Some(dry_run) = &self.dry_run {
+            // Creating it is one of the changes, and not made either
+            let bucket = self.lookup(bucket_name).await?;
+            if bucket.is_none() {
+                dry_run.create_bucket(bucket_name);
+            }
+            return Ok(self.dry_run_bucket(bucket_name, bucket, dry_run));
+        }
+        if let Some(Some(bucket)) = self.bucket_cache.get(bucket_name) {
+            return Ok(Box::new(TracedBucket::new(
+                bucket_name,
+                bucket,
+                self.options,
+            )));
+        }
+        let created = self.store.get_or_create_bucket(bucket_name, ttl);
+        let bucket: Arc<dyn KeyValueBucket> =
+            Arc::from(traced("get_or_create_bucket", bucket_name, created).await?);
+        self.bucket_cache.insert(bucket_name, Some(bucket.clone()));
+        Ok(Box::new(TracedBucket::new(
+            bucket_name,
+            bucket,
+            self.options,
+        )))
+    }
+
+    /// Cached for [`BUCKET_CACHE_TTL`], including the answer that there is no such bucket
+    pub async fn get_bucket(
+        &self,
+        bucket_name: &str,
+    ) -> Result<Option<Box<dyn KeyValueBucket>>, StoreError> {
+        let bucket = self.lookup(bucket_name).await?;
+        if let Some(dry_run) = &self.dry_run
+            && (bucket.is_some() || dry_run.has_bucket(bucket_name))
+        {
+            return Ok(Some(self.dry_run_bucket(bucket_name, bucket, dry_run)));
+        }
+        Ok(bucket.map(|b| {
+            Box::new(TracedBucket::new(bucket_name, b, self.options)) as Box<dyn KeyValueBucket>
+        }))
+    }
+
+    async fn lookup(
+        &self,
+        bucket_name: &str,
+    ) -> Result<Option<Arc<dyn KeyValueBucket>>, StoreError> {
+        if let Some(cached) = self.bucket_cache.get(bucket_name) {
+            return Ok(cached);
+        }
+        let found = traced(
+            "get_bucket",
+            bucket_name,
+            self.store.get_bucket(bucket_name),
+        );
+        let bucket = found.await?.map(Arc::from);
+        self.bucket_cache.insert(bucket_name, bucket.clone());
+        Ok(bucket)
+    }
+
+    fn dry_run_bucket(
+        &self,
+        bucket_name: &str,
+        bucket: Option<Arc<dyn KeyValueBucket>>,
+        dry_run: &Arc<DryRun>,
+    ) -> Box<dyn KeyValueBucket> {
+        let bucket = Arc::new(DryRunBucket::new(bucket_name, bucket, dry_run.clone()));
+        Box::new(TracedBucket::new(bucket_name, bucket, self.options))
+    }
+
+    pub fn connection_id(&self) -> u64 {
+        self.store.connection_id()
+    }
+
+    pub async fn load<T: for<'a> Deserialize<'a>>(
+        &self,
+        bucket_name: &str,
+        key: &Key,
+    ) -> Result<Option<T>, StoreError> {
+        let Some(bucket) = self.get_bucket(bucket_name).await? else {
+            // No bucket means no cards
+            return Ok(None);
+        };
+        let card_bytes = match bucket.get(key).await {
+            Ok(card_bytes) => card_bytes,
+            Err(StoreError::MissingBucket(_)) => {
+                // Deleted since it was cached
+                self.bucket_cache.forget(bucket_name);
+                None
+            }
+            Err(err) => return Err(err),
+        };
+        Ok(match card_bytes {
+            Some(card_bytes) => {
+                let card: T = serde_json::from_slice(card_bytes.as_ref())?;
+                Some(card)
+            }
+            None => None,
+        })
+    }
+
+    /// Returns a receiver that will receive all the existing keys, and
+    /// then block and receive new keys as they are created.
+    /// Starts a task that watches the store until `cancel_token` is cancelled, when the
+    /// receiver gets [`WatchEvent::Closed`], or it fails for good, when the receiver gets
+    /// [`WatchEvent::Error`]. Either is the last event.
+    ///
+    /// When the watch breaks, the receiver gets [`WatchEvent::Disconnected`] and the task
+    /// reconnects with backoff. After [`WatchEvent::Reconnected`] come the changes it missed:
+    /// replayed from the last delivered [`KeyValue::sequence`] by stores that can, otherwise
+    /// the difference between the entries it delivered and the bucket's current ones.
+    pub fn watch(
+        self: Arc<Self>,
+        bucket_name: &str,
+        bucket_ttl: Option<Duration>,
+        cancel_token: CancellationToken,
+    ) -> tokio::sync::mpsc::UnboundedReceiver<WatchEvent> {
+        self.watch_filtered(
+            bucket_name,
+            bucket_ttl,
+            WatchFilter::default(),
+            cancel_token,
+        )
+    }
+
+    /// Like [`KeyValueStoreManager::watch`], but only for the keys `filter` accepts, so a
+    /// consumer interested in one component doesn't get the churn of the whole bucket.
+    ///
+    /// A put the filter no longer accepts, of a key that it did, is delivered as a delete.
+    pub fn watch_filtered(
+        self: Arc<Self>,
+        bucket_name: &str,
+        bucket_ttl: Option<Duration>,
+        filter: WatchFilter,
+        cancel_token: CancellationToken,
+    ) -> tokio::sync::mpsc::UnboundedReceiver<WatchEvent> {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let tx = WatchSender::Unbounded(tx);
+        self.spawn_watch(bucket_name, bucket_ttl, filter, cancel_token, tx);
+        rx
+    }
+
+    /// Like [`KeyValueStoreManager::watch`], but queueing at most `bounds.capacity` events for
+    /// a receiver that falls behind, then doing what `bounds.overflow` says
+    pub fn watch_bounded(
+        self: Arc<Self>,
+        bucket_name: &str,
+        bucket_ttl: Option<Duration>,
+        bounds: WatchBounds,
+        cancel_token: CancellationToken,
+    ) -> WatchReceiver {
+        let (tx, rx) = channel::channel(bounds);
+        self.spawn_watch(
+            bucket_name,
+            bucket_ttl,
+            WatchFilter::default(),
+            cancel_token,
+            tx,
+        );
+        rx
+    }
+
+    /// Like [`KeyValueStoreManager::watch_filtered`], but split over `shards` store watches,
+    /// each pumped by its own task, for prefixes that change faster than one task can take in.
+    ///
+    /// The keys under the prefix are cut into `shards` [key ranges](WatchFilter::key_range) of
+    /// about as many keys each, by the keys there are when the watch starts. Stores that can
+    /// watch a key range, etcd, only send each watch the changes in its range, so the store
+    /// sends each change once. Others send every watch all of them, and each skips those of
+    /// other shards before doing anything else with them. The ranges stay as they were cut, so
+    /// keys added later in one part of the key space all go to the same shard. With fewer keys
+    /// than shards there is nothing to cut at, and the keys are split by a
+    /// [hash](WatchFilter::shard) instead, every watch being sent all the changes.
+    ///
+    /// Changes to a key arrive in order, as one shard has all of them, but changes to different
+    /// keys may not arrive in the order they were made. The shards' other events are merged:
+    /// [`WatchEvent::InitialSyncComplete`] once they have all synced, [`WatchEvent::Disconnected`]
+    /// when the first one disconnects and [`WatchEvent::Reconnected`] when the last one is back,
+    /// and [`WatchEvent::Closed`] when all have closed. The first [`WatchEvent::Error`] ends
+    /// them all.
+    pub fn watch_sharded(
+        self: Arc<Self>,
+        bucket_name: &str,
+        bucket_ttl: Option<Duration>,
+        filter: WatchFilter,
+        shards: usize,
+        cancel_token: CancellationToken,
+    ) -> tokio::sync::mpsc::UnboundedReceiver<WatchEvent> {
+        if shards <= 1 {
+            return self.watch_filtered(bucket_name, bucket_ttl, filter, cancel_token);
+        }
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let bucket_name = bucket_name.to_string();
+        // Kept across restarts, so a merge restarted after a panic picks up where it was
+        let merge = Arc::new(tokio::sync::Mutex::new(None));
+        let supervisor = self.supervisor.clone();
+        let name = format!("watch {bucket_name} shards");
+        supervisor.spawn(name, RestartPolicy::default(), move || {
+            let this = self.clone();
+            let bucket_name = bucket_name.clone();
+            let filter = filter.clone();
+            let cancel_token = cancel_token.clone();
+            let tx = tx.clone();
+            let merge = merge.clone();
+            async move {
+                let mut merge = merge.lock().await;
+                if merge.is_none() {
+                    let filters = this.shard_filters(&bucket_name, bucket_ttl, filter, shards);
+                    let filters = filters.await;
+                    let shard_token = cancel_token.child_token();
+                    let streams = filters.into_iter().enumerate().map(|(index, filter)| {
+                        let token = shard_token.clone();
+                        let this = this.clone();
+                        let rx = this.watch_filtered(&bucket_name, bucket_ttl, filter, token);
+                        let rx = tokio_stream::wrappers::UnboundedReceiverStream::new(rx);
+                        rx.map(move |event| (index, event))
+                    });
+                    *merge = Some(ShardMerge {
+                        merged: futures::stream::select_all(streams),
+                        tx,
+                        syncing: shards,
+                        open: shards,
+                        disconnected: vec![false; shards],
+                        shard_token,
+                    });
+                }
+                if let Some(merge) = merge.as_mut() {
+                    merge.run().await;
+                }
+                Ok(())
+            }
+        });
+        rx
+    }
+
+    /// `filter` cut into `shards` key ranges at the keys under its prefix, see
+    /// [`KeyValueStoreManager::watch_sharded`]
+    async fn shard_filters(
+        &self,
+        bucket_name: &str,
+        bucket_ttl: Option<Duration>,
+        filter: WatchFilter,
+        shards: usize,
+    ) -> Vec<WatchFilter> {
+        let entries = match self
+            .store
+            .get_or_create_bucket(bucket_name, bucket_ttl)
+            .await
+        {
+            Ok(bucket) => bucket.entries_prefix(&filter.prefix).await,
+            Err(err) => Err(err),
+        };
+        let mut keys: Vec<String> = match entries {
+            Ok(entries) => entries
+                .into_keys()
+                .map(|key| KeyValue::new(key, bytes::Bytes::new()))
+                .map(|kv| key_in_bucket(bucket_name, &kv).to_string())
+                .filter(|key| key.starts_with(&filter.prefix))
+                .collect(),
+            Err(err) => {
+                tracing::warn!(bucket_name, %err, "Can't read the keys to shard by, hashing them");
+                Vec::new()
+            }
+        };
+        keys.sort();
+        if keys.len() < shards {
+            return (0..shards)
+                .map(|index| filter.clone().shard(index, shards))
+                .collect();
+        }
+        // Shard `index` starts at the key `index / shards` of the way through
+        let mut starts: Vec<String> = (0..shards)
+            .map(|index| keys[keys.len() * index / shards].clone())
+            .collect();
+        starts[0] = filter.prefix.clone();
+        // The last shard ends where the prefix does, not at the end of the bucket
+        starts.push(prefix_end(&filter.prefix));
+        (0..shards)
+            .map(|index| {
+                filter
+                    .clone()
+                    .key_range(starts[index].clone(), starts[index + 1].clone())
+            })
+            .collect()
+    }
+
+    fn spawn_watch(
+        self: Arc<Self>,
+        bucket_name: &str,
+        bucket_ttl: Option<Duration>,
+        filter: WatchFilter,
+        cancel_token: CancellationToken,
+        tx: WatchSender,
+    ) {
+        let bucket_name = bucket_name.to_string();
+        let state = WatchState {
+            filter,
+            ..Default::default()
+        };
+        // Kept across restarts, so a watch restarted after a panic picks up where it was
+        let watch = Arc::new(tokio::sync::Mutex::new((state, tx)));
+        let supervisor = self.supervisor.clone();
+        supervisor.spawn(
+            format!("watch {bucket_name}"),
+            RestartPolicy::default(),
+            move || {
+                let this = self.clone();
+                let bucket_name = bucket_name.clone();
+                let cancel_token = cancel_token.clone();
+                let watch = watch.clone();
+                async move {
+                    let mut watch = watch.lock().await;
+                    let (state, tx) = &mut *watch;
+                    this.run_watch(&bucket_name, bucket_ttl, state, tx, &cancel_token)
+                        .await;
+                    Ok(())
+                }
+            },
+        );
+    }
+
+    /// Pump the store's watch into `tx` until cancelled or the receiver is gone, reconnecting
+    /// with backoff, then tell the receiver why it ended
+    async fn run_watch(
+        &self,
+        bucket_name: &str,
+        bucket_ttl: Option<Duration>,
+        state: &mut WatchState,
+        tx: &WatchSender,
+        cancel_token: &CancellationToken,
+    ) {
+        let mut backoff = WATCH_INITIAL_BACKOFF;
+        let last = loop {
+            let pumped = tokio::select! {
+                _ = cancel_token.cancelled() => break WatchEvent::Closed,
+                pumped = self.pump_watch(bucket_name, bucket_ttl, state, tx) => pumped,
+            };
+            if tx.is_closed() {
+                return;
+            }
+            match pumped {
+                Ok(()) => {
+                    tracing::warn!(bucket = %bucket_name, ?backoff, "Store watch ended");
+                }
+                Err(err) if err.is_transient() => {
+                    tracing::warn!(bucket = %bucket_name, %err, ?backoff, "Store watch failed");
+                }
+                Err(err) => {
+                    tracing::error!(bucket = %bucket_name, %err, "Store watch failed for good");
+                    break WatchEvent::Error(Arc::new(err));
+                }
+            }
+            if state.connected {
+                state.connected = false;
+                backoff = WATCH_INITIAL_BACKOFF;
+                tx.send(WatchEvent::Disconnected).await;
+            }
+            tokio::select! {
+                _ = cancel_token.cancelled() => break WatchEvent::Closed,
+                _ = tokio::time::sleep(backoff) => {}
+            }
+            backoff = (backoff * 2).min(WATCH_MAX_BACKOFF);
+        };
+        tx.send(last).await;
+    }
+
+    /// Like [`KeyValueStoreManager::watch`], but shared: subscribers to the same bucket through
+    /// this manager or its clones use one store watch, stopped when the last one is dropped.
+    /// Each has its own buffer of [`SHARED_WATCH_CAPACITY`] events, and one that falls further
+    /// behind gets a [`WatchGap`] instead of holding up the others.
+    ///
+    /// Every subscriber gets the bucket's entries as puts then
+    /// [`WatchEvent::InitialSyncComplete`], like `watch`: the first from the store, later ones
+    /// from what the shared watch has seen so far. `bucket_ttl` only matters to the first.
+    pub fn subscribe(&self, bucket_name: &str, bucket_ttl: Option<Duration>) -> WatchSubscriber {
+        let manager = Arc::new(self.clone());
+        let bucket = bucket_name.to_string();
+        self.watches
+            .subscribe(bucket_name, SHARED_WATCH_CAPACITY, move |cancel_token| {
+                let rx = manager.watch(&bucket, bucket_ttl, cancel_token);
+                tokio_stream::wrappers::UnboundedReceiverStream::new(rx)
+            })
+    }
+
+    /// One connection of [`KeyValueStoreManager::watch`]: catch up with what changed since the
+    /// last one, then deliver changes until the bucket's watch ends
+    async fn pump_watch(
+        &self,
+        bucket_name: &str,
+        bucket_ttl: Option<Duration>,
+        state: &mut WatchState,
+        tx: &WatchSender,
+    ) -> Result<(), StoreError> {
+        let bucket = self
+            .store
+            .get_or_create_bucket(bucket_name, bucket_ttl)
+            .await?;
+        let resumed = match state.last_sequence {
+            0 => None,
+            sequence => bucket.watch_from(sequence).await?,
+        };
+        let replaying = resumed.is_some();
+        let (mut stream, entries) = match resumed {
+            Some(stream) => (stream, None),
+            // Start listening for changes but don't poll this yet
+            None => {
+                let stream = match (&state.filter.shard, state.filter.prefix.as_str()) {
+                    (Some(Shard::Range(start, end)), _) => bucket.watch_range(start, end).await?,
+                    (_, "") => bucket.watch().await?,
+                    (_, prefix) => bucket.watch_prefix(prefix).await?,
+                };
+                let mut entries = bucket.entries().await?;
+                entries.retain(|key, value| {
+                    // Logged and counted, and the watch shouldn't fail for good over it
+                    let opened = integrity::open(key, value.clone())
+                        .and_then(|value| delta::strip(key, value));
+                    let Ok(opened) = opened else {
+                        return false;
+                    };
+                    *value = opened;
+                    let kv = KeyValue::new(key.clone(), value.clone());
+                    state.filter.accepts(bucket_name, &kv)
+                });
+                (stream, Some(entries))
+            }
+        };
+
+        if state.started {
+            tx.send(WatchEvent::Reconnected).await;
+        }
+        let first = !state.started;
+        state.started = true;
+        state.connected = true;
+        // Until its marker, the bucket's watch sends the keys we read, if it sends any
+        let mut bucket_syncing = entries.is_some();
+        if let Some(entries) = entries {
+            state.resync(entries, tx).await;
+        }
+        if first {
+            tx.send(WatchEvent::InitialSyncComplete).await;
+        }
+
+        // Now block waiting for new entries
+        let mut delivered = false;
+        while let Some(event) = stream.next().await {
+            if let WatchEvent::Error(err) = event {
+                // Whether to reconnect is for `run_watch` to say
+                return Err(Arc::try_unwrap(err)
+                    .unwrap_or_else(|err| StoreError::provider(err.to_string())));
+            }
+            if event == WatchEvent::InitialSyncComplete {
+                bucket_syncing = false;
+                continue;
+            }
+            if bucket_syncing {
+                continue;
+            }
+            if let WatchEvent::Put(kv) | WatchEvent::Delete(kv) = &event
+                && !state.filter.in_shard_of(bucket_name, kv)
+            {
+                // Another shard's, never known here
+                continue;
+            }
+            let Some(event) = integrity::open_event(event).and_then(delta::open_event) else {
+                continue;
+            };
+            let Some(event) = state.admit(bucket_name, event) else {
+                continue;
+            };
+            let event = self.stamp(event);
+            delivered = true;
+            state.record(&event);
+            if !tx.send(event).await {
+                break;
+            }
+        }
+        if replaying && !delivered {
+            // Maybe the store can't replay from there any more, e.g. etcd compacted it away.
+            // Read the entries next time instead.
+            state.last_sequence = 0;
+        }
+        Ok(())
+    }
+
+    /// Mark a change from the store with when it arrived, and record its propagation latency
+    fn stamp(&self, event: WatchEvent) -> WatchEvent {
+        let received_at = self.bucket_cache.clock.system_time();
+        let stamped = |kv: KeyValue| {
+            let kv = kv.with_received_at(received_at);
+            if let Some(latency) = kv.propagation_latency() {
+                WATCH_PROPAGATION.observe(latency.as_secs_f64());
+            }
+            kv
+        };
+        match event {
+            WatchEvent::Put(kv) => WatchEvent::Put(stamped(kv)),
+            WatchEvent::Delete(kv) => WatchEvent::Delete(stamped(kv)),
+            event => event,
+        }
+    }
+
+    /// Like [`KeyValueStoreManager::watch_filtered`], but puts written with
+    /// [`KeyValueStoreManager::publish_delta`] carry a [`KeyValue::delta`] against the key's
+    /// previous value, when the watch saw the value the writer made it against
+    pub fn watch_with_deltas(
+        self: Arc<Self>,
+        bucket_name: &str,
+        bucket_ttl: Option<Duration>,
+        filter: WatchFilter,
+        cancel_token: CancellationToken,
+    ) -> tokio::sync::mpsc::UnboundedReceiver<WatchEvent> {
+        let name = format!("watch {bucket_name} deltas");
+        let full_rx = self
+            .clone()
+            .watch_filtered(bucket_name, bucket_ttl, filter, cancel_token);
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        // Kept across restarts, so a tracker restarted after a panic picks up where it was
+        let watch = Arc::new(tokio::sync::Mutex::new((
+            delta::DeltaTracker::default(),
+            full_rx,
+        )));
+        self.supervisor
+            .spawn(name, RestartPolicy::default(), move || {
+                let tx = tx.clone();
+                let watch = watch.clone();
+                async move {
+                    let mut watch = watch.lock().await;
+                    let (tracker, full_rx) = &mut *watch;
+                    while let Some(event) = full_rx.recv().await {
+                        if tx.send(tracker.annotate(event)).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(())
+                }
+            });
+        rx
+    }
+
+    /// Write `obj` as JSON, as `mode` allows, and set its revision to the stored one. Only
+    /// [`PublishMode::CompareAndSwap`] compares against the revision `obj` carries.
+    pub async fn publish<T: Serialize + Versioned + Send + Sync>(
+        &self,
+        bucket_name: &str,
+        bucket_ttl: Option<Duration>,
+        key: &Key,
+        obj: &mut T,
+        mode: PublishMode,
+    ) -> anyhow::Result<StoreOutcome> {
+        let obj_json = serde_json::to_string(obj)?;
+        let outcome = self
+            .publish_at(
+                bucket_name,
+                bucket_ttl,
+                key,
+                &obj_json,
+                obj.revision(),
+                mode,
+            )
+            .await?;
+        match outcome {
+            StoreOutcome::Created(revision) | StoreOutcome::Exists(revision) => {
+                obj.set_revision(revision);
+            }
+        }
+        Ok(outcome)
+    }
+
+    /// Like [`KeyValueStoreManager::publish`], but also write the merge patch from `previous`,
+    /// the value this writer last published at `key`, for
+    /// [`KeyValueStoreManager::watch_with_deltas`]. Only worth it for large values; a change a
+    /// patch cannot express is written plain.
+    pub async fn publish_delta<T: Serialize + Versioned + Send + Sync>(
+        &self,
+        bucket_name: &str,
+        bucket_ttl: Option<Duration>,
+        key: &Key,
+        obj: &mut T,
+        previous: &T,
+        mode: PublishMode,
+    ) -> anyhow::Result<StoreOutcome> {
+        let obj_json = serde_json::to_string(obj)?;
+        let previous_json = serde_json::to_string(previous)?;
+        let patch = merge_diff(
+            &serde_json::to_value(previous)?,
+            &serde_json::to_value(&*obj)?,
+        );
+        let value = match patch {
+            Some(patch) => delta::frame(&previous_json, &patch, &obj_json),
+            None => obj_json,
+        };
+        let outcome = self
+            .publish_at(bucket_name, bucket_ttl, key, &value, obj.revision(), mode)
+            .await?;
+        match outcome {
+            StoreOutcome::Created(revision) | StoreOutcome::Exists(revision) => {
+                obj.set_revision(revision);
+            }
+        }
+        Ok(outcome)
+    }
+
+    /// Write `obj_json`, already serialized in whatever encoding, as `mode` allows. With no
+    /// revision to compare against, [`PublishMode::CompareAndSwap`] only creates the key.
+    pub async fn publish_value(
+        &self,
+        bucket_name: &str,
+        bucket_ttl: Option<Duration>,
+        key: &Key,
+        obj_json: &str,
+        mode: PublishMode,
+    ) -> anyhow::Result<StoreOutcome> {
+        self.publish_at(bucket_name, bucket_ttl, key, obj_json, 0, mode)
+            .await
+    }
+
+    /// Write `value` as `mode` allows, comparing against `revision` for
+    /// [`PublishMode::CompareAndSwap`]
+    async fn publish_at(
+        &self,
+        bucket_name: &str,
+        bucket_ttl: Option<Duration>,
+        key: &Key,
+        value: &str,
+        revision: u64,
+        mode: PublishMode,
+    ) -> anyhow::Result<StoreOutcome> {
+        let bucket = self.get_or_create_bucket(bucket_name, bucket_ttl).await?;
+        let outcome = match mode {
+            PublishMode::CompareAndSwap => bucket.compare_and_swap(key, value, revision).await?,
+            PublishMode::CreateOnly => match bucket.compare_and_swap(key, value, 0).await {
+                Err(StoreError::Retry) => {
+                    return Err(StoreError::AlreadyExists(key.to_string()).into());
+                }
+                outcome => outcome?,
+            },
+            PublishMode::UpdateOnly => bucket.update_existing(key, value).await?,
+            PublishMode::Upsert => bucket.upsert(key, value).await?,
+        };
+        Ok(outcome)
+    }
+
+    /// Like [`KeyValueStoreManager::publish`], but the write only happens if `fence` is still
+    /// current: its lease is alive and is the one that took the token. Otherwise fails with
+    /// [`StoreError::Fenced`]. Overwrites any existing value.
+    pub async fn publish_fenced<T: Serialize + Versioned + Send + Sync>(
+        &self,
+        bucket_name: &str,
+        bucket_ttl: Option<Duration>,
+        key: &Key,
+        obj: &mut T,
+        fence: Fence,
+    ) -> anyhow::Result<StoreOutcome> {
+        let obj_json = serde_json::to_string(obj)?;
+        let bucket = self.get_or_create_bucket(bucket_name, bucket_ttl).await?;
+
+        let outcome = bucket.insert_fenced(key, &obj_json, fence).await?;
+
+        match outcome {
+            StoreOutcome::Created(revision) | StoreOutcome::Exists(revision) => {
+                obj.set_revision(revision);
+            }
+        }
+        Ok(outcome)
+    }
+
+    /// Read `key` as JSON, change it with `f` and write it back, unless someone else wrote it
+    /// in between, in which case start over from their value. `f` gets None if the key doesn't
+    /// exist, and may be called several times. Fails with [`StoreError::Retry`] if every try
+    /// lost a race. Returns what was written.
+    pub async fn update<T, F>(&self, bucket_name: &str, key: &Key, mut f: F) -> anyhow::Result<T>
+    where
+        T: Serialize + for<'a> Deserialize<'a> + Send,
+        F: FnMut(Option<T>) -> anyhow::Result<T> + Send,
+    {
+        let bucket = self.get_or_create_bucket(bucket_name, None).await?;
+        let mut backoff = UPDATE_INITIAL_BACKOFF;
+        for attempt in 1..=UPDATE_MAX_ATTEMPTS {
+            let (current, revision) = match bucket.get_if_changed(key, 0).await? {
+                Conditional::Modified { value, revision } => {
+                    (Some(serde_json::from_slice(&value)?), revision)
+                }
+                _ => (None, 0),
+            };
+            let updated = f(current)?;
+            let obj_json = serde_json::to_string(&updated)?;
+            match bucket.compare_and_swap(key, &obj_json, revision).await {
+                Ok(_) => return Ok(updated),
+                Err(StoreError::Retry) if attempt < UPDATE_MAX_ATTEMPTS => {
+                    tracing::debug!(%key, attempt, ?backoff, "Lost an update race, retrying");
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Err(StoreError::Retry.into())
+    }
+}
+
+/// What the buckets handed out by a [`KeyValueStoreManager`] do on top of the store's
+#[derive(Debug, Clone, Copy, Default)]
+struct BucketOptions {
+    limits: SizeLimits,
+    /// See [`KeyValueStoreManager::with_content_hashes`]
+    content_hashes: bool,
+}
+
+/// A bucket looked up at that time, None if it didn't exist
+type CachedBucket = (Instant, Option<Arc<dyn KeyValueBucket>>);
+
+/// Buckets [`KeyValueStoreManager`] looked up recently, None for those that didn't exist
+struct BucketCache {
+    entries: parking_lot::Mutex<HashMap<String, CachedBucket>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl BucketCache {
+    fn new(clock: Arc<dyn Clock>) -> Self {
+        BucketCache {
+            entries: Default::default(),
+            clock,
+        }
+    }
+
+    /// None if not looked up in the last [`BUCKET_CACHE_TTL`]
+    fn get(&self, bucket_name: &str) -> Option<Option<Arc<dyn KeyValueBucket>>> {
+        let mut entries = self.entries.lock();
+        let (at, bucket) = entries.get(bucket_name)?;
+        if self.clock.now().duration_since(*at) < BUCKET_CACHE_TTL {
+            return Some(bucket.clone());
+        }
+        entries.remove(bucket_name);
+        None
+    }
+
+    fn insert(&self, bucket_name: &str, bucket: Option<Arc<dyn KeyValueBucket>>) {
+        let mut entries = self.entries.lock();
+        // Expired ones are only dropped on lookup, so clear out the rest now and then
+        let now = self.clock.now();
+        if entries.len() >= 1024 {
+            entries.retain(|_, (at, _)| now.duration_since(*at) < BUCKET_CACHE_TTL);
+        }
+        entries.insert(bucket_name.to_string(), (now, bucket));
+    }
+
+    fn forget(&self, bucket_name: &str) {
+        self.entries.lock().remove(bucket_name);
+    }
+}
+
+/// Where the merge of [`KeyValueStoreManager::watch_sharded`]'s shards is at
+struct ShardMerge<S> {
+    /// The shards' events, by shard
+    merged: S,
+    tx: tokio::sync::mpsc::UnboundedSender<WatchEvent>,
+    /// Shards yet to send [`WatchEvent::InitialSyncComplete`]
+    syncing: usize,
+    /// Shards yet to send [`WatchEvent::Closed`]
+    open: usize,
+    disconnected: Vec<bool>,
+    /// Stops the shards
+    shard_token: CancellationToken,
+}
+
+impl<S: futures::Stream<Item = (usize, WatchEvent)> + Unpin> ShardMerge<S> {
+    async fn run(&mut self) {
+        while let Some((index, event)) = self.merged.next().await {
+            let event = match event {
+                WatchEvent::InitialSyncComplete => {
+                    self.syncing -= 1;
+                    if self.syncing > 0 {
+                        continue;
+                    }
+                    event
+                }
+                WatchEvent::Disconnected => {
+                    let first = !self.disconnected.contains(&true);
+                    self.disconnected[index] = true;
+                    if !first {
+                        continue;
+                    }
+                    event
+                }
+                WatchEvent::Reconnected => {
+                    self.disconnected[index] = false;
+                    if self.disconnected.contains(&true) {
+                        continue;
+                    }
+                    event
+                }
+                WatchEvent::Closed => {
+                    self.open -= 1;
+                    if self.open > 0 {
+                        continue;
+                    }
+                    event
+                }
+                WatchEvent::Error(_) => {
+                    self.shard_token.cancel();
+                    let _ = self.tx.send(event);
+                    return;
+                }
+                event => event,
+            };
+            if self.tx.send(event).is_err() {
+                break;
+            }
+        }
+        self.shard_token.cancel();
+    }
+}
+
+/// What [`KeyValueStoreManager::watch`] delivered so far, to pick up from after a reconnect
+#[derive(Default)]
+struct WatchState {
+    /// Connected at least once
+    started: bool,
+    connected: bool,
+    /// The highest sequence delivered, 0 if none
+    last_sequence: u64,
+    /// Every key the receiver was told exists, with its value
+    known: HashMap<String, bytes::Bytes>,
+    filter: WatchFilter,
+}
+
+impl WatchState {
+    /// `event` as the receiver should see it through the filter, if at all
+    fn admit(&self, bucket_name: &str, event: WatchEvent) -> Option<WatchEvent> {
+        match event {
+            WatchEvent::Put(kv) if !self.filter.accepts(bucket_name, &kv) => {
+                // It left the filter's view
+                let gone = KeyValue::new(kv.key().to_string(), bytes::Bytes::new())
+                    .with_sequence(kv.sequence());
+                self.known
+                    .contains_key(kv.key())
+                    .then_some(WatchEvent::Delete(gone))
+            }
+            WatchEvent::Delete(kv) if !self.known.contains_key(kv.key()) => None,
+            event => Some(event),
+        }
+    }
+
+    fn record(&mut self, event: &WatchEvent) {
+        match event {
+            WatchEvent::Put(kv) => {
+                self.known.insert(kv.key().to_string(), kv.value().clone());
+            }
+            WatchEvent::Delete(kv) => {
+                self.known.remove(kv.key());
+            }
+            _ => {}
+        }
+        self.last_sequence = self.last_sequence.max(event.sequence());
+    }
+
+    /// Send the changes that take what the receiver knows to `entries`
+    async fn resync(&mut self, entries: HashMap<String, bytes::Bytes>, tx: &WatchSender) {
+        let gone: Vec<String> = self
+            .known
+            .keys()
+            .filter(|key| !entries.contains_key(*key))
+            .cloned()
+            .collect();
+        for key in gone {
+            self.known.remove(&key);
+            tx.send(WatchEvent::Delete(KeyValue::new(key, bytes::Bytes::new())))
+                .await;
+        }
+        for (key, value) in entries {
+            if self.known.get(&key) != Some(&value) {
+                self.known.insert(key.clone(), value.clone());
+                tx.send(WatchEvent::Put(KeyValue::new(key, value))).await;
+            }
+        }
+    }
+}
+
+/// An online storage for key-value config values.
+/// Usually backed by `nats-server`.
+#[async_trait]
+pub trait KeyValueBucket: Send + Sync {
+    /// A bucket is a collection of key/value pairs.
+    /// Insert a value into a bucket, if it doesn't exist already
+    async fn insert(
+        &self,
+        key: &Key,
+        value: &str,
+        revision: u64,
+    ) -> Result<StoreOutcome, StoreError>;
+
+    /// Insert a value only if the key doesn't exist. [`StoreOutcome::Exists`] if it does, and
+    /// nothing is written. For most stores that is `insert` at revision 0, the default.
+    async fn insert_new(&self, key: &Key, value: &str) -> Result<StoreOutcome, StoreError> {
+        self.insert(key, value, 0).await
+    }
+
+    /// Overwrite the value of a key only if it exists, otherwise [`StoreError::MissingKey`].
+    /// The default checks first, so a delete between the check and the write is undone.
+    /// Stores with transactions do both at once.
+    async fn update_existing(&self, key: &Key, value: &str) -> Result<StoreOutcome, StoreError> {
+        let Conditional::Modified { revision, .. } = self.get_if_changed(key, 0).await? else {
+            return Err(StoreError::MissingKey(key.to_string()));
+        };
+        self.insert(key, value, revision).await
+    }
+
+    /// Create the key or overwrite it, whatever its revision. On success, the key's new
+    /// revision. The default creates the key or else updates it, two writes for an existing
+    /// key. Stores that can overwrite unconditionally do it in one.
+    async fn upsert(&self, key: &Key, value: &str) -> Result<StoreOutcome, StoreError> {
+        loop {
+            if let created @ StoreOutcome::Created(_) = self.insert_new(key, value).await? {
+                return Ok(created);
+            }
+            match self.update_existing(key, value).await {
+                // Deleted since, create it after all
+                Err(StoreError::MissingKey(_)) => continue,
+                outcome => return outcome,
+            }
+        }
+    }
+
+    /// Write a value only if the key is still at `revision`, as returned by
+    /// [`KeyValueBucket::get_if_changed`], or still missing if `revision` is 0. Otherwise
+    /// [`StoreError::Retry`], and nothing is written. On success, the key's new revision.
+    ///
+    /// The default checks first, so a write landing between the check and ours is lost.
+    async fn compare_and_swap(
+        &self,
+        key: &Key,
+        value: &str,
+        revision: u64,
+    ) -> Result<StoreOutcome, StoreError> {
+        if revision == 0 {
+            return match self.insert_new(key, value).await? {
+                StoreOutcome::Exists(_) => Err(StoreError::Retry),
+                StoreOutcome::Created(_) => revision_of(self, key).await,
+            };
+        }
+        if self.get_if_changed(key, revision).await? != Conditional::NotModified {
+            return Err(StoreError::Retry);
+        }
+        match self.update_existing(key, value).await {
+            Err(StoreError::MissingKey(_)) => Err(StoreError::Retry),
+            Err(err) => Err(err),
+            Ok(_) => revision_of(self, key).await,
+        }
+    }
+
+    /// Insert or overwrite a value, only if `fence` is still current.
+    /// Stores without leases cannot check a fence and refuse.
+    async fn insert_fenced(
+        &self,
+        _key: &Key,
+        _value: &str,
+        _fence: Fence,
+    ) -> Result<StoreOutcome, StoreError> {
+        Err(StoreError::provider(
+            "This store does not support fenced writes",
+        ))
+    }
+
+    /// Fetch an item from the key-value storage
+    async fn get(&self, key: &Key) -> Result<Option<bytes::Bytes>, StoreError>;
+
+    /// Fetch several items, returned in the order of `keys`. The default fetches them one at a
+    /// time; backends override it to batch them into one round trip.
+    async fn get_many(&self, keys: &[Key]) -> Result<Vec<Option<bytes::Bytes>>, StoreError> {
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            values.push(self.get(key).await?);
+        }
+        Ok(values)
+    }
+
+    /// Fetch an item only if it changed since `known_revision`, like an HTTP conditional GET
+    /// with an ETag. Pass 0 the first time, then the revision from the last
+    /// [`Conditional::Modified`]. Revisions are only meaningful for the bucket that issued them.
+    ///
+    /// The default uses a hash of the value as its revision, so it saves the caller's
+    /// bandwidth but not the store's. Backends that version their keys do better.
+    async fn get_if_changed(
+        &self,
+        key: &Key,
+        known_revision: u64,
+    ) -> Result<Conditional, StoreError> {
+        let Some(value) = self.get(key).await? else {
+            return Ok(Conditional::Missing);
+        };
+        let revision = content_revision(&value);
+        if revision == known_revision {
+            return Ok(Conditional::NotModified);
+        }
+        Ok(Conditional::Modified { value, revision })
+    }
+
+    /// Fetch an item with an explicit [`ReadConsistency`]. `get` is the store's default,
+    /// which for etcd is linearizable.
+    async fn get_with_consistency(
+        &self,
+        key: &Key,
+        _consistency: ReadConsistency,
+    ) -> Result<Option<bytes::Bytes>, StoreError> {
+        self.get(key).await
+    }
+
+    /// Delete an item from the bucket
+    async fn delete(&self, key: &Key) -> Result<(), StoreError>;
+
+    /// A stream of items inserted into the bucket.
+    /// Every time the stream is polled it will either return a newly created entry, or block until
+    /// such time.
+    async fn watch(
+        &self,
+    ) -> Result<Pin<Box<dyn futures::Stream<Item = WatchEvent> + Send + '_>>, StoreError>;
+
+    /// Like [`KeyValueBucket::watch`], but only needing the keys starting with `prefix`.
+    /// Stores that can't watch part of a bucket send all of it, the default.
+    async fn watch_prefix(
+        &self,
+        _prefix: &str,
+    ) -> Result<Pin<Box<dyn futures::Stream<Item = WatchEvent> + Send + '_>>, StoreError> {
+        self.watch().await
+    }
+
+    /// Like [`KeyValueBucket::watch`], but only needing the keys from `start` up to but not
+    /// including `end`, or to the end of the bucket if `end` is empty. Stores that can't watch
+    /// a key range send all of the bucket, the default.
+    async fn watch_range(
+        &self,
+        _start: &str,
+        _end: &str,
+    ) -> Result<Pin<Box<dyn futures::Stream<Item = WatchEvent> + Send + '_>>, StoreError> {
+        self.watch().await
+    }
+
+    /// Like [`KeyValueBucket::watch`], but starting with the first change after `sequence`, a
+    /// [`KeyValue::sequence`] from an earlier watch, and without the existing entries. None if
+    /// the store can't replay its history, which is the default.
+    async fn watch_from(
+        &self,
+        _sequence: u64,
+    ) -> Result<Option<Pin<Box<dyn futures::Stream<Item = WatchEvent> + Send + '_>>>, StoreError>
+    {
+        Ok(None)
+    }
+
+    async fn entries(&self) -> Result<HashMap<String, bytes::Bytes>, StoreError>;
+
+    /// Like [`KeyValueBucket::entries`], but only needing the keys starting with `prefix`,
+    /// within the bucket. Stores that can't read part of a bucket return all of it, the default.
+    async fn entries_prefix(
+        &self,
+        _prefix: &str,
+    ) -> Result<HashMap<String, bytes::Bytes>, StoreError> {
+        self.entries().await
+    }
+}
+
+/// Lets [`KeyValueStoreManager`] hand out the bucket it cached as often as asked
+#[async_trait]
+impl KeyValueBucket for Arc<dyn KeyValueBucket> {
+    async fn insert(
+        &self,
+        key: &Key,
+        value: &str,
+        revision: u64,
+    ) -> Result<StoreOutcome, StoreError> {
+        (**self).insert(key, value, revision).await
+    }
 
-impl KeyValueStoreManager {
-    /// In-memory KeyValueStoreManager for testing
-    pub fn memory() -> Self {
-        Self::new(KeyValueStoreEnum::Memory(MemoryStore::new()))
+    async fn insert_new(&self, key: &Key, value: &str) -> Result<StoreOutcome, StoreError> {
+        (**self).insert_new(key, value).await
     }
 
-    pub fn etcd(etcd_client: crate::transports::etcd::Client) -> Self {
-        Self::new(KeyValueStoreEnum::Etcd(EtcdStore::new(etcd_client)))
+    async fn update_existing(&self, key: &Key, value: &str) -> Result<StoreOutcome, StoreError> {
+        (**self).update_existing(key, value).await
     }
 
-    fn new(s: KeyValueStoreEnum) -> KeyValueStoreManager {
-        KeyValueStoreManager(Arc::new(s))
+    async fn upsert(&self, key: &Key, value: &str) -> Result<StoreOutcome, StoreError> {
+        (**self).upsert(key, value).await
     }
 
-    pub async fn get_or_create_bucket(
+    async fn compare_and_swap(
         &self,
-        bucket_name: &str,
-        // auto-delete items older than this
-        ttl: Option<Duration>,
-    ) -> Result<Box<dyn KeyValueBucket>, StoreError> {
-        self.0.get_or_create_bucket(bucket_name, ttl).await
+        key: &Key,
+        value: &str,
+        revision: u64,
+    ) -> Result<StoreOutcome, StoreError> {
+        (**self).compare_and_swap(key, value, revision).await
     }
 
-    pub async fn get_bucket(
+    async fn insert_fenced(
         &self,
-        bucket_name: &str,
-    ) -> Result<Option<Box<dyn KeyValueBucket>>, StoreError> {
-        self.0.get_bucket(bucket_name).await
+        key: &Key,
+        value: &str,
+        fence: Fence,
+    ) -> Result<StoreOutcome, StoreError> {
+        (**self).insert_fenced(key, value, fence).await
     }
 
-    pub fn connection_id(&self) -> u64 {
-        self.0.connection_id()
+    async fn get(&self, key: &Key) -> Result<Option<bytes::Bytes>, StoreError> {
+        (**self).get(key).await
     }
 
-    pub async fn load<T: for<'a> Deserialize<'a>>(
+    async fn get_many(&self, keys: &[Key]) -> Result<Vec<Option<bytes::Bytes>>, StoreError> {
+        (**self).get_many(keys).await
+    }
+
+    async fn get_if_changed(
         &self,
-        bucket: &str,
         key: &Key,
-    ) -> Result<Option<T>, StoreError> {
-        let Some(bucket) = self.0.get_bucket(bucket).await? else {
-            // No bucket means no cards
-            return Ok(None);
-        };
-        Ok(match bucket.get(key).await? {
-            Some(card_bytes) => {
-                let card: T = serde_json::from_slice(card_bytes.as_ref())?;
-                Some(card)
-            }
-            None => None,
-        })
+        known_revision: u64,
+    ) -> Result<Conditional, StoreError> {
+        (**self).get_if_changed(key, known_revision).await
     }
 
-    /// Returns a receiver that will receive all the existing keys, and
-    /// then block and receive new keys as they are created.
-    /// Starts a task that runs forever, watches the store.
-    pub fn watch(
-        self: Arc<Self>,
-        bucket_name: &str,
-        bucket_ttl: Option<Duration>,
-        cancel_token: CancellationToken,
-    ) -> (
-        tokio::task::JoinHandle<Result<(), StoreError>>,
-        tokio::sync::mpsc::UnboundedReceiver<WatchEvent>,
-    ) {
-        let bucket_name = bucket_name.to_string();
-        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
-        let watch_task = tokio::spawn(async move {
-            // Start listening for changes but don't poll this yet
-            let bucket = self
-                .0
-                .get_or_create_bucket(&bucket_name, bucket_ttl)
-                .await?;
-            let mut stream = bucket.watch().await?;
-
-            // Send all the existing keys
-            for (key, bytes) in bucket.entries().await? {
-                let _ = tx.send(WatchEvent::Put(KeyValue::new(key, bytes)));
-            }
-
-            // Now block waiting for new entries
-            loop {
-                let event = tokio::select! {
-                    _ = cancel_token.cancelled() => break,
-                    result = stream.next() => match result {
-                        Some(event) => event,
-                        None => break,
-                    }
-                };
-                let _ = tx.send(event);
-            }
+    async fn get_with_consistency(
+        &self,
+        key: &Key,
+        consistency: ReadConsistency,
+    ) -> Result<Option<bytes::Bytes>, StoreError> {
+        (**self).get_with_consistency(key, consistency).await
+    }
 
-            Ok::<(), StoreError>(())
-        });
-        (watch_task, rx)
+    async fn delete(&self, key: &Key) -> Result<(), StoreError> {
+        (**self).delete(key).await
     }
 
-    pub async fn publish<T: Serialize + Versioned + Send + Sync>(
+    async fn watch(
         &self,
-        bucket_name: &str,
-        bucket_ttl: Option<Duration>,
-        key: &Key,
-        obj: &mut T,
-    ) -> anyhow::Result<StoreOutcome> {
-        let obj_json = serde_json::to_string(obj)?;
-        let bucket = self.0.get_or_create_bucket(bucket_name, bucket_ttl).await?;
-
-        let outcome = bucket.insert(key, &obj_json, obj.revision()).await?;
+    ) -> Result<Pin<Box<dyn futures::Stream<Item = WatchEvent> + Send + 'life0>>, StoreError> {
+        (**self).watch().await
+    }
 
-        match outcome {
-            StoreOutcome::Created(revision) | StoreOutcome::Exists(revision) => {
-                obj.set_revision(revision);
-            }
-        }
-        Ok(outcome)
+    async fn watch_prefix(
+        &self,
+        prefix: &str,
+    ) -> Result<Pin<Box<dyn futures::Stream<Item = WatchEvent> + Send + 'life0>>, StoreError> {
+        (**self).watch_prefix(prefix).await
     }
-}
 
-/// An online storage for key-value config values.
-/// Usually backed by `nats-server`.
-#[async_trait]
-pub trait KeyValueBucket: Send + Sync {
-    /// A bucket is a collection of key/value pairs.
-    /// Insert a value into a bucket, if it doesn't exist already
-    async fn insert(
+    async fn watch_range(
         &self,
-        key: &Key,
-        value: &str,
-        revision: u64,
-    ) -> Result<StoreOutcome, StoreError>;
+        start: &str,
+        end: &str,
+    ) -> Result<Pin<Box<dyn futures::Stream<Item = WatchEvent> + Send + 'life0>>, StoreError> {
+        (**self).watch_range(start, end).await
+    }
 
-    /// Fetch an item from the key-value storage
-    async fn get(&self, key: &Key) -> Result<Option<bytes::Bytes>, StoreError>;
+    async fn watch_from(
+        &self,
+        sequence: u64,
+    ) -> Result<Option<Pin<Box<dyn futures::Stream<Item = WatchEvent> + Send + 'life0>>>, StoreError>
+    {
+        (**self).watch_from(sequence).await
+    }
 
-    /// Delete an item from the bucket
-    async fn delete(&self, key: &Key) -> Result<(), StoreError>;
+    async fn entries(&self) -> Result<HashMap<String, bytes::Bytes>, StoreError> {
+        (**self).entries().await
+    }
 
-    /// A stream of items inserted into the bucket.
-    /// Every time the stream is polled it will either return a newly created entry, or block until
-    /// such time.
-    async fn watch(
+    async fn entries_prefix(
         &self,
-    ) -> Result<Pin<Box<dyn futures::Stream<Item = WatchEvent> + Send + '_>>, StoreError>;
-
-    async fn entries(&self) -> Result<HashMap<String, bytes::Bytes>, StoreError>;
+        prefix: &str,
+    ) -> Result<HashMap<String, bytes::Bytes>, StoreError> {
+        (**self).entries_prefix(prefix).await
+    }
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -322,6 +1876,13 @@ impl fmt::Display for StoreOutcome {
     }
 }
 
+/// The error a backend client failed with, behind a [`StoreError`] as its
+/// [`source`](std::error::Error::source)
+pub type ErrorSource = Box<dyn std::error::Error + Send + Sync>;
+
+/// The messages are what they were before the backend errors kept their sources, as some callers
+/// still go by them. What was added since, like the kind of an etcd error and the
+/// [`OpId`] of the call, is only in fields.
 #[derive(thiserror::Error, Debug)]
 pub enum StoreError {
     #[error("Could not find bucket '{0}'")]
@@ -330,23 +1891,192 @@ pub enum StoreError {
     #[error("Could not find key '{0}'")]
     MissingKey(String),
 
-    #[error("Internal storage error: '{0}'")]
-    ProviderError(String),
-
-    #[error("Internal NATS error: {0}")]
-    NATSError(String),
-
-    #[error("Internal etcd error: {0}")]
-    EtcdError(String),
-
-    #[error("Key Value Error: {0} for bucket '{1}'")]
-    KeyValueError(String, String),
+    #[error("Key '{0}' already exists")]
+    AlreadyExists(String),
+
+    #[error("Key '{0}' is held by another session")]
+    SessionConflict(String),
+
+    #[error("Internal storage error: '{message}'")]
+    ProviderError {
+        message: String,
+        #[source]
+        source: Option<ErrorSource>,
+        op_id: Option<OpId>,
+    },
+
+    #[error("Internal NATS error: {message}")]
+    NATSError {
+        message: String,
+        #[source]
+        source: Option<ErrorSource>,
+        op_id: Option<OpId>,
+    },
+
+    #[error("Internal etcd error: {message}")]
+    EtcdError {
+        kind: EtcdErrorKind,
+        message: String,
+        #[source]
+        source: Option<ErrorSource>,
+        op_id: Option<OpId>,
+    },
+
+    #[error("Internal Consul error: {message}")]
+    ConsulError {
+        message: String,
+        #[source]
+        source: Option<ErrorSource>,
+        op_id: Option<OpId>,
+    },
+
+    #[error("Internal Redis error: {message}")]
+    RedisError {
+        message: String,
+        #[source]
+        source: Option<ErrorSource>,
+        op_id: Option<OpId>,
+    },
+
+    #[error("Key Value Error: {message} for bucket '{bucket}'")]
+    KeyValueError {
+        message: String,
+        bucket: String,
+        #[source]
+        source: Option<ErrorSource>,
+        op_id: Option<OpId>,
+    },
 
     #[error("Error decoding bytes: {0}")]
     JSONDecodeError(#[from] serde_json::error::Error),
 
     #[error("Race condition, retry the call")]
     Retry,
+
+    #[error("Value of key '{key}' is corrupted: {reason}")]
+    Corrupted { key: String, reason: String },
+
+    #[error("Key of {len} bytes is longer than the store allows, {limit} bytes")]
+    KeyTooLong { len: usize, limit: usize },
+
+    #[error("Value of {size} bytes for key '{key}' is larger than the store allows, {limit} bytes")]
+    ValueTooLarge {
+        key: String,
+        size: usize,
+        limit: usize,
+    },
+
+    #[error("Fencing token {token} of lease {lease_id:x} is no longer current")]
+    Fenced { lease_id: u64, token: u64 },
+}
+
+impl StoreError {
+    /// An error of no backend in particular, without a source
+    pub fn provider(message: impl Into<String>) -> Self {
+        StoreError::ProviderError {
+            message: message.into(),
+            source: None,
+            op_id: None,
+        }
+    }
+
+    pub(crate) fn nats(err: impl std::error::Error + Send + Sync + 'static) -> Self {
+        StoreError::NATSError {
+            message: err.to_string(),
+            source: Some(Box::new(err)),
+            op_id: None,
+        }
+    }
+
+    pub(crate) fn key_value(
+        err: impl std::error::Error + Send + Sync + 'static,
+        bucket: impl Into<String>,
+    ) -> Self {
+        StoreError::KeyValueError {
+            message: err.to_string(),
+            bucket: bucket.into(),
+            source: Some(Box::new(err)),
+            op_id: None,
+        }
+    }
+
+    /// Name `op_id` on an error from the backend, so the calls behind it can be found in the
+    /// traces. The other errors are left as they are, as callers match on them.
+    pub fn with_op_id(self, op_id: OpId) -> Self {
+        let mut err = self;
+        match &mut err {
+            StoreError::ProviderError { op_id: id, .. }
+            | StoreError::NATSError { op_id: id, .. }
+            | StoreError::ConsulError { op_id: id, .. }
+            | StoreError::RedisError { op_id: id, .. }
+            | StoreError::EtcdError { op_id: id, .. }
+            | StoreError::KeyValueError { op_id: id, .. } => *id = Some(op_id),
+            _ => {}
+        }
+        err
+    }
+
+    /// The store call a backend error came from, see [`StoreError::with_op_id`]
+    pub fn op_id(&self) -> Option<OpId> {
+        match self {
+            StoreError::ProviderError { op_id, .. }
+            | StoreError::NATSError { op_id, .. }
+            | StoreError::ConsulError { op_id, .. }
+            | StoreError::RedisError { op_id, .. }
+            | StoreError::EtcdError { op_id, .. }
+            | StoreError::KeyValueError { op_id, .. } => *op_id,
+            _ => None,
+        }
+    }
+
+    /// Likely to go away by itself. etcd errors are classified, and Consul or Redis refusing a
+    /// request is not transient. The other stores' errors are taken to be connection trouble.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            StoreError::EtcdError { kind, .. } => kind.is_transient(),
+            StoreError::ConsulError {
+                source: Some(source),
+                ..
+            } => match source
+                .downcast_ref::<reqwest::Error>()
+                .and_then(|err| err.status())
+            {
+                Some(status) => !status.is_client_error(),
+                None => true,
+            },
+            StoreError::RedisError { source, .. } => source.is_some(),
+            StoreError::ProviderError { .. }
+            | StoreError::NATSError { .. }
+            | StoreError::ConsulError { .. }
+            | StoreError::Retry => true,
+            _ => false,
+        }
+    }
+
+    /// The bucket or key asked for doesn't exist
+    pub fn is_not_found(&self) -> bool {
+        matches!(
+            self,
+            StoreError::MissingBucket(_) | StoreError::MissingKey(_)
+        )
+    }
+}
+
+/// Revision for stores that don't number their changes. Never 0, which means "unknown".
+pub(crate) fn content_revision(value: &[u8]) -> u64 {
+    xxhash_rust::xxh3::xxh3_64(value).max(1)
+}
+
+/// The revision [`KeyValueBucket::get_if_changed`] gives `key` now, as written by us. Retry if
+/// it was deleted since.
+async fn revision_of<B: KeyValueBucket + ?Sized>(
+    bucket: &B,
+    key: &Key,
+) -> Result<StoreOutcome, StoreError> {
+    match bucket.get_if_changed(key, 0).await? {
+        Conditional::Modified { revision, .. } => Ok(StoreOutcome::Created(revision)),
+        _ => Err(StoreError::Retry),
+    }
 }
 
 /// A trait allowing to get/set a revision on an object.
@@ -361,39 +2091,10 @@ mod tests {
     use std::sync::Arc;
 
     use super::*;
-    use futures::{StreamExt, pin_mut};
+    use futures::StreamExt;
 
     const BUCKET_NAME: &str = "v1/mdc";
 
-    /// Convert the value returned by `watch()` into a broadcast stream that multiple
-    /// clients can listen to.
-    #[allow(dead_code)]
-    pub struct TappableStream {
-        tx: tokio::sync::broadcast::Sender<WatchEvent>,
-    }
-
-    #[allow(dead_code)]
-    impl TappableStream {
-        async fn new<T>(stream: T, max_size: usize) -> Self
-        where
-            T: futures::Stream<Item = WatchEvent> + Send + 'static,
-        {
-            let (tx, _) = tokio::sync::broadcast::channel(max_size);
-            let tx2 = tx.clone();
-            tokio::spawn(async move {
-                pin_mut!(stream);
-                while let Some(x) = stream.next().await {
-                    let _ = tx2.send(x);
-                }
-            });
-            TappableStream { tx }
-        }
-
-        fn subscribe(&self) -> tokio::sync::broadcast::Receiver<WatchEvent> {
-            self.tx.subscribe()
-        }
-    }
-
     fn init() {
         crate::logging::init();
     }
@@ -409,12 +2110,16 @@ mod tests {
         let res = bucket.insert(&"test1".into(), "value1", 0).await?;
         assert_eq!(res, StoreOutcome::Created(0));
 
-        let mut expected = Vec::with_capacity(3);
-        for i in 1..=3 {
-            let item = WatchEvent::Put(KeyValue::new(
-                format!("test{i}"),
-                bytes::Bytes::from(format!("value{i}").into_bytes()),
-            ));
+        // Re-putting test2 at a new revision is sent to the watch, like any other change
+        let mut expected = Vec::with_capacity(4);
+        for (i, sequence) in [(1, 1), (2, 2), (2, 3), (3, 4)] {
+            let item = WatchEvent::Put(
+                KeyValue::new(
+                    format!("test{i}"),
+                    bytes::Bytes::from(format!("value{i}").into_bytes()),
+                )
+                .with_sequence(sequence),
+            );
             expected.push(item);
         }
 
@@ -426,6 +2131,8 @@ mod tests {
             // Put in before starting the watch-all
             let v = stream.next().await.unwrap();
             assert_eq!(v, expected[0]);
+            let v = stream.next().await.unwrap();
+            assert_eq!(v, WatchEvent::InitialSyncComplete);
 
             got_first_tx.send(()).unwrap();
 
@@ -436,6 +2143,9 @@ mod tests {
             let v = stream.next().await.unwrap();
             assert_eq!(v, expected[2]);
 
+            let v = stream.next().await.unwrap();
+            assert_eq!(v, expected[3]);
+
             Ok::<_, StoreError>(())
         });
 
@@ -464,6 +2174,105 @@ mod tests {
         Ok(())
     }
 
+    /// Behavior every backend must share. Backends differ in revision numbering and in whether
+    /// keys come back bucket-qualified, so those are not checked.
+    async fn check_conformance<S: KeyValueStore>(store: &S) -> anyhow::Result<()> {
+        let bucket_name = format!("conformance-{}", uuid::Uuid::new_v4());
+        let bucket = store.get_or_create_bucket(&bucket_name, None).await?;
+        assert!(store.get_bucket(&bucket_name).await?.is_some());
+
+        let key: Key = "key1".into();
+        let outcome = bucket.insert(&key, "value1", 0).await?;
+        assert!(matches!(outcome, StoreOutcome::Created(_)), "{outcome}");
+        let outcome = bucket.insert(&key, "other", 0).await?;
+        assert!(matches!(outcome, StoreOutcome::Exists(_)), "{outcome}");
+
+        let value = bucket.get(&key).await?;
+        assert_eq!(value.as_deref(), Some(b"value1".as_slice()));
+        for consistency in [
+            ReadConsistency::Linearizable,
+            ReadConsistency::Serializable,
+            ReadConsistency::BoundedStaleness(Duration::from_secs(1)),
+            ReadConsistency::AtLeast(CausalToken::default()),
+        ] {
+            let value = bucket.get_with_consistency(&key, consistency).await?;
+            assert_eq!(
+                value.as_deref(),
+                Some(b"value1".as_slice()),
+                "{consistency:?}"
+            );
+        }
+        assert_eq!(bucket.get(&"missing".into()).await?, None);
+
+        let entries = bucket.entries().await?;
+        assert_eq!(entries.len(), 1);
+        assert!(entries.keys().all(|k| k.ends_with("key1")), "{entries:?}");
+
+        bucket.delete(&key).await?;
+        assert_eq!(bucket.get(&key).await?, None);
+        assert!(bucket.entries().await?.is_empty());
+
+        // Deleting a missing key is not an error
+        bucket.delete(&key).await?;
+
+        let other = Key::new("key2");
+        bucket.insert(&other, "value2", 0).await?;
+        assert_eq!(
+            bucket
+                .get_many(&[other.clone(), key.clone(), other.clone()])
+                .await?,
+            vec![Some("value2".into()), None, Some("value2".into())]
+        );
+        assert!(bucket.get_many(&[]).await?.is_empty());
+        bucket.delete(&other).await?;
+
+        bucket.insert(&key, "value2", 0).await?;
+        let Conditional::Modified { value, revision } = bucket.get_if_changed(&key, 0).await?
+        else {
+            panic!("first conditional get must return the value");
+        };
+        assert_eq!(value.as_ref(), b"value2");
+        assert_eq!(
+            bucket.get_if_changed(&key, revision).await?,
+            Conditional::NotModified
+        );
+        bucket.delete(&key).await?;
+        assert_eq!(
+            bucket.get_if_changed(&key, revision).await?,
+            Conditional::Missing
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_memory_conformance() -> anyhow::Result<()> {
+        init();
+        check_conformance(&MemoryStore::new()).await
+    }
+
+    #[cfg(feature = "integration")]
+    #[tokio::test]
+    async fn test_consul_conformance() -> anyhow::Result<()> {
+        init();
+        let runtime = crate::Runtime::from_current()?;
+        let store = ConsulStore::new(ConsulOptions::default(), &runtime).await?;
+        let result = check_conformance(&store).await;
+        runtime.shutdown();
+        result
+    }
+
+    #[cfg(feature = "integration")]
+    #[tokio::test]
+    async fn test_redis_conformance() -> anyhow::Result<()> {
+        init();
+        let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1".to_string());
+        let runtime = crate::Runtime::from_current()?;
+        let store = RedisStore::new(RedisOptions::from_url(&url)?, &runtime).await?;
+        let result = check_conformance(&store).await;
+        runtime.shutdown();
+        result
+    }
+
     #[tokio::test]
     async fn test_broadcast_stream() -> anyhow::Result<()> {
         init();
@@ -476,10 +2285,10 @@ mod tests {
         assert_eq!(res, StoreOutcome::Created(0));
 
         let stream = bucket.watch().await?;
-        let tap = TappableStream::new(stream, 10).await;
+        let fanout = WatchFanout::new(stream, 10);
 
-        let mut rx1 = tap.subscribe();
-        let mut rx2 = tap.subscribe();
+        let mut rx1 = fanout.subscribe();
+        let mut rx2 = fanout.subscribe();
 
         let item = WatchEvent::Put(KeyValue::new(
             "test1".to_string(),
@@ -487,11 +2296,11 @@ mod tests {
         ));
         let item_clone = item.clone();
         let handle1 = tokio::spawn(async move {
-            let b = rx1.recv().await.unwrap();
+            let b = rx1.recv().await.unwrap().unwrap();
             assert_eq!(b, item_clone);
         });
         let handle2 = tokio::spawn(async move {
-            let b = rx2.recv().await.unwrap();
+            let b = rx2.recv().await.unwrap().unwrap();
             assert_eq!(b, item);
         });
 
@@ -500,4 +2309,696 @@ mod tests {
         let _ = futures::join!(handle1, handle2);
         Ok(())
     }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_watch_reconnects_and_catches_up() -> anyhow::Result<()> {
+        let store = MemoryStore::new();
+        let bucket = store.get_or_create_bucket(BUCKET_NAME, None).await?;
+        bucket.insert(&"a".into(), "1", 0).await?;
+        bucket.insert(&"b".into(), "1", 0).await?;
+
+        let manager = Arc::new(KeyValueStoreManager::shared_memory(store.clone()));
+        let cancel_token = CancellationToken::new();
+        let mut rx = manager.watch(BUCKET_NAME, None, cancel_token.clone());
+        let drain = |rx: &mut tokio::sync::mpsc::UnboundedReceiver<WatchEvent>| {
+            std::iter::from_fn(|| rx.try_recv().ok()).collect::<Vec<_>>()
+        };
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let initial = drain(&mut rx);
+        assert_eq!(initial.len(), 3, "{initial:?}");
+        assert!(initial[..2].iter().all(|e| matches!(e, WatchEvent::Put(_))));
+        assert_eq!(initial[2], WatchEvent::InitialSyncComplete);
+
+        // Changed while the watch is down
+        store.drop_watches();
+        bucket.delete(&"a".into()).await?;
+        bucket.insert(&"c".into(), "1", 0).await?;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let events = drain(&mut rx);
+        assert_eq!(
+            events[..2],
+            [WatchEvent::Disconnected, WatchEvent::Reconnected]
+        );
+        // Once per watch
+        assert!(!events.contains(&WatchEvent::InitialSyncComplete));
+        let changes: Vec<_> = events[2..]
+            .iter()
+            .map(|e| {
+                (
+                    matches!(e, WatchEvent::Put(_)),
+                    e.key_value().unwrap().key(),
+                )
+            })
+            .collect();
+        assert!(changes.contains(&(false, "a")), "{changes:?}");
+        assert!(changes.contains(&(true, "c")), "{changes:?}");
+        assert!(!changes.contains(&(true, "a")), "{changes:?}");
+
+        // Live again
+        bucket.insert(&"d".into(), "1", 0).await?;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let events = drain(&mut rx);
+        assert!(
+            events
+                .iter()
+                .any(|e| e.key_value().is_some_and(|kv| kv.key() == "d"))
+        );
+
+        cancel_token.cancel();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(drain(&mut rx).last(), Some(&WatchEvent::Closed));
+        assert!(rx.recv().await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_watch_closed_on_cancel() -> anyhow::Result<()> {
+        let manager = Arc::new(KeyValueStoreManager::memory());
+        let cancel_token = CancellationToken::new();
+        let mut rx = manager.watch(BUCKET_NAME, None, cancel_token.clone());
+        assert_eq!(rx.recv().await, Some(WatchEvent::InitialSyncComplete));
+
+        cancel_token.cancel();
+        assert_eq!(rx.recv().await, Some(WatchEvent::Closed));
+        assert!(rx.recv().await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_watch_error_on_lasting_failure() -> anyhow::Result<()> {
+        let store = MemoryStore::new();
+        let manager = Arc::new(KeyValueStoreManager::shared_memory(store.clone()));
+        let cancel_token = CancellationToken::new();
+        let mut rx = manager.watch(BUCKET_NAME, None, cancel_token.clone());
+        assert_eq!(rx.recv().await, Some(WatchEvent::InitialSyncComplete));
+
+        // The reconnect fails for good, so the watch stops trying
+        store.refuse_watches();
+        assert_eq!(rx.recv().await, Some(WatchEvent::Disconnected));
+        let Some(WatchEvent::Error(err)) = rx.recv().await else {
+            panic!("expected the watch to fail");
+        };
+        assert!(!err.is_transient(), "{err}");
+        assert!(err.to_string().contains("watches refused"), "{err}");
+        assert!(rx.recv().await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_bucket_cache() -> anyhow::Result<()> {
+        let store = MemoryStore::new();
+        let manager = KeyValueStoreManager::shared_memory(store.clone());
+        assert!(manager.get_bucket("cached").await?.is_none());
+        assert!(
+            manager
+                .load::<String>("cached", &"k".into())
+                .await?
+                .is_none()
+        );
+
+        // Created behind the manager's back: it keeps its answer until that expires
+        let bucket = store.get_or_create_bucket("cached", None).await?;
+        bucket.insert(&"k".into(), "\"v\"", 0).await?;
+        assert!(manager.get_bucket("cached").await?.is_none());
+        tokio::time::sleep(BUCKET_CACHE_TTL).await;
+        let value = manager.load::<String>("cached", &"k".into()).await?;
+        assert_eq!(value.as_deref(), Some("v"));
+
+        // Created through it: known at once
+        assert!(manager.get_bucket("other").await?.is_none());
+        manager.get_or_create_bucket("other", None).await?;
+        assert!(manager.get_bucket("other").await?.is_some());
+        Ok(())
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Card {
+        name: String,
+        #[serde(skip)]
+        revision: u64,
+    }
+
+    impl Versioned for Card {
+        fn revision(&self) -> u64 {
+            self.revision
+        }
+
+        fn set_revision(&mut self, r: u64) {
+            self.revision = r;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publish_modes() -> anyhow::Result<()> {
+        let manager = KeyValueStoreManager::memory();
+        let key: Key = "card".into();
+        let card = |name: &str| Card {
+            name: name.to_string(),
+            revision: 0,
+        };
+        let name = |card: Option<Card>| card.map(|c| c.name);
+
+        let err = manager
+            .publish(
+                BUCKET_NAME,
+                None,
+                &key,
+                &mut card("a"),
+                PublishMode::UpdateOnly,
+            )
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(StoreError::MissingKey(_))),
+            "{err}"
+        );
+        assert!(manager.load::<Card>(BUCKET_NAME, &key).await?.is_none());
+
+        manager
+            .publish(
+                BUCKET_NAME,
+                None,
+                &key,
+                &mut card("b"),
+                PublishMode::CreateOnly,
+            )
+            .await?;
+        let err = manager
+            .publish(
+                BUCKET_NAME,
+                None,
+                &key,
+                &mut card("c"),
+                PublishMode::CreateOnly,
+            )
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(StoreError::AlreadyExists(_))),
+            "{err}"
+        );
+        let stored = manager.load::<Card>(BUCKET_NAME, &key).await?;
+        assert_eq!(name(stored).as_deref(), Some("b"));
+
+        manager
+            .publish(
+                BUCKET_NAME,
+                None,
+                &key,
+                &mut card("d"),
+                PublishMode::UpdateOnly,
+            )
+            .await?;
+        let stored = manager.load::<Card>(BUCKET_NAME, &key).await?;
+        assert_eq!(name(stored).as_deref(), Some("d"));
+
+        manager
+            .publish(BUCKET_NAME, None, &key, &mut card("e"), PublishMode::Upsert)
+            .await?;
+        let stored = manager.load::<Card>(BUCKET_NAME, &key).await?;
+        assert_eq!(name(stored).as_deref(), Some("e"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_publish_racing_writers() -> anyhow::Result<()> {
+        let manager = KeyValueStoreManager::memory();
+        let key: Key = "card".into();
+        let mut first = Card {
+            name: "a".to_string(),
+            revision: 0,
+        };
+        manager
+            .publish(
+                BUCKET_NAME,
+                None,
+                &key,
+                &mut first,
+                PublishMode::CompareAndSwap,
+            )
+            .await?;
+        assert_ne!(first.revision, 0);
+
+        // Both writers start from the same revision, the second one to write loses
+        let mut second = Card {
+            name: "b".to_string(),
+            revision: first.revision,
+        };
+        first.name = "c".to_string();
+        manager
+            .publish(
+                BUCKET_NAME,
+                None,
+                &key,
+                &mut first,
+                PublishMode::CompareAndSwap,
+            )
+            .await?;
+        let err = manager
+            .publish(
+                BUCKET_NAME,
+                None,
+                &key,
+                &mut second,
+                PublishMode::CompareAndSwap,
+            )
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(StoreError::Retry)),
+            "{err}"
+        );
+        let stored = manager.load::<Card>(BUCKET_NAME, &key).await?;
+        assert_eq!(stored.map(|c| c.name).as_deref(), Some("c"));
+
+        // The winner keeps going from the revision it got back
+        first.name = "d".to_string();
+        manager
+            .publish(
+                BUCKET_NAME,
+                None,
+                &key,
+                &mut first,
+                PublishMode::CompareAndSwap,
+            )
+            .await?;
+
+        // An upsert overwrites whatever revision it carries
+        manager
+            .publish(BUCKET_NAME, None, &key, &mut second, PublishMode::Upsert)
+            .await?;
+        let stored = manager.load::<Card>(BUCKET_NAME, &key).await?;
+        assert_eq!(stored.map(|c| c.name).as_deref(), Some("b"));
+
+        // ...and hands back a revision to compare against, as an update does
+        second.name = "e".to_string();
+        manager
+            .publish(
+                BUCKET_NAME,
+                None,
+                &key,
+                &mut second,
+                PublishMode::CompareAndSwap,
+            )
+            .await?;
+        manager
+            .publish(
+                BUCKET_NAME,
+                None,
+                &key,
+                &mut second,
+                PublishMode::UpdateOnly,
+            )
+            .await?;
+        manager
+            .publish(
+                BUCKET_NAME,
+                None,
+                &key,
+                &mut second,
+                PublishMode::CompareAndSwap,
+            )
+            .await?;
+
+        // Deleted since it was written, e.g. its lease expired: an upsert brings it back
+        let bucket = manager.get_or_create_bucket(BUCKET_NAME, None).await?;
+        bucket.delete(&key).await?;
+        let err = manager
+            .publish(BUCKET_NAME, None, &key, &mut first, PublishMode::UpdateOnly)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(StoreError::MissingKey(_))),
+            "{err}"
+        );
+        let err = manager
+            .publish(
+                BUCKET_NAME,
+                None,
+                &key,
+                &mut first,
+                PublishMode::CompareAndSwap,
+            )
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(StoreError::Retry)),
+            "{err}"
+        );
+        manager
+            .publish(BUCKET_NAME, None, &key, &mut first, PublishMode::Upsert)
+            .await?;
+        let stored = manager.load::<Card>(BUCKET_NAME, &key).await?;
+        assert_eq!(stored.map(|c| c.name).as_deref(), Some("d"));
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_update_retries_lost_races() -> anyhow::Result<()> {
+        let manager = KeyValueStoreManager::memory();
+        let key: Key = "counter".into();
+        let add_one = |n: Option<u64>| -> anyhow::Result<u64> { Ok(n.unwrap_or(0) + 1) };
+        assert_eq!(manager.update(BUCKET_NAME, &key, add_one).await?, 1);
+        assert_eq!(manager.update(BUCKET_NAME, &key, add_one).await?, 2);
+
+        // Another writer gets in between our read and our write, twice
+        let bucket = manager.get_or_create_bucket(BUCKET_NAME, None).await?;
+        let mut calls = 0;
+        let n = manager
+            .update(BUCKET_NAME, &key, |n: Option<u64>| {
+                calls += 1;
+                if calls <= 2 {
+                    futures::executor::block_on(bucket.insert(&key, "100", 100 + calls))?;
+                }
+                Ok(n.unwrap_or(0) + 1)
+            })
+            .await?;
+        assert_eq!(n, 101);
+        assert_eq!(calls, 3);
+
+        // ...every time
+        let mut calls = 0;
+        let err = manager
+            .update(BUCKET_NAME, &key, |n: Option<u64>| {
+                calls += 1;
+                futures::executor::block_on(bucket.insert(&key, "0", 200 + calls))?;
+                Ok(n.unwrap_or(0) + 1)
+            })
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(StoreError::Retry)),
+            "{err}"
+        );
+        assert_eq!(calls, UPDATE_MAX_ATTEMPTS as u64);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_shares_one_watch() -> anyhow::Result<()> {
+        let manager = KeyValueStoreManager::memory();
+        let bucket = manager.get_or_create_bucket(BUCKET_NAME, None).await?;
+        bucket.insert(&"before".into(), "1", 0).await?;
+
+        let mut first = manager.subscribe(BUCKET_NAME, None);
+        let event = first.recv().await.unwrap()?;
+        assert_eq!(event.key_value().map(KeyValue::key), Some("before"));
+        assert_eq!(
+            first.recv().await.unwrap()?,
+            WatchEvent::InitialSyncComplete
+        );
+
+        // Joins the same watch, and is caught up from it
+        let mut second = manager.clone().subscribe(BUCKET_NAME, None);
+        assert_eq!(manager.watches.len(), 1);
+        let event = second.recv().await.unwrap()?;
+        assert_eq!(event.key_value().map(KeyValue::key), Some("before"));
+        assert_eq!(
+            second.recv().await.unwrap()?,
+            WatchEvent::InitialSyncComplete
+        );
+        bucket.insert(&"after".into(), "2", 0).await?;
+        for subscriber in [&mut first, &mut second] {
+            let event = subscriber.recv().await.unwrap()?;
+            assert_eq!(event.key_value().map(KeyValue::key), Some("after"));
+        }
+
+        drop(first);
+        assert_eq!(manager.watches.len(), 1);
+        drop(second);
+        assert_eq!(manager.watches.len(), 0);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_watch_bounded_drops_oldest() -> anyhow::Result<()> {
+        let manager = Arc::new(KeyValueStoreManager::memory());
+        let bucket = manager.get_or_create_bucket(BUCKET_NAME, None).await?;
+        for key in ["a", "b", "c", "d"] {
+            bucket.insert(&key.into(), "1", 0).await?;
+        }
+        let bounds = WatchBounds {
+            capacity: 3,
+            overflow: WatchOverflow::DropOldest,
+        };
+        let cancel_token = CancellationToken::new();
+        let mut rx = manager.watch_bounded(BUCKET_NAME, None, bounds, cancel_token.clone());
+        // Nobody reads while the entries are sent
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert_eq!(rx.lag().dropped, 2);
+
+        assert_eq!(rx.recv().await, Some(Err(WatchGap::Lagged(2))));
+        for _ in 0..2 {
+            assert!(matches!(rx.recv().await, Some(Ok(WatchEvent::Put(_)))));
+        }
+        assert_eq!(rx.recv().await, Some(Ok(WatchEvent::InitialSyncComplete)));
+        cancel_token.cancel();
+        assert_eq!(rx.recv().await, Some(Ok(WatchEvent::Closed)));
+        assert!(rx.recv().await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_watch_filtered() -> anyhow::Result<()> {
+        let manager = Arc::new(KeyValueStoreManager::memory());
+        let bucket = manager.get_or_create_bucket(BUCKET_NAME, None).await?;
+        bucket.insert(&"backend-a".into(), "ready", 0).await?;
+        bucket.insert(&"frontend-a".into(), "ready", 0).await?;
+        let filter = WatchFilter::prefix("backend-").matching(|kv| kv.value() != "draining");
+        let cancel_token = CancellationToken::new();
+        let mut rx = manager.watch_filtered(BUCKET_NAME, None, filter, cancel_token.clone());
+        let mut next = async || match rx.recv().await.unwrap() {
+            WatchEvent::Put(kv) => format!("put {}", kv.key()),
+            WatchEvent::Delete(kv) => format!("delete {}", kv.key()),
+            event => format!("{event:?}"),
+        };
+        assert_eq!(next().await, "put backend-a");
+        assert_eq!(next().await, "InitialSyncComplete");
+
+        bucket.insert(&"frontend-b".into(), "ready", 0).await?;
+        bucket.insert(&"backend-b".into(), "ready", 0).await?;
+        assert_eq!(next().await, "put backend-b");
+        // No longer accepted, so gone as far as the receiver is concerned
+        bucket
+            .update_existing(&"backend-a".into(), "draining")
+            .await?;
+        assert_eq!(next().await, "delete backend-a");
+        bucket.delete(&"frontend-a".into()).await?;
+        bucket.delete(&"backend-a".into()).await?;
+        bucket.delete(&"backend-b".into()).await?;
+        assert_eq!(next().await, "delete backend-b");
+        cancel_token.cancel();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_publish_delta() -> anyhow::Result<()> {
+        let manager = Arc::new(KeyValueStoreManager::memory());
+        let key: Key = "card".into();
+        let card = |name: &str| Card {
+            name: name.to_string(),
+            revision: 0,
+        };
+        let cancel_token = CancellationToken::new();
+        let filter = WatchFilter::prefix("");
+        let mut rx =
+            manager
+                .clone()
+                .watch_with_deltas(BUCKET_NAME, None, filter, cancel_token.clone());
+        let mut next_delta = async || loop {
+            if let WatchEvent::Put(kv) = rx.recv().await.unwrap() {
+                return kv.delta().cloned();
+            }
+        };
+
+        manager
+            .publish(BUCKET_NAME, None, &key, &mut card("a"), PublishMode::Upsert)
+            .await?;
+        assert_eq!(next_delta().await, None);
+        let (a, mut b) = (card("a"), card("b"));
+        manager
+            .publish_delta(BUCKET_NAME, None, &key, &mut b, &a, PublishMode::Upsert)
+            .await?;
+        assert_eq!(next_delta().await, Some(serde_json::json!({"name": "b"})));
+        // Readers get the value, not the header
+        let stored = manager.load::<Card>(BUCKET_NAME, &key).await?;
+        assert_eq!(stored.map(|c| c.name).as_deref(), Some("b"));
+
+        // Another writer got in between, so the patch from "b" doesn't apply
+        manager
+            .publish(BUCKET_NAME, None, &key, &mut card("c"), PublishMode::Upsert)
+            .await?;
+        assert_eq!(next_delta().await, None);
+        manager
+            .publish_delta(
+                BUCKET_NAME,
+                None,
+                &key,
+                &mut card("d"),
+                &b,
+                PublishMode::Upsert,
+            )
+            .await?;
+        assert_eq!(next_delta().await, None);
+        cancel_token.cancel();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_watch_sharded() -> anyhow::Result<()> {
+        let keys: Vec<String> = (0..32).map(|i| format!("backend-{i}")).collect();
+        for key in &keys {
+            let kv = KeyValue::new(key.clone(), "ready".into());
+            let shards: Vec<_> = (0..4)
+                .filter(|&index| {
+                    WatchFilter::default()
+                        .shard(index, 4)
+                        .accepts(BUCKET_NAME, &kv)
+                })
+                .collect();
+            assert_eq!(shards.len(), 1, "{key} is in shards {shards:?}");
+        }
+
+        let manager = Arc::new(KeyValueStoreManager::memory());
+        let bucket = manager.get_or_create_bucket(BUCKET_NAME, None).await?;
+        for key in &keys {
+            bucket.insert(&key.as_str().into(), "ready", 0).await?;
+        }
+        // Cut into key ranges of as many keys each, by the keys there are
+        let filters = manager
+            .shard_filters(BUCKET_NAME, None, WatchFilter::prefix("backend-"), 4)
+            .await;
+        let sizes: Vec<usize> = filters
+            .iter()
+            .map(|filter| {
+                let accepts = |key: &String| {
+                    filter.accepts(BUCKET_NAME, &KeyValue::new(key.clone(), "ready".into()))
+                };
+                keys.iter().filter(|key| accepts(key)).count()
+            })
+            .collect();
+        assert_eq!(sizes, vec![8; 4]);
+        // The last shard asks for no more than the prefix holds
+        let last = filters.last().and_then(|filter| filter.shard.clone());
+        assert!(
+            matches!(&last, Some(Shard::Range(_, end)) if end == "backend."),
+            "{last:?}"
+        );
+        assert_eq!(prefix_end("a\u{10ffff}"), "b");
+        assert_eq!(prefix_end(""), "");
+        let cancel_token = CancellationToken::new();
+        let filter = WatchFilter::prefix("backend-");
+        let mut rx = manager.watch_sharded(BUCKET_NAME, None, filter, 4, cancel_token.clone());
+        let mut seen = Vec::new();
+        loop {
+            match rx.recv().await.unwrap() {
+                WatchEvent::Put(kv) => seen.push(kv.key().to_string()),
+                WatchEvent::InitialSyncComplete => break,
+                event => panic!("unexpected {event:?}"),
+            }
+        }
+        seen.sort();
+        let mut expected = keys.clone();
+        expected.sort();
+        assert_eq!(seen, expected);
+
+        // Changes to one key stay in order, whichever shard it is in
+        for value in ["draining", "ready", "gone"] {
+            bucket.update_existing(&"backend-7".into(), value).await?;
+        }
+        for value in ["draining", "ready", "gone"] {
+            let Some(WatchEvent::Put(kv)) = rx.recv().await else {
+                panic!("expected a put");
+            };
+            assert_eq!(
+                (kv.key(), kv.value().as_ref()),
+                ("backend-7", value.as_bytes())
+            );
+        }
+        cancel_token.cancel();
+        assert_eq!(rx.recv().await, Some(WatchEvent::Closed));
+        assert_eq!(rx.recv().await, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_watch_events_are_timestamped() -> anyhow::Result<()> {
+        let manager = Arc::new(KeyValueStoreManager::memory());
+        let bucket = manager.get_or_create_bucket(BUCKET_NAME, None).await?;
+        let cancel_token = CancellationToken::new();
+        let mut rx = manager.watch(BUCKET_NAME, None, cancel_token.clone());
+        assert_eq!(rx.recv().await, Some(WatchEvent::InitialSyncComplete));
+
+        let observed = watch_propagation().get_sample_count();
+        bucket.insert(&"key".into(), "value", 0).await?;
+        let Some(WatchEvent::Put(kv)) = rx.recv().await else {
+            panic!("expected a put");
+        };
+        assert!(kv.committed_at().unwrap() <= kv.received_at().unwrap());
+        assert!(kv.propagation_latency().is_some());
+        // Other tests watch too, so only a lower bound
+        assert!(watch_propagation().get_sample_count() > observed);
+        // Timing doesn't make it a different change
+        let untimed = KeyValue::new("key".to_string(), "value".into());
+        assert_eq!(kv, untimed.with_sequence(kv.sequence()));
+        cancel_token.cancel();
+        Ok(())
+    }
+
+    #[test]
+    fn test_store_error_sources() {
+        use std::error::Error as _;
+
+        let reset = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "connection reset");
+        let err = StoreError::nats(reset);
+        // Callers still parse these
+        assert_eq!(err.to_string(), "Internal NATS error: connection reset");
+        let source = err
+            .source()
+            .unwrap()
+            .downcast_ref::<std::io::Error>()
+            .unwrap();
+        assert_eq!(source.kind(), std::io::ErrorKind::ConnectionReset);
+        assert!(err.is_transient());
+        assert!(!err.is_not_found());
+
+        let err = StoreError::provider("unsupported");
+        assert_eq!(err.to_string(), "Internal storage error: 'unsupported'");
+        assert!(err.source().is_none());
+
+        assert!(StoreError::MissingKey("key".to_string()).is_not_found());
+        assert!(StoreError::MissingBucket("bucket".to_string()).is_not_found());
+        assert!(!StoreError::Retry.is_not_found());
+    }
+
+    #[test]
+    fn test_store_error_display_is_stable() {
+        let op_id = OpId::new();
+        let etcd = StoreError::EtcdError {
+            kind: EtcdErrorKind::Unavailable,
+            message: "no members".to_string(),
+            source: None,
+            op_id: None,
+        };
+        let key_value = StoreError::key_value(std::io::Error::other("closed"), "v1/mdc");
+        let cases = [
+            (etcd, "Internal etcd error: no members"),
+            (
+                StoreError::provider("unsupported"),
+                "Internal storage error: 'unsupported'",
+            ),
+            (key_value, "Key Value Error: closed for bucket 'v1/mdc'"),
+            (
+                StoreError::MissingKey("k".to_string()),
+                "Could not find key 'k'",
+            ),
+            (StoreError::Retry, "Race condition, retry the call"),
+        ];
+        for (err, expected) in cases {
+            // The op id is a field, the message is the one callers have always parsed
+            let err = err.with_op_id(op_id);
+            assert_eq!(err.to_string(), expected);
+        }
+    }
 }
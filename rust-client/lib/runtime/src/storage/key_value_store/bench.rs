@@ -0,0 +1,213 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A fixed workload for comparing store backends, shared by the `store_backends` criterion
+//! bench and `rust-client bench`. [`OpStats`] also reports `rust-client loadgen` latencies.
+//!
+//! Each run inserts `keys` fresh keys into a new bucket, reads them back and deletes them,
+//! timing every operation. A watch on the bucket measures how long each insert takes to reach
+//! a watcher.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::{Key, KeyValueStoreManager, StoreError, WatchEvent};
+use crate::CancellationToken;
+
+/// How long to wait for the watch to deliver every insert
+const WATCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BenchConfig {
+    /// Keys inserted, read and deleted per run
+    pub keys: usize,
+    /// Size of every value in bytes
+    pub value_size: usize,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        BenchConfig {
+            keys: 1_000,
+            value_size: 256,
+        }
+    }
+}
+
+/// The timings of one kind of operation in a run
+#[derive(Debug, Clone)]
+pub struct OpStats {
+    pub op: &'static str,
+    /// Wall time for all of them, issued one after the other
+    pub elapsed: Duration,
+    /// Sorted
+    latencies: Vec<Duration>,
+}
+
+impl OpStats {
+    pub(crate) fn new(op: &'static str, elapsed: Duration, mut latencies: Vec<Duration>) -> Self {
+        latencies.sort_unstable();
+        OpStats {
+            op,
+            elapsed,
+            latencies,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.latencies.len()
+    }
+
+    /// Operations per second
+    pub fn throughput(&self) -> f64 {
+        self.count() as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// Nearest-rank percentile, `p` in 0..=100
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = (p / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        self.latencies[rank.clamp(1, self.latencies.len()) - 1]
+    }
+}
+
+impl fmt::Display for OpStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<7}{:>8} ops {:>10.0}/s  p50 {:>9.3?}  p90 {:>9.3?}  p99 {:>9.3?}  max {:>9.3?}",
+            self.op,
+            self.count(),
+            self.throughput(),
+            self.percentile(50.0),
+            self.percentile(90.0),
+            self.percentile(99.0),
+            self.percentile(100.0),
+        )
+    }
+}
+
+/// Run the workload once against `store`, returning insert, get, watch and delete timings.
+/// Watch latency runs from the start of each insert to its event reaching the watcher.
+pub async fn run(
+    store: &KeyValueStoreManager,
+    config: &BenchConfig,
+) -> Result<Vec<OpStats>, StoreError> {
+    let bucket_name = format!("bench-{}", uuid::Uuid::new_v4().simple());
+    let cancel_token = CancellationToken::new();
+    let _cancel_watch = cancel_token.clone().drop_guard();
+    let bucket = store.get_or_create_bucket(&bucket_name, None).await?;
+    let mut events = Arc::new(store.clone()).watch(&bucket_name, None, cancel_token);
+
+    // Record arrival times as they happen, not when we get round to reading them
+    let expected = config.keys;
+    let watcher = tokio::spawn(async move {
+        let mut arrivals = HashMap::with_capacity(expected);
+        while arrivals.len() < expected {
+            match events.recv().await {
+                Some(WatchEvent::Put(kv)) => {
+                    arrivals.insert(kv.key().to_string(), Instant::now());
+                }
+                Some(_) => {}
+                None => break,
+            }
+        }
+        arrivals
+    });
+
+    let value = "x".repeat(config.value_size);
+    let keys: Vec<Key> = (0..config.keys)
+        .map(|i| Key::from_raw(format!("key-{i:08}")))
+        .collect();
+
+    let mut sent = Vec::with_capacity(keys.len());
+    let mut latencies = Vec::with_capacity(keys.len());
+    let start = Instant::now();
+    for key in &keys {
+        let issued = Instant::now();
+        bucket.insert(key, &value, 0).await?;
+        latencies.push(issued.elapsed());
+        sent.push(issued);
+    }
+    let insert = OpStats::new("insert", start.elapsed(), latencies);
+
+    let mut latencies = Vec::with_capacity(keys.len());
+    let start = Instant::now();
+    for key in &keys {
+        let issued = Instant::now();
+        if bucket.get(key).await?.is_none() {
+            return Err(StoreError::MissingKey(key.to_string()));
+        }
+        latencies.push(issued.elapsed());
+    }
+    let get = OpStats::new("get", start.elapsed(), latencies);
+
+    let arrivals = tokio::time::timeout(WATCH_TIMEOUT, watcher)
+        .await
+        .map_err(|_| {
+            StoreError::provider(format!(
+                "Watch did not see all inserts in {WATCH_TIMEOUT:?}"
+            ))
+        })?
+        .map_err(|err| StoreError::provider(format!("Watch task failed: {err}")))?;
+    let mut latencies = Vec::with_capacity(keys.len());
+    let mut last_arrival = None;
+    for (key, issued) in keys.iter().zip(&sent) {
+        if let Some(arrived) = arrivals.get(key.as_ref()) {
+            latencies.push(arrived.saturating_duration_since(*issued));
+            last_arrival = last_arrival.max(Some(*arrived));
+        }
+    }
+    let watch_elapsed = match (sent.first(), last_arrival) {
+        (Some(first), Some(last)) => last.saturating_duration_since(*first),
+        _ => Duration::ZERO,
+    };
+    let watch = OpStats::new("watch", watch_elapsed, latencies);
+
+    let mut latencies = Vec::with_capacity(keys.len());
+    let start = Instant::now();
+    for key in &keys {
+        let issued = Instant::now();
+        bucket.delete(key).await?;
+        latencies.push(issued.elapsed());
+    }
+    let delete = OpStats::new("delete", start.elapsed(), latencies);
+
+    Ok(vec![insert, get, watch, delete])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentile() {
+        let latencies = (1..=100).map(Duration::from_millis).collect();
+        let stats = OpStats::new("get", Duration::from_secs(2), latencies);
+        assert_eq!(stats.count(), 100);
+        assert_eq!(stats.throughput(), 50.0);
+        assert_eq!(stats.percentile(50.0), Duration::from_millis(50));
+        assert_eq!(stats.percentile(99.0), Duration::from_millis(99));
+        assert_eq!(stats.percentile(100.0), Duration::from_millis(100));
+        assert_eq!(stats.percentile(0.0), Duration::from_millis(1));
+    }
+
+    #[tokio::test]
+    async fn test_memory_run() {
+        let store = KeyValueStoreManager::memory();
+        let config = BenchConfig {
+            keys: 50,
+            value_size: 16,
+        };
+        let stats = run(&store, &config).await.unwrap();
+        let ops: Vec<_> = stats.iter().map(|s| (s.op, s.count())).collect();
+        assert_eq!(
+            ops,
+            [("insert", 50), ("get", 50), ("watch", 50), ("delete", 50)]
+        );
+    }
+}
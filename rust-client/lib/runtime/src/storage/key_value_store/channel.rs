@@ -0,0 +1,257 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bounded channels from a store watch to its consumer.
+//!
+//! [`KeyValueStoreManager::watch`](super::KeyValueStoreManager::watch) queues events without
+//! limit, so a consumer that stops reading grows the runtime's memory until it resumes. A
+//! [`WatchReceiver`] from [`KeyValueStoreManager::watch_bounded`] holds a fixed number instead,
+//! and either drops the oldest ones, saying so with a [`WatchGap`], or stops reading the store
+//! until there is room.
+//!
+//! [`KeyValueStoreManager::watch_bounded`]: super::KeyValueStoreManager::watch_bounded
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use tokio::sync::{Notify, mpsc};
+
+use super::{WatchEvent, WatchGap};
+
+/// What a bounded watch does with an event when its receiver's queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchOverflow {
+    /// Drop the oldest queued event. The receiver gets a [`WatchGap::Lagged`] in its place and
+    /// should re-read the bucket.
+    DropOldest,
+    /// Wait for the receiver to make room. Changes pile up in the store meanwhile, not here.
+    Block,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchBounds {
+    /// Events queued for the receiver at most. At least 1.
+    pub capacity: usize,
+    pub overflow: WatchOverflow,
+}
+
+/// How far behind a [`WatchReceiver`] is, and has been
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WatchLag {
+    /// Events waiting to be received now
+    pub queued: usize,
+    /// The most that were ever waiting
+    pub max_queued: usize,
+    /// Events dropped with [`WatchOverflow::DropOldest`], in total
+    pub dropped: u64,
+}
+
+pub(super) fn channel(bounds: WatchBounds) -> (WatchSender, WatchReceiver) {
+    let shared = Arc::new(Shared {
+        bounds: WatchBounds {
+            capacity: bounds.capacity.max(1),
+            ..bounds
+        },
+        queue: Mutex::new(Queue::default()),
+        readable: Notify::new(),
+        writable: Notify::new(),
+    });
+    (
+        WatchSender::Bounded(shared.clone()),
+        WatchReceiver { shared },
+    )
+}
+
+/// The sending half of either kind of watch channel
+pub(super) enum WatchSender {
+    Unbounded(mpsc::UnboundedSender<WatchEvent>),
+    Bounded(Arc<Shared>),
+}
+
+impl WatchSender {
+    /// False if the receiver is gone
+    pub(super) async fn send(&self, event: WatchEvent) -> bool {
+        let shared = match self {
+            WatchSender::Unbounded(tx) => return tx.send(event).is_ok(),
+            WatchSender::Bounded(shared) => shared,
+        };
+        loop {
+            {
+                let mut queue = shared.queue.lock();
+                if queue.receiver_dropped {
+                    return false;
+                }
+                let full = queue.events.len() >= shared.bounds.capacity;
+                if !full || shared.bounds.overflow == WatchOverflow::DropOldest {
+                    if full {
+                        queue.events.pop_front();
+                        queue.unreported += 1;
+                        queue.lag.dropped += 1;
+                    }
+                    queue.events.push_back(event);
+                    queue.lag.max_queued = queue.lag.max_queued.max(queue.events.len());
+                    break;
+                }
+            }
+            // Stores a permit if the receiver frees a slot before we get here
+            shared.writable.notified().await;
+        }
+        shared.readable.notify_one();
+        true
+    }
+
+    pub(super) fn is_closed(&self) -> bool {
+        match self {
+            WatchSender::Unbounded(tx) => tx.is_closed(),
+            WatchSender::Bounded(shared) => shared.queue.lock().receiver_dropped,
+        }
+    }
+}
+
+impl Drop for WatchSender {
+    fn drop(&mut self) {
+        if let WatchSender::Bounded(shared) = self {
+            shared.queue.lock().sender_dropped = true;
+            shared.readable.notify_one();
+        }
+    }
+}
+
+pub struct WatchReceiver {
+    shared: Arc<Shared>,
+}
+
+impl WatchReceiver {
+    /// The next event, or None once the watch has ended and every event was received. A
+    /// [`WatchGap`] stands for events dropped since the last one received.
+    pub async fn recv(&mut self) -> Option<Result<WatchEvent, WatchGap>> {
+        loop {
+            {
+                let mut queue = self.shared.queue.lock();
+                if queue.unreported > 0 {
+                    let missed = std::mem::take(&mut queue.unreported);
+                    return Some(Err(WatchGap::Lagged(missed)));
+                }
+                if let Some(event) = queue.events.pop_front() {
+                    drop(queue);
+                    self.shared.writable.notify_one();
+                    return Some(Ok(event));
+                }
+                if queue.sender_dropped {
+                    return None;
+                }
+            }
+            self.shared.readable.notified().await;
+        }
+    }
+
+    pub fn lag(&self) -> WatchLag {
+        let queue = self.shared.queue.lock();
+        WatchLag {
+            queued: queue.events.len(),
+            ..queue.lag
+        }
+    }
+}
+
+impl Drop for WatchReceiver {
+    fn drop(&mut self) {
+        let mut queue = self.shared.queue.lock();
+        queue.receiver_dropped = true;
+        queue.events.clear();
+        drop(queue);
+        self.shared.writable.notify_one();
+    }
+}
+
+pub(super) struct Shared {
+    bounds: WatchBounds,
+    queue: Mutex<Queue>,
+    /// Wakes the receiver. There is one of each side, so `notify_one`'s stored permit means
+    /// no wakeup is lost.
+    readable: Notify,
+    /// Wakes a sender blocked on a full queue
+    writable: Notify,
+}
+
+#[derive(Default)]
+struct Queue {
+    events: VecDeque<WatchEvent>,
+    /// Dropped since the receiver was last told
+    unreported: u64,
+    lag: WatchLag,
+    sender_dropped: bool,
+    receiver_dropped: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::key_value_store::KeyValue;
+
+    fn put(sequence: u64) -> WatchEvent {
+        WatchEvent::Put(KeyValue::new("k".to_string(), bytes::Bytes::new()).with_sequence(sequence))
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest_reports_gap() {
+        let (tx, mut rx) = channel(WatchBounds {
+            capacity: 2,
+            overflow: WatchOverflow::DropOldest,
+        });
+        for sequence in 1..=5 {
+            assert!(tx.send(put(sequence)).await);
+        }
+        drop(tx);
+        assert_eq!(
+            rx.lag(),
+            WatchLag {
+                queued: 2,
+                max_queued: 2,
+                dropped: 3
+            }
+        );
+
+        assert_eq!(rx.recv().await, Some(Err(WatchGap::Lagged(3))));
+        assert_eq!(rx.recv().await.unwrap().unwrap().sequence(), 4);
+        assert_eq!(rx.recv().await.unwrap().unwrap().sequence(), 5);
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_block_waits_for_room() {
+        let (tx, mut rx) = channel(WatchBounds {
+            capacity: 1,
+            overflow: WatchOverflow::Block,
+        });
+        let sender = tokio::spawn(async move {
+            for sequence in 1..=3 {
+                assert!(tx.send(put(sequence)).await);
+            }
+        });
+        tokio::task::yield_now().await;
+        assert_eq!(rx.lag().queued, 1);
+
+        for expected in 1..=3 {
+            assert_eq!(rx.recv().await.unwrap().unwrap().sequence(), expected);
+        }
+        sender.await.unwrap();
+        assert!(rx.recv().await.is_none());
+        assert_eq!(rx.lag().dropped, 0);
+        assert_eq!(rx.lag().max_queued, 1);
+    }
+
+    #[tokio::test]
+    async fn test_send_fails_without_receiver() {
+        let (tx, rx) = channel(WatchBounds {
+            capacity: 1,
+            overflow: WatchOverflow::Block,
+        });
+        assert!(tx.send(put(1)).await);
+        let blocked = tokio::spawn(async move { tx.send(put(2)).await });
+        tokio::task::yield_now().await;
+        drop(rx);
+        assert!(!blocked.await.unwrap());
+    }
+}
@@ -0,0 +1,801 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! [`KeyValueStore`] on Consul KV.
+//!
+//! A bucket is a key prefix, as with etcd. Keys we create are acquired by a Consul session with
+//! `Behavior=delete`, which plays the role of the etcd primary lease: the store renews the session
+//! in the background, and if the process dies the session expires and Consul deletes its keys.
+//!
+//! If Consul forgets the session while we live, as after missed renewals or a lost Consul
+//! cluster, its keys are gone with it. The store then creates a new session, acquires the keys it
+//! held again with their last values, and reports the new session through
+//! [`ConsulStore::session_changes`]. A key someone else wrote in between is left to them.
+
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_stream::stream;
+use async_trait::async_trait;
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use parking_lot::Mutex;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::Runtime;
+use crate::storage::key_value_store::{Key, KeyValue, ReadConsistency, WatchEvent};
+
+use super::{Conditional, KeyValueBucket, KeyValueStore, StoreError, StoreOutcome};
+
+/// How long a blocking watch query waits server side before returning unchanged
+const WATCH_WAIT: &str = "5m";
+
+#[derive(Debug, Clone)]
+pub struct ConsulOptions {
+    /// e.g. `http://127.0.0.1:8500`
+    pub address: String,
+    /// ACL token, sent as `X-Consul-Token`
+    pub token: Option<String>,
+    /// Session TTL. Keys are deleted this long (up to twice this, per Consul) after we stop
+    /// renewing.
+    pub session_ttl: Duration,
+}
+
+impl Default for ConsulOptions {
+    /// Reads `CONSUL_HTTP_ADDR` and `CONSUL_HTTP_TOKEN` like the consul CLI
+    fn default() -> Self {
+        let address =
+            std::env::var("CONSUL_HTTP_ADDR").unwrap_or_else(|_| "127.0.0.1:8500".to_string());
+        let address = if address.contains("://") {
+            address
+        } else {
+            format!("http://{address}")
+        };
+        ConsulOptions {
+            address,
+            token: std::env::var("CONSUL_HTTP_TOKEN").ok(),
+            session_ttl: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ConsulStore {
+    api: ConsulApi,
+    session: Arc<Session>,
+}
+
+/// Our Consul session, and what it holds to acquire again should Consul forget it
+struct Session {
+    id: watch::Sender<String>,
+    /// The keys acquired by the session, with their base64 values as last written
+    held: Mutex<HashMap<String, String>>,
+}
+
+impl Session {
+    fn id(&self) -> String {
+        self.id.borrow().clone()
+    }
+}
+
+/// What Consul answered to a session renewal
+#[derive(Debug, PartialEq, Eq)]
+enum Renewal {
+    Renewed,
+    /// Consul no longer knows the session, and deleted its keys
+    Invalid,
+}
+
+impl ConsulStore {
+    /// Create a session and keep it alive until the runtime shuts down, at which point the
+    /// session is destroyed and the keys it holds are deleted. The session is renewed, and
+    /// replaced if Consul forgets it, by a task on the runtime.
+    pub async fn new(options: ConsulOptions, runtime: &Runtime) -> Result<Self, StoreError> {
+        let api = ConsulApi {
+            http: reqwest::Client::new(),
+            address: options.address.trim_end_matches('/').to_string(),
+            token: options.token,
+        };
+        let ttl_secs = options.session_ttl.as_secs().max(10);
+        let (id, _) = watch::channel(api.create_session(ttl_secs).await?);
+        let session = Arc::new(Session {
+            id,
+            held: Mutex::new(HashMap::new()),
+        });
+        runtime.secondary().spawn(keep_session(
+            api.clone(),
+            session.clone(),
+            ttl_secs,
+            runtime.child_token(),
+        ));
+        Ok(ConsulStore { api, session })
+    }
+
+    /// The Consul session ID that owns our keys
+    pub fn session(&self) -> String {
+        self.session.id()
+    }
+
+    /// The session ID, changing each time Consul forgot the session and a new one took over
+    /// its keys. Registrations made outside the store have to be made again then.
+    pub fn session_changes(&self) -> watch::Receiver<String> {
+        self.session.id.subscribe()
+    }
+
+    fn bucket(&self, bucket_name: &str) -> ConsulBucket {
+        ConsulBucket {
+            api: self.api.clone(),
+            session: self.session.clone(),
+            bucket_name: bucket_name.to_string(),
+        }
+    }
+}
+
+/// Renew `session` every half TTL until `cancel_token` is cancelled, then destroy it. If Consul
+/// forgot it, create another and acquire the held keys again under it.
+async fn keep_session(
+    api: ConsulApi,
+    session: Arc<Session>,
+    ttl_secs: u64,
+    cancel_token: CancellationToken,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(ttl_secs) / 2);
+    loop {
+        tokio::select! {
+            _ = cancel_token.cancelled() => break,
+            _ = interval.tick() => {}
+        }
+        let id = session.id();
+        match api.renew_session(&id).await {
+            Ok(Renewal::Renewed) => continue,
+            Ok(Renewal::Invalid) => {}
+            // Within the TTL the next one may still get through
+            Err(err) => {
+                tracing::warn!(%err, session = id, "Failed to renew Consul session");
+                continue;
+            }
+        }
+        tracing::error!(
+            session = id,
+            "Consul session invalidated and its keys deleted, creating a new one"
+        );
+        let new_id = loop {
+            match api.create_session(ttl_secs).await {
+                Ok(new_id) => break new_id,
+                Err(err) => tracing::warn!(%err, "Failed to create a new Consul session"),
+            }
+            tokio::select! {
+                _ = cancel_token.cancelled() => return,
+                _ = interval.tick() => {}
+            }
+        };
+        session.id.send_replace(new_id.clone());
+        let held: Vec<_> = session.held.lock().clone().into_iter().collect();
+        for (key, value) in held {
+            let ops = [
+                // Only if nobody wrote it since ours was deleted
+                json!({ "KV": { "Verb": "cas", "Key": key, "Value": value, "Index": 0 } }),
+                lock_op(&new_id, &key, &value),
+            ];
+            match api.txn(&ops).await {
+                Ok(TxnOutcome::Committed(_)) => continue,
+                Ok(TxnOutcome::RolledBack { .. }) => {
+                    tracing::warn!(
+                        key,
+                        "Key taken while our Consul session was gone, leaving it"
+                    );
+                }
+                Err(err) => tracing::error!(%err, key, "Failed to acquire key again"),
+            }
+            session.held.lock().remove(&key);
+        }
+        tracing::info!(session = new_id, "Consul session replaced");
+    }
+    let id = session.id();
+    let path = format!("/v1/session/destroy/{id}");
+    if let Err(err) = api.request(reqwest::Method::PUT, &path).send().await {
+        tracing::warn!(%err, session = id, "Failed to destroy Consul session");
+    }
+}
+
+#[async_trait]
+impl KeyValueStore for ConsulStore {
+    type Bucket = ConsulBucket;
+
+    /// A "bucket" in Consul is a path prefix
+    async fn get_or_create_bucket(
+        &self,
+        bucket_name: &str,
+        _ttl: Option<Duration>, // Keys live as long as the session instead
+    ) -> Result<Self::Bucket, StoreError> {
+        Ok(self.bucket(bucket_name))
+    }
+
+    async fn get_bucket(&self, bucket_name: &str) -> Result<Option<Self::Bucket>, StoreError> {
+        Ok(Some(self.bucket(bucket_name)))
+    }
+
+    fn connection_id(&self) -> u64 {
+        xxh3_64(self.session.id().as_bytes())
+    }
+}
+
+pub struct ConsulBucket {
+    api: ConsulApi,
+    session: Arc<Session>,
+    bucket_name: String,
+}
+
+impl ConsulBucket {
+    fn lock_op(&self, key: &str, value: &str) -> serde_json::Value {
+        lock_op(&self.session.id(), key, value)
+    }
+
+    /// Remember that our session holds `key`, for [`keep_session`] to acquire it again
+    fn held(&self, key: String, value: String) {
+        self.session.held.lock().insert(key, value);
+    }
+}
+
+/// Attach `key` to `session` so it is ephemeral. Fails if another session holds it.
+fn lock_op(session: &str, key: &str, value: &str) -> serde_json::Value {
+    json!({ "KV": { "Verb": "lock", "Key": key, "Value": value, "Session": session } })
+}
+
+/// How a Consul transaction ended
+#[derive(Debug, PartialEq, Eq)]
+enum TxnOutcome {
+    /// With the ModifyIndex of the key written last
+    Committed(u64),
+    /// Nothing was written because operation `op` failed
+    RolledBack { op: usize },
+}
+
+#[async_trait]
+impl KeyValueBucket for ConsulBucket {
+    /// `revision` is the Consul ModifyIndex of the previous write. 0 creates the key if it does
+    /// not exist. Like the etcd store, a non-zero revision overwrites regardless of the current
+    /// index. [`StoreError::SessionConflict`] if another session holds the key.
+    async fn insert(
+        &self,
+        key: &Key,
+        value: &str,
+        revision: u64,
+    ) -> Result<StoreOutcome, StoreError> {
+        let k = make_key(&self.bucket_name, key);
+        tracing::trace!("consul insert: {k}");
+
+        let value = BASE64.encode(value);
+        let mut ops = vec![];
+        if revision == 0 {
+            // Index 0 means "only if the key does not exist"
+            ops.push(json!({ "KV": { "Verb": "cas", "Key": k, "Value": value, "Index": 0 } }));
+        }
+        ops.push(self.lock_op(&k, &value));
+
+        match self.api.txn(&ops).await? {
+            TxnOutcome::Committed(index) => {
+                self.held(k, value);
+                Ok(StoreOutcome::Created(index))
+            }
+            TxnOutcome::RolledBack { op } if op == ops.len() - 1 => {
+                Err(StoreError::SessionConflict(k))
+            }
+            TxnOutcome::RolledBack { .. } => {
+                let index = self
+                    .api
+                    .get_entries(&k, false)
+                    .await?
+                    .first()
+                    .map(|e| e.modify_index)
+                    .ok_or(StoreError::Retry)?; // deleted in between
+                Ok(StoreOutcome::Exists(index))
+            }
+        }
+    }
+
+    /// Writes with `cas` at the index read, so a write in between is not overwritten
+    async fn update_existing(&self, key: &Key, value: &str) -> Result<StoreOutcome, StoreError> {
+        let k = make_key(&self.bucket_name, key);
+        loop {
+            let Some(entry) = self.api.get_entries(&k, false).await?.pop() else {
+                return Err(StoreError::MissingKey(key.to_string()));
+            };
+            match self.compare_and_swap(key, value, entry.modify_index).await {
+                // Changed since the read, read it again
+                Err(StoreError::Retry) => continue,
+                result => return result,
+            }
+        }
+    }
+
+    /// Only the lock, which writes the key whether it exists or not
+    async fn upsert(&self, key: &Key, value: &str) -> Result<StoreOutcome, StoreError> {
+        let k = make_key(&self.bucket_name, key);
+        tracing::trace!("consul upsert: {k}");
+
+        let value = BASE64.encode(value);
+        match self.api.txn(&[self.lock_op(&k, &value)]).await? {
+            TxnOutcome::Committed(index) => {
+                self.held(k, value);
+                Ok(StoreOutcome::Created(index))
+            }
+            TxnOutcome::RolledBack { .. } => Err(StoreError::SessionConflict(k)),
+        }
+    }
+
+    /// `cas` at `revision`, checked by Consul in the same transaction that writes the key
+    async fn compare_and_swap(
+        &self,
+        key: &Key,
+        value: &str,
+        revision: u64,
+    ) -> Result<StoreOutcome, StoreError> {
+        let k = make_key(&self.bucket_name, key);
+        tracing::trace!(revision, "consul compare_and_swap: {k}");
+
+        let value = BASE64.encode(value);
+        let ops = [
+            json!({ "KV": { "Verb": "cas", "Key": k, "Value": value, "Index": revision } }),
+            self.lock_op(&k, &value),
+        ];
+        match self.api.txn(&ops).await? {
+            TxnOutcome::Committed(index) => {
+                self.held(k, value);
+                Ok(StoreOutcome::Created(index))
+            }
+            TxnOutcome::RolledBack { op: 0 } => Err(StoreError::Retry),
+            TxnOutcome::RolledBack { .. } => Err(StoreError::SessionConflict(k)),
+        }
+    }
+
+    async fn get(&self, key: &Key) -> Result<Option<bytes::Bytes>, StoreError> {
+        let k = make_key(&self.bucket_name, key);
+        tracing::trace!("consul get: {k}");
+        let mut entries = self.api.get_entries(&k, false).await?;
+        match entries.pop() {
+            Some(entry) => Ok(Some(entry.decode()?.1)),
+            None => Ok(None),
+        }
+    }
+
+    /// The ModifyIndex is the revision
+    async fn get_if_changed(
+        &self,
+        key: &Key,
+        known_revision: u64,
+    ) -> Result<Conditional, StoreError> {
+        let k = make_key(&self.bucket_name, key);
+        tracing::trace!(known_revision, "consul get_if_changed: {k}");
+        let Some(entry) = self.api.get_entries(&k, false).await?.pop() else {
+            return Ok(Conditional::Missing);
+        };
+        let revision = entry.modify_index;
+        if revision == known_revision {
+            return Ok(Conditional::NotModified);
+        }
+        Ok(Conditional::Modified {
+            value: entry.decode()?.1,
+            revision,
+        })
+    }
+
+    async fn get_with_consistency(
+        &self,
+        key: &Key,
+        consistency: ReadConsistency,
+    ) -> Result<Option<bytes::Bytes>, StoreError> {
+        let k = make_key(&self.bucket_name, key);
+        tracing::trace!(?consistency, "consul get: {k}");
+        let mut entries = match consistency {
+            // Consul's indexes are not etcd revisions, only a consistent read is known to be
+            // caught up
+            ReadConsistency::Linearizable | ReadConsistency::AtLeast(_) => {
+                self.api.get_entry_with_mode(&k, "consistent").await?.0
+            }
+            ReadConsistency::Serializable => self.api.get_entry_with_mode(&k, "stale").await?.0,
+            ReadConsistency::BoundedStaleness(max) => {
+                let (entries, last_contact) = self.api.get_entry_with_mode(&k, "stale").await?;
+                if last_contact <= max {
+                    entries
+                } else {
+                    self.api.get_entry_with_mode(&k, "consistent").await?.0
+                }
+            }
+        };
+        match entries.pop() {
+            Some(entry) => Ok(Some(entry.decode()?.1)),
+            None => Ok(None),
+        }
+    }
+
+    async fn delete(&self, key: &Key) -> Result<(), StoreError> {
+        let k = make_key(&self.bucket_name, key);
+        tracing::trace!("consul delete: {k}");
+        self.api
+            .request(reqwest::Method::DELETE, &format!("/v1/kv/{k}"))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(consul_err)?;
+        self.session.held.lock().remove(&k);
+        Ok(())
+    }
+
+    /// Changes after the watch starts, from Consul blocking queries. Consul reports the whole
+    /// prefix each time anything under it changes, so events are the diff between snapshots.
+    async fn watch(
+        &self,
+    ) -> Result<Pin<Box<dyn futures::Stream<Item = WatchEvent> + Send + 'life0>>, StoreError> {
+        let prefix = make_key(&self.bucket_name, &"".into());
+        tracing::trace!("consul watch: {prefix}");
+
+        let (mut index, entries) = self.api.get_prefix_blocking(&prefix, None).await?;
+        let mut snapshot = decode_all(entries)?;
+
+        let output = stream! {
+            // The snapshot is only to diff against, the existing keys aren't sent
+            yield WatchEvent::InitialSyncComplete;
+            loop {
+                let blocking = self.api.get_prefix_blocking(&prefix, Some(index)).await;
+                let (new_index, entries) = match blocking {
+                    Ok(x) => x,
+                    Err(err) => {
+                        tracing::error!(%err, prefix, "Consul watch failed");
+                        break;
+                    }
+                };
+                // Per the Consul docs, an index going backwards means reset
+                index = if new_index < index { 0 } else { new_index };
+
+                let current = match decode_all(entries) {
+                    Ok(current) => current,
+                    Err(err) => {
+                        tracing::error!(%err, prefix, "Invalid value in Consul watch");
+                        break;
+                    }
+                };
+                // Puts in modify index order so sequences increase. Deletes leave no index
+                // behind, they get the index of the snapshot that no longer has the key.
+                let mut changed: Vec<_> = current
+                    .iter()
+                    .filter(|(key, (modify_index, _))| {
+                        snapshot.get(*key).map(|(i, _)| i != modify_index).unwrap_or(true)
+                    })
+                    .collect();
+                changed.sort_by_key(|(_, (modify_index, _))| *modify_index);
+                for (key, (modify_index, value)) in changed {
+                    let item =
+                        KeyValue::new(key.clone(), value.clone()).with_sequence(*modify_index);
+                    yield WatchEvent::Put(item);
+                }
+                for key in snapshot.keys() {
+                    if !current.contains_key(key) {
+                        let item = KeyValue::new(key.clone(), bytes::Bytes::new())
+                            .with_sequence(new_index);
+                        yield WatchEvent::Delete(item);
+                    }
+                }
+                snapshot = current;
+            }
+        };
+        Ok(Box::pin(output))
+    }
+
+    async fn entries(&self) -> Result<HashMap<String, bytes::Bytes>, StoreError> {
+        let prefix = make_key(&self.bucket_name, &"".into());
+        tracing::trace!("consul entries: {prefix}");
+        let entries = self.api.get_entries(&prefix, true).await?;
+        Ok(decode_all(entries)?
+            .into_iter()
+            .map(|(k, (_, v))| (k, v))
+            .collect())
+    }
+}
+
+#[derive(Clone)]
+struct ConsulApi {
+    http: reqwest::Client,
+    address: String,
+    token: Option<String>,
+}
+
+impl ConsulApi {
+    /// A session of `ttl_secs` that deletes the keys it holds when it goes, returning its ID
+    async fn create_session(&self, ttl_secs: u64) -> Result<String, StoreError> {
+        let created: SessionCreated = self
+            .request(reqwest::Method::PUT, "/v1/session/create")
+            .json(&json!({
+                "Name": "dynamo",
+                "TTL": format!("{ttl_secs}s"),
+                "Behavior": "delete",
+                // Keys are released, and deleted, as soon as the session goes away
+                "LockDelay": "0s",
+            }))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(consul_err)?
+            .json()
+            .await
+            .map_err(consul_err)?;
+        Ok(created.id)
+    }
+
+    async fn renew_session(&self, session: &str) -> Result<Renewal, StoreError> {
+        let path = format!("/v1/session/renew/{session}");
+        let resp = self
+            .request(reqwest::Method::PUT, &path)
+            .send()
+            .await
+            .map_err(consul_err)?;
+        let status = resp.status();
+        let body = resp.text().await.map_err(consul_err)?;
+        renewal(status, &body)
+    }
+
+    async fn txn(&self, ops: &[serde_json::Value]) -> Result<TxnOutcome, StoreError> {
+        let resp = self
+            .request(reqwest::Method::PUT, "/v1/txn")
+            .json(ops)
+            .send()
+            .await
+            .map_err(consul_err)?;
+
+        // 409 means the transaction was rolled back, the errors say which operation failed
+        if resp.status() == reqwest::StatusCode::CONFLICT {
+            let body: TxnResponse = resp.json().await.map_err(consul_err)?;
+            return Ok(body.rolled_back());
+        }
+        let result: TxnResponse = resp
+            .error_for_status()
+            .map_err(consul_err)?
+            .json()
+            .await
+            .map_err(consul_err)?;
+        let index = result
+            .results
+            .last()
+            .map(|r| r.kv.modify_index)
+            .ok_or_else(|| StoreError::ConsulError {
+                message: "Empty transaction response".to_string(),
+                source: None,
+                op_id: None,
+            })?;
+        Ok(TxnOutcome::Committed(index))
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let mut request = self.http.request(method, format!("{}{path}", self.address));
+        if let Some(token) = &self.token {
+            request = request.header("X-Consul-Token", token);
+        }
+        request
+    }
+
+    /// The entry at `key`, or everything under it with `recurse`. Empty if nothing is there.
+    async fn get_entries(&self, key: &str, recurse: bool) -> Result<Vec<KvEntry>, StoreError> {
+        let mut request = self.request(reqwest::Method::GET, &format!("/v1/kv/{key}"));
+        if recurse {
+            request = request.query(&[("recurse", "true")]);
+        }
+        let resp = request.send().await.map_err(consul_err)?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(vec![]);
+        }
+        resp.error_for_status()
+            .map_err(consul_err)?
+            .json()
+            .await
+            .map_err(consul_err)
+    }
+
+    /// The entry at `key` read in one of Consul's consistency modes, `consistent` or `stale`,
+    /// and how long ago the answering server last heard from the leader (`X-Consul-LastContact`)
+    async fn get_entry_with_mode(
+        &self,
+        key: &str,
+        mode: &str,
+    ) -> Result<(Vec<KvEntry>, Duration), StoreError> {
+        let resp = self
+            .request(reqwest::Method::GET, &format!("/v1/kv/{key}"))
+            .query(&[(mode, "")])
+            .send()
+            .await
+            .map_err(consul_err)?;
+        let last_contact = resp
+            .headers()
+            .get("X-Consul-LastContact")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_millis)
+            .unwrap_or_default();
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok((vec![], last_contact));
+        }
+        let entries = resp
+            .error_for_status()
+            .map_err(consul_err)?
+            .json()
+            .await
+            .map_err(consul_err)?;
+        Ok((entries, last_contact))
+    }
+
+    /// Everything under `prefix` and the `X-Consul-Index` to block on next. With `index`, waits
+    /// until something changes past that index or [`WATCH_WAIT`] passes.
+    async fn get_prefix_blocking(
+        &self,
+        prefix: &str,
+        index: Option<u64>,
+    ) -> Result<(u64, Vec<KvEntry>), StoreError> {
+        let mut request = self
+            .request(reqwest::Method::GET, &format!("/v1/kv/{prefix}"))
+            .query(&[("recurse", "true")]);
+        if let Some(index) = index {
+            request = request.query(&[("index", index.to_string()), ("wait", WATCH_WAIT.into())]);
+        }
+        let resp = request.send().await.map_err(consul_err)?;
+        let new_index = resp
+            .headers()
+            .get("X-Consul-Index")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok((new_index, vec![]));
+        }
+        let entries = resp
+            .error_for_status()
+            .map_err(consul_err)?
+            .json()
+            .await
+            .map_err(consul_err)?;
+        Ok((new_index, entries))
+    }
+}
+
+#[derive(Deserialize)]
+struct SessionCreated {
+    #[serde(rename = "ID")]
+    id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct KvEntry {
+    key: String,
+    /// Base64, null for an empty value
+    value: Option<String>,
+    modify_index: u64,
+}
+
+impl KvEntry {
+    fn decode(self) -> Result<(String, bytes::Bytes), StoreError> {
+        let value = match self.value {
+            Some(v) => BASE64.decode(v).map_err(|e| StoreError::ConsulError {
+                message: format!("Invalid base64 value: {e}"),
+                source: Some(Box::new(e)),
+                op_id: None,
+            })?,
+            None => vec![],
+        };
+        Ok((self.key, value.into()))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TxnResponse {
+    #[serde(default, deserialize_with = "null_as_empty")]
+    results: Vec<TxnResult>,
+    #[serde(default, deserialize_with = "null_as_empty")]
+    errors: Vec<TxnError>,
+}
+
+impl TxnResponse {
+    fn rolled_back(&self) -> TxnOutcome {
+        let op = self.errors.first().map(|e| e.op_index).unwrap_or_default();
+        TxnOutcome::RolledBack { op }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TxnError {
+    op_index: usize,
+}
+
+/// Consul sends `null` rather than an empty list
+fn null_as_empty<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Ok(Option::<Vec<T>>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+#[derive(Deserialize)]
+struct TxnResult {
+    #[serde(rename = "KV")]
+    kv: KvEntry,
+}
+
+fn decode_all(entries: Vec<KvEntry>) -> Result<HashMap<String, (u64, bytes::Bytes)>, StoreError> {
+    entries
+        .into_iter()
+        .map(|e| {
+            let modify_index = e.modify_index;
+            let (k, v) = e.decode()?;
+            Ok((k, (modify_index, v)))
+        })
+        .collect()
+}
+
+/// A 404, or an empty list of sessions from older Consul versions, means the session is gone
+fn renewal(status: reqwest::StatusCode, body: &str) -> Result<Renewal, StoreError> {
+    if status == reqwest::StatusCode::NOT_FOUND {
+        return Ok(Renewal::Invalid);
+    }
+    if !status.is_success() {
+        return Err(StoreError::ConsulError {
+            message: format!("Session renewal failed with {status}: {body}"),
+            source: None,
+            op_id: None,
+        });
+    }
+    match serde_json::from_str::<Vec<serde_json::Value>>(body) {
+        Ok(sessions) if sessions.is_empty() => Ok(Renewal::Invalid),
+        _ => Ok(Renewal::Renewed),
+    }
+}
+
+fn consul_err(err: reqwest::Error) -> StoreError {
+    StoreError::ConsulError {
+        message: err.to_string(),
+        source: Some(Box::new(err)),
+        op_id: None,
+    }
+}
+
+fn make_key(bucket_name: &str, key: &Key) -> String {
+    [bucket_name.to_string(), key.to_string()].join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rolled_back_op() {
+        // A lock refused because another session holds the key
+        let body = r#"{"Results": null, "Errors": [{"OpIndex": 1, "What": "failed to lock key"}]}"#;
+        let body: TxnResponse = serde_json::from_str(body).unwrap();
+        assert_eq!(body.rolled_back(), TxnOutcome::RolledBack { op: 1 });
+
+        let body = r#"{"Errors": [{"OpIndex": 0, "What": "current modify index 7 != 6"}]}"#;
+        let body: TxnResponse = serde_json::from_str(body).unwrap();
+        assert_eq!(body.rolled_back(), TxnOutcome::RolledBack { op: 0 });
+    }
+
+    #[test]
+    fn test_renewal() {
+        use reqwest::StatusCode;
+        let renewed = r#"[{"ID": "adf4238a-882b-9ddc-4a9d-5b6758e4159e", "TTL": "10s"}]"#;
+        assert_eq!(renewal(StatusCode::OK, renewed).unwrap(), Renewal::Renewed);
+        let not_found = "Session id 'adf4238a-882b-9ddc-4a9d-5b6758e4159e' not found";
+        assert_eq!(
+            renewal(StatusCode::NOT_FOUND, not_found).unwrap(),
+            Renewal::Invalid
+        );
+        assert_eq!(renewal(StatusCode::OK, "[]").unwrap(), Renewal::Invalid);
+        // Not the session's fault, the next renewal may get through
+        assert!(renewal(StatusCode::INTERNAL_SERVER_ERROR, "rpc error").is_err());
+    }
+}
@@ -0,0 +1,337 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! JSON merge patches (RFC 7396) between successive values of a key.
+//!
+//! Model cards are large and change a field or two at a time. A writer that publishes with
+//! [`publish_delta`](super::KeyValueStoreManager::publish_delta) diffs the new value against
+//! the one it published before, and writes the patch in a header in front of the value, with a
+//! hash of the value it was made against. Every manager strips the header when reading.
+//! [`watch_with_deltas`](super::KeyValueStoreManager::watch_with_deltas) attaches the patch to
+//! the [`WatchEvent::Put`] when that hash matches the previous value the watch saw for the key,
+//! so a watcher applies it with [`apply_merge_patch`] instead of deserializing the whole
+//! document again, and nothing on the watch path parses the full value.
+//!
+//! The header starts with a NUL, like the content hash one. Readers that don't go through a
+//! manager see it, so only publish deltas once every reader knows about it.
+
+use std::collections::HashMap;
+
+use serde_json::{Map, Value};
+
+use super::{StoreError, WatchEvent};
+
+const MAGIC: &str = "\0delta:";
+
+/// The merge patch that turns `old` into `new`, or None if one cannot express it. Merge
+/// patches use `null` to mean "remove", so a `null` that has to appear in the result as an
+/// object member cannot be written as a patch.
+pub fn merge_diff(old: &Value, new: &Value) -> Option<Value> {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            let mut patch = Map::new();
+            for (key, new_value) in new {
+                match old.get(key) {
+                    Some(old_value) if old_value == new_value => {}
+                    Some(old_value) => {
+                        patch.insert(key.clone(), merge_diff(old_value, new_value)?);
+                    }
+                    None => {
+                        patch.insert(key.clone(), replacement(new_value)?);
+                    }
+                }
+            }
+            for key in old.keys() {
+                if !new.contains_key(key) {
+                    patch.insert(key.clone(), Value::Null);
+                }
+            }
+            Some(Value::Object(patch))
+        }
+        (_, new) => replacement(new),
+    }
+}
+
+/// `value` as a patch that replaces whatever was there
+fn replacement(value: &Value) -> Option<Value> {
+    if value.is_null() || has_null_member(value) {
+        return None;
+    }
+    Some(value.clone())
+}
+
+/// Arrays are copied into the result verbatim, so only object members matter
+fn has_null_member(value: &Value) -> bool {
+    match value {
+        Value::Object(map) => map.values().any(|v| v.is_null() || has_null_member(v)),
+        _ => false,
+    }
+}
+
+/// Apply an RFC 7396 merge patch to `target` in place
+pub fn apply_merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    if let Value::Object(map) = target {
+        for (key, value) in patch {
+            if value.is_null() {
+                map.remove(key);
+            } else {
+                apply_merge_patch(map.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        }
+    }
+}
+
+/// The members `patch` sets, as dotted paths, and the ones it removes, prefixed with `-`. A
+/// patch that is not an object replaces the whole value and is one empty path.
+pub fn patch_paths(patch: &Value) -> Vec<String> {
+    let mut paths = Vec::new();
+    collect_paths(patch, "", &mut paths);
+    paths
+}
+
+fn collect_paths(patch: &Value, prefix: &str, paths: &mut Vec<String>) {
+    let Value::Object(members) = patch else {
+        paths.push(prefix.to_string());
+        return;
+    };
+    for (key, value) in members {
+        let path = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{prefix}.{key}")
+        };
+        match value {
+            Value::Null => paths.push(format!("-{path}")),
+            Value::Object(_) => collect_paths(value, &path, paths),
+            _ => paths.push(path),
+        }
+    }
+}
+
+/// `value` behind a header holding `patch`, the merge patch from `previous` to it
+pub(super) fn frame(previous: &str, patch: &Value, value: &str) -> String {
+    let base = xxhash_rust::xxh3::xxh3_64(previous.as_bytes());
+    let patch = patch.to_string();
+    format!("{MAGIC}{base:016x}:{}\n{patch}{value}", patch.len())
+}
+
+/// The value `framed` holds, and the hash of the value its patch was made against and the
+/// patch, or `framed` itself if it has no header
+pub(super) fn unframe(
+    key: &str,
+    framed: bytes::Bytes,
+) -> Result<(bytes::Bytes, Option<(u64, bytes::Bytes)>), StoreError> {
+    if !framed.starts_with(MAGIC.as_bytes()) {
+        return Ok((framed, None));
+    }
+    let malformed = || StoreError::Corrupted {
+        key: key.to_string(),
+        reason: format!("malformed delta header in {} bytes", framed.len()),
+    };
+    let header_end = framed
+        .iter()
+        .position(|&b| b == b'\n')
+        .ok_or_else(malformed)?;
+    let header = std::str::from_utf8(&framed[MAGIC.len()..header_end]).map_err(|_| malformed())?;
+    let (base, patch_len) = header.split_once(':').ok_or_else(malformed)?;
+    let base = u64::from_str_radix(base, 16).map_err(|_| malformed())?;
+    let patch_len: usize = patch_len.parse().map_err(|_| malformed())?;
+    let patch_end = header_end + 1 + patch_len;
+    if patch_end > framed.len() {
+        return Err(malformed());
+    }
+    let patch = framed.slice(header_end + 1..patch_end);
+    Ok((framed.slice(patch_end..), Some((base, patch))))
+}
+
+/// The value `framed` holds, without its patch
+pub(super) fn strip(key: &str, framed: bytes::Bytes) -> Result<bytes::Bytes, StoreError> {
+    Ok(unframe(key, framed)?.0)
+}
+
+/// [`unframe`] the value of a change from a watch, keeping the patch for a [`DeltaTracker`].
+/// A malformed put is dropped, as failing would end the watch.
+pub(super) fn open_event(event: WatchEvent) -> Option<WatchEvent> {
+    match event {
+        WatchEvent::Put(mut kv) => {
+            let (value, published) = unframe(&kv.key, kv.value).ok()?;
+            kv.value = value;
+            kv.published_delta = published;
+            Some(WatchEvent::Put(kv))
+        }
+        WatchEvent::Delete(mut kv) => {
+            kv.value = strip(&kv.key, kv.value).ok()?;
+            Some(WatchEvent::Delete(kv))
+        }
+        event => Some(event),
+    }
+}
+
+/// Remembers a hash of the last value of every key seen on a watch, to tell whether the patch
+/// published with the next one applies to it
+#[derive(Default)]
+pub(super) struct DeltaTracker {
+    previous: HashMap<String, u64>,
+}
+
+impl DeltaTracker {
+    pub(super) fn annotate(&mut self, event: WatchEvent) -> WatchEvent {
+        match event {
+            WatchEvent::Put(mut kv) => {
+                let hash = xxhash_rust::xxh3::xxh3_64(kv.value());
+                let previous = self.previous.insert(kv.key.clone(), hash);
+                let published = kv.published_delta.take();
+                // Made against a value this watch didn't see, e.g. when another writer got in
+                // between, or when it started after that value was replaced
+                let Some((_, patch)) = published.filter(|(base, _)| Some(*base) == previous) else {
+                    return WatchEvent::Put(kv);
+                };
+                match serde_json::from_slice(&patch) {
+                    Ok(delta) => WatchEvent::Put(kv.with_delta(delta)),
+                    Err(_) => WatchEvent::Put(kv),
+                }
+            }
+            WatchEvent::Delete(kv) => {
+                self.previous.remove(kv.key());
+                WatchEvent::Delete(kv)
+            }
+            event => event,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::key_value_store::KeyValue;
+    use serde_json::json;
+
+    fn round_trip(old: Value, new: Value) -> Value {
+        let patch = merge_diff(&old, &new).expect("expressible");
+        let mut patched = old;
+        apply_merge_patch(&mut patched, &patch);
+        assert_eq!(patched, new);
+        patch
+    }
+
+    #[test]
+    fn test_merge_diff_round_trip() {
+        let patch = round_trip(
+            json!({"name": "llama", "runtime": {"kv_blocks": 10, "dp": 1}, "tags": ["a"]}),
+            json!({"name": "llama", "runtime": {"kv_blocks": 12, "dp": 1}, "tags": ["a", "b"]}),
+        );
+        assert_eq!(
+            patch,
+            json!({"runtime": {"kv_blocks": 12}, "tags": ["a", "b"]})
+        );
+
+        let patch = round_trip(json!({"a": 1, "b": 2}), json!({"a": 1}));
+        assert_eq!(patch, json!({"b": null}));
+
+        round_trip(json!({"a": 1}), json!({"a": {"b": [null, 1]}}));
+        round_trip(json!([1, 2]), json!({"x": 1}));
+        round_trip(json!({"a": 1}), json!({"a": 1}));
+    }
+
+    #[test]
+    fn test_merge_diff_null_values() {
+        assert_eq!(merge_diff(&json!({"a": 1}), &json!({"a": null})), None);
+        assert_eq!(
+            merge_diff(&json!({"a": 1}), &json!({"b": {"c": null}})),
+            None
+        );
+        assert_eq!(merge_diff(&json!(1), &Value::Null), None);
+    }
+
+    #[test]
+    fn test_patch_paths() {
+        let patch = merge_diff(
+            &json!({"name": "llama", "runtime": {"kv_blocks": 10, "dp": 1}, "old": true}),
+            &json!({"name": "llama", "runtime": {"kv_blocks": 12, "dp": 1}, "tags": ["a"]}),
+        )
+        .unwrap();
+        let mut paths = patch_paths(&patch);
+        paths.sort();
+        assert_eq!(paths, ["-old", "runtime.kv_blocks", "tags"]);
+        assert_eq!(patch_paths(&json!([1])), [""]);
+        assert!(patch_paths(&json!({})).is_empty());
+    }
+
+    #[test]
+    fn test_frame() {
+        let patch = json!({"b": 2});
+        let framed = bytes::Bytes::from(frame(r#"{"a":1,"b":1}"#, &patch, r#"{"a":1,"b":2}"#));
+        let (value, published) = unframe("card", framed.clone()).unwrap();
+        assert_eq!(value, r#"{"a":1,"b":2}"#);
+        let (base, raw) = published.unwrap();
+        assert_eq!(base, xxhash_rust::xxh3::xxh3_64(br#"{"a":1,"b":1}"#));
+        assert_eq!(raw, r#"{"b":2}"#);
+
+        // Written plain
+        assert_eq!(
+            unframe("card", "plain".into()).unwrap(),
+            ("plain".into(), None)
+        );
+
+        let err = strip("card", framed.slice(..MAGIC.len() + 20)).unwrap_err();
+        assert!(err.to_string().contains("malformed delta header"), "{err}");
+    }
+
+    #[test]
+    fn test_delta_tracker() {
+        let put = |value: &str, previous: Option<&str>| {
+            let framed = match previous {
+                Some(previous) => {
+                    let old: Value = serde_json::from_str(previous).unwrap();
+                    let new: Value = serde_json::from_str(value).unwrap();
+                    frame(previous, &merge_diff(&old, &new).unwrap(), value)
+                }
+                None => value.to_string(),
+            };
+            let kv = KeyValue::new("card".to_string(), framed.into());
+            open_event(WatchEvent::Put(kv)).unwrap()
+        };
+        let mut tracker = DeltaTracker::default();
+
+        // Nothing to check the patch against yet
+        let WatchEvent::Put(first) = tracker.annotate(put(r#"{"a":1,"b":1}"#, None)) else {
+            unreachable!()
+        };
+        assert_eq!(first.delta(), None);
+
+        let WatchEvent::Put(second) =
+            tracker.annotate(put(r#"{"a":1,"b":2}"#, Some(r#"{"a":1,"b":1}"#)))
+        else {
+            unreachable!()
+        };
+        assert_eq!(second.value(), r#"{"a":1,"b":2}"#);
+        assert_eq!(second.delta(), Some(&json!({"b": 2})));
+
+        // Made against a value this watch never saw
+        let WatchEvent::Put(third) =
+            tracker.annotate(put(r#"{"a":1,"b":4}"#, Some(r#"{"a":1,"b":3}"#)))
+        else {
+            unreachable!()
+        };
+        assert_eq!(third.delta(), None);
+
+        // A delete forgets the value, the next put is complete again
+        tracker.annotate(WatchEvent::Delete(KeyValue::new(
+            "card".to_string(),
+            bytes::Bytes::new(),
+        )));
+        let WatchEvent::Put(fourth) =
+            tracker.annotate(put(r#"{"a":1,"b":5}"#, Some(r#"{"a":1,"b":4}"#)))
+        else {
+            unreachable!()
+        };
+        assert_eq!(fourth.delta(), None);
+    }
+}
@@ -0,0 +1,399 @@
+// SPDX-FileCopyrightText: Copyright (c) 2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Previewing what a migration script's writes would do to the store as it is, without making
+//! them.
+//!
+//! A [`KeyValueStoreManager::with_dry_run`](super::KeyValueStoreManager::with_dry_run) manager
+//! reads the store as usual, but evaluates each insert, update, compare-and-swap and delete on
+//! its buckets instead of sending it. [`DryRun::changes`] lists them in order as
+//! [`PlannedChange`]s: the key's revision before, and whether the change would go ahead, do
+//! nothing, or conflict. Reads through the same manager see the changes that would go ahead, so
+//! a script that creates a key and then updates it is evaluated as it would run. A bucket that
+//! would be created is a planned change too.
+//!
+//! It can't tell the revision a write would get, which it stands in for with a hash of the
+//! value, nor whether a fenced write's lease is still current. Watches see only the store.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use parking_lot::Mutex;
+
+use super::{
+    Conditional, Fence, Key, KeyValueBucket, StoreError, StoreOutcome, WatchEvent, content_revision,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlannedOp {
+    CreateBucket,
+    Write,
+    Delete,
+}
+
+/// What a [`PlannedChange`] would have done
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlannedOutcome {
+    Applied,
+    /// Nothing, as a create-only write found the key
+    Exists,
+    /// Nothing, as an update or delete found no key
+    Missing,
+    /// It would fail, as the key isn't at the revision the write expected
+    Conflict {
+        expected: u64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedChange {
+    pub op: PlannedOp,
+    pub bucket: String,
+    /// Empty for [`PlannedOp::CreateBucket`]
+    pub key: String,
+    /// The key's revision before the change, 0 if it was missing
+    pub revision: u64,
+    pub outcome: PlannedOutcome,
+}
+
+impl PlannedChange {
+    pub fn conflicts(&self) -> bool {
+        matches!(self.outcome, PlannedOutcome::Conflict { .. })
+    }
+}
+
+impl fmt::Display for PlannedChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.op {
+            PlannedOp::CreateBucket => return write!(f, "create bucket {}", self.bucket),
+            PlannedOp::Write => write!(f, "write {}/{}", self.bucket, self.key)?,
+            PlannedOp::Delete => write!(f, "delete {}/{}", self.bucket, self.key)?,
+        }
+        match self.outcome {
+            PlannedOutcome::Applied if self.revision == 0 => write!(f, ": applies, new key"),
+            PlannedOutcome::Applied => write!(f, ": applies over revision {}", self.revision),
+            PlannedOutcome::Exists => write!(f, ": no-op, exists at revision {}", self.revision),
+            PlannedOutcome::Missing => write!(f, ": no-op, missing"),
+            PlannedOutcome::Conflict { expected } => write!(
+                f,
+                ": CONFLICT, expected revision {expected} but is at {}",
+                self.revision
+            ),
+        }
+    }
+}
+
+/// The changes a dry-run manager would have made, and the state they would leave
+#[derive(Debug, Default)]
+pub struct DryRun {
+    changes: Mutex<Vec<PlannedChange>>,
+    /// The values the changes that would go ahead leave, None for deleted, by bucket and key
+    overlay: Mutex<HashMap<String, HashMap<String, Option<bytes::Bytes>>>>,
+    /// That would be created
+    buckets: Mutex<HashSet<String>>,
+}
+
+impl DryRun {
+    /// In the order they were made
+    pub fn changes(&self) -> Vec<PlannedChange> {
+        self.changes.lock().clone()
+    }
+
+    pub fn conflicts(&self) -> Vec<PlannedChange> {
+        let changes = self.changes.lock();
+        changes.iter().filter(|c| c.conflicts()).cloned().collect()
+    }
+
+    pub(super) fn create_bucket(&self, bucket_name: &str) {
+        if self.buckets.lock().insert(bucket_name.to_string()) {
+            self.changes.lock().push(PlannedChange {
+                op: PlannedOp::CreateBucket,
+                bucket: bucket_name.to_string(),
+                key: String::new(),
+                revision: 0,
+                outcome: PlannedOutcome::Applied,
+            });
+        }
+    }
+
+    pub(super) fn has_bucket(&self, bucket_name: &str) -> bool {
+        self.buckets.lock().contains(bucket_name)
+    }
+}
+
+/// A bucket whose writes are evaluated and recorded in a [`DryRun`] instead of made
+pub(super) struct DryRunBucket {
+    name: String,
+    /// None for a bucket the dry run would create
+    inner: Option<Arc<dyn KeyValueBucket>>,
+    dry_run: Arc<DryRun>,
+}
+
+impl DryRunBucket {
+    pub(super) fn new(
+        name: &str,
+        inner: Option<Arc<dyn KeyValueBucket>>,
+        dry_run: Arc<DryRun>,
+    ) -> Self {
+        DryRunBucket {
+            name: name.to_string(),
+            inner,
+            dry_run,
+        }
+    }
+
+    /// The value `key` would have after the planned changes, None if they didn't touch it
+    fn planned(&self, key: &Key) -> Option<Option<bytes::Bytes>> {
+        let overlay = self.dry_run.overlay.lock();
+        overlay.get(&self.name)?.get(key.as_ref()).cloned()
+    }
+
+    /// The value and revision `key` would have after the planned changes
+    async fn current(&self, key: &Key) -> Result<Option<(bytes::Bytes, u64)>, StoreError> {
+        if let Some(planned) = self.planned(key) {
+            return Ok(planned.map(|value| {
+                let revision = content_revision(&value);
+                (value, revision)
+            }));
+        }
+        let Some(inner) = &self.inner else {
+            return Ok(None);
+        };
+        match inner.get_if_changed(key, 0).await? {
+            Conditional::Modified { value, revision } => Ok(Some((value, revision))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Record a change to `key` at `revision`, and what it leaves if it would go ahead
+    fn plan(
+        &self,
+        op: PlannedOp,
+        key: &Key,
+        revision: u64,
+        outcome: PlannedOutcome,
+        value: Option<&str>,
+    ) -> Result<StoreOutcome, StoreError> {
+        self.dry_run.changes.lock().push(PlannedChange {
+            op,
+            bucket: self.name.clone(),
+            key: key.to_string(),
+            revision,
+            outcome,
+        });
+        tracing::debug!(bucket = %self.name, %key, revision, ?op, ?outcome, "Dry run");
+        match outcome {
+            PlannedOutcome::Applied => {
+                let value = value.map(|value| bytes::Bytes::from(value.to_string()));
+                let revision = value.as_deref().map_or(0, content_revision);
+                let mut overlay = self.dry_run.overlay.lock();
+                let bucket = overlay.entry(self.name.clone()).or_default();
+                bucket.insert(key.to_string(), value);
+                Ok(StoreOutcome::Created(revision))
+            }
+            PlannedOutcome::Exists => Ok(StoreOutcome::Exists(revision)),
+            PlannedOutcome::Missing => Err(StoreError::MissingKey(key.to_string())),
+            PlannedOutcome::Conflict { .. } => Err(StoreError::Retry),
+        }
+    }
+}
+
+#[async_trait]
+impl KeyValueBucket for DryRunBucket {
+    /// Evaluated as etcd does: at revision 0 only if the key is missing, otherwise always
+    async fn insert(
+        &self,
+        key: &Key,
+        value: &str,
+        revision: u64,
+    ) -> Result<StoreOutcome, StoreError> {
+        let (current, outcome) = match self.current(key).await? {
+            Some((_, current)) if revision == 0 => (current, PlannedOutcome::Exists),
+            Some((_, current)) => (current, PlannedOutcome::Applied),
+            None => (0, PlannedOutcome::Applied),
+        };
+        self.plan(PlannedOp::Write, key, current, outcome, Some(value))
+    }
+
+    async fn insert_new(&self, key: &Key, value: &str) -> Result<StoreOutcome, StoreError> {
+        self.insert(key, value, 0).await
+    }
+
+    async fn update_existing(&self, key: &Key, value: &str) -> Result<StoreOutcome, StoreError> {
+        let (current, outcome) = match self.current(key).await? {
+            Some((_, current)) => (current, PlannedOutcome::Applied),
+            None => (0, PlannedOutcome::Missing),
+        };
+        self.plan(PlannedOp::Write, key, current, outcome, Some(value))
+    }
+
+    async fn upsert(&self, key: &Key, value: &str) -> Result<StoreOutcome, StoreError> {
+        let current = self.current(key).await?.map_or(0, |(_, current)| current);
+        self.plan(
+            PlannedOp::Write,
+            key,
+            current,
+            PlannedOutcome::Applied,
+            Some(value),
+        )
+    }
+
+    async fn compare_and_swap(
+        &self,
+        key: &Key,
+        value: &str,
+        revision: u64,
+    ) -> Result<StoreOutcome, StoreError> {
+        let current = self.current(key).await?.map_or(0, |(_, current)| current);
+        let outcome = match current == revision {
+            true => PlannedOutcome::Applied,
+            false => PlannedOutcome::Conflict { expected: revision },
+        };
+        self.plan(PlannedOp::Write, key, current, outcome, Some(value))
+    }
+
+    /// Taken to go ahead, as whether the fence still holds isn't checked
+    async fn insert_fenced(
+        &self,
+        key: &Key,
+        value: &str,
+        _fence: Fence,
+    ) -> Result<StoreOutcome, StoreError> {
+        let current = self.current(key).await?.map_or(0, |(_, current)| current);
+        self.plan(
+            PlannedOp::Write,
+            key,
+            current,
+            PlannedOutcome::Applied,
+            Some(value),
+        )
+    }
+
+    async fn get(&self, key: &Key) -> Result<Option<bytes::Bytes>, StoreError> {
+        Ok(self.current(key).await?.map(|(value, _)| value))
+    }
+
+    async fn get_if_changed(
+        &self,
+        key: &Key,
+        known_revision: u64,
+    ) -> Result<Conditional, StoreError> {
+        Ok(match self.current(key).await? {
+            None => Conditional::Missing,
+            Some((_, revision)) if revision == known_revision => Conditional::NotModified,
+            Some((value, revision)) => Conditional::Modified { value, revision },
+        })
+    }
+
+    async fn delete(&self, key: &Key) -> Result<(), StoreError> {
+        match self.current(key).await? {
+            Some((_, current)) => {
+                self.plan(
+                    PlannedOp::Delete,
+                    key,
+                    current,
+                    PlannedOutcome::Applied,
+                    None,
+                )?;
+            }
+            None => {
+                // Deleting a missing key is no error
+                let _ = self.plan(PlannedOp::Delete, key, 0, PlannedOutcome::Missing, None);
+            }
+        }
+        Ok(())
+    }
+
+    async fn watch(
+        &self,
+    ) -> Result<Pin<Box<dyn futures::Stream<Item = WatchEvent> + Send + 'life0>>, StoreError> {
+        match &self.inner {
+            Some(inner) => inner.watch().await,
+            None => Ok(Box::pin(futures::stream::pending())),
+        }
+    }
+
+    async fn entries(&self) -> Result<HashMap<String, bytes::Bytes>, StoreError> {
+        let mut entries = match &self.inner {
+            Some(inner) => inner.entries().await?,
+            None => HashMap::new(),
+        };
+        let overlay = self.dry_run.overlay.lock();
+        for (key, value) in overlay.get(&self.name).into_iter().flatten() {
+            // Some stores list keys with the bucket in front
+            entries.remove(&format!("{}/{key}", self.name));
+            entries.remove(key);
+            if let Some(value) = value {
+                entries.insert(key.clone(), value.clone());
+            }
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::KeyValueStoreManager;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_dry_run() {
+        let store = KeyValueStoreManager::memory();
+        let bucket = store.get_or_create_bucket("v1/models", None).await.unwrap();
+        let llama = Key::from_raw("llama".to_string());
+        bucket.insert_new(&llama, "v1").await.unwrap();
+
+        let dry = store.clone().with_dry_run();
+        let preview = dry.get_or_create_bucket("v1/models", None).await.unwrap();
+        let current = preview.get_if_changed(&llama, 0).await.unwrap();
+        let Conditional::Modified { revision, .. } = current else {
+            panic!("llama is missing");
+        };
+        preview
+            .compare_and_swap(&llama, "v2", revision)
+            .await
+            .unwrap();
+        // The second write of a script sees the first
+        assert_eq!(preview.get(&llama).await.unwrap().unwrap(), "v2".as_bytes());
+        let stale = preview.compare_and_swap(&llama, "v3", revision).await;
+        assert!(matches!(stale, Err(StoreError::Retry)));
+        preview.delete(&llama).await.unwrap();
+        assert!(preview.entries().await.unwrap().is_empty());
+
+        let created = dry.get_or_create_bucket("v1/new", None).await.unwrap();
+        let key = Key::from_raw("a".to_string());
+        created.insert_new(&key, "1").await.unwrap();
+        assert!(matches!(
+            created.insert_new(&key, "2").await,
+            Ok(StoreOutcome::Exists(_))
+        ));
+        assert!(dry.get_bucket("v1/new").await.unwrap().is_some());
+
+        let dry_run = dry.dry_run().unwrap();
+        let changes = dry_run.changes();
+        let ops: Vec<_> = changes.iter().map(|c| (c.op, c.outcome)).collect();
+        assert_eq!(
+            ops,
+            [
+                (PlannedOp::Write, PlannedOutcome::Applied),
+                (
+                    PlannedOp::Write,
+                    PlannedOutcome::Conflict { expected: revision }
+                ),
+                (PlannedOp::Delete, PlannedOutcome::Applied),
+                (PlannedOp::CreateBucket, PlannedOutcome::Applied),
+                (PlannedOp::Write, PlannedOutcome::Applied),
+                (PlannedOp::Write, PlannedOutcome::Exists),
+            ]
+        );
+        assert_eq!(changes[0].revision, revision);
+        assert_eq!(dry_run.conflicts().len(), 1);
+
+        // Nothing was written
+        assert_eq!(bucket.get(&llama).await.unwrap().unwrap(), "v1".as_bytes());
+        assert!(store.get_bucket("v1/new").await.unwrap().is_none());
+    }
+}
@@ -2,27 +2,290 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::collections::HashMap;
+use std::fmt;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::time::Duration;
 
 use crate::{
-    storage::key_value_store::{Key, KeyValue, WatchEvent},
-    transports::etcd::Client,
+    storage::key_value_store::{Fence, Key, KeyValue, ReadConsistency, WatchEvent},
+    transports::etcd::{Client, fence_key, prefix_range_end},
 };
 use async_stream::stream;
 use async_trait::async_trait;
 use etcd_client::{Compare, CompareOp, EventType, PutOptions, Txn, TxnOp, WatchOptions};
+use futures::FutureExt;
+use futures::future::{BoxFuture, Shared};
+use parking_lot::Mutex;
+use tonic::Code;
 
-use super::{KeyValueBucket, KeyValueStore, StoreError, StoreOutcome};
+use super::{Conditional, ErrorSource, KeyValueBucket, KeyValueStore, StoreError, StoreOutcome};
+
+/// etcd's default `--max-txn-ops`, larger batches are split
+const MAX_TXN_OPS: usize = 128;
+
+/// Tries per operation, the first included, while it fails in a transient way
+const MAX_ATTEMPTS: u32 = 4;
+
+/// Wait before the first retry, doubled for each one after
+const RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Why an etcd request failed, as far as a caller can do something about it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EtcdErrorKind {
+    /// The member lost its leader, or a new one was elected, while handling the request
+    LeaderChanged,
+    /// The request did not finish in time. A write may still have been applied.
+    Timeout,
+    /// No member could be reached, or none would serve the request
+    Unavailable,
+    /// The revision asked for was compacted away
+    Compacted,
+    /// The credentials were rejected, or don't allow the request
+    Auth,
+    Other,
+}
+
+impl EtcdErrorKind {
+    /// Classify an error from the etcd client. Anything else is [`EtcdErrorKind::Other`].
+    pub fn of(err: &anyhow::Error) -> Self {
+        match err.downcast_ref::<etcd_client::Error>() {
+            Some(etcd_client::Error::GRpcStatus(status)) => Self::of_status(status),
+            Some(etcd_client::Error::TransportError(_) | etcd_client::Error::IoError(_)) => {
+                EtcdErrorKind::Unavailable
+            }
+            _ => EtcdErrorKind::Other,
+        }
+    }
+
+    fn of_status(status: &tonic::Status) -> Self {
+        // etcd shares a few status codes between many errors, only the messages tell them apart
+        let message = status.message();
+        match status.code() {
+            _ if message.contains("leader changed") || message.contains("no leader") => {
+                EtcdErrorKind::LeaderChanged
+            }
+            _ if message.contains("compacted") => EtcdErrorKind::Compacted,
+            _ if message.contains("timed out") => EtcdErrorKind::Timeout,
+            _ if message.contains("authentication failed") => EtcdErrorKind::Auth,
+            Code::DeadlineExceeded => EtcdErrorKind::Timeout,
+            Code::Unavailable => EtcdErrorKind::Unavailable,
+            Code::Unauthenticated | Code::PermissionDenied => EtcdErrorKind::Auth,
+            _ => EtcdErrorKind::Other,
+        }
+    }
+
+    /// The cluster gets over these by itself, so the request is worth sending again
+    pub fn is_transient(self) -> bool {
+        matches!(
+            self,
+            EtcdErrorKind::LeaderChanged | EtcdErrorKind::Timeout | EtcdErrorKind::Unavailable
+        )
+    }
+
+    /// The request may have been applied even though it failed, so a write that can't be
+    /// repeated must not be sent again
+    pub fn may_have_applied(self) -> bool {
+        self == EtcdErrorKind::Timeout
+    }
+}
+
+impl fmt::Display for EtcdErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            EtcdErrorKind::LeaderChanged => "leader changed",
+            EtcdErrorKind::Timeout => "timeout",
+            EtcdErrorKind::Unavailable => "unavailable",
+            EtcdErrorKind::Compacted => "compacted",
+            EtcdErrorKind::Auth => "auth",
+            EtcdErrorKind::Other => "other",
+        })
+    }
+}
+
+/// Run `op` until it succeeds, fails in a way that isn't transient, or has been tried
+/// [`MAX_ATTEMPTS`] times
+async fn retry<T, E, F, Fut>(name: &str, op: F) -> Result<T, StoreError>
+where
+    E: Into<anyhow::Error>,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    retry_if(name, EtcdErrorKind::is_transient, op).await
+}
+
+/// Like [`retry`], for a write that must not be applied twice, such as a create or a compare
+/// and swap: one that [may have been applied](EtcdErrorKind::may_have_applied) fails at once.
+/// Repeated, it would find its own change and report a conflict that didn't happen.
+async fn retry_once_applied<T, E, F, Fut>(name: &str, op: F) -> Result<T, StoreError>
+where
+    E: Into<anyhow::Error>,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let retryable = |kind: EtcdErrorKind| kind.is_transient() && !kind.may_have_applied();
+    retry_if(name, retryable, op).await
+}
+
+async fn retry_if<T, E, F, Fut>(
+    name: &str,
+    retryable: impl Fn(EtcdErrorKind) -> bool,
+    mut op: F,
+) -> Result<T, StoreError>
+where
+    E: Into<anyhow::Error>,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut backoff = RETRY_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        let err = match op().await {
+            Ok(value) => return Ok(value),
+            Err(err) => err.into(),
+        };
+        let kind = EtcdErrorKind::of(&err);
+        if !retryable(kind) || attempt == MAX_ATTEMPTS {
+            let message = err.to_string();
+            // The client's own error, not anyhow's wrapper, so callers can downcast to it
+            let source: ErrorSource = match err.downcast::<etcd_client::Error>() {
+                Ok(err) => Box::new(err),
+                Err(err) => err.into(),
+            };
+            return Err(StoreError::EtcdError {
+                kind,
+                message,
+                source: Some(source),
+                op_id: None,
+            });
+        }
+        tracing::debug!(op = name, %kind, attempt, %err, "etcd request failed, retrying");
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
+/// Fail `op` if the bucket's client is read-only. The writes made with etcd transactions
+/// don't go through the client's own checks.
+fn check_writable(client: &Client, op: &str) -> Result<(), StoreError> {
+    client
+        .check_writable(op)
+        .map_err(|err| StoreError::EtcdError {
+            kind: EtcdErrorKind::Other,
+            message: err.to_string(),
+            source: Some(err.into()),
+            op_id: None,
+        })
+}
+
+fn unexpected(message: &str) -> StoreError {
+    StoreError::EtcdError {
+        kind: EtcdErrorKind::Other,
+        message: message.to_string(),
+        source: None,
+        op_id: None,
+    }
+}
+
+type SharedRead = Shared<BoxFuture<'static, Result<Option<bytes::Bytes>, Arc<StoreError>>>>;
+
+#[derive(Default)]
+struct InFlight {
+    next_id: u64,
+    reads: HashMap<String, (u64, SharedRead)>,
+}
+
+/// The reads of single keys that were sent and haven't been answered yet. Concurrent gets of a
+/// key share one read, so a burst of them, such as every client resolving the same instances
+/// after a failover, costs etcd one request. A get joining a read in flight may miss a write
+/// made after that read was sent, as it would have had it come a moment earlier.
+#[derive(Clone, Default)]
+struct InFlightGets(Arc<Mutex<InFlight>>);
+
+impl InFlightGets {
+    /// The result of `read` of `key`, or of the read of `key` in flight already
+    async fn get<F>(&self, key: String, read: F) -> Result<Option<bytes::Bytes>, StoreError>
+    where
+        F: Future<Output = Result<Option<bytes::Bytes>, StoreError>> + Send + 'static,
+    {
+        let shared = {
+            let mut in_flight = self.0.lock();
+            match in_flight.reads.get(&key) {
+                Some((_, shared)) => shared.clone(),
+                None => {
+                    let id = in_flight.next_id;
+                    in_flight.next_id += 1;
+                    let in_flight_reads = Arc::downgrade(&self.0);
+                    let read = remove_when_done(in_flight_reads, key.clone(), id, read);
+                    let shared = read.boxed().shared();
+                    in_flight.reads.insert(key, (id, shared.clone()));
+                    shared
+                }
+            }
+        };
+        // Only the last of the gets sharing a failed read gets the error's source
+        shared.await.map_err(|err| match Arc::try_unwrap(err) {
+            Ok(err) => err,
+            Err(err) => copy_error(&err),
+        })
+    }
+}
+
+/// `read`, taken out of the reads in flight once answered, whether or not the gets that
+/// started it are still waiting
+async fn remove_when_done<F>(
+    in_flight: std::sync::Weak<Mutex<InFlight>>,
+    key: String,
+    id: u64,
+    read: F,
+) -> Result<Option<bytes::Bytes>, Arc<StoreError>>
+where
+    F: Future<Output = Result<Option<bytes::Bytes>, StoreError>>,
+{
+    let result = read.await.map_err(Arc::new);
+    if let Some(in_flight) = in_flight.upgrade() {
+        let mut in_flight = in_flight.lock();
+        if in_flight
+            .reads
+            .get(&key)
+            .is_some_and(|(current, _)| *current == id)
+        {
+            in_flight.reads.remove(&key);
+        }
+    }
+    result
+}
+
+fn copy_error(err: &StoreError) -> StoreError {
+    match err {
+        StoreError::EtcdError {
+            kind,
+            message,
+            op_id,
+            ..
+        } => StoreError::EtcdError {
+            kind: *kind,
+            message: message.clone(),
+            source: None,
+            op_id: *op_id,
+        },
+        err => unexpected(&err.to_string()),
+    }
+}
 
 #[derive(Clone)]
 pub struct EtcdStore {
     client: Client,
+    in_flight: InFlightGets,
 }
 
 impl EtcdStore {
     pub fn new(client: Client) -> Self {
-        Self { client }
+        Self {
+            client,
+            in_flight: InFlightGets::default(),
+        }
     }
 }
 
@@ -39,6 +302,7 @@ impl KeyValueStore for EtcdStore {
         Ok(EtcdBucket {
             client: self.client.clone(),
             bucket_name: bucket_name.to_string(),
+            in_flight: self.in_flight.clone(),
         })
     }
 
@@ -48,6 +312,7 @@ impl KeyValueStore for EtcdStore {
         Ok(Some(EtcdBucket {
             client: self.client.clone(),
             bucket_name: bucket_name.to_string(),
+            in_flight: self.in_flight.clone(),
         }))
     }
 
@@ -59,6 +324,7 @@ impl KeyValueStore for EtcdStore {
 pub struct EtcdBucket {
     client: Client,
     bucket_name: String,
+    in_flight: InFlightGets,
 }
 
 #[async_trait]
@@ -78,15 +344,251 @@ impl KeyValueBucket for EtcdBucket {
         }
     }
 
+    async fn insert_fenced(
+        &self,
+        key: &Key,
+        value: &str,
+        fence: Fence,
+    ) -> Result<StoreOutcome, StoreError> {
+        check_writable(&self.client, "fenced put")?;
+        let k = make_key(&self.bucket_name, key);
+        tracing::trace!("etcd fenced put: {k}");
+
+        // The fence key is created once, with the lease, and deleted with it. If it still has
+        // the create revision we were given, the lease is ours and alive.
+        let put_options = PutOptions::new()
+            .with_lease(fence.lease_id as i64)
+            .with_prev_key();
+        let txn = Txn::new()
+            .when(vec![Compare::create_revision(
+                fence_key(fence.lease_id),
+                CompareOp::Equal,
+                fence.token as i64,
+            )])
+            .and_then(vec![TxnOp::put(k.as_str(), value, Some(put_options))]);
+
+        let client = &self.client;
+        let result = retry("fenced put", || {
+            let mut kv_client = client.etcd_client().kv_client();
+            let txn = txn.clone();
+            async move { kv_client.txn(txn).await }
+        })
+        .await?;
+        if !result.succeeded() {
+            return Err(StoreError::Fenced {
+                lease_id: fence.lease_id,
+                token: fence.token,
+            });
+        }
+
+        let prev_version = match result.op_responses().into_iter().next() {
+            Some(etcd_client::TxnOpResponse::Put(put_resp)) => {
+                put_resp.prev_key().map(|kv| kv.version() as u64)
+            }
+            _ => None,
+        };
+        Ok(StoreOutcome::Created(prev_version.map_or(1, |v| v + 1)))
+    }
+
+    /// One transaction, so a key deleted meanwhile stays deleted. The key keeps its lease.
+    /// Returns the mod revision, as `compare_and_swap` does.
+    async fn update_existing(&self, key: &Key, value: &str) -> Result<StoreOutcome, StoreError> {
+        check_writable(&self.client, "update_existing")?;
+        let k = make_key(&self.bucket_name, key);
+        tracing::trace!("etcd update_existing: {k}");
+
+        let put_options = PutOptions::new().with_ignore_lease();
+        let txn = Txn::new()
+            .when(vec![Compare::version(k.as_str(), CompareOp::Greater, 0)])
+            .and_then(vec![TxnOp::put(k.as_str(), value, Some(put_options))]);
+        let result = retry("update_existing", || {
+            let mut kv_client = self.client.etcd_client().kv_client();
+            let txn = txn.clone();
+            async move { kv_client.txn(txn).await }
+        })
+        .await?;
+        if !result.succeeded() {
+            return Err(StoreError::MissingKey(key.to_string()));
+        }
+        // The put is the transaction's only change, so it has the transaction's revision
+        let revision = result
+            .header()
+            .map(|h| h.revision() as u64)
+            .ok_or_else(|| unexpected("update_existing response without a header"))?;
+        Ok(StoreOutcome::Created(revision))
+    }
+
+    /// One put, attaching the key to our lease whether it existed or not. Returns the mod
+    /// revision, as `compare_and_swap` does.
+    async fn upsert(&self, key: &Key, value: &str) -> Result<StoreOutcome, StoreError> {
+        check_writable(&self.client, "upsert")?;
+        let k = make_key(&self.bucket_name, key);
+        tracing::trace!("etcd upsert: {k}");
+
+        let put_resp = retry("upsert", || {
+            self.client.kv_put_with_options(&k, value, None)
+        })
+        .await?;
+        let revision = put_resp
+            .header()
+            .map(|h| h.revision() as u64)
+            .ok_or_else(|| unexpected("upsert response without a header"))?;
+        Ok(StoreOutcome::Created(revision))
+    }
+
+    /// Revisions here are mod revisions, as from `get_if_changed`. A new key gets our lease, an
+    /// existing one keeps its own.
+    async fn compare_and_swap(
+        &self,
+        key: &Key,
+        value: &str,
+        revision: u64,
+    ) -> Result<StoreOutcome, StoreError> {
+        check_writable(&self.client, "compare_and_swap")?;
+        let k = make_key(&self.bucket_name, key);
+        tracing::trace!(revision, "etcd compare_and_swap: {k}");
+
+        let (compare, put_options) = if revision == 0 {
+            let lease_id = self.client.primary_lease().id() as i64;
+            (
+                Compare::version(k.as_str(), CompareOp::Equal, 0),
+                PutOptions::new().with_lease(lease_id),
+            )
+        } else {
+            (
+                Compare::mod_revision(k.as_str(), CompareOp::Equal, revision as i64),
+                PutOptions::new().with_ignore_lease(),
+            )
+        };
+        let txn = Txn::new().when(vec![compare]).and_then(vec![TxnOp::put(
+            k.as_str(),
+            value,
+            Some(put_options),
+        )]);
+        let result = retry_once_applied("compare_and_swap", || {
+            let mut kv_client = self.client.etcd_client().kv_client();
+            let txn = txn.clone();
+            async move { kv_client.txn(txn).await }
+        })
+        .await?;
+        if !result.succeeded() {
+            return Err(StoreError::Retry);
+        }
+        // The put is the transaction's only change, so it has the transaction's revision
+        let revision = result
+            .header()
+            .map(|h| h.revision() as u64)
+            .ok_or_else(|| unexpected("compare_and_swap response without a header"))?;
+        Ok(StoreOutcome::Created(revision))
+    }
+
+    /// Shares the read with the concurrent gets of the same key, see [`InFlightGets`]
     async fn get(&self, key: &Key) -> Result<Option<bytes::Bytes>, StoreError> {
         let k = make_key(&self.bucket_name, key);
         tracing::trace!("etcd get: {k}");
 
-        let mut kvs = self
-            .client
-            .kv_get(k, None)
-            .await
-            .map_err(|e| StoreError::EtcdError(e.to_string()))?;
+        let client = self.client.clone();
+        let read_key = k.clone();
+        let read = async move {
+            let mut kvs = retry("get", || client.kv_get(read_key.as_str(), None)).await?;
+            if kvs.is_empty() {
+                return Ok(None);
+            }
+            let (_, val) = kvs.swap_remove(0).into_key_value();
+            Ok(Some(val.into()))
+        };
+        self.in_flight.get(k, read).await
+    }
+
+    /// One transaction of range reads per [`MAX_TXN_OPS`] keys
+    async fn get_many(&self, keys: &[Key]) -> Result<Vec<Option<bytes::Bytes>>, StoreError> {
+        let mut values = Vec::with_capacity(keys.len());
+        for chunk in keys.chunks(MAX_TXN_OPS) {
+            let ops = chunk
+                .iter()
+                .map(|key| TxnOp::get(make_key(&self.bucket_name, key), None))
+                .collect::<Vec<_>>();
+            tracing::trace!(keys = ops.len(), bucket = %self.bucket_name, "etcd get_many");
+            let txn = Txn::new().and_then(ops);
+            let result = retry("get_many", || {
+                let mut kv_client = self.client.etcd_client().kv_client();
+                let txn = txn.clone();
+                async move { kv_client.txn(txn).await }
+            })
+            .await?;
+            for response in result.op_responses() {
+                let etcd_client::TxnOpResponse::Get(get_resp) = response else {
+                    return Err(unexpected("Unexpected response in get_many transaction"));
+                };
+                values.push(
+                    get_resp
+                        .kvs()
+                        .first()
+                        .map(|kv| bytes::Bytes::from(kv.value().to_vec())),
+                );
+            }
+        }
+        Ok(values)
+    }
+
+    /// The key's mod revision is the revision. When it still matches, etcd sends no value back.
+    async fn get_if_changed(
+        &self,
+        key: &Key,
+        known_revision: u64,
+    ) -> Result<Conditional, StoreError> {
+        let k = make_key(&self.bucket_name, key);
+        tracing::trace!(known_revision, "etcd get_if_changed: {k}");
+
+        let get = vec![TxnOp::get(k.as_str(), None)];
+        // A missing key has mod revision 0, so there is nothing to compare against yet. An
+        // empty comparison always succeeds, so the get goes in the success branch instead.
+        let txn = if known_revision == 0 {
+            Txn::new().and_then(get)
+        } else {
+            Txn::new()
+                .when(vec![Compare::mod_revision(
+                    k.as_str(),
+                    CompareOp::Equal,
+                    known_revision as i64,
+                )])
+                .or_else(get)
+        };
+        let result = retry("get_if_changed", || {
+            let mut kv_client = self.client.etcd_client().kv_client();
+            let txn = txn.clone();
+            async move { kv_client.txn(txn).await }
+        })
+        .await?;
+
+        if result.succeeded() && known_revision != 0 {
+            return Ok(Conditional::NotModified);
+        }
+        let kv = match result.op_responses().into_iter().next() {
+            Some(etcd_client::TxnOpResponse::Get(get_resp)) => get_resp.kvs().first().cloned(),
+            _ => None,
+        };
+        Ok(match kv {
+            Some(kv) => Conditional::Modified {
+                revision: kv.mod_revision() as u64,
+                value: kv.value().to_vec().into(),
+            },
+            None => Conditional::Missing,
+        })
+    }
+
+    async fn get_with_consistency(
+        &self,
+        key: &Key,
+        consistency: ReadConsistency,
+    ) -> Result<Option<bytes::Bytes>, StoreError> {
+        let k = make_key(&self.bucket_name, key);
+        tracing::trace!(?consistency, "etcd get: {k}");
+
+        let mut kvs = retry("get", || {
+            self.client.kv_get_with_consistency(k.as_str(), consistency)
+        })
+        .await?;
         if kvs.is_empty() {
             return Ok(None);
         }
@@ -97,33 +599,132 @@ impl KeyValueBucket for EtcdBucket {
     async fn delete(&self, key: &Key) -> Result<(), StoreError> {
         let k = make_key(&self.bucket_name, key);
         tracing::trace!("etcd delete: {k}");
-        let _ = self
-            .client
-            .kv_delete(k, None)
-            .await
-            .map_err(|e| StoreError::EtcdError(e.to_string()))?;
+        retry("delete", || self.client.kv_delete(k.as_str(), None)).await?;
         Ok(())
     }
 
     async fn watch(
         &self,
     ) -> Result<Pin<Box<dyn futures::Stream<Item = WatchEvent> + Send + 'life0>>, StoreError> {
+        self.watch_prefix("").await
+    }
+
+    /// A watch of the key range under `prefix` only
+    async fn watch_prefix(
+        &self,
+        prefix: &str,
+    ) -> Result<Pin<Box<dyn futures::Stream<Item = WatchEvent> + Send + 'life0>>, StoreError> {
+        use futures::StreamExt;
+
+        // Only changes from now on, there are no existing keys to sync first
+        let prefix = make_key(&self.bucket_name, &Key::from_raw(prefix.to_string()));
+        let changes = self
+            .watch_with(prefix, WatchOptions::new().with_prefix())
+            .await?;
+        let synced = futures::stream::iter([WatchEvent::InitialSyncComplete]);
+        Ok(Box::pin(synced.chain(changes)))
+    }
+
+    /// A watch of the key range from `start` to `end` only
+    async fn watch_range(
+        &self,
+        start: &str,
+        end: &str,
+    ) -> Result<Pin<Box<dyn futures::Stream<Item = WatchEvent> + Send + 'life0>>, StoreError> {
+        use futures::StreamExt;
+
+        let from = make_key(&self.bucket_name, &Key::from_raw(start.to_string()));
+        let to = match end {
+            "" => prefix_range_end(make_key(&self.bucket_name, &"".into()).as_bytes()),
+            end => make_key(&self.bucket_name, &Key::from_raw(end.to_string())).into_bytes(),
+        };
+        let changes = self
+            .watch_with(from, WatchOptions::new().with_range(to))
+            .await?;
+        let synced = futures::stream::iter([WatchEvent::InitialSyncComplete]);
+        Ok(Box::pin(synced.chain(changes)))
+    }
+
+    /// From the revision after `sequence`, unless etcd compacted it away. Then the stream
+    /// ends without any events.
+    async fn watch_from(
+        &self,
+        sequence: u64,
+    ) -> Result<Option<Pin<Box<dyn futures::Stream<Item = WatchEvent> + Send + 'life0>>>, StoreError>
+    {
+        let options = WatchOptions::new()
+            .with_prefix()
+            .with_start_revision(sequence as i64 + 1);
         let prefix = make_key(&self.bucket_name, &"".into());
+        Ok(Some(self.watch_with(prefix, options).await?))
+    }
+
+    async fn entries(&self) -> Result<HashMap<String, bytes::Bytes>, StoreError> {
+        let k = make_key(&self.bucket_name, &"".into());
+        tracing::trace!("etcd entries: {k}");
+
+        let resp = retry("entries", || self.client.kv_get_prefix(&k)).await?;
+        let out: HashMap<String, bytes::Bytes> = resp
+            .into_iter()
+            .map(|kv| {
+                let (k, v) = kv.into_key_value();
+                (String::from_utf8_lossy(&k).to_string(), v.into())
+            })
+            .collect();
+
+        Ok(out)
+    }
+
+    /// Only the range of keys under `prefix`
+    async fn entries_prefix(
+        &self,
+        prefix: &str,
+    ) -> Result<HashMap<String, bytes::Bytes>, StoreError> {
+        let k = make_key(&self.bucket_name, &Key::from_raw(prefix.to_string()));
+        tracing::trace!("etcd entries_prefix: {k}");
+
+        let resp = retry("entries_prefix", || self.client.kv_get_prefix(&k)).await?;
+        Ok(resp
+            .into_iter()
+            .map(|kv| {
+                let (k, v) = kv.into_key_value();
+                (String::from_utf8_lossy(&k).to_string(), v.into())
+            })
+            .collect())
+    }
+}
+
+impl EtcdBucket {
+    async fn watch_with(
+        &self,
+        prefix: String,
+        options: WatchOptions,
+    ) -> Result<Pin<Box<dyn futures::Stream<Item = WatchEvent> + Send + '_>>, StoreError> {
         tracing::trace!("etcd watch: {prefix}");
-        let (watcher, mut watch_stream) = self
-            .client
-            .etcd_client()
-            .clone()
-            .watch(prefix.as_bytes(), Some(WatchOptions::new().with_prefix()))
-            .await
-            .map_err(|e| StoreError::EtcdError(e.to_string()))?;
+        let (watcher, mut watch_stream) = retry("watch", || {
+            let mut client = self.client.etcd_client().clone();
+            let prefix = prefix.clone();
+            let options = options.clone();
+            async move { client.watch(prefix, Some(options)).await }
+        })
+        .await?;
         let output = stream! {
             let _watcher = watcher; // Keep it alive. Not sure if necessary.
             while let Ok(Some(resp)) = watch_stream.message().await {
+                if resp.canceled() {
+                    tracing::warn!(
+                        prefix,
+                        compact_revision = resp.compact_revision(),
+                        reason = resp.cancel_reason(),
+                        "etcd cancelled the watch"
+                    );
+                    break;
+                }
                 for e in resp.events() {
                     let Some(kv) = e.kv() else {
                         continue;
                     };
+                    let sequence = kv.mod_revision() as u64;
                     let (k_bytes, v_bytes) = kv.clone().into_key_value();
                     let key = match String::from_utf8(k_bytes) {
                         Ok(k) => k,
@@ -132,7 +733,7 @@ impl KeyValueBucket for EtcdBucket {
                             continue;
                         }
                     };
-                    let item = KeyValue::new(key, v_bytes.into());
+                    let item = KeyValue::new(key, v_bytes.into()).with_sequence(sequence);
                     match e.event_type() {
                         EventType::Put => {
                             yield WatchEvent::Put(item);
@@ -147,34 +748,14 @@ impl KeyValueBucket for EtcdBucket {
         Ok(Box::pin(output))
     }
 
-    async fn entries(&self) -> Result<HashMap<String, bytes::Bytes>, StoreError> {
-        let k = make_key(&self.bucket_name, &"".into());
-        tracing::trace!("etcd entries: {k}");
-
-        let resp = self
-            .client
-            .kv_get_prefix(k)
-            .await
-            .map_err(|e| StoreError::EtcdError(e.to_string()))?;
-        let out: HashMap<String, bytes::Bytes> = resp
-            .into_iter()
-            .map(|kv| {
-                let (k, v) = kv.into_key_value();
-                (String::from_utf8_lossy(&k).to_string(), v.into())
-            })
-            .collect();
-
-        Ok(out)
-    }
-}
-
-impl EtcdBucket {
     async fn create(&self, key: &Key, value: &str) -> Result<StoreOutcome, StoreError> {
+        check_writable(&self.client, "create")?;
         let k = make_key(&self.bucket_name, key);
         tracing::trace!("etcd create: {k}");
 
         // Use atomic transaction to check and create in one operation
-        let put_options = PutOptions::new().with_lease(self.client.primary_lease().id() as i64);
+        let lease_id = self.client.primary_lease().id() as i64;
+        let put_options = PutOptions::new().with_lease(lease_id);
 
         // Build transaction that creates key only if it doesn't exist
         let txn = Txn::new()
@@ -185,13 +766,12 @@ impl EtcdBucket {
             ]);
 
         // Execute the transaction
-        let result = self
-            .client
-            .etcd_client()
-            .kv_client()
-            .txn(txn)
-            .await
-            .map_err(|e| StoreError::EtcdError(e.to_string()))?;
+        let result = retry_once_applied("create", || {
+            let mut kv_client = self.client.etcd_client().kv_client();
+            let txn = txn.clone();
+            async move { kv_client.txn(txn).await }
+        })
+        .await?;
 
         if result.succeeded() {
             // Key was created successfully
@@ -203,13 +783,10 @@ impl EtcdBucket {
             result.op_responses().into_iter().next()
             && let Some(kv) = get_resp.kvs().first()
         {
-            let version = kv.version() as u64;
-            return Ok(StoreOutcome::Exists(version));
+            return Ok(StoreOutcome::Exists(kv.version() as u64));
         }
         // Shouldn't happen, but handle edge case
-        Err(StoreError::EtcdError(
-            "Unexpected transaction response".to_string(),
-        ))
+        Err(unexpected("Unexpected transaction response"))
     }
 
     async fn update(
@@ -218,15 +795,12 @@ impl EtcdBucket {
         value: &str,
         revision: u64,
     ) -> Result<StoreOutcome, StoreError> {
+        check_writable(&self.client, "update")?;
         let version = revision;
         let k = make_key(&self.bucket_name, key);
         tracing::trace!("etcd update: {k}");
 
-        let kvs = self
-            .client
-            .kv_get(k.clone(), None)
-            .await
-            .map_err(|e| StoreError::EtcdError(e.to_string()))?;
+        let kvs = retry("get", || self.client.kv_get(k.as_str(), None)).await?;
         if kvs.is_empty() {
             return Err(StoreError::MissingKey(key.to_string()));
         }
@@ -245,11 +819,11 @@ impl EtcdBucket {
         let put_options = PutOptions::new()
             .with_lease(self.client.primary_lease().id() as i64)
             .with_prev_key();
-        let mut put_resp = self
-            .client
-            .kv_put_with_options(k, value, Some(put_options))
-            .await
-            .map_err(|e| StoreError::EtcdError(e.to_string()))?;
+        let mut put_resp = retry("put", || {
+            self.client
+                .kv_put_with_options(&k, value, Some(put_options.clone()))
+        })
+        .await?;
         Ok(match put_resp.take_prev_key() {
             // Should this be an error?
             // The key was deleted between our get and put. We re-created it.
@@ -268,6 +842,233 @@ fn make_key(bucket_name: &str, key: &Key) -> String {
     [bucket_name.to_string(), key.to_string()].join("/")
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(code: Code, message: &str) -> anyhow::Error {
+        etcd_client::Error::GRpcStatus(tonic::Status::new(code, message)).into()
+    }
+
+    #[test]
+    fn test_classify() {
+        let cases = [
+            (
+                Code::Unavailable,
+                "etcdserver: leader changed",
+                EtcdErrorKind::LeaderChanged,
+            ),
+            (
+                Code::Unavailable,
+                "etcdserver: no leader",
+                EtcdErrorKind::LeaderChanged,
+            ),
+            (
+                Code::Unavailable,
+                "etcdserver: request timed out",
+                EtcdErrorKind::Timeout,
+            ),
+            (
+                Code::DeadlineExceeded,
+                "context deadline exceeded",
+                EtcdErrorKind::Timeout,
+            ),
+            (
+                Code::Unavailable,
+                "connection refused",
+                EtcdErrorKind::Unavailable,
+            ),
+            (
+                Code::OutOfRange,
+                "etcdserver: mvcc: required revision has been compacted",
+                EtcdErrorKind::Compacted,
+            ),
+            (
+                Code::Unauthenticated,
+                "etcdserver: invalid auth token",
+                EtcdErrorKind::Auth,
+            ),
+            (
+                Code::InvalidArgument,
+                "etcdserver: authentication failed, invalid user ID or password",
+                EtcdErrorKind::Auth,
+            ),
+            (
+                Code::InvalidArgument,
+                "etcdserver: key is not provided",
+                EtcdErrorKind::Other,
+            ),
+        ];
+        for (code, message, kind) in cases {
+            assert_eq!(EtcdErrorKind::of(&status(code, message)), kind, "{message}");
+        }
+        assert_eq!(
+            EtcdErrorKind::of(&anyhow::anyhow!("leader changed")),
+            EtcdErrorKind::Other
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retry_transient() {
+        let mut attempts = 0;
+        let start = tokio::time::Instant::now();
+        let result = retry("test", || {
+            attempts += 1;
+            let result = if attempts < 3 {
+                Err(status(Code::Unavailable, "etcdserver: leader changed"))
+            } else {
+                Ok(attempts)
+            };
+            std::future::ready(result)
+        })
+        .await;
+        assert_eq!(result.unwrap(), 3);
+        // 50ms + 100ms
+        assert_eq!(start.elapsed(), Duration::from_millis(150));
+
+        let mut attempts = 0;
+        let result: Result<(), _> = retry("test", || {
+            attempts += 1;
+            std::future::ready(Err(status(
+                Code::Unavailable,
+                "etcdserver: request timed out",
+            )))
+        })
+        .await;
+        assert_eq!(attempts, MAX_ATTEMPTS);
+        assert!(matches!(
+            result,
+            Err(StoreError::EtcdError {
+                kind: EtcdErrorKind::Timeout,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_no_retry_once_applied() {
+        let mut attempts = 0;
+        let result: Result<(), _> = retry_once_applied("test", || {
+            attempts += 1;
+            std::future::ready(Err(status(
+                Code::Unavailable,
+                "etcdserver: request timed out",
+            )))
+        })
+        .await;
+        assert_eq!(attempts, 1);
+        assert!(matches!(
+            result,
+            Err(StoreError::EtcdError {
+                kind: EtcdErrorKind::Timeout,
+                ..
+            })
+        ));
+
+        // Refused before it was applied
+        let mut attempts = 0;
+        let result = retry_once_applied("test", || {
+            attempts += 1;
+            let result = match attempts {
+                1 => Err(status(Code::Unavailable, "etcdserver: leader changed")),
+                _ => Ok(attempts),
+            };
+            std::future::ready(result)
+        })
+        .await;
+        assert_eq!(result.unwrap(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_no_retry_permanent() {
+        let mut attempts = 0;
+        let result: Result<(), _> = retry("test", || {
+            attempts += 1;
+            std::future::ready(Err(status(Code::PermissionDenied, "permission denied")))
+        })
+        .await;
+        assert_eq!(attempts, 1);
+        let Err(StoreError::EtcdError {
+            kind,
+            message,
+            source,
+            ..
+        }) = result
+        else {
+            panic!("expected an etcd error, got {result:?}");
+        };
+        assert_eq!(kind, EtcdErrorKind::Auth);
+        assert!(message.contains("permission denied"), "{message}");
+        let source = source.expect("the etcd client's error is kept");
+        assert!(
+            source.downcast_ref::<etcd_client::Error>().is_some(),
+            "{source:?}"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_in_flight_gets() {
+        let gets = InFlightGets::default();
+        let reads = Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let read = |value: &'static str| {
+            let reads = reads.clone();
+            async move {
+                reads.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                Ok(Some(bytes::Bytes::from_static(value.as_bytes())))
+            }
+        };
+        let value = |value: &'static str| Some(bytes::Bytes::from_static(value.as_bytes()));
+
+        let (a, b, other) = tokio::join!(
+            gets.get("k".to_string(), read("first")),
+            gets.get("k".to_string(), read("second")),
+            gets.get("other".to_string(), read("third")),
+        );
+        assert_eq!((a.unwrap(), b.unwrap()), (value("first"), value("first")));
+        assert_eq!(other.unwrap(), value("third"));
+        assert_eq!(reads.load(std::sync::atomic::Ordering::Relaxed), 2);
+
+        // Answered, so the next get reads again
+        assert!(gets.0.lock().reads.is_empty());
+        assert_eq!(
+            gets.get("k".to_string(), read("fourth")).await.unwrap(),
+            value("fourth")
+        );
+
+        // A read no one waits for anymore is still answered for the next get
+        let abandoned = gets.get("k".to_string(), read("fifth"));
+        let _ = tokio::time::timeout(Duration::from_millis(1), abandoned).await;
+        assert_eq!(
+            gets.get("k".to_string(), read("sixth")).await.unwrap(),
+            value("fifth")
+        );
+
+        let fail = || async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            Err(StoreError::EtcdError {
+                kind: EtcdErrorKind::Unavailable,
+                message: "no members".to_string(),
+                source: Some(anyhow::anyhow!("connection refused").into()),
+                op_id: None,
+            })
+        };
+        let (a, b) = tokio::join!(
+            gets.get("k".to_string(), fail()),
+            gets.get("k".to_string(), fail())
+        );
+        for err in [a.unwrap_err(), b.unwrap_err()] {
+            let StoreError::EtcdError { kind, message, .. } = err else {
+                panic!("expected an etcd error, got {err:?}");
+            };
+            assert_eq!(
+                (kind, message.as_str()),
+                (EtcdErrorKind::Unavailable, "no members")
+            );
+        }
+    }
+}
+
 #[cfg(feature = "integration")]
 #[cfg(test)]
 mod concurrent_create_tests {
@@ -280,7 +1081,7 @@ mod concurrent_create_tests {
     fn test_concurrent_etcd_create_race_condition() {
         let rt = Runtime::from_settings().unwrap();
         let rt_clone = rt.clone();
-        let config = DistributedConfig::from_settings(false);
+        let config = DistributedConfig::from_settings(false).unwrap();
 
         rt_clone.primary().block_on(async move {
             let drt = DistributedRuntime::new(rt, config).await.unwrap();
@@ -416,3 +1217,207 @@ mod concurrent_create_tests {
         Ok(())
     }
 }
+
+#[cfg(feature = "testing-etcd")]
+#[cfg(test)]
+mod read_only_tests {
+    use super::*;
+    use crate::Runtime;
+    use crate::storage::key_value_store::Key;
+
+    /// A bucket on a read-only client, with `key` already in it, and a writable client on the
+    /// same cluster
+    async fn read_only_bucket(key: &Key) -> (std::mem::ManuallyDrop<Client>, EtcdBucket) {
+        let runtime = Runtime::from_settings().unwrap();
+        let options = |read_only| {
+            Client::builder()
+                .etcd_url(vec!["http://localhost:2379".to_string()])
+                .read_only(read_only)
+                .build()
+                .unwrap()
+        };
+        let writer = Client::new(options(false), runtime.clone()).await.unwrap();
+        let reader = Client::new(options(true), runtime).await.unwrap();
+        // Prevent runtime from being dropped in async context at end of test
+        let writer = std::mem::ManuallyDrop::new(writer);
+        let reader = std::mem::ManuallyDrop::new(reader);
+
+        let bucket_name = format!("test_read_only_{}", uuid::Uuid::new_v4().simple());
+        let writable = EtcdStore::new((*writer).clone())
+            .get_or_create_bucket(&bucket_name, None)
+            .await
+            .unwrap();
+        writable.insert(key, "original", 0).await.unwrap();
+        let bucket = EtcdStore::new((*reader).clone())
+            .get_or_create_bucket(&bucket_name, None)
+            .await
+            .unwrap();
+        (writer, bucket)
+    }
+
+    async fn assert_unchanged(bucket: &EtcdBucket, key: &Key) {
+        let value = bucket.get(key).await.unwrap().expect("key still there");
+        assert_eq!(&value[..], b"original");
+    }
+
+    #[tokio::test]
+    async fn test_read_only_create() {
+        let key = Key::new("existing");
+        let (_writer, bucket) = read_only_bucket(&key).await;
+        let new_key = Key::new("new");
+        assert!(bucket.insert(&new_key, "x", 0).await.is_err());
+        assert!(bucket.get(&new_key).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_read_only_update() {
+        let key = Key::new("existing");
+        let (_writer, bucket) = read_only_bucket(&key).await;
+        assert!(bucket.insert(&key, "x", 1).await.is_err());
+        assert_unchanged(&bucket, &key).await;
+    }
+
+    #[tokio::test]
+    async fn test_read_only_fenced_put() {
+        let key = Key::new("existing");
+        let (writer, bucket) = read_only_bucket(&key).await;
+        let fence = writer.primary_lease().fence();
+        assert!(bucket.insert_fenced(&key, "x", fence).await.is_err());
+        assert_unchanged(&bucket, &key).await;
+    }
+
+    #[tokio::test]
+    async fn test_read_only_update_existing() {
+        let key = Key::new("existing");
+        let (_writer, bucket) = read_only_bucket(&key).await;
+        assert!(bucket.update_existing(&key, "x").await.is_err());
+        assert_unchanged(&bucket, &key).await;
+    }
+
+    #[tokio::test]
+    async fn test_read_only_compare_and_swap() {
+        let key = Key::new("existing");
+        let (_writer, bucket) = read_only_bucket(&key).await;
+        let Conditional::Modified { revision, .. } = bucket.get_if_changed(&key, 0).await.unwrap()
+        else {
+            panic!("key missing");
+        };
+        let result = bucket.compare_and_swap(&key, "x", revision).await;
+        assert!(matches!(result, Err(StoreError::EtcdError { .. })));
+        assert_unchanged(&bucket, &key).await;
+    }
+
+    #[tokio::test]
+    async fn test_read_only_delete() {
+        let key = Key::new("existing");
+        let (_writer, bucket) = read_only_bucket(&key).await;
+        assert!(bucket.delete(&key).await.is_err());
+        assert_unchanged(&bucket, &key).await;
+    }
+}
+
+#[cfg(feature = "testing-etcd")]
+#[cfg(test)]
+mod fencing_tests {
+    use super::*;
+    use crate::Runtime;
+
+    #[tokio::test]
+    async fn test_stale_fence_rejected_after_reacquire() {
+        let runtime = Runtime::from_settings().unwrap();
+        let options = Client::builder()
+            .etcd_url(vec!["http://localhost:2379".to_string()])
+            .build()
+            .unwrap();
+        let client = Client::new(options, runtime).await.unwrap();
+        // Prevent runtime from being dropped in async context at end of test
+        let client = std::mem::ManuallyDrop::new(client);
+
+        let bucket_name = format!("test_fencing_{}", uuid::Uuid::new_v4().simple());
+        let bucket = EtcdStore::new((*client).clone())
+            .get_or_create_bucket(&bucket_name, None)
+            .await
+            .unwrap();
+        let key = Key::new("leader");
+
+        let first = client.create_lease(10).await.unwrap();
+        bucket
+            .insert_fenced(&key, "first", first.fence())
+            .await
+            .unwrap();
+
+        // Lost and taken again, as by a worker that was partitioned away and replaced
+        client.revoke_lease(first.id()).await.unwrap();
+        let second = client.create_lease(10).await.unwrap();
+        assert!(second.fence_token() > first.fence_token());
+
+        let stale = bucket.insert_fenced(&key, "stale", first.fence()).await;
+        assert!(matches!(
+            stale,
+            Err(StoreError::Fenced { lease_id, token })
+                if lease_id == first.id() && token == first.fence_token()
+        ));
+        assert!(bucket.get(&key).await.unwrap().is_none());
+
+        bucket
+            .insert_fenced(&key, "current", second.fence())
+            .await
+            .unwrap();
+        let value = bucket.get(&key).await.unwrap().unwrap();
+        assert_eq!(&value[..], b"current");
+
+        client.revoke_lease(second.id()).await.unwrap();
+    }
+}
+
+#[cfg(feature = "testing-etcd")]
+#[cfg(test)]
+mod watch_tests {
+    use std::sync::Arc;
+
+    use tokio_util::sync::CancellationToken;
+
+    use super::*;
+    use crate::Runtime;
+    use crate::storage::key_value_store::{KeyValueStoreManager, WatchEvent, WatchFilter};
+
+    async fn manager() -> (std::mem::ManuallyDrop<Client>, Arc<KeyValueStoreManager>) {
+        let runtime = Runtime::from_settings().unwrap();
+        let options = Client::builder()
+            .etcd_url(vec!["http://localhost:2379".to_string()])
+            .build()
+            .unwrap();
+        let client = Client::new(options, runtime).await.unwrap();
+        let manager = Arc::new(KeyValueStoreManager::etcd(client.clone()));
+        // Prevent runtime from being dropped in async context at end of test
+        (std::mem::ManuallyDrop::new(client), manager)
+    }
+
+    #[tokio::test]
+    async fn test_watch_closed_on_cancel() {
+        let (_client, manager) = manager().await;
+        let bucket_name = format!("test_watch_{}", uuid::Uuid::new_v4().simple());
+        let cancel_token = CancellationToken::new();
+        let mut rx = manager.watch(&bucket_name, None, cancel_token.clone());
+        assert_eq!(rx.recv().await, Some(WatchEvent::InitialSyncComplete));
+
+        cancel_token.cancel();
+        assert_eq!(rx.recv().await, Some(WatchEvent::Closed));
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_watch_error_on_refused_request() {
+        let (_client, manager) = manager().await;
+        let bucket_name = format!("test_watch_{}", uuid::Uuid::new_v4().simple());
+        // Past the server's request size limit, 1.5 MiB by default, so no retry gets through
+        let filter = WatchFilter::prefix("x".repeat(3 << 20));
+        let cancel_token = CancellationToken::new();
+        let mut rx = manager.watch_filtered(&bucket_name, None, filter, cancel_token);
+        let Some(WatchEvent::Error(err)) = rx.recv().await else {
+            panic!("expected the watch to fail");
+        };
+        assert!(!err.is_transient(), "{err}");
+        assert!(rx.recv().await.is_none());
+    }
+}
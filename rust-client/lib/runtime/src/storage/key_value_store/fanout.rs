@@ -0,0 +1,359 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Share one bucket watch between many consumers, without losing events silently.
+//!
+//! A broadcast channel drops the oldest events when a subscriber falls behind. Before this,
+//! a slow consumer simply never saw them and its view of the bucket diverged. Now it gets a
+//! [`WatchGap`] instead and knows to re-read the bucket with `entries()`.
+//!
+//! [`WatchRegistry`] keeps one fanout per bucket for the whole process, so consumers of the
+//! same bucket share a store watch instead of opening one each. A consumer that joins after the
+//! watch started is first sent what the others already know: the current entries as puts, then
+//! [`WatchEvent::InitialSyncComplete`] if it was sent.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Weak};
+
+use futures::{Stream, StreamExt, pin_mut};
+use parking_lot::Mutex;
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+use super::{KeyValue, WatchEvent};
+
+/// Why a [`WatchSubscriber`] cannot vouch for the events it delivers any more
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum WatchGap {
+    #[error("Watch subscriber fell behind and missed {0} events")]
+    Lagged(u64),
+
+    #[error("Watch sequence went back from {last} to {got}")]
+    OutOfOrder { last: u64, got: u64 },
+}
+
+/// Fans a bucket's watch stream out to any number of [`WatchSubscriber`]s
+pub struct WatchFanout {
+    state: Arc<Mutex<FanoutState>>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+/// Locked together so a new subscriber gets each event either in its replay or live, not both
+struct FanoutState {
+    /// None once the source stream has ended, so subscribers see the channel close
+    tx: Option<broadcast::Sender<WatchEvent>>,
+    /// The bucket as the events so far left it, by key
+    entries: HashMap<String, KeyValue>,
+    synced: bool,
+    disconnected: bool,
+}
+
+impl FanoutState {
+    fn apply(&mut self, event: &WatchEvent) {
+        match event {
+            WatchEvent::Put(kv) => {
+                self.entries.insert(kv.key().to_string(), kv.clone());
+            }
+            WatchEvent::Delete(kv) => {
+                self.entries.remove(kv.key());
+            }
+            WatchEvent::InitialSyncComplete => self.synced = true,
+            WatchEvent::Disconnected => self.disconnected = true,
+            WatchEvent::Reconnected => self.disconnected = false,
+            WatchEvent::Error(_) | WatchEvent::Closed => {}
+        }
+    }
+
+    /// What a subscriber joining now needs to catch up with the others, oldest first
+    fn replay(&self) -> VecDeque<WatchEvent> {
+        let mut entries: Vec<_> = self.entries.values().cloned().collect();
+        entries.sort_by_key(KeyValue::sequence);
+        let mut replay: VecDeque<_> = entries.into_iter().map(WatchEvent::Put).collect();
+        if self.synced {
+            replay.push_back(WatchEvent::InitialSyncComplete);
+        }
+        if self.disconnected {
+            replay.push_back(WatchEvent::Disconnected);
+        }
+        replay
+    }
+}
+
+impl WatchFanout {
+    /// Start forwarding `stream`. A subscriber more than `capacity` events behind loses events
+    /// and is told so.
+    pub fn new<S>(stream: S, capacity: usize) -> Self
+    where
+        S: Stream<Item = WatchEvent> + Send + 'static,
+    {
+        Self::start(stream, capacity).0
+    }
+
+    /// Like `new`, with a subscriber that sees the stream from its first event
+    fn start<S>(stream: S, capacity: usize) -> (Self, WatchSubscriber)
+    where
+        S: Stream<Item = WatchEvent> + Send + 'static,
+    {
+        let (forward, rx) = broadcast::channel(capacity);
+        let state = Arc::new(Mutex::new(FanoutState {
+            tx: Some(forward.clone()),
+            entries: HashMap::new(),
+            synced: false,
+            disconnected: false,
+        }));
+        let task_state = state.clone();
+        let task = tokio::spawn(async move {
+            pin_mut!(stream);
+            while let Some(event) = stream.next().await {
+                let mut state = task_state.lock();
+                state.apply(&event);
+                // No subscribers is fine, later ones get the state replayed
+                let _ = forward.send(event);
+            }
+            task_state.lock().tx.take();
+        });
+        (WatchFanout { state, task }, WatchSubscriber::new(rx))
+    }
+
+    /// The bucket's entries so far as puts, [`WatchEvent::InitialSyncComplete`] if the stream
+    /// sent it, [`WatchEvent::Disconnected`] if it is down, then events from now on
+    pub fn subscribe(&self) -> WatchSubscriber {
+        let state = self.state.lock();
+        let Some(tx) = state.tx.as_ref() else {
+            // Already ended, hand out a receiver that is closed too
+            return WatchSubscriber::new(broadcast::channel(1).1);
+        };
+        let mut subscriber = WatchSubscriber::new(tx.subscribe());
+        subscriber.replay = state.replay();
+        subscriber
+    }
+
+    fn has_ended(&self) -> bool {
+        self.state.lock().tx.is_none()
+    }
+}
+
+impl Drop for WatchFanout {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+pub struct WatchSubscriber {
+    rx: broadcast::Receiver<WatchEvent>,
+    /// Sent before anything from `rx`
+    replay: VecDeque<WatchEvent>,
+    last_sequence: u64,
+    /// From a [`WatchRegistry`], keeps the shared watch running
+    shared: Option<Arc<SharedWatch>>,
+}
+
+impl WatchSubscriber {
+    fn new(rx: broadcast::Receiver<WatchEvent>) -> Self {
+        WatchSubscriber {
+            rx,
+            replay: VecDeque::new(),
+            last_sequence: 0,
+            shared: None,
+        }
+    }
+
+    /// The next event, or None once the watch has ended. After an error the subscriber keeps
+    /// going from the oldest event still buffered, but its view may be wrong until the caller
+    /// resyncs.
+    pub async fn recv(&mut self) -> Option<Result<WatchEvent, WatchGap>> {
+        let received = match self.replay.pop_front() {
+            Some(event) => Ok(event),
+            None => self.rx.recv().await,
+        };
+        let event = match received {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                return Some(Err(WatchGap::Lagged(missed)));
+            }
+            Err(broadcast::error::RecvError::Closed) => return None,
+        };
+        // Events with no sequence cannot be checked
+        let sequence = event.sequence();
+        if sequence != 0 {
+            let last = self.last_sequence;
+            self.last_sequence = sequence;
+            if sequence < last {
+                return Some(Err(WatchGap::OutOfOrder {
+                    last,
+                    got: sequence,
+                }));
+            }
+        }
+        Some(Ok(event))
+    }
+}
+
+/// The shared watches of a process, by bucket
+#[derive(Default)]
+pub(super) struct WatchRegistry {
+    watches: Mutex<HashMap<String, Weak<SharedWatch>>>,
+}
+
+/// Lives as long as its subscribers. The last one to go stops the store watch.
+struct SharedWatch {
+    fanout: WatchFanout,
+    cancel_token: CancellationToken,
+}
+
+impl Drop for SharedWatch {
+    fn drop(&mut self) {
+        self.cancel_token.cancel();
+    }
+}
+
+impl WatchRegistry {
+    /// A subscriber to the watch on `bucket_name`. If there is none, or it has ended, `start`
+    /// makes one, to run until the token it is given is cancelled, and the new subscriber sees
+    /// it from the start. Otherwise it joins the running one as [`WatchFanout::subscribe`] does.
+    pub(super) fn subscribe<S>(
+        &self,
+        bucket_name: &str,
+        capacity: usize,
+        start: impl FnOnce(CancellationToken) -> S,
+    ) -> WatchSubscriber
+    where
+        S: Stream<Item = WatchEvent> + Send + 'static,
+    {
+        let mut watches = self.watches.lock();
+        if let Some(shared) = watches.get(bucket_name).and_then(Weak::upgrade)
+            && !shared.fanout.has_ended()
+        {
+            let mut subscriber = shared.fanout.subscribe();
+            subscriber.shared = Some(shared);
+            return subscriber;
+        }
+        watches.retain(|_, shared| shared.strong_count() > 0);
+
+        let cancel_token = CancellationToken::new();
+        let (fanout, mut subscriber) = WatchFanout::start(start(cancel_token.clone()), capacity);
+        let shared = Arc::new(SharedWatch {
+            fanout,
+            cancel_token,
+        });
+        watches.insert(bucket_name.to_string(), Arc::downgrade(&shared));
+        subscriber.shared = Some(shared);
+        subscriber
+    }
+
+    #[cfg(test)]
+    pub(super) fn len(&self) -> usize {
+        self.watches
+            .lock()
+            .values()
+            .filter(|shared| shared.strong_count() > 0)
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::key_value_store::KeyValue;
+
+    fn put(key: &str, sequence: u64) -> WatchEvent {
+        WatchEvent::Put(KeyValue::new(key.to_string(), bytes::Bytes::new()).with_sequence(sequence))
+    }
+
+    #[tokio::test]
+    async fn test_fanout_in_order() {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let fanout = WatchFanout::new(tokio_stream::wrappers::UnboundedReceiverStream::new(rx), 8);
+        let mut a = fanout.subscribe();
+        let mut b = fanout.subscribe();
+
+        for (key, sequence) in [("a", 1), ("b", 5), ("c", 5), ("d", 9)] {
+            tx.send(put(key, sequence)).unwrap();
+        }
+        for sub in [&mut a, &mut b] {
+            for expected in [1, 5, 5, 9] {
+                let event = sub.recv().await.unwrap().unwrap();
+                assert_eq!(event.sequence(), expected);
+            }
+        }
+
+        drop(tx);
+        assert!(a.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_fanout_replays_to_late_subscribers() {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let fanout = WatchFanout::new(tokio_stream::wrappers::UnboundedReceiverStream::new(rx), 8);
+        let mut early = fanout.subscribe();
+
+        tx.send(put("a", 1)).unwrap();
+        tx.send(put("b", 2)).unwrap();
+        tx.send(WatchEvent::InitialSyncComplete).unwrap();
+        tx.send(put("c", 3)).unwrap();
+        tx.send(WatchEvent::Delete(
+            KeyValue::new("a".to_string(), bytes::Bytes::new()).with_sequence(4),
+        ))
+        .unwrap();
+        tx.send(put("b", 5)).unwrap();
+        // Once the early subscriber has it all, so does the fanout's state
+        for _ in 0..6 {
+            early.recv().await.unwrap().unwrap();
+        }
+
+        let mut late = fanout.subscribe();
+        let event = late.recv().await.unwrap().unwrap();
+        assert_eq!(
+            (event.key_value().map(KeyValue::key), event.sequence()),
+            (Some("c"), 3)
+        );
+        let event = late.recv().await.unwrap().unwrap();
+        assert_eq!(
+            (event.key_value().map(KeyValue::key), event.sequence()),
+            (Some("b"), 5)
+        );
+        assert_eq!(late.recv().await, Some(Ok(WatchEvent::InitialSyncComplete)));
+
+        // Then live, like the others
+        tx.send(put("d", 6)).unwrap();
+        for sub in [&mut early, &mut late] {
+            assert_eq!(sub.recv().await.unwrap().unwrap().sequence(), 6);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fanout_reports_lag() {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let fanout = WatchFanout::new(tokio_stream::wrappers::UnboundedReceiverStream::new(rx), 2);
+        let mut sub = fanout.subscribe();
+
+        for sequence in 1..=5 {
+            tx.send(put("k", sequence)).unwrap();
+        }
+        drop(tx);
+        // Let the forwarding task drain the channel
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        assert_eq!(sub.recv().await, Some(Err(WatchGap::Lagged(3))));
+        assert_eq!(sub.recv().await.unwrap().unwrap().sequence(), 4);
+        assert_eq!(sub.recv().await.unwrap().unwrap().sequence(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_fanout_reports_out_of_order() {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let fanout = WatchFanout::new(tokio_stream::wrappers::UnboundedReceiverStream::new(rx), 8);
+        let mut sub = fanout.subscribe();
+
+        tx.send(put("a", 3)).unwrap();
+        tx.send(put("b", 2)).unwrap();
+        drop(tx);
+
+        assert_eq!(sub.recv().await.unwrap().unwrap().sequence(), 3);
+        assert_eq!(
+            sub.recv().await,
+            Some(Err(WatchGap::OutOfOrder { last: 3, got: 2 }))
+        );
+        assert!(sub.recv().await.is_none());
+    }
+}
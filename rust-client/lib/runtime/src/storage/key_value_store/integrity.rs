@@ -0,0 +1,174 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Content hashes stored with values, to tell damaged data from a bug in the code reading it.
+//!
+//! A [`KeyValueStoreManager`](super::KeyValueStoreManager) made
+//! [`with_content_hashes`](super::KeyValueStoreManager::with_content_hashes) writes each value
+//! behind a header holding its xxh3 hash. Every manager checks the hash of the values that have
+//! one when reading them, so a value cut short or altered in the store fails with
+//! [`StoreError::Corrupted`] instead of a [`StoreError::JSONDecodeError`], and counts in
+//! [`integrity_failures`]. Values without a header are read as they are.
+//!
+//! The header starts with a NUL, which no JSON or text value does. Readers that don't go
+//! through a manager see it, so turn hashing on once every reader knows about it.
+
+use std::collections::HashMap;
+
+use once_cell::sync::Lazy;
+
+use crate::metrics::prometheus_names::kv_store as kv_store_metrics;
+
+use super::{StoreError, WatchEvent};
+
+const MAGIC: &str = "\0xxh3:";
+
+/// The magic, the hash in hex and a newline
+const HEADER_LEN: usize = MAGIC.len() + 16 + 1;
+
+static INTEGRITY_FAILURES: Lazy<prometheus::IntCounter> = Lazy::new(|| {
+    prometheus::IntCounter::new(
+        kv_store_metrics::INTEGRITY_FAILURES_TOTAL,
+        "Values read from the store whose content hash didn't match",
+    )
+    .expect("valid counter options")
+});
+
+/// Values read in this process that failed their hash check
+pub fn integrity_failures() -> prometheus::IntCounter {
+    INTEGRITY_FAILURES.clone()
+}
+
+/// `value` behind a header with its hash
+pub(super) fn seal(value: &str) -> String {
+    let hash = xxhash_rust::xxh3::xxh3_64(value.as_bytes());
+    format!("{MAGIC}{hash:016x}\n{value}")
+}
+
+/// The value `sealed` was made from, checked against its hash, or `sealed` itself if it has no
+/// header
+pub(super) fn open(key: &str, sealed: bytes::Bytes) -> Result<bytes::Bytes, StoreError> {
+    if !sealed.starts_with(MAGIC.as_bytes()) {
+        return Ok(sealed);
+    }
+    let corrupted = |reason: String| {
+        INTEGRITY_FAILURES.inc();
+        tracing::error!(key, %reason, "Corrupted value in store");
+        StoreError::Corrupted {
+            key: key.to_string(),
+            reason,
+        }
+    };
+    let Some(expected) = sealed
+        .get(MAGIC.len()..HEADER_LEN - 1)
+        .and_then(|hex| std::str::from_utf8(hex).ok())
+        .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+        .filter(|_| sealed.get(HEADER_LEN - 1) == Some(&b'\n'))
+    else {
+        return Err(corrupted(format!(
+            "malformed hash header in {} bytes",
+            sealed.len()
+        )));
+    };
+    let value = sealed.slice(HEADER_LEN..);
+    let actual = xxhash_rust::xxh3::xxh3_64(&value);
+    if actual != expected {
+        return Err(corrupted(format!(
+            "{} bytes hash to {actual:016x}, expected {expected:016x}",
+            value.len()
+        )));
+    }
+    Ok(value)
+}
+
+/// [`open`] each of `entries`
+pub(super) fn open_entries(
+    entries: HashMap<String, bytes::Bytes>,
+) -> Result<HashMap<String, bytes::Bytes>, StoreError> {
+    entries
+        .into_iter()
+        .map(|(key, value)| {
+            let value = open(&key, value)?;
+            Ok((key, value))
+        })
+        .collect()
+}
+
+/// [`open`] the value of a change from a watch. A corrupted put is dropped, as failing would
+/// end the watch.
+pub(super) fn open_event(event: WatchEvent) -> Option<WatchEvent> {
+    match event {
+        WatchEvent::Put(mut kv) => {
+            kv.value = open(&kv.key, kv.value).ok()?;
+            Some(WatchEvent::Put(kv))
+        }
+        WatchEvent::Delete(mut kv) => {
+            kv.value = open(&kv.key, kv.value).ok()?;
+            Some(WatchEvent::Delete(kv))
+        }
+        event => Some(event),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{KeyValueStoreManager, MemoryStore};
+    use super::*;
+
+    #[test]
+    fn test_open() {
+        let sealed = bytes::Bytes::from(seal(r#"{"model": "llama"}"#));
+        assert_eq!(sealed.len(), HEADER_LEN + 18);
+        assert_eq!(
+            open("key", sealed.clone()).unwrap(),
+            r#"{"model": "llama"}"#
+        );
+        // Written without a hash
+        assert_eq!(open("key", "plain".into()).unwrap(), "plain");
+
+        let failures = integrity_failures().get();
+        let truncated = sealed.slice(..sealed.len() - 2);
+        let err = open("key", truncated).unwrap_err();
+        assert!(
+            matches!(err, StoreError::Corrupted { ref key, .. } if key == "key"),
+            "{err}"
+        );
+        let err = open("key", sealed.slice(..10)).unwrap_err();
+        assert!(err.to_string().contains("malformed hash header"), "{err}");
+        // Other tests read values too, so only a lower bound
+        assert!(integrity_failures().get() >= failures + 2);
+    }
+
+    #[tokio::test]
+    async fn test_hashed_values_are_checked() -> anyhow::Result<()> {
+        let store = MemoryStore::new();
+        let hashing = KeyValueStoreManager::shared_memory(store.clone()).with_content_hashes();
+        let plain = KeyValueStoreManager::shared_memory(store);
+        let hashed_bucket = hashing.get_or_create_bucket("integrity", None).await?;
+        let plain_bucket = plain.get_or_create_bucket("integrity", None).await?;
+
+        hashed_bucket
+            .insert(&"card".into(), r#"{"ok": true}"#, 0)
+            .await?;
+        // Both read the value, not the header
+        assert_eq!(
+            hashed_bucket.get(&"card".into()).await?.unwrap(),
+            r#"{"ok": true}"#
+        );
+        assert_eq!(
+            plain_bucket.get(&"card".into()).await?.unwrap(),
+            r#"{"ok": true}"#
+        );
+
+        // Cut short by a storage incident
+        let sealed = seal(r#"{"ok": true}"#);
+        plain_bucket
+            .update_existing(&"card".into(), &sealed[..sealed.len() - 3])
+            .await?;
+        let err = hashing
+            .load::<serde_json::Value>("integrity", &"card".into())
+            .await;
+        assert!(matches!(err, Err(StoreError::Corrupted { .. })), "{err:?}");
+        Ok(())
+    }
+}
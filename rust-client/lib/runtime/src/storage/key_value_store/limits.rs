@@ -0,0 +1,157 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! How large keys and values a store takes.
+//!
+//! A backend refuses an oversized write with an error of its own, if at all, and only once it
+//! has been sent, typically somewhere down a publish. Writes through
+//! [`KeyValueStoreManager`](super::KeyValueStoreManager) are checked against its
+//! [`SizeLimits`] first and fail with [`StoreError::KeyTooLong`] or
+//! [`StoreError::ValueTooLarge`] instead.
+
+use super::{Key, KeyValueStoreEnum, StoreError};
+
+/// In bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeLimits {
+    pub max_key_len: usize,
+    pub max_value_size: usize,
+}
+
+impl SizeLimits {
+    pub const UNLIMITED: SizeLimits = SizeLimits {
+        max_key_len: usize::MAX,
+        max_value_size: usize::MAX,
+    };
+
+    /// etcd's default `--max-request-bytes`. Key and value of a put have to fit in it together.
+    pub const ETCD: SizeLimits = SizeLimits {
+        max_key_len: 1536 * 1024,
+        max_value_size: 1536 * 1024,
+    };
+
+    /// The NATS server's default `max_payload`, and its `max_control_line`, which the subject
+    /// naming the key has to fit in
+    pub const NATS: SizeLimits = SizeLimits {
+        max_key_len: 4096,
+        max_value_size: 1024 * 1024,
+    };
+
+    /// Consul's default `kv_max_value_size`. Keys are only limited by the request size.
+    pub const CONSUL: SizeLimits = SizeLimits {
+        max_key_len: 512 * 1024,
+        max_value_size: 512 * 1024,
+    };
+
+    /// Redis' `proto-max-bulk-len`, the longest string a command may carry
+    pub const REDIS: SizeLimits = SizeLimits {
+        max_key_len: 512 * 1024 * 1024,
+        max_value_size: 512 * 1024 * 1024,
+    };
+
+    /// The limits of the backend's default configuration
+    pub(super) fn of(store: &KeyValueStoreEnum) -> Self {
+        match store {
+            KeyValueStoreEnum::Memory(_) => SizeLimits::UNLIMITED,
+            KeyValueStoreEnum::Nats(_) => SizeLimits::NATS,
+            KeyValueStoreEnum::Etcd(_) => SizeLimits::ETCD,
+            KeyValueStoreEnum::Consul(_) => SizeLimits::CONSUL,
+            KeyValueStoreEnum::Redis(_) => SizeLimits::REDIS,
+        }
+    }
+
+    pub fn check(&self, key: &Key, value: &str) -> Result<(), StoreError> {
+        let key = key.as_ref();
+        if key.len() > self.max_key_len {
+            return Err(StoreError::KeyTooLong {
+                len: key.len(),
+                limit: self.max_key_len,
+            });
+        }
+        if value.len() > self.max_value_size {
+            return Err(StoreError::ValueTooLarge {
+                key: key.to_string(),
+                size: value.len(),
+                limit: self.max_value_size,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Default for SizeLimits {
+    fn default() -> Self {
+        SizeLimits::UNLIMITED
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{KeyValueStoreManager, Versioned};
+    use super::*;
+
+    #[derive(serde::Serialize)]
+    struct Card {
+        blob: String,
+    }
+
+    impl Versioned for Card {
+        fn revision(&self) -> u64 {
+            0
+        }
+
+        fn set_revision(&mut self, _revision: u64) {}
+    }
+
+    #[tokio::test]
+    async fn test_oversized_writes_are_refused() -> anyhow::Result<()> {
+        let limits = SizeLimits {
+            max_key_len: 8,
+            max_value_size: 16,
+        };
+        let manager = KeyValueStoreManager::memory().with_size_limits(limits);
+        let bucket = manager.get_or_create_bucket("limits", None).await?;
+
+        bucket.insert(&"key".into(), "small", 0).await?;
+        let err = bucket
+            .insert(&"key".into(), &"x".repeat(17), 0)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, StoreError::ValueTooLarge { limit: 16, .. }),
+            "{err}"
+        );
+        assert_eq!(
+            err.to_string(),
+            "Value of 17 bytes for key 'key' is larger than the store allows, 16 bytes"
+        );
+        let long_key = Key::from_raw("123456789".to_string());
+        let err = bucket.insert_new(&long_key, "small").await.unwrap_err();
+        assert!(
+            matches!(err, StoreError::KeyTooLong { len: 9, limit: 8 }),
+            "{err}"
+        );
+        // Nothing was written
+        assert_eq!(bucket.entries().await?.len(), 1);
+
+        let mut card = Card {
+            blob: "x".repeat(16),
+        };
+        let err = manager
+            .publish(
+                "limits",
+                None,
+                &"card".into(),
+                &mut card,
+                Default::default(),
+            )
+            .await
+            .unwrap_err();
+        let err = err.downcast_ref::<StoreError>().unwrap();
+        assert!(
+            matches!(err, StoreError::ValueTooLarge { limit: 16, .. }),
+            "{err}"
+        );
+        Ok(())
+    }
+}
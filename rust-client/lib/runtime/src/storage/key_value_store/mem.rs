@@ -1,24 +1,32 @@
 // SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::collections::HashMap;
 use std::collections::hash_map::Entry;
-use std::collections::{HashMap, HashSet};
 use std::pin::Pin;
 use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
 
 use async_trait::async_trait;
 use rand::Rng as _;
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::sync::mpsc::UnboundedSender;
 
 use crate::storage::key_value_store::{Key, KeyValue, WatchEvent};
 
-use super::{KeyValueBucket, KeyValueStore, StoreError, StoreOutcome};
+use super::{Conditional, KeyValueBucket, KeyValueStore, StoreError, StoreOutcome};
 
 #[derive(Clone, Debug)]
 enum MemoryEvent {
-    Put { key: String, value: String },
-    Delete { key: String },
+    Put {
+        key: String,
+        value: String,
+        sequence: u64,
+    },
+    Delete {
+        key: String,
+        sequence: u64,
+    },
 }
 
 #[derive(Clone)]
@@ -33,10 +41,35 @@ impl Default for MemoryStore {
     }
 }
 
+/// The changes sent to a watch, each with the time it was committed
+type ChangeSender = UnboundedSender<(SystemTime, MemoryEvent)>;
+
 struct MemoryStoreInner {
     data: parking_lot::Mutex<HashMap<String, MemoryBucket>>,
-    change_sender: UnboundedSender<MemoryEvent>,
-    change_receiver: tokio::sync::Mutex<UnboundedReceiver<MemoryEvent>>,
+    /// One sender per active watch, with the bucket it watches
+    watchers: parking_lot::Mutex<Vec<(String, ChangeSender)>>,
+    /// Last sequence number handed out, shared by all buckets
+    sequence: AtomicU64,
+    /// See [`MemoryStore::refuse_watches`]
+    refuse_watches: AtomicBool,
+}
+
+impl MemoryStoreInner {
+    fn next_sequence(&self) -> u64 {
+        self.sequence.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Send `event` to every watch on `bucket_name`, forgetting watches that have ended.
+    /// Called with the data lock held, so watchers see events in sequence order.
+    fn notify(&self, bucket_name: &str, event: MemoryEvent) {
+        let committed_at = SystemTime::now();
+        self.watchers.lock().retain(|(bucket, tx)| {
+            if bucket != bucket_name {
+                return !tx.is_closed();
+            }
+            tx.send((committed_at, event.clone())).is_ok()
+        });
+    }
 }
 
 pub struct MemoryBucketRef {
@@ -44,8 +77,29 @@ pub struct MemoryBucketRef {
     inner: Arc<MemoryStoreInner>,
 }
 
+impl MemoryBucketRef {
+    /// Store `value` and tell the watchers, returning its sequence. Called with the data lock
+    /// held, like `notify`.
+    fn put_locked(&self, bucket: &mut MemoryBucket, key: &Key, value: &str, revision: u64) -> u64 {
+        let sequence = self.inner.next_sequence();
+        bucket
+            .data
+            .insert(key.to_string(), (revision, value.to_string(), sequence));
+        self.inner.notify(
+            &self.name,
+            MemoryEvent::Put {
+                key: key.to_string(),
+                value: value.to_string(),
+                sequence,
+            },
+        );
+        sequence
+    }
+}
+
 struct MemoryBucket {
-    data: HashMap<String, (u64, String)>,
+    /// key -> (revision, value, sequence)
+    data: HashMap<String, (u64, String, u64)>,
 }
 
 impl MemoryBucket {
@@ -58,16 +112,28 @@ impl MemoryBucket {
 
 impl MemoryStore {
     pub fn new() -> Self {
-        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
         MemoryStore {
             inner: Arc::new(MemoryStoreInner {
                 data: parking_lot::Mutex::new(HashMap::new()),
-                change_sender: tx,
-                change_receiver: tokio::sync::Mutex::new(rx),
+                watchers: parking_lot::Mutex::new(Vec::new()),
+                sequence: AtomicU64::new(0),
+                refuse_watches: AtomicBool::new(false),
             }),
             connection_id: rand::rng().random(),
         }
     }
+
+    /// End every watch on the store, as a lost connection would
+    pub fn drop_watches(&self) {
+        self.inner.watchers.lock().clear();
+    }
+
+    /// End every watch on the store, and fail new ones in a way they don't recover from, as a
+    /// store that turned the client away would
+    pub fn refuse_watches(&self) {
+        self.inner.refuse_watches.store(true, Ordering::Relaxed);
+        self.drop_watches();
+    }
 }
 
 #[async_trait]
@@ -124,19 +190,34 @@ impl KeyValueBucket for MemoryBucketRef {
         };
         let outcome = match bucket.data.entry(key.to_string()) {
             Entry::Vacant(e) => {
-                e.insert((revision, value.to_string()));
-                let _ = self.inner.change_sender.send(MemoryEvent::Put {
-                    key: key.to_string(),
-                    value: value.to_string(),
-                });
+                // Taken under the data lock, so events are sent in sequence order
+                let sequence = self.inner.next_sequence();
+                e.insert((revision, value.to_string(), sequence));
+                self.inner.notify(
+                    &self.name,
+                    MemoryEvent::Put {
+                        key: key.to_string(),
+                        value: value.to_string(),
+                        sequence,
+                    },
+                );
                 StoreOutcome::Created(revision)
             }
             Entry::Occupied(mut entry) => {
-                let (rev, _v) = entry.get();
+                let (rev, _v, _seq) = entry.get();
                 if *rev == revision {
                     StoreOutcome::Exists(revision)
                 } else {
-                    entry.insert((revision, value.to_string()));
+                    let sequence = self.inner.next_sequence();
+                    entry.insert((revision, value.to_string(), sequence));
+                    self.inner.notify(
+                        &self.name,
+                        MemoryEvent::Put {
+                            key: key.to_string(),
+                            value: value.to_string(),
+                            sequence,
+                        },
+                    );
                     StoreOutcome::Created(revision)
                 }
             }
@@ -144,6 +225,65 @@ impl KeyValueBucket for MemoryBucketRef {
         Ok(outcome)
     }
 
+    /// Unlike `insert`, leaves a key at another revision alone
+    async fn insert_new(&self, key: &Key, value: &str) -> Result<StoreOutcome, StoreError> {
+        let mut locked_data = self.inner.data.lock();
+        let Some(bucket) = locked_data.get_mut(&self.name) else {
+            return Err(StoreError::MissingBucket(self.name.to_string()));
+        };
+        if let Some((revision, _, _)) = bucket.data.get(key.as_ref()) {
+            return Ok(StoreOutcome::Exists(*revision));
+        }
+        self.put_locked(bucket, key, value, 0);
+        Ok(StoreOutcome::Created(0))
+    }
+
+    /// Returns the new sequence, like `compare_and_swap`
+    async fn update_existing(&self, key: &Key, value: &str) -> Result<StoreOutcome, StoreError> {
+        let mut locked_data = self.inner.data.lock();
+        let Some(bucket) = locked_data.get_mut(&self.name) else {
+            return Err(StoreError::MissingBucket(self.name.to_string()));
+        };
+        let Some((revision, _, _)) = bucket.data.get(key.as_ref()) else {
+            return Err(StoreError::MissingKey(key.to_string()));
+        };
+        let sequence = self.put_locked(bucket, key, value, revision + 1);
+        Ok(StoreOutcome::Created(sequence))
+    }
+
+    async fn upsert(&self, key: &Key, value: &str) -> Result<StoreOutcome, StoreError> {
+        let mut locked_data = self.inner.data.lock();
+        let Some(bucket) = locked_data.get_mut(&self.name) else {
+            return Err(StoreError::MissingBucket(self.name.to_string()));
+        };
+        let next = bucket
+            .data
+            .get(key.as_ref())
+            .map_or(0, |(rev, _, _)| rev + 1);
+        let sequence = self.put_locked(bucket, key, value, next);
+        Ok(StoreOutcome::Created(sequence))
+    }
+
+    /// Revisions here are sequences, as from `get_if_changed`
+    async fn compare_and_swap(
+        &self,
+        key: &Key,
+        value: &str,
+        revision: u64,
+    ) -> Result<StoreOutcome, StoreError> {
+        let mut locked_data = self.inner.data.lock();
+        let Some(bucket) = locked_data.get_mut(&self.name) else {
+            return Err(StoreError::MissingBucket(self.name.to_string()));
+        };
+        let next = match bucket.data.get(key.as_ref()) {
+            None if revision == 0 => 0,
+            Some((rev, _, sequence)) if *sequence == revision => rev + 1,
+            _ => return Err(StoreError::Retry),
+        };
+        let sequence = self.put_locked(bucket, key, value, next);
+        Ok(StoreOutcome::Created(sequence))
+    }
+
     async fn get(&self, key: &Key) -> Result<Option<bytes::Bytes>, StoreError> {
         let locked_data = self.inner.data.lock();
         let Some(bucket) = locked_data.get(&self.name) else {
@@ -152,7 +292,45 @@ impl KeyValueBucket for MemoryBucketRef {
         Ok(bucket
             .data
             .get(&key.0)
-            .map(|(_, v)| bytes::Bytes::from(v.clone())))
+            .map(|(_, v, _)| bytes::Bytes::from(v.clone())))
+    }
+
+    async fn get_many(&self, keys: &[Key]) -> Result<Vec<Option<bytes::Bytes>>, StoreError> {
+        let locked_data = self.inner.data.lock();
+        let Some(bucket) = locked_data.get(&self.name) else {
+            return Ok(vec![None; keys.len()]);
+        };
+        Ok(keys
+            .iter()
+            .map(|key| {
+                bucket
+                    .data
+                    .get(&key.0)
+                    .map(|(_, v, _)| bytes::Bytes::from(v.clone()))
+            })
+            .collect())
+    }
+
+    /// The change sequence number is the revision
+    async fn get_if_changed(
+        &self,
+        key: &Key,
+        known_revision: u64,
+    ) -> Result<Conditional, StoreError> {
+        let locked_data = self.inner.data.lock();
+        let Some((_, value, sequence)) = locked_data
+            .get(&self.name)
+            .and_then(|bucket| bucket.data.get(&key.0))
+        else {
+            return Ok(Conditional::Missing);
+        };
+        if *sequence == known_revision {
+            return Ok(Conditional::NotModified);
+        }
+        Ok(Conditional::Modified {
+            value: bytes::Bytes::from(value.clone()),
+            revision: *sequence,
+        })
     }
 
     async fn delete(&self, key: &Key) -> Result<(), StoreError> {
@@ -161,54 +339,70 @@ impl KeyValueBucket for MemoryBucketRef {
             return Err(StoreError::MissingBucket(self.name.to_string()));
         };
         if bucket.data.remove(&key.0).is_some() {
-            let _ = self.inner.change_sender.send(MemoryEvent::Delete {
-                key: key.to_string(),
-            });
+            let sequence = self.inner.next_sequence();
+            self.inner.notify(
+                &self.name,
+                MemoryEvent::Delete {
+                    key: key.to_string(),
+                    sequence,
+                },
+            );
         }
         Ok(())
     }
 
     /// All current values in the bucket first, then block waiting for new
-    /// values to be published.
-    /// Caller takes the lock so only a single caller may use this at once.
+    /// values to be published. Any number of watches can run at once.
     async fn watch(
         &self,
     ) -> Result<Pin<Box<dyn futures::Stream<Item = WatchEvent> + Send + 'life0>>, StoreError> {
+        if self.inner.refuse_watches.load(Ordering::Relaxed) {
+            return Err(StoreError::KeyValueError {
+                message: "watches refused".to_string(),
+                bucket: self.name.clone(),
+                source: None,
+                op_id: None,
+            });
+        }
         // All the existing ones first
         let mut existing_items = vec![];
-        let mut seen_keys = HashSet::new();
         let data_lock = self.inner.data.lock();
         let Some(bucket) = data_lock.get(&self.name) else {
             return Err(StoreError::MissingBucket(self.name.to_string()));
         };
-        for (key, (_rev, v)) in &bucket.data {
-            seen_keys.insert(key.clone());
-            let item = KeyValue::new(key.clone(), bytes::Bytes::from(v.clone().into_bytes()));
+        for (key, (_rev, v, sequence)) in &bucket.data {
+            let item = KeyValue::new(key.clone(), bytes::Bytes::from(v.clone().into_bytes()))
+                .with_sequence(*sequence);
             existing_items.push(WatchEvent::Put(item));
         }
+        // Subscribed under the same lock as the snapshot, so every later change is seen once
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        self.inner.watchers.lock().push((self.name.clone(), tx));
         drop(data_lock);
+        existing_items.sort_by_key(|event| event.sequence());
 
         Ok(Box::pin(async_stream::stream! {
             for event in existing_items {
                 yield event;
             }
+            yield WatchEvent::InitialSyncComplete;
             // Now any new ones
-            let mut rcv_lock = self.inner.change_receiver.lock().await;
             loop {
-                match rcv_lock.recv().await {
+                match rx.recv().await {
                     None => {
                         // Channel is closed, no more values coming
                         break;
                     },
-                    Some(MemoryEvent::Put { key, value }) => {
-                        if seen_keys.contains(&key) {
-                            continue;
-                        }
-                        let item = KeyValue::new(key, bytes::Bytes::from(value));
+                    Some((committed_at, MemoryEvent::Put { key, value, sequence })) => {
+                        let item = KeyValue::new(key, bytes::Bytes::from(value))
+                            .with_sequence(sequence)
+                            .with_committed_at(committed_at);
                         yield WatchEvent::Put(item);
                     },
-                    Some(MemoryEvent::Delete { key }) => {
-                        let item = KeyValue::new(key, bytes::Bytes::new());
+                    Some((committed_at, MemoryEvent::Delete { key, sequence })) => {
+                        let item = KeyValue::new(key, bytes::Bytes::new())
+                            .with_sequence(sequence)
+                            .with_committed_at(committed_at);
                         yield WatchEvent::Delete(item);
                     }
                 }
@@ -222,7 +416,7 @@ impl KeyValueBucket for MemoryBucketRef {
             Some(bucket) => Ok(bucket
                 .data
                 .iter()
-                .map(|(k, (_rev, v))| (k.to_string(), bytes::Bytes::from(v.clone())))
+                .map(|(k, (_rev, v, _seq))| (k.to_string(), bytes::Bytes::from(v.clone())))
                 .collect()),
             None => Err(StoreError::MissingBucket(self.name.clone())),
         }
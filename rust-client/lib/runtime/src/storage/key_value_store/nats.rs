@@ -13,7 +13,7 @@ use async_nats::jetstream::kv::Operation;
 use async_trait::async_trait;
 use futures::StreamExt;
 
-use super::{KeyValueBucket, KeyValueStore, StoreError, StoreOutcome};
+use super::{Conditional, KeyValueBucket, KeyValueStore, StoreError, StoreOutcome};
 
 #[derive(Clone)]
 pub struct NATSStore {
@@ -88,8 +88,7 @@ impl NATSStore {
                 },
             )
             .await;
-        let nats_store = create_result
-            .map_err(|err| StoreError::KeyValueError(err.to_string(), bucket_name.clone()))?;
+        let nats_store = create_result.map_err(|err| StoreError::key_value(err, &bucket_name))?;
         tracing::debug!("Created bucket {bucket_name}");
         Ok(nats_store)
     }
@@ -109,7 +108,7 @@ impl NATSStore {
                 // bucket doesn't exist
                 Ok(None)
             }
-            Err(err) => Err(StoreError::KeyValueError(err.to_string(), bucket_name)),
+            Err(err) => Err(StoreError::key_value(err, bucket_name)),
         }
     }
 }
@@ -130,17 +129,39 @@ impl KeyValueBucket for NATSBucket {
     }
 
     async fn get(&self, key: &Key) -> Result<Option<bytes::Bytes>, StoreError> {
-        self.nats_store
-            .get(key)
-            .await
-            .map_err(|e| StoreError::NATSError(e.to_string()))
+        self.nats_store.get(key).await.map_err(StoreError::nats)
+    }
+
+    /// JetStream has no multi-get, so the gets are sent concurrently instead
+    async fn get_many(&self, keys: &[Key]) -> Result<Vec<Option<bytes::Bytes>>, StoreError> {
+        futures::future::try_join_all(keys.iter().map(|key| self.get(key))).await
+    }
+
+    /// NATS still downloads the entry, but the revision is exact rather than a content hash
+    async fn get_if_changed(
+        &self,
+        key: &Key,
+        known_revision: u64,
+    ) -> Result<Conditional, StoreError> {
+        let entry = self.nats_store.entry(key).await.map_err(StoreError::nats)?;
+        match entry {
+            Some(entry) if matches!(entry.operation, Operation::Put) => {
+                if entry.revision == known_revision {
+                    Ok(Conditional::NotModified)
+                } else {
+                    Ok(Conditional::Modified {
+                        value: entry.value,
+                        revision: entry.revision,
+                    })
+                }
+            }
+            // Deleted and purged keys keep a tombstone entry
+            _ => Ok(Conditional::Missing),
+        }
     }
 
     async fn delete(&self, key: &Key) -> Result<(), StoreError> {
-        self.nats_store
-            .delete(key)
-            .await
-            .map_err(|e| StoreError::NATSError(e.to_string()))
+        self.nats_store.delete(key).await.map_err(StoreError::nats)
     }
 
     async fn watch(
@@ -150,40 +171,28 @@ impl KeyValueBucket for NATSBucket {
             .nats_store
             .watch_all()
             .await
-            .map_err(|e| StoreError::NATSError(e.to_string()))?;
-        // Map the `Entry` to `Entry.value` which is Bytes of the stored value.
-        Ok(Box::pin(
-            watch_stream.filter_map(
-                |maybe_entry: Result<
-                    async_nats::jetstream::kv::Entry,
-                    async_nats::error::Error<_>,
-                >| async move {
-                    match maybe_entry {
-                        Ok(entry) => {
-                            let item = KeyValue::new(entry.key, entry.value);
-                            Some(match entry.operation {
-                                Operation::Put => WatchEvent::Put(item),
-                                Operation::Delete => WatchEvent::Delete(item),
-                                // TODO: What is Purge? Not urgent, NATS impl not used
-                                Operation::Purge => WatchEvent::Delete(item),
-                            })
-                        }
-                        Err(e) => {
-                            tracing::error!(error=%e, "watch fatal err");
-                            None
-                        }
-                    }
-                },
-            ),
-        ))
+            .map_err(StoreError::nats)?;
+        Ok(watch_events(watch_stream))
     }
 
-    async fn entries(&self) -> Result<HashMap<String, bytes::Bytes>, StoreError> {
-        let mut key_stream = self
+    /// By subject filter when `prefix` ends with a whole token, i.e. a `.`
+    async fn watch_prefix(
+        &self,
+        prefix: &str,
+    ) -> Result<Pin<Box<dyn futures::Stream<Item = WatchEvent> + Send + 'life0>>, StoreError> {
+        if !prefix.ends_with('.') {
+            return self.watch().await;
+        }
+        let watch_stream = self
             .nats_store
-            .keys()
+            .watch(format!("{prefix}>"))
             .await
-            .map_err(|e| StoreError::NATSError(e.to_string()))?;
+            .map_err(StoreError::nats)?;
+        Ok(watch_events(watch_stream))
+    }
+
+    async fn entries(&self) -> Result<HashMap<String, bytes::Bytes>, StoreError> {
+        let mut key_stream = self.nats_store.keys().await.map_err(StoreError::nats)?;
         let mut out = HashMap::new();
         while let Some(Ok(key)) = key_stream.next().await {
             if let Ok(Some(entry)) = self.nats_store.entry(&key).await {
@@ -194,6 +203,35 @@ impl KeyValueBucket for NATSBucket {
     }
 }
 
+/// The changes from a NATS watch, as [`WatchEvent`]s
+fn watch_events(
+    watch_stream: async_nats::jetstream::kv::Watch,
+) -> Pin<Box<dyn futures::Stream<Item = WatchEvent> + Send>> {
+    // Map the `Entry` to `Entry.value` which is Bytes of the stored value.
+    let changes = watch_stream.filter_map(|maybe_entry| async move {
+        match maybe_entry {
+            Ok(entry) => {
+                let item = KeyValue::new(entry.key, entry.value)
+                    .with_sequence(entry.revision)
+                    .with_committed_at(entry.created.into());
+                Some(match entry.operation {
+                    Operation::Put => WatchEvent::Put(item),
+                    Operation::Delete => WatchEvent::Delete(item),
+                    // TODO: What is Purge? Not urgent, NATS impl not used
+                    Operation::Purge => WatchEvent::Delete(item),
+                })
+            }
+            Err(e) => {
+                tracing::error!(error=%e, "watch fatal err");
+                None
+            }
+        }
+    });
+    // NATS watches only send changes, so there is nothing to sync first
+    let synced = futures::stream::iter([WatchEvent::InitialSyncComplete]);
+    Box::pin(synced.chain(changes))
+}
+
 impl NATSBucket {
     async fn create(&self, key: &Key, value: &str) -> Result<StoreOutcome, StoreError> {
         match self.nats_store.create(&key, value.to_string().into()).await {
@@ -209,10 +247,10 @@ impl NATSBucket {
                         );
                         Err(StoreError::Retry)
                     }
-                    Err(err) => Err(StoreError::NATSError(err.to_string())),
+                    Err(err) => Err(StoreError::nats(err)),
                 }
             }
-            Err(err) => Err(StoreError::NATSError(err.to_string())),
+            Err(err) => Err(StoreError::nats(err)),
         }
     }
 
@@ -234,7 +272,7 @@ impl NATSBucket {
                 tracing::warn!(revision, %key, "Update WrongLastRevision, resync");
                 self.resync_update(key, value).await
             }
-            Err(err) => Err(StoreError::NATSError(err.to_string())),
+            Err(err) => Err(StoreError::nats(err)),
         }
     }
 
@@ -251,9 +289,11 @@ impl NATSBucket {
                     .await
                 {
                     Ok(correct_revision) => Ok(StoreOutcome::Created(correct_revision)),
-                    Err(err) => Err(StoreError::NATSError(format!(
-                        "Error during update of key {key} after resync: {err}"
-                    ))),
+                    Err(err) => Err(StoreError::NATSError {
+                        message: format!("Error during update of key {key} after resync: {err}"),
+                        source: Some(Box::new(err)),
+                        op_id: None,
+                    }),
                 }
             }
             Ok(None) => {
@@ -262,7 +302,7 @@ impl NATSBucket {
             }
             Err(err) => {
                 tracing::error!(%key, %err, "Failed fetching entry during resync");
-                Err(StoreError::NATSError(err.to_string()))
+                Err(StoreError::nats(err))
             }
         }
     }
@@ -0,0 +1,293 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Operation ids tying a [`StoreError`] to the backend interaction that caused it.
+//!
+//! Every call on a bucket from [`KeyValueStoreManager`](super::KeyValueStoreManager) gets an
+//! [`OpId`]. The call runs in a `store_op` span carrying it, so what the backend logs can be
+//! found by it, and a backend error returned from it names it in its message. A call slower than
+//! the store threshold of [`crate::logging::slow_ops`] is logged with it.
+//!
+//! The same wrapper around the bucket applies the manager's other [`BucketOptions`].
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use tracing::Instrument;
+
+use crate::logging::slow_ops::{SlowOpKind, watch_slow};
+
+use super::{
+    BucketOptions, Conditional, Fence, Key, KeyValueBucket, ReadConsistency, StoreError,
+    StoreOutcome, WatchEvent, delta, integrity,
+};
+
+/// Names one store call, in its tracing span and its errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OpId(u64);
+
+impl OpId {
+    pub fn new() -> Self {
+        OpId(rand::random())
+    }
+}
+
+impl Default for OpId {
+    fn default() -> Self {
+        OpId::new()
+    }
+}
+
+impl fmt::Display for OpId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// Run `call`, operation `op` on `bucket`, under a new [`OpId`]
+pub(super) async fn traced<T>(
+    op: &'static str,
+    bucket: &str,
+    call: impl Future<Output = Result<T, StoreError>>,
+) -> Result<T, StoreError> {
+    let op_id = OpId::new();
+    let span = tracing::debug_span!("store_op", op, bucket, %op_id);
+    let args = || format!("bucket={bucket}, op_id={op_id}");
+    watch_slow(SlowOpKind::Store, op, args, call.instrument(span))
+        .await
+        .map_err(|err| err.with_op_id(op_id))
+}
+
+/// A cached bucket, each call on it [traced], what it writes sealed and checked against the
+/// size limits, and what it reads opened, as the [`BucketOptions`] say
+pub(super) struct TracedBucket {
+    name: String,
+    inner: Arc<dyn KeyValueBucket>,
+    options: BucketOptions,
+}
+
+impl TracedBucket {
+    pub(super) fn new(name: &str, inner: Arc<dyn KeyValueBucket>, options: BucketOptions) -> Self {
+        TracedBucket {
+            name: name.to_string(),
+            inner,
+            options,
+        }
+    }
+
+    /// `value` as it is to be written. Oversized ones fail before they are sent.
+    fn prepare<'a>(&self, key: &Key, value: &'a str) -> Result<Cow<'a, str>, StoreError> {
+        let value = match self.options.content_hashes {
+            true => Cow::Owned(integrity::seal(value)),
+            false => Cow::Borrowed(value),
+        };
+        self.options.limits.check(key, &value)?;
+        Ok(value)
+    }
+}
+
+/// `value` checked against its hash and without its delta header, if it has them
+fn open_value(key: &str, value: bytes::Bytes) -> Result<bytes::Bytes, StoreError> {
+    delta::strip(key, integrity::open(key, value)?)
+}
+
+fn open(key: &Key, value: Option<bytes::Bytes>) -> Result<Option<bytes::Bytes>, StoreError> {
+    value
+        .map(|value| open_value(key.as_ref(), value))
+        .transpose()
+}
+
+fn open_events<'a>(
+    stream: Pin<Box<dyn futures::Stream<Item = WatchEvent> + Send + 'a>>,
+) -> Pin<Box<dyn futures::Stream<Item = WatchEvent> + Send + 'a>> {
+    Box::pin(stream.filter_map(|event| {
+        std::future::ready(integrity::open_event(event).and_then(delta::open_event))
+    }))
+}
+
+#[async_trait]
+impl KeyValueBucket for TracedBucket {
+    async fn insert(
+        &self,
+        key: &Key,
+        value: &str,
+        revision: u64,
+    ) -> Result<StoreOutcome, StoreError> {
+        let value = self.prepare(key, value)?;
+        let call = self.inner.insert(key, &value, revision);
+        traced("insert", &self.name, call).await
+    }
+
+    async fn insert_new(&self, key: &Key, value: &str) -> Result<StoreOutcome, StoreError> {
+        let value = self.prepare(key, value)?;
+        traced("insert_new", &self.name, self.inner.insert_new(key, &value)).await
+    }
+
+    async fn update_existing(&self, key: &Key, value: &str) -> Result<StoreOutcome, StoreError> {
+        let value = self.prepare(key, value)?;
+        let call = self.inner.update_existing(key, &value);
+        traced("update_existing", &self.name, call).await
+    }
+
+    async fn upsert(&self, key: &Key, value: &str) -> Result<StoreOutcome, StoreError> {
+        let value = self.prepare(key, value)?;
+        traced("upsert", &self.name, self.inner.upsert(key, &value)).await
+    }
+
+    async fn compare_and_swap(
+        &self,
+        key: &Key,
+        value: &str,
+        revision: u64,
+    ) -> Result<StoreOutcome, StoreError> {
+        let value = self.prepare(key, value)?;
+        let call = self.inner.compare_and_swap(key, &value, revision);
+        traced("compare_and_swap", &self.name, call).await
+    }
+
+    async fn insert_fenced(
+        &self,
+        key: &Key,
+        value: &str,
+        fence: Fence,
+    ) -> Result<StoreOutcome, StoreError> {
+        let value = self.prepare(key, value)?;
+        let call = self.inner.insert_fenced(key, &value, fence);
+        traced("insert_fenced", &self.name, call).await
+    }
+
+    async fn get(&self, key: &Key) -> Result<Option<bytes::Bytes>, StoreError> {
+        open(key, traced("get", &self.name, self.inner.get(key)).await?)
+    }
+
+    async fn get_many(&self, keys: &[Key]) -> Result<Vec<Option<bytes::Bytes>>, StoreError> {
+        let values = traced("get_many", &self.name, self.inner.get_many(keys)).await?;
+        keys.iter()
+            .zip(values)
+            .map(|(key, value)| open(key, value))
+            .collect()
+    }
+
+    async fn get_if_changed(
+        &self,
+        key: &Key,
+        known_revision: u64,
+    ) -> Result<Conditional, StoreError> {
+        let call = self.inner.get_if_changed(key, known_revision);
+        match traced("get_if_changed", &self.name, call).await? {
+            Conditional::Modified { value, revision } => Ok(Conditional::Modified {
+                value: open_value(key.as_ref(), value)?,
+                revision,
+            }),
+            unchanged => Ok(unchanged),
+        }
+    }
+
+    async fn get_with_consistency(
+        &self,
+        key: &Key,
+        consistency: ReadConsistency,
+    ) -> Result<Option<bytes::Bytes>, StoreError> {
+        let call = self.inner.get_with_consistency(key, consistency);
+        open(key, traced("get_with_consistency", &self.name, call).await?)
+    }
+
+    async fn delete(&self, key: &Key) -> Result<(), StoreError> {
+        traced("delete", &self.name, self.inner.delete(key)).await
+    }
+
+    /// Only starting the watch is traced, not the events that follow
+    async fn watch(
+        &self,
+    ) -> Result<Pin<Box<dyn futures::Stream<Item = WatchEvent> + Send + 'life0>>, StoreError> {
+        let stream = traced("watch", &self.name, self.inner.watch()).await?;
+        Ok(open_events(stream))
+    }
+
+    async fn watch_prefix(
+        &self,
+        prefix: &str,
+    ) -> Result<Pin<Box<dyn futures::Stream<Item = WatchEvent> + Send + 'life0>>, StoreError> {
+        let stream = traced("watch_prefix", &self.name, self.inner.watch_prefix(prefix)).await?;
+        Ok(open_events(stream))
+    }
+
+    async fn watch_range(
+        &self,
+        start: &str,
+        end: &str,
+    ) -> Result<Pin<Box<dyn futures::Stream<Item = WatchEvent> + Send + 'life0>>, StoreError> {
+        let stream = traced(
+            "watch_range",
+            &self.name,
+            self.inner.watch_range(start, end),
+        )
+        .await?;
+        Ok(open_events(stream))
+    }
+
+    async fn watch_from(
+        &self,
+        sequence: u64,
+    ) -> Result<Option<Pin<Box<dyn futures::Stream<Item = WatchEvent> + Send + 'life0>>>, StoreError>
+    {
+        let stream = traced("watch_from", &self.name, self.inner.watch_from(sequence)).await?;
+        Ok(stream.map(open_events))
+    }
+
+    async fn entries(&self) -> Result<HashMap<String, bytes::Bytes>, StoreError> {
+        integrity::open_entries(traced("entries", &self.name, self.inner.entries()).await?)?
+            .into_iter()
+            .map(|(key, value)| {
+                let value = delta::strip(&key, value)?;
+                Ok((key, value))
+            })
+            .collect()
+    }
+
+    async fn entries_prefix(
+        &self,
+        prefix: &str,
+    ) -> Result<HashMap<String, bytes::Bytes>, StoreError> {
+        let call = self.inner.entries_prefix(prefix);
+        integrity::open_entries(traced("entries_prefix", &self.name, call).await?)?
+            .into_iter()
+            .map(|(key, value)| {
+                let value = delta::strip(&key, value)?;
+                Ok((key, value))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_backend_errors_name_the_op() {
+        let failed = traced("get", "bucket", async {
+            Err::<(), _>(StoreError::provider("connection reset"))
+        })
+        .await
+        .unwrap_err();
+        assert!(failed.op_id().is_some());
+        assert_eq!(
+            failed.to_string(),
+            "Internal storage error: 'connection reset'"
+        );
+
+        // Callers match on these, so they stay as they were
+        let missing = traced("get", "bucket", async {
+            Err::<(), _>(StoreError::MissingKey("key".to_string()))
+        })
+        .await;
+        assert!(matches!(missing, Err(StoreError::MissingKey(key)) if key == "key"));
+    }
+}
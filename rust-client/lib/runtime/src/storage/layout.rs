@@ -0,0 +1,760 @@
+// SPDX-FileCopyrightText: Copyright (c) 2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Versioned key layouts: where discovery records live in the store, and moving them to a new
+//! layout while the cluster keeps running.
+//!
+//! A [`KeyLayout`] says which bucket and key each kind of record goes under: an instance of a
+//! namespace's component's endpoint, and a model card. Which layout is in use is recorded in a
+//! [`LayoutMarker`] at [`LAYOUT_BUCKET`]/[`LAYOUT_MARKER`]; no marker means [`KeyLayout::V1`].
+//! Writers read it when they register, routers when they start watching.
+//!
+//! [`migrate`] moves an etcd cluster to another layout without downtime:
+//!
+//! 1. **Double write.** The marker says to write both layouts, still reading the old one.
+//!    Registrations from then on go to both. After [`MigrateConfig::settle`], for those under
+//!    way to finish, the records only in the old layout are copied, each with the lease it has
+//!    so it goes with its owner.
+//! 2. **Verify.** Both layouts are compared until they hold the same records with the same
+//!    values and leases, repairing what differs in the new one, for at most
+//!    [`MigrateConfig::verify_passes`] passes. Registrations and status changes racing the
+//!    copy show up as differences, and are gone by the next pass.
+//! 3. **Cut over.** The marker says to read the new layout, and still write both, so routers
+//!    started before see every change.
+//!
+//! Once every process started before the cutover has restarted, [`finish_migration`] stops the
+//! writes to the old layout and deletes its records. Before the cutover, [`abort_migration`]
+//! goes back to the old layout alone.
+//!
+//! [`plan_migration`], [`plan_finish`] and [`plan_abort`] list what each would write to the
+//! cluster as it is, writing nothing. The migration writes through the etcd client, with
+//! leases, which a [dry run](KeyValueStoreManager::with_dry_run) of the store can't see.
+//!
+//! Only etcd is migrated this way, as copies have to keep their lease. Other stores stay on
+//! [`KeyLayout::V1`].
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::component::INSTANCE_ROOT_PATH;
+use crate::slug::Slug;
+use crate::transports::etcd::Client as EtcdClient;
+use crate::{Result, error};
+
+use super::key_value_store::{Key, KeyValueStoreManager, StoreError};
+use super::model_card::MODEL_CARD_BUCKET;
+
+/// Holds the [`LayoutMarker`]
+pub const LAYOUT_BUCKET: &str = "v1/layout";
+
+/// The key of the [`LayoutMarker`] in [`LAYOUT_BUCKET`]
+pub const LAYOUT_MARKER: &str = "marker";
+
+/// Between the passes of the verify phase
+const VERIFY_PAUSE: Duration = Duration::from_secs(1);
+
+/// Where discovery records are kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyLayout {
+    /// Instances in [`INSTANCE_ROOT_PATH`] under `{namespace}/{component}/{endpoint}/{id:x}`,
+    /// model cards in [`MODEL_CARD_BUCKET`] under `{model}.{id:x}`
+    V1,
+    /// Everything under one `v2/discovery/` root, so one watch or range covers it. Instances
+    /// are keyed as in V1, model cards under `{model}/{id:x}`, the same shape.
+    V2,
+}
+
+/// A discovery record, by what it is rather than where it is kept
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DiscoveryKey {
+    Instance {
+        namespace: String,
+        component: String,
+        endpoint: String,
+        instance_id: u64,
+    },
+    /// `model` is the slugified model name
+    Card { model: String, instance_id: u64 },
+}
+
+impl KeyLayout {
+    pub fn instance_bucket(self) -> &'static str {
+        match self {
+            KeyLayout::V1 => INSTANCE_ROOT_PATH,
+            KeyLayout::V2 => "v2/discovery/instances",
+        }
+    }
+
+    pub fn card_bucket(self) -> &'static str {
+        match self {
+            KeyLayout::V1 => MODEL_CARD_BUCKET,
+            KeyLayout::V2 => "v2/discovery/cards",
+        }
+    }
+
+    /// Every bucket the layout keeps records in
+    pub fn buckets(self) -> [&'static str; 2] {
+        [self.instance_bucket(), self.card_bucket()]
+    }
+
+    /// The full etcd key of an instance
+    pub fn instance_path(
+        self,
+        namespace: &str,
+        component: &str,
+        endpoint: &str,
+        instance_id: u64,
+    ) -> String {
+        let bucket = self.instance_bucket();
+        format!("{bucket}/{namespace}/{component}/{endpoint}/{instance_id:x}")
+    }
+
+    /// What the etcd keys of an endpoint's instances start with, as
+    /// [`Endpoint::etcd_root`](crate::component::Endpoint::etcd_root) for V1
+    pub fn endpoint_root(self, namespace: &str, component: &str, endpoint: &str) -> String {
+        format!(
+            "{}/{namespace}/{component}/{endpoint}",
+            self.instance_bucket()
+        )
+    }
+
+    /// The key in [`KeyLayout::card_bucket`] of the card `instance_id` publishes for `model`,
+    /// which is slugified
+    pub fn card_key(self, model: &str, instance_id: u64) -> Key {
+        Key::from_raw(format!("{}{instance_id:x}", self.card_prefix(model)))
+    }
+
+    /// What the keys of `model`'s cards start with
+    pub fn card_prefix(self, model: &str) -> String {
+        let model = Slug::slugify(model);
+        match self {
+            KeyLayout::V1 => format!("{model}."),
+            KeyLayout::V2 => format!("{model}/"),
+        }
+    }
+
+    /// The slugified model name and the instance of a card's key, which the store may give
+    /// with the bucket in front
+    pub fn parse_card_key(self, key: &str) -> Option<(&str, u64)> {
+        let key = in_bucket(self.card_bucket(), key);
+        let (model, instance_id) = match self {
+            KeyLayout::V1 => key.rsplit('/').next()?.rsplit_once('.')?,
+            KeyLayout::V2 => key.split_once('/')?,
+        };
+        Some((model, u64::from_str_radix(instance_id, 16).ok()?))
+    }
+
+    /// The full etcd key of `record`
+    pub fn path(self, record: &DiscoveryKey) -> String {
+        match record {
+            DiscoveryKey::Instance {
+                namespace,
+                component,
+                endpoint,
+                instance_id,
+            } => self.instance_path(namespace, component, endpoint, *instance_id),
+            DiscoveryKey::Card { model, instance_id } => {
+                format!(
+                    "{}/{}",
+                    self.card_bucket(),
+                    self.card_key(model, *instance_id)
+                )
+            }
+        }
+    }
+
+    /// The record a full etcd key is of, None if it isn't one in this layout
+    pub fn parse(self, path: &str) -> Option<DiscoveryKey> {
+        if let Some(key) = path
+            .strip_prefix(self.instance_bucket())
+            .and_then(|key| key.strip_prefix('/'))
+        {
+            let mut parts = key.split('/');
+            let (Some(namespace), Some(component), Some(endpoint), Some(id), None) = (
+                parts.next(),
+                parts.next(),
+                parts.next(),
+                parts.next(),
+                parts.next(),
+            ) else {
+                return None;
+            };
+            return Some(DiscoveryKey::Instance {
+                namespace: namespace.to_string(),
+                component: component.to_string(),
+                endpoint: endpoint.to_string(),
+                instance_id: u64::from_str_radix(id, 16).ok()?,
+            });
+        }
+        let key = path
+            .strip_prefix(self.card_bucket())
+            .and_then(|key| key.strip_prefix('/'))?;
+        let (model, instance_id) = self.parse_card_key(key)?;
+        Some(DiscoveryKey::Card {
+            model: model.to_string(),
+            instance_id,
+        })
+    }
+}
+
+impl fmt::Display for KeyLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyLayout::V1 => write!(f, "v1"),
+            KeyLayout::V2 => write!(f, "v2"),
+        }
+    }
+}
+
+impl FromStr for KeyLayout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "v1" => Ok(KeyLayout::V1),
+            "v2" => Ok(KeyLayout::V2),
+            other => Err(error!("Unknown key layout '{other}', expected v1 or v2")),
+        }
+    }
+}
+
+/// Where a migration between layouts has got to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationPhase {
+    /// No migration: one layout is read and written
+    Stable,
+    /// Reading the old layout, writing both
+    DoubleWrite,
+    /// Reading the new layout, writing both
+    CutOver,
+}
+
+/// Which layouts are read and written, see the [module docs](self)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LayoutMarker {
+    /// Read, and written first
+    pub layout: KeyLayout,
+    /// Written as well while migrating: the new layout during [`MigrationPhase::DoubleWrite`],
+    /// the old one after the cutover
+    pub also_write: Option<KeyLayout>,
+    pub phase: MigrationPhase,
+}
+
+impl Default for LayoutMarker {
+    fn default() -> Self {
+        LayoutMarker::stable(KeyLayout::V1)
+    }
+}
+
+impl LayoutMarker {
+    pub fn stable(layout: KeyLayout) -> Self {
+        LayoutMarker {
+            layout,
+            also_write: None,
+            phase: MigrationPhase::Stable,
+        }
+    }
+
+    /// The layouts writers write, the one read first
+    pub fn writes(&self) -> impl Iterator<Item = KeyLayout> {
+        std::iter::once(self.layout).chain(self.also_write)
+    }
+
+    /// The marker in etcd, the default if there is none
+    pub async fn load(client: &EtcdClient) -> Result<Self> {
+        let kvs = client.kv_get(marker_path(), None).await?;
+        match kvs.first() {
+            Some(kv) => Ok(serde_json::from_slice(kv.value())?),
+            None => Ok(LayoutMarker::default()),
+        }
+    }
+
+    /// The marker in `store`, the default if there is none
+    pub async fn fetch(store: &KeyValueStoreManager) -> Result<Self, StoreError> {
+        let Some(bucket) = store.get_bucket(LAYOUT_BUCKET).await? else {
+            return Ok(LayoutMarker::default());
+        };
+        match bucket
+            .get(&Key::from_raw(LAYOUT_MARKER.to_string()))
+            .await?
+        {
+            Some(value) => Ok(serde_json::from_slice(&value)?),
+            None => Ok(LayoutMarker::default()),
+        }
+    }
+
+    /// Without a lease, the marker has to outlive whoever wrote it
+    async fn save(&self, client: &EtcdClient) -> Result<()> {
+        client
+            .kv_put(marker_path(), serde_json::to_vec(self)?, Some(0))
+            .await?;
+        tracing::info!(marker = ?self, "Key layout marker written");
+        Ok(())
+    }
+}
+
+fn marker_path() -> String {
+    format!("{LAYOUT_BUCKET}/{LAYOUT_MARKER}")
+}
+
+#[derive(Debug, Clone)]
+pub struct MigrateConfig {
+    /// Between writing both layouts and copying, for registrations that read the marker before
+    /// it changed to finish
+    pub settle: Duration,
+    /// Comparing and repairing, before giving up
+    pub verify_passes: usize,
+}
+
+impl Default for MigrateConfig {
+    fn default() -> Self {
+        MigrateConfig {
+            settle: Duration::from_secs(10),
+            verify_passes: 5,
+        }
+    }
+}
+
+/// What [`migrate`] did
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MigrationReport {
+    /// In the new layout when it verified
+    pub records: usize,
+    /// Created in the new layout
+    pub copied: usize,
+    /// Overwritten in the new layout, its value or lease differed
+    pub repaired: usize,
+    /// Deleted from the new layout, gone from the old one
+    pub removed: usize,
+    pub verify_passes: usize,
+}
+
+/// A write [`plan_migration`], [`plan_finish`] or [`plan_abort`] found would be made
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlannedWrite {
+    /// The marker would be saved as this
+    Marker(LayoutMarker),
+    /// Copied from the old layout to this path in the new one
+    Copy(String),
+    /// Overwritten from the old layout, its value or lease differs
+    Repair(String),
+    /// Deleted from the new layout, it isn't in the old one
+    Remove(String),
+    /// Deleted with the rest of its layout
+    Delete(String),
+}
+
+impl fmt::Display for PlannedWrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlannedWrite::Marker(marker) => write!(
+                f,
+                "marker: read {}, phase {:?}, also write {:?}",
+                marker.layout, marker.phase, marker.also_write
+            ),
+            PlannedWrite::Copy(path) => write!(f, "copy {path}"),
+            PlannedWrite::Repair(path) => write!(f, "repair {path}"),
+            PlannedWrite::Remove(path) => write!(f, "remove {path}"),
+            PlannedWrite::Delete(path) => write!(f, "delete {path}"),
+        }
+    }
+}
+
+/// The markers a migration from `marker` to `to` saves: double write, then cut over
+fn migration_markers(marker: LayoutMarker, to: KeyLayout) -> Result<[LayoutMarker; 2]> {
+    let from = match marker.phase {
+        MigrationPhase::Stable if marker.layout == to => {
+            return Err(error!("The key layout is {to} already"));
+        }
+        MigrationPhase::Stable => marker.layout,
+        MigrationPhase::DoubleWrite if marker.also_write == Some(to) => marker.layout,
+        _ => return Err(error!("A migration is under way already: {marker:?}")),
+    };
+    let double_write = LayoutMarker {
+        layout: from,
+        also_write: Some(to),
+        phase: MigrationPhase::DoubleWrite,
+    };
+    let cut_over = LayoutMarker {
+        layout: to,
+        also_write: Some(from),
+        phase: MigrationPhase::CutOver,
+    };
+    Ok([double_write, cut_over])
+}
+
+/// The marker finishing the migration at `marker` saves, and the layout it deletes
+fn finish_marker(marker: LayoutMarker) -> Result<(LayoutMarker, KeyLayout)> {
+    let (MigrationPhase::CutOver, Some(old)) = (marker.phase, marker.also_write) else {
+        return Err(error!("No migration has cut over: {marker:?}"));
+    };
+    Ok((LayoutMarker::stable(marker.layout), old))
+}
+
+/// The marker aborting the migration at `marker` saves, and the layout it deletes
+fn abort_marker(marker: LayoutMarker) -> Result<(LayoutMarker, KeyLayout)> {
+    let (MigrationPhase::DoubleWrite, Some(new)) = (marker.phase, marker.also_write) else {
+        return Err(error!(
+            "No migration to abort before its cutover: {marker:?}"
+        ));
+    };
+    Ok((LayoutMarker::stable(marker.layout), new))
+}
+
+/// Move the discovery records in etcd to the layout `to`, and read them from there, see the
+/// [module docs](self). Picks up a migration to `to` that stopped before the cutover.
+pub async fn migrate(
+    client: &EtcdClient,
+    to: KeyLayout,
+    config: &MigrateConfig,
+) -> Result<MigrationReport> {
+    let [double_write, cut_over] = migration_markers(LayoutMarker::load(client).await?, to)?;
+    let from = double_write.layout;
+    double_write.save(client).await?;
+    tokio::time::sleep(config.settle).await;
+
+    let report = verify(client, from, to, config.verify_passes).await?;
+    tracing::info!(%from, %to, ?report, "Key layouts verified, cutting over");
+
+    cut_over.save(client).await?;
+    Ok(report)
+}
+
+/// What [`migrate`] would write if the records stayed as they are now. Registrations and
+/// expiries during its settle and verify passes change what it copies and repairs.
+pub async fn plan_migration(client: &EtcdClient, to: KeyLayout) -> Result<Vec<PlannedWrite>> {
+    let [double_write, cut_over] = migration_markers(LayoutMarker::load(client).await?, to)?;
+    let from = double_write.layout;
+    let old = records(client, from).await?;
+    let new = records(client, to).await?;
+    let mut writes = vec![PlannedWrite::Marker(double_write)];
+    writes.extend(Difference::between(&old, &new).writes(to));
+    writes.push(PlannedWrite::Marker(cut_over));
+    Ok(writes)
+}
+
+/// After the cutover, write the new layout only and delete the records of the old one. The
+/// number deleted.
+pub async fn finish_migration(client: &EtcdClient) -> Result<usize> {
+    let (stable, old) = finish_marker(LayoutMarker::load(client).await?)?;
+    stable.save(client).await?;
+    delete_records(client, old).await
+}
+
+/// What [`finish_migration`] would write
+pub async fn plan_finish(client: &EtcdClient) -> Result<Vec<PlannedWrite>> {
+    let (stable, old) = finish_marker(LayoutMarker::load(client).await?)?;
+    plan_deletes(client, stable, old).await
+}
+
+/// Before the cutover, go back to the old layout and delete the records copied to the new
+/// one. The number deleted.
+pub async fn abort_migration(client: &EtcdClient) -> Result<usize> {
+    let (stable, new) = abort_marker(LayoutMarker::load(client).await?)?;
+    stable.save(client).await?;
+    delete_records(client, new).await
+}
+
+/// What [`abort_migration`] would write
+pub async fn plan_abort(client: &EtcdClient) -> Result<Vec<PlannedWrite>> {
+    let (stable, new) = abort_marker(LayoutMarker::load(client).await?)?;
+    plan_deletes(client, stable, new).await
+}
+
+/// Saving `marker`, then deleting the records of `layout`
+async fn plan_deletes(
+    client: &EtcdClient,
+    marker: LayoutMarker,
+    layout: KeyLayout,
+) -> Result<Vec<PlannedWrite>> {
+    let mut paths: Vec<String> = records(client, layout)
+        .await?
+        .keys()
+        .map(|record| layout.path(record))
+        .collect();
+    paths.sort();
+    let deletes = paths.into_iter().map(PlannedWrite::Delete);
+    Ok(std::iter::once(PlannedWrite::Marker(marker))
+        .chain(deletes)
+        .collect())
+}
+
+/// A record's value and lease
+type Records = HashMap<DiscoveryKey, (Vec<u8>, i64)>;
+
+async fn records(client: &EtcdClient, layout: KeyLayout) -> Result<Records> {
+    let mut records = HashMap::new();
+    for bucket in layout.buckets() {
+        for kv in client.kv_get_prefix(format!("{bucket}/")).await? {
+            let Some(record) = kv.key_str().ok().and_then(|key| layout.parse(key)) else {
+                continue;
+            };
+            records.insert(record, (kv.value().to_vec(), kv.lease()));
+        }
+    }
+    Ok(records)
+}
+
+async fn delete_records(client: &EtcdClient, layout: KeyLayout) -> Result<usize> {
+    let records = records(client, layout).await?;
+    for record in records.keys() {
+        client.kv_delete(layout.path(record), None).await?;
+    }
+    tracing::info!(%layout, deleted = records.len(), "Deleted the records of a key layout");
+    Ok(records.len())
+}
+
+/// How the records of the new layout differ from the old one's
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Difference {
+    missing: Vec<DiscoveryKey>,
+    changed: Vec<DiscoveryKey>,
+    extra: Vec<DiscoveryKey>,
+}
+
+impl Difference {
+    fn between(old: &Records, new: &Records) -> Self {
+        let mut difference = Difference::default();
+        for (record, value) in old {
+            match new.get(record) {
+                None => difference.missing.push(record.clone()),
+                Some(new_value) if new_value != value => difference.changed.push(record.clone()),
+                Some(_) => {}
+            }
+        }
+        difference.extra = new
+            .keys()
+            .filter(|record| !old.contains_key(record))
+            .cloned()
+            .collect();
+        difference
+    }
+
+    fn len(&self) -> usize {
+        self.missing.len() + self.changed.len() + self.extra.len()
+    }
+
+    /// The writes repairing the new layout `to`, in order of path within each kind
+    fn writes(&self, to: KeyLayout) -> Vec<PlannedWrite> {
+        let paths = |records: &[DiscoveryKey]| {
+            let mut paths: Vec<String> = records.iter().map(|record| to.path(record)).collect();
+            paths.sort();
+            paths
+        };
+        let copies = paths(&self.missing).into_iter().map(PlannedWrite::Copy);
+        let repairs = paths(&self.changed).into_iter().map(PlannedWrite::Repair);
+        let removals = paths(&self.extra).into_iter().map(PlannedWrite::Remove);
+        copies.chain(repairs).chain(removals).collect()
+    }
+}
+
+/// Compare and repair until the layouts agree, the first pass doing the copy
+async fn verify(
+    client: &EtcdClient,
+    from: KeyLayout,
+    to: KeyLayout,
+    passes: usize,
+) -> Result<MigrationReport> {
+    let mut report = MigrationReport::default();
+    for pass in 1..=passes.max(1) {
+        report.verify_passes = pass;
+        let old = records(client, from).await?;
+        let new = records(client, to).await?;
+        let difference = Difference::between(&old, &new);
+        if difference.len() == 0 {
+            report.records = new.len();
+            return Ok(report);
+        }
+        tracing::info!(
+            pass,
+            differences = difference.len(),
+            "Repairing the new key layout"
+        );
+        for record in &difference.missing {
+            let (value, lease) = &old[record];
+            // Fails if it was registered since, or its lease expired; the next pass sees
+            if let Err(err) = client
+                .kv_create(&to.path(record), value.clone(), Some(*lease as u64))
+                .await
+            {
+                tracing::debug!(%err, ?record, "Not copied");
+                continue;
+            }
+            report.copied += 1;
+        }
+        // Failing ones, as on a lease that just expired, are left for the next pass too, and
+        // the migration stays in double write until one finds nothing to repair
+        for record in &difference.changed {
+            let (value, lease) = &old[record];
+            if let Err(err) = client
+                .kv_put(to.path(record), value, Some(*lease as u64))
+                .await
+            {
+                tracing::debug!(%err, ?record, "Not repaired");
+                continue;
+            }
+            report.repaired += 1;
+        }
+        for record in &difference.extra {
+            if let Err(err) = client.kv_delete(to.path(record), None).await {
+                tracing::debug!(%err, ?record, "Not removed");
+                continue;
+            }
+            report.removed += 1;
+        }
+        tokio::time::sleep(VERIFY_PAUSE).await;
+    }
+    Err(error!(
+        "The {from} and {to} key layouts still differ after {passes} passes"
+    ))
+}
+
+fn in_bucket<'a>(bucket: &str, key: &'a str) -> &'a str {
+    key.strip_prefix(bucket)
+        .and_then(|key| key.strip_prefix('/'))
+        .unwrap_or(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout_paths() {
+        let instance = DiscoveryKey::Instance {
+            namespace: "dynamo.eu".to_string(),
+            component: "backend".to_string(),
+            endpoint: "generate".to_string(),
+            instance_id: 0x1234,
+        };
+        let card = DiscoveryKey::Card {
+            model: "llama-3_1-8b".to_string(),
+            instance_id: 0xff,
+        };
+        let v1 = KeyLayout::V1.path(&instance);
+        assert_eq!(v1, "v1/instances/dynamo.eu/backend/generate/1234");
+        assert_eq!(KeyLayout::V1.path(&card), "v1/mdc/llama-3_1-8b.ff");
+        assert_eq!(
+            KeyLayout::V2.path(&card),
+            "v2/discovery/cards/llama-3_1-8b/ff"
+        );
+        for layout in [KeyLayout::V1, KeyLayout::V2] {
+            for record in [&instance, &card] {
+                assert_eq!(layout.parse(&layout.path(record)).as_ref(), Some(record));
+            }
+        }
+        // Not of the layout, or not a record
+        assert_eq!(KeyLayout::V2.parse(&v1), None);
+        assert_eq!(
+            KeyLayout::V1.parse("v1/instances/dynamo/backend/generate"),
+            None
+        );
+        assert_eq!("v2".parse::<KeyLayout>().unwrap(), KeyLayout::V2);
+        assert!("v3".parse::<KeyLayout>().is_err());
+    }
+
+    #[test]
+    fn test_parse_card_key() {
+        let key = KeyLayout::V1.card_key("Llama-3.1-8B", 0x1234);
+        assert_eq!(key.as_ref(), "llama-3_1-8b.1234");
+        let parse = |key| KeyLayout::V1.parse_card_key(key);
+        assert_eq!(parse(key.as_ref()), Some(("llama-3_1-8b", 0x1234)));
+        assert_eq!(parse("v1/mdc/llama.ff"), Some(("llama", 0xff)));
+        assert_eq!(parse("llama"), None);
+
+        let key = KeyLayout::V2.card_key("Llama-3.1-8B", 0x1234);
+        assert_eq!(key.as_ref(), "llama-3_1-8b/1234");
+        let parse = |key| KeyLayout::V2.parse_card_key(key);
+        assert_eq!(parse(key.as_ref()), Some(("llama-3_1-8b", 0x1234)));
+        assert_eq!(parse("v2/discovery/cards/llama/ff"), Some(("llama", 0xff)));
+        assert_eq!(parse("llama.ff"), None);
+    }
+
+    #[test]
+    fn test_difference() {
+        let card = |instance_id| DiscoveryKey::Card {
+            model: "llama".to_string(),
+            instance_id,
+        };
+        let old = Records::from([
+            (card(1), (b"a".to_vec(), 7)),
+            (card(2), (b"b".to_vec(), 7)),
+            (card(3), (b"c".to_vec(), 7)),
+        ]);
+        let new = Records::from([
+            (card(2), (b"b".to_vec(), 7)),
+            // Copied with the wrong lease
+            (card(3), (b"c".to_vec(), 0)),
+            (card(4), (b"d".to_vec(), 7)),
+        ]);
+        let difference = Difference::between(&old, &new);
+        assert_eq!(difference.missing, [card(1)]);
+        assert_eq!(difference.changed, [card(3)]);
+        assert_eq!(difference.extra, [card(4)]);
+        assert_eq!(Difference::between(&old, &old).len(), 0);
+        assert_eq!(
+            difference.writes(KeyLayout::V2),
+            [
+                PlannedWrite::Copy("v2/discovery/cards/llama/1".to_string()),
+                PlannedWrite::Repair("v2/discovery/cards/llama/3".to_string()),
+                PlannedWrite::Remove("v2/discovery/cards/llama/4".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_planned_markers() {
+        let [double_write, cut_over] =
+            migration_markers(LayoutMarker::default(), KeyLayout::V2).unwrap();
+        assert_eq!(
+            double_write.writes().collect::<Vec<_>>(),
+            [KeyLayout::V1, KeyLayout::V2]
+        );
+        assert_eq!(cut_over.phase, MigrationPhase::CutOver);
+        // Picked up again before the cutover, and nothing else
+        assert_eq!(
+            migration_markers(double_write, KeyLayout::V2).unwrap()[1],
+            cut_over
+        );
+        assert!(migration_markers(cut_over, KeyLayout::V2).is_err());
+        assert!(migration_markers(LayoutMarker::default(), KeyLayout::V1).is_err());
+
+        assert!(abort_marker(cut_over).is_err());
+        assert_eq!(
+            abort_marker(double_write).unwrap(),
+            (LayoutMarker::stable(KeyLayout::V1), KeyLayout::V2)
+        );
+        assert!(finish_marker(double_write).is_err());
+        assert_eq!(
+            finish_marker(cut_over).unwrap(),
+            (LayoutMarker::stable(KeyLayout::V2), KeyLayout::V1)
+        );
+    }
+
+    #[test]
+    fn test_marker() {
+        let marker = LayoutMarker::default();
+        assert_eq!(marker.writes().collect::<Vec<_>>(), [KeyLayout::V1]);
+        let marker = LayoutMarker {
+            layout: KeyLayout::V2,
+            also_write: Some(KeyLayout::V1),
+            phase: MigrationPhase::CutOver,
+        };
+        assert_eq!(
+            marker.writes().collect::<Vec<_>>(),
+            [KeyLayout::V2, KeyLayout::V1]
+        );
+        let json = serde_json::to_string(&marker).unwrap();
+        assert_eq!(
+            json,
+            r#"{"layout":"v2","also_write":"v1","phase":"cut_over"}"#
+        );
+        assert_eq!(serde_json::from_str::<LayoutMarker>(&json).unwrap(), marker);
+    }
+}
@@ -0,0 +1,526 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Model cards: what a worker serving a model tells the frontends about it.
+//!
+//! Each instance serving a model publishes a [`ModelCard`] in the [`MODEL_CARD_BUCKET`], under
+//! `{model}.{instance_id:x}` with the model name slugified, or where the [`KeyLayout`] in use
+//! says. [`ModelCards`] is the one place cards are read and written, so readers and writers agree
+//! on the layout and the serialization.
+//!
+//! Cards carry the [`SCHEMA_VERSION`] they were written with. Fields added later must have a
+//! default, so older cards still load. A card from a newer schema than this build knows is
+//! refused rather than read wrong.
+//!
+//! Cards are JSON, or protobuf in a card bucket whose [`BucketVersion`] allows it.
+//!
+//! [`BucketVersion`]: super::encoding::BucketVersion
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use prost::Message;
+use serde::{Deserialize, Serialize};
+
+use crate::CancellationToken;
+use crate::slug::Slug;
+
+use super::encoding::{self, DiscoveryRecord, EncodingError, ValueEncoding};
+use super::key_value_store::{
+    KeyValueStoreManager, PublishMode, StoreError, StoreOutcome, Versioned, WatchEvent, WatchFilter,
+};
+use super::layout::{KeyLayout, LayoutMarker};
+
+pub const MODEL_CARD_BUCKET: &str = "v1/mdc";
+
+/// Of the [`ModelCard`] layout written by this build
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelCard {
+    /// Cards written before there was a version are version 1
+    #[serde(default = "first_schema_version")]
+    pub schema_version: u32,
+    /// As requested by clients
+    pub name: String,
+    /// In tokens
+    pub context_length: u32,
+    /// In tokens
+    pub kv_cache_block_size: u32,
+    /// How often a request may move to another instance when this one goes away
+    #[serde(default)]
+    pub migration_limit: u32,
+    /// Engine settings the frontends may want to know about, by name
+    #[serde(default)]
+    pub runtime_config: HashMap<String, serde_json::Value>,
+    #[serde(skip)]
+    revision: u64,
+}
+
+fn first_schema_version() -> u32 {
+    1
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ModelCardError {
+    #[error("Model card for '{name}' is invalid: {reason}")]
+    Invalid { name: String, reason: String },
+
+    #[error("Model card for '{name}' has schema version {found}, newer than {SCHEMA_VERSION}")]
+    UnsupportedSchema { name: String, found: u32 },
+
+    #[error(transparent)]
+    Decode(#[from] EncodingError),
+
+    #[error(transparent)]
+    Store(#[from] StoreError),
+}
+
+impl ModelCard {
+    pub fn new(name: impl Into<String>, context_length: u32, kv_cache_block_size: u32) -> Self {
+        ModelCard {
+            schema_version: SCHEMA_VERSION,
+            name: name.into(),
+            context_length,
+            kv_cache_block_size,
+            migration_limit: 0,
+            runtime_config: HashMap::new(),
+            revision: 0,
+        }
+    }
+
+    /// Checked before publishing and after loading
+    pub fn validate(&self) -> Result<(), ModelCardError> {
+        let invalid = |reason: &str| {
+            Err(ModelCardError::Invalid {
+                name: self.name.clone(),
+                reason: reason.to_string(),
+            })
+        };
+        if self.schema_version > SCHEMA_VERSION {
+            return Err(ModelCardError::UnsupportedSchema {
+                name: self.name.clone(),
+                found: self.schema_version,
+            });
+        }
+        if self.name.trim().is_empty() {
+            return invalid("the name is empty");
+        }
+        if Slug::slugify(&self.name).to_string().is_empty() {
+            return invalid("the name has no characters usable in a key");
+        }
+        if self.context_length == 0 {
+            return invalid("the context length is 0");
+        }
+        if self.kv_cache_block_size == 0 {
+            return invalid("the KV cache block size is 0");
+        }
+        if self.kv_cache_block_size > self.context_length {
+            return invalid("the KV cache block size is larger than the context length");
+        }
+        Ok(())
+    }
+
+    fn decode(value: &[u8]) -> Result<Self, ModelCardError> {
+        let card: ModelCard = encoding::decode(value)?;
+        card.validate()?;
+        Ok(card)
+    }
+}
+
+impl Versioned for ModelCard {
+    fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    fn set_revision(&mut self, r: u64) {
+        self.revision = r;
+    }
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ModelCardProto {
+    #[prost(uint32, tag = "1")]
+    schema_version: u32,
+    #[prost(string, tag = "2")]
+    name: String,
+    #[prost(uint32, tag = "3")]
+    context_length: u32,
+    #[prost(uint32, tag = "4")]
+    kv_cache_block_size: u32,
+    #[prost(uint32, tag = "5")]
+    migration_limit: u32,
+    /// As JSON, as its values are, and empty when there are none
+    #[prost(bytes = "vec", tag = "6")]
+    runtime_config: Vec<u8>,
+}
+
+impl DiscoveryRecord for ModelCard {
+    fn to_protobuf(&self) -> Vec<u8> {
+        let runtime_config = if self.runtime_config.is_empty() {
+            Vec::new()
+        } else {
+            // A map of JSON values always serializes
+            serde_json::to_vec(&self.runtime_config).unwrap_or_default()
+        };
+        ModelCardProto {
+            schema_version: self.schema_version,
+            name: self.name.clone(),
+            context_length: self.context_length,
+            kv_cache_block_size: self.kv_cache_block_size,
+            migration_limit: self.migration_limit,
+            runtime_config,
+        }
+        .encode_to_vec()
+    }
+
+    fn from_protobuf(bytes: &[u8]) -> Result<Self, EncodingError> {
+        let proto = ModelCardProto::decode(bytes)?;
+        let runtime_config = if proto.runtime_config.is_empty() {
+            HashMap::new()
+        } else {
+            serde_json::from_slice(&proto.runtime_config)?
+        };
+        Ok(ModelCard {
+            schema_version: proto.schema_version,
+            name: proto.name,
+            context_length: proto.context_length,
+            kv_cache_block_size: proto.kv_cache_block_size,
+            migration_limit: proto.migration_limit,
+            runtime_config,
+            revision: 0,
+        })
+    }
+}
+
+/// A change seen by [`ModelCards::watch`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum ModelCardEvent {
+    Published {
+        instance_id: u64,
+        card: ModelCard,
+    },
+    /// `model` is the slugified name the card was stored under
+    Removed {
+        model: String,
+        instance_id: u64,
+    },
+    /// The cards that existed when the watch started have all been sent
+    Synced,
+}
+
+/// The model cards in one store
+#[derive(Clone)]
+pub struct ModelCards {
+    store: Arc<KeyValueStoreManager>,
+}
+
+impl ModelCards {
+    pub fn new(store: Arc<KeyValueStoreManager>) -> Self {
+        ModelCards { store }
+    }
+
+    /// Write the card `instance_id` serves `card.name` by, replacing any earlier one, in each
+    /// layout written
+    pub async fn publish(
+        &self,
+        instance_id: u64,
+        card: &mut ModelCard,
+    ) -> anyhow::Result<StoreOutcome> {
+        card.validate()?;
+        card.schema_version = SCHEMA_VERSION;
+        let marker = LayoutMarker::fetch(&self.store).await?;
+        // The layout read last, so the card has its revision there
+        let layouts: Vec<_> = marker.writes().collect();
+        let buckets: Vec<_> = layouts.iter().map(|layout| layout.card_bucket()).collect();
+        let encoding = ValueEncoding::negotiate(&self.store, &buckets).await?;
+        let value = encoding::encode_text(card, encoding)?;
+        let mut outcome = None;
+        for layout in layouts.into_iter().rev() {
+            let key = layout.card_key(&card.name, instance_id);
+            let bucket = layout.card_bucket();
+            outcome = Some(
+                self.store
+                    .publish_value(bucket, None, &key, &value, PublishMode::Upsert)
+                    .await?,
+            );
+        }
+        let outcome = outcome.expect("a layout is always written");
+        match outcome {
+            StoreOutcome::Created(revision) | StoreOutcome::Exists(revision) => {
+                card.set_revision(revision);
+            }
+        }
+        Ok(outcome)
+    }
+
+    pub async fn load(
+        &self,
+        model: &str,
+        instance_id: u64,
+    ) -> Result<Option<ModelCard>, ModelCardError> {
+        let layout = LayoutMarker::fetch(&self.store).await?.layout;
+        let Some(bucket) = self.store.get_bucket(layout.card_bucket()).await? else {
+            return Ok(None);
+        };
+        let key = layout.card_key(model, instance_id);
+        let Some(value) = bucket.get(&key).await? else {
+            return Ok(None);
+        };
+        Ok(Some(ModelCard::decode(&value)?))
+    }
+
+    /// The cards of `model`, or of every model, by instance. Cards that don't load are
+    /// skipped, and logged.
+    pub async fn list(&self, model: Option<&str>) -> Result<Vec<(u64, ModelCard)>, StoreError> {
+        let layout = LayoutMarker::fetch(&self.store).await?.layout;
+        let Some(bucket) = self.store.get_bucket(layout.card_bucket()).await? else {
+            return Ok(Vec::new());
+        };
+        let wanted = model.map(|model| Slug::slugify(model).to_string());
+        let mut cards: Vec<_> = bucket
+            .entries()
+            .await?
+            .into_iter()
+            .filter_map(|(key, value)| {
+                let (slug, instance_id) = layout.parse_card_key(&key)?;
+                if wanted.as_deref().is_some_and(|wanted| wanted != slug) {
+                    return None;
+                }
+                match ModelCard::decode(&value) {
+                    Ok(card) => Some((instance_id, card)),
+                    Err(err) => {
+                        tracing::warn!(%key, %err, "Skipping model card that doesn't load");
+                        None
+                    }
+                }
+            })
+            .collect();
+        cards.sort_by_key(|(instance_id, _)| *instance_id);
+        Ok(cards)
+    }
+
+    /// Delete the card `instance_id` published for `model`, if there is one, from each layout
+    /// written
+    pub async fn remove(&self, model: &str, instance_id: u64) -> Result<(), StoreError> {
+        let marker = LayoutMarker::fetch(&self.store).await?;
+        for layout in marker.writes() {
+            let Some(bucket) = self.store.get_bucket(layout.card_bucket()).await? else {
+                continue;
+            };
+            bucket.delete(&layout.card_key(model, instance_id)).await?;
+        }
+        Ok(())
+    }
+
+    /// The cards of `model`, or of every model, and then the changes to them, until
+    /// `cancel_token` is cancelled or the watch fails for good. Cards that don't load are
+    /// skipped, and logged.
+    pub fn watch(
+        &self,
+        model: Option<&str>,
+        cancel_token: CancellationToken,
+    ) -> tokio::sync::mpsc::UnboundedReceiver<ModelCardEvent> {
+        let model = model.map(str::to_string);
+        let store = self.store.clone();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let layout = match LayoutMarker::fetch(&store).await {
+                Ok(marker) => marker.layout,
+                Err(err) => {
+                    tracing::error!(%err, "Model card watch failed");
+                    return;
+                }
+            };
+            let prefix = model.map(|model| layout.card_prefix(&model));
+            let filter = WatchFilter::prefix(prefix.unwrap_or_default());
+            let bucket = layout.card_bucket();
+            let mut events = store.watch_filtered(bucket, None, filter, cancel_token);
+            while let Some(event) = events.recv().await {
+                let card_event = match event {
+                    WatchEvent::Put(kv) => {
+                        let Some((_, instance_id)) = layout.parse_card_key(kv.key()) else {
+                            continue;
+                        };
+                        match ModelCard::decode(kv.value()) {
+                            Ok(card) => ModelCardEvent::Published { instance_id, card },
+                            Err(err) => {
+                                tracing::warn!(key = kv.key(), %err, "Skipping model card");
+                                continue;
+                            }
+                        }
+                    }
+                    WatchEvent::Delete(kv) => {
+                        let Some((model, instance_id)) = layout.parse_card_key(kv.key()) else {
+                            continue;
+                        };
+                        let model = model.to_string();
+                        ModelCardEvent::Removed { model, instance_id }
+                    }
+                    WatchEvent::InitialSyncComplete => ModelCardEvent::Synced,
+                    WatchEvent::Error(err) => {
+                        tracing::error!(%err, "Model card watch failed");
+                        break;
+                    }
+                    WatchEvent::Closed => break,
+                    WatchEvent::Disconnected | WatchEvent::Reconnected => continue,
+                };
+                if tx.send(card_event).is_err() {
+                    break;
+                }
+            }
+        });
+        rx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::encoding::{BucketVersion, PROTOBUF_VERSION};
+    use crate::storage::key_value_store::Key;
+    use crate::storage::layout::{LAYOUT_BUCKET, LAYOUT_MARKER, MigrationPhase};
+
+    #[test]
+    fn test_validate() {
+        let card = ModelCard::new("Llama-3.1-8B", 8192, 16);
+        card.validate().unwrap();
+
+        let err = ModelCard::new(" ", 8192, 16).validate().unwrap_err();
+        assert!(matches!(err, ModelCardError::Invalid { .. }), "{err}");
+        let err = ModelCard::new("llama", 8, 16).validate().unwrap_err();
+        assert!(
+            err.to_string().contains("larger than the context length"),
+            "{err}"
+        );
+
+        let newer = ModelCard {
+            schema_version: SCHEMA_VERSION + 1,
+            ..card.clone()
+        };
+        let err = newer.validate().unwrap_err();
+        assert!(
+            matches!(err, ModelCardError::UnsupportedSchema { .. }),
+            "{err}"
+        );
+
+        // Written before versioning and before migration limits
+        let old = r#"{"name": "llama", "context_length": 8192, "kv_cache_block_size": 16}"#;
+        let old = ModelCard::decode(old.as_bytes()).unwrap();
+        assert_eq!(old.schema_version, 1);
+        assert_eq!(old.migration_limit, 0);
+    }
+
+    #[tokio::test]
+    async fn test_publish_load_list_watch() -> anyhow::Result<()> {
+        let cards = ModelCards::new(Arc::new(KeyValueStoreManager::memory()));
+        assert!(cards.load("llama", 1).await?.is_none());
+
+        let mut llama = ModelCard::new("llama", 8192, 16);
+        cards.publish(1, &mut llama).await?;
+        cards.publish(2, &mut llama).await?;
+        cards
+            .publish(3, &mut ModelCard::new("mistral", 4096, 32))
+            .await?;
+        let loaded = cards.load("llama", 2).await?.unwrap();
+        assert_eq!(
+            (loaded.name, loaded.context_length),
+            ("llama".to_string(), 8192)
+        );
+
+        let listed = cards.list(Some("llama")).await?;
+        assert_eq!(
+            listed.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(cards.list(None).await?.len(), 3);
+
+        let cancel_token = CancellationToken::new();
+        let mut events = cards.watch(Some("mistral"), cancel_token.clone());
+        let Some(ModelCardEvent::Published { instance_id, card }) = events.recv().await else {
+            panic!("expected the mistral card");
+        };
+        assert_eq!((instance_id, card.name.as_str()), (3, "mistral"));
+        assert_eq!(events.recv().await, Some(ModelCardEvent::Synced));
+
+        cards.remove("mistral", 3).await?;
+        let removed = ModelCardEvent::Removed {
+            model: "mistral".to_string(),
+            instance_id: 3,
+        };
+        assert_eq!(events.recv().await, Some(removed));
+        cancel_token.cancel();
+        assert_eq!(events.recv().await, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_publish_double_write() -> anyhow::Result<()> {
+        let store = Arc::new(KeyValueStoreManager::memory());
+        let marker = LayoutMarker {
+            layout: KeyLayout::V1,
+            also_write: Some(KeyLayout::V2),
+            phase: MigrationPhase::DoubleWrite,
+        };
+        let key = Key::from_raw(LAYOUT_MARKER.to_string());
+        let bucket = store.get_or_create_bucket(LAYOUT_BUCKET, None).await?;
+        bucket
+            .insert_new(&key, &serde_json::to_string(&marker)?)
+            .await?;
+
+        let cards = ModelCards::new(store.clone());
+        cards
+            .publish(1, &mut ModelCard::new("llama", 8192, 16))
+            .await?;
+        for layout in [KeyLayout::V1, KeyLayout::V2] {
+            let bucket = store.get_bucket(layout.card_bucket()).await?.unwrap();
+            assert!(
+                bucket.get(&layout.card_key("llama", 1)).await?.is_some(),
+                "{layout}"
+            );
+        }
+        assert_eq!(cards.list(None).await?.len(), 1);
+
+        cards.remove("llama", 1).await?;
+        for layout in [KeyLayout::V1, KeyLayout::V2] {
+            let bucket = store.get_bucket(layout.card_bucket()).await?.unwrap();
+            assert!(
+                bucket.get(&layout.card_key("llama", 1)).await?.is_none(),
+                "{layout}"
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_publish_protobuf() -> anyhow::Result<()> {
+        let store = Arc::new(KeyValueStoreManager::memory());
+        let cards = ModelCards::new(store.clone());
+        let mut llama = ModelCard::new("llama", 8192, 16);
+        llama
+            .runtime_config
+            .insert("max_batch".to_string(), 64.into());
+        cards.publish(1, &mut llama).await?;
+
+        BucketVersion::set(&store, MODEL_CARD_BUCKET, PROTOBUF_VERSION).await?;
+        cards.publish(2, &mut llama).await?;
+        let bucket = store.get_bucket(MODEL_CARD_BUCKET).await?.unwrap();
+        let json = bucket
+            .get(&KeyLayout::V1.card_key("llama", 1))
+            .await?
+            .unwrap();
+        let protobuf = bucket
+            .get(&KeyLayout::V1.card_key("llama", 2))
+            .await?
+            .unwrap();
+        assert!(json.starts_with(b"{") && protobuf.starts_with(b"pb:"));
+
+        // Both read the same
+        for instance_id in [1, 2] {
+            let mut loaded = cards.load("llama", instance_id).await?.unwrap();
+            loaded.set_revision(llama.revision());
+            assert_eq!(loaded, llama);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,259 @@
+// SPDX-FileCopyrightText: Copyright (c) 2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Writing a record to the key-value store and telling others about it on NATS, without losing
+//! the message when the process dies in between.
+//!
+//! Updating a key and then publishing a message drops the message if the process crashes after
+//! the first step, and whoever caches the key goes stale. [`Outbox::write`] first records a
+//! [`WriteIntent`] with both in [`OUTBOX_BUCKET`], then writes the record, publishes the message
+//! and removes the intent. Intents left behind by a crash or a NATS outage are finished by the
+//! drainer of any process that [started](Outbox::start) an outbox, once they are
+//! [`INTENT_GRACE`] old.
+//!
+//! The record is written with a compare-and-swap from the revision the key had when the intent
+//! was recorded, so a drainer never puts an older value over a newer one. An intent whose key has
+//! moved on since only has its message sent if the key holds its value; otherwise it lost to the
+//! later write, which sends its own.
+//!
+//! Messages are sent at least once: a crash after publishing but before removing the intent sends
+//! it again, so receivers should take them to mean "read this key again".
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+
+use crate::Result;
+use crate::storage::key_value_store::{
+    Conditional, Key, KeyValueBucket, KeyValueStoreManager, StoreError, StoreOutcome,
+};
+use crate::transports::nats;
+
+/// Where the intents of every process are kept, so that any drainer finishes them
+pub const OUTBOX_BUCKET: &str = "v1/outbox";
+
+/// How old an intent is before a drainer takes it for abandoned
+pub const INTENT_GRACE: Duration = Duration::from_secs(10);
+
+/// How often a drainer looks for abandoned intents
+pub const DRAIN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A record write and the message announcing it, kept until both are done
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteIntent {
+    pub bucket: String,
+    pub key: String,
+    pub value: String,
+    /// The key's revision when the intent was recorded, 0 if it was missing
+    pub revision: u64,
+    pub subject: String,
+    /// The message, in base64
+    pub payload: String,
+    /// When the intent was recorded, in Unix milliseconds
+    pub created_at: u64,
+}
+
+pub struct Outbox {
+    store: KeyValueStoreManager,
+    nats_client: nats::Client,
+}
+
+impl Outbox {
+    /// An outbox without a drainer, relying on another process's to finish abandoned intents
+    pub fn new(store: KeyValueStoreManager, nats_client: nats::Client) -> Arc<Self> {
+        Arc::new(Outbox { store, nats_client })
+    }
+
+    /// An outbox that also finishes abandoned intents until `cancel_token` is cancelled
+    pub fn start(
+        store: KeyValueStoreManager,
+        nats_client: nats::Client,
+        cancel_token: CancellationToken,
+    ) -> Arc<Self> {
+        let outbox = Outbox::new(store, nats_client);
+        tokio::spawn(drain_outbox(outbox.clone(), cancel_token));
+        outbox
+    }
+
+    /// Write `value` to `key` in `bucket_name`, then publish `payload` on `subject`. Returns the
+    /// key's new revision.
+    ///
+    /// Fails with [`StoreError::Retry`] if someone else wrote the key while the intent was being
+    /// recorded, and nothing is written or sent. Once the record is written this succeeds even if
+    /// publishing fails, leaving the message to a drainer. After any other error the intent is
+    /// kept, and a drainer may still finish the write.
+    pub async fn write(
+        &self,
+        bucket_name: &str,
+        key: &Key,
+        value: &str,
+        subject: impl Into<String>,
+        payload: bytes::Bytes,
+    ) -> Result<u64> {
+        let bucket = self.store.get_or_create_bucket(bucket_name, None).await?;
+        let revision = match bucket.get_if_changed(key, 0).await? {
+            Conditional::Modified { revision, .. } => revision,
+            _ => 0,
+        };
+        let intent = WriteIntent {
+            bucket: bucket_name.to_string(),
+            key: key.to_string(),
+            value: value.to_string(),
+            revision,
+            subject: subject.into(),
+            payload: BASE64.encode(&payload),
+            created_at: self.store.clock().unix_millis(),
+        };
+        let outbox = self.store.get_or_create_bucket(OUTBOX_BUCKET, None).await?;
+        let id = uuid::Uuid::new_v4().simple();
+        let intent_key = Key::from_raw(format!("{:013}-{id}", intent.created_at));
+        outbox
+            .insert(&intent_key, &serde_json::to_string(&intent)?, 0)
+            .await?;
+
+        let revision = match bucket.compare_and_swap(key, value, revision).await {
+            Ok(StoreOutcome::Created(revision) | StoreOutcome::Exists(revision)) => revision,
+            Err(StoreError::Retry) => {
+                outbox.delete(&intent_key).await?;
+                return Err(StoreError::Retry.into());
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if let Err(err) = self.publish(&intent.subject, payload).await {
+            let subject = &intent.subject;
+            tracing::warn!(%err, subject, "Publishing failed, leaving it to the drainer");
+            return Ok(revision);
+        }
+        if let Err(err) = outbox.delete(&intent_key).await {
+            tracing::warn!(%err, %intent_key, "Failed to remove a finished write intent");
+        }
+        Ok(revision)
+    }
+
+    /// Finish the intents older than [`INTENT_GRACE`], oldest first. Returns how many.
+    pub async fn drain(&self) -> Result<usize> {
+        let Some(outbox) = self.store.get_bucket(OUTBOX_BUCKET).await? else {
+            return Ok(0);
+        };
+        let mut entries: Vec<_> = outbox.entries().await?.into_iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        let cutoff = self
+            .store
+            .clock()
+            .unix_millis()
+            .saturating_sub(INTENT_GRACE.as_millis() as u64);
+        let mut finished = 0;
+        for (intent_key, value) in entries {
+            let intent_key = Key::from_raw(intent_key);
+            let intent = match serde_json::from_slice::<WriteIntent>(&value) {
+                Ok(intent) if intent.created_at > cutoff => continue,
+                Ok(intent) => intent,
+                Err(err) => {
+                    tracing::warn!(%err, %intent_key, "Removing an unreadable write intent");
+                    outbox.delete(&intent_key).await?;
+                    continue;
+                }
+            };
+            let bucket = self
+                .store
+                .get_or_create_bucket(&intent.bucket, None)
+                .await?;
+            if apply(bucket.as_ref(), &intent).await? {
+                let payload = BASE64.decode(&intent.payload)?;
+                self.publish(&intent.subject, payload.into()).await?;
+            } else {
+                tracing::debug!(key = %intent.key, "A later write superseded a write intent");
+            }
+            outbox.delete(&intent_key).await?;
+            finished += 1;
+        }
+        Ok(finished)
+    }
+
+    async fn publish(&self, subject: &str, payload: bytes::Bytes) -> Result<()> {
+        let client = self.nats_client.client();
+        client.publish(subject.to_string(), payload).await?;
+        client.flush().await?;
+        Ok(())
+    }
+}
+
+/// Write the intent's record if its key is still where the intent found it. Whether the key now
+/// holds the intent's value, so that its message should be sent.
+async fn apply(bucket: &dyn KeyValueBucket, intent: &WriteIntent) -> Result<bool> {
+    let key = Key::from_raw(intent.key.clone());
+    match bucket
+        .compare_and_swap(&key, &intent.value, intent.revision)
+        .await
+    {
+        Ok(_) => Ok(true),
+        Err(StoreError::Retry) => {
+            let current = bucket.get(&key).await?;
+            Ok(current.as_deref() == Some(intent.value.as_bytes()))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+async fn drain_outbox(outbox: Arc<Outbox>, cancel_token: CancellationToken) {
+    let mut ticker = tokio::time::interval(DRAIN_INTERVAL);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = cancel_token.cancelled() => return,
+        }
+        match outbox.drain().await {
+            Ok(0) => {}
+            Ok(finished) => tracing::info!(finished, "Finished abandoned write intents"),
+            Err(err) => tracing::warn!(%err, "Draining the outbox failed, retrying later"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn intent(value: &str, revision: u64) -> WriteIntent {
+        WriteIntent {
+            bucket: "v1/models".to_string(),
+            key: "llama".to_string(),
+            value: value.to_string(),
+            revision,
+            subject: "models.changed".to_string(),
+            payload: BASE64.encode("llama"),
+            created_at: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_apply() {
+        let store = KeyValueStoreManager::memory();
+        let bucket = store.get_or_create_bucket("v1/models", None).await.unwrap();
+        let key = Key::from_raw("llama".to_string());
+
+        // Abandoned before the record was written
+        assert!(apply(bucket.as_ref(), &intent("v1", 0)).await.unwrap());
+        assert_eq!(bucket.get(&key).await.unwrap().unwrap(), "v1".as_bytes());
+        // Abandoned after, still the key's value
+        assert!(apply(bucket.as_ref(), &intent("v1", 0)).await.unwrap());
+
+        // A later write went first, the older value is neither written nor announced
+        let current = bucket.get_if_changed(&key, 0).await.unwrap();
+        let Conditional::Modified { revision, .. } = current else {
+            panic!("llama is missing");
+        };
+        bucket.compare_and_swap(&key, "v3", revision).await.unwrap();
+        assert!(
+            !apply(bucket.as_ref(), &intent("v2", revision))
+                .await
+                .unwrap()
+        );
+        assert_eq!(bucket.get(&key).await.unwrap().unwrap(), "v3".as_bytes());
+    }
+}
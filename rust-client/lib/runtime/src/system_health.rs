@@ -33,6 +33,19 @@ pub struct HealthCheckTarget {
     pub payload: serde_json::Value,
 }
 
+/// The Kubernetes probe a check feeds, served at `/healthz` and `/readyz`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Probe {
+    /// Failing makes Kubernetes restart the worker. For states it cannot recover from.
+    Liveness,
+    /// Failing takes the worker out of rotation until it passes again
+    Readiness,
+}
+
+/// A user check for a [`Probe`], returning why the worker is unhealthy if it is. Called on
+/// every probe request, so it should be cheap and must not block.
+pub type ProbeCheck = Arc<dyn Fn() -> Result<(), String> + Send + Sync>;
+
 /// Current Health Status
 /// If use_endpoint_health_status is set then
 /// initialize the endpoint_health hashmap to the
@@ -55,6 +68,8 @@ pub struct SystemHealth {
     live_path: String,
     start_time: Instant,
     uptime_gauge: OnceLock<prometheus::Gauge>,
+    /// User checks for `/healthz` and `/readyz`, by name
+    probe_checks: Vec<(Probe, String, ProbeCheck)>,
 }
 
 impl SystemHealth {
@@ -84,6 +99,7 @@ impl SystemHealth {
             live_path,
             start_time: Instant::now(),
             uptime_gauge: OnceLock::new(),
+            probe_checks: Vec::new(),
         }
     }
     pub fn set_health_status(&mut self, status: HealthStatus) {
@@ -266,6 +282,28 @@ impl SystemHealth {
         }
     }
 
+    /// Add a check to `probe`, replacing any earlier check with the same name
+    pub fn add_probe_check(
+        &mut self,
+        probe: Probe,
+        name: impl Into<String>,
+        check: impl Fn() -> Result<(), String> + Send + Sync + 'static,
+    ) {
+        let name = name.into();
+        self.probe_checks
+            .retain(|(p, n, _)| *p != probe || *n != name);
+        self.probe_checks.push((probe, name, Arc::new(check)));
+    }
+
+    /// The user checks for `probe`, to run without holding the lock on us
+    pub fn probe_checks(&self, probe: Probe) -> Vec<(String, ProbeCheck)> {
+        self.probe_checks
+            .iter()
+            .filter(|(p, _, _)| *p == probe)
+            .map(|(_, name, check)| (name.clone(), check.clone()))
+            .collect()
+    }
+
     /// Get the health check path
     pub fn health_path(&self) -> &str {
         &self.health_path
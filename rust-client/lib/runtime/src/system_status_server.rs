@@ -5,6 +5,7 @@ use crate::config::HealthStatus;
 use crate::logging::make_request_span;
 use crate::metrics::MetricsHierarchy;
 use crate::metrics::prometheus_names::{nats_client, nats_service};
+use crate::system_health::Probe;
 use crate::traits::DistributedRuntimeProvider;
 use axum::{Router, http::StatusCode, response::IntoResponse, routing::get};
 use serde_json::json;
@@ -15,6 +16,21 @@ use tokio::{net::TcpListener, task::JoinHandle};
 use tokio_util::sync::CancellationToken;
 use tower_http::trace::TraceLayer;
 
+/// Kubernetes liveness probe, see [`liveness_checks`]
+pub const LIVENESS_PATH: &str = "/healthz";
+
+/// Kubernetes readiness probe, see [`readiness_checks`]
+pub const READINESS_PATH: &str = "/readyz";
+
+/// Bytes per transport, endpoint and connection, see [`crate::transports::accounting`]
+pub const TRANSPORT_DIAGNOSTICS_PATH: &str = "/diagnostics/transport";
+
+/// The runtime's supervised background tasks, see [`crate::utils::tasks::supervisor`]
+pub const TASK_DIAGNOSTICS_PATH: &str = "/diagnostics/tasks";
+
+#[cfg(feature = "profiling")]
+pub mod profiling;
+
 /// System status server information containing socket address and handle
 #[derive(Debug)]
 pub struct SystemStatusServerInfo {
@@ -92,7 +108,37 @@ pub async fn spawn_system_status_server(
         .live_path()
         .to_string();
 
-    let app = Router::new()
+    let mut app = Router::new();
+    // Unless the configured paths already took them
+    if health_path != LIVENESS_PATH && live_path != LIVENESS_PATH {
+        let state = Arc::clone(&server_state);
+        app = app.route(
+            LIVENESS_PATH,
+            get(move || probe_handler(state, Probe::Liveness)),
+        );
+    }
+    if health_path != READINESS_PATH && live_path != READINESS_PATH {
+        let state = Arc::clone(&server_state);
+        app = app.route(
+            READINESS_PATH,
+            get(move || probe_handler(state, Probe::Readiness)),
+        );
+    }
+
+    #[cfg(feature = "profiling")]
+    {
+        app = app
+            .route(
+                profiling::CPU_PROFILE_PATH,
+                get(profiling::cpu_profile_handler),
+            )
+            .route(
+                profiling::HEAP_PROFILE_PATH,
+                get(profiling::heap_profile_handler),
+            );
+    }
+
+    let app = app
         .route(
             &health_path,
             get({
@@ -114,6 +160,14 @@ pub async fn spawn_system_status_server(
                 move || metrics_handler(state)
             }),
         )
+        .route(TRANSPORT_DIAGNOSTICS_PATH, get(transport_handler))
+        .route(
+            TASK_DIAGNOSTICS_PATH,
+            get({
+                let state = Arc::clone(&server_state);
+                move || tasks_handler(state)
+            }),
+        )
         .fallback(|| async {
             tracing::info!("[fallback handler] called");
             (StatusCode::NOT_FOUND, "Route not found").into_response()
@@ -180,6 +234,122 @@ async fn health_handler(state: Arc<SystemStatusState>) -> impl IntoResponse {
     (status_code, response.to_string())
 }
 
+/// The outcome of every check behind a probe, by name
+type ProbeResults = Vec<(String, Result<(), String>)>;
+
+/// Whether the worker should be restarted: it is shutting down or has lost its etcd lease, so
+/// it no longer appears in discovery and never will again. Plus the user's liveness checks.
+pub fn liveness_checks(drt: &crate::DistributedRuntime) -> ProbeResults {
+    let mut results = Vec::new();
+    let runtime = if drt.primary_token().is_cancelled() {
+        Err("shutting down".to_string())
+    } else {
+        Ok(())
+    };
+    results.push(("runtime".to_string(), runtime));
+    if let Some(lease) = drt.primary_lease() {
+        let valid = if lease.primary_token().is_cancelled() {
+            Err(format!(
+                "primary lease {:x} expired or was revoked",
+                lease.id()
+            ))
+        } else {
+            Ok(())
+        };
+        results.push(("lease".to_string(), valid));
+    }
+    results.extend(user_checks(drt, Probe::Liveness));
+    results
+}
+
+/// Whether the worker should get traffic: it is live, connected to etcd and NATS, and its
+/// endpoints pass their health checks. Plus the user's readiness checks.
+pub fn readiness_checks(drt: &crate::DistributedRuntime) -> ProbeResults {
+    let mut results = liveness_checks(drt);
+    if let Some(offline) = drt.offline() {
+        let connected = match offline.client() {
+            Some(_) => Ok(()),
+            None => Err("not connected yet, registrations are queued".to_string()),
+        };
+        results.push(("etcd".to_string(), connected));
+    }
+    if let Some(nats) = drt.nats_client() {
+        let connected = match nats.client().connection_state() {
+            async_nats::connection::State::Connected => Ok(()),
+            state => Err(format!("connection {state:?}")),
+        };
+        results.push(("nats".to_string(), connected));
+    }
+    for (name, nats) in drt
+        .nats_clusters()
+        .into_iter()
+        .flat_map(|clusters| clusters.clients())
+    {
+        let connected = match nats.client().connection_state() {
+            async_nats::connection::State::Connected => Ok(()),
+            state => Err(format!("connection {state:?}")),
+        };
+        results.push((format!("nats:{name}"), connected));
+    }
+    let (healthy, endpoints) = drt.system_health.lock().get_health_status();
+    let pipeline = if healthy {
+        Ok(())
+    } else {
+        let mut failing: Vec<_> = endpoints
+            .into_iter()
+            .filter(|(_, status)| status != "ready")
+            .map(|(endpoint, _)| endpoint)
+            .collect();
+        failing.sort();
+        Err(format!("not ready: {}", failing.join(", ")))
+    };
+    results.push(("endpoints".to_string(), pipeline));
+    results.extend(user_checks(drt, Probe::Readiness));
+    results
+}
+
+fn user_checks(drt: &crate::DistributedRuntime, probe: Probe) -> ProbeResults {
+    // Don't hold the health lock while user code runs
+    let checks = drt.system_health.lock().probe_checks(probe);
+    checks
+        .into_iter()
+        .map(|(name, check)| (name, check()))
+        .collect()
+}
+
+/// `/healthz` and `/readyz`: 200 if every check passes, 503 otherwise, with each check's
+/// outcome in the body
+#[tracing::instrument(skip(state), level = "trace")]
+async fn probe_handler(state: Arc<SystemStatusState>, probe: Probe) -> impl IntoResponse {
+    let results = match probe {
+        Probe::Liveness => liveness_checks(state.drt()),
+        Probe::Readiness => readiness_checks(state.drt()),
+    };
+    let ok = results.iter().all(|(_, result)| result.is_ok());
+    let checks: serde_json::Map<_, _> = results
+        .into_iter()
+        .map(|(name, result)| {
+            (
+                name,
+                json!(result.err().unwrap_or_else(|| "ok".to_string())),
+            )
+        })
+        .collect();
+    if !ok {
+        tracing::debug!(?probe, ?checks, "Probe failed");
+    }
+    let (status_code, status) = if ok {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "failed")
+    };
+    let response = json!({
+        "status": status,
+        "checks": checks,
+    });
+    (status_code, response.to_string())
+}
+
 /// Metrics handler with DistributedRuntime uptime
 #[tracing::instrument(skip_all, level = "trace")]
 async fn metrics_handler(state: Arc<SystemStatusState>) -> impl IntoResponse {
@@ -205,6 +375,15 @@ async fn metrics_handler(state: Arc<SystemStatusState>) -> impl IntoResponse {
     (StatusCode::OK, response)
 }
 
+/// The bytes of every transport, see [`crate::transports::accounting::snapshot`]
+async fn transport_handler() -> impl IntoResponse {
+    axum::Json(crate::transports::accounting::snapshot())
+}
+
+async fn tasks_handler(state: Arc<SystemStatusState>) -> impl IntoResponse {
+    axum::Json(state.drt().runtime().supervisor().snapshot())
+}
+
 // Regular tests: cargo test system_status_server --lib
 #[cfg(test)]
 mod tests {
@@ -663,6 +842,8 @@ mod integration_tests {
                 for (path, expect_200, expect_body) in [
                     ("/health", true, "ready"),
                     ("/live", true, "ready"),
+                    (TRANSPORT_DIAGNOSTICS_PATH, true, "\"connections\""),
+                    (TASK_DIAGNOSTICS_PATH, true, "\"name\":\"runtime\""),
                     ("/someRandomPathNotFoundHere", false, "Route not found"),
                 ] {
                     println!("[test] Sending request to {}", path);
@@ -692,6 +873,67 @@ mod integration_tests {
         .await;
     }
 
+    #[tokio::test]
+    async fn test_probe_endpoints_with_user_checks() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        temp_env::async_with_vars(
+            [
+                ("DYN_SYSTEM_ENABLED", Some("true")),
+                ("DYN_SYSTEM_PORT", Some("0")),
+                ("DYN_SYSTEM_STARTING_HEALTH_STATUS", Some("ready")),
+            ],
+            async {
+                let drt = Arc::new(create_test_drt_async().await);
+                let addr = drt.system_status_server_info().unwrap().socket_addr;
+                let client = reqwest::Client::new();
+                let get = |path: &'static str| {
+                    let request = client.get(format!("http://{addr}{path}")).send();
+                    async move {
+                        let response = request.await.unwrap();
+                        let status = response.status().as_u16();
+                        let body: serde_json::Value = response.json().await.unwrap();
+                        (status, body)
+                    }
+                };
+
+                let (status, body) = get(LIVENESS_PATH).await;
+                assert_eq!(status, 200, "{body}");
+                assert_eq!(body["checks"]["runtime"], "ok");
+                let (status, body) = get(READINESS_PATH).await;
+                assert_eq!(status, 200, "{body}");
+                assert_eq!(body["checks"]["endpoints"], "ok");
+
+                // A failing readiness check leaves the worker live
+                let warm = Arc::new(AtomicBool::new(false));
+                let check = warm.clone();
+                drt.add_probe_check(Probe::Readiness, "cache", move || {
+                    if check.load(Ordering::Relaxed) {
+                        Ok(())
+                    } else {
+                        Err("cold".to_string())
+                    }
+                });
+                let (status, body) = get(READINESS_PATH).await;
+                assert_eq!(status, 503, "{body}");
+                assert_eq!(body["status"], "failed");
+                assert_eq!(body["checks"]["cache"], "cold");
+                assert_eq!(get(LIVENESS_PATH).await.0, 200);
+
+                warm.store(true, Ordering::Relaxed);
+                assert_eq!(get(READINESS_PATH).await.0, 200);
+
+                // A failing liveness check fails both
+                drt.add_probe_check(Probe::Liveness, "wedged", || Err("stuck".to_string()));
+                let (status, body) = get(LIVENESS_PATH).await;
+                assert_eq!(status, 503, "{body}");
+                assert_eq!(body["checks"]["wedged"], "stuck");
+                assert_eq!(get(READINESS_PATH).await.0, 503);
+            },
+        )
+        .await;
+    }
+
     #[cfg(feature = "integration")]
     #[tokio::test]
     async fn test_health_check_with_payload_and_timeout() {
@@ -745,6 +987,11 @@ mod integration_tests {
                             transport: crate::component::TransportType::NatsTcp(
                                 endpoint.to_string(),
                             ),
+                            worker_id: None,
+                            status: Default::default(),
+                            region: None,
+                            codec: Default::default(),
+                            signature: None,
                         },
                         health_check_payload.clone(),
                     );
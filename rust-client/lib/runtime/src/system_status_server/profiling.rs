@@ -0,0 +1,178 @@
+// SPDX-FileCopyrightText: Copyright (c) 2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! CPU and heap profiles of a running worker, taken without restarting it.
+//!
+//! Built with the `profiling` feature, the system status server answers:
+//!
+//! - [`CPU_PROFILE_PATH`]`?seconds=30`: where the threads of the process spend their CPU time
+//!   over the next `seconds`, at most [`MAX_PROFILE_SECONDS`], as a pprof protobuf. With
+//!   `&format=flamegraph`, as a flamegraph SVG instead. One profile runs at a time, and another
+//!   request meanwhile gets a 409.
+//! - [`HEAP_PROFILE_PATH`]: the allocations jemalloc sampled that are still live, as a pprof
+//!   protobuf. Only when the binary allocates with `tikv-jemallocator`, started with profiling on
+//!   (`_RJEM_MALLOC_CONF=prof:true,prof_active:true,lg_prof_sample:19`), otherwise a 501.
+//!
+//! ```text
+//! go tool pprof -http :8000 'http://worker:8081/debug/pprof/profile?seconds=20'
+//! curl -o flame.svg 'http://worker:8081/debug/pprof/profile?seconds=20&format=flamegraph'
+//! ```
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use axum::extract::Query;
+use axum::http::{StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use pprof::protos::Message;
+use serde::Deserialize;
+
+pub const CPU_PROFILE_PATH: &str = "/debug/pprof/profile";
+
+pub const HEAP_PROFILE_PATH: &str = "/debug/pprof/heap";
+
+pub const MAX_PROFILE_SECONDS: u64 = 300;
+
+const DEFAULT_PROFILE_SECONDS: u64 = 30;
+
+/// Samples per second. Not 100, so sampling doesn't line up with work done on round intervals.
+const SAMPLE_FREQUENCY: i32 = 99;
+
+const PPROF_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// The profiler is process wide
+static PROFILING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Format {
+    #[default]
+    Pprof,
+    Flamegraph,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct CpuProfileQuery {
+    seconds: Option<u64>,
+    #[serde(default)]
+    format: Format,
+}
+
+impl CpuProfileQuery {
+    fn duration(&self) -> Result<Duration, String> {
+        match self.seconds.unwrap_or(DEFAULT_PROFILE_SECONDS) {
+            seconds @ 1..=MAX_PROFILE_SECONDS => Ok(Duration::from_secs(seconds)),
+            seconds => Err(format!(
+                "seconds must be 1 to {MAX_PROFILE_SECONDS}, not {seconds}"
+            )),
+        }
+    }
+}
+
+/// Clears [`PROFILING`] when the profile is done, even if the client went away before
+struct Running;
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        PROFILING.store(false, Ordering::Release);
+    }
+}
+
+pub(super) async fn cpu_profile_handler(Query(query): Query<CpuProfileQuery>) -> Response {
+    let duration = match query.duration() {
+        Ok(duration) => duration,
+        Err(err) => return (StatusCode::BAD_REQUEST, err).into_response(),
+    };
+    if PROFILING.swap(true, Ordering::AcqRel) {
+        let busy = "A CPU profile is being taken already";
+        return (StatusCode::CONFLICT, busy).into_response();
+    }
+    let running = Running;
+    tracing::info!(?duration, format = ?query.format, "Taking a CPU profile");
+    let profiled = tokio::task::spawn_blocking(move || {
+        let profile = profile_cpu(duration, query.format);
+        drop(running);
+        profile
+    })
+    .await;
+    match profiled {
+        Ok(Ok((content_type, body))) => {
+            ([(header::CONTENT_TYPE, content_type)], body).into_response()
+        }
+        Ok(Err(err)) => {
+            tracing::warn!(%err, "CPU profile failed");
+            let message = format!("CPU profile failed: {err}");
+            (StatusCode::INTERNAL_SERVER_ERROR, message).into_response()
+        }
+        Err(err) => {
+            let message = format!("CPU profile failed: {err}");
+            (StatusCode::INTERNAL_SERVER_ERROR, message).into_response()
+        }
+    }
+}
+
+/// Blocks for `duration`
+fn profile_cpu(duration: Duration, format: Format) -> anyhow::Result<(&'static str, Vec<u8>)> {
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(SAMPLE_FREQUENCY)
+        // Unwinding through these can crash the profiler's signal handler
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()?;
+    std::thread::sleep(duration);
+    let report = guard.report().build()?;
+    match format {
+        Format::Pprof => Ok((PPROF_CONTENT_TYPE, report.pprof()?.encode_to_vec())),
+        Format::Flamegraph => {
+            let mut svg = Vec::new();
+            report.flamegraph(&mut svg)?;
+            Ok(("image/svg+xml", svg))
+        }
+    }
+}
+
+pub(super) async fn heap_profile_handler() -> Response {
+    let Some(prof_ctl) = jemalloc_pprof::PROF_CTL.as_ref() else {
+        let message = "Heap profiles need jemalloc with profiling as the allocator";
+        return (StatusCode::NOT_IMPLEMENTED, message).into_response();
+    };
+    let mut prof_ctl = prof_ctl.lock().await;
+    if !prof_ctl.activated() {
+        let message = "Heap profiling is off, start with \
+                       _RJEM_MALLOC_CONF=prof:true,prof_active:true,lg_prof_sample:19";
+        return (StatusCode::NOT_IMPLEMENTED, message).into_response();
+    }
+    match prof_ctl.dump_pprof() {
+        Ok(profile) => ([(header::CONTENT_TYPE, PPROF_CONTENT_TYPE)], profile).into_response(),
+        Err(err) => {
+            tracing::warn!(%err, "Heap profile failed");
+            let message = format!("Heap profile failed: {err}");
+            (StatusCode::INTERNAL_SERVER_ERROR, message).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cpu_profile_query() {
+        let query = |uri: &str| {
+            let uri: axum::http::Uri = uri.parse().unwrap();
+            Query::<CpuProfileQuery>::try_from_uri(&uri).map(|Query(query)| query)
+        };
+        let default = query("/debug/pprof/profile").unwrap();
+        assert_eq!(default.duration(), Ok(Duration::from_secs(30)));
+        assert_eq!(default.format, Format::Pprof);
+
+        let flamegraph = query("/debug/pprof/profile?seconds=5&format=flamegraph").unwrap();
+        assert_eq!(flamegraph.duration(), Ok(Duration::from_secs(5)));
+        assert_eq!(flamegraph.format, Format::Flamegraph);
+
+        for seconds in [0, 301] {
+            let uri = format!("/debug/pprof/profile?seconds={seconds}");
+            assert!(query(&uri).unwrap().duration().is_err());
+        }
+        assert!(query("/debug/pprof/profile?format=svg").is_err());
+    }
+}
@@ -0,0 +1,402 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Multi-worker tests without etcd, NATS or sockets.
+//!
+//! An [`InProcessCluster`] is a set of [`DistributedRuntime`]s that register in one shared
+//! [`MemoryStore`] and send requests over one [`InProcessNetwork`]. Endpoints, clients and
+//! routers are the regular ones, so serving, discovery, routing and failover can be tested
+//! across workers in a plain `#[tokio::test]`.
+//!
+//! ```ignore
+//! let cluster = InProcessCluster::new(3)?;
+//! // serve on workers 0 and 1, route from worker 2
+//! cluster.crash(0);
+//! cluster.expire(0).await?;
+//! ```
+//!
+//! [`etcd::EtcdCluster`] runs real etcd members as child processes instead, and [`scenario`]
+//! scripts timed failures against either. [`versions`] runs one etcd scenario against several
+//! etcd releases to compare how leases and watches fare.
+
+pub mod etcd;
+pub mod scenario;
+pub mod versions;
+
+use crate::component::INSTANCE_ROOT_PATH;
+use crate::pipeline::network::in_process::InProcessNetwork;
+use crate::storage::key_value_store::{Key, MemoryStore};
+use crate::{DistributedRuntime, Result, Runtime};
+
+pub struct InProcessCluster {
+    network: InProcessNetwork,
+    store: MemoryStore,
+    workers: Vec<DistributedRuntime>,
+}
+
+impl InProcessCluster {
+    /// A cluster of `size` workers. Must be called from within a tokio runtime.
+    pub fn new(size: usize) -> Result<Self> {
+        let mut cluster = InProcessCluster {
+            network: InProcessNetwork::new(),
+            store: MemoryStore::new(),
+            workers: Vec::with_capacity(size),
+        };
+        for _ in 0..size {
+            cluster.add_worker()?;
+        }
+        Ok(cluster)
+    }
+
+    /// Start another worker, returning its index. Each worker has its own [`Runtime`], so
+    /// shutting one down leaves the others running.
+    pub fn add_worker(&mut self) -> Result<usize> {
+        let runtime = Runtime::from_current()?;
+        let network = self.network.join();
+        let drt = DistributedRuntime::in_process(runtime, network, self.store.clone())?;
+        self.workers.push(drt);
+        Ok(self.workers.len() - 1)
+    }
+
+    /// Panics if there is no worker `index`
+    pub fn worker(&self, index: usize) -> &DistributedRuntime {
+        &self.workers[index]
+    }
+
+    pub fn workers(&self) -> &[DistributedRuntime] {
+        &self.workers
+    }
+
+    /// The ID worker `index` registers its endpoints under, in place of an etcd lease ID
+    pub fn instance_id(&self, index: usize) -> u64 {
+        self.worker(index)
+            .in_process_network()
+            .map(|n| n.instance_id())
+            .expect("in-process cluster worker without a network")
+    }
+
+    /// The store all workers register in
+    pub fn store(&self) -> &MemoryStore {
+        &self.store
+    }
+
+    /// Shut worker `index` down gracefully: its endpoints finish their inflight requests and
+    /// deregister
+    pub fn shutdown(&self, index: usize) {
+        self.worker(index).shutdown();
+    }
+
+    /// Cut worker `index` off, as if its process died. Requests to it fail straight away with
+    /// [`NoResponders`](crate::pipeline::network::in_process::NoResponders), but its
+    /// registrations stay until [`InProcessCluster::expire`].
+    pub fn crash(&self, index: usize) {
+        self.network.disconnect(self.instance_id(index));
+    }
+
+    /// Make requests to worker `index` fail as they do after [`InProcessCluster::crash`], until
+    /// [`InProcessCluster::heal`]. Unlike a crash its endpoints keep serving, so nothing needs to
+    /// start again.
+    pub fn partition(&self, index: usize) {
+        self.network.partition(self.instance_id(index), true);
+    }
+
+    pub fn heal(&self, index: usize) {
+        self.network.partition(self.instance_id(index), false);
+    }
+
+    /// Remove the registrations of worker `index`, as etcd does when a crashed worker's lease
+    /// expires
+    pub async fn expire(&self, index: usize) -> Result<()> {
+        let suffix = format!("/{:x}", self.instance_id(index));
+        let store = self.worker(index).store();
+        let Some(bucket) = store.get_bucket(INSTANCE_ROOT_PATH).await? else {
+            return Ok(());
+        };
+        for key in bucket.entries().await?.into_keys() {
+            if key.ends_with(&suffix) {
+                bucket.delete(&Key::from_raw(key)).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for InProcessCluster {
+    fn drop(&mut self) {
+        // Stop watchers and endpoints still running on the test's runtime
+        for worker in &self.workers {
+            worker.primary_token().cancel();
+        }
+    }
+}
+
+impl std::fmt::Debug for InProcessCluster {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InProcessCluster")
+            .field("workers", &self.workers.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::component::{Client, InstanceStatus};
+    use crate::error;
+    use crate::pipeline::{
+        AsyncEngine, AsyncEngineContextProvider, Error, ManyOut, PushRouter, ResponseStream,
+        RouterMode, ServiceEngine, SingleIn, async_trait, network::Ingress,
+    };
+    use crate::protocols::annotated::Annotated;
+    use futures::StreamExt;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Answers every request with the index of the worker it runs on
+    struct WhoAmI(usize);
+
+    #[async_trait]
+    impl AsyncEngine<SingleIn<String>, ManyOut<Annotated<String>>, Error> for WhoAmI {
+        async fn generate(&self, input: SingleIn<String>) -> Result<ManyOut<Annotated<String>>> {
+            let (_, ctx) = input.into_parts();
+            let stream = futures::stream::iter([Annotated::from_data(self.0.to_string())]);
+            Ok(ResponseStream::new(Box::pin(stream), ctx.context()))
+        }
+    }
+
+    /// Panics in `generate` on "panic", and in its stream on "panic later" after one item
+    struct Panicky;
+
+    #[async_trait]
+    impl AsyncEngine<SingleIn<String>, ManyOut<Annotated<String>>, Error> for Panicky {
+        async fn generate(&self, input: SingleIn<String>) -> Result<ManyOut<Annotated<String>>> {
+            let (request, ctx) = input.into_parts();
+            let items = match request.as_str() {
+                "panic" => panic!("generate broke"),
+                "panic later" => vec!["before", "boom"],
+                _ => vec!["pong"],
+            };
+            let stream = futures::stream::iter(items).map(|item| {
+                if item == "boom" {
+                    panic!("stream broke");
+                }
+                Annotated::from_data(item.to_string())
+            });
+            Ok(ResponseStream::new(Box::pin(stream), ctx.context()))
+        }
+    }
+
+    fn serve(cluster: &InProcessCluster, index: usize) -> tokio::task::JoinHandle<Result<()>> {
+        serve_engine(cluster, index, Arc::new(WhoAmI(index)))
+    }
+
+    fn serve_engine(
+        cluster: &InProcessCluster,
+        index: usize,
+        engine: ServiceEngine<SingleIn<String>, ManyOut<Annotated<String>>>,
+    ) -> tokio::task::JoinHandle<Result<()>> {
+        let drt = cluster.worker(index).clone();
+        tokio::spawn(async move {
+            let ingress = Ingress::for_engine(engine)?;
+            let mut component = drt.namespace("cluster")?.component("backend")?;
+            component.add_stats_service().await?;
+            component
+                .endpoint("generate")
+                .endpoint_builder()
+                .handler(ingress)
+                .start()
+                .await
+        })
+    }
+
+    async fn client(cluster: &InProcessCluster, index: usize) -> Result<Client> {
+        let drt = cluster.worker(index);
+        let component = drt.namespace("cluster")?.component("backend")?;
+        component.endpoint("generate").client().await
+    }
+
+    async fn wait_for_instances(client: &Client, count: usize) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while client.instance_ids().len() != count {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| {
+            panic!(
+                "expected {count} instances, have {:?}",
+                client.instance_ids()
+            )
+        });
+    }
+
+    async fn wait_for_routable(client: &Client, expected: &[u64]) {
+        let waited = tokio::time::timeout(Duration::from_secs(5), async {
+            while client.instance_ids_avail().as_slice() != expected {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        assert!(
+            waited.is_ok(),
+            "expected {expected:?}, have {:?}",
+            client.instance_ids_avail()
+        );
+    }
+
+    async fn set_status(
+        cluster: &InProcessCluster,
+        index: usize,
+        status: InstanceStatus,
+    ) -> Result<()> {
+        let component = cluster
+            .worker(index)
+            .namespace("cluster")?
+            .component("backend")?;
+        component.endpoint("generate").set_status(status).await
+    }
+
+    async fn call(router: &PushRouter<String, Annotated<String>>) -> Result<String> {
+        let mut stream = router.round_robin("ping".to_string().into()).await?;
+        let response = stream.next().await.ok_or(error!("empty response"))?;
+        response.data.ok_or(error!("response without data"))
+    }
+
+    #[tokio::test]
+    async fn test_round_robin_and_failover() -> Result<()> {
+        let cluster = InProcessCluster::new(3)?;
+        let _servers = [serve(&cluster, 0), serve(&cluster, 1)];
+
+        let client = client(&cluster, 2).await?;
+        wait_for_instances(&client, 2).await;
+        let router = PushRouter::<String, Annotated<String>>::from_client(
+            client.clone(),
+            RouterMode::RoundRobin,
+        )
+        .await?;
+
+        let mut seen = HashSet::new();
+        for _ in 0..4 {
+            seen.insert(call(&router).await?);
+        }
+        assert_eq!(seen, HashSet::from(["0".to_string(), "1".to_string()]));
+
+        // The first request to land on the crashed worker fails and takes it out of rotation
+        cluster.crash(0);
+        let mut failures = 0;
+        for _ in 0..4 {
+            match call(&router).await {
+                Ok(worker) => assert_eq!(worker, "1"),
+                Err(_) => failures += 1,
+            }
+        }
+        assert_eq!(failures, 1);
+        assert_eq!(
+            client.instance_ids_avail().as_slice(),
+            &[cluster.instance_id(1)]
+        );
+
+        // It is still registered until its "lease" expires
+        assert_eq!(client.instance_ids().len(), 2);
+        cluster.expire(0).await?;
+        wait_for_instances(&client, 1).await;
+        assert_eq!(client.instance_ids(), vec![cluster.instance_id(1)]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_draining_and_deprecated_instances() -> Result<()> {
+        let cluster = InProcessCluster::new(3)?;
+        let _servers = [serve(&cluster, 0), serve(&cluster, 1)];
+        let client = client(&cluster, 2).await?;
+        wait_for_instances(&client, 2).await;
+        let router = PushRouter::<String, Annotated<String>>::from_client(
+            client.clone(),
+            RouterMode::RoundRobin,
+        )
+        .await?;
+
+        // A draining instance stays registered but gets no new requests
+        set_status(&cluster, 0, InstanceStatus::Draining).await?;
+        wait_for_routable(&client, &[cluster.instance_id(1)]).await;
+        assert_eq!(client.instance_ids().len(), 2);
+        for _ in 0..4 {
+            assert_eq!(call(&router).await?, "1");
+        }
+
+        // A deprecated one only gets requests while nothing active is left
+        set_status(&cluster, 1, InstanceStatus::Deprecated).await?;
+        assert_eq!(call(&router).await?, "1");
+        set_status(&cluster, 0, InstanceStatus::Active).await?;
+        wait_for_routable(&client, &[cluster.instance_id(0)]).await;
+        for _ in 0..4 {
+            assert_eq!(call(&router).await?, "0");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_deregisters() -> Result<()> {
+        let cluster = InProcessCluster::new(2)?;
+        let server = serve(&cluster, 0);
+
+        let client = client(&cluster, 1).await?;
+        wait_for_instances(&client, 1).await;
+
+        cluster.shutdown(0);
+        wait_for_instances(&client, 0).await;
+        server.await??;
+
+        let bucket = cluster
+            .worker(1)
+            .store()
+            .get_bucket(INSTANCE_ROOT_PATH)
+            .await?;
+        let entries = match bucket {
+            Some(bucket) => bucket.entries().await?,
+            None => Default::default(),
+        };
+        assert!(entries.is_empty(), "left behind: {entries:?}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_handler_panics_fail_only_their_request() -> Result<()> {
+        let cluster = InProcessCluster::new(2)?;
+        let _server = serve_engine(&cluster, 0, Arc::new(Panicky));
+
+        let client = client(&cluster, 1).await?;
+        wait_for_instances(&client, 1).await;
+        let router = PushRouter::<String, Annotated<String>>::from_client(
+            client.clone(),
+            RouterMode::RoundRobin,
+        )
+        .await?;
+
+        let err = router.round_robin("panic".to_string().into()).await.err();
+        let err = err.expect("a panic in generate fails the request");
+        assert!(err.to_string().contains("generate broke"), "{err}");
+
+        let mut stream = router.round_robin("panic later".to_string().into()).await?;
+        let first = stream.next().await.ok_or(error!("empty response"))?;
+        assert_eq!(first.data.as_deref(), Some("before"));
+        let last = stream
+            .next()
+            .await
+            .ok_or(error!("no error after the panic"))?;
+        let err = last
+            .into_result()
+            .expect_err("a panic in the stream ends it with an error");
+        assert!(err.to_string().contains("stream broke"), "{err}");
+        assert!(stream.next().await.is_none());
+
+        // The handler, and the instance, keep serving
+        assert_eq!(call(&router).await?, "pong");
+        assert_eq!(
+            client.instance_ids_avail().as_slice(),
+            &[cluster.instance_id(0)]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A real etcd cluster on localhost, one child process per member, to break on purpose.
+//!
+//! Members can be killed, restarted and paused (SIGSTOP, which looks like a partition to the
+//! rest of the cluster and to clients: connections stay open but nothing answers). Data lives
+//! in a temporary directory that is removed, and the processes killed, on drop.
+
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use tokio::process::{Child, Command};
+use tokio::sync::Mutex;
+
+use crate::{ErrorContext, Result, error};
+
+/// How long a new cluster gets to elect a leader
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Per-request timeout when asking members for their status
+const STATUS_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub struct EtcdCluster {
+    binary: PathBuf,
+    dir: PathBuf,
+    initial_cluster: String,
+    members: Vec<Member>,
+}
+
+struct Member {
+    name: String,
+    client_url: String,
+    peer_url: String,
+    process: Mutex<Option<Child>>,
+}
+
+impl EtcdCluster {
+    /// Start `size` members of `binary`, an etcd server executable, and wait for a leader
+    pub async fn start(binary: impl Into<PathBuf>, size: usize) -> Result<Self> {
+        if size == 0 {
+            return Err(error!("An etcd cluster needs at least one member"));
+        }
+        let dir = std::env::temp_dir().join(format!("etcd-{}", uuid::Uuid::new_v4().simple()));
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Unable to create {}", dir.display()))?;
+        let mut members = Vec::with_capacity(size);
+        for index in 0..size {
+            members.push(Member {
+                name: format!("member{index}"),
+                client_url: format!("http://127.0.0.1:{}", free_port()?),
+                peer_url: format!("http://127.0.0.1:{}", free_port()?),
+                process: Mutex::new(None),
+            });
+        }
+        let initial_cluster = members
+            .iter()
+            .map(|m| format!("{}={}", m.name, m.peer_url))
+            .collect::<Vec<_>>()
+            .join(",");
+        let cluster = EtcdCluster {
+            binary: binary.into(),
+            dir,
+            initial_cluster,
+            members,
+        };
+        for index in 0..size {
+            cluster.restart(index).await?;
+        }
+        tokio::time::timeout(STARTUP_TIMEOUT, async {
+            while cluster.leader().await.is_err() {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .map_err(|_| error!("etcd cluster elected no leader in {STARTUP_TIMEOUT:?}"))?;
+        Ok(cluster)
+    }
+
+    pub fn binary(&self) -> &Path {
+        &self.binary
+    }
+
+    pub fn size(&self) -> usize {
+        self.members.len()
+    }
+
+    /// The client URLs of every member, for [`ClientOptions::etcd_url`]
+    ///
+    /// [`ClientOptions::etcd_url`]: crate::transports::etcd::ClientOptions::etcd_url
+    pub fn client_urls(&self) -> Vec<String> {
+        self.members.iter().map(|m| m.client_url.clone()).collect()
+    }
+
+    /// The index of the member that is leader now, by asking each running member
+    pub async fn leader(&self) -> Result<usize> {
+        let mut ids = Vec::with_capacity(self.members.len());
+        let mut leader = None;
+        for member in &self.members {
+            let status = tokio::time::timeout(STATUS_TIMEOUT, async {
+                let mut client = etcd_client::Client::connect([&member.client_url], None).await?;
+                client.status().await
+            })
+            .await;
+            match status {
+                Ok(Ok(status)) => {
+                    ids.push(status.header().map(|h| h.member_id()));
+                    leader = leader.or(Some(status.leader()).filter(|id| *id != 0));
+                }
+                _ => ids.push(None),
+            }
+        }
+        let leader = leader.ok_or_else(|| error!("No running etcd member knows of a leader"))?;
+        ids.iter()
+            .position(|id| *id == Some(leader))
+            .ok_or_else(|| error!("The etcd leader {leader:x} is not running"))
+    }
+
+    /// Kill member `index` with SIGKILL. Its data is kept for [`EtcdCluster::restart`].
+    pub async fn kill(&self, index: usize) -> Result<()> {
+        let member = self.member(index)?;
+        if let Some(mut process) = member.process.lock().await.take() {
+            process.kill().await?;
+        }
+        Ok(())
+    }
+
+    /// Start member `index` again, or for the first time, killing it first if it runs
+    pub async fn restart(&self, index: usize) -> Result<()> {
+        self.kill(index).await?;
+        let member = self.member(index)?;
+        let process = Command::new(&self.binary)
+            .arg("--name")
+            .arg(&member.name)
+            .arg("--data-dir")
+            .arg(self.dir.join(&member.name))
+            .args(["--listen-client-urls", &member.client_url])
+            .args(["--advertise-client-urls", &member.client_url])
+            .args(["--listen-peer-urls", &member.peer_url])
+            .args(["--initial-advertise-peer-urls", &member.peer_url])
+            .args(["--initial-cluster", &self.initial_cluster])
+            .args(["--initial-cluster-state", "new"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Unable to start {}", self.binary.display()))?;
+        *member.process.lock().await = Some(process);
+        Ok(())
+    }
+
+    /// Stop (`true`) or continue (`false`) member `index` without killing it
+    pub async fn pause(&self, index: usize, paused: bool) -> Result<()> {
+        let member = self.member(index)?;
+        let process = member.process.lock().await;
+        let pid = process
+            .as_ref()
+            .and_then(|p| p.id())
+            .ok_or_else(|| error!("etcd member {index} is not running"))?;
+        let sent = if paused {
+            Signal::SIGSTOP
+        } else {
+            Signal::SIGCONT
+        };
+        signal::kill(Pid::from_raw(pid as i32), sent)?;
+        Ok(())
+    }
+
+    fn member(&self, index: usize) -> Result<&Member> {
+        self.members
+            .get(index)
+            .ok_or_else(|| error!("No etcd member {index}, there are {}", self.members.len()))
+    }
+}
+
+impl Drop for EtcdCluster {
+    fn drop(&mut self) {
+        for member in &mut self.members {
+            if let Some(process) = member.process.get_mut().as_mut() {
+                let _ = process.start_kill();
+            }
+        }
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+impl std::fmt::Debug for EtcdCluster {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EtcdCluster")
+            .field("binary", &self.binary)
+            .field("members", &self.client_urls())
+            .finish()
+    }
+}
+
+/// A port nothing listens on right now. Another process could take it before etcd does, which
+/// is unlikely enough on a test machine.
+fn free_port() -> Result<u16> {
+    Ok(TcpListener::bind("127.0.0.1:0")?.local_addr()?.port())
+}
@@ -0,0 +1,715 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Repeatable chaos experiments: a script of timed actions against a [`Harness`], with a probe
+//! running throughout and limits on how long each action may keep the probe failing.
+//!
+//! Scripts are TOML, or JSON for files ending in `.json`:
+//!
+//! ```toml
+//! name = "lose the etcd leader"
+//! duration = "60s"
+//!
+//! [target.etcd]
+//! members = 3
+//! binary = "/usr/local/bin/etcd"
+//!
+//! [[actions]]
+//! at = "10s"
+//! action = "kill_leader"
+//! for = "15s"
+//! recover_within = "5s"
+//!
+//! [[actions]]
+//! at = "30s"
+//! action = "partition_member"
+//! member = 2
+//! for = "20s"
+//! ```
+//!
+//! An etcd target is a fresh [`EtcdCluster`], probed with a write and a linearizable read
+//! through the runtime's etcd client. A `[target.cluster]` target with `workers = 3` is an
+//! [`InProcessCluster`] serving a test endpoint on that many workers, probed with requests
+//! routed from one more. Each target takes the [`Action`]s that make sense for it.
+//!
+//! An action has recovered at the first probe after which none fail until the next action, and
+//! `recover_within` limits how long after the action that may be. An action with `for` is undone
+//! that long after, and the undoing is measured as an action of its own.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use figment::Figment;
+use figment::providers::{Format, Json, Toml};
+use futures::StreamExt;
+use serde::{Deserialize, Deserializer};
+use tokio::time::Instant;
+
+use super::InProcessCluster;
+use super::etcd::EtcdCluster;
+use crate::pipeline::{
+    AsyncEngine, AsyncEngineContextProvider, Context, Error, ManyOut, PushRouter, ResponseStream,
+    RouterMode, SingleIn, async_trait, network::Ingress,
+};
+use crate::protocols::annotated::Annotated;
+use crate::transports::etcd::Client;
+use crate::{CancellationToken, DistributedRuntime, ErrorContext, Result, Runtime, error};
+
+/// A probe that takes longer than this has failed
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long the workers of a new [`ClusterHarness`] get to register
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+const NAMESPACE: &str = "scenario";
+const COMPONENT: &str = "backend";
+const ENDPOINT: &str = "generate";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Scenario {
+    pub name: String,
+    pub target: Target,
+    /// How long the scenario runs, from its start
+    #[serde(deserialize_with = "duration")]
+    pub duration: Duration,
+    #[serde(default = "default_probe_interval", deserialize_with = "duration")]
+    pub probe_interval: Duration,
+    #[serde(default)]
+    pub actions: Vec<TimedAction>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Target {
+    /// An [`InProcessCluster`] with `workers` serving workers
+    Cluster { workers: usize },
+    /// An [`EtcdCluster`] of `members` started from `binary`, `etcd` on the `PATH` by default
+    Etcd {
+        members: usize,
+        #[serde(default = "default_etcd_binary")]
+        binary: PathBuf,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TimedAction {
+    /// From the start of the scenario
+    #[serde(deserialize_with = "duration")]
+    pub at: Duration,
+    #[serde(flatten)]
+    pub action: Action,
+    /// Undo the action this long after
+    #[serde(default, rename = "for", deserialize_with = "optional_duration")]
+    pub lasting: Option<Duration>,
+    #[serde(default, deserialize_with = "optional_duration")]
+    pub recover_within: Option<Duration>,
+}
+
+/// Workers are numbered among those serving, from 0, and members from 0 in the order started.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    /// Cut a worker off as if its process died. Its registrations stay until `expire`.
+    Crash {
+        worker: usize,
+    },
+    /// Fail requests to a worker until `heal`, or for `for`
+    Partition {
+        worker: usize,
+    },
+    Heal {
+        worker: usize,
+    },
+    /// Remove a worker's registrations, as etcd does when the lease of a crashed worker expires
+    Expire {
+        worker: usize,
+    },
+    /// Shut a worker down gracefully
+    Shutdown {
+        worker: usize,
+    },
+    /// Start or stop workers until this many serve
+    Scale {
+        workers: usize,
+    },
+    /// Kill whichever etcd member is leader. Restarted by `for`.
+    KillLeader,
+    KillMember {
+        member: usize,
+    },
+    RestartMember {
+        member: usize,
+    },
+    /// Pause an etcd member until `heal_member`, or for `for`
+    PartitionMember {
+        member: usize,
+    },
+    HealMember {
+        member: usize,
+    },
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Crash { worker } => write!(f, "crash worker {worker}"),
+            Action::Partition { worker } => write!(f, "partition worker {worker}"),
+            Action::Heal { worker } => write!(f, "heal worker {worker}"),
+            Action::Expire { worker } => write!(f, "expire worker {worker}"),
+            Action::Shutdown { worker } => write!(f, "shut down worker {worker}"),
+            Action::Scale { workers } => write!(f, "scale to {workers} workers"),
+            Action::KillLeader => write!(f, "kill the etcd leader"),
+            Action::KillMember { member } => write!(f, "kill etcd member {member}"),
+            Action::RestartMember { member } => write!(f, "restart etcd member {member}"),
+            Action::PartitionMember { member } => write!(f, "partition etcd member {member}"),
+            Action::HealMember { member } => write!(f, "heal etcd member {member}"),
+        }
+    }
+}
+
+/// What a scenario runs against
+#[async_trait]
+pub trait Harness: Send + Sync {
+    /// Carry `action` out, returning the action that undoes it if there is one
+    async fn apply(&self, action: &Action) -> Result<Option<Action>>;
+
+    /// One round trip through the system under test
+    async fn probe(&self) -> Result<()>;
+}
+
+impl Scenario {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.is_file() {
+            return Err(error!("No scenario file {}", path.display()));
+        }
+        let figment = if path.extension().is_some_and(|ext| ext == "json") {
+            Figment::from(Json::file(path))
+        } else {
+            Figment::from(Toml::file(path))
+        };
+        figment
+            .extract()
+            .with_context(|| format!("Invalid scenario {}", path.display()))
+    }
+
+    pub fn from_toml(toml: &str) -> Result<Self> {
+        Ok(Figment::from(Toml::string(toml)).extract()?)
+    }
+
+    /// Start the target the scenario names
+    pub async fn harness(&self) -> Result<Arc<dyn Harness>> {
+        Ok(match &self.target {
+            Target::Cluster { workers } => Arc::new(ClusterHarness::start(*workers).await?),
+            Target::Etcd { members, binary } => {
+                Arc::new(EtcdHarness::start(binary.clone(), *members).await?)
+            }
+        })
+    }
+
+    /// Run the scenario against `harness`. An error if an action fails; the probe failing is
+    /// what the report is for.
+    pub async fn run(&self, harness: Arc<dyn Harness>) -> Result<ScenarioReport> {
+        let start = Instant::now();
+        let stop_probing = CancellationToken::new();
+        let prober = tokio::spawn(probe(
+            harness.clone(),
+            self.probe_interval,
+            start,
+            stop_probing.clone(),
+        ));
+        let _stop_probing = stop_probing.clone().drop_guard();
+
+        // (at, action, for, recover_within), in the order they run
+        let mut pending: Vec<_> = self
+            .actions
+            .iter()
+            .map(|a| (a.at, a.action.clone(), a.lasting, a.recover_within))
+            .collect();
+        let mut applied = Vec::new();
+        while !pending.is_empty() {
+            pending.sort_by_key(|(at, ..)| *at);
+            let (at, action, lasting, recover_within) = pending.remove(0);
+            if at >= self.duration {
+                break;
+            }
+            tokio::time::sleep_until(start + at).await;
+            tracing::info!(scenario = %self.name, ?at, %action, "Scenario action");
+            let undo = harness
+                .apply(&action)
+                .await
+                .with_context(|| format!("Failed to {action} at {at:?}"))?;
+            if let Some(lasting) = lasting {
+                let undo = undo.ok_or_else(|| error!("There is no undoing '{action}'"))?;
+                pending.push((at + lasting, undo, None, None));
+            }
+            applied.push((at, action, recover_within));
+        }
+
+        tokio::time::sleep_until(start + self.duration).await;
+        stop_probing.cancel();
+        let probes = prober.await?;
+
+        let mut actions = Vec::with_capacity(applied.len());
+        for (index, (at, action, recover_within)) in applied.iter().enumerate() {
+            let until = applied
+                .get(index + 1)
+                .map_or(self.duration, |(next, ..)| *next);
+            actions.push(ActionOutcome {
+                at: *at,
+                action: action.clone(),
+                recovery: recovery(&probes, *at, until),
+                recover_within: *recover_within,
+            });
+        }
+        Ok(ScenarioReport {
+            name: self.name.clone(),
+            probes,
+            actions,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Probe {
+    /// From the start of the scenario
+    pub offset: Duration,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ActionOutcome {
+    pub at: Duration,
+    pub action: Action,
+    /// From the action until the probe had recovered, None if it never did
+    pub recovery: Option<Duration>,
+    pub recover_within: Option<Duration>,
+}
+
+impl ActionOutcome {
+    pub fn passed(&self) -> bool {
+        match (self.recover_within, self.recovery) {
+            (None, _) => true,
+            (Some(limit), Some(recovery)) => recovery <= limit,
+            (Some(_), None) => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ScenarioReport {
+    pub name: String,
+    /// In the order they were sent
+    pub probes: Vec<Probe>,
+    pub actions: Vec<ActionOutcome>,
+}
+
+impl ScenarioReport {
+    /// Every action recovered within its limit
+    pub fn passed(&self) -> bool {
+        self.actions.iter().all(ActionOutcome::passed)
+    }
+
+    pub fn failed_probes(&self) -> usize {
+        self.probes.iter().filter(|p| p.error.is_some()).count()
+    }
+}
+
+impl fmt::Display for ScenarioReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} probes, {} failed",
+            self.name,
+            self.probes.len(),
+            self.failed_probes()
+        )?;
+        for outcome in &self.actions {
+            let recovery = match outcome.recovery {
+                Some(recovery) => format!("recovered in {recovery:.1?}"),
+                None => "did not recover".to_string(),
+            };
+            let limit = match outcome.recover_within {
+                Some(limit) if outcome.passed() => format!(" (limit {limit:?})"),
+                Some(limit) => format!(" (limit {limit:?}, FAILED)"),
+                None => String::new(),
+            };
+            write!(
+                f,
+                "\n  {:>8.1?} {}: {recovery}{limit}",
+                outcome.at, outcome.action
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// From `from` to the first probe after which none fail before `until`. None if the last probe
+/// before `until` failed.
+fn recovery(probes: &[Probe], from: Duration, until: Duration) -> Option<Duration> {
+    let window: Vec<&Probe> = probes
+        .iter()
+        .filter(|p| p.offset >= from && p.offset < until)
+        .collect();
+    match window.iter().rposition(|p| p.error.is_some()) {
+        None => Some(Duration::ZERO),
+        Some(last_failed) => window.get(last_failed + 1).map(|p| p.offset - from),
+    }
+}
+
+/// Probe `harness` every `interval` until `token` is cancelled. Probes run concurrently, so
+/// one that hangs doesn't hold up the next.
+async fn probe(
+    harness: Arc<dyn Harness>,
+    interval: Duration,
+    start: Instant,
+    token: CancellationToken,
+) -> Vec<Probe> {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut handles = Vec::new();
+    loop {
+        tokio::select! {
+            _ = token.cancelled() => break,
+            _ = ticker.tick() => {}
+        }
+        let harness = harness.clone();
+        let offset = start.elapsed();
+        handles.push(tokio::spawn(async move {
+            let error = match tokio::time::timeout(PROBE_TIMEOUT, harness.probe()).await {
+                Ok(Ok(())) => None,
+                Ok(Err(err)) => Some(err.to_string()),
+                Err(_) => Some(format!("No answer in {PROBE_TIMEOUT:?}")),
+            };
+            Probe { offset, error }
+        }));
+    }
+    let mut probes = Vec::with_capacity(handles.len());
+    for handle in handles {
+        match handle.await {
+            Ok(probe) => probes.push(probe),
+            Err(err) => tracing::warn!(%err, "Scenario probe panicked"),
+        }
+    }
+    probes
+}
+
+/// Answers every request with the request
+struct Echo;
+
+#[async_trait]
+impl AsyncEngine<SingleIn<String>, ManyOut<Annotated<String>>, Error> for Echo {
+    async fn generate(&self, input: SingleIn<String>) -> Result<ManyOut<Annotated<String>>> {
+        let (data, ctx) = input.into_parts();
+        let stream = futures::stream::iter([Annotated::from_data(data)]);
+        Ok(ResponseStream::new(Box::pin(stream), ctx.context()))
+    }
+}
+
+/// An [`InProcessCluster`] whose worker 0 routes to the workers serving the test endpoint
+pub struct ClusterHarness {
+    state: tokio::sync::Mutex<ClusterState>,
+    router: PushRouter<String, Annotated<String>>,
+}
+
+struct ClusterState {
+    cluster: InProcessCluster,
+    /// The cluster index of each serving worker, in the order they started
+    serving: Vec<usize>,
+}
+
+impl ClusterHarness {
+    /// Start with `workers` serving, and wait until the router has found them all
+    pub async fn start(workers: usize) -> Result<Self> {
+        let cluster = InProcessCluster::new(1)?;
+        let client = cluster
+            .worker(0)
+            .namespace(NAMESPACE)?
+            .component(COMPONENT)?
+            .endpoint(ENDPOINT)
+            .client()
+            .await?;
+        let router = PushRouter::from_client(client.clone(), RouterMode::RoundRobin).await?;
+        let harness = ClusterHarness {
+            state: tokio::sync::Mutex::new(ClusterState {
+                cluster,
+                serving: Vec::new(),
+            }),
+            router,
+        };
+        harness.scale(workers).await?;
+        tokio::time::timeout(STARTUP_TIMEOUT, async {
+            while client.instance_ids().len() < workers {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .map_err(|_| error!("{workers} workers did not register in {STARTUP_TIMEOUT:?}"))?;
+        Ok(harness)
+    }
+
+    /// Returns the number of workers serving before
+    async fn scale(&self, workers: usize) -> Result<usize> {
+        let mut state = self.state.lock().await;
+        let before = state.serving.len();
+        while state.serving.len() < workers {
+            let index = state.cluster.add_worker()?;
+            serve(state.cluster.worker(index).clone());
+            state.serving.push(index);
+        }
+        while state.serving.len() > workers {
+            if let Some(index) = state.serving.pop() {
+                state.cluster.shutdown(index);
+            }
+        }
+        Ok(before)
+    }
+}
+
+fn serve(drt: DistributedRuntime) {
+    tokio::spawn(async move {
+        let served = async {
+            let ingress = Ingress::for_engine(Arc::new(Echo))?;
+            let mut component = drt.namespace(NAMESPACE)?.component(COMPONENT)?;
+            component.add_stats_service().await?;
+            component
+                .endpoint(ENDPOINT)
+                .endpoint_builder()
+                .handler(ingress)
+                .start()
+                .await
+        };
+        if let Err(err) = served.await {
+            tracing::warn!(%err, "Scenario worker stopped serving");
+        }
+    });
+}
+
+#[async_trait]
+impl Harness for ClusterHarness {
+    async fn apply(&self, action: &Action) -> Result<Option<Action>> {
+        if let Action::Scale { workers } = action {
+            let before = self.scale(*workers).await?;
+            return Ok(Some(Action::Scale { workers: before }));
+        }
+        let state = self.state.lock().await;
+        let index = |worker: &usize| {
+            state
+                .serving
+                .get(*worker)
+                .copied()
+                .ok_or_else(|| error!("No worker {worker}, {} serve", state.serving.len()))
+        };
+        match action {
+            Action::Crash { worker } => state.cluster.crash(index(worker)?),
+            Action::Partition { worker } => {
+                state.cluster.partition(index(worker)?);
+                return Ok(Some(Action::Heal { worker: *worker }));
+            }
+            Action::Heal { worker } => state.cluster.heal(index(worker)?),
+            Action::Expire { worker } => state.cluster.expire(index(worker)?).await?,
+            Action::Shutdown { worker } => state.cluster.shutdown(index(worker)?),
+            other => return Err(error!("An in-process cluster can't {other}")),
+        }
+        Ok(None)
+    }
+
+    async fn probe(&self) -> Result<()> {
+        let mut stream = self
+            .router
+            .round_robin(Context::new("probe".into()))
+            .await?;
+        let mut answered = false;
+        while let Some(item) = stream.next().await {
+            item.ok().map_err(|err| error!(err))?;
+            answered = true;
+        }
+        if !answered {
+            return Err(error!("Empty response"));
+        }
+        Ok(())
+    }
+}
+
+/// An [`EtcdCluster`] and a runtime etcd client connected to all of its members
+pub struct EtcdHarness {
+    cluster: EtcdCluster,
+    client: Client,
+}
+
+impl EtcdHarness {
+    pub async fn start(binary: impl Into<PathBuf>, members: usize) -> Result<Self> {
+        let cluster = EtcdCluster::start(binary, members).await?;
+        let options = Client::builder().etcd_url(cluster.client_urls()).build()?;
+        let client = Client::new(options, Runtime::from_current()?).await?;
+        Ok(EtcdHarness { cluster, client })
+    }
+
+    pub fn cluster(&self) -> &EtcdCluster {
+        &self.cluster
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+}
+
+#[async_trait]
+impl Harness for EtcdHarness {
+    async fn apply(&self, action: &Action) -> Result<Option<Action>> {
+        Ok(match action {
+            Action::KillLeader => {
+                let member = self.cluster.leader().await?;
+                self.cluster.kill(member).await?;
+                Some(Action::RestartMember { member })
+            }
+            Action::KillMember { member } => {
+                self.cluster.kill(*member).await?;
+                Some(Action::RestartMember { member: *member })
+            }
+            Action::RestartMember { member } => {
+                self.cluster.restart(*member).await?;
+                None
+            }
+            Action::PartitionMember { member } => {
+                self.cluster.pause(*member, true).await?;
+                Some(Action::HealMember { member: *member })
+            }
+            Action::HealMember { member } => {
+                self.cluster.pause(*member, false).await?;
+                None
+            }
+            other => return Err(error!("An etcd cluster can't {other}")),
+        })
+    }
+
+    async fn probe(&self) -> Result<()> {
+        let key = format!("scenario/probe/{:x}", self.client.lease_id());
+        let value = uuid::Uuid::new_v4().to_string();
+        self.client.kv_put(&key, &value, None).await?;
+        let read = self.client.kv_get(key.as_str(), None).await?;
+        if read.first().map(|kv| kv.value()) != Some(value.as_bytes()) {
+            return Err(error!(
+                "Read back something other than was written to {key}"
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn default_probe_interval() -> Duration {
+    Duration::from_millis(100)
+}
+
+fn default_etcd_binary() -> PathBuf {
+    PathBuf::from("etcd")
+}
+
+fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let duration = String::deserialize(deserializer)?;
+    humantime::parse_duration(&duration).map_err(serde::de::Error::custom)
+}
+
+fn optional_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    match Option::<String>::deserialize(deserializer)? {
+        Some(duration) => humantime::parse_duration(&duration)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_scenario() {
+        let scenario = Scenario::from_toml(
+            r#"
+            name = "partition"
+            duration = "1m"
+
+            [target.etcd]
+            members = 3
+
+            [[actions]]
+            at = "10s"
+            action = "kill_leader"
+            recover_within = "5s"
+
+            [[actions]]
+            at = "30s"
+            action = "partition_member"
+            member = 2
+            for = "20s"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(scenario.duration, Duration::from_secs(60));
+        assert_eq!(scenario.probe_interval, default_probe_interval());
+        assert!(matches!(scenario.target, Target::Etcd { members: 3, .. }));
+        assert_eq!(scenario.actions[0].action, Action::KillLeader);
+        assert_eq!(
+            scenario.actions[0].recover_within,
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            scenario.actions[1].action,
+            Action::PartitionMember { member: 2 }
+        );
+        assert_eq!(scenario.actions[1].lasting, Some(Duration::from_secs(20)));
+
+        let unknown = "name = \"x\"\nduration = \"1s\"\n[target.cluster]\nworkers = 1\n\
+            [[actions]]\nat = \"0s\"\naction = \"reboot\"";
+        assert!(Scenario::from_toml(unknown).is_err());
+    }
+
+    #[test]
+    fn test_recovery() {
+        let probes: Vec<Probe> = [(0, true), (1, false), (2, false), (3, true), (4, true)]
+            .into_iter()
+            .map(|(secs, ok)| Probe {
+                offset: Duration::from_secs(secs),
+                error: (!ok).then(|| "down".to_string()),
+            })
+            .collect();
+        let secs = Duration::from_secs;
+        assert_eq!(recovery(&probes, secs(1), secs(5)), Some(secs(2)));
+        assert_eq!(recovery(&probes, secs(3), secs(5)), Some(Duration::ZERO));
+        assert_eq!(recovery(&probes, secs(0), secs(3)), None);
+    }
+
+    #[tokio::test]
+    async fn test_partition_worker() -> Result<()> {
+        let scenario = Scenario::from_toml(
+            r#"
+            name = "partition a worker"
+            duration = "1s"
+            probe_interval = "20ms"
+
+            [target.cluster]
+            workers = 2
+
+            [[actions]]
+            at = "200ms"
+            action = "partition"
+            worker = 0
+            for = "300ms"
+            recover_within = "200ms"
+            "#,
+        )?;
+        let report = scenario.run(scenario.harness().await?).await?;
+        assert!(report.passed(), "{report}");
+        let actions: Vec<_> = report.actions.iter().map(|a| a.action.clone()).collect();
+        assert_eq!(
+            actions,
+            [Action::Partition { worker: 0 }, Action::Heal { worker: 0 }]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,418 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! One etcd [`Scenario`] against several etcd releases, side by side.
+//!
+//! Each release gets a fresh [`EtcdHarness`] started from its binary, the `binary` of the
+//! scenario's target being ignored. While the scenario runs, an observer counts what the
+//! scenario's probe doesn't show:
+//!
+//! - lease expiries: a lease with a short TTL is kept alive by the runtime's keep-alive loop,
+//!   and granted again each time etcd reports it gone
+//! - keep-alive errors: failures sending a heartbeat or receiving its response
+//! - watch gaps: a counter is written every [`WRITE_INTERVAL`], and a runtime watcher on it
+//!   counts the values it never saw. A watch that closes is a restart, and opened again.
+//!
+//! ```ignore
+//! let scenario = Scenario::from_file("kill-leader.toml")?;
+//! let binaries = ["/opt/etcd-3.4/etcd".into(), "/opt/etcd-3.5/etcd".into()];
+//! let comparison = compare(&scenario, &binaries).await?;
+//! println!("{comparison}");
+//! ```
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use tokio::process::Command;
+use tokio::task::JoinHandle;
+
+use super::scenario::{EtcdHarness, Scenario, ScenarioReport, Target};
+use crate::transports::etcd::{
+    Client, EtcdHeartbeat, LeaseClient, LeaseHeartbeat, Progress, WatchEvent, run_keep_alive,
+};
+use crate::{CancellationToken, ErrorContext, Result, error};
+
+/// TTL of the observed lease, in seconds. Short, so that an outage of a few seconds costs it.
+const LEASE_TTL: u64 = 3;
+
+/// How often the watched counter is written
+pub const WRITE_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Limit on each etcd request of the observer, so that a paused member can't stall it
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Pause before trying again after a failed request
+const RETRY_DELAY: Duration = Duration::from_millis(200);
+
+const WATCH_KEY: &str = "scenario/versions/counter";
+
+/// What the observer counted during one run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Observed {
+    pub lease_expiries: u64,
+    pub keep_alive_errors: u64,
+    /// Counter values written but never seen by the watcher
+    pub watch_gaps: u64,
+    pub watch_restarts: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReleaseRun {
+    /// As reported by `etcd --version`
+    pub version: String,
+    pub binary: PathBuf,
+    pub report: ScenarioReport,
+    pub observed: Observed,
+}
+
+#[derive(Debug, Clone)]
+pub struct Comparison {
+    pub scenario: String,
+    /// In the order the binaries were given
+    pub runs: Vec<ReleaseRun>,
+}
+
+/// Run `scenario` once against each etcd server in `binaries`, one after the other
+pub async fn compare(scenario: &Scenario, binaries: &[PathBuf]) -> Result<Comparison> {
+    let Target::Etcd { members, .. } = scenario.target else {
+        return Err(error!("Scenario '{}' does not target etcd", scenario.name));
+    };
+    let mut runs = Vec::with_capacity(binaries.len());
+    for binary in binaries {
+        let version = etcd_version(binary).await?;
+        tracing::info!(scenario = %scenario.name, %version, "Running scenario against etcd");
+        let harness = Arc::new(EtcdHarness::start(binary.clone(), members).await?);
+        let observer = Observer::start(harness.client());
+        let report = scenario
+            .run(harness.clone())
+            .await
+            .with_context(|| format!("Scenario failed against etcd {version}"))?;
+        runs.push(ReleaseRun {
+            version,
+            binary: binary.clone(),
+            report,
+            observed: observer.stop().await,
+        });
+    }
+    Ok(Comparison {
+        scenario: scenario.name.clone(),
+        runs,
+    })
+}
+
+/// The version an etcd server binary reports, such as `3.5.9`
+pub async fn etcd_version(binary: &Path) -> Result<String> {
+    let output = Command::new(binary)
+        .arg("--version")
+        .output()
+        .await
+        .with_context(|| format!("Unable to run {}", binary.display()))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("etcd Version:"))
+        .map(|version| version.trim().to_string())
+        .ok_or_else(|| error!("{} reports no etcd version", binary.display()))
+}
+
+/// A row of the [`Comparison`] table: its name, and its cell for a run
+type Row = (&'static str, fn(&ReleaseRun) -> String);
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rows: [Row; 7] = [
+            ("lease expiries", |r| r.observed.lease_expiries.to_string()),
+            ("keep-alive errors", |r| {
+                r.observed.keep_alive_errors.to_string()
+            }),
+            ("watch gaps", |r| r.observed.watch_gaps.to_string()),
+            ("watch restarts", |r| r.observed.watch_restarts.to_string()),
+            ("probes", |r| r.report.probes.len().to_string()),
+            ("failed probes", |r| r.report.failed_probes().to_string()),
+            ("recovered in time", |r| {
+                String::from(if r.report.passed() { "yes" } else { "no" })
+            }),
+        ];
+        write!(f, "{}\n{:<20}", self.scenario, "etcd")?;
+        for run in &self.runs {
+            write!(f, "{:>12}", run.version)?;
+        }
+        for (name, value) in rows {
+            write!(f, "\n{name:<20}")?;
+            for run in &self.runs {
+                write!(f, "{:>12}", value(run))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    lease_expiries: AtomicU64,
+    keep_alive_errors: AtomicU64,
+    watch_gaps: AtomicU64,
+    watch_restarts: AtomicU64,
+}
+
+struct Observer {
+    counters: Arc<Counters>,
+    token: CancellationToken,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Observer {
+    fn start(client: &Client) -> Self {
+        let counters = Arc::new(Counters::default());
+        let token = CancellationToken::new();
+        let etcd = client.etcd_client().clone();
+        let tasks = vec![
+            tokio::spawn(keep_lease(
+                etcd.lease_client(),
+                counters.clone(),
+                token.clone(),
+            )),
+            tokio::spawn(write_counter(etcd, token.clone())),
+            tokio::spawn(watch_counter(
+                client.clone(),
+                counters.clone(),
+                token.clone(),
+            )),
+        ];
+        Observer {
+            counters,
+            token,
+            tasks,
+        }
+    }
+
+    async fn stop(self) -> Observed {
+        self.token.cancel();
+        for task in self.tasks {
+            let _ = task.await;
+        }
+        Observed {
+            lease_expiries: self.counters.lease_expiries.load(Ordering::Relaxed),
+            keep_alive_errors: self.counters.keep_alive_errors.load(Ordering::Relaxed),
+            watch_gaps: self.counters.watch_gaps.load(Ordering::Relaxed),
+            watch_restarts: self.counters.watch_restarts.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Counts the errors of the heartbeat it wraps
+struct Counted<H> {
+    inner: H,
+    counters: Arc<Counters>,
+}
+
+impl<H> Counted<H> {
+    fn count<T>(&self, result: Result<T>) -> Result<T> {
+        if result.is_err() {
+            self.counters
+                .keep_alive_errors
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+#[async_trait::async_trait]
+impl<H: LeaseHeartbeat> LeaseHeartbeat for Counted<H> {
+    async fn send(&mut self) -> Result<()> {
+        let sent = self.inner.send().await;
+        self.count(sent)
+    }
+
+    async fn receive(&mut self) -> Result<Option<u64>> {
+        let received = self.inner.receive().await;
+        self.count(received)
+    }
+
+    async fn remaining(&mut self) -> Result<u64> {
+        let remaining = self.inner.remaining().await;
+        self.count(remaining)
+    }
+
+    async fn revoke(&mut self) -> Result<()> {
+        self.inner.revoke().await
+    }
+}
+
+/// Keep a lease alive with the runtime's keep-alive loop, granting a new one whenever etcd
+/// says the last one is gone
+async fn keep_lease(mut client: LeaseClient, counters: Arc<Counters>, token: CancellationToken) {
+    while !token.is_cancelled() {
+        let granted = tokio::time::timeout(REQUEST_TIMEOUT, client.grant(LEASE_TTL as i64, None));
+        let lease_id = match granted.await {
+            Ok(Ok(lease)) => lease.id() as u64,
+            _ => {
+                wait(&token, RETRY_DELAY).await;
+                continue;
+            }
+        };
+        while !token.is_cancelled() {
+            let kept = match EtcdHeartbeat::open(client.clone(), lease_id).await {
+                Ok(heartbeat) => {
+                    let heartbeat = Counted {
+                        inner: heartbeat,
+                        counters: counters.clone(),
+                    };
+                    let progress = Progress::default();
+                    run_keep_alive(heartbeat, lease_id, LEASE_TTL, token.clone(), progress).await
+                }
+                Err(err) => {
+                    counters.keep_alive_errors.fetch_add(1, Ordering::Relaxed);
+                    Err(err)
+                }
+            };
+            // Ok only once cancelled, having revoked the lease
+            if kept.is_ok() {
+                return;
+            }
+            let ttl = client.time_to_live(lease_id as i64, None);
+            match tokio::time::timeout(REQUEST_TIMEOUT, ttl).await {
+                Ok(Ok(ttl)) if ttl.ttl() <= 0 => {
+                    counters.lease_expiries.fetch_add(1, Ordering::Relaxed);
+                    break;
+                }
+                // Still alive, or unknown until etcd answers again
+                _ => wait(&token, RETRY_DELAY).await,
+            }
+        }
+    }
+}
+
+/// Write 1, 2, 3... to [`WATCH_KEY`], moving on only once a write is acknowledged. A write that
+/// took effect but timed out is written again, which the watcher ignores.
+async fn write_counter(client: etcd_client::Client, token: CancellationToken) {
+    let mut kv = client.kv_client();
+    let mut ticker = tokio::time::interval(WRITE_INTERVAL);
+    let mut next = 1u64;
+    loop {
+        tokio::select! {
+            _ = token.cancelled() => return,
+            _ = ticker.tick() => {}
+        }
+        let put = kv.put(WATCH_KEY, next.to_string(), None);
+        if let Ok(Ok(_)) = tokio::time::timeout(REQUEST_TIMEOUT, put).await {
+            next += 1;
+        }
+    }
+}
+
+/// Watch [`WATCH_KEY`] through the runtime's watcher and count the values it skips
+async fn watch_counter(client: Client, counters: Arc<Counters>, token: CancellationToken) {
+    let mut last_seen = None;
+    let mut watching = false;
+    while !token.is_cancelled() {
+        let watcher = tokio::time::timeout(REQUEST_TIMEOUT, client.kv_watch_prefix(WATCH_KEY));
+        let (_prefix, _watcher, mut rx) = match watcher.await {
+            Ok(Ok(watcher)) => watcher.dissolve(),
+            _ => {
+                wait(&token, RETRY_DELAY).await;
+                continue;
+            }
+        };
+        if watching {
+            counters.watch_restarts.fetch_add(1, Ordering::Relaxed);
+        }
+        watching = true;
+        loop {
+            let event = tokio::select! {
+                _ = token.cancelled() => return,
+                event = rx.recv() => event,
+            };
+            let Some(WatchEvent::Put(kv)) = event else {
+                break;
+            };
+            let Some(seen) = kv.value_str().ok().and_then(|v| v.parse::<u64>().ok()) else {
+                continue;
+            };
+            if let Some(last) = last_seen {
+                if seen > last + 1 {
+                    counters
+                        .watch_gaps
+                        .fetch_add(seen - last - 1, Ordering::Relaxed);
+                }
+                if seen <= last {
+                    continue;
+                }
+            }
+            last_seen = Some(seen);
+        }
+    }
+}
+
+/// Sleep for `duration`, or until `token` is cancelled
+async fn wait(token: &CancellationToken, duration: Duration) {
+    tokio::select! {
+        _ = token.cancelled() => {}
+        _ = tokio::time::sleep(duration) => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_comparison() {
+        let run = |version: &str, watch_gaps| ReleaseRun {
+            version: version.to_string(),
+            binary: PathBuf::from("etcd"),
+            report: ScenarioReport {
+                name: "kill leader".to_string(),
+                probes: Vec::new(),
+                actions: Vec::new(),
+            },
+            observed: Observed {
+                watch_gaps,
+                ..Default::default()
+            },
+        };
+        let comparison = Comparison {
+            scenario: "kill leader".to_string(),
+            runs: vec![run("3.4.27", 0), run("3.5.9", 12)],
+        };
+        let text = comparison.to_string();
+        let mut lines = text.lines();
+        assert_eq!(lines.next(), Some("kill leader"));
+        let header: Vec<_> = lines.next().unwrap().split_whitespace().collect();
+        assert_eq!(header, ["etcd", "3.4.27", "3.5.9"]);
+        let gaps = text.lines().find(|l| l.starts_with("watch gaps")).unwrap();
+        assert_eq!(
+            gaps.split_whitespace().collect::<Vec<_>>(),
+            ["watch", "gaps", "0", "12"]
+        );
+    }
+
+    #[cfg(feature = "integration")]
+    #[tokio::test]
+    async fn test_compare_one_release() -> Result<()> {
+        let scenario = Scenario::from_toml(
+            r#"
+            name = "pause a member"
+            duration = "3s"
+
+            [target.etcd]
+            members = 3
+
+            [[actions]]
+            at = "1s"
+            action = "partition_member"
+            member = 0
+            for = "1s"
+            "#,
+        )?;
+        let comparison = compare(&scenario, &[PathBuf::from("etcd")]).await?;
+        let run = &comparison.runs[0];
+        assert!(run.version.starts_with('3'), "{}", run.version);
+        assert!(!run.report.probes.is_empty());
+        assert_eq!(run.observed.lease_expiries, 0);
+        Ok(())
+    }
+}
@@ -6,8 +6,10 @@
 //!
 //! These are the low-level building blocks for the distributed system.
 
+pub mod accounting;
 pub mod etcd;
 pub mod nats;
+pub mod proxy;
 pub mod tcp;
 mod utils;
 pub mod zmq;
@@ -0,0 +1,402 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bytes sent, received and in flight on each transport, per endpoint and per connection.
+//!
+//! The tcp response streams, NATS requests and ZMQ sockets meter their traffic against the
+//! endpoint it belongs to, named by [`Endpoint::subject`](crate::component::Endpoint::subject).
+//! In flight are the bytes handed to a transport that it hasn't yet written out, which grows when
+//! a link saturates.
+//!
+//! The totals are exported as the [`transport`](crate::metrics::prometheus_names::transport)
+//! metrics, and the system status server returns a [`TransportSnapshot`], including the open
+//! connections, at [`TRANSPORT_DIAGNOSTICS_PATH`].
+//!
+//! [`TRANSPORT_DIAGNOSTICS_PATH`]: crate::system_status_server::TRANSPORT_DIAGNOSTICS_PATH
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use prometheus::IntGaugeVec;
+use serde::Serialize;
+
+use crate::metrics::MetricsHierarchy;
+use crate::metrics::prometheus_names::transport as transport_metrics;
+
+/// The endpoint label of traffic that can't be attributed to one
+pub const UNATTRIBUTED: &str = "unattributed";
+
+static REGISTRY: Lazy<Registry> = Lazy::new(Registry::default);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    Tcp,
+    Nats,
+    Zmq,
+}
+
+impl Transport {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Transport::Tcp => "tcp",
+            Transport::Nats => "nats",
+            Transport::Zmq => "zmq",
+        }
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    sent: AtomicU64,
+    received: AtomicU64,
+    in_flight: AtomicU64,
+}
+
+impl Counters {
+    fn load(&self) -> ByteCounts {
+        ByteCounts {
+            sent: self.sent.load(Ordering::Relaxed),
+            received: self.received.load(Ordering::Relaxed),
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ByteCounts {
+    pub sent: u64,
+    pub received: u64,
+    pub in_flight: u64,
+}
+
+struct Connection {
+    transport: Transport,
+    endpoint: String,
+    peer: String,
+    opened: Instant,
+    counters: Arc<Counters>,
+}
+
+#[derive(Default)]
+struct Registry {
+    endpoints: Mutex<HashMap<(Transport, String), Arc<Counters>>>,
+    connections: Mutex<HashMap<u64, Connection>>,
+    next_connection: AtomicU64,
+}
+
+impl Registry {
+    fn endpoint(&self, transport: Transport, endpoint: &str) -> Arc<Counters> {
+        let mut endpoints = self.endpoints.lock();
+        if let Some(counters) = endpoints.get(&(transport, endpoint.to_string())) {
+            return counters.clone();
+        }
+        let counters = Arc::new(Counters::default());
+        endpoints.insert((transport, endpoint.to_string()), counters.clone());
+        counters
+    }
+}
+
+/// Counts the bytes of one endpoint on one transport, and of one connection if made with
+/// [`connection`]. The connection is listed until the last clone is dropped.
+#[derive(Clone)]
+pub struct Meter {
+    inner: Arc<MeterInner>,
+}
+
+struct MeterInner {
+    endpoint: Arc<Counters>,
+    connection: Option<(u64, Arc<Counters>)>,
+}
+
+impl Drop for MeterInner {
+    fn drop(&mut self) {
+        if let Some((id, _)) = &self.connection {
+            REGISTRY.connections.lock().remove(id);
+        }
+    }
+}
+
+/// A [`Meter`] for traffic of `endpoint` that isn't tied to a connection of its own, such as
+/// requests multiplexed over the NATS client's connection
+pub fn meter(transport: Transport, endpoint: &str) -> Meter {
+    Meter {
+        inner: Arc::new(MeterInner {
+            endpoint: REGISTRY.endpoint(transport, endpoint),
+            connection: None,
+        }),
+    }
+}
+
+/// A [`Meter`] for a connection to or from `peer` carrying the traffic of `endpoint`
+pub fn connection(transport: Transport, endpoint: &str, peer: impl fmt::Display) -> Meter {
+    let id = REGISTRY.next_connection.fetch_add(1, Ordering::Relaxed);
+    let counters = Arc::new(Counters::default());
+    REGISTRY.connections.lock().insert(
+        id,
+        Connection {
+            transport,
+            endpoint: endpoint.to_string(),
+            peer: peer.to_string(),
+            opened: Instant::now(),
+            counters: counters.clone(),
+        },
+    );
+    Meter {
+        inner: Arc::new(MeterInner {
+            endpoint: REGISTRY.endpoint(transport, endpoint),
+            connection: Some((id, counters)),
+        }),
+    }
+}
+
+impl Meter {
+    pub fn sent(&self, bytes: usize) {
+        self.each(|c| c.sent.fetch_add(bytes as u64, Ordering::Relaxed));
+    }
+
+    pub fn received(&self, bytes: usize) {
+        self.each(|c| c.received.fetch_add(bytes as u64, Ordering::Relaxed));
+    }
+
+    /// `bytes` handed to the transport, in flight until [`Sending::finish`] counts them as sent.
+    /// Dropping the guard instead, when the send failed, only takes them out of flight.
+    pub fn sending(&self, bytes: usize) -> Sending {
+        self.each(|c| c.in_flight.fetch_add(bytes as u64, Ordering::Relaxed));
+        Sending {
+            meter: self.clone(),
+            bytes,
+        }
+    }
+
+    /// The counts of the connection, or of the endpoint if there is no connection
+    pub fn counts(&self) -> ByteCounts {
+        match &self.inner.connection {
+            Some((_, counters)) => counters.load(),
+            None => self.inner.endpoint.load(),
+        }
+    }
+
+    fn each(&self, f: impl Fn(&Counters) -> u64) {
+        f(&self.inner.endpoint);
+        if let Some((_, counters)) = &self.inner.connection {
+            f(counters);
+        }
+    }
+}
+
+/// Bytes in flight, see [`Meter::sending`]
+#[must_use = "the bytes are out of flight as soon as this is dropped"]
+pub struct Sending {
+    meter: Meter,
+    bytes: usize,
+}
+
+impl Sending {
+    pub fn finish(self) {
+        self.meter.sent(self.bytes);
+    }
+}
+
+impl Drop for Sending {
+    fn drop(&mut self) {
+        let bytes = self.bytes as u64;
+        self.meter
+            .each(|c| c.in_flight.fetch_sub(bytes, Ordering::Relaxed));
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EndpointBytes {
+    pub transport: Transport,
+    pub endpoint: String,
+    #[serde(flatten)]
+    pub bytes: ByteCounts,
+    /// Connections open now
+    pub connections: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionBytes {
+    pub id: u64,
+    pub transport: Transport,
+    pub endpoint: String,
+    pub peer: String,
+    pub open_secs: f64,
+    #[serde(flatten)]
+    pub bytes: ByteCounts,
+}
+
+/// Every endpoint metered since the process started, and every connection open now
+#[derive(Debug, Clone, Serialize)]
+pub struct TransportSnapshot {
+    pub endpoints: Vec<EndpointBytes>,
+    pub connections: Vec<ConnectionBytes>,
+}
+
+pub fn snapshot() -> TransportSnapshot {
+    let mut connections: Vec<ConnectionBytes> = REGISTRY
+        .connections
+        .lock()
+        .iter()
+        .map(|(id, connection)| ConnectionBytes {
+            id: *id,
+            transport: connection.transport,
+            endpoint: connection.endpoint.clone(),
+            peer: connection.peer.clone(),
+            open_secs: connection.opened.elapsed().as_secs_f64(),
+            bytes: connection.counters.load(),
+        })
+        .collect();
+    connections.sort_by_key(|connection| connection.id);
+
+    let mut endpoints: Vec<EndpointBytes> = REGISTRY
+        .endpoints
+        .lock()
+        .iter()
+        .map(|((transport, endpoint), counters)| EndpointBytes {
+            transport: *transport,
+            endpoint: endpoint.clone(),
+            bytes: counters.load(),
+            connections: connections
+                .iter()
+                .filter(|c| c.transport == *transport && c.endpoint == *endpoint)
+                .count(),
+        })
+        .collect();
+    endpoints.sort_by(|a, b| (a.transport, &a.endpoint).cmp(&(b.transport, &b.endpoint)));
+
+    TransportSnapshot {
+        endpoints,
+        connections,
+    }
+}
+
+/// The per endpoint gauges, set from a [`snapshot`] before every scrape
+pub(crate) struct TransportMetrics {
+    sent: IntGaugeVec,
+    received: IntGaugeVec,
+    in_flight: IntGaugeVec,
+    connections: IntGaugeVec,
+}
+
+impl TransportMetrics {
+    pub(crate) fn new(drt: &crate::DistributedRuntime) -> anyhow::Result<Self> {
+        let metrics = drt.metrics();
+        let label_names = &[
+            transport_metrics::TRANSPORT_LABEL,
+            transport_metrics::ENDPOINT_LABEL,
+        ];
+        Ok(TransportMetrics {
+            sent: metrics.create_intgaugevec(
+                transport_metrics::SENT_BYTES,
+                "Bytes sent per transport and endpoint",
+                label_names,
+                &[],
+            )?,
+            received: metrics.create_intgaugevec(
+                transport_metrics::RECEIVED_BYTES,
+                "Bytes received per transport and endpoint",
+                label_names,
+                &[],
+            )?,
+            in_flight: metrics.create_intgaugevec(
+                transport_metrics::INFLIGHT_BYTES,
+                "Bytes handed to a transport and not yet written, per transport and endpoint",
+                label_names,
+                &[],
+            )?,
+            connections: metrics.create_intgaugevec(
+                transport_metrics::CONNECTIONS,
+                "Open connections per transport and endpoint",
+                label_names,
+                &[],
+            )?,
+        })
+    }
+
+    pub(crate) fn update(&self) {
+        for endpoint in snapshot().endpoints {
+            let labels = &[endpoint.transport.as_str(), endpoint.endpoint.as_str()];
+            let bytes = endpoint.bytes;
+            self.sent.with_label_values(labels).set(bytes.sent as i64);
+            self.received
+                .with_label_values(labels)
+                .set(bytes.received as i64);
+            self.in_flight
+                .with_label_values(labels)
+                .set(bytes.in_flight as i64);
+            self.connections
+                .with_label_values(labels)
+                .set(endpoint.connections as i64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The registry is shared by every test in the process, so each test has its own endpoint
+    fn endpoint_bytes(endpoint: &str) -> Option<EndpointBytes> {
+        snapshot()
+            .endpoints
+            .into_iter()
+            .find(|e| e.transport == Transport::Tcp && e.endpoint == endpoint)
+    }
+
+    #[test]
+    fn test_connection_counts_roll_up_to_endpoint() {
+        let endpoint = "test_accounting.roll_up";
+        let first = connection(Transport::Tcp, endpoint, "10.0.0.1:4000");
+        let second = connection(Transport::Tcp, endpoint, "10.0.0.2:4000");
+        first.sent(100);
+        first.received(10);
+        second.sent(50);
+
+        assert_eq!(first.counts().sent, 100);
+        let totals = endpoint_bytes(endpoint).unwrap();
+        assert_eq!((totals.bytes.sent, totals.bytes.received), (150, 10));
+        assert_eq!(totals.connections, 2);
+
+        // Closing a connection keeps its bytes in the endpoint's totals
+        drop(second);
+        let totals = endpoint_bytes(endpoint).unwrap();
+        assert_eq!((totals.bytes.sent, totals.connections), (150, 1));
+        assert!(
+            snapshot()
+                .connections
+                .iter()
+                .all(|c| c.peer != "10.0.0.2:4000")
+        );
+    }
+
+    #[test]
+    fn test_in_flight_until_finished() {
+        let endpoint = "test_accounting.in_flight";
+        let meter = connection(Transport::Tcp, endpoint, "10.0.0.3:4000");
+
+        let sending = meter.sending(64);
+        assert_eq!(meter.counts().in_flight, 64);
+        assert_eq!(endpoint_bytes(endpoint).unwrap().bytes.in_flight, 64);
+        sending.finish();
+        let counts = meter.counts();
+        assert_eq!((counts.sent, counts.in_flight), (64, 0));
+
+        // A failed send is never counted as sent
+        drop(meter.sending(32));
+        let counts = meter.counts();
+        assert_eq!((counts.sent, counts.in_flight), (64, 0));
+    }
+}
@@ -6,10 +6,10 @@ use crate::{CancellationToken, ErrorContext, Result, Runtime, error};
 use async_nats::jetstream::kv;
 use derive_builder::Builder;
 use derive_getters::Dissolve;
-use futures::StreamExt;
+use futures::{Stream, StreamExt, TryStreamExt};
 use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::{RwLock, mpsc};
+use tokio::sync::{RwLock, broadcast, mpsc};
 use validator::Validate;
 
 use etcd_client::{
@@ -17,7 +17,52 @@ use etcd_client::{
     LockResponse, PutOptions, PutResponse, TlsOptions, Txn, TxnOp, TxnOpResponse, WatchOptions,
     Watcher,
 };
-pub use etcd_client::{ConnectOptions, KeyValue, LeaseClient};
+pub use etcd_client::{ConnectOptions, KeyValue, KvClient, LeaseClient};
+
+use crate::logging::slow_ops::{SlowOpKind, watch_slow};
+use crate::secrets::SecretsProvider;
+use crate::storage::key_value_store::{CausalToken, Fence, ReadConsistency};
+use crate::transports::proxy::{ProxyConfig, Routes};
+use crate::utils::tasks::supervisor::Supervisor;
+
+/// Every lease owns one key under this prefix. Its create revision is the lease's fencing token.
+pub const FENCE_ROOT_PATH: &str = "v1/fence/";
+
+/// Keys read per request when reading a prefix. One range over thousands of instances is slow to
+/// serve and holds up the etcd member serving it, and nothing can be done with it until it all
+/// arrives.
+pub const PREFIX_PAGE_SIZE: usize = 500;
+
+/// `call`, the etcd call `op` on `key`, warned about if slow, see [`crate::logging::slow_ops`]
+async fn slow_op<T>(
+    op: &'static str,
+    key: &[u8],
+    value_len: Option<usize>,
+    call: impl Future<Output = T>,
+) -> T {
+    let args = || {
+        let key = String::from_utf8_lossy(key);
+        match value_len {
+            Some(len) => format!("key={key}, value=<{len} bytes>"),
+            None => format!("key={key}"),
+        }
+    };
+    watch_slow(SlowOpKind::Etcd, op, args, call).await
+}
+
+/// The key backing the fencing token of `lease_id`
+pub fn fence_key(lease_id: u64) -> String {
+    format!("{FENCE_ROOT_PATH}{lease_id:x}")
+}
+
+/// [`Client::kv_register`] found the key held by another lease, or by ours with another value
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("{key} is already registered by lease {holder:x}")]
+pub struct AlreadyRegistered {
+    pub key: String,
+    /// The lease the key is attached to, 0 for none
+    pub holder: u64,
+}
 
 /// Debug macro that adds file and line number to colored output
 #[macro_export]
@@ -49,33 +94,69 @@ macro_rules! debug_println {
         };
         // Only print file:line if tag or fmt is not empty
         if $tag.is_empty() {
-            eprintln!(concat!("{}", $tag, "{}", " ", $fmt, "\x1b[0m"), 
+            eprintln!(concat!("{}", $tag, "{}", " ", $fmt, "\x1b[0m"),
                      tag_color, fmt_color $(, $arg)*);
         } else {
-            eprintln!(concat!("{}", $tag, "{}", " ",$fmt, "\x1b[0m", " \x1b[36m\x1b[1m\x1b[2m{}:{}\x1b[0m"), 
+            eprintln!(concat!("{}", $tag, "{}", " ",$fmt, "\x1b[0m", " \x1b[36m\x1b[1m\x1b[2m{}:{}\x1b[0m"),
                      tag_color, fmt_color $(, $arg)*, file!(), line!());
         }
     }};
 }
 use tokio::time::{Duration, interval};
 
+mod clock;
+mod dns;
+mod janitor;
+mod keep_alive;
 mod lease;
+mod lease_group;
 mod lock;
 mod path;
+mod pool;
+mod sequential;
 
+pub use clock::{ClockCheck, ClockWarning};
+pub use dns::{DnsDiscovery, DnsRecord};
+pub use janitor::{
+    AuditRecord, Janitor, JanitorConfig, OrphanReason, OwnerHeartbeat, heartbeat_owner,
+};
+use keep_alive::{EtcdLeaseServer, KeepAliveMux};
+#[cfg(any(test, feature = "simulation"))]
+pub(crate) use lease::Intercepted;
 use lease::*;
+pub(crate) use lease::{EtcdHeartbeat, LeaseHeartbeat, Progress, run_keep_alive};
+#[cfg(any(test, feature = "simulation"))]
+pub use lease::{HeartbeatAction, KeepAliveInterceptor, ResponseAction};
+pub use lease::{LeaseInfo, LeaseTtlOutOfRange, LeaseTtlPolicy, LeaseTuning, WatchdogEvent};
+pub use lease_group::LeaseGroup;
 pub use lock::*;
 pub use path::*;
+pub use pool::ConnectionPool;
+pub use sequential::*;
 
-use super::utils::build_in_runtime;
+/// TTL of the primary lease, in seconds
+const LEASE_TTL: u64 = 10;
 
 /// ETCD Client
 #[derive(Clone)]
 pub struct Client {
     client: etcd_client::Client,
     primary_lease: u64,
+    primary_fence_token: u64,
+    read_only: bool,
+    /// When the last linearizable read was sent and the revision it returned. A serializable
+    /// read at or past that revision is no staler than the time elapsed since.
+    last_linearizable: Arc<parking_lot::Mutex<Option<(std::time::Instant, i64)>>>,
     runtime: Runtime,
-    rt: Arc<tokio::runtime::Runtime>,
+    /// Of the control plane, see [`Runtime::control_plane`]
+    rt: tokio::runtime::Handle,
+    /// Set if the connection and primary lease come from the [`ConnectionPool`]
+    shared: Option<Arc<pool::PooledConnection>>,
+    watchdog_events: broadcast::Sender<WatchdogEvent>,
+    lease_tuning: LeaseTuning,
+    lease_ttl_policy: LeaseTtlPolicy,
+    /// The keep-alive stream the leases share, None if each has its own
+    keep_alive: Option<KeepAliveMux>,
 }
 
 impl std::fmt::Debug for Client {
@@ -92,6 +173,9 @@ pub struct Lease {
 
     /// [`CancellationToken`] associated with the lease
     cancel_token: CancellationToken,
+
+    /// etcd revision at which the lease's fence key was created
+    fence_token: u64,
 }
 
 impl Lease {
@@ -100,6 +184,27 @@ impl Lease {
         self.id
     }
 
+    /// A fencing token for writes to external systems (object stores, databases) made on behalf
+    /// of this lease. Tokens come from etcd revisions, so a lease granted later always has a
+    /// larger token. The external system remembers the largest token it has seen and rejects
+    /// writes carrying a smaller one, which stops a worker that lost its lease, but has not
+    /// noticed yet, from overwriting its successor.
+    ///
+    /// 0 if the client holds no lease.
+    pub fn fence_token(&self) -> u64 {
+        self.fence_token
+    }
+
+    /// The fencing token with its lease, to check in [`KeyValueStoreManager::publish_fenced`]
+    ///
+    /// [`KeyValueStoreManager::publish_fenced`]: crate::storage::key_value_store::KeyValueStoreManager::publish_fenced
+    pub fn fence(&self) -> Fence {
+        Fence {
+            lease_id: self.id,
+            token: self.fence_token,
+        }
+    }
+
     /// Get the primary [`CancellationToken`] associated with the lease.
     /// This token will revoke the lease if canceled.
     pub fn primary_token(&self) -> CancellationToken {
@@ -137,60 +242,167 @@ impl Client {
     /// to the lease.
     ///
     /// If the lease expires, the [`Runtime`] will be shutdown.
-    /// If the [`Runtime`] is shutdown, the lease will be revoked.
+    /// If the [`Runtime`] is shutdown, the lease will be revoked. A
+    /// [shared](ClientOptions::shared) lease is revoked once no client uses it any more.
     pub async fn new(config: ClientOptions, runtime: Runtime) -> Result<Self> {
+        if config.shared {
+            return ConnectionPool::global().connect(config, runtime).await;
+        }
         let token = runtime.primary_token();
+        Self::connect(config, runtime, token).await
+    }
 
-        let ((client, lease_id), rt) = build_in_runtime(
-            async move {
-                let client = etcd_client::Client::connect(
-                    config.etcd_url.clone(),
-                    config.etcd_connect_options,
-                )
+    /// Connect and grant a primary lease revoked when `token` is cancelled
+    async fn connect(
+        mut config: ClientOptions,
+        runtime: Runtime,
+        token: CancellationToken,
+    ) -> Result<Self> {
+        let read_only = config.read_only;
+        let dns = config.dns.clone();
+        if let Some(dns) = &dns {
+            config.etcd_url = dns.resolve().await?;
+            tracing::info!(etcd_url = ?config.etcd_url, "etcd endpoints from DNS");
+        }
+        if let Some(secrets) = &config.secrets {
+            let found = secret_auth_options(secrets.as_ref())
                 .await
-                .with_context(|| {
-                    format!(
-                        "Unable to connect to etcd server at {}. Check etcd server status",
-                        config.etcd_url.join(", ")
-                    )
-                })?;
-
-                let lease_id = if config.attach_lease {
-                    let lease_client = client.lease_client();
+                .context("Reading the etcd credentials")?;
+            if found.is_some() {
+                config.etcd_connect_options = found;
+            }
+        }
+        let etcd_url = config.etcd_url.clone();
+        let clock_check_interval = config.clock_check_interval;
+        let lease_tuning = config.lease_tuning;
+        let lease_ttl_policy = config.lease_ttl_policy;
+        let refresh_token = token.clone();
+        let clock_token = token.clone();
+        let (watchdog_events, _) = broadcast::channel(16);
+        let events = watchdog_events.clone();
+
+        let share_keep_alive = config.shares_keep_alive();
+        if !share_keep_alive && !read_only {
+            tracing::debug!("etcd leases each keep their own keep-alive stream");
+        }
 
-                    let lease = create_lease(lease_client, 10, token)
+        // Lease keep-alives and watches run on the control plane, so a primary runtime
+        // saturated with requests doesn't delay them
+        let rt = runtime.control_plane();
+        let supervisor = lease_supervisor(&runtime);
+        let (client, lease_id, fence_token, routes, keep_alive) = rt
+            .spawn(async move {
+                // Tunnels through the proxy run on this runtime, for as long as the client
+                let mut routes = Routes::new(config.proxy.clone(), 2379, token.clone());
+                let mut dialed = Vec::with_capacity(config.etcd_url.len());
+                for url in &config.etcd_url {
+                    dialed.push(routes.add(url).await?);
+                }
+                let client =
+                    etcd_client::Client::connect(dialed.clone(), config.etcd_connect_options)
                         .await
                         .with_context(|| {
                             format!(
-                                "Unable to create lease. Check etcd server status at {}",
+                                "Unable to connect to etcd server at {}. Check etcd server status",
                                 config.etcd_url.join(", ")
                             )
                         })?;
+                let keep_alive = if share_keep_alive {
+                    let server = EtcdLeaseServer::new(client.lease_client(), &dialed)?;
+                    Some(KeepAliveMux::new(server))
+                } else {
+                    None
+                };
+
+                let (lease_id, fence_token) = if config.attach_lease && !config.read_only {
+                    let lease_client = client.lease_client();
 
-                    lease.id
+                    let kv_client = client.kv_client();
+                    let lease = create_lease(
+                        lease_client,
+                        kv_client,
+                        LEASE_TTL,
+                        token,
+                        events,
+                        lease_tuning,
+                        keep_alive.clone(),
+                        &supervisor,
+                    )
+                    .await
+                    .with_context(|| {
+                        format!(
+                            "Unable to create lease. Check etcd server status at {}",
+                            config.etcd_url.join(", ")
+                        )
+                    })?;
+
+                    (lease.id, lease.fence_token)
                 } else {
-                    0
+                    (0, 0)
                 };
 
-                Ok((client, lease_id))
-            },
-            1,
-        )
-        .await?;
+                Ok::<_, anyhow::Error>((client, lease_id, fence_token, routes, keep_alive))
+            })
+            .await??;
+
+        if let Some(dns) = dns {
+            let handle = runtime.secondary();
+            let client = client.clone();
+            dns::spawn_refresh(&handle, dns, client, etcd_url, routes, refresh_token);
+        }
+        if let Some(interval) = clock_check_interval
+            && !read_only
+        {
+            let check = ClockCheck::new(client.lease_client(), LEASE_TTL);
+            runtime.secondary().spawn(check.run(interval, clock_token));
+        }
 
         Ok(Client {
             client,
             primary_lease: lease_id,
+            primary_fence_token: fence_token,
+            read_only,
+            last_linearizable: Arc::new(parking_lot::Mutex::new(None)),
             rt,
             runtime,
+            shared: None,
+            watchdog_events,
+            lease_tuning,
+            lease_ttl_policy,
+            keep_alive,
         })
     }
 
+    /// True if the connection and primary lease are shared with other runtimes in this
+    /// process, see [`ConnectionPool`]
+    pub fn is_shared(&self) -> bool {
+        self.shared.is_some()
+    }
+
+    /// What the watchdogs of this client's keep-alive tasks do, for the primary lease and those
+    /// from [`Client::create_lease`]. Events before the call are not seen.
+    pub fn watchdog_events(&self) -> broadcast::Receiver<WatchdogEvent> {
+        self.watchdog_events.subscribe()
+    }
+
     /// Get a reference to the underlying [`etcd_client::Client`] instance.
     pub(crate) fn etcd_client(&self) -> &etcd_client::Client {
         &self.client
     }
 
+    /// True if created with [`ClientOptions::read_only`]
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Fail `op` if this is a read-only client
+    pub(crate) fn check_writable(&self, op: &str) -> Result<()> {
+        if self.read_only {
+            return Err(error!("etcd client is read-only, refusing {op}"));
+        }
+        Ok(())
+    }
+
     /// Get the primary lease ID.
     pub fn lease_id(&self) -> u64 {
         self.primary_lease
@@ -201,28 +413,72 @@ impl Client {
         Lease {
             id: self.primary_lease,
             cancel_token: self.runtime.primary_token(),
+            fence_token: self.primary_fence_token,
         }
     }
 
     /// Create a [`Lease`] with a given time-to-live (TTL).
     /// This [`Lease`] will be tied to the [`Runtime`], specifically a child [`CancellationToken`].
+    /// The TTL is checked against [`ClientOptions::lease_ttl_policy`] first.
     pub async fn create_lease(&self, ttl: u64) -> Result<Lease> {
+        self.check_writable("create_lease")?;
+        let ttl = self.lease_ttl_policy.apply(ttl)?;
         let token = self.runtime.child_token();
         let lease_client = self.client.lease_client();
+        let kv_client = self.client.kv_client();
+        let events = self.watchdog_events.clone();
+        let tuning = self.lease_tuning;
+        let mux = self.keep_alive.clone();
+        let supervisor = lease_supervisor(&self.runtime);
         self.rt
-            .spawn(create_lease(lease_client, ttl, token))
+            .spawn(async move {
+                let supervisor = &supervisor;
+                create_lease(
+                    lease_client,
+                    kv_client,
+                    ttl,
+                    token,
+                    events,
+                    tuning,
+                    mux,
+                    supervisor,
+                )
+                .await
+            })
             .await?
     }
 
+    /// An empty [`LeaseGroup`], for leases that are revoked together. `name` is only used in
+    /// logs and errors.
+    pub fn create_lease_group(&self, name: impl Into<String>) -> LeaseGroup {
+        LeaseGroup::new(self.clone(), name.into())
+    }
+
     // Revoke an etcd lease given its lease id. A wrapper over etcd_client::LeaseClient::revoke
     pub async fn revoke_lease(&self, lease_id: u64) -> Result<()> {
+        self.check_writable("revoke_lease")?;
         let lease_client = self.client.lease_client();
         self.rt.spawn(revoke_lease(lease_client, lease_id)).await?
     }
 
+    /// Every lease of the cluster, with its TTL and the keys attached to it. A request per
+    /// lease, so for operators rather than anything on a hot path.
+    pub async fn list_leases(&self) -> Result<Vec<LeaseInfo>> {
+        let lease_client = self.client.lease_client();
+        self.rt.spawn(list_leases(lease_client)).await?
+    }
+
+    /// The TTL of a lease and the keys attached to it, None if it expired or never existed
+    pub async fn lease_info(&self, lease_id: u64) -> Result<Option<LeaseInfo>> {
+        let lease_client = self.client.lease_client();
+        self.rt.spawn(lease_info(lease_client, lease_id)).await?
+    }
+
     pub async fn kv_create(&self, key: &str, value: Vec<u8>, lease_id: Option<u64>) -> Result<()> {
+        self.check_writable("kv_create")?;
         let id = lease_id.unwrap_or(self.lease_id());
         let put_options = PutOptions::new().with_lease(id as i64);
+        let value_len = Some(value.len());
 
         // Build the transaction
         let txn = Txn::new()
@@ -232,7 +488,9 @@ impl Client {
             ]);
 
         // Execute the transaction
-        let result = self.client.kv_client().txn(txn).await?;
+        let mut kv_client = self.client.kv_client();
+        let call = kv_client.txn(txn);
+        let result = slow_op("kv_create", key.as_bytes(), value_len, call).await?;
 
         if result.succeeded() {
             Ok(())
@@ -244,6 +502,80 @@ impl Client {
         }
     }
 
+    /// Create `key` for a discovery registration. Registering the same value under the same lease
+    /// again succeeds, anything else already there fails with [`AlreadyRegistered`].
+    ///
+    /// With `takeover` the key is replaced instead and the lease it was attached to revoked, so
+    /// the process holding it loses its lease and shuts down rather than serving alongside us.
+    pub async fn kv_register(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        lease_id: Option<u64>,
+        takeover: bool,
+    ) -> Result<()> {
+        self.check_writable("kv_register")?;
+        let id = lease_id.unwrap_or(self.lease_id());
+        let put_options = PutOptions::new().with_lease(id as i64);
+
+        let value_len = Some(value.len());
+        let txn = Txn::new()
+            .when(vec![Compare::version(key, CompareOp::Equal, 0)])
+            .and_then(vec![TxnOp::put(key, value.clone(), Some(put_options))])
+            .or_else(vec![TxnOp::get(key, None)]);
+        let mut kv_client = self.client.kv_client();
+        let call = kv_client.txn(txn);
+        let result = slow_op("kv_register", key.as_bytes(), value_len, call).await?;
+        if result.succeeded() {
+            return Ok(());
+        }
+        let existing = match result.op_responses().into_iter().next() {
+            Some(TxnOpResponse::Get(response)) => response.kvs().first().cloned(),
+            _ => None,
+        };
+        let Some(existing) = existing else {
+            // Deleted in between, e.g. its lease expired
+            return self.kv_create(key, value, Some(id)).await;
+        };
+        let holder = existing.lease() as u64;
+        if holder == id && existing.value() == value.as_slice() {
+            return Ok(());
+        }
+        if !takeover {
+            return Err(AlreadyRegistered {
+                key: key.to_string(),
+                holder,
+            }
+            .into());
+        }
+
+        // Only replace the registration we looked at, not one made since
+        let put_options = PutOptions::new().with_lease(id as i64);
+        let unchanged = Compare::mod_revision(key, CompareOp::Equal, existing.mod_revision());
+        let txn = Txn::new().when(vec![unchanged]).and_then(vec![TxnOp::put(
+            key,
+            value,
+            Some(put_options),
+        )]);
+        let mut kv_client = self.client.kv_client();
+        let call = kv_client.txn(txn);
+        if !slow_op("kv_register", key.as_bytes(), value_len, call)
+            .await?
+            .succeeded()
+        {
+            return Err(error!("{key} changed while taking it over"));
+        }
+        tracing::warn!(
+            key,
+            holder = %format_args!("{holder:x}"),
+            "Took over registration, revoking the previous holder's lease"
+        );
+        if holder != 0 && holder != id {
+            self.revoke_lease(holder).await?;
+        }
+        Ok(())
+    }
+
     /// Atomically create a key if it does not exist, or validate the values are identical if the key exists.
     pub async fn kv_create_or_validate(
         &self,
@@ -251,8 +583,10 @@ impl Client {
         value: Vec<u8>,
         lease_id: Option<u64>,
     ) -> Result<()> {
+        self.check_writable("kv_create_or_validate")?;
         let id = lease_id.unwrap_or(self.lease_id());
         let put_options = PutOptions::new().with_lease(id as i64);
+        let value_len = Some(value.len());
 
         // Build the transaction that either creates the key if it doesn't exist,
         // or validates the existing value matches what we expect
@@ -271,7 +605,9 @@ impl Client {
             ]);
 
         // Execute the transaction
-        let result = self.client.kv_client().txn(txn).await?;
+        let mut kv_client = self.client.kv_client();
+        let call = kv_client.txn(txn);
+        let result = slow_op("kv_create_or_validate", key.as_bytes(), value_len, call).await?;
 
         // We have to enumerate the response paths to determine if the transaction succeeded
         if result.succeeded() {
@@ -302,28 +638,46 @@ impl Client {
         value: impl AsRef<[u8]>,
         lease_id: Option<u64>,
     ) -> Result<()> {
+        self.check_writable("kv_put")?;
         let id = lease_id.unwrap_or(self.lease_id());
         let put_options = PutOptions::new().with_lease(id as i64);
-        let _ = self
-            .client
-            .kv_client()
-            .put(key.as_ref(), value.as_ref(), Some(put_options))
-            .await?;
+        let (key, value) = (key.as_ref(), value.as_ref());
+        let mut kv_client = self.client.kv_client();
+        let call = kv_client.put(key, value, Some(put_options));
+        slow_op("kv_put", key.as_bytes(), Some(value.len()), call).await?;
         Ok(())
     }
 
+    /// Replace the value of an existing key, keeping its lease. False if there is no such key.
+    pub async fn kv_update(&self, key: &str, value: Vec<u8>) -> Result<bool> {
+        self.check_writable("kv_update")?;
+        let put_options = PutOptions::new().with_ignore_lease();
+        let value_len = Some(value.len());
+        let txn = Txn::new()
+            .when(vec![Compare::version(key, CompareOp::Greater, 0)])
+            .and_then(vec![TxnOp::put(key, value, Some(put_options))]);
+        let mut kv_client = self.client.kv_client();
+        let call = kv_client.txn(txn);
+        Ok(slow_op("kv_update", key.as_bytes(), value_len, call)
+            .await?
+            .succeeded())
+    }
+
     pub async fn kv_put_with_options(
         &self,
         key: impl AsRef<str>,
         value: impl AsRef<[u8]>,
         options: Option<PutOptions>,
     ) -> Result<PutResponse> {
+        self.check_writable("kv_put_with_options")?;
         let options = options
             .unwrap_or_default()
             .with_lease(self.primary_lease().id() as i64);
-        self.client
-            .kv_client()
-            .put(key.as_ref(), value.as_ref(), Some(options))
+        let (key, value) = (key.as_ref(), value.as_ref());
+        let mut kv_client = self.client.kv_client();
+        let call = kv_client.put(key, value, Some(options));
+        let value_len = Some(value.len());
+        slow_op("kv_put_with_options", key.as_bytes(), value_len, call)
             .await
             .map_err(|err| err.into())
     }
@@ -333,31 +687,173 @@ impl Client {
         key: impl Into<Vec<u8>>,
         options: Option<GetOptions>,
     ) -> Result<Vec<KeyValue>> {
-        let mut get_response = self.client.kv_client().get(key, options).await?;
+        let key: Vec<u8> = key.into();
+        let mut kv_client = self.client.kv_client();
+        let call = kv_client.get(key.clone(), options);
+        let mut get_response = slow_op("kv_get", &key, None, call).await?;
         Ok(get_response.take_kvs())
     }
 
+    /// Get a key with an explicit [`ReadConsistency`]. [`Client::kv_get`] is linearizable
+    /// unless its options say otherwise.
+    pub async fn kv_get_with_consistency(
+        &self,
+        key: impl Into<Vec<u8>>,
+        consistency: ReadConsistency,
+    ) -> Result<Vec<KeyValue>> {
+        let key = key.into();
+        match consistency {
+            ReadConsistency::Linearizable => self.linearizable_get(key).await,
+            ReadConsistency::Serializable => Ok(self.serializable_get(key).await?.take_kvs()),
+            ReadConsistency::BoundedStaleness(max) => {
+                let floor = *self.last_linearizable.lock();
+                if let Some((sent, revision)) = floor
+                    && sent.elapsed() <= max
+                {
+                    let mut resp = self.serializable_get(key.clone()).await?;
+                    if resp.header().is_some_and(|h| h.revision() >= revision) {
+                        return Ok(resp.take_kvs());
+                    }
+                    tracing::trace!(revision, "Serializable read too far behind");
+                }
+                self.linearizable_get(key).await
+            }
+            ReadConsistency::AtLeast(token) => {
+                let mut resp = self.serializable_get(key.clone()).await?;
+                if resp
+                    .header()
+                    .is_some_and(|h| h.revision() as u64 >= token.revision())
+                {
+                    return Ok(resp.take_kvs());
+                }
+                tracing::trace!(?token, "Serializable read behind the causal token");
+                self.linearizable_get(key).await
+            }
+        }
+    }
+
+    /// The revision of the etcd cluster now, i.e. of the last write any client made. Asks the
+    /// leader.
+    pub async fn cluster_revision(&self) -> Result<i64> {
+        let sent = std::time::Instant::now();
+        let options = GetOptions::new().with_count_only();
+        let mut kv_client = self.client.kv_client();
+        let call = kv_client.get(vec![0], Some(options));
+        let resp = slow_op("cluster_revision", &[], None, call).await?;
+        let revision = resp
+            .header()
+            .ok_or(error!("missing header; unable to get revision"))?
+            .revision();
+        let mut last = self.last_linearizable.lock();
+        if last.is_none_or(|(_, floor)| floor <= revision) {
+            *last = Some((sent, revision));
+        }
+        Ok(revision)
+    }
+
+    /// A [`CausalToken`] covering every write acknowledged so far, by any client
+    pub async fn causal_token(&self) -> Result<CausalToken> {
+        Ok(CausalToken::new(self.cluster_revision().await? as u64))
+    }
+
+    async fn linearizable_get(&self, key: Vec<u8>) -> Result<Vec<KeyValue>> {
+        let sent = std::time::Instant::now();
+        let mut kv_client = self.client.kv_client();
+        let call = kv_client.get(key.clone(), None);
+        let mut resp = slow_op("linearizable_get", &key, None, call).await?;
+        if let Some(header) = resp.header() {
+            let mut last = self.last_linearizable.lock();
+            if last.is_none_or(|(_, revision)| revision <= header.revision()) {
+                *last = Some((sent, header.revision()));
+            }
+        }
+        Ok(resp.take_kvs())
+    }
+
+    async fn serializable_get(&self, key: Vec<u8>) -> Result<etcd_client::GetResponse> {
+        let options = GetOptions::new().with_serializable();
+        let mut kv_client = self.client.kv_client();
+        let call = kv_client.get(key.clone(), Some(options));
+        Ok(slow_op("serializable_get", &key, None, call).await?)
+    }
+
     pub async fn kv_delete(
         &self,
         key: impl Into<Vec<u8>>,
         options: Option<DeleteOptions>,
     ) -> Result<u64> {
-        self.client
-            .kv_client()
-            .delete(key, options)
+        self.check_writable("kv_delete")?;
+        let key: Vec<u8> = key.into();
+        let mut kv_client = self.client.kv_client();
+        let call = kv_client.delete(key.clone(), options);
+        slow_op("kv_delete", &key, None, call)
             .await
             .map(|del_response| del_response.deleted() as u64)
             .map_err(|err| err.into())
     }
 
+    /// The keys starting with `prefix`, read [`PREFIX_PAGE_SIZE`] at a time
     pub async fn kv_get_prefix(&self, prefix: impl AsRef<str>) -> Result<Vec<KeyValue>> {
-        let mut get_response = self
-            .client
-            .kv_client()
-            .get(prefix.as_ref(), Some(GetOptions::new().with_prefix()))
-            .await?;
+        let pages = self.kv_get_prefix_pages(prefix.as_ref(), PREFIX_PAGE_SIZE);
+        let mut pages = std::pin::pin!(pages);
+        let mut kvs = Vec::new();
+        while let Some(page) = pages.try_next().await? {
+            kvs.extend(page.kvs);
+        }
+        Ok(kvs)
+    }
 
-        Ok(get_response.take_kvs())
+    /// The keys starting with `prefix`, `page_size` at a time in key order, each page read
+    /// when the one before it has been taken. All pages are read at the revision of the first,
+    /// so together they are the prefix as it was then; if etcd compacts that revision before
+    /// the last page is read, the stream ends with the error.
+    pub fn kv_get_prefix_pages(
+        &self,
+        prefix: impl Into<String>,
+        page_size: usize,
+    ) -> impl Stream<Item = Result<PrefixPage>> + Send + 'static {
+        let prefix = prefix.into();
+        let kv_client = self.client.kv_client();
+        let range_end = prefix_range_end(prefix.as_bytes());
+        let page_size = page_size.max(1) as i64;
+        // The key the next page starts at and the revision read at, 0 until the first page
+        let start = Some((prefix.as_bytes().to_vec(), 0));
+        futures::stream::try_unfold(start, move |state| {
+            let mut kv_client = kv_client.clone();
+            let range_end = range_end.clone();
+            async move {
+                let Some((key, revision)) = state else {
+                    return Ok(None);
+                };
+                let mut options = GetOptions::new()
+                    .with_range(range_end)
+                    .with_limit(page_size);
+                if revision != 0 {
+                    options = options.with_revision(revision);
+                }
+                let call = kv_client.get(key.clone(), Some(options));
+                let mut response = slow_op("kv_get_prefix", &key, None, call).await?;
+                let revision = match revision {
+                    0 => response
+                        .header()
+                        .ok_or(error!("missing header; unable to get revision"))?
+                        .revision(),
+                    revision => revision,
+                };
+                let more = response.more();
+                let kvs = response.take_kvs();
+                // Just after the last key read
+                let next = match kvs.last() {
+                    Some(last) if more => {
+                        let mut next = last.key().to_vec();
+                        next.push(0);
+                        Some((next, revision))
+                    }
+                    _ => None,
+                };
+                Ok::<_, anyhow::Error>(Some((PrefixPage { kvs, revision }, next)))
+            }
+        })
     }
 
     /// Acquire a distributed lock using etcd's native lock mechanism
@@ -367,6 +863,7 @@ impl Client {
         key: impl Into<Vec<u8>>,
         lease_id: Option<u64>,
     ) -> Result<LockResponse> {
+        self.check_writable("lock")?;
         let mut lock_client = self.client.lock_client();
         let id = lease_id.unwrap_or(self.lease_id());
         let options = LockOptions::new().with_lease(id as i64);
@@ -378,6 +875,7 @@ impl Client {
 
     /// Release a distributed lock using the key from the LockResponse
     pub async fn unlock(&self, lock_key: impl Into<Vec<u8>>) -> Result<()> {
+        self.check_writable("unlock")?;
         let mut lock_client = self.client.lock_client();
         lock_client
             .unlock(lock_key)
@@ -406,17 +904,16 @@ impl Client {
         prefix: impl AsRef<str> + std::fmt::Display,
         include_existing: bool,
     ) -> Result<PrefixWatcher> {
-        let mut kv_client = self.client.kv_client();
         let mut watch_client = self.client.watch_client();
 
-        let mut get_response = kv_client
-            .get(prefix.as_ref(), Some(GetOptions::new().with_prefix()))
-            .await?;
-
-        let start_revision = get_response
-            .header()
-            .ok_or(error!("missing header; unable to get revision"))?
-            .revision();
+        // Existing keys are sent a page at a time, so that the first are used while the rest
+        // are read. The watch starts just after the revision they are all read at.
+        let mut pages = Box::pin(self.kv_get_prefix_pages(prefix.as_ref(), PREFIX_PAGE_SIZE));
+        let first_page = pages
+            .try_next()
+            .await?
+            .ok_or(error!("missing first page; unable to get revision"))?;
+        let start_revision = first_page.revision;
 
         tracing::trace!("{prefix}: start_revision: {start_revision}");
         let start_revision = start_revision + 1;
@@ -433,23 +930,29 @@ impl Client {
             )
             .await?;
 
-        let kvs = if include_existing {
-            let kvs = get_response.take_kvs();
-            tracing::trace!("initial kv count: {:?}", kvs.len());
-            kvs
-        } else {
-            vec![]
-        };
-
         let (tx, rx) = mpsc::channel(32);
 
+        let prefix_for_task = prefix.to_string();
         self.rt.spawn(async move {
             if include_existing {
-                for kv in kvs {
-                    if tx.send(WatchEvent::Put(kv)).await.is_err() {
-                        // receiver is already closed
-                        return;
+                let mut page = Some(Ok(first_page));
+                while let Some(result) = page {
+                    let kvs = match result {
+                        Ok(page) => page.kvs,
+                        Err(err) => {
+                            let prefix = &prefix_for_task;
+                            tracing::warn!(%err, %prefix, "Unable to read the existing keys");
+                            return;
+                        }
+                    };
+                    tracing::trace!("initial kv page: {:?}", kvs.len());
+                    for kv in kvs {
+                        if tx.send(WatchEvent::Put(kv)).await.is_err() {
+                            // receiver is already closed
+                            return;
+                        }
                     }
+                    page = pages.next().await;
                 }
             }
 
@@ -500,6 +1003,33 @@ impl Client {
     }
 }
 
+/// One page of [`Client::kv_get_prefix_pages`]
+#[derive(Debug, Clone)]
+pub struct PrefixPage {
+    pub kvs: Vec<KeyValue>,
+    /// Of the whole read, the same for every page
+    pub revision: i64,
+}
+
+/// Where the keep-alives of a client's leases run, on the control plane
+fn lease_supervisor(runtime: &Runtime) -> Supervisor {
+    let supervisor = runtime.supervisor().child("leases");
+    supervisor.with_handle(runtime.control_plane())
+}
+
+/// The end of the range of keys starting with `prefix`, as etcd's own prefix option makes it
+pub(crate) fn prefix_range_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return end;
+        }
+    }
+    // Every key
+    vec![0]
+}
+
 #[derive(Dissolve)]
 pub struct PrefixWatcher {
     prefix: String,
@@ -525,39 +1055,134 @@ pub struct ClientOptions {
     /// If true, the client will attach a lease to the primary [`CancellationToken`].
     #[builder(default = "true")]
     pub attach_lease: bool,
+
+    /// Observe only: no primary lease is created, whatever `attach_lease` says, and every
+    /// write, lease or lock operation fails. For dashboards and monitors that watch the
+    /// discovery plane but must not change it.
+    #[builder(default)]
+    pub read_only: bool,
+
+    /// Share the connection and primary lease with every other client in the process created
+    /// with the same endpoints and options. See [`ConnectionPool`].
+    #[builder(default)]
+    pub shared: bool,
+
+    /// Find the endpoints in DNS instead of using `etcd_url`, and follow them as they change.
+    /// See [`DnsDiscovery`].
+    #[builder(default)]
+    pub dns: Option<DnsDiscovery>,
+
+    /// Reach etcd through this proxy. See [`crate::transports::proxy`].
+    #[builder(default)]
+    pub proxy: Option<ProxyConfig>,
+
+    /// Compare the local clocks with etcd this often, warning when lease TTLs are unsafe.
+    /// See [`ClockCheck`]. Never if None, and never for a read-only client.
+    #[builder(default = "Some(CLOCK_CHECK_INTERVAL)")]
+    pub clock_check_interval: Option<Duration>,
+
+    /// How lease keep-alives react to slow refreshes, see [`WatchdogEvent::SlowRenewal`]
+    #[builder(default)]
+    pub lease_tuning: LeaseTuning,
+
+    /// The TTLs [`Client::create_lease`] accepts, any by default. Not applied to the primary
+    /// lease.
+    #[builder(default)]
+    pub lease_ttl_policy: LeaseTtlPolicy,
+
+    /// Read the credentials from here on connecting, in place of `etcd_connect_options` if it
+    /// has any. See [`crate::secrets`].
+    #[builder(default)]
+    pub secrets: Option<Arc<dyn SecretsProvider>>,
+}
+
+const CLOCK_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+impl ClientOptions {
+    /// Whether the leases of a client connected with these options share one keep-alive
+    /// stream, see [`keep_alive`]. The shared stream has a plain gRPC channel of its own, as
+    /// etcd-client's is private and so are the TLS and credentials in `etcd_connect_options`.
+    /// A client with any connect options, `https` endpoints or DNS discovery, whose endpoints
+    /// change under the channel, falls back to a stream per lease, as does a read-only one,
+    /// which has no leases.
+    fn shares_keep_alive(&self) -> bool {
+        self.etcd_connect_options.is_none()
+            && self.dns.is_none()
+            && !self.read_only
+            && !self.etcd_url.iter().any(|url| url.starts_with("https://"))
+    }
 }
 
 impl Default for ClientOptions {
     fn default() -> Self {
-        let mut connect_options = None;
-
-        if let (Ok(username), Ok(password)) = (
-            std::env::var("ETCD_AUTH_USERNAME"),
-            std::env::var("ETCD_AUTH_PASSWORD"),
-        ) {
-            // username and password are set
-            connect_options = Some(ConnectOptions::new().with_user(username, password));
-        } else if let (Ok(ca), Ok(cert), Ok(key)) = (
-            std::env::var("ETCD_AUTH_CA"),
-            std::env::var("ETCD_AUTH_CLIENT_CERT"),
-            std::env::var("ETCD_AUTH_CLIENT_KEY"),
-        ) {
-            // TLS is set
-            connect_options = Some(
-                ConnectOptions::new().with_tls(
-                    TlsOptions::new()
-                        .ca_certificate(Certificate::from_pem(ca))
-                        .identity(Identity::from_pem(cert, key)),
-                ),
-            );
-        }
+        let connect_options = auth_options(|name| std::env::var(name).ok());
+
+        let dns = DnsDiscovery::from_env().unwrap_or_else(|err| {
+            tracing::warn!(%err, "Ignoring etcd DNS discovery");
+            None
+        });
+
+        let proxy = ProxyConfig::from_env("ETCD").unwrap_or_else(|err| {
+            tracing::warn!(%err, "Ignoring the etcd proxy");
+            None
+        });
 
         ClientOptions {
             etcd_url: default_servers(),
             etcd_connect_options: connect_options,
             attach_lease: true,
+            read_only: false,
+            shared: false,
+            dns,
+            proxy,
+            clock_check_interval: Some(CLOCK_CHECK_INTERVAL),
+            lease_tuning: LeaseTuning::default(),
+            lease_ttl_policy: LeaseTtlPolicy::default(),
+            secrets: None,
+        }
+    }
+}
+
+/// The names of the variables, or secrets, holding the etcd credentials
+const AUTH_SECRETS: [&str; 5] = [
+    "ETCD_AUTH_USERNAME",
+    "ETCD_AUTH_PASSWORD",
+    "ETCD_AUTH_CA",
+    "ETCD_AUTH_CLIENT_CERT",
+    "ETCD_AUTH_CLIENT_KEY",
+];
+
+/// A username and password if both are set, otherwise TLS if the CA, certificate and key are
+fn auth_options(mut get: impl FnMut(&str) -> Option<String>) -> Option<ConnectOptions> {
+    if let (Some(username), Some(password)) = (get("ETCD_AUTH_USERNAME"), get("ETCD_AUTH_PASSWORD"))
+    {
+        return Some(ConnectOptions::new().with_user(username, password));
+    }
+    let (Some(ca), Some(cert), Some(key)) = (
+        get("ETCD_AUTH_CA"),
+        get("ETCD_AUTH_CLIENT_CERT"),
+        get("ETCD_AUTH_CLIENT_KEY"),
+    ) else {
+        return None;
+    };
+    Some(
+        ConnectOptions::new().with_tls(
+            TlsOptions::new()
+                .ca_certificate(Certificate::from_pem(ca))
+                .identity(Identity::from_pem(cert, key)),
+        ),
+    )
+}
+
+/// [`auth_options`] from `secrets`, read afresh for each connection
+async fn secret_auth_options(secrets: &dyn SecretsProvider) -> Result<Option<ConnectOptions>> {
+    let mut found = HashMap::new();
+    for name in AUTH_SECRETS {
+        if let Some(secret) = secrets.get(name).await? {
+            found.insert(name, secret.into_inner());
         }
     }
+    Ok(auth_options(|name| found.remove(name)))
 }
 
 fn default_servers() -> Vec<String> {
@@ -716,7 +1341,7 @@ mod tests {
     fn test_ectd_client() {
         let rt = Runtime::from_settings().unwrap();
         let rt_clone = rt.clone();
-        let config = DistributedConfig::from_settings(false);
+        let config = DistributedConfig::from_settings(false).unwrap();
 
         rt_clone.primary().block_on(async move {
             let drt = DistributedRuntime::new(rt, config).await.unwrap();
@@ -758,11 +1383,107 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_read_only_client() {
+        let rt = Runtime::from_settings().unwrap();
+        let rt_clone = rt.clone();
+        let options = ClientOptions {
+            read_only: true,
+            ..Default::default()
+        };
+
+        rt_clone.primary().block_on(async move {
+            let client = Client::new(options, rt).await.unwrap();
+            assert!(client.is_read_only());
+            assert_eq!(client.lease_id(), 0);
+
+            let key = "__integration_test_read_only_key";
+            assert!(client.kv_get(key, None).await.is_ok());
+            assert!(client.kv_create(key, b"x".to_vec(), None).await.is_err());
+            assert!(client.kv_put(key, b"x", None).await.is_err());
+            assert!(client.kv_delete(key, None).await.is_err());
+            assert!(client.create_lease(10).await.is_err());
+        });
+    }
+
+    #[test]
+    fn test_shared_connection() {
+        let rt = Runtime::from_settings().unwrap();
+        let rt_clone = rt.clone();
+        let options = ClientOptions {
+            shared: true,
+            ..Default::default()
+        };
+
+        rt_clone.primary().block_on(async move {
+            let a = Client::new(options.clone(), rt.clone()).await.unwrap();
+            let b = Client::new(options, rt).await.unwrap();
+            assert!(a.is_shared() && b.is_shared());
+            assert_ne!(a.lease_id(), 0);
+            assert_eq!(a.lease_id(), b.lease_id());
+            assert_eq!(ConnectionPool::global().len(), 1);
+
+            // The lease goes with the last client
+            drop(a);
+            assert_eq!(ConnectionPool::global().len(), 1);
+            drop(b);
+            assert!(ConnectionPool::global().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_kv_register_conflict_and_takeover() {
+        let rt = Runtime::from_settings().unwrap();
+        let rt_clone = rt.clone();
+
+        rt_clone.primary().block_on(async move {
+            let client = Client::new(ClientOptions::default(), rt).await.unwrap();
+            let stale = client.create_lease(10).await.unwrap();
+            let prefix = format!("__integration_test_register_{}", uuid::Uuid::new_v4());
+            let key = format!("{prefix}/instance");
+            let other = format!("{prefix}/other");
+            client
+                .kv_create(&other, b"x".to_vec(), Some(stale.id()))
+                .await
+                .unwrap();
+
+            let register = |value: &'static [u8], lease_id, takeover| {
+                client.kv_register(&key, value.to_vec(), Some(lease_id), takeover)
+            };
+            register(b"a", stale.id(), false).await.unwrap();
+            // Again with the same value and lease is fine
+            register(b"a", stale.id(), false).await.unwrap();
+
+            let err = register(b"b", client.lease_id(), false).await.unwrap_err();
+            let conflict = err.downcast_ref::<AlreadyRegistered>().unwrap();
+            assert_eq!(conflict.holder, stale.id());
+            assert_eq!(
+                client.kv_get(key.as_str(), None).await.unwrap()[0].value(),
+                b"a"
+            );
+
+            // Taking over revokes the stale holder's lease, and its other keys with it
+            register(b"b", client.lease_id(), true).await.unwrap();
+            let current = client.kv_get(key.as_str(), None).await.unwrap();
+            assert_eq!(current[0].value(), b"b");
+            assert_eq!(current[0].lease() as u64, client.lease_id());
+            assert!(
+                client
+                    .kv_get(other.as_str(), None)
+                    .await
+                    .unwrap()
+                    .is_empty()
+            );
+
+            client.kv_delete(key.as_str(), None).await.unwrap();
+        });
+    }
+
     #[test]
     fn test_kv_cache() {
         let rt = Runtime::from_settings().unwrap();
         let rt_clone = rt.clone();
-        let config = DistributedConfig::from_settings(false);
+        let config = DistributedConfig::from_settings(false).unwrap();
 
         rt_clone.primary().block_on(async move {
             let drt = DistributedRuntime::new(rt, config).await.unwrap();
@@ -856,3 +1577,135 @@ mod tests {
         Ok(())
     }
 }
+
+#[cfg(feature = "testing-etcd")]
+#[cfg(test)]
+mod etcd_tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_causal_token_across_clients() {
+        let runtime = Runtime::from_settings().unwrap();
+        let options = || {
+            Client::builder()
+                .etcd_url(vec!["http://localhost:2379".to_string()])
+                .build()
+                .unwrap()
+        };
+        let writer = Client::new(options(), runtime.clone()).await.unwrap();
+        let reader = Client::new(options(), runtime).await.unwrap();
+        // Prevent runtime from being dropped in async context at end of test
+        let writer = std::mem::ManuallyDrop::new(writer);
+        let reader = std::mem::ManuallyDrop::new(reader);
+
+        let key = format!("/test/causal/{}", uuid::Uuid::new_v4());
+        let before = writer.causal_token().await.unwrap();
+        writer.kv_put(&key, "registered", None).await.unwrap();
+        let token = writer.causal_token().await.unwrap();
+        assert!(token > before);
+        assert_eq!(token.merge(before), token);
+
+        let kvs = reader
+            .kv_get_with_consistency(key.as_str(), ReadConsistency::AtLeast(token))
+            .await
+            .unwrap();
+        assert_eq!(kvs[0].value(), b"registered");
+        assert!(reader.cluster_revision().await.unwrap() as u64 >= token.revision());
+        writer.kv_delete(key, None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_list_and_revoke_leases() {
+        let runtime = Runtime::from_settings().unwrap();
+        let options = Client::builder()
+            .etcd_url(vec!["http://localhost:2379".to_string()])
+            .build()
+            .unwrap();
+        let client = Client::new(options, runtime).await.unwrap();
+        // Prevent runtime from being dropped in async context at end of test
+        let client = std::mem::ManuallyDrop::new(client);
+
+        let zombie = client.create_lease(30).await.unwrap().id();
+        let key = format!("/test/leases/{}", uuid::Uuid::new_v4());
+        client
+            .kv_put(&key, "registered", Some(zombie))
+            .await
+            .unwrap();
+
+        let leases = client.list_leases().await.unwrap();
+        let listed = leases.iter().find(|lease| lease.id == zombie).unwrap();
+        assert_eq!(listed.granted_ttl, 30);
+        assert_eq!(listed.keys, std::slice::from_ref(&key));
+        assert!(leases.iter().any(|lease| lease.id == client.lease_id()));
+
+        client.revoke_lease(zombie).await.unwrap();
+        assert_eq!(client.lease_info(zombie).await.unwrap(), None);
+        assert!(client.kv_get(key.as_str(), None).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_leases_share_keep_alive_stream() {
+        let runtime = Runtime::from_settings().unwrap();
+        let options = Client::builder()
+            .etcd_url(vec!["http://localhost:2379".to_string()])
+            .build()
+            .unwrap();
+        let client = Client::new(options, runtime).await.unwrap();
+        // Prevent runtime from being dropped in async context at end of test
+        let client = std::mem::ManuallyDrop::new(client);
+
+        let mut leases = Vec::new();
+        for _ in 0..5 {
+            leases.push(client.create_lease(2).await.unwrap().id());
+        }
+        // Outlives the TTL only if the keep-alives get through
+        tokio::time::sleep(Duration::from_secs(4)).await;
+        for lease_id in &leases {
+            assert!(client.lease_info(*lease_id).await.unwrap().is_some());
+        }
+        // The primary lease and the five
+        let mux = client.keep_alive.as_ref().unwrap();
+        assert_eq!(mux.streams_opened(), 1);
+
+        for lease_id in leases {
+            client.revoke_lease(lease_id).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prefix_pages() {
+        let runtime = Runtime::from_settings().unwrap();
+        let options = Client::builder()
+            .etcd_url(vec!["http://localhost:2379".to_string()])
+            .build()
+            .unwrap();
+        let client = Client::new(options, runtime).await.unwrap();
+        // Prevent runtime from being dropped in async context at end of test
+        let client = std::mem::ManuallyDrop::new(client);
+
+        let prefix = format!("/test/pages/{}/", uuid::Uuid::new_v4());
+        for i in 0..5 {
+            client
+                .kv_put(format!("{prefix}{i}"), "registered", None)
+                .await
+                .unwrap();
+        }
+        let pages: Vec<_> = client
+            .kv_get_prefix_pages(&prefix, 2)
+            .try_collect()
+            .await
+            .unwrap();
+        let sizes: Vec<_> = pages.iter().map(|page| page.kvs.len()).collect();
+        assert_eq!(sizes, [2, 2, 1]);
+        assert!(pages.iter().all(|page| page.revision == pages[0].revision));
+        assert_eq!(client.kv_get_prefix(&prefix).await.unwrap().len(), 5);
+
+        assert_eq!(prefix_range_end(b"v1/instances/"), b"v1/instances0");
+        assert_eq!(prefix_range_end(b"a\xff"), b"b");
+        assert_eq!(prefix_range_end(b""), [0]);
+        client
+            .kv_delete(prefix, Some(DeleteOptions::new().with_prefix()))
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,208 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Sanity checks of the local clocks that lease deadlines rely on.
+//!
+//! The keep-alive loop times its deadlines with the monotonic clock, which stops while the
+//! process is suspended, as on a paused VM or a sleeping laptop. etcd keeps counting the lease
+//! down meanwhile, so after resuming the loop believes in a lease that expired long ago. A
+//! [`ClockCheck`] samples the monotonic clock, the wall clock and the TTL left on a probe lease
+//! that is never refreshed, and warns when they disagree by more than a lease can absorb.
+
+use std::time::{Duration, Instant, SystemTime};
+
+use super::*;
+
+/// Granted to the probe lease, which is replaced once half of it is used up
+const PROBE_TTL: i64 = 3600;
+
+/// Disagreement between clocks that is put down to etcd's whole-second TTLs and to jitter
+const TOLERANCE: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClockWarning {
+    /// The wall clock moved `wall`, 0 if backwards, while the monotonic clock moved
+    /// `monotonic`. The process was suspended, or the wall clock was stepped.
+    WallClockDiverged { monotonic: Duration, wall: Duration },
+    /// etcd counted the probe lease down by `server` while the monotonic clock moved `local`,
+    /// so local lease deadlines run late by the difference
+    ServerAhead { local: Duration, server: Duration },
+    /// Asking etcd about the probe lease took this long, too much of the lease TTL for
+    /// refreshes to arrive in time
+    SlowRoundTrip { round_trip: Duration },
+    /// The store revision went back, as after a restore from backup or when the endpoints
+    /// now point at another cluster
+    RevisionWentBack { from: i64, to: i64 },
+}
+
+/// The clocks as of one request for the probe lease
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    monotonic: Instant,
+    wall: SystemTime,
+    /// Seconds left on the probe lease
+    remaining: i64,
+    revision: i64,
+}
+
+/// Periodic clock checks against one etcd cluster, see the [module docs](self)
+pub struct ClockCheck {
+    lease_client: LeaseClient,
+    /// TTL of the leases being kept alive
+    lease_ttl: Duration,
+    probe: Option<i64>,
+    last: Option<Sample>,
+    last_revision: i64,
+}
+
+impl ClockCheck {
+    pub fn new(lease_client: LeaseClient, lease_ttl: u64) -> Self {
+        ClockCheck {
+            lease_client,
+            lease_ttl: Duration::from_secs(lease_ttl),
+            probe: None,
+            last: None,
+            last_revision: 0,
+        }
+    }
+
+    /// Sample the clocks and compare them with the last sample. The first call only grants
+    /// the probe lease and checks the round trip.
+    pub async fn check(&mut self) -> Result<Vec<ClockWarning>> {
+        let probe = match self.probe {
+            Some(probe) => probe,
+            None => {
+                let probe = self.lease_client.grant(PROBE_TTL, None).await?.id();
+                self.probe = Some(probe);
+                probe
+            }
+        };
+        let monotonic = Instant::now();
+        let wall = SystemTime::now();
+        let response = self.lease_client.time_to_live(probe, None).await?;
+        let round_trip = monotonic.elapsed();
+        let sample = Sample {
+            monotonic,
+            wall,
+            remaining: response.ttl(),
+            revision: response.header().map(|h| h.revision()).unwrap_or_default(),
+        };
+
+        let mut warnings = Vec::new();
+        if round_trip > self.lease_ttl / 4 {
+            warnings.push(ClockWarning::SlowRoundTrip { round_trip });
+        }
+        if sample.revision < self.last_revision {
+            warnings.push(ClockWarning::RevisionWentBack {
+                from: self.last_revision,
+                to: sample.revision,
+            });
+        }
+        self.last_revision = sample.revision;
+        if let Some(last) = self.last.replace(sample) {
+            warnings.extend(compare(&last, &sample));
+        }
+        // Expired means gone for longer than any warning could describe
+        if sample.remaining < PROBE_TTL / 2 {
+            if sample.remaining > 0 {
+                let _ = self.lease_client.revoke(probe).await;
+            }
+            self.probe = None;
+            self.last = None;
+        }
+        Ok(warnings)
+    }
+
+    /// Check every `interval`, starting now, and log what is found, until `token` is
+    /// cancelled. Revokes the probe lease when done.
+    pub async fn run(mut self, interval: Duration, token: CancellationToken) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = token.cancelled() => break,
+                _ = ticker.tick() => {}
+            }
+            match self.check().await {
+                Ok(warnings) => {
+                    for warning in warnings {
+                        tracing::warn!(?warning, "Clock check failed, lease TTLs may be unsafe");
+                    }
+                }
+                Err(err) => tracing::debug!(%err, "Unable to check clocks against etcd"),
+            }
+        }
+        if let Some(probe) = self.probe {
+            let _ = self.lease_client.revoke(probe).await;
+        }
+    }
+}
+
+fn compare(last: &Sample, next: &Sample) -> Vec<ClockWarning> {
+    let mut warnings = Vec::new();
+    let monotonic = next.monotonic.duration_since(last.monotonic);
+    let wall = next.wall.duration_since(last.wall).unwrap_or_default();
+    if wall.abs_diff(monotonic) > TOLERANCE {
+        warnings.push(ClockWarning::WallClockDiverged { monotonic, wall });
+    }
+    // etcd counting slower is normal: a new leader extends every lease
+    let server = Duration::from_secs((last.remaining - next.remaining).max(0) as u64);
+    if server > monotonic + TOLERANCE {
+        warnings.push(ClockWarning::ServerAhead {
+            local: monotonic,
+            server,
+        });
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(base: &Sample, monotonic: u64, wall: u64, server: i64) -> Sample {
+        Sample {
+            monotonic: base.monotonic + Duration::from_secs(monotonic),
+            wall: base.wall + Duration::from_secs(wall),
+            remaining: base.remaining - server,
+            revision: base.revision,
+        }
+    }
+
+    #[test]
+    fn test_compare() {
+        let base = Sample {
+            monotonic: Instant::now(),
+            wall: SystemTime::now(),
+            remaining: PROBE_TTL,
+            revision: 1,
+        };
+        // In step, give or take etcd's rounding
+        assert!(compare(&base, &sample(&base, 60, 60, 61)).is_empty());
+        // A new etcd leader restarted the countdown
+        assert!(compare(&base, &sample(&base, 60, 60, 5)).is_empty());
+
+        // Suspended for 5 minutes: only the monotonic clock stopped
+        assert_eq!(
+            compare(&base, &sample(&base, 60, 360, 360)),
+            vec![
+                ClockWarning::WallClockDiverged {
+                    monotonic: Duration::from_secs(60),
+                    wall: Duration::from_secs(360),
+                },
+                ClockWarning::ServerAhead {
+                    local: Duration::from_secs(60),
+                    server: Duration::from_secs(360),
+                },
+            ]
+        );
+        // The wall clock was stepped back
+        assert_eq!(
+            compare(&base, &sample(&base, 60, 0, 60)),
+            vec![ClockWarning::WallClockDiverged {
+                monotonic: Duration::from_secs(60),
+                wall: Duration::ZERO,
+            }]
+        );
+    }
+}
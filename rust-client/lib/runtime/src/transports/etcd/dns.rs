@@ -0,0 +1,220 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Finding the etcd members in DNS, for members behind a headless Service whose IPs change.
+//!
+//! - `ETCD_DNS_SRV`: an SRV name, such as `_etcd-client._tcp.etcd.default.svc.cluster.local`.
+//!   The target and port of each record is a member.
+//! - `ETCD_DNS_HOST`: `host:port`. Every A and AAAA record of `host` is a member on `port`.
+//! - `ETCD_DNS_REFRESH`: how often to look again, `30s` by default.
+//!
+//! What is found replaces [`ClientOptions::etcd_url`](super::ClientOptions::etcd_url), with
+//! `https` when `ETCD_AUTH_CA` is set. The client then adds and removes endpoints as the records
+//! change. A lookup that fails or finds nothing leaves the endpoints as they are.
+
+use std::collections::BTreeSet;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use hickory_resolver::TokioAsyncResolver;
+
+use crate::transports::proxy::Routes;
+use crate::{CancellationToken, ErrorContext, Result, error};
+
+const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DnsRecord {
+    Srv(String),
+    /// The A and AAAA records of `name`, all on `port`
+    Host {
+        name: String,
+        port: u16,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DnsDiscovery {
+    pub record: DnsRecord,
+    /// Connect with `https` rather than `http`
+    pub tls: bool,
+    pub refresh_interval: Duration,
+}
+
+impl DnsDiscovery {
+    /// From the environment, see the [module docs](self). None without `ETCD_DNS_SRV` or
+    /// `ETCD_DNS_HOST`.
+    pub fn from_env() -> Result<Option<Self>> {
+        let record = if let Ok(name) = std::env::var("ETCD_DNS_SRV") {
+            DnsRecord::Srv(name)
+        } else if let Ok(host) = std::env::var("ETCD_DNS_HOST") {
+            parse_host(&host)?
+        } else {
+            return Ok(None);
+        };
+        let refresh_interval = match std::env::var("ETCD_DNS_REFRESH") {
+            Ok(interval) => humantime::parse_duration(&interval)
+                .with_context(|| format!("Invalid ETCD_DNS_REFRESH '{interval}'"))?,
+            Err(_) => DEFAULT_REFRESH_INTERVAL,
+        };
+        Ok(Some(DnsDiscovery {
+            record,
+            tls: std::env::var("ETCD_AUTH_CA").is_ok(),
+            refresh_interval,
+        }))
+    }
+
+    /// The etcd endpoint URLs in DNS now, sorted. An error if there are none.
+    pub async fn resolve(&self) -> Result<Vec<String>> {
+        let scheme = if self.tls { "https" } else { "http" };
+        let resolver = TokioAsyncResolver::tokio_from_system_conf()?;
+        let mut endpoints = BTreeSet::new();
+        match &self.record {
+            DnsRecord::Srv(name) => {
+                let records = resolver
+                    .srv_lookup(name.as_str())
+                    .await
+                    .with_context(|| format!("SRV lookup of {name} failed"))?;
+                for record in records.iter() {
+                    let target = record.target().to_utf8();
+                    let target = target.trim_end_matches('.');
+                    endpoints.insert(format!("{scheme}://{target}:{}", record.port()));
+                }
+            }
+            DnsRecord::Host { name, port } => {
+                let ips = resolver
+                    .lookup_ip(name.as_str())
+                    .await
+                    .with_context(|| format!("Lookup of {name} failed"))?;
+                for ip in ips.iter() {
+                    endpoints.insert(format!("{scheme}://{}", SocketAddr::new(ip, *port)));
+                }
+            }
+        }
+        if endpoints.is_empty() {
+            return Err(error!("No etcd endpoints in DNS for {:?}", self.record));
+        }
+        Ok(endpoints.into_iter().collect())
+    }
+}
+
+/// Look the members up again every [`DnsDiscovery::refresh_interval`] until `token` is
+/// cancelled, moving `client` on to the endpoints found. `endpoints` are those it has now, dialed
+/// through `routes`.
+pub(crate) fn spawn_refresh(
+    handle: &tokio::runtime::Handle,
+    dns: DnsDiscovery,
+    client: etcd_client::Client,
+    mut endpoints: Vec<String>,
+    mut routes: Routes,
+    token: CancellationToken,
+) {
+    handle.spawn(async move {
+        let mut ticker = tokio::time::interval(dns.refresh_interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        // The first tick is immediate, and we've only just resolved
+        ticker.tick().await;
+        loop {
+            tokio::select! {
+                _ = token.cancelled() => break,
+                _ = ticker.tick() => {}
+            }
+            let found = match dns.resolve().await {
+                Ok(found) => found,
+                Err(err) => {
+                    tracing::warn!(%err, "Keeping the etcd endpoints we have");
+                    continue;
+                }
+            };
+            let (added, removed) = diff(&endpoints, &found);
+            if added.is_empty() && removed.is_empty() {
+                continue;
+            }
+            tracing::info!(?added, ?removed, "etcd endpoints changed in DNS");
+            // Add first so that there is always an endpoint to use
+            let mut failed = Vec::new();
+            for endpoint in added {
+                let result = match routes.add(endpoint).await {
+                    Ok(dialed) => client.add_endpoint(dialed).await.map_err(Into::into),
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = result {
+                    tracing::warn!(%err, endpoint, "Failed to add etcd endpoint");
+                    routes.remove(endpoint);
+                    failed.push(endpoint.to_string());
+                }
+            }
+            for endpoint in removed {
+                if let Err(err) = client.remove_endpoint(routes.remove(endpoint)).await {
+                    tracing::warn!(%err, endpoint, "Failed to remove etcd endpoint");
+                }
+            }
+            // Those that failed are tried again next time
+            endpoints = found;
+            endpoints.retain(|endpoint| !failed.contains(endpoint));
+        }
+    });
+}
+
+fn parse_host(host: &str) -> Result<DnsRecord> {
+    let Some((name, port)) = host.rsplit_once(':') else {
+        return Err(error!("ETCD_DNS_HOST '{host}' is not host:port"));
+    };
+    let port = port
+        .parse()
+        .with_context(|| format!("Invalid port in ETCD_DNS_HOST '{host}'"))?;
+    Ok(DnsRecord::Host {
+        name: name.to_string(),
+        port,
+    })
+}
+
+/// The endpoints in `found` but not `current`, and those in `current` but not `found`
+fn diff<'a>(current: &'a [String], found: &'a [String]) -> (Vec<&'a str>, Vec<&'a str>) {
+    let added = found
+        .iter()
+        .filter(|endpoint| !current.contains(*endpoint))
+        .map(String::as_str)
+        .collect();
+    let removed = current
+        .iter()
+        .filter(|endpoint| !found.contains(*endpoint))
+        .map(String::as_str)
+        .collect();
+    (added, removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_host() {
+        assert_eq!(
+            parse_host("etcd.default.svc:2379").unwrap(),
+            DnsRecord::Host {
+                name: "etcd.default.svc".to_string(),
+                port: 2379
+            }
+        );
+        assert!(parse_host("etcd.default.svc").is_err());
+        assert!(parse_host("etcd.default.svc:http").is_err());
+    }
+
+    #[test]
+    fn test_diff_endpoints() {
+        let urls = |hosts: &[u8]| -> Vec<String> {
+            hosts
+                .iter()
+                .map(|host| format!("http://10.0.0.{host}:2379"))
+                .collect()
+        };
+        let (current, found) = (urls(&[1, 2]), urls(&[2, 3]));
+        let (added, removed) = diff(&current, &found);
+        assert_eq!(added, vec!["http://10.0.0.3:2379"]);
+        assert_eq!(removed, vec!["http://10.0.0.1:2379"]);
+
+        let (added, removed) = diff(&found, &found);
+        assert!(added.is_empty() && removed.is_empty());
+    }
+}
@@ -0,0 +1,612 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Delete the keys left behind by workers that died without cleaning up.
+//!
+//! A key attached to a lease goes when the lease does, so the janitor only has to delete those
+//! whose lease is already gone. Workers that register with `attach_lease = false`, or write
+//! keys without a lease for some other reason, leave them behind after an unclean shutdown.
+//! Their value names the owner, by its [worker ID](crate::identity), and while the owner lives
+//! it keeps an [`OwnerHeartbeat`] fresh under [`JanitorConfig::heartbeat_prefix`] with
+//! [`heartbeat_owner`]. Once that is older than [`JanitorConfig::stale_after`], the owner is
+//! taken for dead and its keys for orphans. The janitor compares the heartbeat's time with its
+//! own clock, so `stale_after` must be well above the clock skew between hosts.
+//!
+//! One [`Janitor`] per cluster is elected with an etcd lock and sweeps the configured prefixes
+//! every so often. Each key it deletes is replaced, in the same transaction, by an
+//! [`AuditRecord`] under [`JanitorConfig::audit_prefix`]. A stale heartbeat goes the same way
+//! once none of its owner's keys are left.
+//!
+//! ```ignore
+//! // In the worker, without a lease
+//! runtime.secondary().spawn(heartbeat_owner(client.clone(), config.clone(), owner, token));
+//!
+//! // Anywhere
+//! let janitor = Janitor::new(client, JanitorConfig::default());
+//! runtime.supervisor().child("janitor").spawn("orphans", RestartPolicy::default(), move || {
+//!     let janitor = janitor.clone();
+//!     let token = token.clone();
+//!     async move { janitor.run(token).await }
+//! });
+//! ```
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use etcd_client::{Compare, CompareOp, Txn, TxnOp};
+use serde::{Deserialize, Serialize};
+
+use crate::component::{INSTANCE_ROOT_PATH, Instance};
+use crate::storage::encoding;
+use crate::utils::clock::{Clock, system_clock};
+use crate::{CancellationToken, Result, error};
+
+use super::{Client, KeyValue};
+
+#[derive(Debug, Clone)]
+pub struct JanitorConfig {
+    /// Scanned for orphans. Anything else is never touched.
+    pub prefixes: Vec<String>,
+    /// Between sweeps
+    pub interval: Duration,
+    /// The field of a JSON value naming the key's owner, by the ID it heartbeats as
+    pub owner_field: String,
+    /// Where owners keep their [`OwnerHeartbeat`], by owner
+    pub heartbeat_prefix: String,
+    /// Between an owner's heartbeats
+    pub heartbeat_interval: Duration,
+    /// An owner that hasn't heartbeated for this long is taken for dead
+    pub stale_after: Duration,
+    /// Held by the elected janitor
+    pub election_key: String,
+    pub audit_prefix: String,
+    /// Dates heartbeats and audit records, and tells how old a heartbeat is
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for JanitorConfig {
+    fn default() -> Self {
+        JanitorConfig {
+            prefixes: vec![format!("{INSTANCE_ROOT_PATH}/")],
+            interval: Duration::from_secs(60),
+            owner_field: "worker_id".to_string(),
+            heartbeat_prefix: "v1/janitor/heartbeats/".to_string(),
+            heartbeat_interval: Duration::from_secs(10),
+            stale_after: Duration::from_secs(300),
+            election_key: "v1/janitor/leader".to_string(),
+            audit_prefix: "v1/janitor/audit/".to_string(),
+            clock: system_clock(),
+        }
+    }
+}
+
+/// Why a key was taken for an orphan
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrphanReason {
+    /// The lease the key is attached to no longer exists
+    LeaseGone { lease_id: u64 },
+    /// The key has no lease, and the owner its value names last heartbeated longer than
+    /// [`JanitorConfig::stale_after`] ago, at this Unix time in milliseconds
+    OwnerStale { owner: String, last_heartbeat: u64 },
+}
+
+/// Written by an owner of lease-less keys at [`JanitorConfig::heartbeat_prefix`] + `owner`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OwnerHeartbeat {
+    pub owner: String,
+    /// Unix time in milliseconds
+    pub at: u64,
+}
+
+/// Written in place of each key the janitor deletes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub key: String,
+    /// The deleted value, lossily decoded as UTF-8
+    pub value: String,
+    pub reason: OrphanReason,
+    /// Unix time in milliseconds
+    pub deleted_at: u64,
+    /// The lease of the janitor that deleted it
+    pub janitor: u64,
+}
+
+#[derive(Clone)]
+pub struct Janitor {
+    client: Client,
+    config: JanitorConfig,
+}
+
+impl Janitor {
+    pub fn new(client: Client, config: JanitorConfig) -> Self {
+        Janitor { client, config }
+    }
+
+    /// Wait to be elected, then sweep every [`JanitorConfig::interval`] until `cancel_token`
+    /// is cancelled. The election is by the client's primary lease, which must exist.
+    pub async fn run(&self, cancel_token: CancellationToken) -> Result<()> {
+        if self.client.lease_id() == 0 {
+            return Err(error!(
+                "The orphan janitor needs a primary lease to be elected"
+            ));
+        }
+        let elected = tokio::select! {
+            _ = cancel_token.cancelled() => return Ok(()),
+            elected = self.client.lock(self.config.election_key.as_str(), None) => elected?,
+        };
+        tracing::info!(lease_id = self.client.lease_id(), "Elected orphan janitor");
+
+        let mut interval = tokio::time::interval(self.config.interval);
+        loop {
+            tokio::select! {
+                _ = cancel_token.cancelled() => break,
+                _ = interval.tick() => {}
+            }
+            match self.sweep().await {
+                Ok(deleted) if !deleted.is_empty() => {
+                    tracing::info!(count = deleted.len(), "Deleted orphaned keys");
+                }
+                Ok(_) => {}
+                Err(err) => tracing::warn!(%err, "Orphan sweep failed"),
+            }
+        }
+        if let Err(err) = self.client.unlock(elected.key().to_vec()).await {
+            tracing::warn!(%err, "Unable to step down as orphan janitor");
+        }
+        Ok(())
+    }
+
+    /// Delete the orphans under the configured prefixes once, whether elected or not, then the
+    /// stale heartbeats of owners that have no keys left
+    pub async fn sweep(&self) -> Result<Vec<AuditRecord>> {
+        self.client.check_writable("janitor sweep")?;
+        let now = self.config.clock.unix_millis();
+        let heartbeats = self.heartbeats().await?;
+        // Lease lookups for this sweep, as many keys tend to share a lease
+        let mut alive = HashMap::new();
+        // Owners with keys still there, whose heartbeats must stay
+        let mut owning = HashSet::new();
+        let mut deleted = Vec::new();
+        for prefix in &self.config.prefixes {
+            for kv in self.client.kv_get_prefix(prefix).await? {
+                let reason = self
+                    .orphan_reason(&kv, &heartbeats, now, &mut alive)
+                    .await?;
+                let deleted_kv = match reason {
+                    Some(reason) => self.delete(&kv, reason).await?,
+                    None => None,
+                };
+                match deleted_kv {
+                    Some(record) => {
+                        tracing::info!(key = %record.key, reason = ?record.reason, "Deleted orphaned key");
+                        deleted.push(record);
+                    }
+                    None if kv.lease() == 0 => {
+                        owning.extend(owner_of(kv.value(), &self.config.owner_field));
+                    }
+                    None => {}
+                }
+            }
+        }
+        for (owner, (kv, heartbeat)) in &heartbeats {
+            if owning.contains(owner) {
+                continue;
+            }
+            let Some(reason) = stale_reason(owner, Some(heartbeat), now, self.config.stale_after)
+            else {
+                continue;
+            };
+            if let Some(record) = self.delete(kv, reason).await? {
+                tracing::info!(%owner, "Deleted stale owner heartbeat");
+                deleted.push(record);
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// The heartbeats under [`JanitorConfig::heartbeat_prefix`], by owner, unreadable ones left out
+    async fn heartbeats(&self) -> Result<HashMap<String, (KeyValue, OwnerHeartbeat)>> {
+        let mut heartbeats = HashMap::new();
+        for kv in self
+            .client
+            .kv_get_prefix(&self.config.heartbeat_prefix)
+            .await?
+        {
+            match serde_json::from_slice::<OwnerHeartbeat>(kv.value()) {
+                Ok(heartbeat) => {
+                    heartbeats.insert(heartbeat.owner.clone(), (kv, heartbeat));
+                }
+                Err(err) => {
+                    let key = String::from_utf8_lossy(kv.key());
+                    tracing::warn!(%key, %err, "Ignoring unreadable owner heartbeat");
+                }
+            }
+        }
+        Ok(heartbeats)
+    }
+
+    async fn orphan_reason(
+        &self,
+        kv: &KeyValue,
+        heartbeats: &HashMap<String, (KeyValue, OwnerHeartbeat)>,
+        now: u64,
+        alive: &mut HashMap<u64, bool>,
+    ) -> Result<Option<OrphanReason>> {
+        if kv.lease() != 0 {
+            let lease_id = kv.lease() as u64;
+            let gone = !self.lease_alive(lease_id, alive).await?;
+            return Ok(gone.then_some(OrphanReason::LeaseGone { lease_id }));
+        }
+        // Without an owner, there is no telling whether it is still wanted
+        let Some(owner) = owner_of(kv.value(), &self.config.owner_field) else {
+            return Ok(None);
+        };
+        let heartbeat = heartbeats.get(&owner).map(|(_, heartbeat)| heartbeat);
+        Ok(stale_reason(
+            &owner,
+            heartbeat,
+            now,
+            self.config.stale_after,
+        ))
+    }
+
+    async fn lease_alive(&self, lease_id: u64, alive: &mut HashMap<u64, bool>) -> Result<bool> {
+        if let Some(known) = alive.get(&lease_id) {
+            return Ok(*known);
+        }
+        let response = self
+            .client
+            .etcd_client()
+            .lease_client()
+            .time_to_live(lease_id as i64, None)
+            .await?;
+        // -1 for a lease that expired or never existed
+        let is_alive = response.ttl() >= 0;
+        alive.insert(lease_id, is_alive);
+        Ok(is_alive)
+    }
+
+    /// Delete `kv` and write its audit record, unless it changed since it was read. A lease
+    /// never comes back, but the key could have been taken over by a new owner.
+    async fn delete(&self, kv: &KeyValue, reason: OrphanReason) -> Result<Option<AuditRecord>> {
+        self.client.check_writable("janitor delete")?;
+        let key = String::from_utf8_lossy(kv.key()).to_string();
+        let deleted_at = self.config.clock.unix_millis();
+        let record = AuditRecord {
+            key: key.clone(),
+            value: String::from_utf8_lossy(kv.value()).to_string(),
+            reason,
+            deleted_at,
+            janitor: self.client.lease_id(),
+        };
+        let audit_key = format!(
+            "{}{deleted_at:013}-{}",
+            self.config.audit_prefix,
+            uuid::Uuid::new_v4().simple()
+        );
+        let txn = Txn::new()
+            .when(vec![Compare::mod_revision(
+                key.as_str(),
+                CompareOp::Equal,
+                kv.mod_revision(),
+            )])
+            .and_then(vec![
+                TxnOp::delete(key.as_str(), None),
+                TxnOp::put(audit_key, serde_json::to_vec(&record)?, None),
+            ]);
+        let response = self.client.etcd_client().kv_client().txn(txn).await?;
+        Ok(response.succeeded().then_some(record))
+    }
+}
+
+/// Write `owner`'s [`OwnerHeartbeat`] every [`JanitorConfig::heartbeat_interval`], without a
+/// lease, until `cancel_token` is cancelled. For workers whose keys have no lease either, so the
+/// janitor can tell they are alive.
+pub async fn heartbeat_owner(
+    client: Client,
+    config: JanitorConfig,
+    owner: String,
+    cancel_token: CancellationToken,
+) -> Result<()> {
+    let key = format!("{}{owner}", config.heartbeat_prefix);
+    let mut interval = tokio::time::interval(config.heartbeat_interval);
+    loop {
+        tokio::select! {
+            _ = cancel_token.cancelled() => return Ok(()),
+            _ = interval.tick() => {}
+        }
+        let heartbeat = OwnerHeartbeat {
+            owner: owner.clone(),
+            at: config.clock.unix_millis(),
+        };
+        // The next one may get through, and a few missed ones are within `stale_after`
+        if let Err(err) = client
+            .kv_put(&key, serde_json::to_vec(&heartbeat)?, Some(0))
+            .await
+        {
+            tracing::warn!(%err, %owner, "Unable to write owner heartbeat");
+        }
+    }
+}
+
+/// Whether `owner` is dead by its last heartbeat. None if it never heartbeated, as an owner
+/// from before heartbeats or one that hasn't written its first yet can't be told from a dead one.
+fn stale_reason(
+    owner: &str,
+    heartbeat: Option<&OwnerHeartbeat>,
+    now: u64,
+    stale_after: Duration,
+) -> Option<OrphanReason> {
+    let last_heartbeat = heartbeat?.at;
+    let stale = now.saturating_sub(last_heartbeat) > stale_after.as_millis() as u64;
+    stale.then(|| OrphanReason::OwnerStale {
+        owner: owner.to_string(),
+        last_heartbeat,
+    })
+}
+
+/// The owner named by `field` in a JSON object, None if there is none. An instance registered
+/// as protobuf has no field names, but its `worker_id` is still there.
+fn owner_of(value: &[u8], field: &str) -> Option<String> {
+    let owner = match serde_json::from_slice::<serde_json::Value>(value) {
+        Ok(value) => match value.get(field)? {
+            serde_json::Value::String(owner) => owner.clone(),
+            serde_json::Value::Number(owner) => owner.to_string(),
+            _ => return None,
+        },
+        Err(_) if field == "worker_id" => encoding::decode::<Instance>(value)
+            .ok()?
+            .worker_id?
+            .to_string(),
+        Err(_) => return None,
+    };
+    Some(owner).filter(|owner| !owner.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_owner_of() {
+        // No lease, as with attach_lease = false, but an owner
+        let value = br#"{"component": "backend", "instance_id": 0, "worker_id": "w-1"}"#;
+        assert_eq!(owner_of(value, "worker_id").as_deref(), Some("w-1"));
+        assert_eq!(owner_of(value, "worker"), None);
+        assert_eq!(
+            owner_of(br#"{"owner": 1234}"#, "owner").as_deref(),
+            Some("1234")
+        );
+        assert_eq!(owner_of(br#"{"worker_id": ""}"#, "worker_id"), None);
+        assert_eq!(owner_of(br#"{"worker_id": null}"#, "worker_id"), None);
+        assert_eq!(owner_of(b"not json", "worker_id"), None);
+    }
+
+    #[test]
+    fn test_stale_reason() {
+        let stale_after = Duration::from_secs(60);
+        let heartbeat = OwnerHeartbeat {
+            owner: "w-1".to_string(),
+            at: 1_000_000,
+        };
+        let at = |secs: u64| heartbeat.at + secs * 1000;
+        assert_eq!(
+            stale_reason("w-1", Some(&heartbeat), at(0), stale_after),
+            None
+        );
+        assert_eq!(
+            stale_reason("w-1", Some(&heartbeat), at(60), stale_after),
+            None
+        );
+        assert_eq!(
+            stale_reason("w-1", Some(&heartbeat), at(61), stale_after),
+            Some(OrphanReason::OwnerStale {
+                owner: "w-1".to_string(),
+                last_heartbeat: heartbeat.at,
+            })
+        );
+        // From a clock behind the owner's
+        assert_eq!(stale_reason("w-1", Some(&heartbeat), 0, stale_after), None);
+        // Never heartbeated
+        assert_eq!(stale_reason("w-1", None, at(600), stale_after), None);
+    }
+}
+
+#[cfg(feature = "testing-etcd")]
+#[cfg(test)]
+mod etcd_tests {
+    use super::*;
+    use crate::Runtime;
+
+    #[tokio::test]
+    async fn test_sweep_deletes_orphans_only() {
+        let runtime = Runtime::from_settings().unwrap();
+        let options = Client::builder()
+            .etcd_url(vec!["http://localhost:2379".to_string()])
+            .build()
+            .unwrap();
+        let client = Client::new(options, runtime).await.unwrap();
+        // Prevent runtime from being dropped in async context at end of test
+        let client = std::mem::ManuallyDrop::new(client);
+
+        let root = format!("/test/janitor/{}", uuid::Uuid::new_v4());
+        let config = JanitorConfig {
+            prefixes: vec![format!("{root}/keys/")],
+            heartbeat_prefix: format!("{root}/heartbeats/"),
+            audit_prefix: format!("{root}/audit/"),
+            stale_after: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let janitor = Janitor::new((*client).clone(), config.clone());
+
+        let now = config.clock.unix_millis();
+        let heartbeat = |owner: &str, at: u64| {
+            let key = format!("{root}/heartbeats/{owner}");
+            let heartbeat = OwnerHeartbeat {
+                owner: owner.to_string(),
+                at,
+            };
+            (key, serde_json::to_vec(&heartbeat).unwrap())
+        };
+        let (stale_key, stale_value) = heartbeat("stale", now - 3_600_000);
+        let (fresh_key, fresh_value) = heartbeat("fresh", now);
+        client
+            .kv_put(&stale_key, stale_value, Some(0))
+            .await
+            .unwrap();
+        client
+            .kv_put(&fresh_key, fresh_value, Some(0))
+            .await
+            .unwrap();
+
+        // Lease-less, as with attach_lease = false, so only the owner says whether it is wanted
+        let value = |owner: &str| format!(r#"{{"instance_id": 0, "worker_id": "{owner}"}}"#);
+        let orphan = format!("{root}/keys/orphan");
+        let owned = format!("{root}/keys/owned");
+        let never = format!("{root}/keys/never");
+        let unowned = format!("{root}/keys/unowned");
+        let leased = format!("{root}/keys/leased");
+        client
+            .kv_put(&orphan, value("stale"), Some(0))
+            .await
+            .unwrap();
+        client
+            .kv_put(&owned, value("fresh"), Some(0))
+            .await
+            .unwrap();
+        client
+            .kv_put(&never, value("never"), Some(0))
+            .await
+            .unwrap();
+        client
+            .kv_put(&unowned, r#"{"instance_id": 0}"#, Some(0))
+            .await
+            .unwrap();
+        client.kv_put(&leased, value("stale"), None).await.unwrap();
+
+        let deleted = janitor.sweep().await.unwrap();
+        let stale = OrphanReason::OwnerStale {
+            owner: "stale".to_string(),
+            last_heartbeat: now - 3_600_000,
+        };
+        let keys: Vec<_> = deleted.iter().map(|record| record.key.as_str()).collect();
+        assert_eq!(keys, [orphan.as_str(), stale_key.as_str()]);
+        assert!(deleted.iter().all(|record| record.reason == stale));
+
+        let left = client.kv_get_prefix(format!("{root}/keys/")).await.unwrap();
+        assert_eq!(left.len(), 4);
+        let left = client
+            .kv_get_prefix(format!("{root}/heartbeats/"))
+            .await
+            .unwrap();
+        assert_eq!(left.len(), 1);
+        let audit = client
+            .kv_get_prefix(format!("{root}/audit/"))
+            .await
+            .unwrap();
+        let mut records: Vec<AuditRecord> = audit
+            .iter()
+            .map(|kv| serde_json::from_slice(kv.value()).unwrap())
+            .collect();
+        records.sort_by(|a, b| a.key.cmp(&b.key));
+        let mut expected = deleted.clone();
+        expected.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(records, expected);
+
+        for key in [owned, never, unowned, leased, fresh_key] {
+            client.kv_delete(key, None).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_owner() {
+        let runtime = Runtime::from_settings().unwrap();
+        let options = Client::builder()
+            .etcd_url(vec!["http://localhost:2379".to_string()])
+            .build()
+            .unwrap();
+        let client = Client::new(options, runtime).await.unwrap();
+        // Prevent runtime from being dropped in async context at end of test
+        let client = std::mem::ManuallyDrop::new(client);
+
+        let root = format!("/test/janitor/{}", uuid::Uuid::new_v4());
+        let config = JanitorConfig {
+            heartbeat_prefix: format!("{root}/heartbeats/"),
+            heartbeat_interval: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let cancel_token = CancellationToken::new();
+        let task = tokio::spawn(heartbeat_owner(
+            (*client).clone(),
+            config,
+            "w-1".to_string(),
+            cancel_token.clone(),
+        ));
+
+        let key = format!("{root}/heartbeats/w-1");
+        let read = || async {
+            let kvs = client.kv_get(key.as_str(), None).await.unwrap();
+            let heartbeat: OwnerHeartbeat = serde_json::from_slice(kvs[0].value()).unwrap();
+            (heartbeat, kvs[0].lease())
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let (first, lease) = read().await;
+        assert_eq!(first.owner, "w-1");
+        assert_eq!(lease, 0);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let (later, _) = read().await;
+        assert!(later.at > first.at);
+
+        cancel_token.cancel();
+        task.await.unwrap().unwrap();
+        client.kv_delete(key, None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_only_sweep() {
+        let runtime = Runtime::from_settings().unwrap();
+        let options = |read_only| {
+            Client::builder()
+                .etcd_url(vec!["http://localhost:2379".to_string()])
+                .read_only(read_only)
+                .build()
+                .unwrap()
+        };
+        let writer = Client::new(options(false), runtime.clone()).await.unwrap();
+        let reader = Client::new(options(true), runtime).await.unwrap();
+        // Prevent runtime from being dropped in async context at end of test
+        let writer = std::mem::ManuallyDrop::new(writer);
+        let reader = std::mem::ManuallyDrop::new(reader);
+
+        let root = format!("/test/janitor/{}", uuid::Uuid::new_v4());
+        let janitor = Janitor::new(
+            (*reader).clone(),
+            JanitorConfig {
+                prefixes: vec![format!("{root}/keys/")],
+                heartbeat_prefix: format!("{root}/heartbeats/"),
+                audit_prefix: format!("{root}/audit/"),
+                ..Default::default()
+            },
+        );
+        let heartbeat = format!("{root}/heartbeats/w-1");
+        let value = serde_json::to_vec(&OwnerHeartbeat {
+            owner: "w-1".to_string(),
+            at: 0,
+        })
+        .unwrap();
+        writer.kv_put(&heartbeat, value, Some(0)).await.unwrap();
+        let orphan = format!("{root}/keys/orphan");
+        writer
+            .kv_put(&orphan, r#"{"worker_id": "w-1"}"#, Some(0))
+            .await
+            .unwrap();
+
+        assert!(janitor.sweep().await.is_err());
+        assert_eq!(writer.kv_get(orphan.as_str(), None).await.unwrap().len(), 1);
+        for key in [orphan, heartbeat] {
+            writer.kv_delete(key, None).await.unwrap();
+        }
+    }
+}
@@ -0,0 +1,446 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! One lease keep-alive stream for all the leases of a client.
+//!
+//! etcd refreshes any lease named in a request on a keep-alive stream, and its responses name
+//! the lease too. etcd-client's [`etcd_client::LeaseKeeper`] is bound to the lease it was opened
+//! for, so each lease used to open a stream of its own: a process holding hundreds of leases
+//! kept hundreds of gRPC streams open. [`KeepAliveMux`] opens one, sends every lease's requests
+//! on it and routes the responses back by lease.
+//!
+//! The stream is opened when the first lease needs it and again after it breaks. Every lease
+//! on a broken stream gets an error from [`LeaseHeartbeat::receive`], so its keep-alive loop
+//! restarts and joins the new stream.
+//!
+//! The etcd stream is a plain gRPC call over its own channel to the client's endpoints, as
+//! etcd-client's channel is private. Clients with TLS or credentials, or that follow DNS, keep
+//! a stream per lease through etcd-client, which has their configuration; see
+//! `ClientOptions::shares_keep_alive` for the whole rule.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use etcd_client::LeaseClient;
+use futures::StreamExt;
+use futures::stream::BoxStream;
+use parking_lot::Mutex;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::{Result, error};
+
+use super::lease::LeaseHeartbeat;
+
+/// Keep-alive requests waiting to go out on the shared stream
+const REQUEST_BUFFER: usize = 256;
+
+/// The lease calls of a [`KeepAliveMux`], so it can run against a simulated server
+#[async_trait::async_trait]
+pub(crate) trait LeaseServer: Send + Sync {
+    /// Open a keep-alive stream. Each lease ID from `requests` asks for that lease to be
+    /// refreshed, each item of the stream returned is a lease ID and its TTL, 0 or less if it
+    /// is gone.
+    async fn open(
+        &self,
+        requests: ReceiverStream<u64>,
+    ) -> Result<BoxStream<'static, Result<(u64, i64)>>>;
+
+    /// Seconds left on `lease_id`, 0 if it is gone
+    async fn remaining(&self, lease_id: u64) -> Result<u64>;
+
+    async fn revoke(&self, lease_id: u64) -> Result<()>;
+}
+
+/// See the [module](self) docs. Cheap to clone, clones share the stream.
+#[derive(Clone)]
+pub(crate) struct KeepAliveMux {
+    inner: Arc<MuxInner>,
+}
+
+struct MuxInner {
+    server: Box<dyn LeaseServer>,
+    /// Locked across opening, so leases that need a stream at once share the one opened
+    current: tokio::sync::Mutex<Option<Arc<SharedStream>>>,
+    opened: AtomicU64,
+}
+
+/// Where the responses for each lease on a stream go. None once the stream has ended, so no
+/// lease joins it.
+type Routes = Arc<Mutex<Option<HashMap<u64, mpsc::UnboundedSender<Result<u64>>>>>>;
+
+struct SharedStream {
+    requests: mpsc::Sender<u64>,
+    routes: Routes,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for SharedStream {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl KeepAliveMux {
+    pub(crate) fn new(server: impl LeaseServer + 'static) -> Self {
+        KeepAliveMux {
+            inner: Arc::new(MuxInner {
+                server: Box::new(server),
+                current: tokio::sync::Mutex::new(None),
+                opened: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// How many streams were opened so far
+    pub(crate) fn streams_opened(&self) -> u64 {
+        self.inner.opened.load(Ordering::Relaxed)
+    }
+
+    /// The heartbeat of `lease_id` on the shared stream, opening it if there is none or it broke
+    pub(crate) async fn heartbeat(&self, lease_id: u64) -> Result<MuxHeartbeat> {
+        let (tx, responses) = mpsc::unbounded_channel();
+        let mut current = self.inner.current.lock().await;
+        if let Some(stream) = current.as_ref()
+            && let Some(routes) = stream.routes.lock().as_mut()
+        {
+            routes.insert(lease_id, tx);
+            return Ok(self.join(lease_id, stream.clone(), responses));
+        }
+
+        let (requests, requests_rx) = mpsc::channel(REQUEST_BUFFER);
+        let mut incoming = self
+            .inner
+            .server
+            .open(ReceiverStream::new(requests_rx))
+            .await?;
+        self.inner.opened.fetch_add(1, Ordering::Relaxed);
+        tracing::debug!(lease_id, "Opened the shared lease keep-alive stream");
+        let routes: Routes = Arc::new(Mutex::new(Some(HashMap::from([(lease_id, tx)]))));
+        let task_routes = routes.clone();
+        let task = tokio::spawn(async move {
+            let err = loop {
+                match incoming.next().await {
+                    Some(Ok((id, ttl))) => {
+                        if let Some(tx) = task_routes.lock().as_ref().and_then(|r| r.get(&id)) {
+                            let _ = tx.send(Ok(ttl.max(0) as u64));
+                        }
+                    }
+                    Some(Err(err)) => break err,
+                    None => break error!("Lease keep-alive stream ended"),
+                }
+            };
+            tracing::warn!(%err, "Shared lease keep-alive stream broke");
+            for (_, tx) in task_routes.lock().take().into_iter().flatten() {
+                let _ = tx.send(Err(error!("{err}")));
+            }
+        });
+        let stream = Arc::new(SharedStream {
+            requests,
+            routes,
+            task,
+        });
+        *current = Some(stream.clone());
+        Ok(self.join(lease_id, stream, responses))
+    }
+
+    fn join(
+        &self,
+        lease_id: u64,
+        stream: Arc<SharedStream>,
+        responses: mpsc::UnboundedReceiver<Result<u64>>,
+    ) -> MuxHeartbeat {
+        MuxHeartbeat {
+            mux: self.inner.clone(),
+            lease_id,
+            stream,
+            responses,
+        }
+    }
+}
+
+/// One lease's share of a [`KeepAliveMux`] stream
+pub(crate) struct MuxHeartbeat {
+    mux: Arc<MuxInner>,
+    lease_id: u64,
+    stream: Arc<SharedStream>,
+    responses: mpsc::UnboundedReceiver<Result<u64>>,
+}
+
+impl Drop for MuxHeartbeat {
+    fn drop(&mut self) {
+        if let Some(routes) = self.stream.routes.lock().as_mut() {
+            routes.remove(&self.lease_id);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl LeaseHeartbeat for MuxHeartbeat {
+    async fn send(&mut self) -> Result<()> {
+        self.stream
+            .requests
+            .send(self.lease_id)
+            .await
+            .map_err(|_| error!("Lease keep-alive stream closed"))
+    }
+
+    async fn receive(&mut self) -> Result<Option<u64>> {
+        match self.responses.recv().await {
+            Some(ttl) => ttl.map(Some),
+            None => Err(error!("Lease keep-alive stream closed")),
+        }
+    }
+
+    async fn remaining(&mut self) -> Result<u64> {
+        self.mux.server.remaining(self.lease_id).await
+    }
+
+    async fn revoke(&mut self) -> Result<()> {
+        self.mux.server.revoke(self.lease_id).await
+    }
+}
+
+/// etcd's `LeaseKeepAliveRequest`
+#[derive(Clone, PartialEq, prost::Message)]
+struct LeaseKeepAliveRequest {
+    #[prost(int64, tag = "1")]
+    id: i64,
+}
+
+/// etcd's `LeaseKeepAliveResponse`, without the header
+#[derive(Clone, PartialEq, prost::Message)]
+struct LeaseKeepAliveResponse {
+    #[prost(int64, tag = "2")]
+    id: i64,
+    #[prost(int64, tag = "3")]
+    ttl: i64,
+}
+
+/// Keep-alive streams over a plain gRPC channel to etcd, the other calls through `client`
+pub(crate) struct EtcdLeaseServer {
+    client: LeaseClient,
+    channel: tonic::transport::Channel,
+}
+
+impl EtcdLeaseServer {
+    /// Balanced across `endpoints`, as `client` is. Must be called within a tokio runtime.
+    pub(crate) fn new(client: LeaseClient, endpoints: &[String]) -> Result<Self> {
+        let endpoints = endpoints
+            .iter()
+            .map(|url| {
+                let url = if url.contains("://") {
+                    url.clone()
+                } else {
+                    format!("http://{url}")
+                };
+                tonic::transport::Endpoint::from_shared(url).map_err(|err| error!("{err}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let channel = tonic::transport::Channel::balance_list(endpoints.into_iter());
+        Ok(EtcdLeaseServer { client, channel })
+    }
+}
+
+#[async_trait::async_trait]
+impl LeaseServer for EtcdLeaseServer {
+    async fn open(
+        &self,
+        requests: ReceiverStream<u64>,
+    ) -> Result<BoxStream<'static, Result<(u64, i64)>>> {
+        let mut grpc = tonic::client::Grpc::new(self.channel.clone());
+        grpc.ready().await.map_err(|err| error!("{err}"))?;
+        let path = tonic::codegen::http::uri::PathAndQuery::from_static(
+            "/etcdserverpb.Lease/LeaseKeepAlive",
+        );
+        let codec = tonic::codec::ProstCodec::default();
+        let requests = requests.map(|id| LeaseKeepAliveRequest { id: id as i64 });
+        let response = grpc
+            .streaming(tonic::Request::new(requests), path, codec)
+            .await?;
+        let responses = response.into_inner().map(|resp| {
+            let resp: LeaseKeepAliveResponse = resp?;
+            Ok((resp.id as u64, resp.ttl))
+        });
+        Ok(responses.boxed())
+    }
+
+    async fn remaining(&self, lease_id: u64) -> Result<u64> {
+        let mut client = self.client.clone();
+        let resp = client.time_to_live(lease_id as i64, None).await?;
+        Ok(resp.ttl().max(0) as u64)
+    }
+
+    async fn revoke(&self, lease_id: u64) -> Result<()> {
+        self.client.clone().revoke(lease_id as i64).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CancellationToken;
+    use crate::transports::etcd::lease::{Progress, run_keep_alive};
+    use crate::transports::etcd::{ClientOptions, DnsDiscovery, DnsRecord, auth_options};
+    use etcd_client::ConnectOptions;
+    use std::time::Duration;
+
+    const TTL: i64 = 4;
+
+    /// Where [`Echo`] sends its responses, a lease and its TTL
+    type Responses = mpsc::UnboundedSender<Result<(u64, i64)>>;
+
+    /// A lease server answering every request with the full TTL, except for revoked leases
+    #[derive(Clone, Default)]
+    struct Echo {
+        revoked: Arc<Mutex<Vec<u64>>>,
+        /// Requests seen, by lease
+        seen: Arc<Mutex<HashMap<u64, u64>>>,
+        /// Set to break the open stream
+        broken: Arc<Mutex<Option<Responses>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl LeaseServer for Echo {
+        async fn open(
+            &self,
+            mut requests: ReceiverStream<u64>,
+        ) -> Result<BoxStream<'static, Result<(u64, i64)>>> {
+            let (tx, rx) = mpsc::unbounded_channel();
+            *self.broken.lock() = Some(tx.clone());
+            let echo = self.clone();
+            tokio::spawn(async move {
+                while let Some(id) = requests.next().await {
+                    *echo.seen.lock().entry(id).or_default() += 1;
+                    let ttl = if echo.revoked.lock().contains(&id) {
+                        -1
+                    } else {
+                        TTL
+                    };
+                    if tx.send(Ok((id, ttl))).is_err() {
+                        break;
+                    }
+                }
+            });
+            Ok(tokio_stream::wrappers::UnboundedReceiverStream::new(rx).boxed())
+        }
+
+        async fn remaining(&self, _lease_id: u64) -> Result<u64> {
+            Ok(TTL as u64)
+        }
+
+        async fn revoke(&self, lease_id: u64) -> Result<()> {
+            self.revoked.lock().push(lease_id);
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_leases_share_one_stream() {
+        let echo = Echo::default();
+        let mux = KeepAliveMux::new(echo.clone());
+        let token = CancellationToken::new();
+
+        let mut tasks = Vec::new();
+        for lease_id in 1..=5 {
+            let heartbeat = mux.heartbeat(lease_id).await.unwrap();
+            let keep_alive = run_keep_alive(
+                heartbeat,
+                lease_id,
+                TTL as u64,
+                token.clone(),
+                Progress::default(),
+            );
+            tasks.push(tokio::spawn(keep_alive));
+        }
+        // Heartbeats every TTL / 2 = 2s, so 3 each in 7s
+        tokio::time::sleep(Duration::from_secs(7)).await;
+        assert_eq!(mux.streams_opened(), 1);
+        let seen = echo.seen.lock().clone();
+        assert_eq!(seen, (1..=5).map(|id| (id, 3)).collect());
+        assert!(tasks.iter().all(|task| !task.is_finished()));
+
+        // The server says lease 3 is gone, only its loop ends
+        echo.revoked.lock().push(3);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let ended = tasks.remove(2).await.unwrap();
+        assert!(
+            ended
+                .unwrap_err()
+                .to_string()
+                .contains("expired or revoked")
+        );
+        assert!(tasks.iter().all(|task| !task.is_finished()));
+        assert_eq!(mux.streams_opened(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_broken_stream_is_reopened() {
+        let echo = Echo::default();
+        let mux = KeepAliveMux::new(echo.clone());
+
+        let mut first = mux.heartbeat(1).await.unwrap();
+        let mut second = mux.heartbeat(2).await.unwrap();
+        first.send().await.unwrap();
+        assert_eq!(first.receive().await.unwrap(), Some(TTL as u64));
+
+        let broken = echo.broken.lock().take().unwrap();
+        broken.send(Err(error!("connection reset"))).unwrap();
+        for heartbeat in [&mut first, &mut second] {
+            let err = heartbeat.receive().await.unwrap_err();
+            assert!(err.to_string().contains("connection reset"), "{err}");
+        }
+
+        // Restarted keep-alive loops join a new stream
+        drop((first, second));
+        let mut again = mux.heartbeat(1).await.unwrap();
+        let _other = mux.heartbeat(2).await.unwrap();
+        assert_eq!(mux.streams_opened(), 2);
+        again.send().await.unwrap();
+        assert_eq!(again.receive().await.unwrap(), Some(TTL as u64));
+    }
+
+    #[test]
+    fn test_keep_alive_sharing_falls_back() {
+        let plain = ClientOptions {
+            etcd_url: vec!["http://localhost:2379".to_string()],
+            etcd_connect_options: None,
+            dns: None,
+            ..Default::default()
+        };
+        assert!(plain.shares_keep_alive());
+
+        let fallbacks = [
+            ClientOptions {
+                etcd_connect_options: Some(ConnectOptions::new().with_user("root", "secret")),
+                ..plain.clone()
+            },
+            // As the credentials from the environment or a secrets provider make them
+            ClientOptions {
+                etcd_connect_options: auth_options(|name| Some(format!("{name} value"))),
+                ..plain.clone()
+            },
+            ClientOptions {
+                etcd_url: vec!["https://localhost:2379".to_string()],
+                ..plain.clone()
+            },
+            ClientOptions {
+                dns: Some(DnsDiscovery {
+                    record: DnsRecord::Srv("_etcd-client._tcp.example.com".to_string()),
+                    tls: false,
+                    refresh_interval: Duration::from_secs(30),
+                }),
+                ..plain.clone()
+            },
+            ClientOptions {
+                read_only: true,
+                ..plain.clone()
+            },
+        ];
+        for options in fallbacks {
+            assert!(!options.shares_keep_alive(), "{options:?}");
+        }
+    }
+}
@@ -2,89 +2,499 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use super::*;
-use std::time::Duration;
+use crate::component::INSTANCE_ROOT_PATH;
+use crate::identity::{WORKER_ROOT_PATH, WorkerId};
+use crate::utils::clock::{Clock, system_clock};
+use crate::utils::tasks::supervisor::{RestartPolicy, Supervisor};
 use rand::Rng;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Longest the keep-alive loop of a lease with `ttl` may go without progress before its
+/// watchdog restarts it. The loop wakes at least every `ttl / 2`, and a restart must still have
+/// time to refresh the lease before it expires.
+fn stall_limit(ttl: u64) -> Duration {
+    Duration::from_millis(ttl.max(1) * 750)
+}
+
+/// Restarts in a row after which the watchdog gives the lease up
+const MAX_WATCHDOG_RESTARTS: u32 = 3;
+
+/// How much later than planned the keep-alive loop may come round, or how far the wall clock
+/// may get ahead of the monotonic one between two turns, before it assumes the process was
+/// suspended
+const SUSPEND_SLACK: Duration = Duration::from_secs(2);
+
+/// How the keep-alive loop reacts to slow refreshes, see [`ClientOptions::lease_tuning`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LeaseTuning {
+    /// A refresh whose response takes longer than this share of the TTL is slow
+    pub slow_fraction: f64,
+    /// While refreshes are slow, send them every quarter of the TTL instead of every half,
+    /// rather than only reporting them
+    pub adaptive: bool,
+}
+
+impl Default for LeaseTuning {
+    fn default() -> Self {
+        LeaseTuning {
+            slow_fraction: 0.25,
+            adaptive: true,
+        }
+    }
+}
+
+/// Bounds on the TTL of leases from [`Client::create_lease`], see
+/// [`ClientOptions::lease_ttl_policy`]. The default has none, see [`LeaseTtlPolicy::clamped`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LeaseTtlPolicy {
+    /// Seconds. A lease much shorter loses its keys to every GC pause or slow refresh.
+    pub min: u64,
+    /// Seconds. A dead worker's keys outlive it by up to this long.
+    pub max: u64,
+    /// Bring a TTL out of range into it, with a warning, instead of failing with
+    /// [`LeaseTtlOutOfRange`]
+    pub clamp: bool,
+}
+
+impl Default for LeaseTtlPolicy {
+    fn default() -> Self {
+        LeaseTtlPolicy {
+            min: 0,
+            max: u64::MAX,
+            clamp: false,
+        }
+    }
+}
+
+impl LeaseTtlPolicy {
+    /// Bring TTLs into `min..=max` seconds, e.g. 5 to 600
+    pub fn clamped(min: u64, max: u64) -> Self {
+        LeaseTtlPolicy {
+            min,
+            max,
+            clamp: true,
+        }
+    }
+
+    /// The TTL to grant when asked for `requested`
+    pub fn apply(&self, requested: u64) -> std::result::Result<u64, LeaseTtlOutOfRange> {
+        if (self.min..=self.max).contains(&requested) {
+            return Ok(requested);
+        }
+        let err = LeaseTtlOutOfRange {
+            requested,
+            min: self.min,
+            max: self.max,
+        };
+        if !self.clamp {
+            return Err(err);
+        }
+        let ttl = requested.clamp(self.min, self.max);
+        tracing::warn!(%err, ttl, "Clamping lease TTL");
+        Ok(ttl)
+    }
+}
+
+/// A lease TTL was refused by the client's [`LeaseTtlPolicy`]
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error(
+    "Lease TTL of {requested}s is outside the allowed {min}s to {max}s: shorter leases expire \
+     on a GC pause, longer ones keep the keys of dead workers around"
+)]
+pub struct LeaseTtlOutOfRange {
+    pub requested: u64,
+    pub min: u64,
+    pub max: u64,
+}
+
+/// When the keep-alive loop last made progress, shared with its watchdog, and the clock both
+/// time themselves with
+#[derive(Clone)]
+pub(crate) struct Progress {
+    clock: Arc<dyn Clock>,
+    base: tokio::time::Instant,
+    /// Milliseconds from `base`
+    last: Arc<AtomicU64>,
+    /// Where the loop reports what the watchdog doesn't see, see [`Progress::reporting`]
+    events: Option<broadcast::Sender<WatchdogEvent>>,
+    tuning: LeaseTuning,
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Progress::with_clock(system_clock())
+    }
+}
+
+impl Progress {
+    pub(crate) fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Progress {
+            base: clock.now(),
+            clock,
+            last: Arc::new(AtomicU64::new(0)),
+            events: None,
+            tuning: LeaseTuning::default(),
+        }
+    }
+
+    /// Have the loop send its [`WatchdogEvent::Resumed`] and [`WatchdogEvent::SlowRenewal`]
+    /// events to `events`
+    pub(crate) fn reporting(mut self, events: broadcast::Sender<WatchdogEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    pub(crate) fn tuned(mut self, tuning: LeaseTuning) -> Self {
+        self.tuning = tuning;
+        self
+    }
+
+    fn report(&self, event: WatchdogEvent) {
+        if let Some(events) = &self.events {
+            let _ = events.send(event);
+        }
+    }
+
+    pub(crate) fn tick(&self) {
+        let now = self.elapsed().as_millis() as u64;
+        self.last.store(now, Ordering::Relaxed);
+    }
+
+    /// Time since the last tick
+    pub(crate) fn idle(&self) -> Duration {
+        let last = Duration::from_millis(self.last.load(Ordering::Relaxed));
+        self.elapsed().saturating_sub(last)
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.clock.now().duration_since(self.base)
+    }
+}
+
+/// What the watchdog of a lease's keep-alive task did, see [`Client::watchdog_events`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchdogEvent {
+    /// The task made no progress for `idle` and was restarted
+    Stalled {
+        lease_id: u64,
+        idle: Duration,
+        restarts: u32,
+    },
+    /// The task panicked and was restarted
+    Panicked { lease_id: u64, restarts: u32 },
+    /// Restarting didn't help, so the lease's token was cancelled
+    GaveUp { lease_id: u64 },
+    /// The process was suspended for about `suspended`, and etcd still had `remaining` seconds
+    /// on the lease afterwards. Keys attached to it survived, but anything registered some
+    /// other way should be checked.
+    Resumed {
+        lease_id: u64,
+        suspended: Duration,
+        remaining: u64,
+    },
+    /// A refresh took `round_trip`, more than [`LeaseTuning::slow_fraction`] of the TTL. A TTL
+    /// of `recommended_ttl` seconds would absorb it. Sent when refreshes turn slow, not for
+    /// every slow one.
+    SlowRenewal {
+        lease_id: u64,
+        round_trip: Duration,
+        recommended_ttl: u64,
+    },
+}
 
 /// Create a [`Lease`] with a given time-to-live (TTL) attached to the [`CancellationToken`].
-pub async fn create_lease(
+/// Also creates the lease's fence key, see [`Lease::fence_token`]. The lease is kept alive on
+/// `mux` if given, otherwise on a stream of its own, by a task under `supervisor`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn create_lease(
     mut lease_client: LeaseClient,
+    mut kv_client: KvClient,
     ttl: u64,
     token: CancellationToken,
+    events: broadcast::Sender<WatchdogEvent>,
+    tuning: LeaseTuning,
+    mux: Option<KeepAliveMux>,
+    supervisor: &Supervisor,
 ) -> Result<Lease> {
     debug_println!(BLUE, "[CREATE_LEASE]", RESET, "Creating lease ttl={}", ttl);
-    
+
     let lease = lease_client.grant(ttl as i64, None).await?;
-    debug_println!(BLUE, "[CREATE_LEASE]", RESET, "Lease granted lease_id={}, ttl={}", lease.id(), lease.ttl());
+    debug_println!(
+        BLUE,
+        "[CREATE_LEASE]",
+        RESET,
+        "Lease granted lease_id={}, ttl={}",
+        lease.id(),
+        lease.ttl()
+    );
 
     let id = lease.id() as u64;
     let ttl = lease.ttl() as u64;
+
+    // Attached to the lease, so it goes away with it and can never be recreated with the same
+    // create revision
+    let put_options = PutOptions::new().with_lease(id as i64);
+    let fence_token = match kv_client.put(fence_key(id), "", Some(put_options)).await {
+        Ok(resp) => resp
+            .header()
+            .map(|h| h.revision() as u64)
+            .unwrap_or_default(),
+        Err(err) => {
+            let _ = lease_client.revoke(id as i64).await;
+            return Err(error!("Unable to create fence key for lease {id}: {err}"));
+        }
+    };
     let child = token.child_token();
     let clone = token.clone();
 
+    debug_println!(
+        BLUE,
+        "[CREATE_LEASE]",
+        RESET,
+        "Spawning keep-alive task lease_id={}",
+        id
+    );
+    // The watchdog restarts the keep-alive loop; the supervisor restarts the watchdog
+    supervisor.spawn(
+        format!("keep-alive {id:x}"),
+        RestartPolicy::default(),
+        move || {
+            let lease_client = lease_client.clone();
+            let mux = mux.clone();
+            let child = child.clone();
+            let token = token.clone();
+            let events = events.clone();
+            async move {
+                debug_println!(
+                    BLUE,
+                    "[CREATE_LEASE]",
+                    RESET,
+                    "Keep-alive task started lease_id={}",
+                    id
+                );
+
+                // Add a panic hook to catch any panics
+                std::panic::set_hook(Box::new(move |panic_info| {
+                    debug_println!(
+                        RED,
+                        "[CREATE_LEASE]",
+                        RESET,
+                        "PANIC in keep-alive task lease_id={}: {:?}",
+                        id,
+                        panic_info
+                    );
+                }));
+
+                let progress = Progress::default().reporting(events.clone()).tuned(tuning);
+                let start = || {
+                    tokio::spawn(maintain_lease(
+                        lease_client.clone(),
+                        mux.clone(),
+                        id,
+                        ttl,
+                        child.clone(),
+                        token.clone(),
+                        progress.clone(),
+                    ))
+                };
+                watchdog(id, ttl, progress.clone(), token.clone(), events, start).await;
+
+                debug_println!(
+                    BLUE,
+                    "[CREATE_LEASE]",
+                    RESET,
+                    "Keep-alive task completely finished lease_id={}",
+                    id
+                );
+                Ok(())
+            }
+        },
+    );
+
+    debug_println!(
+        BLUE,
+        "[CREATE_LEASE]",
+        RESET,
+        "Returning lease with lease_id={}",
+        id
+    );
+    Ok(Lease {
+        id,
+        cancel_token: clone,
+        fence_token,
+    })
+}
+
+/// Keep lease `id` alive, retrying the keep-alive loop when it fails, until `child` is cancelled
+/// or the retries run out, which cancels `token`
+async fn maintain_lease(
+    lease_client: LeaseClient,
+    mux: Option<KeepAliveMux>,
+    id: u64,
+    ttl: u64,
+    child: CancellationToken,
+    token: CancellationToken,
+    progress: Progress,
+) {
     let mut retry_count = 0;
     const MAX_RETRIES: u32 = 20;
     const RETRY_DELAY: Duration = Duration::from_millis(500);
     const RETRY_JITTER: u64 = 100;
-    let mut last_retry_time = std::time::Instant::now();
-
-    debug_println!(BLUE, "[CREATE_LEASE]", RESET, "Spawning keep-alive task lease_id={}", id);
-    tokio::spawn(async move {
-        debug_println!(BLUE, "[CREATE_LEASE]", RESET, "Keep-alive task started lease_id={}", id);
-        
-        // Add a panic hook to catch any panics
-        std::panic::set_hook(Box::new(move |panic_info| {
-            debug_println!(RED, "[CREATE_LEASE]", RESET, "PANIC in keep-alive task lease_id={}: {:?}", id, panic_info);
-        }));
-
-        loop {      
-            match keep_alive(lease_client.clone(), id, ttl, child.clone()).await {
-                Ok(_) => {
-                    debug_println!(GREEN, "[CREATE_LEASE]", RESET, "Keep-alive task EXITED successfully lease_id={}", id);
-                    tracing::trace!("keep alive task exited successfully");
-                },
-                Err(e) => {
-                    debug_println!(RED, "[CREATE_LEASE]", RESET, "❌ Keep-alive task FAILED lease_id={}: {}", id, e);
+    let clock = progress.clock.clone();
+    let mut last_retry_time = clock.now();
+
+    loop {
+        let keep_alive = keep_alive(
+            lease_client.clone(),
+            mux.as_ref(),
+            id,
+            ttl,
+            child.clone(),
+            progress.clone(),
+        );
+        match keep_alive.await {
+            Ok(_) => {
+                debug_println!(
+                    GREEN,
+                    "[CREATE_LEASE]",
+                    RESET,
+                    "Keep-alive task EXITED successfully lease_id={}",
+                    id
+                );
+                tracing::trace!("keep alive task exited successfully");
+                break;
+            }
+            Err(e) => {
+                debug_println!(
+                    RED,
+                    "[CREATE_LEASE]",
+                    RESET,
+                    "❌ Keep-alive task FAILED lease_id={}: {}",
+                    id,
+                    e
+                );
+                tracing::error!(
+                    error = %e,
+                    "Unable to maintain lease. Check etcd server status"
+                );
+
+                if retry_count > 0 {
+                    let time_since_last_retry = clock.now().duration_since(last_retry_time);
+                    if time_since_last_retry.as_secs() >= ttl {
+                        debug_println!(
+                            YELLOW,
+                            "[KEEP_ALIVE]",
+                            RESET,
+                            "Resetting retry_count after TTL ({}) passed since last retry for lease_id={}",
+                            ttl,
+                            id
+                        );
+                        retry_count = 0;
+                    }
+                }
+                retry_count += 1;
+                if retry_count >= MAX_RETRIES {
+                    debug_println!(
+                        RED,
+                        "[CREATE_LEASE]",
+                        RESET,
+                        "Max retries {} exceeded lease_id={}, giving up",
+                        MAX_RETRIES,
+                        id
+                    );
                     tracing::error!(
                         error = %e,
-                        "Unable to maintain lease. Check etcd server status"
+                        "Unable to maintain lease after {} retries. Check etcd server status",
+                        MAX_RETRIES
                     );
+                    token.cancel();
+                    break;
+                }
+                last_retry_time = clock.now();
+                let jitter_ms = rand::random_range(0..RETRY_JITTER);
+                let sleep = RETRY_DELAY + Duration::from_millis(jitter_ms);
+                debug_println!(
+                    YELLOW,
+                    "[KEEP_ALIVE]",
+                    RESET,
+                    "Retrying {}/{}, sleep={:?} for lease_id={}",
+                    retry_count,
+                    MAX_RETRIES,
+                    sleep,
+                    id
+                );
+                progress.tick();
+                clock.sleep(sleep).await;
+                continue;
+            }
+        }
+    }
+}
 
-                    if retry_count > 0 {
-                        let time_since_last_retry = std::time::Instant::now().duration_since(last_retry_time);
-                        if time_since_last_retry.as_secs() >= ttl {
-                            debug_println!(YELLOW, "[KEEP_ALIVE]", RESET, "Resetting retry_count after TTL ({}) passed since last retry for lease_id={}", ttl, id);
-                            retry_count = 0;
-                        }
-                    }
-                    retry_count += 1;
-                    if retry_count >= MAX_RETRIES {
-                        debug_println!(RED, "[CREATE_LEASE]", RESET, "Max retries {} exceeded lease_id={}, giving up", MAX_RETRIES, id);
-                        tracing::error!(
-                            error = %e,
-                            "Unable to maintain lease after {} retries. Check etcd server status",
-                            MAX_RETRIES
-                        );
-                        token.cancel();
-                        break
+/// Watch over the keep-alive task of lease `lease_id`, which `start` spawns and which ticks
+/// `progress`. Restarts the task when it panics or makes no progress for [`stall_limit`], and
+/// cancels `token` after [`MAX_WATCHDOG_RESTARTS`] restarts in a row. Returns once the task
+/// ends by itself.
+pub(crate) async fn watchdog(
+    lease_id: u64,
+    ttl: u64,
+    progress: Progress,
+    token: CancellationToken,
+    events: broadcast::Sender<WatchdogEvent>,
+    mut start: impl FnMut() -> tokio::task::JoinHandle<()>,
+) {
+    let clock = progress.clock.clone();
+    let limit = stall_limit(ttl);
+    let mut next_check = clock.now();
+    let mut restarts = 0;
+    let mut restarted_at = clock.now();
+    progress.tick();
+    let mut task = start();
+    loop {
+        let event = tokio::select! {
+            joined = &mut task => match joined {
+                Err(err) if err.is_panic() => WatchdogEvent::Panicked {
+                    lease_id,
+                    restarts: restarts + 1,
+                },
+                _ => return,
+            },
+            _ = clock.sleep_until(next_check) => {
+                next_check += limit / 4;
+                let idle = progress.idle();
+                if idle < limit {
+                    // A restart that kept going for a TTL has worked
+                    let running = clock.now().duration_since(restarted_at);
+                    if restarts > 0 && running >= Duration::from_secs(ttl) {
+                        restarts = 0;
                     }
-                    last_retry_time = std::time::Instant::now();
-                    let jitter_ms = rand::random_range(0..RETRY_JITTER);
-                    let sleep = RETRY_DELAY + Duration::from_millis(jitter_ms);
-                    debug_println!(YELLOW, "[KEEP_ALIVE]", RESET, "Retrying {}/{}, sleep={:?} for lease_id={}", retry_count, MAX_RETRIES, sleep, id);
-                    tokio::time::sleep(sleep).await;
                     continue;
                 }
+                task.abort();
+                WatchdogEvent::Stalled {
+                    lease_id,
+                    idle,
+                    restarts: restarts + 1,
+                }
             }
+        };
+        restarts += 1;
+        if restarts > MAX_WATCHDOG_RESTARTS {
+            tracing::error!(
+                lease_id,
+                "Keep-alive task keeps failing, giving up the lease"
+            );
+            let _ = events.send(WatchdogEvent::GaveUp { lease_id });
+            token.cancel();
+            return;
         }
-
-        debug_println!(BLUE, "[CREATE_LEASE]", RESET, "Keep-alive task completely finished lease_id={}", id);
-    });
-
-    debug_println!(BLUE, "[CREATE_LEASE]", RESET, "Returning lease with lease_id={}", id);
-    Ok(Lease {
-        id,
-        cancel_token: clone,
-    })
+        tracing::warn!(lease_id, ?event, "Restarting keep-alive task");
+        let _ = events.send(event);
+        progress.tick();
+        restarted_at = clock.now();
+        task = start();
+    }
 }
 
 /// Revoke a lease given its lease id. A wrapper over etcd_client::LeaseClient::revoke
@@ -98,50 +508,278 @@ pub async fn revoke_lease(mut lease_client: LeaseClient, lease_id: u64) -> Resul
     }
 }
 
-/// Task to keep leases alive.
+/// A lease as etcd has it, with the keys that go when it does
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeaseInfo {
+    pub id: u64,
+    /// Seconds left
+    pub ttl: i64,
+    /// Seconds it was granted, and is refreshed to
+    pub granted_ttl: i64,
+    pub keys: Vec<String>,
+}
+
+impl LeaseInfo {
+    /// The [worker](crate::identity) holding it, if it recorded its ID under the lease
+    pub fn worker_id(&self) -> Option<WorkerId> {
+        self.keys.iter().find_map(|key| {
+            let id = key.strip_prefix(WORKER_ROOT_PATH)?.strip_prefix('/')?;
+            id.parse().ok()
+        })
+    }
+
+    /// The instances registered under it, as `{namespace}/{component}/{endpoint}/{id:x}`
+    pub fn instances(&self) -> impl Iterator<Item = &str> {
+        self.keys
+            .iter()
+            .filter_map(|key| key.strip_prefix(INSTANCE_ROOT_PATH)?.strip_prefix('/'))
+    }
+}
+
+/// The lease's TTL and keys, None if it expired or never existed
+pub async fn lease_info(mut lease_client: LeaseClient, lease_id: u64) -> Result<Option<LeaseInfo>> {
+    let options = etcd_client::LeaseTimeToLiveOptions::new().with_keys();
+    let response = lease_client
+        .time_to_live(lease_id as i64, Some(options))
+        .await?;
+    // -1 for a lease that expired or never existed
+    if response.ttl() < 0 {
+        return Ok(None);
+    }
+    Ok(Some(LeaseInfo {
+        id: lease_id,
+        ttl: response.ttl(),
+        granted_ttl: response.granted_ttl(),
+        keys: response
+            .keys()
+            .iter()
+            .map(|key| String::from_utf8_lossy(key).to_string())
+            .collect(),
+    }))
+}
+
+/// Every lease of the cluster by ID, one request each for the TTL and keys
+pub async fn list_leases(mut lease_client: LeaseClient) -> Result<Vec<LeaseInfo>> {
+    let response = lease_client.leases().await?;
+    let mut leases = Vec::with_capacity(response.leases().len());
+    for status in response.leases() {
+        // Leases that expired since being listed are left out
+        if let Some(info) = lease_info(lease_client.clone(), status.id() as u64).await? {
+            leases.push(info);
+        }
+    }
+    leases.sort_by_key(|lease| lease.id);
+    Ok(leases)
+}
+
+/// The two halves of an etcd lease keep-alive stream, plus the client to revoke with.
+/// [`keep_alive`] only talks to the lease server through this, so it can run against a
+/// simulated one.
+#[async_trait::async_trait]
+pub(crate) trait LeaseHeartbeat: Send {
+    /// Ask the server to refresh the lease
+    async fn send(&mut self) -> Result<()>;
+
+    /// The TTL from the next refresh response, None if the stream yielded nothing.
+    /// Must be cancel safe.
+    async fn receive(&mut self) -> Result<Option<u64>>;
+
+    /// Seconds left on the lease according to the server, 0 if it is gone
+    async fn remaining(&mut self) -> Result<u64>;
+
+    async fn revoke(&mut self) -> Result<()>;
+}
+
+/// A keep-alive stream to an etcd server for one lease. See [`KeepAliveMux`] for one shared by
+/// many.
+pub(crate) struct EtcdHeartbeat {
+    client: LeaseClient,
+    lease_id: u64,
+    sender: etcd_client::LeaseKeeper,
+    receiver: etcd_client::LeaseKeepAliveStream,
+}
+
+impl EtcdHeartbeat {
+    pub(crate) async fn open(mut client: LeaseClient, lease_id: u64) -> Result<Self> {
+        let (sender, receiver) = client.keep_alive(lease_id as i64).await?;
+        Ok(EtcdHeartbeat {
+            client,
+            lease_id,
+            sender,
+            receiver,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl LeaseHeartbeat for EtcdHeartbeat {
+    async fn send(&mut self) -> Result<()> {
+        Ok(self.sender.keep_alive().await?)
+    }
+
+    async fn receive(&mut self) -> Result<Option<u64>> {
+        let resp = self.receiver.message().await?;
+        if let Some(resp) = &resp {
+            tracing::trace!(
+                lease_id = self.lease_id,
+                "keep alive response received: {:?}",
+                resp
+            );
+        }
+        Ok(resp.map(|resp| resp.ttl().max(0) as u64))
+    }
+
+    async fn remaining(&mut self) -> Result<u64> {
+        let resp = self.client.time_to_live(self.lease_id as i64, None).await?;
+        Ok(resp.ttl().max(0) as u64)
+    }
+
+    async fn revoke(&mut self) -> Result<()> {
+        self.client.revoke(self.lease_id as i64).await?;
+        Ok(())
+    }
+}
+
+/// Task to keep leases alive, on `mux` if given.
 ///
 /// If this task returns an error, the cancellation token will be invoked on the runtime.
-pub async fn keep_alive(
+pub(crate) async fn keep_alive(
     client: LeaseClient,
+    mux: Option<&KeepAliveMux>,
     lease_id: u64,
     ttl: u64,
     token: CancellationToken,
+    progress: Progress,
 ) -> Result<()> {
-    let mut ttl = ttl;
-    let mut deadline = create_deadline(ttl)?;
+    if let Some(mux) = mux {
+        let heartbeat = mux.heartbeat(lease_id).await?;
+        return run_keep_alive(heartbeat, lease_id, ttl, token, progress).await;
+    }
+    let heartbeat = EtcdHeartbeat::open(client, lease_id).await?;
+    run_keep_alive(heartbeat, lease_id, ttl, token, progress).await
+}
 
-    let mut client = client;
-    let (mut heartbeat_sender, mut heartbeat_receiver) = client.keep_alive(lease_id as i64).await?;
+/// The keep-alive loop of [`keep_alive`]. Deadlines use the clock of `progress`, tokio's unless
+/// set otherwise, so with a paused clock they follow virtual time. Ticks `progress` on every
+/// turn.
+pub(crate) async fn run_keep_alive(
+    mut heartbeat: impl LeaseHeartbeat,
+    lease_id: u64,
+    ttl: u64,
+    token: CancellationToken,
+    progress: Progress,
+) -> Result<()> {
+    let clock = progress.clock.clone();
+    let mut ttl = ttl;
+    let mut deadline = create_deadline(&*clock, ttl)?;
+    let mut last_turn = (clock.now(), clock.system_time());
+    // When the last heartbeat went out, and whether the last refresh was slow
+    let mut sent_at = None;
+    let mut slow = false;
 
     loop {
+        progress.tick();
+
+        // The deadline means nothing after a suspension: the monotonic clock either jumped
+        // past it while etcd may have kept the lease, or stood still while etcd counted down.
+        // Ask etcd instead.
+        let turn = (clock.now(), clock.system_time());
+        let suspended = suspension(last_turn, turn, ttl);
+        last_turn = turn;
+        if let Some(suspended) = suspended {
+            tracing::warn!(
+                lease_id,
+                ?suspended,
+                "Process was suspended, checking the lease"
+            );
+            let remaining = tokio::select! {
+                remaining = heartbeat.remaining() => remaining?,
+                _ = clock.sleep(Duration::from_secs(ttl.max(1))) => {
+                    return Err(error!("Unable to check lease {lease_id} after a suspension"));
+                }
+            };
+            if remaining == 0 {
+                return Err(error!(
+                    "Lease {lease_id} expired while the process was suspended for {suspended:?}"
+                ));
+            }
+            deadline = create_deadline(&*clock, remaining)?;
+            // Refresh at once, through the same path as a failed send
+            ttl = 0;
+            progress.report(WatchdogEvent::Resumed {
+                lease_id,
+                suspended,
+                remaining,
+            });
+            last_turn = (clock.now(), clock.system_time());
+        }
+
         // if the deadline is exceeded, then we have failed to issue a heartbeat in time
         // we may be permanently disconnected from the etcd server, so we are now officially done
-        if deadline < std::time::Instant::now() {
-            debug_println!(RED, "[KEEP_ALIVE]", RESET, "Deadline exceeded lease_id={}", lease_id);
+        if deadline < clock.now() {
+            debug_println!(
+                RED,
+                "[KEEP_ALIVE]",
+                RESET,
+                "Deadline exceeded lease_id={}",
+                lease_id
+            );
             return Err(error!(
                 "Unable to refresh lease - deadline exceeded. Check etcd server status"
             ));
         }
 
-        let time_until_deadline = deadline.duration_since(std::time::Instant::now());
-        debug_println!(GREEN, "[KEEP_ALIVE]", RESET, "Loop iteration lease_id={}, ttl={}, time_until_deadline={:.1}s", 
-                 lease_id, ttl, time_until_deadline.as_secs_f64());
-        
+        let time_until_deadline = deadline.duration_since(clock.now());
+        let renew_every = if slow && progress.tuning.adaptive {
+            Duration::from_secs(ttl) / 4
+        } else {
+            Duration::from_secs(ttl / 2)
+        };
+        debug_println!(
+            GREEN,
+            "[KEEP_ALIVE]",
+            RESET,
+            "Loop iteration lease_id={}, ttl={}, time_until_deadline={:.1}s",
+            lease_id,
+            ttl,
+            time_until_deadline.as_secs_f64()
+        );
+
         tokio::select! {
             biased;
 
-            status = heartbeat_receiver.message() => {
+            status = heartbeat.receive() => {
                 match status {
-                    Ok(Some(resp)) => {
+                    Ok(Some(resp_ttl)) => {
                         // Good response - process the heartbeat
                         debug_println!(GREEN, "[KEEP_ALIVE]", RESET, "❤️ Heartbeat response received lease_id={}", lease_id);
-                        tracing::trace!(lease_id, "keep alive response received: {:?}", resp);
 
                         // update ttl and deadline
-                        ttl = resp.ttl() as u64;
-                        deadline = create_deadline(ttl)?;
+                        ttl = resp_ttl;
+                        deadline = create_deadline(&*clock, ttl)?;
+
+                        if let Some(sent) = sent_at.take() {
+                            let round_trip = clock.now().duration_since(sent);
+                            let recommended = slow_renewal(round_trip, ttl, &progress.tuning);
+                            if let Some(recommended_ttl) = recommended
+                                && !slow
+                            {
+                                tracing::warn!(
+                                    lease_id,
+                                    ?round_trip,
+                                    recommended_ttl,
+                                    "Lease refreshes are slow for the lease TTL"
+                                );
+                                progress.report(WatchdogEvent::SlowRenewal {
+                                    lease_id,
+                                    round_trip,
+                                    recommended_ttl,
+                                });
+                            }
+                            slow = recommended.is_some();
+                        }
 
-                        if resp.ttl() == 0 {
+                        if resp_ttl == 0 {
                             return Err(error!("Unable to maintain lease - expired or revoked. Check etcd server status"));
                         }
                     },
@@ -152,7 +790,7 @@ pub async fn keep_alive(
                     Err(e) => {
                         // Error getting the message
                         debug_println!(RED, "[KEEP_ALIVE]", RESET, "💔 Error receiving heartbeat message for lease_id={}: {}", lease_id, e);
-                        return Err(e.into());
+                        return Err(e);
                     }
                 }
             }
@@ -160,11 +798,11 @@ pub async fn keep_alive(
             _ = token.cancelled() => {
                 debug_println!(RED, "[KEEP_ALIVE]", RESET, "Cancellation token triggered lease_id={}", lease_id);
                 tracing::trace!(lease_id, "cancellation token triggered; revoking lease");
-                let _ = client.revoke(lease_id as i64).await?;
+                heartbeat.revoke().await?;
                 return Ok(());
             }
 
-            _ = tokio::time::sleep(tokio::time::Duration::from_secs(ttl / 2)) => {
+            _ = clock.sleep(renew_every) => {
                 tracing::trace!(lease_id, "sending keep alive");
                 debug_println!(GREEN, "[KEEP_ALIVE]", RESET, "Slept for {:?} seconds lease_id={}, sending heartbeat 💕", ttl / 2, lease_id);
 
@@ -172,7 +810,8 @@ pub async fn keep_alive(
                 // this will allow us to poll the response stream once and the cancellation token once, then
                 // immediately try to tick the heartbeat
                 // this will repeat until either the heartbeat is reestablished or the deadline is exceeded
-                if let Err(e) = heartbeat_sender.keep_alive().await {
+                sent_at = Some(clock.now());
+                if let Err(e) = heartbeat.send().await {
                     debug_println!(RED, "[KEEP_ALIVE]", RED, "Error with lease_id={}: {}", lease_id, e);
                     tracing::warn!(
                         lease_id,
@@ -187,7 +826,548 @@ pub async fn keep_alive(
     }
 }
 
+/// The TTL that would absorb refreshes taking `round_trip`, if that is too long for `ttl`
+fn slow_renewal(round_trip: Duration, ttl: u64, tuning: &LeaseTuning) -> Option<u64> {
+    let limit = Duration::from_secs(ttl).mul_f64(tuning.slow_fraction);
+    let recommended = round_trip.as_secs_f64() / tuning.slow_fraction;
+    (round_trip > limit).then(|| recommended.ceil() as u64)
+}
+
+/// How long the process was suspended between two turns of the keep-alive loop, which sleeps
+/// `ttl / 2` at most between turns. None if it wasn't.
+fn suspension(
+    (last_monotonic, last_wall): (tokio::time::Instant, std::time::SystemTime),
+    (monotonic, wall): (tokio::time::Instant, std::time::SystemTime),
+    ttl: u64,
+) -> Option<Duration> {
+    let monotonic = monotonic.duration_since(last_monotonic);
+    let wall = wall.duration_since(last_wall).unwrap_or_default();
+    let late = monotonic > Duration::from_secs(ttl / 2) + SUSPEND_SLACK;
+    let stopped = wall > monotonic + SUSPEND_SLACK;
+    (late || stopped).then(|| monotonic.max(wall))
+}
+
 /// Create a deadline for a given time-to-live (TTL).
-fn create_deadline(ttl: u64) -> Result<std::time::Instant> {
-    Ok(std::time::Instant::now() + std::time::Duration::from_secs(ttl))
+fn create_deadline(clock: &dyn Clock, ttl: u64) -> Result<tokio::time::Instant> {
+    Ok(clock.now() + std::time::Duration::from_secs(ttl))
+}
+
+/// What a [`KeepAliveInterceptor`] does with an outgoing heartbeat
+#[cfg(any(test, feature = "simulation"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeartbeatAction {
+    Send,
+    /// Lose it silently, so only the missing response tells
+    Drop,
+    /// Fail to send it, as when the connection to etcd is down
+    Fail,
+}
+
+/// What a [`KeepAliveInterceptor`] does with a refresh response
+#[cfg(any(test, feature = "simulation"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseAction {
+    Deliver,
+    /// Deliver it this much later. Responses arriving meanwhile are not held up.
+    Delay(Duration),
+    /// Deliver this TTL instead; 0 is what etcd answers for an expired or revoked lease
+    Forge(u64),
+    Drop,
+}
+
+/// Fault injection between the keep-alive loop and the lease server, to test the loop's
+/// deadline and recovery handling. Both hooks default to passing everything through.
+#[cfg(any(test, feature = "simulation"))]
+pub trait KeepAliveInterceptor: Send + Sync {
+    fn before_send(&self, _lease_id: u64) -> HeartbeatAction {
+        HeartbeatAction::Send
+    }
+
+    fn on_response(&self, _lease_id: u64, _ttl: u64) -> ResponseAction {
+        ResponseAction::Deliver
+    }
+}
+
+/// A [`LeaseHeartbeat`] with a [`KeepAliveInterceptor`] in front of it
+#[cfg(any(test, feature = "simulation"))]
+pub(crate) struct Intercepted<H> {
+    inner: H,
+    lease_id: u64,
+    interceptor: Arc<dyn KeepAliveInterceptor>,
+    /// Delayed responses and when to deliver them, soonest first
+    delayed: std::collections::VecDeque<(tokio::time::Instant, u64)>,
+}
+
+#[cfg(any(test, feature = "simulation"))]
+impl<H: LeaseHeartbeat> Intercepted<H> {
+    pub(crate) fn new(inner: H, lease_id: u64, interceptor: Arc<dyn KeepAliveInterceptor>) -> Self {
+        Intercepted {
+            inner,
+            lease_id,
+            interceptor,
+            delayed: Default::default(),
+        }
+    }
+}
+
+#[cfg(any(test, feature = "simulation"))]
+#[async_trait::async_trait]
+impl<H: LeaseHeartbeat> LeaseHeartbeat for Intercepted<H> {
+    async fn send(&mut self) -> Result<()> {
+        match self.interceptor.before_send(self.lease_id) {
+            HeartbeatAction::Send => self.inner.send().await,
+            HeartbeatAction::Drop => Ok(()),
+            HeartbeatAction::Fail => Err(error!("Heartbeat failed by interceptor")),
+        }
+    }
+
+    async fn receive(&mut self) -> Result<Option<u64>> {
+        loop {
+            // Cancel safe: a delayed response stays queued until its sleep completes
+            let resp = match self.delayed.front().copied() {
+                Some((due, ttl)) => tokio::select! {
+                    biased;
+                    _ = tokio::time::sleep_until(due) => {
+                        self.delayed.pop_front();
+                        return Ok(Some(ttl));
+                    }
+                    resp = self.inner.receive() => resp?,
+                },
+                None => self.inner.receive().await?,
+            };
+            let Some(ttl) = resp else {
+                return Ok(None);
+            };
+            match self.interceptor.on_response(self.lease_id, ttl) {
+                ResponseAction::Deliver => return Ok(Some(ttl)),
+                ResponseAction::Forge(ttl) => return Ok(Some(ttl)),
+                ResponseAction::Delay(delay) => {
+                    let due = tokio::time::Instant::now() + delay;
+                    let pos = self.delayed.partition_point(|(at, _)| *at <= due);
+                    self.delayed.insert(pos, (due, ttl));
+                }
+                ResponseAction::Drop => {}
+            }
+        }
+    }
+
+    async fn remaining(&mut self) -> Result<u64> {
+        self.inner.remaining().await
+    }
+
+    async fn revoke(&mut self) -> Result<()> {
+        self.inner.revoke().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::clock::TestClock;
+    use parking_lot::Mutex;
+    use std::collections::VecDeque;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use tokio::time::Instant;
+
+    const TTL: u64 = 4;
+
+    #[test]
+    fn test_lease_info_owner() {
+        let worker_id = WorkerId::new();
+        let lease = LeaseInfo {
+            id: 0x1234,
+            ttl: 7,
+            granted_ttl: 10,
+            keys: vec![
+                format!("{INSTANCE_ROOT_PATH}/dynamo/backend/generate/1234"),
+                format!("{WORKER_ROOT_PATH}/{worker_id}"),
+                "v1/mdc/llama.1234".to_string(),
+            ],
+        };
+        assert_eq!(lease.worker_id(), Some(worker_id));
+        let instances: Vec<_> = lease.instances().collect();
+        assert_eq!(instances, ["dynamo/backend/generate/1234"]);
+
+        let bare = LeaseInfo {
+            keys: vec![],
+            ..lease
+        };
+        assert_eq!(bare.worker_id(), None);
+        assert_eq!(bare.instances().count(), 0);
+    }
+
+    /// A lease server that answers every heartbeat with the full TTL
+    struct Echo {
+        responses_tx: tokio::sync::mpsc::UnboundedSender<u64>,
+        responses: tokio::sync::mpsc::UnboundedReceiver<u64>,
+        sent: Arc<AtomicU64>,
+        /// What the server says is left on the lease
+        remaining: u64,
+    }
+
+    impl Echo {
+        fn new() -> (Self, Arc<AtomicU64>) {
+            let (responses_tx, responses) = tokio::sync::mpsc::unbounded_channel();
+            let sent = Arc::new(AtomicU64::new(0));
+            let echo = Echo {
+                responses_tx,
+                responses,
+                sent: sent.clone(),
+                remaining: TTL,
+            };
+            (echo, sent)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl LeaseHeartbeat for Echo {
+        async fn send(&mut self) -> Result<()> {
+            self.sent.fetch_add(1, Ordering::SeqCst);
+            let _ = self.responses_tx.send(TTL);
+            Ok(())
+        }
+
+        async fn receive(&mut self) -> Result<Option<u64>> {
+            Ok(self.responses.recv().await)
+        }
+
+        async fn remaining(&mut self) -> Result<u64> {
+            Ok(self.remaining)
+        }
+
+        async fn revoke(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Applies the scripted actions in order, then passes everything through
+    #[derive(Default)]
+    struct Script {
+        sends: Mutex<VecDeque<HeartbeatAction>>,
+        responses: Mutex<VecDeque<ResponseAction>>,
+    }
+
+    impl Script {
+        fn sends(actions: impl IntoIterator<Item = HeartbeatAction>) -> Arc<Self> {
+            Arc::new(Script {
+                sends: Mutex::new(actions.into_iter().collect()),
+                ..Default::default()
+            })
+        }
+
+        fn responses(actions: impl IntoIterator<Item = ResponseAction>) -> Arc<Self> {
+            Arc::new(Script {
+                responses: Mutex::new(actions.into_iter().collect()),
+                ..Default::default()
+            })
+        }
+    }
+
+    impl KeepAliveInterceptor for Script {
+        fn before_send(&self, _lease_id: u64) -> HeartbeatAction {
+            self.sends
+                .lock()
+                .pop_front()
+                .unwrap_or(HeartbeatAction::Send)
+        }
+
+        fn on_response(&self, _lease_id: u64, _ttl: u64) -> ResponseAction {
+            self.responses
+                .lock()
+                .pop_front()
+                .unwrap_or(ResponseAction::Deliver)
+        }
+    }
+
+    /// Run the keep-alive loop for up to `limit`, returning its result if it ended
+    async fn run(script: Arc<Script>, limit: Duration) -> Option<Result<()>> {
+        let (echo, _) = Echo::new();
+        let heartbeat = Intercepted::new(echo, 1, script);
+        let token = CancellationToken::new();
+        let keep_alive = run_keep_alive(heartbeat, 1, TTL, token, Progress::default());
+        tokio::time::timeout(limit, keep_alive).await.ok()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_one_lost_heartbeat_is_tolerated() {
+        // Heartbeats go out every TTL / 2 = 2s, the one at 2s is lost
+        let script = Script::sends([HeartbeatAction::Drop]);
+        assert!(run(script, Duration::from_secs(60)).await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_two_lost_heartbeats_exceed_deadline() {
+        let start = Instant::now();
+        let script = Script::sends([HeartbeatAction::Drop, HeartbeatAction::Drop]);
+        let result = run(script, Duration::from_secs(60)).await;
+        let err = result.expect("keep alive should give up").unwrap_err();
+        assert!(err.to_string().contains("deadline exceeded"), "{err}");
+        // The deadline is 4s, noticed at the first check after it
+        assert_eq!(start.elapsed(), Duration::from_secs(6));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_late_response_within_deadline() {
+        let script = Script::responses([ResponseAction::Delay(Duration::from_secs(1))]);
+        assert!(run(script, Duration::from_secs(60)).await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_responses_later_than_deadline() {
+        let start = Instant::now();
+        let script = Script::responses([ResponseAction::Delay(Duration::from_secs(5)); 3]);
+        let result = run(script, Duration::from_secs(60)).await;
+        assert!(result.expect("keep alive should give up").is_err());
+        assert_eq!(start.elapsed(), Duration::from_secs(6));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_forged_zero_ttl_ends_lease() {
+        let start = Instant::now();
+        let script = Script::responses([ResponseAction::Deliver, ResponseAction::Forge(0)]);
+        let result = run(script, Duration::from_secs(60)).await;
+        let err = result.expect("keep alive should give up").unwrap_err();
+        assert!(err.to_string().contains("expired or revoked"), "{err}");
+        assert_eq!(start.elapsed(), Duration::from_secs(4));
+    }
+
+    /// Freeze the keep-alive loop for `frozen` once it is waiting, as if the process were
+    /// suspended, and return it with its events
+    async fn suspend(
+        echo: Echo,
+        frozen: Duration,
+    ) -> (
+        tokio::task::JoinHandle<Result<()>>,
+        broadcast::Receiver<WatchdogEvent>,
+    ) {
+        let (events, rx) = broadcast::channel(4);
+        let progress = Progress::default().reporting(events);
+        let keep_alive = run_keep_alive(echo, 1, TTL, CancellationToken::new(), progress);
+        let keep_alive = tokio::spawn(keep_alive);
+        tokio::task::yield_now().await;
+        tokio::time::advance(frozen).await;
+        (keep_alive, rx)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_resume_checks_lease_before_deadline() {
+        let (echo, sent) = Echo::new();
+        // Past the deadline, but etcd kept the lease, as it does across a leader change
+        let (keep_alive, mut events) = suspend(echo, Duration::from_secs(10)).await;
+        assert_eq!(
+            events.recv().await.unwrap(),
+            WatchdogEvent::Resumed {
+                lease_id: 1,
+                suspended: Duration::from_secs(10),
+                remaining: TTL,
+            }
+        );
+        // The heartbeat due while frozen went out on waking, and the lease is kept
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+        assert!(!keep_alive.is_finished());
+        keep_alive.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_resume_with_expired_lease_fails() {
+        let (mut echo, sent) = Echo::new();
+        echo.remaining = 0;
+        let (keep_alive, _) = suspend(echo, Duration::from_secs(10)).await;
+        let err = keep_alive.await.unwrap().unwrap_err();
+        assert!(
+            err.to_string().contains("while the process was suspended"),
+            "{err}"
+        );
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_resume_after_monotonic_clock_stopped() {
+        let clock = TestClock::new();
+        let (mut echo, _) = Echo::new();
+        echo.remaining = 0;
+        let progress = Progress::with_clock(Arc::new(clock.clone()));
+        let keep_alive = run_keep_alive(echo, 1, TTL, CancellationToken::new(), progress);
+        let keep_alive = tokio::spawn(keep_alive);
+        tokio::task::yield_now().await;
+
+        // Well within the deadline by the monotonic clock, but etcd counted a minute
+        clock.suspend(Duration::from_secs(60));
+        clock.advance(Duration::from_secs(2));
+        let err = keep_alive.await.unwrap().unwrap_err();
+        assert!(
+            err.to_string().contains("while the process was suspended"),
+            "{err}"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_slow_renewal_refreshes_more_often() {
+        let (echo, sent) = Echo::new();
+        // The refresh at 2s is answered at 3.5s, later than a quarter of the TTL
+        let script = Script::responses([ResponseAction::Delay(Duration::from_millis(1_500))]);
+        let heartbeat = Intercepted::new(echo, 1, script);
+        let (events, mut rx) = broadcast::channel(4);
+        let progress = Progress::default().reporting(events);
+        let keep_alive = run_keep_alive(heartbeat, 1, TTL, CancellationToken::new(), progress);
+        let keep_alive = tokio::spawn(keep_alive);
+
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            WatchdogEvent::SlowRenewal {
+                lease_id: 1,
+                round_trip: Duration::from_millis(1_500),
+                recommended_ttl: 6,
+            }
+        );
+        // The next one goes out a quarter TTL later, at 4.5s rather than 5.5s, and is fast
+        tokio::time::sleep(Duration::from_millis(1_500)).await;
+        assert_eq!(sent.load(Ordering::SeqCst), 2);
+        // So the one after that waits half a TTL again, until 6.5s
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(sent.load(Ordering::SeqCst), 2);
+        keep_alive.abort();
+    }
+
+    #[test]
+    fn test_slow_renewal() {
+        let tuning = LeaseTuning::default();
+        assert_eq!(slow_renewal(Duration::from_secs(2), 10, &tuning), None);
+        assert_eq!(
+            slow_renewal(Duration::from_millis(2_600), 10, &tuning),
+            Some(11)
+        );
+        assert_eq!(slow_renewal(Duration::from_secs(5), 10, &tuning), Some(20));
+    }
+
+    #[test]
+    fn test_lease_ttl_policy() {
+        // Whatever the caller asks for
+        let policy = LeaseTtlPolicy::default();
+        assert_eq!(policy.apply(1), Ok(1));
+        assert_eq!(policy.apply(86_400), Ok(86_400));
+
+        let mut policy = LeaseTtlPolicy::clamped(5, 600);
+        assert_eq!(policy.apply(10), Ok(10));
+        assert_eq!(policy.apply(1), Ok(5));
+        assert_eq!(policy.apply(86_400), Ok(600));
+
+        policy.clamp = false;
+        let err = policy.apply(1).unwrap_err();
+        assert_eq!(
+            err,
+            LeaseTtlOutOfRange {
+                requested: 1,
+                min: 5,
+                max: 600,
+            }
+        );
+        assert!(
+            err.to_string().starts_with("Lease TTL of 1s is outside"),
+            "{err}"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failed_send_is_retried_at_once() {
+        let (echo, sent) = Echo::new();
+        let script = Script::sends([HeartbeatAction::Fail]);
+        let heartbeat = Intercepted::new(echo, 1, script);
+        let token = CancellationToken::new();
+        let keep_alive = run_keep_alive(heartbeat, 1, TTL, token.clone(), Progress::default());
+        let keep_alive = tokio::spawn(keep_alive);
+
+        // The failure at 2s drops the wait to zero, so the retry goes out at 2s too
+        tokio::time::sleep(Duration::from_millis(2_500)).await;
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+
+        // Recovered: back to one heartbeat every 2s, and the lease outlives its TTL
+        tokio::time::sleep(Duration::from_secs(20)).await;
+        assert_eq!(sent.load(Ordering::SeqCst), 11);
+        assert!(!keep_alive.is_finished());
+
+        token.cancel();
+        keep_alive.await.unwrap().unwrap();
+    }
+
+    /// Run a watchdog over tasks from `task`, returning the events it sent and whether it
+    /// cancelled the lease's token, once it returns or after `limit`
+    async fn watch<F>(task: fn(Progress) -> F, limit: Duration) -> (Vec<WatchdogEvent>, bool)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let (events, mut rx) = broadcast::channel(16);
+        let token = CancellationToken::new();
+        let progress = Progress::default();
+        let start = || tokio::spawn(task(progress.clone()));
+        let watchdog = watchdog(1, TTL, progress.clone(), token.clone(), events, start);
+        let _ = tokio::time::timeout(limit, watchdog).await;
+        let mut sent = Vec::new();
+        while let Ok(event) = rx.try_recv() {
+            sent.push(event);
+        }
+        (sent, token.is_cancelled())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_watchdog_leaves_progressing_task_alone() {
+        let (events, cancelled) = watch(
+            |progress| async move {
+                loop {
+                    progress.tick();
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            },
+            Duration::from_secs(60),
+        )
+        .await;
+        assert!(events.is_empty(), "{events:?}");
+        assert!(!cancelled);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_watchdog_restarts_stalled_task_then_gives_up() {
+        let start = Instant::now();
+        let (events, cancelled) =
+            watch(|_| std::future::pending::<()>(), Duration::from_secs(60)).await;
+        assert_eq!(events.len(), 4, "{events:?}");
+        assert!(matches!(
+            events[0],
+            WatchdogEvent::Stalled { restarts: 1, idle, .. } if idle >= stall_limit(TTL)
+        ));
+        assert_eq!(events[3], WatchdogEvent::GaveUp { lease_id: 1 });
+        assert!(cancelled);
+        // Noticed within a quarter of the limit, four times over
+        assert!(
+            start.elapsed() <= stall_limit(TTL) * 5,
+            "{:?}",
+            start.elapsed()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_watchdog_restarts_panicked_task() {
+        let (events, cancelled) = watch(
+            |_| async { panic!("keep-alive bug") },
+            Duration::from_secs(60),
+        )
+        .await;
+        let restarts: Vec<_> = events
+            .iter()
+            .filter_map(|e| match e {
+                WatchdogEvent::Panicked { restarts, .. } => Some(*restarts),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(restarts, [1, 2, 3]);
+        assert!(cancelled);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_watchdog_returns_when_task_ends() {
+        let (events, cancelled) = watch(|_| async {}, Duration::from_secs(60)).await;
+        assert!(events.is_empty());
+        assert!(!cancelled);
+    }
 }
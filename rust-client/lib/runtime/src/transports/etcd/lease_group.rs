@@ -0,0 +1,169 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Secondary leases created and revoked together.
+//!
+//! Tests and batch jobs that create dozens of leases with [`Client::create_lease`] otherwise have
+//! to keep track of them to clean up, and [`Lease::revoke`] only asks the keep-alive task to
+//! revoke, so keys attached to the lease may still be there when it returns.
+//! [`LeaseGroup::revoke_all`] returns once etcd has dropped every lease in the group.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+
+use crate::{Result, error};
+
+use super::{Client, Lease};
+
+/// How long [`LeaseGroup::revoke_all`] waits for etcd to drop the leases
+const REVOKE_TIMEOUT: Duration = Duration::from_secs(5);
+const REVOKE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// See [`Client::create_lease_group`]. Cheap to clone, clones share their leases.
+#[derive(Clone)]
+pub struct LeaseGroup {
+    name: String,
+    client: Client,
+    leases: Arc<Mutex<Vec<Lease>>>,
+}
+
+impl LeaseGroup {
+    pub(super) fn new(client: Client, name: String) -> Self {
+        LeaseGroup {
+            name,
+            client,
+            leases: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// [`Client::create_lease`], adding the lease to the group
+    pub async fn create_lease(&self, ttl: u64) -> Result<Lease> {
+        let lease = self.client.create_lease(ttl).await?;
+        self.leases.lock().push(lease.clone());
+        Ok(lease)
+    }
+
+    /// The leases created since the last [`LeaseGroup::revoke_all`]
+    pub fn leases(&self) -> Vec<Lease> {
+        self.leases.lock().clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.leases.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leases.lock().is_empty()
+    }
+
+    /// True unless a lease in the group was revoked or lost, also when there are none
+    pub fn is_all_valid(&self) -> bool {
+        self.leases
+            .lock()
+            .iter()
+            .all(|lease| !lease.cancel_token.is_cancelled())
+    }
+
+    /// Revoke every lease in the group, and wait until etcd has dropped them and the keys
+    /// attached to them. The group is empty afterwards, even if that fails.
+    pub async fn revoke_all(&self) -> Result<()> {
+        self.client.check_writable("revoke_all")?;
+        let leases = std::mem::take(&mut *self.leases.lock());
+        for lease in &leases {
+            lease.revoke();
+        }
+        let mut lease_client = self.client.etcd_client().lease_client();
+        let deadline = tokio::time::Instant::now() + REVOKE_TIMEOUT;
+        for lease in &leases {
+            // -1 once the lease is gone
+            while lease_client
+                .time_to_live(lease.id as i64, None)
+                .await?
+                .ttl()
+                >= 0
+            {
+                if tokio::time::Instant::now() >= deadline {
+                    return Err(error!(
+                        "Lease {:x} of group {} was not revoked within {REVOKE_TIMEOUT:?}",
+                        lease.id, self.name
+                    ));
+                }
+                tokio::time::sleep(REVOKE_POLL_INTERVAL).await;
+            }
+        }
+        tracing::debug!(group = %self.name, count = leases.len(), "Revoked lease group");
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for LeaseGroup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LeaseGroup")
+            .field("name", &self.name)
+            .field("leases", &self.len())
+            .finish()
+    }
+}
+
+#[cfg(feature = "testing-etcd")]
+#[cfg(test)]
+mod etcd_tests {
+    use super::*;
+    use crate::Runtime;
+
+    #[tokio::test]
+    async fn test_revoke_all() {
+        let runtime = Runtime::from_settings().unwrap();
+        let options = Client::builder()
+            .etcd_url(vec!["http://localhost:2379".to_string()])
+            .build()
+            .unwrap();
+        let client = Client::new(options, runtime).await.unwrap();
+        // Prevent runtime from being dropped in async context at end of test
+        let client = std::mem::ManuallyDrop::new(client);
+
+        let group = client.create_lease_group("batch");
+        let prefix = format!("/test/lease_group/{}", uuid::Uuid::new_v4());
+        for i in 0..3 {
+            let lease = group.create_lease(10).await.unwrap();
+            let key = format!("{prefix}/{i}");
+            client.kv_put(key, "", Some(lease.id())).await.unwrap();
+        }
+        assert_eq!(group.len(), 3);
+        assert!(group.is_all_valid());
+
+        let leases = group.leases();
+        group.revoke_all().await.unwrap();
+        assert!(group.is_empty());
+        // Gone by the time revoke_all returns, not eventually
+        assert!(client.kv_get_prefix(&prefix).await.unwrap().is_empty());
+        assert!(
+            leases
+                .iter()
+                .all(|lease| lease.primary_token().is_cancelled())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_only_group() {
+        let runtime = Runtime::from_settings().unwrap();
+        let options = Client::builder()
+            .etcd_url(vec!["http://localhost:2379".to_string()])
+            .read_only(true)
+            .build()
+            .unwrap();
+        let client = Client::new(options, runtime).await.unwrap();
+        // Prevent runtime from being dropped in async context at end of test
+        let client = std::mem::ManuallyDrop::new(client);
+
+        let group = client.create_lease_group("observer");
+        assert!(group.create_lease(10).await.is_err());
+        assert!(group.revoke_all().await.is_err());
+    }
+}
@@ -110,6 +110,10 @@ impl DistributedRWLock {
         &'a self,
         etcd_client: &'a Client,
     ) -> Option<WriteLockGuard<'a>> {
+        if let Err(err) = etcd_client.check_writable("try_write_lock") {
+            tracing::warn!(%err, "Cannot take write lock");
+            return None;
+        }
         let write_key = format!("v1/{}/writer", self.lock_prefix);
         let lease_id = etcd_client.lease_id();
         let put_options = PutOptions::new().with_lease(lease_id as i64);
@@ -190,6 +194,7 @@ impl DistributedRWLock {
         reader_id: &str,
         timeout: Option<Duration>,
     ) -> Result<ReadLockGuard<'a>> {
+        etcd_client.check_writable("read_lock_with_wait")?;
         let timeout = timeout.unwrap_or(Duration::from_secs(DEFAULT_READ_LOCK_TIMEOUT_SECS));
         let write_key = format!("v1/{}/writer", self.lock_prefix);
         let reader_key = format!("v1/{}/readers/{reader_id}", self.lock_prefix);
@@ -393,4 +398,30 @@ mod tests {
 
         println!("\n🎉 All DistributedRWLock tests passed!");
     }
+
+    #[tokio::test]
+    async fn test_read_only_rwlock() {
+        let runtime = Runtime::from_settings().unwrap();
+        let options = Client::builder()
+            .etcd_url(vec!["http://localhost:2379".to_string()])
+            .read_only(true)
+            .build()
+            .unwrap();
+        let etcd_client = Client::new(options, runtime).await.unwrap();
+        // Prevent runtime from being dropped in async context at end of test
+        let etcd_client = std::mem::ManuallyDrop::new(etcd_client);
+
+        let lock_prefix = format!("/test/rwlock/{}", uuid::Uuid::new_v4());
+        let rwlock = DistributedRWLock::new(lock_prefix.clone());
+        assert!(rwlock.try_write_lock(&etcd_client).await.is_none());
+        let read = rwlock
+            .read_lock_with_wait(&etcd_client, "reader", Some(Duration::from_millis(100)))
+            .await;
+        assert!(read.is_err());
+        let keys = etcd_client
+            .kv_get_prefix(&format!("v1/{lock_prefix}"))
+            .await
+            .unwrap();
+        assert!(keys.is_empty());
+    }
 }
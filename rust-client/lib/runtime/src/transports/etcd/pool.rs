@@ -0,0 +1,219 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! One etcd connection and primary lease per process, instead of one per runtime.
+//!
+//! Each [`Client`] normally opens its own connection and grants its own lease, so a process
+//! hosting several components has that many gRPC connections, keep-alive streams and leases.
+//! Clients created with [`ClientOptions::shared`] come from the [`ConnectionPool`] instead:
+//! the first one for a given set of endpoints and options connects, later ones reuse the
+//! connection and lease, and the lease is revoked once the last of them is dropped.
+//!
+//! A shared lease outlives the shutdown of any one runtime using it, so keys registered by
+//! that runtime have to be deleted explicitly rather than left to the lease.
+
+use super::*;
+use std::sync::{LazyLock, Weak};
+
+static POOL: LazyLock<ConnectionPool> = LazyLock::new(ConnectionPool::default);
+
+/// Clients that share a connection. Two [`ClientOptions`] share if they name the same
+/// endpoints, in any order, with identical connect options and lease settings.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct PoolKey {
+    etcd_url: Vec<String>,
+    /// Debug form of the connect options, which hold no comparable value of their own
+    connect_options: String,
+    /// Debug form of the secrets provider, for the same reason
+    secrets: String,
+    attach_lease: bool,
+    read_only: bool,
+    dns: Option<DnsDiscovery>,
+    proxy: Option<ProxyConfig>,
+}
+
+impl PoolKey {
+    fn new(config: &ClientOptions) -> Self {
+        let mut etcd_url: Vec<String> = config
+            .etcd_url
+            .iter()
+            .map(|url| url.trim().trim_end_matches('/').to_string())
+            .collect();
+        etcd_url.sort();
+        etcd_url.dedup();
+        PoolKey {
+            etcd_url,
+            connect_options: format!("{:?}", config.etcd_connect_options),
+            secrets: format!("{:?}", config.secrets),
+            attach_lease: config.attach_lease,
+            read_only: config.read_only,
+            dns: config.dns.clone(),
+            proxy: config.proxy.clone(),
+        }
+    }
+}
+
+/// Held by every client using a pooled connection. The last one to go revokes the lease.
+pub(crate) struct PooledConnection {
+    key: PoolKey,
+    /// Revokes the lease when cancelled. The keep-alive task cancels it if the lease is lost.
+    lease_token: CancellationToken,
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        tracing::debug!(etcd_url = ?self.key.etcd_url, "last user of shared etcd connection gone");
+        self.lease_token.cancel();
+        let mut connections = POOL.connections.lock();
+        if connections
+            .get(&self.key)
+            .is_some_and(|pooled| pooled.connection.strong_count() == 0)
+        {
+            connections.remove(&self.key);
+        }
+    }
+}
+
+struct Pooled {
+    connection: Weak<PooledConnection>,
+    /// The first client, without its reference to `connection`; copied for later users
+    client: Client,
+}
+
+#[derive(Default)]
+pub struct ConnectionPool {
+    connections: parking_lot::Mutex<HashMap<PoolKey, Pooled>>,
+    /// Held while connecting, so runtimes starting together do not each connect
+    connecting: tokio::sync::Mutex<()>,
+}
+
+impl ConnectionPool {
+    /// The process-wide pool used by [`Client::new`]
+    pub fn global() -> &'static ConnectionPool {
+        &POOL
+    }
+
+    /// Connections with at least one client
+    pub fn len(&self) -> usize {
+        self.connections
+            .lock()
+            .values()
+            .filter(|pooled| pooled.connection.strong_count() > 0)
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// A client for `runtime` on the pooled connection for `config`, connecting if there is
+    /// none yet, or if its lease was lost
+    pub(crate) async fn connect(&self, config: ClientOptions, runtime: Runtime) -> Result<Client> {
+        let key = PoolKey::new(&config);
+        let _connecting = self.connecting.lock().await;
+
+        // Released before `existing` can be dropped, because dropping the last reference
+        // takes the lock
+        let existing = self.connections.lock().get(&key).and_then(|pooled| {
+            let connection = pooled.connection.upgrade()?;
+            Some((pooled.client.clone(), connection))
+        });
+        if let Some((client, connection)) = existing
+            && !connection.lease_token.is_cancelled()
+        {
+            tracing::debug!(
+                lease_id = client.lease_id(),
+                "reusing shared etcd connection"
+            );
+            return Ok(join(client, connection, runtime));
+        }
+
+        let lease_token = CancellationToken::new();
+        let client = Client::connect(config, runtime.clone(), lease_token.clone()).await?;
+        let connection = Arc::new(PooledConnection {
+            key: key.clone(),
+            lease_token,
+        });
+        self.connections.lock().insert(
+            key,
+            Pooled {
+                connection: Arc::downgrade(&connection),
+                client: client.clone(),
+            },
+        );
+        Ok(join(client, connection, runtime))
+    }
+}
+
+impl std::fmt::Debug for ConnectionPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectionPool")
+            .field("connections", &self.len())
+            .finish()
+    }
+}
+
+/// `client` for another runtime. Losing the lease shuts that runtime down too, as it would
+/// with a lease of its own.
+fn join(client: Client, connection: Arc<PooledConnection>, runtime: Runtime) -> Client {
+    let lease_token = connection.lease_token.clone();
+    let users = Arc::downgrade(&connection);
+    let primary_token = runtime.primary_token();
+    runtime.secondary().spawn(async move {
+        tokio::select! {
+            _ = lease_token.cancelled() => {
+                // Still in use, so this is the keep-alive giving up rather than the last
+                // client going away
+                if users.strong_count() > 0 {
+                    tracing::error!("Shared etcd lease lost, shutting down");
+                    primary_token.cancel();
+                }
+            }
+            _ = primary_token.cancelled() => {}
+        }
+    });
+    Client {
+        runtime,
+        shared: Some(connection),
+        ..client
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(urls: &[&str]) -> ClientOptions {
+        ClientOptions {
+            etcd_url: urls.iter().map(|url| url.to_string()).collect(),
+            etcd_connect_options: None,
+            attach_lease: true,
+            read_only: false,
+            shared: true,
+            dns: None,
+            proxy: None,
+            clock_check_interval: None,
+            lease_tuning: Default::default(),
+            lease_ttl_policy: Default::default(),
+            secrets: None,
+        }
+    }
+
+    #[test]
+    fn test_pool_key() {
+        let key = PoolKey::new(&options(&["http://a:2379", "http://b:2379/"]));
+        assert_eq!(
+            key,
+            PoolKey::new(&options(&["http://b:2379", "http://a:2379"]))
+        );
+        assert_ne!(key, PoolKey::new(&options(&["http://a:2379"])));
+
+        let mut read_only = options(&["http://a:2379", "http://b:2379"]);
+        read_only.read_only = true;
+        assert_ne!(key, PoolKey::new(&read_only));
+
+        let mut with_auth = options(&["http://a:2379", "http://b:2379"]);
+        with_auth.etcd_connect_options = Some(ConnectOptions::new().with_user("u", "p"));
+        assert_ne!(key, PoolKey::new(&with_auth));
+    }
+}
@@ -0,0 +1,322 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! ZooKeeper-style ephemeral sequential nodes on etcd
+//!
+//! ZooKeeper appends a monotonically increasing, per-parent counter to a node name when it is
+//! created with the SEQUENTIAL flag, and deletes EPHEMERAL nodes when the creating session ends.
+//! Queues and fair leader election are built on the two together: every participant creates a
+//! node, sorts the children by sequence, and waits for the node just before its own to go away.
+//!
+//! Here the session is an etcd lease and the counter is a key under the parent, bumped in the
+//! same transaction that creates the node so two creators can never get the same sequence number.
+
+use etcd_client::{Compare, CompareOp, EventType, PutOptions, Txn, TxnOp, WatchOptions};
+
+use crate::{Result, error};
+
+use super::Client;
+
+/// Width of the zero padded sequence suffix, the same as ZooKeeper uses
+const SEQUENCE_WIDTH: usize = 10;
+
+/// Name of the counter key under the parent. Not a valid node name as it has no sequence.
+const COUNTER_KEY: &str = "_sequence";
+
+/// Give up creating a node after this many lost races on the counter
+const MAX_CREATE_ATTEMPTS: usize = 64;
+
+/// A node created by [`SequentialNodes::create`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SequentialNode {
+    /// Full etcd key
+    pub key: String,
+    pub sequence: u64,
+    pub value: Vec<u8>,
+}
+
+/// The sequential children of one parent path
+#[derive(Debug, Clone)]
+pub struct SequentialNodes {
+    parent: String,
+}
+
+impl SequentialNodes {
+    /// `parent` is a key prefix such as `v1/queues/jobs`, without trailing slash
+    pub fn new(parent: impl Into<String>) -> Self {
+        let parent = parent.into().trim_end_matches('/').to_string();
+        Self { parent }
+    }
+
+    pub fn parent(&self) -> &str {
+        &self.parent
+    }
+
+    /// Create `{parent}/{name}{sequence}` attached to `lease_id`, or the primary lease if None.
+    /// The node is deleted when the lease expires or is revoked. Errors without a lease, as a
+    /// node nothing deletes would block the queue behind it for good.
+    pub async fn create(
+        &self,
+        client: &Client,
+        name: &str,
+        value: Vec<u8>,
+        lease_id: Option<u64>,
+    ) -> Result<SequentialNode> {
+        client.check_writable("sequential create")?;
+        let counter_key = format!("{}/{COUNTER_KEY}", self.parent);
+        let lease_id = lease_id.unwrap_or(client.lease_id());
+        if lease_id == 0 {
+            return Err(error!(
+                "Sequential nodes under {} need a lease, and the client has none",
+                self.parent
+            ));
+        }
+        let mut kv_client = client.etcd_client().kv_client();
+
+        for _ in 0..MAX_CREATE_ATTEMPTS {
+            let current = client.kv_get(counter_key.as_str(), None).await?;
+            let (sequence, guard) = match current.first() {
+                Some(kv) => {
+                    let n: u64 = kv
+                        .value_str()?
+                        .parse()
+                        .map_err(|e| error!("Corrupt sequence counter at {counter_key}: {e}"))?;
+                    (
+                        n,
+                        Compare::mod_revision(
+                            counter_key.as_str(),
+                            CompareOp::Equal,
+                            kv.mod_revision(),
+                        ),
+                    )
+                }
+                None => (
+                    0,
+                    Compare::version(counter_key.as_str(), CompareOp::Equal, 0),
+                ),
+            };
+
+            let key = node_key(&self.parent, name, sequence);
+            // The counter outlives any one lease, like a ZooKeeper parent's cversion
+            let txn = Txn::new().when(vec![guard]).and_then(vec![
+                TxnOp::put(counter_key.as_str(), (sequence + 1).to_string(), None),
+                TxnOp::put(
+                    key.as_str(),
+                    value.clone(),
+                    Some(PutOptions::new().with_lease(lease_id as i64)),
+                ),
+            ]);
+            if kv_client.txn(txn).await?.succeeded() {
+                return Ok(SequentialNode {
+                    key,
+                    sequence,
+                    value,
+                });
+            }
+            tracing::trace!(parent = %self.parent, sequence, "Lost sequence race, retrying");
+        }
+
+        Err(error!(
+            "Unable to create sequential node under {} after {MAX_CREATE_ATTEMPTS} attempts",
+            self.parent
+        ))
+    }
+
+    /// All current nodes, lowest sequence first
+    pub async fn children(&self, client: &Client) -> Result<Vec<SequentialNode>> {
+        let prefix = format!("{}/", self.parent);
+        let mut nodes: Vec<SequentialNode> = client
+            .kv_get_prefix(&prefix)
+            .await?
+            .into_iter()
+            .filter_map(|kv| {
+                let key = kv.key_str().ok()?.to_string();
+                let sequence = parse_sequence(&key[prefix.len()..])?;
+                Some(SequentialNode {
+                    key,
+                    sequence,
+                    value: kv.value().to_vec(),
+                })
+            })
+            .collect();
+        nodes.sort_by_key(|n| n.sequence);
+        Ok(nodes)
+    }
+
+    /// Wait until `node` has the lowest sequence of the remaining children. This is the
+    /// ZooKeeper queue/election recipe: only the immediate predecessor is watched, so each
+    /// deletion wakes a single waiter rather than all of them.
+    ///
+    /// Errors if `node` itself no longer exists, e.g. because its lease expired.
+    pub async fn wait_for_turn(&self, client: &Client, node: &SequentialNode) -> Result<()> {
+        loop {
+            let children = self.children(client).await?;
+            let Some(position) = children.iter().position(|n| n.key == node.key) else {
+                return Err(error!("Sequential node {} no longer exists", node.key));
+            };
+            if position == 0 {
+                return Ok(());
+            }
+            let predecessor = &children[position - 1].key;
+
+            let read = client
+                .etcd_client()
+                .kv_client()
+                .get(predecessor.as_str(), None)
+                .await?;
+            if read.kvs().is_empty() {
+                continue;
+            }
+            // Watch that one key from just after the read, so a delete in between is not missed
+            let revision = read.header().map(|h| h.revision()).unwrap_or_default();
+            let options = WatchOptions::new().with_start_revision(revision + 1);
+            let (_watcher, mut stream) = client
+                .etcd_client()
+                .watch_client()
+                .watch(predecessor.as_str(), Some(options))
+                .await?;
+            tracing::trace!(node = %node.key, %predecessor, "Waiting for predecessor");
+            loop {
+                let Some(response) = stream.message().await? else {
+                    return Err(error!("Watch on {predecessor} closed"));
+                };
+                if response.canceled() {
+                    return Err(error!("Watch on {predecessor} was cancelled by etcd"));
+                }
+                if response
+                    .events()
+                    .iter()
+                    .any(|event| event.event_type() == EventType::Delete)
+                {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Delete a node before its lease ends
+    pub async fn delete(&self, client: &Client, node: &SequentialNode) -> Result<()> {
+        client.kv_delete(node.key.as_str(), None).await?;
+        Ok(())
+    }
+}
+
+fn node_key(parent: &str, name: &str, sequence: u64) -> String {
+    format!("{parent}/{name}{sequence:0width$}", width = SEQUENCE_WIDTH)
+}
+
+/// The sequence suffix of a child name, or None if it is not a sequential node
+fn parse_sequence(child: &str) -> Option<u64> {
+    if child.contains('/') || child.len() < SEQUENCE_WIDTH {
+        return None;
+    }
+    let suffix = &child[child.len() - SEQUENCE_WIDTH..];
+    if !suffix.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    suffix.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_node_key_round_trip() {
+        let key = node_key("v1/queues/jobs", "job-", 42);
+        assert_eq!(key, "v1/queues/jobs/job-0000000042");
+        assert_eq!(parse_sequence("job-0000000042"), Some(42));
+        assert_eq!(parse_sequence("0000000007"), Some(7));
+    }
+
+    #[test]
+    fn test_parse_sequence_rejects_non_nodes() {
+        assert_eq!(parse_sequence(COUNTER_KEY), None);
+        assert_eq!(parse_sequence("job-42"), None);
+        assert_eq!(parse_sequence("job-00000000x2"), None);
+        assert_eq!(parse_sequence("sub/job-0000000042"), None);
+    }
+}
+
+#[cfg(feature = "testing-etcd")]
+#[cfg(test)]
+mod etcd_tests {
+    use super::*;
+    use crate::Runtime;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_sequential_nodes_queue() {
+        let runtime = Runtime::from_settings().unwrap();
+        let options = Client::builder()
+            .etcd_url(vec!["http://localhost:2379".to_string()])
+            .build()
+            .unwrap();
+        let client = Client::new(options, runtime).await.unwrap();
+        // Prevent runtime from being dropped in async context at end of test
+        let client = std::mem::ManuallyDrop::new(client);
+
+        let nodes = SequentialNodes::new(format!("/test/sequential/{}", uuid::Uuid::new_v4()));
+        let first = nodes
+            .create(&client, "n-", b"a".to_vec(), None)
+            .await
+            .unwrap();
+        let second = nodes
+            .create(&client, "n-", b"b".to_vec(), None)
+            .await
+            .unwrap();
+        assert_eq!(first.sequence, 0);
+        assert_eq!(second.sequence, 1);
+        assert_eq!(
+            nodes.children(&client).await.unwrap(),
+            vec![first.clone(), second.clone()]
+        );
+
+        nodes.wait_for_turn(&client, &first).await.unwrap();
+
+        let waiter = {
+            let nodes = nodes.clone();
+            let client = (*client).clone();
+            let second = second.clone();
+            tokio::spawn(async move { nodes.wait_for_turn(&client, &second).await })
+        };
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!waiter.is_finished(), "second node must wait for the first");
+
+        nodes.delete(&client, &first).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), waiter)
+            .await
+            .expect("second node should get its turn")
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sequential_node_needs_lease() {
+        let runtime = Runtime::from_settings().unwrap();
+        let options = Client::builder()
+            .etcd_url(vec!["http://localhost:2379".to_string()])
+            .attach_lease(false)
+            .build()
+            .unwrap();
+        let client = Client::new(options, runtime).await.unwrap();
+        // Prevent runtime from being dropped in async context at end of test
+        let client = std::mem::ManuallyDrop::new(client);
+
+        let nodes = SequentialNodes::new(format!("/test/sequential/{}", uuid::Uuid::new_v4()));
+        let err = nodes
+            .create(&client, "n-", b"a".to_vec(), None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("need a lease"), "{err}");
+        assert!(nodes.children(&client).await.unwrap().is_empty());
+
+        let lease = client.create_lease(10).await.unwrap();
+        let node = nodes
+            .create(&client, "n-", b"a".to_vec(), Some(lease.id()))
+            .await
+            .unwrap();
+        assert_eq!(node.sequence, 0);
+        lease.revoke();
+    }
+}
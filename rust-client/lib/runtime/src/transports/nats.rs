@@ -16,7 +16,20 @@
 //! - `NATS_AUTH_CREDENTIALS_FILE`: the path to the credentials file
 //!
 //! Note: `NATS_AUTH_USERNAME` and `NATS_AUTH_PASSWORD` must be used together.
+//!
+//! With `DYN_SECRETS_PROVIDER`, all but the credentials file are read from a secrets provider
+//! under the same names, again on every reconnect. See [`crate::secrets`].
+//!
+//! `DYN_NATS_PROXY` or `DYN_PROXY` connect through a proxy, see [`crate::transports::proxy`].
+//! `DYN_NATS_CLUSTERS` and `DYN_NATS_NAMESPACES` put some namespaces on other clusters, see
+//! [`NatsClustersConfig`].
+//!
+//! The client reconnects by itself when the connection drops. A request waiting for its reply
+//! then fails with [`ConnectionLost`] straight away, or is sent again once the client is back if
+//! its endpoint is idempotent, see [`Client::request_with_reconnect`].
+use crate::secrets::SecretsProvider;
 use crate::traits::events::EventPublisher;
+use crate::transports::proxy::{ProxyConfig, Routes};
 use crate::{Result, metrics::MetricsHierarchy};
 
 use async_nats::connection::State;
@@ -28,10 +41,13 @@ use futures::{StreamExt, TryStreamExt};
 use prometheus::{Counter, Gauge, Histogram, HistogramOpts, IntCounter, IntGauge, Opts, Registry};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::future::Future;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use tokio::fs::File as TokioFile;
 use tokio::io::AsyncRead;
+use tokio::sync::watch;
 use tokio::time;
 use url::Url;
 use validator::{Validate, ValidationError};
@@ -42,12 +58,23 @@ use tracing as log;
 
 use super::utils::build_in_runtime;
 
+mod clusters;
+
+pub use clusters::{DEFAULT_CLUSTER, NamespaceRoute, NatsClusters, NatsClustersConfig};
+
 pub const URL_PREFIX: &str = "nats://";
 
+/// How long a request to be sent again waits for the client to reconnect
+pub const REDISPATCH_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+
+/// How many times a request is sent again before it fails with [`ConnectionLost`]
+pub const MAX_REDISPATCHES: u32 = 3;
+
 #[derive(Clone)]
 pub struct Client {
     client: client::Client,
     js_ctx: jetstream::Context,
+    connection: Arc<ConnectionMonitor>,
 }
 
 impl Client {
@@ -66,6 +93,34 @@ impl Client {
         &self.js_ctx
     }
 
+    /// How the connection to the server has fared
+    pub fn connection(&self) -> &Arc<ConnectionMonitor> {
+        &self.connection
+    }
+
+    /// Send a request and wait for its reply. If the connection drops first, fail with
+    /// [`ConnectionLost`] rather than at the request timeout, or with `redispatch` send it again
+    /// once the client has reconnected. Only requests to idempotent endpoints should be sent
+    /// again, as the handler may have received the first one.
+    pub async fn request_with_reconnect(
+        &self,
+        subject: String,
+        headers: async_nats::HeaderMap,
+        payload: Bytes,
+        redispatch: bool,
+    ) -> Result<async_nats::Message> {
+        self.connection
+            .dispatch(&subject, redispatch, || async {
+                let request = self.client.request_with_headers(
+                    subject.clone(),
+                    headers.clone(),
+                    payload.clone(),
+                );
+                Ok(request.await?)
+            })
+            .await
+    }
+
     /// host:port of NATS
     pub fn addr(&self) -> String {
         let info = self.client.server_info();
@@ -267,6 +322,120 @@ impl Client {
     }
 }
 
+/// The connection to the NATS server dropped while a request waited for its reply, which the
+/// handler may or may not have received. Transient, as the client reconnects by itself.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("The NATS connection dropped during the request to {subject}")]
+pub struct ConnectionLost {
+    pub subject: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct ConnectionState {
+    connected: bool,
+    disconnects: u64,
+}
+
+/// Follows the client's connection events, for the requests waiting on it and for metrics
+#[derive(Debug)]
+pub struct ConnectionMonitor {
+    state: watch::Sender<ConnectionState>,
+    reconnects: AtomicU64,
+    dropped_requests: AtomicU64,
+    redispatched_requests: AtomicU64,
+}
+
+impl ConnectionMonitor {
+    fn new() -> Self {
+        ConnectionMonitor {
+            state: watch::channel(ConnectionState::default()).0,
+            reconnects: AtomicU64::new(0),
+            dropped_requests: AtomicU64::new(0),
+            redispatched_requests: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state.borrow().connected
+    }
+
+    /// Connections made after the first one was lost
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects.load(Ordering::Relaxed)
+    }
+
+    /// Requests that failed with [`ConnectionLost`]
+    pub fn dropped_requests(&self) -> u64 {
+        self.dropped_requests.load(Ordering::Relaxed)
+    }
+
+    /// Requests sent again after a reconnect
+    pub fn redispatched_requests(&self) -> u64 {
+        self.redispatched_requests.load(Ordering::Relaxed)
+    }
+
+    fn on_event(&self, event: async_nats::Event) {
+        match event {
+            async_nats::Event::Connected => {
+                let reconnected = self.state.borrow().disconnects > 0;
+                if reconnected {
+                    self.reconnects.fetch_add(1, Ordering::Relaxed);
+                    log::info!("Reconnected to NATS");
+                }
+                self.state.send_modify(|state| state.connected = true);
+            }
+            async_nats::Event::Disconnected => {
+                log::warn!("Disconnected from NATS, reconnecting");
+                self.state.send_modify(|state| {
+                    state.connected = false;
+                    state.disconnects += 1;
+                });
+            }
+            _ => {}
+        }
+    }
+
+    /// Run `send` until it finishes without the connection dropping in between, see
+    /// [`Client::request_with_reconnect`]
+    async fn dispatch<T, F, Fut>(&self, subject: &str, redispatch: bool, send: F) -> Result<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut state = self.state.subscribe();
+        let mut redispatches = 0;
+        loop {
+            // sent while disconnected, it waits in the client's buffer until the next connection
+            let disconnects = state.borrow_and_update().disconnects;
+            tokio::select! {
+                result = send() => return result,
+                _ = state.wait_for(|state| state.disconnects != disconnects) => {}
+            }
+
+            let lost = ConnectionLost {
+                subject: subject.to_string(),
+            };
+            if !redispatch || redispatches == MAX_REDISPATCHES {
+                self.dropped_requests.fetch_add(1, Ordering::Relaxed);
+                return Err(lost.into());
+            }
+            let reconnected =
+                time::timeout(REDISPATCH_TIMEOUT, state.wait_for(|state| state.connected));
+            if !matches!(reconnected.await, Ok(Ok(_))) {
+                self.dropped_requests.fetch_add(1, Ordering::Relaxed);
+                return Err(lost.into());
+            }
+            redispatches += 1;
+            self.redispatched_requests.fetch_add(1, Ordering::Relaxed);
+            log::debug!(
+                subject,
+                redispatches,
+                "Sending the request again after a reconnect"
+            );
+        }
+    }
+}
+
 /// NATS client options
 ///
 /// This object uses the builder pattern with default values that are evaluates
@@ -279,6 +448,14 @@ pub struct ClientOptions {
 
     #[builder(default)]
     auth: NatsAuth,
+
+    /// Reach the server through this proxy
+    #[builder(default = "default_proxy()")]
+    proxy: Option<ProxyConfig>,
+
+    /// Read the credentials from here, in place of `auth` if it has any
+    #[builder(default)]
+    pub(crate) secrets: Option<Arc<dyn SecretsProvider>>,
 }
 
 fn default_server() -> String {
@@ -289,6 +466,13 @@ fn default_server() -> String {
     "nats://localhost:4222".to_string()
 }
 
+fn default_proxy() -> Option<ProxyConfig> {
+    ProxyConfig::from_env("NATS").unwrap_or_else(|err| {
+        tracing::warn!(%err, "Ignoring the NATS proxy");
+        None
+    })
+}
+
 fn validate_nats_server(server: &str) -> Result<(), ValidationError> {
     if server.starts_with("nats://") {
         Ok(())
@@ -310,21 +494,57 @@ impl ClientOptions {
     pub async fn connect(self) -> Result<Client> {
         self.validate()?;
 
-        let client = match self.auth {
-            NatsAuth::UserPass(username, password) => {
-                async_nats::ConnectOptions::with_user_and_password(username, password)
-            }
-            NatsAuth::Token(token) => async_nats::ConnectOptions::with_token(token),
-            NatsAuth::NKey(nkey) => async_nats::ConnectOptions::with_nkey(nkey),
-            NatsAuth::CredentialsFile(path) => {
-                async_nats::ConnectOptions::with_credentials_file(path).await?
+        let from_secrets = match &self.secrets {
+            Some(secrets) => NatsAuth::from_secrets(secrets.as_ref()).await?,
+            None => None,
+        };
+        let mut client = match (from_secrets, self.secrets) {
+            (Some(NatsAuth::UserPass(..) | NatsAuth::Token(_)), Some(secrets)) => {
+                rotating_auth(secrets)
             }
+            (from_secrets, _) => match from_secrets.unwrap_or(self.auth) {
+                NatsAuth::UserPass(username, password) => {
+                    async_nats::ConnectOptions::with_user_and_password(username, password)
+                }
+                NatsAuth::Token(token) => async_nats::ConnectOptions::with_token(token),
+                NatsAuth::NKey(nkey) => async_nats::ConnectOptions::with_nkey(nkey),
+                NatsAuth::CredentialsFile(path) => {
+                    async_nats::ConnectOptions::with_credentials_file(path).await?
+                }
+            },
         };
 
+        if self.proxy.is_some() {
+            // The servers a cluster advertises would be dialed directly
+            client = client.ignore_discovered_servers();
+        }
+
+        let connection = Arc::new(ConnectionMonitor::new());
+        client = client.event_callback({
+            let connection = connection.clone();
+            move |event| {
+                let connection = connection.clone();
+                async move { connection.on_event(event) }
+            }
+        });
+
         let (client, _) = build_in_runtime(
             async move {
+                // Tunnels through the proxy run on this runtime, which lives as long as the
+                // process
+                let server = match self.proxy {
+                    Some(proxy) => {
+                        let mut routes = Routes::new(Some(proxy), 4222, Default::default());
+                        let mut dialed = Vec::new();
+                        for server in self.server.split(',') {
+                            dialed.push(routes.add(server.trim()).await?);
+                        }
+                        dialed.join(",")
+                    }
+                    None => self.server,
+                };
                 client
-                    .connect(self.server)
+                    .connect(server)
                     .await
                     .map_err(|e| anyhow::anyhow!("Failed to connect to NATS: {e}. Verify NATS server is running and accessible."))
             },
@@ -340,7 +560,11 @@ impl ClientOptions {
             .await
             .map_err(|e| anyhow::anyhow!("JetStream not available: {e}"))?;
 
-        Ok(Client { client, js_ctx })
+        Ok(Client {
+            client,
+            js_ctx,
+            connection,
+        })
     }
 }
 
@@ -349,10 +573,39 @@ impl Default for ClientOptions {
         ClientOptions {
             server: default_server(),
             auth: NatsAuth::default(),
+            proxy: default_proxy(),
+            secrets: None,
         }
     }
 }
 
+/// Reads the credentials from `secrets` each time the client connects, so that a reconnect
+/// after they were rotated uses the new ones
+fn rotating_auth(secrets: Arc<dyn SecretsProvider>) -> async_nats::ConnectOptions {
+    async_nats::ConnectOptions::with_auth_callback(move |_nonce| {
+        let secrets = secrets.clone();
+        // The callback's future has to be Sync, which the provider's isn't
+        let read = tokio::spawn(async move { NatsAuth::from_secrets(secrets.as_ref()).await });
+        async move {
+            let mut auth = async_nats::Auth::new();
+            match read.await {
+                Ok(Ok(Some(NatsAuth::UserPass(username, password)))) => {
+                    auth.username = Some(username);
+                    auth.password = Some(password);
+                }
+                Ok(Ok(Some(NatsAuth::Token(token)))) => auth.token = Some(token),
+                Ok(Ok(other)) => {
+                    let err = format!("Expected a NATS user or token secret, found {other:?}");
+                    return Err(async_nats::AuthError::new(err));
+                }
+                Ok(Err(err)) => return Err(async_nats::AuthError::new(err)),
+                Err(err) => return Err(async_nats::AuthError::new(err)),
+            }
+            Ok(auth)
+        }
+    })
+}
+
 #[derive(Clone, Eq, PartialEq)]
 pub enum NatsAuth {
     UserPass(String, String),
@@ -374,6 +627,23 @@ impl std::fmt::Debug for NatsAuth {
     }
 }
 
+impl NatsAuth {
+    /// The credentials in `secrets`, in the order of the environment variables, if any
+    pub async fn from_secrets(secrets: &dyn SecretsProvider) -> Result<Option<NatsAuth>> {
+        let username = secrets.get("NATS_AUTH_USERNAME").await?;
+        let password = secrets.get("NATS_AUTH_PASSWORD").await?;
+        if let (Some(username), Some(password)) = (username, password) {
+            let auth = NatsAuth::UserPass(username.into_inner(), password.into_inner());
+            return Ok(Some(auth));
+        }
+        if let Some(token) = secrets.get("NATS_AUTH_TOKEN").await? {
+            return Ok(Some(NatsAuth::Token(token.into_inner())));
+        }
+        let nkey = secrets.get("NATS_AUTH_NKEY").await?;
+        Ok(nkey.map(|nkey| NatsAuth::NKey(nkey.into_inner())))
+    }
+}
+
 impl Default for NatsAuth {
     fn default() -> Self {
         if let (Ok(username), Ok(password)) = (
@@ -905,11 +1175,18 @@ pub struct DRTNatsClientPrometheusMetrics {
     pub connects: IntGauge,
     /// Current connection state (0 = disconnected, 1 = connected, 2 = reconnecting)
     pub connection_state: IntGauge,
+    connection: Arc<ConnectionMonitor>,
+    /// Number of times the connection was made again after dropping
+    pub reconnects: IntGauge,
+    /// Number of requests that failed as the connection dropped while they were in flight
+    pub dropped_requests: IntGauge,
+    /// Number of requests sent again after a reconnect
+    pub redispatched_requests: IntGauge,
 }
 
 impl DRTNatsClientPrometheusMetrics {
     /// Create a new instance of NATS client metrics using a DistributedRuntime's Prometheus constructors
-    pub fn new(drt: &crate::DistributedRuntime, nats_client: client::Client) -> Result<Self> {
+    pub fn new(drt: &crate::DistributedRuntime, nats_client: &Client) -> Result<Self> {
         let metrics = drt.metrics();
         let in_bytes = metrics.create_intgauge(
             nats_metrics::IN_TOTAL_BYTES,
@@ -941,15 +1218,34 @@ impl DRTNatsClientPrometheusMetrics {
             "Current connection state of NATS client (0=disconnected, 1=connected, 2=reconnecting)",
             &[],
         )?;
+        let reconnects = metrics.create_intgauge(
+            nats_metrics::RECONNECTS,
+            "Total number of times NATS client reconnected after losing its connection",
+            &[],
+        )?;
+        let dropped_requests = metrics.create_intgauge(
+            nats_metrics::DROPPED_REQUESTS,
+            "Total number of requests in flight when NATS client lost its connection",
+            &[],
+        )?;
+        let redispatched_requests = metrics.create_intgauge(
+            nats_metrics::REDISPATCHED_REQUESTS,
+            "Total number of requests NATS client sent again after reconnecting",
+            &[],
+        )?;
 
         Ok(Self {
-            nats_client,
+            nats_client: nats_client.client().clone(),
             in_bytes,
             out_bytes,
             in_messages,
             out_messages,
             connects,
             connection_state,
+            connection: nats_client.connection().clone(),
+            reconnects,
+            dropped_requests,
+            redispatched_requests,
         })
     }
 
@@ -979,6 +1275,11 @@ impl DRTNatsClientPrometheusMetrics {
         self.out_messages.set(out_messages as i64);
         self.connects.set(connects as i64);
         self.connection_state.set(connection_state);
+        self.reconnects.set(self.connection.reconnects() as i64);
+        self.dropped_requests
+            .set(self.connection.dropped_requests() as i64);
+        self.redispatched_requests
+            .set(self.connection.redispatched_requests() as i64);
     }
 }
 
@@ -997,6 +1298,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::result_large_err)]
     fn test_client_options_builder() {
         Jail::expect_with(|_jail| {
             let opts = ClientOptions::builder().build();
@@ -1041,6 +1343,46 @@ mod tests {
         });
     }
 
+    #[tokio::test]
+    async fn test_dispatch_across_reconnect() {
+        let monitor = ConnectionMonitor::new();
+        monitor.on_event(async_nats::Event::Connected);
+        assert!(monitor.is_connected());
+        assert_eq!(monitor.reconnects(), 0);
+
+        // a reply that never comes fails when the connection drops
+        let never = || futures::future::pending::<Result<u64>>();
+        let (dropped, _) = tokio::join!(monitor.dispatch("subject", false, never), async {
+            tokio::task::yield_now().await;
+            monitor.on_event(async_nats::Event::Disconnected);
+        });
+        let err = dropped.unwrap_err();
+        assert!(err.downcast_ref::<ConnectionLost>().is_some());
+        assert_eq!(monitor.dropped_requests(), 1);
+
+        // or is sent again once reconnected
+        let attempts = AtomicU64::new(0);
+        let send = || {
+            let attempt = attempts.fetch_add(1, Ordering::Relaxed);
+            async move {
+                if attempt == 0 {
+                    futures::future::pending::<()>().await;
+                }
+                Ok(attempt)
+            }
+        };
+        let (sent, _) = tokio::join!(monitor.dispatch("subject", true, send), async {
+            tokio::task::yield_now().await;
+            monitor.on_event(async_nats::Event::Disconnected);
+            tokio::task::yield_now().await;
+            monitor.on_event(async_nats::Event::Connected);
+        });
+        assert_eq!(sent.unwrap(), 1);
+        assert_eq!(monitor.redispatched_requests(), 1);
+        assert_eq!(monitor.reconnects(), 1);
+        assert_eq!(monitor.dropped_requests(), 1);
+    }
+
     // Integration test for object store data operations using bincode
     #[tokio::test]
     #[ignore] // Requires NATS server to be running
@@ -0,0 +1,210 @@
+// SPDX-FileCopyrightText: Copyright (c) 2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! NATS clusters besides the default one, for namespaces whose traffic may not share a bus with
+//! the rest, e.g. those of two business units on the same runtime.
+//!
+//! `DYN_NATS_CLUSTERS` names the other clusters, `;` separated, each with its servers as in
+//! `NATS_SERVER`. They use the default cluster's credentials:
+//!
+//! ```text
+//! DYN_NATS_CLUSTERS="billing=nats://bus-b1:4222,nats://bus-b2:4222;search=nats://bus-s:4222"
+//! ```
+//!
+//! `DYN_NATS_NAMESPACES` puts namespaces on them, the first match winning. A pattern is a
+//! namespace, or a prefix of namespaces ending in `*`. After the cluster, which may be
+//! [`DEFAULT_CLUSTER`], an optional prefix is put in front of every subject of the namespace's
+//! endpoints and events, for clusters whose subjects are laid out by account or unit:
+//!
+//! ```text
+//! DYN_NATS_NAMESPACES="billing-*=billing:bu.billing;search=search;legacy=default:old"
+//! ```
+//!
+//! Namespaces that match none stay on the default cluster, unprefixed. Every process serving or
+//! calling a namespace needs the same settings, or they won't find each other.
+
+use std::collections::HashMap;
+
+use anyhow::Context as _;
+
+use super::*;
+
+/// The cluster of `NATS_SERVER`
+pub const DEFAULT_CLUSTER: &str = "default";
+
+/// Where a namespace's traffic goes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamespaceRoute {
+    /// A namespace, or a prefix of namespaces ending in `*`
+    pub pattern: String,
+    pub cluster: String,
+    /// Put in front of the namespace's subjects, ending in a `.` if not empty
+    pub subject_prefix: String,
+}
+
+impl NamespaceRoute {
+    pub fn matches(&self, namespace: &str) -> bool {
+        match self.pattern.strip_suffix('*') {
+            Some(prefix) => namespace.starts_with(prefix),
+            None => self.pattern == namespace,
+        }
+    }
+}
+
+/// From `DYN_NATS_CLUSTERS` and `DYN_NATS_NAMESPACES`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NatsClustersConfig {
+    /// The servers of each cluster besides the default one, by name
+    pub clusters: Vec<(String, String)>,
+    pub routes: Vec<NamespaceRoute>,
+}
+
+impl NatsClustersConfig {
+    /// None if neither variable is set
+    pub fn from_env() -> Result<Option<Self>> {
+        let clusters = std::env::var("DYN_NATS_CLUSTERS").unwrap_or_default();
+        let routes = std::env::var("DYN_NATS_NAMESPACES").unwrap_or_default();
+        if clusters.trim().is_empty() && routes.trim().is_empty() {
+            return Ok(None);
+        }
+        Self::parse(&clusters, &routes).map(Some)
+    }
+
+    pub fn parse(clusters: &str, routes: &str) -> Result<Self> {
+        let mut config = NatsClustersConfig::default();
+        for cluster in entries(clusters) {
+            let Some((name, servers)) = cluster.split_once('=') else {
+                anyhow::bail!("DYN_NATS_CLUSTERS entry '{cluster}' is not name=servers");
+            };
+            let name = name.trim();
+            if name == DEFAULT_CLUSTER || config.clusters.iter().any(|(other, _)| other == name) {
+                anyhow::bail!("DYN_NATS_CLUSTERS names cluster '{name}' twice");
+            }
+            validate_nats_server(servers.trim()).map_err(|_| {
+                anyhow::anyhow!("DYN_NATS_CLUSTERS servers of '{name}' must start with nats://")
+            })?;
+            config
+                .clusters
+                .push((name.to_string(), servers.trim().to_string()));
+        }
+        for route in entries(routes) {
+            let Some((pattern, target)) = route.split_once('=') else {
+                anyhow::bail!("DYN_NATS_NAMESPACES entry '{route}' is not pattern=cluster");
+            };
+            let (cluster, subject_prefix) = match target.split_once(':') {
+                Some((cluster, prefix)) => (cluster.trim(), prefix.trim().trim_end_matches('.')),
+                None => (target.trim(), ""),
+            };
+            let known = cluster == DEFAULT_CLUSTER
+                || config.clusters.iter().any(|(name, _)| name == cluster);
+            if !known {
+                anyhow::bail!("DYN_NATS_NAMESPACES names unknown cluster '{cluster}'");
+            }
+            config.routes.push(NamespaceRoute {
+                pattern: pattern.trim().to_string(),
+                cluster: cluster.to_string(),
+                subject_prefix: match subject_prefix {
+                    "" => String::new(),
+                    prefix => format!("{prefix}."),
+                },
+            });
+        }
+        Ok(config)
+    }
+
+    /// Connect to each cluster, with `options` but for their servers
+    pub async fn connect(self, options: &ClientOptions) -> Result<NatsClusters> {
+        let mut clients = HashMap::new();
+        for (name, servers) in self.clusters {
+            let client = ClientOptions {
+                server: servers,
+                ..options.clone()
+            }
+            .connect()
+            .await
+            .with_context(|| format!("NATS cluster '{name}'"))?;
+            clients.insert(name, client);
+        }
+        Ok(NatsClusters {
+            clients,
+            routes: self.routes,
+        })
+    }
+}
+
+fn entries(list: &str) -> impl Iterator<Item = &str> {
+    list.split(';')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+}
+
+/// The clients of the other clusters, and which namespaces use them
+#[derive(Clone)]
+pub struct NatsClusters {
+    clients: HashMap<String, Client>,
+    routes: Vec<NamespaceRoute>,
+}
+
+impl NatsClusters {
+    pub fn route(&self, namespace: &str) -> Option<&NamespaceRoute> {
+        self.routes.iter().find(|route| route.matches(namespace))
+    }
+
+    /// The client of `namespace`'s cluster, None for the default one
+    pub fn client(&self, namespace: &str) -> Option<&Client> {
+        self.clients.get(&self.route(namespace)?.cluster)
+    }
+
+    /// Empty if none, see [`NamespaceRoute::subject_prefix`]
+    pub fn subject_prefix(&self, namespace: &str) -> &str {
+        self.route(namespace)
+            .map_or("", |route| route.subject_prefix.as_str())
+    }
+
+    /// Each cluster besides the default one, by name
+    pub fn clients(&self) -> impl Iterator<Item = (&str, &Client)> {
+        self.clients
+            .iter()
+            .map(|(name, client)| (name.as_str(), client))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = NatsClustersConfig::parse(
+            "billing=nats://b1:4222,nats://b2:4222; search=nats://s:4222",
+            "billing-*=billing:bu.billing.;search=search;legacy=default:old",
+        )
+        .unwrap();
+        assert_eq!(config.clusters[0].1, "nats://b1:4222,nats://b2:4222");
+        assert_eq!(config.routes.len(), 3);
+
+        let clusters = NatsClusters {
+            clients: HashMap::new(),
+            routes: config.routes,
+        };
+        assert_eq!(clusters.route("billing-eu").unwrap().cluster, "billing");
+        assert_eq!(clusters.subject_prefix("billing-eu"), "bu.billing.");
+        assert_eq!(clusters.subject_prefix("search"), "");
+        assert_eq!(clusters.subject_prefix("legacy"), "old.");
+        assert!(clusters.route("billing").is_none());
+        assert!(clusters.client("other").is_none());
+
+        for (clusters, routes) in [
+            ("billing", ""),
+            ("billing=http://b:4222", ""),
+            ("a=nats://a:4222;a=nats://b:4222", ""),
+            ("", "ns=nowhere"),
+            ("", "ns"),
+        ] {
+            assert!(
+                NatsClustersConfig::parse(clusters, routes).is_err(),
+                "{clusters} {routes}"
+            );
+        }
+    }
+}
@@ -1,4 +1,156 @@
 // SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+//! TCP response streams, and the socket handling shared by their two ends.
+//!
+//! A server started with [`server::ServerOptions::dual_stack`] (`DYN_TCP_DUAL_STACK=true` for
+//! the runtime's own) listens on every IPv4 and IPv6 address and advertises one address of each
+//! family. Clients race them Happy Eyeballs style with [`connect`], so a worker reachable over
+//! only one family still gets its responses.
+
+use std::io;
+use std::net::{Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::Duration;
+
+use futures::StreamExt;
+use futures::stream::FuturesUnordered;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpListener, TcpStream};
+
 pub use crate::pipeline::network::tcp::{client, server};
+
+/// How long an attempt gets before the next address is tried alongside it, from RFC 8305
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// The family of the last address [`connect`] reached: 0 for none yet, else 4 or 6
+static PREFERRED_FAMILY: AtomicU8 = AtomicU8::new(0);
+
+/// A listener on every IPv4 and IPv6 address of the host, on `port` or any free port for 0
+pub fn bind_dual_stack(port: u16) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_only_v6(false)?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Connect to whichever of `addresses`, all for the same server, answers first.
+///
+/// Attempts start [`CONNECTION_ATTEMPT_DELAY`] apart, or as soon as the one before fails,
+/// alternating between address families and starting with the family that worked last. The
+/// first connection made wins and the other attempts are dropped.
+pub async fn connect(addresses: &[SocketAddr]) -> io::Result<TcpStream> {
+    let mut pending = interleave(addresses, preferred_family()).into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_err = None;
+    loop {
+        if attempts.is_empty() {
+            let Some(address) = pending.next() else {
+                return Err(last_err.unwrap_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to")
+                }));
+            };
+            attempts.push(attempt(address));
+        }
+        tokio::select! {
+            Some((address, result)) = attempts.next() => match result {
+                Ok(stream) => {
+                    PREFERRED_FAMILY.store(family(&address), Ordering::Relaxed);
+                    return Ok(stream);
+                }
+                Err(err) => {
+                    tracing::debug!(%address, %err, "connection attempt failed");
+                    last_err = Some(err);
+                    if let Some(address) = pending.next() {
+                        attempts.push(attempt(address));
+                    }
+                }
+            },
+            _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY), if pending.len() > 0 => {
+                if let Some(address) = pending.next() {
+                    attempts.push(attempt(address));
+                }
+            }
+        }
+    }
+}
+
+async fn attempt(address: SocketAddr) -> (SocketAddr, io::Result<TcpStream>) {
+    (address, TcpStream::connect(address).await)
+}
+
+fn family(address: &SocketAddr) -> u8 {
+    if address.is_ipv4() { 4 } else { 6 }
+}
+
+fn preferred_family() -> Option<u8> {
+    match PREFERRED_FAMILY.load(Ordering::Relaxed) {
+        0 => None,
+        family => Some(family),
+    }
+}
+
+/// `addresses` alternating between families, starting with `preferred`, or with the family of
+/// the first address if there is no preference yet. Order within a family is kept.
+fn interleave(addresses: &[SocketAddr], preferred: Option<u8>) -> Vec<SocketAddr> {
+    let Some(first) = preferred.or_else(|| addresses.first().map(family)) else {
+        return Vec::new();
+    };
+    let (firsts, seconds): (Vec<SocketAddr>, Vec<SocketAddr>) = addresses
+        .iter()
+        .partition(|address| family(address) == first);
+    let mut ordered = Vec::with_capacity(addresses.len());
+    let (mut firsts, mut seconds) = (firsts.into_iter(), seconds.into_iter());
+    loop {
+        match (firsts.next(), seconds.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interleave_families() {
+        let a: SocketAddr = "10.0.0.1:1".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:1".parse().unwrap();
+        let v6: SocketAddr = "[fd00::1]:1".parse().unwrap();
+        let addresses = [a, b, v6];
+
+        assert_eq!(interleave(&addresses, None), vec![a, v6, b]);
+        assert_eq!(interleave(&addresses, Some(6)), vec![v6, a, b]);
+        assert!(interleave(&[], None).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connect_skips_unreachable_address() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let reachable = listener.local_addr().unwrap();
+        // Nothing listens on port 1 here, so the first attempt is refused
+        let refused: SocketAddr = "127.0.0.1:1".parse().unwrap();
+
+        let stream = connect(&[refused, reachable]).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), reachable);
+        assert!(connect(&[refused]).await.is_err());
+        assert!(connect(&[]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack_listener() {
+        // Hosts without IPv6 can't have a dual-stack socket
+        let Ok(listener) = bind_dual_stack(0) else {
+            return;
+        };
+        let port = listener.local_addr().unwrap().port();
+        let stream = connect(&[SocketAddr::from(([127, 0, 0, 1], port))])
+            .await
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap().port(), port);
+    }
+}
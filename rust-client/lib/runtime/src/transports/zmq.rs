@@ -15,6 +15,9 @@
 //! connection between the client and server per stream. The ZMQ transport will enable the
 //! equivalent of a connection pool per upstream service at the cost of needing an extra internal
 //! routing step per service endpoint.
+//!
+//! A [Client] opens with a [Client::handshake], a message with an empty request_id carrying its
+//! [ProtocolInfo], which the [Server] answers with its own.
 
 use anyhow::{Result, anyhow};
 use async_zmq::{Context, Dealer, Router, Sink, SinkExt, StreamExt};
@@ -30,6 +33,9 @@ use tokio::{
 use tokio_util::sync::CancellationToken;
 use tracing as log;
 
+use crate::pipeline::network::protocol::{self, IncompatiblePeer, Negotiated, ProtocolInfo};
+use crate::transports::accounting;
+
 // Core message types
 #[derive(Debug, Clone, Serialize, Deserialize)]
 enum ControlMessage {
@@ -45,6 +51,31 @@ enum MessageType {
     Control(ControlMessage),
 }
 
+/// The [Server]'s answer to a [Client::handshake]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct HandshakeReply {
+    protocol: ProtocolInfo,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    incompatible: Option<IncompatiblePeer>,
+}
+
+impl HandshakeReply {
+    fn answering(handshake: &[u8]) -> Self {
+        // one this build can't read is taken as no handshake at all
+        let peer = serde_json::from_slice::<ProtocolInfo>(handshake)
+            .inspect_err(|e| log::warn!("Unreadable handshake: {}", e))
+            .ok();
+        let incompatible = protocol::negotiate_with(peer.as_ref()).err();
+        if let Some(incompatible) = &incompatible {
+            log::warn!(%incompatible, "Refusing a zmq client");
+        }
+        HandshakeReply {
+            protocol: ProtocolInfo::local(),
+            incompatible,
+        }
+    }
+}
+
 enum StreamAction {
     SendEager(usize),
     SendDelayed(usize),
@@ -110,7 +141,10 @@ impl Server {
 
         // can cancel the router's event loop
         let child = cancel_token.child_token();
-        let primary_task = tokio::spawn(Self::run(router, state.clone(), child.child_token()));
+        // the router multiplexes every peer, so its bytes are only counted per address
+        let meter = accounting::meter(accounting::Transport::Zmq, address);
+        let primary_task =
+            tokio::spawn(Self::run(router, state.clone(), meter, child.child_token()));
 
         // this task captures the primary cancellation token, so if an error occurs, we can cancel the router's event loop
         // but we also propagate the error to the caller's cancellation token
@@ -145,6 +179,7 @@ impl Server {
     async fn run(
         router: Router<IntoIter<Vec<u8>>, Vec<u8>>,
         state: Arc<Mutex<RouterState>>,
+        meter: accounting::Meter,
         token: CancellationToken,
     ) -> Result<()> {
         let mut router = router;
@@ -182,6 +217,8 @@ impl Server {
                 }
             };
 
+            meter.received(frames.iter().map(|frame| frame.len()).sum());
+
             // we should have at least 3 frames
             // 0: identity
             // 1: request_id
@@ -195,6 +232,18 @@ impl Server {
                 );
             }
 
+            // an empty request_id is a client's handshake
+            if frames[1].is_empty() {
+                let reply = serde_json::to_vec(&HandshakeReply::answering(&frames[2]))?;
+                let sending = meter.sending(reply.len());
+                let reply = vec![frames[0].to_vec(), Vec::new(), reply];
+                match router.send(reply.into()).await {
+                    Ok(()) => sending.finish(),
+                    Err(e) => log::warn!("Error sending handshake reply: {}", e),
+                }
+                continue;
+            }
+
             let request_id = String::from_utf8_lossy(&frames[1]).to_string();
             let message = frames[2].to_vec();
             let message_size = message.len();
@@ -312,6 +361,29 @@ impl Client {
         &mut self.dealer
     }
 
+    /// Exchange [ProtocolInfo] with the server, before anything else is sent on the connection.
+    ///
+    /// Fails with [IncompatiblePeer] if either end is too old for the other.
+    pub async fn handshake(&mut self) -> Result<Negotiated> {
+        let local = ProtocolInfo::local();
+        let handshake = serde_json::to_vec(&local)?;
+        self.dealer.send(vec![Vec::new(), handshake].into()).await?;
+
+        let frames = self
+            .dealer
+            .next()
+            .await
+            .ok_or_else(|| anyhow!("Connection closed during the handshake"))??;
+        if frames.len() != 2 || !frames[0].is_empty() {
+            anyhow::bail!("Expected a handshake reply, got {} frames", frames.len());
+        }
+        let reply: HandshakeReply = serde_json::from_slice(&frames[1])?;
+        if let Some(incompatible) = reply.incompatible {
+            return Err(incompatible.into());
+        }
+        Ok(protocol::negotiate(&local, &reply.protocol)?)
+    }
+
     // async fn send_data(&self, data: Vec<u8>) -> Result<()> {
     //     let msg_type = MessageType::Data(data);
     //     let type_bytes = serde_json::to_vec(&msg_type)?;
@@ -368,6 +440,8 @@ mod tests {
 
         // Create client
         let mut client = Client::new(&context, address)?;
+        let negotiated = client.handshake().await?;
+        assert_eq!(negotiated.version, protocol::PROTOCOL_VERSION);
 
         client
             .dealer()
@@ -3,6 +3,7 @@
 
 pub use tokio::time::{Duration, Instant};
 
+pub mod clock;
 pub mod graceful_shutdown;
 pub mod leader_worker_barrier;
 pub mod pool;
@@ -11,4 +12,5 @@ pub mod task;
 pub mod tasks;
 pub mod typed_prefix_watcher;
 
+pub use clock::{Clock, SystemClock, TestClock};
 pub use graceful_shutdown::GracefulShutdownTracker;
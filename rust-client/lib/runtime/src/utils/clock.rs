@@ -0,0 +1,173 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Time as seen by lease deadlines, cache TTLs and restart backoff.
+//!
+//! Code that reads the time or sleeps through a [`Clock`] can be run against a [`TestClock`],
+//! which only moves when told to. Unlike tokio's paused clock it keeps a wall clock of its own,
+//! so a process suspension, where the monotonic clock stands still while the wall clock moves
+//! on, can be staged as well.
+//!
+//! [`SystemClock`] is tokio's clock, so code using it still follows virtual time under
+//! `#[tokio::test(start_paused = true)]`.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::future::BoxFuture;
+use parking_lot::Mutex;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+pub trait Clock: Send + Sync + fmt::Debug {
+    /// Monotonic, except across a suspension on some platforms
+    fn now(&self) -> Instant;
+
+    fn system_time(&self) -> SystemTime;
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()>;
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        self.sleep_until(self.now() + duration)
+    }
+
+    /// The wall clock in milliseconds since the Unix epoch, as records in the store are dated
+    fn unix_millis(&self) -> u64 {
+        self.system_time()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64
+    }
+}
+
+/// The [`SystemClock`], shared
+pub fn system_clock() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+/// tokio's clock and the system's wall clock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep_until(deadline))
+    }
+}
+
+/// A clock that stands still until [advanced](TestClock::advance). Cheap to clone, clones
+/// share their time.
+#[derive(Clone)]
+pub struct TestClock {
+    base: Instant,
+    /// Monotonic time since `base`. Sleepers wait for it to pass their deadline.
+    elapsed: Arc<watch::Sender<Duration>>,
+    wall: Arc<Mutex<SystemTime>>,
+}
+
+impl TestClock {
+    /// Starts at the current time
+    pub fn new() -> Self {
+        let (elapsed, _) = watch::channel(Duration::ZERO);
+        TestClock {
+            base: Instant::now(),
+            elapsed: Arc::new(elapsed),
+            wall: Arc::new(Mutex::new(SystemTime::now())),
+        }
+    }
+
+    /// Move both clocks forward, waking the sleepers whose deadline has come. They run once
+    /// the caller yields.
+    pub fn advance(&self, duration: Duration) {
+        *self.wall.lock() += duration;
+        self.elapsed.send_modify(|elapsed| *elapsed += duration);
+    }
+
+    /// Move only the wall clock forward, as a suspended process sees it on Linux, where the
+    /// monotonic clock stops during suspension
+    pub fn suspend(&self, duration: Duration) {
+        *self.wall.lock() += duration;
+    }
+
+    /// Step the wall clock, as NTP or an operator may
+    pub fn set_system_time(&self, time: SystemTime) {
+        *self.wall.lock() = time;
+    }
+}
+
+impl Default for TestClock {
+    fn default() -> Self {
+        TestClock::new()
+    }
+}
+
+impl Clock for TestClock {
+    fn now(&self) -> Instant {
+        self.base + *self.elapsed.borrow()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        *self.wall.lock()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        let base = self.base;
+        let mut elapsed = self.elapsed.subscribe();
+        Box::pin(async move {
+            // Fails only once every clone of the clock is gone, and then time never moves
+            if elapsed.wait_for(|e| base + *e >= deadline).await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        })
+    }
+}
+
+impl fmt::Debug for TestClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TestClock")
+            .field("elapsed", &*self.elapsed.borrow())
+            .field("wall", &*self.wall.lock())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sleepers_wake_on_advance() {
+        let clock = TestClock::new();
+        let start = clock.now();
+        let sleeper = tokio::spawn(clock.sleep(Duration::from_secs(10)));
+
+        clock.advance(Duration::from_secs(9));
+        tokio::task::yield_now().await;
+        assert!(!sleeper.is_finished());
+
+        clock.advance(Duration::from_secs(1));
+        sleeper.await.unwrap();
+        assert_eq!(clock.now() - start, Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_suspend_moves_only_wall_clock() {
+        let clock = TestClock::new();
+        let (now, wall) = (clock.now(), clock.system_time());
+        clock.suspend(Duration::from_secs(60));
+        assert_eq!(clock.now(), now);
+        assert_eq!(
+            clock.system_time().duration_since(wall).unwrap(),
+            Duration::from_secs(60)
+        );
+    }
+}
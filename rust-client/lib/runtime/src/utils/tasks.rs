@@ -2,4 +2,16 @@
 // SPDX-License-Identifier: Apache-2.0
 
 pub mod critical;
+pub mod supervisor;
 pub mod tracker;
+
+/// The message a panic was raised with, if it was a string as from `panic!`
+pub(crate) fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    if let Some(s) = panic.downcast_ref::<String>() {
+        s.clone()
+    } else if let Some(s) = panic.downcast_ref::<&str>() {
+        s.to_string()
+    } else {
+        "Unknown panic".to_string()
+    }
+}
@@ -0,0 +1,451 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Restart long-lived background tasks when they fail, instead of losing them silently.
+//!
+//! A [`Supervisor`] runs each task it is given until the task returns `Ok`, restarting it with
+//! exponential backoff when it returns an error or panics. Supervisors form a tree: the
+//! [`Runtime`](crate::Runtime) has the root, and [`Supervisor::child`] groups the tasks of one
+//! subsystem under a name. [`Supervisor::snapshot`] is the tree with the state and restart count
+//! of every task, which the system status server serves at `/diagnostics/tasks`.
+//!
+//! ```ignore
+//! let supervisor = runtime.supervisor().child("health_check");
+//! supervisor.spawn("ns/backend/generate", RestartPolicy::default(), move || {
+//!     let manager = manager.clone();
+//!     async move { manager.run().await }
+//! });
+//! ```
+
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::FutureExt;
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::Result;
+use crate::utils::clock::{Clock, system_clock};
+
+#[derive(Debug, Clone)]
+pub struct RestartPolicy {
+    /// Wait before the first restart, doubled for each one after
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Give up after this many restarts in a row, never if None. A run that lasts
+    /// `max_backoff` ends the row.
+    pub max_restarts: Option<u32>,
+    /// On giving up, cancel the supervisor's token, stopping every task under it
+    pub escalate: bool,
+    /// When the supervisor's token is cancelled, let a run in progress end by itself instead of
+    /// dropping it, for tasks that flush on a token of their own. None is restarted after.
+    pub run_to_completion: bool,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            max_restarts: None,
+            escalate: false,
+            run_to_completion: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskState {
+    Running,
+    /// Failed, waiting to be restarted
+    BackingOff,
+    /// Failed more times in a row than its policy allows
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskSnapshot {
+    pub name: String,
+    pub state: TaskState,
+    /// Since the task was spawned
+    pub restarts: u32,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SupervisorSnapshot {
+    pub name: String,
+    pub tasks: Vec<TaskSnapshot>,
+    pub children: Vec<SupervisorSnapshot>,
+}
+
+struct Node {
+    name: String,
+    /// Running or failed. Tasks that ended are removed.
+    tasks: Mutex<Vec<Arc<Mutex<TaskSnapshot>>>>,
+    children: Mutex<Vec<Arc<Node>>>,
+}
+
+#[derive(Clone)]
+pub struct Supervisor {
+    node: Arc<Node>,
+    token: CancellationToken,
+    /// Where tasks run, the current runtime if None
+    handle: Option<Handle>,
+    /// Times backoff and how long a task ran
+    clock: Arc<dyn Clock>,
+}
+
+impl Supervisor {
+    /// A root supervisor. Its tasks are dropped when `token` is cancelled.
+    pub fn new(name: impl Into<String>, token: CancellationToken, handle: Option<Handle>) -> Self {
+        Supervisor {
+            node: Arc::new(Node {
+                name: name.into(),
+                tasks: Mutex::new(Vec::new()),
+                children: Mutex::new(Vec::new()),
+            }),
+            token,
+            handle,
+            clock: system_clock(),
+        }
+    }
+
+    /// Time backoff with `clock` instead of tokio's, here and in children made from now on
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Run the tasks spawned from here on on `handle`, as those of the control plane
+    pub fn with_handle(mut self, handle: Handle) -> Self {
+        self.handle = Some(handle);
+        self
+    }
+
+    /// The child supervisor called `name`, created if there is none. Cancelling its token, as
+    /// escalation does, leaves this supervisor's other tasks running.
+    pub fn child(&self, name: &str) -> Supervisor {
+        let mut children = self.node.children.lock();
+        let node = match children.iter().find(|c| c.name == name) {
+            Some(node) => node.clone(),
+            None => {
+                let node = Arc::new(Node {
+                    name: name.to_string(),
+                    tasks: Mutex::new(Vec::new()),
+                    children: Mutex::new(Vec::new()),
+                });
+                children.push(node.clone());
+                node
+            }
+        };
+        Supervisor {
+            node,
+            token: self.token.child_token(),
+            handle: self.handle.clone(),
+            clock: self.clock.clone(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.node.name
+    }
+
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    /// Run the future `task` makes until one returns `Ok`, making another after each error or
+    /// panic. Aborting the returned handle stops the task for good.
+    pub fn spawn<F, Fut>(
+        &self,
+        name: impl Into<String>,
+        policy: RestartPolicy,
+        task: F,
+    ) -> JoinHandle<()>
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let status = Arc::new(Mutex::new(TaskSnapshot {
+            name: name.into(),
+            state: TaskState::Running,
+            restarts: 0,
+            last_error: None,
+        }));
+        self.node.tasks.lock().push(status.clone());
+        let supervised = supervise(
+            self.node.clone(),
+            status,
+            policy,
+            self.token.clone(),
+            self.clock.clone(),
+            task,
+        );
+        match &self.handle {
+            Some(handle) => handle.spawn(supervised),
+            None => tokio::spawn(supervised),
+        }
+    }
+
+    pub fn snapshot(&self) -> SupervisorSnapshot {
+        self.node.snapshot()
+    }
+}
+
+impl std::fmt::Debug for Supervisor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Supervisor")
+            .field("name", &self.node.name)
+            .field("tasks", &self.node.tasks.lock().len())
+            .finish()
+    }
+}
+
+impl Node {
+    fn snapshot(&self) -> SupervisorSnapshot {
+        SupervisorSnapshot {
+            name: self.name.clone(),
+            tasks: self.tasks.lock().iter().map(|t| t.lock().clone()).collect(),
+            children: self.children.lock().iter().map(|c| c.snapshot()).collect(),
+        }
+    }
+
+    fn remove(&self, status: &Arc<Mutex<TaskSnapshot>>) {
+        self.tasks.lock().retain(|t| !Arc::ptr_eq(t, status));
+    }
+}
+
+async fn supervise<F, Fut>(
+    node: Arc<Node>,
+    status: Arc<Mutex<TaskSnapshot>>,
+    policy: RestartPolicy,
+    token: CancellationToken,
+    clock: Arc<dyn Clock>,
+    mut task: F,
+) where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    // Removes the task from the tree however this ends, including by abort
+    struct Registered(Arc<Node>, Arc<Mutex<TaskSnapshot>>);
+    impl Drop for Registered {
+        fn drop(&mut self) {
+            if self.1.lock().state != TaskState::Failed {
+                self.0.remove(&self.1);
+            }
+        }
+    }
+    let _registered = Registered(node, status.clone());
+
+    let name = status.lock().name.clone();
+    let mut backoff = policy.initial_backoff;
+    let mut in_a_row = 0;
+    loop {
+        status.lock().state = TaskState::Running;
+        let started = clock.now();
+        // Made inside, so that a panic making the future counts as the task's too
+        let run = AssertUnwindSafe(async { task().await }).catch_unwind();
+        let outcome = if policy.run_to_completion {
+            if token.is_cancelled() {
+                return;
+            }
+            run.await
+        } else {
+            tokio::select! {
+                _ = token.cancelled() => return,
+                outcome = run => outcome,
+            }
+        };
+        let error = match outcome {
+            Ok(Ok(())) => return,
+            Ok(Err(err)) => format!("{err:#}"),
+            Err(panic) => format!("panicked: {}", super::panic_message(&*panic)),
+        };
+
+        if clock.now().duration_since(started) >= policy.max_backoff {
+            in_a_row = 0;
+            backoff = policy.initial_backoff;
+        }
+        in_a_row += 1;
+        if policy.max_restarts.is_some_and(|max| in_a_row > max) {
+            tracing::error!(task = %name, %error, "Supervised task keeps failing, giving up");
+            {
+                let mut status = status.lock();
+                status.state = TaskState::Failed;
+                status.last_error = Some(error);
+            }
+            if policy.escalate {
+                token.cancel();
+            }
+            return;
+        }
+        tracing::warn!(task = %name, %error, ?backoff, "Supervised task failed, restarting");
+        {
+            let mut status = status.lock();
+            status.state = TaskState::BackingOff;
+            status.last_error = Some(error);
+        }
+        tokio::select! {
+            _ = token.cancelled() => return,
+            _ = clock.sleep(backoff) => {}
+        }
+        backoff = (backoff * 2).min(policy.max_backoff);
+        status.lock().restarts += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error;
+    use crate::utils::clock::TestClock;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use tokio::time::Instant;
+
+    /// A task that fails its first `failures` runs, and counts its runs
+    fn flaky(
+        failures: u32,
+    ) -> (
+        impl FnMut() -> futures::future::Ready<Result<()>>,
+        Arc<AtomicU32>,
+    ) {
+        let runs = Arc::new(AtomicU32::new(0));
+        let counted = runs.clone();
+        let task = move || {
+            let run = counted.fetch_add(1, Ordering::SeqCst);
+            futures::future::ready(if run < failures {
+                Err(error!("run {run} failed"))
+            } else {
+                Ok(())
+            })
+        };
+        (task, runs)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_restarts_with_backoff() {
+        let supervisor = Supervisor::new("root", CancellationToken::new(), None);
+        let (task, runs) = flaky(3);
+        let start = Instant::now();
+        supervisor
+            .spawn("flaky", RestartPolicy::default(), task)
+            .await
+            .unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 4);
+        // 100ms + 200ms + 400ms
+        assert_eq!(start.elapsed(), Duration::from_millis(700));
+        // Done, so gone from the tree
+        assert!(supervisor.snapshot().tasks.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_restarts_panicked_task() {
+        let supervisor = Supervisor::new("root", CancellationToken::new(), None);
+        let runs = Arc::new(AtomicU32::new(0));
+        let counted = runs.clone();
+        let handle = supervisor.spawn("panicky", RestartPolicy::default(), move || {
+            let run = counted.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if run == 0 {
+                    panic!("first run");
+                }
+                Ok(())
+            }
+        });
+        handle.await.unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_gives_up_and_escalates() {
+        let token = CancellationToken::new();
+        let supervisor = Supervisor::new("root", token.clone(), None);
+        let child = supervisor.child("etcd");
+        let policy = RestartPolicy {
+            max_restarts: Some(2),
+            escalate: true,
+            ..Default::default()
+        };
+        let (task, runs) = flaky(u32::MAX);
+        child.spawn("watch", policy, task).await.unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        assert!(child.token().is_cancelled());
+        // Only the child's subtree
+        assert!(!token.is_cancelled());
+
+        let snapshot = supervisor.snapshot();
+        assert_eq!(snapshot.children[0].name, "etcd");
+        let failed = &snapshot.children[0].tasks[0];
+        assert_eq!(failed.state, TaskState::Failed);
+        assert_eq!(failed.restarts, 2);
+        assert_eq!(failed.last_error.as_deref(), Some("run 2 failed"));
+    }
+
+    #[tokio::test]
+    async fn test_backoff_follows_clock() {
+        let clock = TestClock::new();
+        let supervisor = Supervisor::new("root", CancellationToken::new(), None)
+            .with_clock(Arc::new(clock.clone()));
+        let (task, runs) = flaky(1);
+        let handle = supervisor.spawn("flaky", RestartPolicy::default(), task);
+        tokio::task::yield_now().await;
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert_eq!(supervisor.snapshot().tasks[0].state, TaskState::BackingOff);
+
+        clock.advance(Duration::from_millis(100));
+        handle.await.unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_snapshot_running_and_cancel() {
+        let token = CancellationToken::new();
+        let supervisor = Supervisor::new("root", token.clone(), None);
+        let handle = supervisor.spawn("forever", RestartPolicy::default(), || {
+            std::future::pending::<Result<()>>()
+        });
+        tokio::task::yield_now().await;
+        let snapshot = supervisor.snapshot();
+        assert_eq!(snapshot.tasks[0].name, "forever");
+        assert_eq!(snapshot.tasks[0].state, TaskState::Running);
+
+        token.cancel();
+        handle.await.unwrap();
+        assert!(supervisor.snapshot().tasks.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_run_to_completion() {
+        let token = CancellationToken::new();
+        let supervisor = Supervisor::new("root", token.clone(), None);
+        let policy = RestartPolicy {
+            run_to_completion: true,
+            ..Default::default()
+        };
+        let flushed = Arc::new(AtomicU32::new(0));
+        let counted = flushed.clone();
+        let own_token = token.clone();
+        let handle = supervisor.spawn("publisher", policy, move || {
+            let counted = counted.clone();
+            let own_token = own_token.clone();
+            async move {
+                own_token.cancelled().await;
+                // A last publish, after the token went
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                counted.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            }
+        });
+        tokio::task::yield_now().await;
+        token.cancel();
+        handle.await.unwrap();
+        assert_eq!(flushed.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,68 @@
+use dynamo_runtime::Runtime;
+use dynamo_runtime::distributed::StoreUrl;
+use dynamo_runtime::storage::key_value_store::bench::{self, BenchConfig};
+
+use dynamo_runtime::debug_println;
+
+/// `bench [--store URL]... [--keys N] [--value-size BYTES]`
+///
+/// Runs the store benchmark workload once per store and prints throughput and latency
+/// percentiles. Benchmarks the in-memory store if no `--store` is given.
+pub fn run(runtime: Runtime, args: Vec<String>) -> anyhow::Result<()> {
+    let mut urls = Vec::new();
+    let mut config = BenchConfig::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--store" => {
+                urls.push(
+                    args.next()
+                        .ok_or_else(|| anyhow::anyhow!("--store needs a value"))?,
+                );
+            }
+            "--keys" => {
+                let v = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--keys needs a value"))?;
+                config.keys = v
+                    .parse()
+                    .map_err(|e| anyhow::anyhow!("Invalid --keys '{}': {}", v, e))?;
+            }
+            "--value-size" => {
+                let v = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--value-size needs a value"))?;
+                config.value_size = v
+                    .parse()
+                    .map_err(|e| anyhow::anyhow!("Invalid --value-size '{}': {}", v, e))?;
+            }
+            other => anyhow::bail!(
+                "Unknown option '{}'. Usage: bench [--store URL]... [--keys N] [--value-size N]",
+                other
+            ),
+        }
+    }
+    if urls.is_empty() {
+        urls.push("mem://".to_string());
+    }
+
+    runtime.primary().block_on(async {
+        for url in &urls {
+            let store_url: StoreUrl = url.parse()?;
+            let store = store_url.connect(runtime.clone()).await?;
+            debug_println!(
+                WHITE,
+                "[BENCH]",
+                RESET,
+                "{}: {} keys of {} bytes",
+                url,
+                config.keys,
+                config.value_size
+            );
+            for stats in bench::run(&store, &config).await? {
+                debug_println!(WHITE, "[BENCH]", RESET, "  {}", stats);
+            }
+        }
+        Ok::<(), anyhow::Error>(())
+    })
+}
@@ -0,0 +1,83 @@
+use dynamo_runtime::Runtime;
+use dynamo_runtime::distributed::StoreUrl;
+use dynamo_runtime::storage::encoding::{BucketVersion, JSON_VERSION, PROTOBUF_VERSION};
+use dynamo_runtime::storage::layout::KeyLayout;
+
+use dynamo_runtime::debug_println;
+
+const USAGE: &str = "\
+Usage: encoding status [--store URL]
+       encoding set <BUCKET> <VERSION> [--store URL]";
+
+/// `encoding status [--store URL]`
+/// `encoding set <BUCKET> <VERSION> [--store URL]`
+///
+/// Shows which encoding each discovery bucket's records are written in, or sets a bucket's
+/// version: 1 for JSON, 2 for protobuf. Set 2 only once every process reading the bucket
+/// decodes protobuf; going back to 1 is always safe, as readers take either. The store is
+/// `--store`, or `DISCOVERY_URL` if not given.
+pub fn run(runtime: Runtime, args: Vec<String>) -> anyhow::Result<()> {
+    let mut positional = Vec::new();
+    let mut store_url = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--store" => {
+                let v = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--store needs a value"))?;
+                store_url = Some(v.parse::<StoreUrl>()?);
+            }
+            _ => positional.push(arg),
+        }
+    }
+    let store_url = match store_url {
+        Some(url) => url,
+        None => StoreUrl::from_env()?
+            .ok_or_else(|| anyhow::anyhow!("Give the store with --store or DISCOVERY_URL"))?,
+    };
+
+    runtime.primary().block_on(async {
+        // Without a lease, so the versions don't go with this process
+        let store = store_url.connect(runtime.clone()).await?;
+        match positional.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+            ["status"] => {
+                for layout in [KeyLayout::V1, KeyLayout::V2] {
+                    for bucket in layout.buckets() {
+                        let version = BucketVersion::fetch(&store, bucket).await?;
+                        debug_println!(
+                            WHITE,
+                            "[ENCODING]",
+                            RESET,
+                            "{}  version {}  {:?}",
+                            bucket,
+                            version.version,
+                            version.encoding()
+                        );
+                    }
+                }
+            }
+            ["set", bucket, version] => {
+                let version: u32 = version
+                    .parse()
+                    .map_err(|e| anyhow::anyhow!("Invalid version '{}': {}", version, e))?;
+                if !(JSON_VERSION..=PROTOBUF_VERSION).contains(&version) {
+                    anyhow::bail!("Versions are {} to {}", JSON_VERSION, PROTOBUF_VERSION);
+                }
+                BucketVersion::set(&store, bucket, version).await?;
+                let encoding = BucketVersion { version }.encoding();
+                debug_println!(
+                    WHITE,
+                    "[ENCODING]",
+                    GREEN,
+                    "✅ {} is version {}, {:?}",
+                    bucket,
+                    version,
+                    encoding
+                );
+            }
+            _ => anyhow::bail!(USAGE),
+        }
+        Ok::<(), anyhow::Error>(())
+    })
+}
@@ -0,0 +1,146 @@
+use std::time::Duration;
+
+use dynamo_runtime::Runtime;
+use dynamo_runtime::distributed::StoreUrl;
+use dynamo_runtime::storage::layout::{self, KeyLayout, LayoutMarker, MigrateConfig};
+use dynamo_runtime::transports::etcd::{Client, ClientOptions};
+
+use dynamo_runtime::debug_println;
+
+const USAGE: &str = "\
+Usage: layout status [--store URL]
+       layout migrate <VERSION> [--settle SECS] [--passes N] [--store URL] [--dry-run]
+       layout finish [--store URL] [--dry-run]
+       layout abort [--store URL] [--dry-run]";
+
+/// `layout status [--store URL]`
+/// `layout migrate <VERSION> [--settle SECS] [--passes N] [--store URL] [--dry-run]`
+/// `layout finish [--store URL] [--dry-run]`
+/// `layout abort [--store URL] [--dry-run]`
+///
+/// Shows the key layout discovery records are kept in, or moves them to another one while the
+/// cluster runs: `migrate` double-writes, copies, verifies and cuts over; `finish` deletes the
+/// old layout once everything started before the cutover has restarted; `abort` goes back
+/// before the cutover. The store is `--store`, or `DISCOVERY_URL` if not given, and must be
+/// etcd.
+///
+/// With `--dry-run`, nothing is written and nobody waited for: the marker changes and the
+/// records that would be copied, repaired or deleted are printed, as the cluster is now.
+pub fn run(runtime: Runtime, args: Vec<String>) -> anyhow::Result<()> {
+    let mut positional = Vec::new();
+    let mut config = MigrateConfig::default();
+    let mut store_url = None;
+    let mut dry_run = false;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--settle" => {
+                let v = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--settle needs a value"))?;
+                let secs = v
+                    .parse()
+                    .map_err(|e| anyhow::anyhow!("Invalid --settle '{}': {}", v, e))?;
+                config.settle = Duration::from_secs(secs);
+            }
+            "--passes" => {
+                let v = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--passes needs a value"))?;
+                config.verify_passes = v
+                    .parse()
+                    .map_err(|e| anyhow::anyhow!("Invalid --passes '{}': {}", v, e))?;
+            }
+            "--store" => {
+                let v = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--store needs a value"))?;
+                store_url = Some(v.parse::<StoreUrl>()?);
+            }
+            "--dry-run" => dry_run = true,
+            _ => positional.push(arg),
+        }
+    }
+    let store_url = match store_url {
+        Some(url) => url,
+        None => StoreUrl::from_env()?
+            .ok_or_else(|| anyhow::anyhow!("Give the store with --store or DISCOVERY_URL"))?,
+    };
+    let hosts = match store_url {
+        StoreUrl::Etcd(hosts) => hosts,
+        other => anyhow::bail!("Only etcd is migrated between key layouts, not {:?}", other),
+    };
+
+    runtime.primary().block_on(async {
+        // Without a lease, so the marker and the copies don't go with this process
+        let options = ClientOptions {
+            etcd_url: hosts,
+            attach_lease: false,
+            dns: None,
+            ..Default::default()
+        };
+        let client = Client::new(options, runtime.clone()).await?;
+        if dry_run {
+            let writes = match positional.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+                ["migrate", version] => layout::plan_migration(&client, version.parse()?).await?,
+                ["finish"] => layout::plan_finish(&client).await?,
+                ["abort"] => layout::plan_abort(&client).await?,
+                _ => anyhow::bail!(USAGE),
+            };
+            for write in &writes {
+                debug_println!(WHITE, "[DRY RUN]", RESET, "  {}", write);
+            }
+            debug_println!(WHITE, "[DRY RUN]", YELLOW, "Nothing was written");
+            return Ok(());
+        }
+        match positional.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+            ["status"] => print_marker(&LayoutMarker::load(&client).await?),
+            ["migrate", version] => {
+                let to: KeyLayout = version.parse()?;
+                debug_println!(WHITE, "[LAYOUT]", RESET, "Migrating to {}, settling for {:?}",
+                    to, config.settle);
+                let report = layout::migrate(&client, to, &config).await?;
+                debug_println!(WHITE, "[LAYOUT]", GREEN,
+                    "✅ Cut over to {}: {} records, {} copied, {} repaired, {} removed in {} passes",
+                    to, report.records, report.copied, report.repaired, report.removed,
+                    report.verify_passes);
+                debug_println!(WHITE, "[LAYOUT]", YELLOW,
+                    "Run 'layout finish' once every process started before now has restarted");
+            }
+            ["finish"] => {
+                let deleted = layout::finish_migration(&client).await?;
+                debug_println!(WHITE, "[LAYOUT]", GREEN, "✅ Finished, {} old records deleted",
+                    deleted);
+            }
+            ["abort"] => {
+                let deleted = layout::abort_migration(&client).await?;
+                debug_println!(WHITE, "[LAYOUT]", GREEN, "✅ Aborted, {} copied records deleted",
+                    deleted);
+            }
+            _ => anyhow::bail!(USAGE),
+        }
+        Ok::<(), anyhow::Error>(())
+    })
+}
+
+fn print_marker(marker: &LayoutMarker) {
+    debug_println!(
+        WHITE,
+        "[LAYOUT]",
+        RESET,
+        "Reading {}, phase {:?}",
+        marker.layout,
+        marker.phase
+    );
+    for layout in marker.writes() {
+        debug_println!(
+            WHITE,
+            "[LAYOUT]",
+            RESET,
+            "    writing {}: {}, {}",
+            layout,
+            layout.instance_bucket(),
+            layout.card_bucket()
+        );
+    }
+}
@@ -0,0 +1,107 @@
+use dynamo_runtime::Runtime;
+use dynamo_runtime::distributed::StoreUrl;
+use dynamo_runtime::transports::etcd::{Client, ClientOptions, LeaseInfo};
+
+use dynamo_runtime::debug_println;
+
+const USAGE: &str = "\
+Usage: leases list [--keys] [--store URL]
+       leases revoke <ID> [--store URL]";
+
+/// `leases list [--keys] [--store URL]`
+/// `leases revoke <ID> [--store URL]`
+///
+/// Lists every lease in etcd with its TTL, owner worker and the instances registered under it,
+/// or revokes one, deleting the keys attached to it. IDs are in hex, as in instance paths and
+/// etcdctl. The store is `--store`, or `DISCOVERY_URL` if not given, and must be etcd.
+pub fn run(runtime: Runtime, args: Vec<String>) -> anyhow::Result<()> {
+    let mut positional = Vec::new();
+    let mut show_keys = false;
+    let mut store_url = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--keys" => show_keys = true,
+            "--store" => {
+                let v = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--store needs a value"))?;
+                store_url = Some(v.parse::<StoreUrl>()?);
+            }
+            _ => positional.push(arg),
+        }
+    }
+    let store_url = match store_url {
+        Some(url) => url,
+        None => StoreUrl::from_env()?
+            .ok_or_else(|| anyhow::anyhow!("Give the store with --store or DISCOVERY_URL"))?,
+    };
+    let hosts = match store_url {
+        StoreUrl::Etcd(hosts) => hosts,
+        other => anyhow::bail!("Leases are only in etcd, not {:?}", other),
+    };
+
+    runtime.primary().block_on(async {
+        // Without a lease of its own, so it lists only the cluster's
+        let options = ClientOptions {
+            etcd_url: hosts,
+            attach_lease: false,
+            dns: None,
+            ..Default::default()
+        };
+        let client = Client::new(options, runtime.clone()).await?;
+        match positional.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+            ["list"] => {
+                let leases = client.list_leases().await?;
+                for lease in &leases {
+                    print_lease(lease, show_keys);
+                }
+                debug_println!(WHITE, "[LEASES]", RESET, "{} leases", leases.len());
+            }
+            ["revoke", id] => {
+                let id = u64::from_str_radix(id.trim_start_matches("0x"), 16)
+                    .map_err(|e| anyhow::anyhow!("Invalid lease ID '{}': {}", id, e))?;
+                let Some(lease) = client.lease_info(id).await? else {
+                    anyhow::bail!("Lease {:x} does not exist, or expired", id);
+                };
+                print_lease(&lease, true);
+                client.revoke_lease(id).await?;
+                debug_println!(
+                    WHITE,
+                    "[LEASES]",
+                    GREEN,
+                    "✅ Revoked {:x}, {} keys deleted",
+                    id,
+                    lease.keys.len()
+                );
+            }
+            _ => anyhow::bail!(USAGE),
+        }
+        Ok::<(), anyhow::Error>(())
+    })
+}
+
+fn print_lease(lease: &LeaseInfo, show_keys: bool) {
+    let worker = lease
+        .worker_id()
+        .map_or_else(|| "-".to_string(), |id| id.to_string());
+    debug_println!(
+        WHITE,
+        "[LEASES]",
+        RESET,
+        "{:x}  ttl {}s/{}s  {} keys  worker {}",
+        lease.id,
+        lease.ttl,
+        lease.granted_ttl,
+        lease.keys.len(),
+        worker
+    );
+    for instance in lease.instances() {
+        debug_println!(WHITE, "[LEASES]", RESET, "    instance {}", instance);
+    }
+    if show_keys {
+        for key in &lease.keys {
+            debug_println!(WHITE, "[LEASES]", FAINT, "    {}", key);
+        }
+    }
+}
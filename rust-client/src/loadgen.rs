@@ -0,0 +1,116 @@
+use dynamo_runtime::DistributedRuntime;
+use dynamo_runtime::Runtime;
+use dynamo_runtime::loadgen::{self, LoadConfig, PayloadTemplate, Profile};
+use dynamo_runtime::pipeline::{PushRouter, RouterMode};
+use dynamo_runtime::protocols::EndpointId;
+
+use dynamo_runtime::debug_println;
+
+const USAGE: &str = "Usage: loadgen <ENDPOINT> [--profile P] [--payload JSON|@FILE] [--instance ID] [--max-in-flight N]";
+
+/// `loadgen <ENDPOINT> [--profile P] [--payload JSON|@FILE] [--instance ID] [--max-in-flight N]`
+///
+/// Sends requests on the schedule of the profile, `1@10s` by default, and prints latency
+/// percentiles and the seconds in which requests failed. Exits non-zero if any failed.
+pub fn run(runtime: Runtime, args: Vec<String>) -> anyhow::Result<()> {
+    let mut positional = Vec::new();
+    let mut profile = "1@10s".to_string();
+    let mut template = PayloadTemplate::default();
+    let mut instance_id = None;
+    let mut max_in_flight = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--profile" => {
+                profile = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--profile needs a value"))?;
+            }
+            "--payload" => {
+                let v = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--payload needs a value"))?;
+                let json = match v.strip_prefix('@') {
+                    Some(path) => std::fs::read_to_string(path)
+                        .map_err(|e| anyhow::anyhow!("Unable to read payload {}: {}", path, e))?,
+                    None => v,
+                };
+                template = json.parse()?;
+            }
+            "--instance" => {
+                let v = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--instance needs a value"))?;
+                instance_id = Some(
+                    v.parse()
+                        .map_err(|e| anyhow::anyhow!("Invalid --instance '{}': {}", v, e))?,
+                );
+            }
+            "--max-in-flight" => {
+                let v = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--max-in-flight needs a value"))?;
+                max_in_flight = Some(
+                    v.parse()
+                        .map_err(|e| anyhow::anyhow!("Invalid --max-in-flight '{}': {}", v, e))?,
+                );
+            }
+            _ => positional.push(arg),
+        }
+    }
+    let [endpoint] = <[String; 1]>::try_from(positional).map_err(|_| anyhow::anyhow!(USAGE))?;
+    let endpoint_id: EndpointId = endpoint.as_str().into();
+    let profile: Profile = profile.parse()?;
+    let mut config = LoadConfig::new(profile);
+    config.template = template;
+    config.instance_id = instance_id;
+    if let Some(max_in_flight) = max_in_flight {
+        config.max_in_flight = max_in_flight;
+    }
+
+    runtime.primary().block_on(async {
+        let drt = DistributedRuntime::from_settings(runtime.clone()).await?;
+        let client = drt
+            .namespace(&endpoint_id.namespace)?
+            .component(&endpoint_id.component)?
+            .endpoint(&endpoint_id.name)
+            .client()
+            .await?;
+        let instances = client.wait_for_instances().await?;
+        debug_println!(
+            WHITE,
+            "[LOADGEN]",
+            RESET,
+            "Loading {} ({} instances) for {:?}",
+            endpoint,
+            instances.len(),
+            config.profile.duration()
+        );
+
+        let router = PushRouter::from_client(client, RouterMode::RoundRobin).await?;
+        let report = loadgen::run(&router, &config).await?;
+
+        for line in report.to_string().lines() {
+            debug_println!(WHITE, "[LOADGEN]", RESET, "{}", line);
+        }
+        for (second, failed) in report.failures_by_second() {
+            debug_println!(
+                WHITE,
+                "[LOADGEN]",
+                RED,
+                "❌ {} failed at {}s",
+                failed,
+                second
+            );
+        }
+
+        if report.failed() > 0 {
+            anyhow::bail!(
+                "{} of {} requests failed",
+                report.failed(),
+                report.samples.len()
+            );
+        }
+        Ok::<(), anyhow::Error>(())
+    })
+}
@@ -1,89 +1,111 @@
-use dynamo_runtime::transports::etcd::{Client, ClientOptions};
 use dynamo_runtime::Runtime;
-use std::time::Instant;
-use tokio::time::{sleep, Duration};
 
-// Import the debug macro
-use dynamo_runtime::debug_println;
+mod bench;
+mod encoding;
+mod layout;
+mod leases;
+mod loadgen;
+mod monitor;
+mod namespace;
+mod replay;
+mod scenario;
+mod tail;
 
-/// Format elapsed time in a human-friendly way
-fn format_elapsed(elapsed: std::time::Duration) -> String {
-    let total_secs = elapsed.as_secs();
-    let hours = total_secs / 3600;
-    let mins = (total_secs % 3600) / 60;
-    let secs = total_secs % 60;
-    
-    match (hours, mins, secs) {
-        (0, 0, s) => format!("{}s", s),
-        (0, m, s) => format!("{}min {}s", m, s),
-        (h, m, s) => format!("{}hr {}min {}s", h, m, s),
-    }
-}
+const USAGE: &str = "\
+Usage: rust-client [COMMAND]
+
+Commands:
+  monitor                         Monitor the primary lease (default)
+  replay <FILE> <ENDPOINT> [OPTS] Replay captured requests against an endpoint
+                                  --rate <N>       requests per second (default 1)
+                                  --instance <ID>  send every request to one instance
+  bench [OPTS]                    Measure store throughput and latency percentiles
+                                  --store <URL>          store to benchmark, repeatable
+                                                         (default mem://)
+                                  --keys <N>             keys per run (default 1000)
+                                  --value-size <BYTES>   value size (default 256)
+  loadgen <ENDPOINT> [OPTS]       Send open-loop load to an endpoint, report latencies
+                                  --profile <P>          rates over time, such as
+                                                         10@30s,10-200@1m (default 1@10s)
+                                  --payload <JSON|@FILE> request template, {{seq}} and
+                                                         {{uuid}} are filled in
+                                  --instance <ID>        send every request to one instance
+                                  --max-in-flight <N>    drop requests past N in flight
+                                                         (default 10000)
+  scenario <FILE>                 Run a scripted failure scenario (TOML, or JSON), check
+                                  that each action recovered within its limit
+                                  --etcd <BINARY>  compare etcd releases instead, one
+                                                   run per binary, repeatable
+  namespace retire <NAME> [OPTS]  Close a namespace, let routers drain, delete its keys
+                                  and write an audit record
+                                  --drain <SECS>   wait before deleting (default 60)
+                                  --by <WHO>       recorded as who retired it
+                                                   (default DYN_IDENTITY)
+                                  --store <URL>    store to retire it in
+                                                   (default DISCOVERY_URL)
+                                  --dry-run        print what each write would do,
+                                                   write nothing
+  namespace reopen <NAME>         Let a retired namespace be registered under again
+                                  --store <URL>    as for retire
+                                  --dry-run        as for retire
+  leases list [OPTS]              List etcd's leases: TTL, worker, instances
+                                  --keys           also every key attached
+                                  --store <URL>    etcd to list (default DISCOVERY_URL)
+  leases revoke <ID>              Revoke a lease by hex ID, deleting its keys
+                                  --store <URL>    as for list
+  layout status                   Show the key layout discovery records are read from
+                                  and written to in etcd
+                                  --store <URL>    etcd to ask (default DISCOVERY_URL)
+  layout migrate <VERSION>        Move discovery records to another key layout online:
+                                  double-write, copy, verify, cut over
+                                  --settle <SECS>  wait before copying (default 10)
+                                  --passes <N>     verify passes before giving up
+                                                   (default 5)
+                                  --store <URL>    as for status
+                                  --dry-run        print what would be written,
+                                                   write nothing
+  layout finish                   After a cutover, delete the old layout's records
+                                  --store <URL>    as for status
+                                  --dry-run        as for migrate
+  layout abort                    Before a cutover, go back to the old layout
+                                  --store <URL>    as for status
+                                  --dry-run        as for migrate
+  encoding status                 Show whether each discovery bucket is written as
+                                  JSON or protobuf
+                                  --store <URL>    store to ask (default DISCOVERY_URL)
+  encoding set <BUCKET> <VERSION> Set a bucket's version: 1 JSON, 2 protobuf
+                                  --store <URL>    as for status
+  tail <BUCKET> [OPTS]            Print a bucket's changes live: key, revision,
+                                  changed fields, latency
+                                  --prefix <P>     only keys starting with P
+                                  --store <URL>    store to watch (default DISCOVERY_URL)
+";
 
 fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let command = args.next();
+
     // Initialize Dynamo runtime
     let runtime = Runtime::from_settings()?;
-    
-    // Record start time
-    let start_time = Instant::now();
-    
-    // Run the async code in the Dynamo runtime's primary executor
-    runtime.primary().block_on(async {
-        // Create etcd client configuration
-        let endpoints: Vec<String> = std::env::var("ETCD_ENDPOINTS")
-            .map_err(|e| anyhow::anyhow!("Failed to get ETCD_ENDPOINTS: {}", e))?
-            .split(',')
-            .map(|s| s.to_string())
-            .collect();
-        
-        let client_options = ClientOptions {
-            etcd_url: endpoints,
-            etcd_connect_options: None,
-            attach_lease: true,
-        };
-        
-        // Create the Dynamo etcd client
-        let client = Client::new(client_options, runtime.clone()).await
-            .map_err(|e| anyhow::anyhow!("Failed to create etcd client: {}", e))?;
-        
-        debug_println!(WHITE, "[MAIN]", RESET, "Connected to etcd with primary lease ID: {}", client.lease_id());
-        
-        // Get the primary lease
-        let primary_lease = client.primary_lease();
-        debug_println!(WHITE, "[MAIN]", RESET, "Primary lease ID: {}", primary_lease.id());
-        // Create a secondary lease with a 15 second TTL
-        let secondary_lease = client.create_lease(10).await
-            .map_err(|e| anyhow::anyhow!("Failed to create secondary lease: {}", e))?;
-        debug_println!(WHITE, "[MAIN]", RESET, "Secondary lease ID: {}", secondary_lease.id());
-        
-        // Keep the primary lease alive by running the runtime
-        debug_println!(WHITE, "[MAIN]", RESET, "Monitoring primary lease. Press Ctrl+C to stop...");
-        debug_println!(WHITE, "[MAIN]", WHITE, "ℹ️ Try running 'make restart-leader' in another terminal to test leader re-election");
-        
-        loop { 
-            sleep(Duration::from_secs(5)).await;
-            let primary_valid: bool = primary_lease.is_valid().await
-                .map_err(|e| anyhow::anyhow!("Failed to check primary lease validity: {}", e))?;
-            let secondary_valid: bool = secondary_lease.is_valid().await
-                .map_err(|e| anyhow::anyhow!("Failed to check secondary lease validity: {}", e))?;
-            let elapsed = start_time.elapsed();
-            let elapsed_str = format_elapsed(elapsed);
-            if primary_valid && secondary_valid {
-                debug_println!(WHITE, "[MAIN]", RESET, "Primary lease valid: {} Secondary lease valid: {} (elapsed: {})", primary_valid, secondary_valid, elapsed_str);
-                debug_println!(WHITE, "", RESET, "---");
-                debug_println!(WHITE, "", RESET, "");
-            } else {        
-                if !primary_valid {
-                    debug_println!(WHITE, "[MAIN]", RED, "⚠️  PRIMARY LEASE BECAME INVALID! (elapsed: {})", elapsed_str);
-                }
-                if !secondary_valid {
-                    debug_println!(WHITE, "[MAIN]", RED, "⚠️  SECONDARY LEASE BECAME INVALID! (elapsed: {})", elapsed_str);
-                }
-                debug_println!(WHITE, "[MAIN]", RED, "Exiting due to lease invalidation...");
-                break;
-            }
+
+    match command.as_deref() {
+        None | Some("monitor") => monitor::run(runtime),
+        Some("replay") => replay::run(runtime, args.collect()),
+        Some("bench") => bench::run(runtime, args.collect()),
+        Some("loadgen") => loadgen::run(runtime, args.collect()),
+        Some("scenario") => scenario::run(runtime, args.collect()),
+        Some("namespace") => namespace::run(runtime, args.collect()),
+        Some("leases") => leases::run(runtime, args.collect()),
+        Some("layout") => layout::run(runtime, args.collect()),
+        Some("encoding") => encoding::run(runtime, args.collect()),
+        Some("tail") => tail::run(runtime, args.collect()),
+        Some("-h") | Some("--help") | Some("help") => {
+            print!("{USAGE}");
+            Ok(())
+        }
+        Some(other) => {
+            eprint!("Unknown command '{other}'\n\n{USAGE}");
+            std::process::exit(2);
         }
-        
-        Ok::<(), anyhow::Error>(())
-    })
+    }
 }
@@ -0,0 +1,144 @@
+use std::time::Duration;
+
+use dynamo_runtime::Runtime;
+use dynamo_runtime::distributed::StoreUrl;
+use dynamo_runtime::retirement::{self, RetireConfig};
+use dynamo_runtime::storage::key_value_store::KeyValueStoreManager;
+
+use dynamo_runtime::debug_println;
+
+const USAGE: &str = "\
+Usage: namespace retire <NAME> [--drain SECS] [--by WHO] [--store URL] [--dry-run]
+       namespace reopen <NAME> [--store URL] [--dry-run]";
+
+/// `namespace retire <NAME> [--drain SECS] [--by WHO] [--store URL] [--dry-run]`
+/// `namespace reopen <NAME> [--store URL] [--dry-run]`
+///
+/// Retires a namespace: closes it, waits for routers to drain, deletes its keys and writes an
+/// audit record. Reopening lets the name be registered under again. The store is `--store`, or
+/// `DISCOVERY_URL` if not given.
+///
+/// With `--dry-run`, nothing is written and nobody waited for: each write is printed with what
+/// it would do against the store as it is. Exits non-zero if one would conflict.
+pub fn run(runtime: Runtime, args: Vec<String>) -> anyhow::Result<()> {
+    let mut positional = Vec::new();
+    let mut config = RetireConfig::default();
+    let mut store_url = None;
+    let mut dry_run = false;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--drain" => {
+                let v = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--drain needs a value"))?;
+                let secs = v
+                    .parse()
+                    .map_err(|e| anyhow::anyhow!("Invalid --drain '{}': {}", v, e))?;
+                config.drain = Duration::from_secs(secs);
+            }
+            "--by" => {
+                config.retired_by = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--by needs a value"))?;
+            }
+            "--store" => {
+                let v = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--store needs a value"))?;
+                store_url = Some(v.parse::<StoreUrl>()?);
+            }
+            "--dry-run" => dry_run = true,
+            _ => positional.push(arg),
+        }
+    }
+    let [command, namespace] =
+        <[String; 2]>::try_from(positional).map_err(|_| anyhow::anyhow!(USAGE))?;
+    let store_url = match store_url {
+        Some(url) => url,
+        None => StoreUrl::from_env()?
+            .ok_or_else(|| anyhow::anyhow!("Give the store with --store or DISCOVERY_URL"))?,
+    };
+
+    if dry_run {
+        config.drain = Duration::ZERO;
+    }
+
+    runtime.primary().block_on(async {
+        let mut store = store_url.connect(runtime.clone()).await?;
+        if dry_run {
+            store = store.with_dry_run();
+        }
+        let result = match command.as_str() {
+            "retire" => retire(&store, &namespace, &config).await,
+            "reopen" => reopen(&store, &namespace).await,
+            other => Err(anyhow::anyhow!(
+                "Unknown namespace command '{}'\n{}",
+                other,
+                USAGE
+            )),
+        };
+        // What a dry run got to, also when it failed part way
+        if let Some(dry_run) = store.dry_run() {
+            for change in dry_run.changes() {
+                if change.conflicts() {
+                    debug_println!(WHITE, "[DRY RUN]", RED, "  {}", change);
+                } else {
+                    debug_println!(WHITE, "[DRY RUN]", RESET, "  {}", change);
+                }
+            }
+            result?;
+            let conflicts = dry_run.conflicts().len();
+            if conflicts > 0 {
+                anyhow::bail!("{} of the writes would conflict", conflicts);
+            }
+            debug_println!(WHITE, "[DRY RUN]", YELLOW, "Nothing was written");
+            return Ok(());
+        }
+        result
+    })
+}
+
+async fn retire(
+    store: &KeyValueStoreManager,
+    namespace: &str,
+    config: &RetireConfig,
+) -> anyhow::Result<()> {
+    debug_println!(
+        WHITE,
+        "[NAMESPACE]",
+        RESET,
+        "Retiring {} after a {:?} drain",
+        namespace,
+        config.drain
+    );
+    let record = retirement::retire_namespace(store, namespace, config).await?;
+    for key in &record.deleted_keys {
+        debug_println!(WHITE, "[NAMESPACE]", RESET, "  deleted {}", key);
+    }
+    debug_println!(
+        WHITE,
+        "[NAMESPACE]",
+        GREEN,
+        "✅ Retired {}: {} instances drained, {} keys deleted",
+        namespace,
+        record.drained,
+        record.deleted_keys.len()
+    );
+    Ok(())
+}
+
+async fn reopen(store: &KeyValueStoreManager, namespace: &str) -> anyhow::Result<()> {
+    if retirement::reopen_namespace(store, namespace).await? {
+        debug_println!(WHITE, "[NAMESPACE]", GREEN, "✅ Reopened {}", namespace);
+    } else {
+        debug_println!(
+            WHITE,
+            "[NAMESPACE]",
+            YELLOW,
+            "⚠️  {} was not retired",
+            namespace
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,116 @@
+use dynamo_runtime::DistributedRuntime;
+use dynamo_runtime::Runtime;
+use dynamo_runtime::pipeline::{PushRouter, RouterMode};
+use dynamo_runtime::protocols::EndpointId;
+use dynamo_runtime::replay::{ReplayOptions, Replayer, read_captures};
+
+use dynamo_runtime::debug_println;
+
+/// `replay <FILE> <ENDPOINT> [--rate N] [--instance ID]`
+///
+/// Exits non-zero if any replayed request does not match its capture.
+pub fn run(runtime: Runtime, args: Vec<String>) -> anyhow::Result<()> {
+    let mut positional = Vec::new();
+    let mut options = ReplayOptions::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--rate" => {
+                let v = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--rate needs a value"))?;
+                options.rate = v
+                    .parse()
+                    .map_err(|e| anyhow::anyhow!("Invalid --rate '{}': {}", v, e))?;
+            }
+            "--instance" => {
+                let v = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--instance needs a value"))?;
+                options.instance_id = Some(
+                    v.parse()
+                        .map_err(|e| anyhow::anyhow!("Invalid --instance '{}': {}", v, e))?,
+                );
+            }
+            _ => positional.push(arg),
+        }
+    }
+    let [file, endpoint] = <[String; 2]>::try_from(positional).map_err(|_| {
+        anyhow::anyhow!("Usage: replay <FILE> <ENDPOINT> [--rate N] [--instance ID]")
+    })?;
+    let endpoint_id: EndpointId = endpoint.as_str().into();
+    let captures = read_captures(&file)?;
+
+    runtime.primary().block_on(async {
+        let drt = DistributedRuntime::from_settings(runtime.clone()).await?;
+        let client = drt
+            .namespace(&endpoint_id.namespace)?
+            .component(&endpoint_id.component)?
+            .endpoint(&endpoint_id.name)
+            .client()
+            .await?;
+        let instances = client.wait_for_instances().await?;
+        debug_println!(
+            WHITE,
+            "[REPLAY]",
+            RESET,
+            "Replaying {} requests from {} to {} ({} instances) at {}/s",
+            captures.len(),
+            file,
+            endpoint,
+            instances.len(),
+            options.rate
+        );
+
+        let router = PushRouter::from_client(client, RouterMode::RoundRobin).await?;
+        let results = Replayer::new(router, options)?.replay_all(captures).await?;
+
+        let mut mismatched = 0;
+        for result in &results {
+            if result.is_match() {
+                debug_println!(
+                    WHITE,
+                    "[REPLAY]",
+                    GREEN,
+                    "✅ {} matched ({:?})",
+                    result.id,
+                    result.elapsed
+                );
+                continue;
+            }
+            mismatched += 1;
+            if let Some(err) = &result.error {
+                debug_println!(WHITE, "[REPLAY]", RED, "❌ {} failed: {}", result.id, err);
+            }
+            for diff in &result.diffs {
+                debug_println!(
+                    WHITE,
+                    "[REPLAY]",
+                    YELLOW,
+                    "⚠️  {} item {}: expected {:?} got {:?}",
+                    result.id,
+                    diff.index,
+                    diff.expected,
+                    diff.actual
+                );
+            }
+        }
+        debug_println!(
+            WHITE,
+            "[REPLAY]",
+            RESET,
+            "{}/{} matched",
+            results.len() - mismatched,
+            results.len()
+        );
+
+        if mismatched > 0 {
+            anyhow::bail!(
+                "{} of {} replayed requests did not match",
+                mismatched,
+                results.len()
+            );
+        }
+        Ok::<(), anyhow::Error>(())
+    })
+}
@@ -0,0 +1,75 @@
+use std::path::PathBuf;
+
+use dynamo_runtime::Runtime;
+use dynamo_runtime::testing::scenario::Scenario;
+use dynamo_runtime::testing::versions;
+
+use dynamo_runtime::debug_println;
+
+const USAGE: &str = "Usage: scenario <FILE> [--etcd BINARY]...";
+
+/// `scenario <FILE> [--etcd BINARY]...`
+///
+/// Starts the scenario's target, runs its actions while probing, and prints how long each
+/// action took to recover from. Exits non-zero if one took longer than its `recover_within`.
+///
+/// With `--etcd`, runs an etcd scenario once per binary instead and prints lease expiries,
+/// keep-alive errors and watch gaps per etcd version side by side.
+pub fn run(runtime: Runtime, args: Vec<String>) -> anyhow::Result<()> {
+    let mut positional = Vec::new();
+    let mut binaries = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--etcd" => {
+                let v = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--etcd needs a value"))?;
+                binaries.push(PathBuf::from(v));
+            }
+            _ => positional.push(arg),
+        }
+    }
+    let [path] = <[String; 1]>::try_from(positional).map_err(|_| anyhow::anyhow!(USAGE))?;
+    let scenario = Scenario::from_file(&path)?;
+
+    runtime.primary().block_on(async {
+        if !binaries.is_empty() {
+            let comparison = versions::compare(&scenario, &binaries).await?;
+            for line in comparison.to_string().lines() {
+                debug_println!(WHITE, "[SCENARIO]", RESET, "{}", line);
+            }
+            return Ok(());
+        }
+
+        debug_println!(
+            WHITE,
+            "[SCENARIO]",
+            RESET,
+            "Starting {:?} for {}",
+            scenario.target,
+            scenario.name
+        );
+        let harness = scenario.harness().await?;
+        let report = scenario.run(harness).await?;
+
+        for line in report.to_string().lines() {
+            debug_println!(WHITE, "[SCENARIO]", RESET, "{}", line);
+        }
+        for outcome in report.actions.iter().filter(|o| !o.passed()) {
+            debug_println!(
+                WHITE,
+                "[SCENARIO]",
+                RED,
+                "❌ {} at {:?} recovered too slowly",
+                outcome.action,
+                outcome.at
+            );
+        }
+
+        if !report.passed() {
+            anyhow::bail!("{} did not recover in time", report.name);
+        }
+        Ok::<(), anyhow::Error>(())
+    })
+}
@@ -0,0 +1,157 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use dynamo_runtime::Runtime;
+use dynamo_runtime::distributed::StoreUrl;
+use dynamo_runtime::storage::key_value_store::{KeyValue, WatchEvent, WatchFilter, patch_paths};
+
+use dynamo_runtime::debug_println;
+
+const USAGE: &str = "Usage: tail <BUCKET> [--prefix PREFIX] [--store URL]";
+
+/// `tail <BUCKET> [--prefix PREFIX] [--store URL]`
+///
+/// Prints every change to a bucket as it happens, until Ctrl+C: the key, its revision, which
+/// fields of the JSON value changed and how long the change took to get here. The keys already
+/// there are printed first, faint. The store is `--store`, or `DISCOVERY_URL` if not given.
+///
+/// Latency needs the store's commit time, which NATS and the memory store give and etcd does
+/// not, so it is `-` there.
+pub fn run(runtime: Runtime, args: Vec<String>) -> anyhow::Result<()> {
+    let mut positional = Vec::new();
+    let mut prefix = String::new();
+    let mut store_url = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--prefix" => {
+                prefix = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--prefix needs a value"))?;
+            }
+            "--store" => {
+                let v = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--store needs a value"))?;
+                store_url = Some(v.parse::<StoreUrl>()?);
+            }
+            _ => positional.push(arg),
+        }
+    }
+    let [bucket] = <[String; 1]>::try_from(positional).map_err(|_| anyhow::anyhow!(USAGE))?;
+    let store_url = match store_url {
+        Some(url) => url,
+        None => StoreUrl::from_env()?
+            .ok_or_else(|| anyhow::anyhow!("Give the store with --store or DISCOVERY_URL"))?,
+    };
+
+    runtime.primary().block_on(async {
+        let store = Arc::new(store_url.connect(runtime.clone()).await?);
+        let cancel_token = runtime.child_token();
+        let filter = WatchFilter::prefix(prefix.as_str());
+        let mut events = store.watch_with_deltas(&bucket, None, filter, cancel_token.clone());
+        debug_println!(
+            WHITE,
+            "[TAIL]",
+            RESET,
+            "Watching {}/{}. Press Ctrl+C to stop...",
+            bucket,
+            prefix
+        );
+
+        // Keys seen so far, to tell a new key from a value that is not JSON
+        let mut known = HashSet::new();
+        let mut synced = false;
+        loop {
+            let event = tokio::select! {
+                event = events.recv() => event,
+                _ = tokio::signal::ctrl_c() => {
+                    cancel_token.cancel();
+                    return Ok(());
+                }
+            };
+            let Some(event) = event else {
+                return Ok(());
+            };
+            match event {
+                WatchEvent::Put(kv) if !synced => {
+                    known.insert(kv.key().to_string());
+                    debug_println!(
+                        WHITE,
+                        "[TAIL]",
+                        FAINT,
+                        "PUT  {}  rev {}  {} bytes",
+                        kv.key(),
+                        kv.sequence(),
+                        kv.value().len()
+                    );
+                }
+                WatchEvent::Put(kv) => {
+                    let changed = summary(&kv, known.insert(kv.key().to_string()));
+                    debug_println!(
+                        WHITE,
+                        "[TAIL]",
+                        GREEN,
+                        "PUT  {}  rev {}  {}  {}",
+                        kv.key(),
+                        kv.sequence(),
+                        changed,
+                        latency(&kv)
+                    );
+                }
+                WatchEvent::Delete(kv) => {
+                    known.remove(kv.key());
+                    debug_println!(
+                        WHITE,
+                        "[TAIL]",
+                        RED,
+                        "DEL  {}  rev {}  {}",
+                        kv.key(),
+                        kv.sequence(),
+                        latency(&kv)
+                    );
+                }
+                WatchEvent::InitialSyncComplete => {
+                    synced = true;
+                    debug_println!(
+                        WHITE,
+                        "[TAIL]",
+                        RESET,
+                        "--- {} keys, changes follow ---",
+                        known.len()
+                    );
+                }
+                WatchEvent::Disconnected => {
+                    debug_println!(WHITE, "[TAIL]", YELLOW, "⚠️  Disconnected from the store");
+                }
+                WatchEvent::Reconnected => {
+                    debug_println!(
+                        WHITE,
+                        "[TAIL]",
+                        YELLOW,
+                        "Reconnected, changes missed follow"
+                    );
+                }
+                WatchEvent::Error(e) => anyhow::bail!("Watch failed: {}", e),
+                WatchEvent::Closed => return Ok(()),
+            }
+        }
+    })
+}
+
+/// What a put changed: the fields of its JSON value, or its size when there is nothing to
+/// compare it to
+fn summary(kv: &KeyValue, new_key: bool) -> String {
+    match kv.delta().map(patch_paths) {
+        Some(paths) if paths.is_empty() => "unchanged".to_string(),
+        Some(paths) if paths == [""] => format!("replaced, {} bytes", kv.value().len()),
+        Some(paths) => paths.join(", "),
+        None if new_key => format!("new, {} bytes", kv.value().len()),
+        None => format!("replaced, {} bytes", kv.value().len()),
+    }
+}
+
+fn latency(kv: &KeyValue) -> String {
+    kv.propagation_latency()
+        .map_or_else(|| "-".to_string(), |latency| format!("{:?}", latency))
+}